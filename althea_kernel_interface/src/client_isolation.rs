@@ -0,0 +1,510 @@
+//! Layer 3 isolation for individual exit clients. An isolated client's traffic is looked up in a routing
+//! table of its own that only knows the way back to that client and out to the internet, every other client
+//! subnet is prohibited, and traffic other clients send towards it is refused by policy rules. Optionally
+//! its traffic leaves the exit from a dedicated public address instead of the shared masquerade address.
+
+use super::KernelInterface;
+use crate::rita_owned::RITA_ROUTE_PROTO;
+use crate::KernelInterfaceError as Error;
+use ipnetwork::IpNetwork;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Priority of the policy rules for isolated clients, ahead of the main table at 32766
+pub const ISOLATION_RULE_PRIORITY: &str = "900";
+/// Isolated clients use the routing tables from this number up
+pub const ISOLATION_TABLE_BASE: u32 = 1000;
+
+/// Everything needed to install the routes, rules and nat for one isolated client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsolatedClientConfig {
+    /// Routing table of this client
+    pub table: u32,
+    pub internal_ip: IpAddr,
+    pub internet_ipv6: Option<IpNetwork>,
+    /// The exit interface the client is tunneled over
+    pub interface: String,
+    /// Every exit interface, traffic arriving on any of them for this client is refused
+    pub exit_interfaces: Vec<String>,
+    pub exit_internal_v4: IpAddr,
+    /// The subnets all exit clients are numbered from, prohibited in this client's table
+    pub client_subnet_v4: IpNetwork,
+    pub client_subnet_v6: Option<IpNetwork>,
+    pub external_nic: String,
+    /// If set this client's v4 traffic is source natted to this address
+    pub public_ipv4: Option<Ipv4Addr>,
+}
+
+impl IsolatedClientConfig {
+    fn snat_comment(&self) -> String {
+        format!("rita-isolation-{}", self.internal_ip)
+    }
+}
+
+impl dyn KernelInterface {
+    pub(crate) fn run_ip(&self, args: &[&str]) -> Result<(), Error> {
+        let output = self.run_command("ip", args)?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "ip {} failed {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Installs or updates the routing table, policy rules and source nat of an isolated client, safe to
+    /// call again with the same config
+    pub fn setup_isolated_client(&self, config: &IsolatedClientConfig) -> Result<(), Error> {
+        let table = config.table.to_string();
+        let internal_ip = config.internal_ip.to_string();
+        let exit_v4 = config.exit_internal_v4.to_string();
+        let subnet_v4 = config.client_subnet_v4.to_string();
+
+        // the table only knows the client itself and the way out to the internet
+        self.run_ip(&[
+            "route",
+            "replace",
+            "prohibit",
+            &subnet_v4,
+            "table",
+            &table,
+            "proto",
+            RITA_ROUTE_PROTO,
+        ])?;
+        self.run_ip(&[
+            "route",
+            "replace",
+            &internal_ip,
+            "dev",
+            &config.interface,
+            "src",
+            &exit_v4,
+            "table",
+            &table,
+            "proto",
+            RITA_ROUTE_PROTO,
+        ])?;
+        // copied from the main table, reconciliation copies it again when the main one changes
+        match self.get_default_route()? {
+            Some(default) => self.run_ip(&[
+                "route",
+                "replace",
+                "default",
+                "via",
+                &default.via.to_string(),
+                "dev",
+                &default.nic,
+                "table",
+                &table,
+                "proto",
+                RITA_ROUTE_PROTO,
+            ])?,
+            None => warn!("No default route to copy into isolation table {}", table),
+        }
+        if let Some(ipv6) = config.internet_ipv6 {
+            let ipv6 = ipv6.to_string();
+            if let Some(subnet_v6) = config.client_subnet_v6 {
+                self.run_ip(&[
+                    "-6",
+                    "route",
+                    "replace",
+                    "prohibit",
+                    &subnet_v6.to_string(),
+                    "table",
+                    &table,
+                    "proto",
+                    RITA_ROUTE_PROTO,
+                ])?;
+            }
+            self.run_ip(&[
+                "-6",
+                "route",
+                "replace",
+                &ipv6,
+                "dev",
+                &config.interface,
+                "table",
+                &table,
+                "proto",
+                RITA_ROUTE_PROTO,
+            ])?;
+            match self.get_default_route_v6()? {
+                Some(default) => self.run_ip(&[
+                    "-6",
+                    "route",
+                    "replace",
+                    "default",
+                    "via",
+                    &default.via.to_string(),
+                    "dev",
+                    &default.nic,
+                    "table",
+                    &table,
+                    "proto",
+                    RITA_ROUTE_PROTO,
+                ])?,
+                None => warn!(
+                    "No ipv6 default route to copy into isolation table {}",
+                    table
+                ),
+            }
+        }
+
+        self.remove_isolation_rules(&config.internal_ip, config.internet_ipv6)?;
+        self.run_ip(&[
+            "rule",
+            "add",
+            "from",
+            &internal_ip,
+            "lookup",
+            &table,
+            "priority",
+            ISOLATION_RULE_PRIORITY,
+        ])?;
+        for interface in config.exit_interfaces.iter() {
+            self.run_ip(&[
+                "rule",
+                "add",
+                "to",
+                &internal_ip,
+                "iif",
+                interface,
+                "prohibit",
+                "priority",
+                ISOLATION_RULE_PRIORITY,
+            ])?;
+        }
+        if let Some(ipv6) = config.internet_ipv6 {
+            let ipv6 = ipv6.to_string();
+            self.run_ip(&[
+                "-6",
+                "rule",
+                "add",
+                "from",
+                &ipv6,
+                "lookup",
+                &table,
+                "priority",
+                ISOLATION_RULE_PRIORITY,
+            ])?;
+            for interface in config.exit_interfaces.iter() {
+                self.run_ip(&[
+                    "-6",
+                    "rule",
+                    "add",
+                    "to",
+                    &ipv6,
+                    "iif",
+                    interface,
+                    "prohibit",
+                    "priority",
+                    ISOLATION_RULE_PRIORITY,
+                ])?;
+            }
+        }
+
+        self.remove_isolation_snat(config)?;
+        if let Some(public_ipv4) = config.public_ipv4 {
+            let public_ipv4 = public_ipv4.to_string();
+            if self.does_nftables_exist() {
+                self.run_command(
+                    "nft",
+                    &[
+                        "insert",
+                        "rule",
+                        "ip",
+                        "nat",
+                        "postrouting",
+                        "ip",
+                        "saddr",
+                        &internal_ip,
+                        "oifname",
+                        &config.external_nic,
+                        "counter",
+                        "snat",
+                        "to",
+                        &public_ipv4,
+                        "comment",
+                        &config.snat_comment(),
+                    ],
+                )?;
+            } else {
+                // inserted so that it is hit before the masquerade rule
+                self.add_iptables_rule(
+                    "iptables",
+                    &[
+                        "-w",
+                        "-t",
+                        "nat",
+                        "-I",
+                        "POSTROUTING",
+                        "-s",
+                        &internal_ip,
+                        "-o",
+                        &config.external_nic,
+                        "-j",
+                        "SNAT",
+                        "--to-source",
+                        &public_ipv4,
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// True if everything setup_isolated_client installed for this client is still in the kernel and the
+    /// default routes of its table still match the main table. Interface restarts and firewall reloads
+    /// take parts of it away, and the uplink gateway can change under it
+    pub fn is_isolated_client_current(&self, config: &IsolatedClientConfig) -> Result<bool, Error> {
+        let mut families = vec![false];
+        if config.internet_ipv6.is_some() {
+            families.push(true);
+        }
+        for ipv6 in families.iter().copied() {
+            let main = if ipv6 {
+                self.get_default_route_v6()?
+            } else {
+                self.get_default_route()?
+            };
+            let isolated = self.get_table_default_route(config.table, ipv6)?;
+            let same_gateway = match (&main, &isolated) {
+                (Some(main), Some(isolated)) => {
+                    main.via == isolated.via && main.nic == isolated.nic
+                }
+                (None, _) => true,
+                (Some(_), None) => false,
+            };
+            if !same_gateway {
+                return Ok(false);
+            }
+        }
+
+        // one lookup rule and a prohibit rule per exit interface, for each family
+        let expected_rules = families.len() * (1 + config.exit_interfaces.len());
+        if self
+            .get_isolation_rules(&config.internal_ip, config.internet_ipv6)?
+            .len()
+            != expected_rules
+        {
+            return Ok(false);
+        }
+
+        if let Some(public_ipv4) = config.public_ipv4 {
+            let present = if self.does_nftables_exist() {
+                let output =
+                    self.run_command("nft", &["-a", "list", "chain", "ip", "nat", "postrouting"])?;
+                let stdout = String::from_utf8(output.stdout)?;
+                !nft_rule_handles(&stdout, &config.snat_comment()).is_empty()
+            } else {
+                self.check_iptable_rule(
+                    "iptables",
+                    &[
+                        "-w",
+                        "-t",
+                        "nat",
+                        "-C",
+                        "POSTROUTING",
+                        "-s",
+                        &config.internal_ip.to_string(),
+                        "-o",
+                        &config.external_nic,
+                        "-j",
+                        "SNAT",
+                        "--to-source",
+                        &public_ipv4.to_string(),
+                    ],
+                )?
+            };
+            if !present {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Removes everything setup_isolated_client installed for this client
+    pub fn teardown_isolated_client(&self, config: &IsolatedClientConfig) -> Result<(), Error> {
+        self.remove_isolation_rules(&config.internal_ip, config.internet_ipv6)?;
+        self.remove_isolation_snat(config)?;
+        let table = config.table.to_string();
+        self.run_command("ip", &["route", "flush", "table", &table])?;
+        self.run_command("ip", &["-6", "route", "flush", "table", &table])?;
+        Ok(())
+    }
+
+    /// Deletes the isolation rules that mention the client's addresses
+    fn remove_isolation_rules(
+        &self,
+        internal_ip: &IpAddr,
+        internet_ipv6: Option<IpNetwork>,
+    ) -> Result<(), Error> {
+        let mut addresses = vec![(false, internal_ip.to_string())];
+        if let Some(ipv6) = internet_ipv6 {
+            addresses.push((true, ipv6.to_string()));
+        }
+        for (v6, address) in addresses {
+            let family = if v6 { "-6" } else { "-4" };
+            let output = self.run_command(
+                "ip",
+                &[
+                    "-o",
+                    family,
+                    "rule",
+                    "show",
+                    "priority",
+                    ISOLATION_RULE_PRIORITY,
+                ],
+            )?;
+            let stdout = String::from_utf8(output.stdout)?;
+            for selector in isolation_rule_selectors(&stdout, &address) {
+                let mut args = vec![family, "rule", "del", "priority", ISOLATION_RULE_PRIORITY];
+                args.extend(selector.split_whitespace());
+                self.run_ip(&args)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The isolation rules that mention the client's addresses, as `ip rule` prints them after the priority
+    pub fn get_isolation_rules(
+        &self,
+        internal_ip: &IpAddr,
+        internet_ipv6: Option<IpNetwork>,
+    ) -> Result<Vec<String>, Error> {
+        let mut addresses = vec![("-4", internal_ip.to_string())];
+        if let Some(ipv6) = internet_ipv6 {
+            addresses.push(("-6", ipv6.to_string()));
+        }
+        let mut rules = Vec::new();
+        for (family, address) in addresses {
+            let output = self.run_command(
+                "ip",
+                &[
+                    "-o",
+                    family,
+                    "rule",
+                    "show",
+                    "priority",
+                    ISOLATION_RULE_PRIORITY,
+                ],
+            )?;
+            let stdout = String::from_utf8(output.stdout)?;
+            rules.extend(isolation_rule_selectors(&stdout, &address));
+        }
+        Ok(rules)
+    }
+
+    /// Removes every isolation rule, used at startup before the isolated clients are set up again
+    pub fn remove_all_isolation_rules(&self) -> Result<(), Error> {
+        for family in ["-4", "-6"] {
+            // each del removes one rule at this priority and fails once there are none left
+            while self
+                .run_command(
+                    "ip",
+                    &[family, "rule", "del", "priority", ISOLATION_RULE_PRIORITY],
+                )?
+                .status
+                .success()
+            {}
+        }
+        Ok(())
+    }
+
+    fn remove_isolation_snat(&self, config: &IsolatedClientConfig) -> Result<(), Error> {
+        if self.does_nftables_exist() {
+            let output =
+                self.run_command("nft", &["-a", "list", "chain", "ip", "nat", "postrouting"])?;
+            let stdout = String::from_utf8(output.stdout)?;
+            for handle in nft_rule_handles(&stdout, &config.snat_comment()) {
+                self.run_command(
+                    "nft",
+                    &[
+                        "delete",
+                        "rule",
+                        "ip",
+                        "nat",
+                        "postrouting",
+                        "handle",
+                        &handle,
+                    ],
+                )?;
+            }
+        } else {
+            let internal_ip = config.internal_ip;
+            let output = self.run_command("iptables", &["-w", "-t", "nat", "-S", "POSTROUTING"])?;
+            let stdout = String::from_utf8(output.stdout)?;
+            let source = format!("{internal_ip}/32");
+            for line in stdout.lines() {
+                let args: Vec<&str> = line.split_whitespace().collect();
+                let snat_for_client = args.windows(2).any(|w| w[0] == "-s" && w[1] == source)
+                    && args.contains(&"SNAT");
+                if snat_for_client && args.first() == Some(&"-A") {
+                    let mut delete = vec!["-w", "-t", "nat", "-D"];
+                    delete.extend(&args[1..]);
+                    self.run_command("iptables", &delete)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The selectors, everything after the priority, of the rules in `ip -o rule show` output that match
+/// traffic from or to address
+fn isolation_rule_selectors(out: &str, address: &str) -> Vec<String> {
+    out.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(_, selector)| selector.trim().to_string())
+        .filter(|selector| {
+            let words: Vec<&str> = selector.split_whitespace().collect();
+            words
+                .windows(2)
+                .any(|w| (w[0] == "from" || w[0] == "to") && w[1] == address)
+        })
+        .collect()
+}
+
+/// Handles of the rules in `nft -a list chain` output carrying the given comment
+fn nft_rule_handles(out: &str, comment: &str) -> Vec<String> {
+    let comment = format!("comment \"{comment}\"");
+    out.lines()
+        .filter(|line| line.contains(&comment))
+        .filter_map(|line| line.rsplit_once("# handle "))
+        .map(|(_, handle)| handle.trim().to_string())
+        .collect()
+}
+
+#[test]
+fn test_isolation_rule_selectors() {
+    let out = "900:\tfrom 172.168.1.5 lookup 1000
+900:\tfrom all to 172.168.1.5 iif wg_exit_v2 prohibit
+900:\tfrom all to 172.168.1.50 iif wg_exit_v2 prohibit
+900:\tfrom 172.168.1.9 lookup 1001
+";
+    assert_eq!(
+        isolation_rule_selectors(out, "172.168.1.5"),
+        vec![
+            "from 172.168.1.5 lookup 1000".to_string(),
+            "from all to 172.168.1.5 iif wg_exit_v2 prohibit".to_string()
+        ]
+    );
+    assert!(isolation_rule_selectors(out, "172.168.1.7").is_empty());
+}
+
+#[test]
+fn test_nft_rule_handles() {
+    let out = "table ip nat {
+	chain postrouting { # handle 1
+		type nat hook postrouting priority srcnat; policy accept;
+		ip saddr 172.168.1.5 oifname \"eth0\" counter packets 0 bytes 0 snat to 203.0.113.5 comment \"rita-isolation-172.168.1.5\" # handle 7
+		oifname \"eth0\" counter packets 0 bytes 0 masquerade # handle 2
+	}
+}";
+    assert_eq!(
+        nft_rule_handles(out, "rita-isolation-172.168.1.5"),
+        vec!["7".to_string()]
+    );
+    assert!(nft_rule_handles(out, "rita-isolation-172.168.1.50").is_empty());
+}
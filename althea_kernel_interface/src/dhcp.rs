@@ -0,0 +1,75 @@
+use super::KernelInterface;
+use crate::KernelInterfaceError as Error;
+use mac_address::MacAddress;
+use std::fs;
+use std::net::IpAddr;
+
+/// Where dnsmasq keeps its active leases on OpenWrt
+const DHCP_LEASES_FILE: &str = "/tmp/dhcp.leases";
+
+/// A lease handed out by the lan dhcp server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    /// Unix time the lease expires at, 0 for leases that never expire
+    pub expires: u64,
+    pub mac: MacAddress,
+    pub ip: IpAddr,
+    /// The name the device sent, if any
+    pub hostname: Option<String>,
+}
+
+impl dyn KernelInterface {
+    /// Gets the active dhcp leases from dnsmasq, no leases file means no leases
+    pub fn get_dhcp_leases(&self) -> Result<Vec<DhcpLease>, Error> {
+        match fs::read_to_string(DHCP_LEASES_FILE) {
+            Ok(contents) => Ok(parse_dhcp_leases(&contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Parses the dnsmasq leases file, each line is `<expiry> <mac> <ip> <hostname or *> <client id or *>`
+/// dhcpv6 leases and malformed lines are skipped
+fn parse_dhcp_leases(contents: &str) -> Vec<DhcpLease> {
+    let mut leases = Vec::new();
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let (expires, mac, ip, hostname) =
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(expires), Some(mac), Some(ip), Some(hostname)) => {
+                    (expires, mac, ip, hostname)
+                }
+                _ => continue,
+            };
+        if let (Ok(expires), Ok(mac), Ok(ip)) = (expires.parse(), mac.parse(), ip.parse()) {
+            leases.push(DhcpLease {
+                expires,
+                mac,
+                ip,
+                hostname: match hostname {
+                    "*" => None,
+                    name => Some(name.to_string()),
+                },
+            });
+        }
+    }
+    leases
+}
+
+#[test]
+fn test_parse_dhcp_leases() {
+    let contents = "1700000000 aa:bb:cc:dd:ee:ff 192.168.10.120 laptop 01:aa:bb:cc:dd:ee:ff\n\
+                    0 11:22:33:44:55:66 192.168.10.5 * *\n\
+                    duid 00:01:00:01:2b:2c:3d:4e:aa:bb:cc:dd:ee:ff\n";
+    let leases = parse_dhcp_leases(contents);
+    assert_eq!(leases.len(), 2);
+    assert_eq!(leases[0].expires, 1_700_000_000);
+    assert_eq!(
+        leases[0].mac,
+        "aa:bb:cc:dd:ee:ff".parse::<MacAddress>().unwrap()
+    );
+    assert_eq!(leases[0].hostname, Some("laptop".to_string()));
+    assert_eq!(leases[1].expires, 0);
+    assert_eq!(leases[1].hostname, None);
+}
@@ -1,5 +1,6 @@
 use super::KernelInterface;
 use crate::hardware_info::{get_kernel_version, parse_kernel_version};
+use crate::rita_owned::RITA_ROUTE_PROTO;
 use crate::{open_tunnel::to_wg_local, KernelInterfaceError as Error};
 use althea_types::WgKey;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
@@ -30,6 +31,10 @@ pub struct ClientExitTunnelConfig {
     /// by cake. Traffic is shaped incoming on wg_exit and outgoing on br_lan resulting
     /// in a symmetrical limit of the users choice. Specified in mbit/s
     pub user_specified_speed: Option<usize>,
+    /// The mtu to set on wg_exit, tcp connections over the tunnel are clamped to fit inside it
+    pub mtu: usize,
+    /// Wireguard persistent keepalive interval in seconds, 0 disables it
+    pub persistent_keepalive: u16,
 }
 
 impl dyn KernelInterface {
@@ -59,7 +64,7 @@ impl dyn KernelInterface {
                 "allowed-ips",
                 "0.0.0.0/0, ::/0",
                 "persistent-keepalive",
-                "5",
+                &args.persistent_keepalive.to_string(),
             ],
         )?;
 
@@ -136,7 +141,17 @@ impl dyn KernelInterface {
             }
         }
 
-        let output = self.run_command("ip", &["link", "set", "dev", "wg_exit", "mtu", "1340"])?;
+        let output = self.run_command(
+            "ip",
+            &[
+                "link",
+                "set",
+                "dev",
+                "wg_exit",
+                "mtu",
+                &args.mtu.to_string(),
+            ],
+        )?;
         if !output.stderr.is_empty() {
             return Err(Error::RuntimeError(format!(
                 "received error adding wg link: {}",
@@ -172,6 +187,8 @@ impl dyn KernelInterface {
                 &gateway.to_string(),
                 "dev",
                 "wg_exit",
+                "proto",
+                RITA_ROUTE_PROTO,
             ],
         )?;
         if !output.stderr.is_empty() {
@@ -190,8 +207,19 @@ impl dyn KernelInterface {
             warn!("Failed to delete default ip6 route {:?}", e);
         }
         // Set new default route
-        let output =
-            self.run_command("ip", &["-6", "route", "add", "default", "dev", "wg_exit"])?;
+        let output = self.run_command(
+            "ip",
+            &[
+                "-6",
+                "route",
+                "add",
+                "default",
+                "dev",
+                "wg_exit",
+                "proto",
+                RITA_ROUTE_PROTO,
+            ],
+        )?;
         if !output.stderr.is_empty() {
             error!("IPV6 ERROR: Unable to set ip -6 default route");
             return Err(Error::RuntimeError(format!(
@@ -209,8 +237,8 @@ impl dyn KernelInterface {
     /// on incoming interface or ip, this is intentional, as it allows
     /// the phone clients over in light_client_manager to function using these
     /// same rules. It may be advisable in the future to split them up into
-    /// individual nat entires for each option
-    pub fn create_client_nat_rules(&self) -> Result<(), Error> {
+    /// individual nat entires for each option. Also clamps tcp mss to fit the wg_exit mtu
+    pub fn create_client_nat_rules(&self, mtu: usize) -> Result<(), Error> {
         let use_iptables = !self.does_nftables_exist();
 
         if use_iptables {
@@ -233,63 +261,154 @@ impl dyn KernelInterface {
             self.set_nft_lan_fwd_rule()?;
         }
 
-        // Set mtu
-        if use_iptables {
-            self.add_iptables_rule(
-                "iptables",
+        // clamp tcp connections to fit inside the tunnel, path mtu discovery can not be relied on
+        // to do this because the icmp messages it needs are exactly what gets lost in a blackhole
+        self.set_mss_clamp("wg_exit", mtu)?;
+
+        Ok(())
+    }
+
+    /// The forwarding chains of the zones that nat out over wg_exit, the guest chain only exists
+    /// while the guest network is provisioned
+    fn client_forward_chains(&self, nftables: bool) -> Result<Vec<&'static str>, Error> {
+        let (lan, guest) = if nftables {
+            ("forward_lan", "forward_guest")
+        } else {
+            ("zone_lan_forward", "zone_guest_forward")
+        };
+        let guest_exists = if nftables {
+            self.does_nft_chain_exist(guest)?
+        } else {
+            self.run_command("iptables", &["-S", guest])?
+                .status
+                .success()
+        };
+        Ok(if guest_exists {
+            vec![lan, guest]
+        } else {
+            vec![lan]
+        })
+    }
+
+    /// blocks the client nat by inserting a blocker in the start of the special lan forwarding
+    /// table created by openwrt, and the guest network's if there is one.
+    pub fn block_client_nat(&self) -> Result<(), Error> {
+        let nftables = self.does_nftables_exist();
+        for chain in self.client_forward_chains(nftables)? {
+            if nftables {
+                self.insert_reject_rule(chain)?;
+            } else {
+                self.add_iptables_rule("iptables", &["-I", chain, "-j", "REJECT"])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the block created by block_client_nat() will fail if not run after that command
+    pub fn restore_client_nat(&self) -> Result<(), Error> {
+        let nftables = self.does_nftables_exist();
+        for chain in self.client_forward_chains(nftables)? {
+            if nftables {
+                self.delete_reject_rule(chain)?;
+            } else {
+                self.add_iptables_rule("iptables", &["-D", chain, "-j", "REJECT"])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Installs or removes the exit kill switch, a reject at the start of the lan forwarding table for
+    /// everything not leaving over wg_exit. With it in place lan traffic can never go out the local gateway
+    /// uplink unencrypted, when the exit tunnel is down it is dropped instead. Safe to call repeatedly
+    pub fn set_client_kill_switch(&self, enabled: bool) -> Result<(), Error> {
+        if self.does_nftables_exist() {
+            if enabled {
+                self.insert_nft_kill_switch_rule()?;
+            } else {
+                self.delete_nft_kill_switch_rule()?;
+            }
+            return Ok(());
+        }
+        for command in ["iptables", "ip6tables"] {
+            let present = self.check_iptable_rule(
+                command,
                 &[
-                    "-I",
-                    "FORWARD",
-                    "-p",
-                    "tcp",
-                    "--tcp-flags",
-                    "SYN,RST",
-                    "SYN",
+                    "-C",
+                    "zone_lan_forward",
+                    "!",
+                    "-o",
+                    "wg_exit",
                     "-j",
-                    "TCPMSS",
-                    "--clamp-mss-to-pmtu", //should be the same as --set-mss 1300
+                    "REJECT",
                 ],
             )?;
-
-            //ipv6 support
-            self.add_iptables_rule(
-                "ip6tables",
+            let action = match (enabled, present) {
+                (true, false) => "-I",
+                (false, true) => "-D",
+                _ => continue,
+            };
+            self.run_command(
+                command,
                 &[
-                    "-I",
-                    "FORWARD",
-                    "-p",
-                    "tcp",
-                    "--tcp-flags",
-                    "SYN,RST",
-                    "SYN",
+                    action,
+                    "zone_lan_forward",
+                    "!",
+                    "-o",
+                    "wg_exit",
                     "-j",
-                    "TCPMSS",
-                    "--clamp-mss-to-pmtu", //should be the same as --set-mss 1300
+                    "REJECT",
                 ],
             )?;
         }
-
         Ok(())
     }
 
-    /// blocks the client nat by inserting a blocker in the start of the special lan forwarding
-    /// table created by openwrt.
-    pub fn block_client_nat(&self) -> Result<(), Error> {
-        if !self.does_nftables_exist() {
-            self.add_iptables_rule("iptables", &["-I", "zone_lan_forward", "-j", "REJECT"])?;
-        } else {
-            self.insert_reject_rule()?;
+    /// Checks if the rule from create_guest_forward_rules() is in place
+    pub fn is_guest_forward_rule_present(&self) -> Result<bool, Error> {
+        if self.does_nftables_exist() {
+            return self.is_nft_guest_forward_rule_present();
         }
-        Ok(())
+        self.check_iptable_rule(
+            "iptables",
+            &["-C", "zone_guest_forward", "-o", "wg_exit", "-j", "ACCEPT"],
+        )
     }
 
-    /// Removes the block created by block_client_nat() will fail if not run after that command
-    pub fn restore_client_nat(&self) -> Result<(), Error> {
-        if !self.does_nftables_exist() {
-            self.add_iptables_rule("iptables", &["-D", "zone_lan_forward", "-j", "REJECT"])?;
+    /// Lets the guest network out over wg_exit, the firewall zone for it rejects all other forwarding so
+    /// guests can't reach the lan. The nat rules for wg_exit cover guest traffic already. Safe to call repeatedly
+    pub fn create_guest_forward_rules(&self) -> Result<(), Error> {
+        if self.does_nftables_exist() {
+            self.insert_nft_guest_forward_rule()?;
         } else {
-            self.delete_reject_rule()?;
+            self.add_iptables_rule(
+                "iptables",
+                &["-I", "zone_guest_forward", "-o", "wg_exit", "-j", "ACCEPT"],
+            )?;
         }
         Ok(())
     }
+
+    /// Checks if the kill switch rules from set_client_kill_switch() are in place
+    pub fn is_client_kill_switch_set(&self) -> Result<bool, Error> {
+        if self.does_nftables_exist() {
+            return self.is_nft_kill_switch_rule_present();
+        }
+        for command in ["iptables", "ip6tables"] {
+            if !self.check_iptable_rule(
+                command,
+                &[
+                    "-C",
+                    "zone_lan_forward",
+                    "!",
+                    "-o",
+                    "wg_exit",
+                    "-j",
+                    "REJECT",
+                ],
+            )? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 }
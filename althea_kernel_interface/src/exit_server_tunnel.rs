@@ -1,9 +1,12 @@
 use super::{KernelInterface, KernelInterfaceError};
 use crate::open_tunnel::to_wg_local;
+use crate::rita_owned::RITA_ROUTE_PROTO;
+use crate::wg_netlink::WgPeerInfo;
 use althea_types::WgKey;
 use ipnetwork::IpNetwork;
 use std::collections::HashSet;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, UNIX_EPOCH};
 use KernelInterfaceError as Error;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -15,6 +18,76 @@ pub struct ExitClient {
     pub port: u16,
 }
 
+/// The `wg set` arguments that add or update a single client
+fn client_peer_args(c: &ExitClient) -> Vec<String> {
+    // For the allowed IPs, we appends the clients internal ip as well
+    // as the client ipv6 assigned ip and add this to wireguards allowed ips
+    let mut allowed_ips = c.internal_ip.to_string();
+    if let Some(i_ipv6) = &c.internet_ipv6 {
+        allowed_ips.push(',');
+        allowed_ips.push_str(&i_ipv6.to_string());
+    }
+    vec![
+        "peer".into(),
+        format!("{}", c.public_key),
+        "endpoint".into(),
+        format!("[{}]:{}", c.mesh_ip, c.port),
+        "allowed-ips".into(),
+        allowed_ips,
+    ]
+}
+
+/// Parses the peer lines of `wg show <if> dump`, the first line describes the interface itself
+fn parse_wg_dump(out: &str) -> Result<Vec<WgPeerInfo>, Error> {
+    let mut peers = Vec::new();
+    for line in out.lines().skip(1) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 7 {
+            return Err(Error::ParseError(format!("Invalid wg dump line {line}")));
+        }
+        let endpoint =
+            match fields[2] {
+                "(none)" => None,
+                endpoint => Some(endpoint.parse::<SocketAddr>().map_err(|e| {
+                    Error::ParseError(format!("Invalid wg endpoint {endpoint} {e}"))
+                })?),
+            };
+        let mut allowed_ips = Vec::new();
+        if fields[3] != "(none)" {
+            for ip in fields[3].split(',') {
+                allowed_ips.push(
+                    ip.parse::<IpNetwork>().map_err(|e| {
+                        Error::ParseError(format!("Invalid wg allowed ip {ip} {e}"))
+                    })?,
+                );
+            }
+        }
+        let handshake: u64 = fields[4].parse()?;
+        peers.push(WgPeerInfo {
+            public_key: fields[0].parse()?,
+            endpoint,
+            allowed_ips,
+            last_handshake: if handshake == 0 {
+                None
+            } else {
+                Some(UNIX_EPOCH + Duration::from_secs(handshake))
+            },
+            rx_bytes: fields[5].parse()?,
+            tx_bytes: fields[6].parse()?,
+        });
+    }
+    Ok(peers)
+}
+
+/// Parses the destinations of the host routes in `ip -4 route show dev <if>`, routes to a subnet
+/// are printed with a prefix and are skipped
+fn parse_host_routes(out: &str) -> HashSet<IpAddr> {
+    out.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|dst| dst.parse().ok())
+        .collect()
+}
+
 impl dyn KernelInterface {
     // This function sets up the exit config and returns the updated list of tc filter handles
     pub fn set_exit_wg_config(
@@ -38,23 +111,7 @@ impl dyn KernelInterface {
         let mut client_pubkeys = HashSet::new();
 
         for c in clients.iter() {
-            // For the allowed IPs, we appends the clients internal ip as well
-            // as the client ipv6 assigned ip and add this to wireguards allowed ips
-            // internet_ipv6 is already in the form of "<subnet1>,<subnet2>.."
-            let i_ipv6 = &c.internet_ipv6;
-            let mut allowed_ips = c.internal_ip.to_string().to_owned();
-            if let Some(i_ipv6) = i_ipv6 {
-                allowed_ips.push(',');
-                allowed_ips.push_str(&i_ipv6.to_string());
-            }
-
-            args.push("peer".into());
-            args.push(format!("{}", c.public_key));
-            args.push("endpoint".into());
-            args.push(format!("[{}]:{}", c.mesh_ip, c.port));
-            args.push("allowed-ips".into());
-            args.push(allowed_ips);
-
+            args.extend(client_peer_args(c));
             client_pubkeys.insert(c.public_key);
         }
 
@@ -74,60 +131,133 @@ impl dyn KernelInterface {
         Ok(())
     }
 
-    /// This function adds a route for each client ipv4 subnet to the routing table
-    /// this works on the premise of smallest prefix first routing meaning that we can assign
-    /// ip route 172.168.0.1/16 to wg_exit_v2 and then individually add /32 routes to wg_exit_v1
-    /// and this will produce the same routing outcomes with many less routes than adding individual routes
-    /// on both
-    pub fn setup_individual_client_routes(
+    /// Shell version of update_exit_wg_peers_netlink, adds or updates the given clients and removes
+    /// the given peers in a single `wg set` call
+    pub fn update_exit_wg_peers(
+        &self,
+        upsert: &[ExitClient],
+        remove: &[WgKey],
+        listen_port: u16,
+        private_key_path: &str,
+        if_name: &str,
+    ) -> Result<(), Error> {
+        let mut args = vec![
+            "set".into(),
+            if_name.into(),
+            "listen-port".into(),
+            format!("{listen_port}"),
+            "private-key".into(),
+            private_key_path.to_string(),
+        ];
+        for c in upsert {
+            args.extend(client_peer_args(c));
+        }
+        for key in remove {
+            warn!("Removing no longer authorized peer {}", key);
+            args.extend(["peer".into(), format!("{key}"), "remove".into()]);
+        }
+        let arg_str: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        let output = self.run_command("wg", &arg_str)?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to update peers on {if_name} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads the configured state of every peer on an interface with `wg show <if> dump`
+    pub fn get_wg_peers_dump(&self, if_name: &str) -> Result<Vec<WgPeerInfo>, Error> {
+        let output = self.run_command("wg", &["show", if_name, "dump"])?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to dump peers of {if_name} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        parse_wg_dump(&String::from_utf8(output.stdout)?)
+    }
+
+    /// Lists the client ips with a host route on the given interface. Legacy wg_exit clients each get a
+    /// /32 route, which takes priority over the subnet route on wg_exit_v2 by longest prefix match
+    pub fn get_individual_client_routes(&self, interface: &str) -> Result<HashSet<IpAddr>, Error> {
+        let output = self.run_command(
+            "ip",
+            &[
+                "-4",
+                "route",
+                "show",
+                "dev",
+                interface,
+                "proto",
+                RITA_ROUTE_PROTO,
+            ],
+        )?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to list routes on {interface} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(parse_host_routes(&String::from_utf8(output.stdout)?))
+    }
+
+    /// Routes a single client ip over the given interface, replacing any route to that ip that
+    /// was pointing at another interface
+    pub fn add_individual_client_route(
         &self,
         client_internal_ip: IpAddr,
         exit_internal_v4: IpAddr,
         interface: &str,
-    ) {
-        let mut interface_cloned = interface.to_string();
-        interface_cloned.push(' ');
-        // Setup ipv4 route
-        // 1.) Select all ipv4 routes with 'client_internal_ip'. This gives us all routes with wg_exit and wg_exit_v2 for the ip
-        //     THere should be only one route
-        // 2.) Does the route contain 'interface'? Yes? we continue
-        // 3.) No? That means either no route exists or there is a route with the other interface name
-        // 4.) Delete route, add new route with the correct interface
-        let output = self
-            .run_command("ip", &["route", "show", &client_internal_ip.to_string()])
-            .expect("Fix command");
-        let route = String::from_utf8(output.stdout).unwrap();
-        if !route.contains(&interface_cloned) {
-            self.run_command("ip", &["route", "del", &client_internal_ip.to_string()])
-                .expect("Fix command");
-
-            self.run_command(
-                "ip",
-                &[
-                    "route",
-                    "add",
-                    &client_internal_ip.to_string(),
-                    "dev",
-                    interface,
-                    "src",
-                    &exit_internal_v4.to_string(),
-                ],
-            )
-            .expect("Fix command");
+    ) -> Result<(), Error> {
+        let output = self.run_command(
+            "ip",
+            &[
+                "route",
+                "replace",
+                &client_internal_ip.to_string(),
+                "dev",
+                interface,
+                "src",
+                &exit_internal_v4.to_string(),
+                "proto",
+                RITA_ROUTE_PROTO,
+            ],
+        )?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to route {client_internal_ip} over {interface} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
         }
+        Ok(())
     }
 
-    /// this function performs the teardown step of setup_indvidual_client_routes, when a router upgrades
-    /// to beta20 or later this function checks for and deltes the rules
-    pub fn teardown_individual_client_routes(&self, client_internal_ip: IpAddr) {
-        let output = self
-            .run_command("ip", &["route", "show", &client_internal_ip.to_string()])
-            .expect("Fix command");
-        let route = String::from_utf8(output.stdout).unwrap();
-        if !route.is_empty() {
-            self.run_command("ip", &["route", "del", &client_internal_ip.to_string()])
-                .expect("Fix command");
+    /// Removes a route added by add_individual_client_route, used when a router moves to wg_exit_v2
+    /// or is no longer a client
+    pub fn remove_individual_client_route(
+        &self,
+        client_internal_ip: IpAddr,
+        interface: &str,
+    ) -> Result<(), Error> {
+        let output = self.run_command(
+            "ip",
+            &[
+                "route",
+                "del",
+                &client_internal_ip.to_string(),
+                "dev",
+                interface,
+            ],
+        )?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to remove route to {client_internal_ip} on {interface} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
         }
+        Ok(())
     }
 
     /// Performs the one time startup tasks for the rita_exit clients loop
@@ -138,6 +268,7 @@ impl dyn KernelInterface {
         exit_mesh: IpAddr,
         interface: &str,
         enable_enforcement: bool,
+        mtu: usize,
     ) -> Result<(), Error> {
         if let Some((local_ip_v4, netmask_v4)) = local_v4 {
             // sanity checking
@@ -188,7 +319,10 @@ impl dyn KernelInterface {
             ],
         )?;
 
-        let output = self.run_command("ip", &["link", "set", "dev", interface, "mtu", "1500"])?;
+        let output = self.run_command(
+            "ip",
+            &["link", "set", "dev", interface, "mtu", &mtu.to_string()],
+        )?;
         if !output.stderr.is_empty() {
             return Err(KernelInterfaceError::RuntimeError(format!(
                 "received error adding wg link: {}",
@@ -218,12 +352,14 @@ impl dyn KernelInterface {
         Ok(())
     }
 
-    /// Sets up the natting rules for forwarding ipv4 and ipv6 traffic
+    /// Sets up the natting rules for forwarding ipv4 and ipv6 traffic and clamps the mss of
+    /// forwarded tcp connections to fit inside the tunnel mtu
     pub fn setup_nat(
         &self,
         external_interface: &str,
         interface: &str,
         external_v6: Option<(IpAddr, u8)>,
+        mtu: usize,
     ) -> Result<(), Error> {
         // nat masquerade on exit
         if !self.does_nftables_exist() {
@@ -323,6 +459,8 @@ impl dyn KernelInterface {
             self.insert_nft_exit_forward_rules(interface, external_interface, external_v6)?;
         }
 
+        self.set_mss_clamp(interface, mtu)?;
+
         Ok(())
     }
 }
@@ -344,3 +482,46 @@ fn test_iproute_parsing() {
         }
     }
 }
+
+#[test]
+fn test_parse_wg_dump() {
+    let out = "cGF0aGVyaWNh8J+Yg2RlZmluaXRlbHlub3Rha2V5Cg=\t88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs=\t59998\toff
+88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs=\t(none)\t[fd00::1337]:59999\t172.168.1.5/32,2001:db8:1::/64\t1536936247\t15403040\t11212\toff
+bGkj7Z6bX1593G0pExfzxocWKhS3Un9uifIhZP9c5iM=\t(none)\t(none)\t(none)\t0\t0\t0\toff
+";
+    let peers = parse_wg_dump(out).unwrap();
+    assert_eq!(peers.len(), 2);
+    assert_eq!(
+        peers[0].endpoint,
+        Some("[fd00::1337]:59999".parse().unwrap())
+    );
+    assert_eq!(
+        peers[0].allowed_ips,
+        vec![
+            "172.168.1.5/32".parse::<IpNetwork>().unwrap(),
+            "2001:db8:1::/64".parse::<IpNetwork>().unwrap()
+        ]
+    );
+    assert_eq!(
+        peers[0].last_handshake,
+        Some(UNIX_EPOCH + Duration::from_secs(1_536_936_247))
+    );
+    assert_eq!(peers[1].endpoint, None);
+    assert!(peers[1].allowed_ips.is_empty());
+    assert_eq!(peers[1].last_handshake, None);
+
+    assert!(parse_wg_dump("iface line\nnot a peer line\n").is_err());
+}
+
+#[test]
+fn test_parse_host_routes() {
+    let out = "172.168.1.5 scope link src 172.168.0.1
+172.168.1.9 scope link src 172.168.0.1
+172.168.0.0/16 proto kernel scope link src 172.168.0.1
+";
+    let routes = parse_host_routes(out);
+    assert_eq!(routes.len(), 2);
+    assert!(routes.contains(&"172.168.1.5".parse().unwrap()));
+    assert!(routes.contains(&"172.168.1.9".parse().unwrap()));
+    assert!(parse_host_routes("").is_empty());
+}
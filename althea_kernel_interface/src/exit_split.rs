@@ -0,0 +1,443 @@
+//! Splitting client traffic between two exits. A second exit tunnel, wg_exit_split, is set up next to
+//! wg_exit and new lan flows are hashed onto it with the configured weight, the rest keep leaving through
+//! wg_exit. A flow is marked once in conntrack when it starts so that all of its packets leave through the
+//! same exit, marked packets are looked up in a routing table of their own whose only route is
+//! wg_exit_split. Just ahead of that a rule sends marked packets with a more specific route than the
+//! default in the main table, the lan, the router itself and anything else local, through the main table
+//! as usual. Only ipv4 is split, our ipv6 subnet is assigned by the primary exit and can't leave
+//! through another one.
+
+use super::KernelInterface;
+use crate::rita_owned::{rita_fwmark, RITA_ROUTE_PROTO};
+use crate::KernelInterfaceError as Error;
+use althea_types::WgKey;
+use std::net::{IpAddr, SocketAddr};
+
+/// The second exit tunnel
+pub const SPLIT_EXIT_INTERFACE: &str = "wg_exit_split";
+/// Routing table flows marked for the secondary exit are looked up in
+pub const SPLIT_EXIT_TABLE: &str = "78";
+/// Priority of the policy rule for marked flows, ahead of the main table at 32766
+pub const SPLIT_EXIT_RULE_PRIORITY: &str = "950";
+/// Priority of the rule that keeps local destinations of marked flows on the main table
+pub const SPLIT_EXIT_LOCAL_RULE_PRIORITY: &str = "949";
+/// Index of the split fwmark in the Rita fwmark range
+const SPLIT_EXIT_FWMARK_INDEX: u16 = 1;
+/// Name of the nftables table, or the iptables mangle chain, that marks flows
+const SPLIT_FILTER_NAME: &str = "rita_exit_split";
+const LAN_NIC: &str = "br-lan";
+
+pub fn split_exit_fwmark() -> u32 {
+    rita_fwmark(SPLIT_EXIT_FWMARK_INDEX)
+}
+
+/// Everything needed to tunnel to the secondary exit and send part of the lan traffic through it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitSplitConfig {
+    /// The mesh ip of the secondary exit and its wg port
+    pub endpoint: SocketAddr,
+    pub pubkey: WgKey,
+    pub private_key_path: String,
+    /// Must differ from the wg_exit listen port
+    pub listen_port: u16,
+    /// The internal ip the secondary exit assigned us
+    pub local_ip: IpAddr,
+    pub mtu: usize,
+    pub persistent_keepalive: u16,
+    /// Percent of new flows sent to the secondary exit, 1 to 99
+    pub weight: u8,
+}
+
+/// The nftables rule that marks the share of new lan flows that go to the secondary exit. Flows
+/// are hashed on their addresses and ports so the choice is the same for every packet of a flow
+fn nft_mark_rule(weight: u8, mark: u32) -> Vec<String> {
+    let mark = format!("{mark:#x}");
+    let weight = weight.to_string();
+    [
+        "add",
+        "rule",
+        "inet",
+        SPLIT_FILTER_NAME,
+        "prerouting",
+        "iifname",
+        LAN_NIC,
+        "ct",
+        "state",
+        "new",
+        "jhash",
+        "ip",
+        "saddr",
+        ".",
+        "ip",
+        "daddr",
+        ".",
+        "th",
+        "sport",
+        ".",
+        "th",
+        "dport",
+        "mod",
+        "100",
+        "lt",
+        &weight,
+        "ct",
+        "mark",
+        "set",
+        &mark,
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+/// Probability argument for the iptables statistic match, iptables can't hash flows so each new flow
+/// is picked at random instead, the conntrack mark still keeps the flow on one exit
+fn iptables_probability(weight: u8) -> String {
+    format!("{:.2}", f64::from(weight.min(100)) / 100.0)
+}
+
+impl dyn KernelInterface {
+    /// Sets up wg_exit_split, its routing table and the flow marking, safe to call again with a
+    /// changed config
+    pub fn set_exit_split(&self, config: &ExitSplitConfig) -> Result<(), Error> {
+        match self.create_blank_wg_interface(SPLIT_EXIT_INTERFACE) {
+            Ok(()) | Err(Error::WgExistsError) => {}
+            Err(e) => return Err(e),
+        }
+        self.run_command(
+            "wg",
+            &[
+                "set",
+                SPLIT_EXIT_INTERFACE,
+                "listen-port",
+                &config.listen_port.to_string(),
+                "private-key",
+                &config.private_key_path,
+                "peer",
+                &config.pubkey.to_string(),
+                "endpoint",
+                &format!("[{}]:{}", config.endpoint.ip(), config.endpoint.port()),
+                "allowed-ips",
+                "0.0.0.0/0",
+                "persistent-keepalive",
+                &config.persistent_keepalive.to_string(),
+            ],
+        )?;
+        // the secondary exit can change, only ever talk to the current one
+        for peer in self.get_peers(SPLIT_EXIT_INTERFACE)? {
+            if peer != config.pubkey {
+                self.run_command(
+                    "wg",
+                    &[
+                        "set",
+                        SPLIT_EXIT_INTERFACE,
+                        "peer",
+                        &peer.to_string(),
+                        "remove",
+                    ],
+                )?;
+            }
+        }
+
+        // a /32 so that no connected route is added to the main table, both exits may number us out
+        // of the same subnet
+        self.run_ip(&["address", "flush", "dev", SPLIT_EXIT_INTERFACE])?;
+        self.run_ip(&[
+            "address",
+            "add",
+            &format!("{}/32", config.local_ip),
+            "dev",
+            SPLIT_EXIT_INTERFACE,
+        ])?;
+        self.run_ip(&[
+            "link",
+            "set",
+            "dev",
+            SPLIT_EXIT_INTERFACE,
+            "mtu",
+            &config.mtu.to_string(),
+        ])?;
+        self.run_ip(&["link", "set", "dev", SPLIT_EXIT_INTERFACE, "up"])?;
+        // replies to marked flows arrive here while the main table points at wg_exit
+        self.run_command(
+            "sysctl",
+            &[
+                "-w",
+                &format!("net.ipv4.conf.{SPLIT_EXIT_INTERFACE}.rp_filter=2"),
+            ],
+        )?;
+
+        self.run_ip(&[
+            "route",
+            "replace",
+            "default",
+            "dev",
+            SPLIT_EXIT_INTERFACE,
+            "table",
+            SPLIT_EXIT_TABLE,
+            "proto",
+            RITA_ROUTE_PROTO,
+        ])?;
+        self.remove_split_rule()?;
+        // everything but the default route, which is the only route suppress_prefixlength 0 hides
+        self.run_ip(&[
+            "rule",
+            "add",
+            "lookup",
+            "main",
+            "suppress_prefixlength",
+            "0",
+            "priority",
+            SPLIT_EXIT_LOCAL_RULE_PRIORITY,
+        ])?;
+        self.run_ip(&[
+            "rule",
+            "add",
+            "fwmark",
+            &format!("{:#x}", split_exit_fwmark()),
+            "lookup",
+            SPLIT_EXIT_TABLE,
+            "priority",
+            SPLIT_EXIT_RULE_PRIORITY,
+        ])?;
+
+        if self.does_nftables_exist() {
+            self.set_nft_exit_split(config.weight)?;
+        } else {
+            self.set_iptables_exit_split(config.weight)?;
+        }
+        self.set_mss_clamp(SPLIT_EXIT_INTERFACE, config.mtu)?;
+        Ok(())
+    }
+
+    /// Sends all traffic back through wg_exit and removes wg_exit_split, does nothing if the split
+    /// isn't set up
+    pub fn clear_exit_split(&self) -> Result<(), Error> {
+        if self.does_nftables_exist() {
+            self.run_command("nft", &["delete", "table", "inet", SPLIT_FILTER_NAME])?;
+        } else {
+            self.clear_iptables_exit_split()?;
+        }
+        self.remove_split_rule()?;
+        self.run_command("ip", &["route", "flush", "table", SPLIT_EXIT_TABLE])?;
+        // so that the next split gets a clamp for its own mtu
+        self.clear_mss_clamp(SPLIT_EXIT_INTERFACE)?;
+        if self
+            .get_interfaces()?
+            .iter()
+            .any(|i| i == SPLIT_EXIT_INTERFACE)
+        {
+            self.del_interface(SPLIT_EXIT_INTERFACE)?;
+        }
+        Ok(())
+    }
+
+    /// Each del removes one rule at the priority and fails once there are none left
+    fn remove_split_rule(&self) -> Result<(), Error> {
+        for priority in [SPLIT_EXIT_RULE_PRIORITY, SPLIT_EXIT_LOCAL_RULE_PRIORITY] {
+            while self
+                .run_command("ip", &["rule", "del", "priority", priority])?
+                .status
+                .success()
+            {}
+        }
+        Ok(())
+    }
+
+    fn set_nft_exit_split(&self, weight: u8) -> Result<(), Error> {
+        // the table is rebuilt from scratch so that a weight change replaces the old rule, flows that
+        // are already marked keep their mark in conntrack
+        self.run_command("nft", &["delete", "table", "inet", SPLIT_FILTER_NAME])?;
+        self.run_command("nft", &["add", "table", "inet", SPLIT_FILTER_NAME])?;
+        self.run_command(
+            "nft",
+            &[
+                "add",
+                "chain",
+                "inet",
+                SPLIT_FILTER_NAME,
+                "prerouting",
+                "{",
+                "type",
+                "filter",
+                "hook",
+                "prerouting",
+                "priority",
+                "-150",
+                ";",
+                "}",
+            ],
+        )?;
+        let mark = format!("{:#x}", split_exit_fwmark());
+        let mark_rule = nft_mark_rule(weight, split_exit_fwmark());
+        let mark_rule: Vec<&str> = mark_rule.iter().map(|s| s.as_str()).collect();
+        self.run_command("nft", &mark_rule)?;
+        self.run_command(
+            "nft",
+            &[
+                "add",
+                "rule",
+                "inet",
+                SPLIT_FILTER_NAME,
+                "prerouting",
+                "iifname",
+                LAN_NIC,
+                "ct",
+                "mark",
+                &mark,
+                "meta",
+                "mark",
+                "set",
+                "ct",
+                "mark",
+            ],
+        )?;
+        self.run_command(
+            "nft",
+            &[
+                "add",
+                "chain",
+                "inet",
+                SPLIT_FILTER_NAME,
+                "postrouting",
+                "{",
+                "type",
+                "nat",
+                "hook",
+                "postrouting",
+                "priority",
+                "100",
+                ";",
+                "}",
+            ],
+        )?;
+        self.run_command(
+            "nft",
+            &[
+                "add",
+                "rule",
+                "inet",
+                SPLIT_FILTER_NAME,
+                "postrouting",
+                "oifname",
+                SPLIT_EXIT_INTERFACE,
+                "masquerade",
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn set_iptables_exit_split(&self, weight: u8) -> Result<(), Error> {
+        let mark = format!("{:#x}", split_exit_fwmark());
+        // creating the chain fails if it exists already, it is flushed right after either way
+        self.run_command("iptables", &["-t", "mangle", "-N", SPLIT_FILTER_NAME])?;
+        self.run_command("iptables", &["-t", "mangle", "-F", SPLIT_FILTER_NAME])?;
+        self.run_command(
+            "iptables",
+            &[
+                "-t",
+                "mangle",
+                "-A",
+                SPLIT_FILTER_NAME,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "NEW",
+                "-m",
+                "statistic",
+                "--mode",
+                "random",
+                "--probability",
+                &iptables_probability(weight),
+                "-j",
+                "CONNMARK",
+                "--set-mark",
+                &mark,
+            ],
+        )?;
+        self.run_command(
+            "iptables",
+            &[
+                "-t",
+                "mangle",
+                "-A",
+                SPLIT_FILTER_NAME,
+                "-j",
+                "CONNMARK",
+                "--restore-mark",
+            ],
+        )?;
+        self.add_iptables_rule(
+            "iptables",
+            &[
+                "-t",
+                "mangle",
+                "-A",
+                "PREROUTING",
+                "-i",
+                LAN_NIC,
+                "-j",
+                SPLIT_FILTER_NAME,
+            ],
+        )?;
+        self.add_iptables_rule(
+            "iptables",
+            &[
+                "-t",
+                "nat",
+                "-A",
+                "POSTROUTING",
+                "-o",
+                SPLIT_EXIT_INTERFACE,
+                "-j",
+                "MASQUERADE",
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn clear_iptables_exit_split(&self) -> Result<(), Error> {
+        self.run_command(
+            "iptables",
+            &[
+                "-t",
+                "mangle",
+                "-D",
+                "PREROUTING",
+                "-i",
+                LAN_NIC,
+                "-j",
+                SPLIT_FILTER_NAME,
+            ],
+        )?;
+        self.run_command("iptables", &["-t", "mangle", "-F", SPLIT_FILTER_NAME])?;
+        self.run_command("iptables", &["-t", "mangle", "-X", SPLIT_FILTER_NAME])?;
+        self.run_command(
+            "iptables",
+            &[
+                "-t",
+                "nat",
+                "-D",
+                "POSTROUTING",
+                "-o",
+                SPLIT_EXIT_INTERFACE,
+                "-j",
+                "MASQUERADE",
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_split_rules() {
+    let rule = nft_mark_rule(30, split_exit_fwmark()).join(" ");
+    assert_eq!(
+        rule,
+        "add rule inet rita_exit_split prerouting iifname br-lan ct state new jhash ip saddr . \
+         ip daddr . th sport . th dport mod 100 lt 30 ct mark set 0x52490001"
+    );
+    assert_eq!(iptables_probability(30), "0.30");
+    assert_eq!(iptables_probability(5), "0.05");
+    assert_eq!(iptables_probability(200), "1.00");
+}
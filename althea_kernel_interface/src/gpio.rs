@@ -0,0 +1,38 @@
+use super::KernelInterface;
+use crate::KernelInterfaceError as Error;
+use std::fs;
+
+/// Parses the contents of a sysfs gpio value file
+fn parse_gpio_value(gpio: u32, contents: &str) -> Result<bool, Error> {
+    match contents.trim() {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        other => Err(Error::RuntimeError(format!(
+            "unexpected value {other} for gpio {gpio}"
+        ))),
+    }
+}
+
+impl dyn KernelInterface {
+    /// Reads the level of a gpio exported through sysfs, true if the line is high. Read directly rather
+    /// than through a command since buttons are polled several times a second
+    pub fn get_gpio_value(&self, gpio: u32) -> Result<bool, Error> {
+        let path = format!("/sys/class/gpio/gpio{gpio}/value");
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| Error::RuntimeError(format!("received error reading gpio {gpio}: {e}")))?;
+        parse_gpio_value(gpio, &contents)
+    }
+
+    /// True if a button on this gpio is being held down, buttons that pull the line low when
+    /// pressed are active low
+    pub fn is_gpio_button_pressed(&self, gpio: u32, active_low: bool) -> Result<bool, Error> {
+        Ok(self.get_gpio_value(gpio)? != active_low)
+    }
+}
+
+#[test]
+fn test_parse_gpio_value() {
+    assert!(!parse_gpio_value(17, "0\n").unwrap());
+    assert!(parse_gpio_value(17, "1\n").unwrap());
+    assert!(parse_gpio_value(17, "high\n").is_err());
+}
@@ -6,8 +6,12 @@ use althea_types::extract_wifi_survey_data;
 use althea_types::ConntrackInfo;
 use althea_types::EthOperationMode;
 use althea_types::EthernetStats;
+use althea_types::FlashWear;
 use althea_types::HardwareInfo;
+use althea_types::HardwareTelemetry;
+use althea_types::ResourceUsage;
 use althea_types::SensorReading;
+use althea_types::StorageUsage;
 use althea_types::WifiDevice;
 use althea_types::WifiStationData;
 use althea_types::WifiSurveyData;
@@ -75,9 +79,186 @@ pub fn get_hardware_info(device_name: Option<String>) -> Result<HardwareInfo, Er
         wifi_devices,
         extender_list,
         conntrack: conntrack_info,
+        // This is populated by the caller if the user allows it
+        telemetry: None,
     })
 }
 
+/// Gathers the extended hardware telemetry operators use to plan maintenance, every field is best
+/// effort since what is available varies a lot between devices
+pub fn get_hardware_telemetry() -> HardwareTelemetry {
+    let storage = match KI.run_command("df", &["-k"]) {
+        Ok(output) => parse_df_output(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            error!("Unable to get storage usage {:?}", e);
+            Vec::new()
+        }
+    };
+
+    HardwareTelemetry {
+        board_model: get_board_model(),
+        available_memory: get_available_memory(),
+        storage,
+        flash_wear: get_flash_wear(),
+    }
+}
+
+/// Gathers load, memory, uptime, temperature and flash space for own_info, cheap enough to run on
+/// every dashboard request
+pub fn get_resource_usage() -> ResourceUsage {
+    let load_avg = get_load_avg().ok();
+    let flash = match KI.run_command("df", &["-k"]) {
+        Ok(output) => {
+            persistent_storage(&parse_df_output(&String::from_utf8_lossy(&output.stdout)))
+        }
+        Err(e) => {
+            warn!("Unable to get flash usage {:?}", e);
+            None
+        }
+    };
+
+    ResourceUsage {
+        load_avg_one_minute: load_avg.map(|l| l.0),
+        load_avg_five_minute: load_avg.map(|l| l.1),
+        load_avg_fifteen_minute: load_avg.map(|l| l.2),
+        logical_processors: get_numcpus().ok(),
+        memory_total: get_memory_info().ok().map(|(total, _)| total),
+        memory_available: get_available_memory(),
+        uptime_secs: get_sys_uptime().ok().map(|uptime| uptime.as_secs()),
+        temperature: get_max_temperature(),
+        flash_total: flash.as_ref().map(|f| f.total),
+        flash_free: flash.map(|f| f.total.saturating_sub(f.used)),
+    }
+}
+
+/// The filesystem settings and data are written to, /overlay on OpenWrt and / everywhere else
+fn persistent_storage(storage: &[StorageUsage]) -> Option<StorageUsage> {
+    storage
+        .iter()
+        .find(|s| s.mount_point == "/overlay")
+        .or_else(|| storage.iter().find(|s| s.mount_point == "/"))
+        .cloned()
+}
+
+/// The hottest reading of any hwmon sensor or, on devices without any, of any thermal zone
+fn get_max_temperature() -> Option<u64> {
+    if let Some(readings) = get_sensor_readings() {
+        return readings.iter().map(|r| r.reading).max();
+    }
+    let mut zone_num = 0;
+    let mut max = None;
+    let mut path = format!("/sys/class/thermal/thermal_zone{zone_num}");
+    while fs::metadata(path.clone()).is_ok() {
+        // some zones report a negative or zero temperature when the sensor isn't connected
+        if let Some(temp) = maybe_get_single_line_u64(&format!("{path}/temp")).filter(|t| *t > 0) {
+            max = max.max(Some(temp));
+        }
+        zone_num += 1;
+        path = format!("/sys/class/thermal/thermal_zone{zone_num}");
+    }
+    max
+}
+
+/// OpenWrt writes the board model to /tmp/sysinfo/model, otherwise we try the device tree,
+/// which contains a null terminated string
+fn get_board_model() -> Option<String> {
+    maybe_get_single_line_string("/tmp/sysinfo/model")
+        .or_else(|| maybe_get_single_line_string("/proc/device-tree/model"))
+        .map(|model| model.trim_end_matches('\0').trim().to_string())
+        .filter(|model| !model.is_empty())
+}
+
+fn get_available_memory() -> Option<u64> {
+    let lines = get_lines("/proc/meminfo").ok()?;
+    for line in lines {
+        if line.starts_with("MemAvailable:") {
+            return line.split_whitespace().nth(1)?.parse().ok();
+        }
+    }
+    None
+}
+
+/// Parses the output of 'df -k' keeping only persistent filesystems, on OpenWrt the writable
+/// storage is mounted at /overlay while x86 devices use the root filesystem directly
+fn parse_df_output(output: &str) -> Vec<StorageUsage> {
+    const MOUNT_POINTS: [&str; 2] = ["/", "/overlay"];
+    let mut ret = Vec::new();
+    // skip the header line
+    for line in output.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || !MOUNT_POINTS.contains(&fields[5]) {
+            continue;
+        }
+        if let (Ok(total), Ok(used)) = (fields[1].parse(), fields[2].parse()) {
+            ret.push(StorageUsage {
+                mount_point: fields[5].to_string(),
+                total,
+                used,
+            });
+        }
+    }
+    ret
+}
+
+/// Parses a hex value such as 0x01 as found in the eMMC sysfs files
+fn parse_emmc_hex(val: &str) -> Option<u8> {
+    u8::from_str_radix(val.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// Parses the eMMC life_time sysfs file which contains the type A and type B estimates
+/// as two space separated hex values
+fn parse_emmc_life_time(line: &str) -> Option<(u8, u8)> {
+    let mut vals = line.split_whitespace();
+    match (
+        vals.next().and_then(parse_emmc_hex),
+        vals.next().and_then(parse_emmc_hex),
+    ) {
+        (Some(a), Some(b)) => Some((a, b)),
+        _ => None,
+    }
+}
+
+fn get_flash_wear() -> Vec<FlashWear> {
+    let mut ret = Vec::new();
+
+    // eMMC devices, zero indexed with no gaps like sensors
+    let mut mmc_num = 0;
+    let mut path = format!("/sys/block/mmcblk{mmc_num}/device");
+    while fs::metadata(path.clone()).is_ok() {
+        let life_time_estimate = maybe_get_single_line_string(&format!("{path}/life_time"))
+            .and_then(|line| parse_emmc_life_time(&line));
+        let pre_eol_info = maybe_get_single_line_string(&format!("{path}/pre_eol_info"))
+            .and_then(|line| parse_emmc_hex(&line));
+        if life_time_estimate.is_some() || pre_eol_info.is_some() {
+            ret.push(FlashWear {
+                device: format!("mmcblk{mmc_num}"),
+                life_time_estimate,
+                pre_eol_info,
+                max_erase_count: None,
+                bad_block_count: None,
+            });
+        }
+        mmc_num += 1;
+        path = format!("/sys/block/mmcblk{mmc_num}/device");
+    }
+
+    // raw nand under UBI
+    let mut ubi_num = 0;
+    let mut path = format!("/sys/class/ubi/ubi{ubi_num}");
+    while fs::metadata(path.clone()).is_ok() {
+        ret.push(FlashWear {
+            device: format!("ubi{ubi_num}"),
+            life_time_estimate: None,
+            pre_eol_info: None,
+            max_erase_count: maybe_get_single_line_u64(&format!("{path}/max_ec")),
+            bad_block_count: maybe_get_single_line_u64(&format!("{path}/bad_peb_count")),
+        });
+        ubi_num += 1;
+        path = format!("/sys/class/ubi/ubi{ubi_num}");
+    }
+    ret
+}
+
 pub fn get_kernel_version() -> Result<String, Error> {
     let sys_kernel_ver_error = Err(Error::FailedToGetSystemKernelVersion);
 
@@ -573,6 +754,54 @@ mod test {
         assert_eq!(hw_info.model, "test");
     }
 
+    #[test]
+    fn test_parse_df_output() {
+        let output = "Filesystem           1K-blocks      Used Available Use% Mounted on
+/dev/root                 4352      4352         0 100% /rom
+tmpfs                    61032      1112     59920   2% /tmp
+/dev/ubi0_1              86760      1464     80736   2% /overlay
+overlayfs:/overlay       86760      1464     80736   2% /
+";
+        let res = parse_df_output(output);
+        assert_eq!(
+            res,
+            vec![
+                StorageUsage {
+                    mount_point: "/overlay".to_string(),
+                    total: 86760,
+                    used: 1464,
+                },
+                StorageUsage {
+                    mount_point: "/".to_string(),
+                    total: 86760,
+                    used: 1464,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_persistent_storage() {
+        let usage = |mount_point: &str, total| StorageUsage {
+            mount_point: mount_point.to_string(),
+            total,
+            used: 1000,
+        };
+        let openwrt = vec![usage("/overlay", 86760), usage("/", 90000)];
+        assert_eq!(persistent_storage(&openwrt).unwrap().total, 86760);
+        let x86 = vec![usage("/", 90000)];
+        assert_eq!(persistent_storage(&x86).unwrap().total, 90000);
+        assert!(persistent_storage(&[]).is_none());
+    }
+
+    #[test]
+    fn test_parse_emmc_life_time() {
+        assert_eq!(parse_emmc_life_time("0x01 0x02"), Some((1, 2)));
+        assert_eq!(parse_emmc_life_time("0x0b 0x0B"), Some((11, 11)));
+        assert_eq!(parse_emmc_life_time("0x01"), None);
+        assert_eq!(parse_emmc_hex("0x03"), Some(3));
+    }
+
     #[test]
     fn test_numcpus() {
         let res = get_numcpus();
@@ -7,6 +7,7 @@ use std::fs::read_dir;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::net::SocketAddr;
 use std::str::from_utf8;
 
 /// Utility function for get_per_interface_usage that makes options ? compatible
@@ -136,6 +137,15 @@ impl dyn KernelInterface {
         }
     }
 
+    /// The endpoint wireguard is currently sending to for the single peer of a tunnel, this is
+    /// where the peer's packets last came from so it shows the peer's address after any nat. None
+    /// until the peer has been heard from
+    pub fn get_wg_endpoint(&self, name: &str) -> Result<Option<SocketAddr>, Error> {
+        let output = self.run_command("wg", &["show", name, "endpoints"])?;
+        let stdout = String::from_utf8(output.stdout)?;
+        Ok(stdout.lines().next().and_then(parse_wg_endpoint))
+    }
+
     /// Gets all the IPv4 addresses from an interface and returns the address and it's netmask
     /// as a tuple.
     pub fn get_ip_from_iface(&self, name: &str) -> Result<Vec<(Ipv4Addr, u8)>, Error> {
@@ -299,6 +309,36 @@ impl dyn KernelInterface {
     }
 }
 
+/// Parses a line of `wg show <iface> endpoints`, the scope of link local addresses is dropped
+fn parse_wg_endpoint(line: &str) -> Option<SocketAddr> {
+    let endpoint = line.split_whitespace().nth(1)?;
+    let endpoint = match (endpoint.find('%'), endpoint.find(']')) {
+        (Some(scope), Some(end)) if scope < end => {
+            format!("{}{}", &endpoint[..scope], &endpoint[end..])
+        }
+        _ => endpoint.to_string(),
+    };
+    endpoint.parse().ok()
+}
+
+#[test]
+fn test_parse_wg_endpoint() {
+    assert_eq!(
+        parse_wg_endpoint("fvLYbeMV+RYbzJEc4lNEPuK8ulva/5wcSJBz0W5t3hM=\t71.8.186.226:60000"),
+        Some("71.8.186.226:60000".parse().unwrap())
+    );
+    assert_eq!(
+        parse_wg_endpoint(
+            "v5yFYZVfl98N/LRVDK3hbyt5/dK/00VnEGHRBikHHXs=\t[fe80::78e4:1cff:fe61:560d%veth-1-6]:60001"
+        ),
+        Some("[fe80::78e4:1cff:fe61:560d]:60001".parse().unwrap())
+    );
+    assert_eq!(
+        parse_wg_endpoint("v5yFYZVfl98N/LRVDK3hbyt5/dK/00VnEGHRBikHHXs=\t(none)"),
+        None
+    );
+}
+
 #[test]
 fn test_get_wg_remote_ip() {
     use crate::KI;
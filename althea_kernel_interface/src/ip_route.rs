@@ -1,3 +1,4 @@
+use crate::rita_owned::RITA_ROUTE_PROTO;
 use crate::KernelInterface;
 use crate::KernelInterfaceError as Error;
 use althea_types::FromStr;
@@ -197,7 +198,27 @@ impl dyn KernelInterface {
     /// Gets the default route, returns Error if the command fails and None
     /// if no default route is set
     pub fn get_default_route(&self) -> Result<Option<DefaultRoute>, Error> {
-        let output = self.run_command("ip", &["route", "list", "default"])?;
+        self.get_first_default_route(&["route", "list", "default"])
+    }
+
+    /// Gets the ipv6 default route, like get_default_route()
+    pub fn get_default_route_v6(&self) -> Result<Option<DefaultRoute>, Error> {
+        self.get_first_default_route(&["-6", "route", "list", "default"])
+    }
+
+    /// Gets the default route in a routing table other than main, like get_default_route()
+    pub fn get_table_default_route(
+        &self,
+        table: u32,
+        ipv6: bool,
+    ) -> Result<Option<DefaultRoute>, Error> {
+        let table = table.to_string();
+        let family = if ipv6 { "-6" } else { "-4" };
+        self.get_first_default_route(&[family, "route", "list", "default", "table", &table])
+    }
+
+    fn get_first_default_route(&self, args: &[&str]) -> Result<Option<DefaultRoute>, Error> {
+        let output = self.run_command("ip", args)?;
 
         let stdout = String::from_utf8(output.stdout).unwrap();
         // return the first valid default route that correctly parses into a route
@@ -225,6 +246,49 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    /// Adds a route to dst, a subnet in cidr notation, or updates the existing one with the same metric.
+    /// At least one of via and nic must be set
+    pub fn replace_route(
+        &self,
+        dst: &str,
+        via: Option<IpAddr>,
+        nic: Option<&str>,
+        metric: Option<u32>,
+    ) -> Result<(), Error> {
+        let via = via.map(|via| via.to_string());
+        let metric = metric.map(|metric| metric.to_string());
+        let mut args = vec!["route", "replace", dst];
+        if let Some(via) = &via {
+            args.extend(["via", via.as_str()]);
+        }
+        if let Some(nic) = nic {
+            args.extend(["dev", nic]);
+        }
+        args.extend(["proto", RITA_ROUTE_PROTO]);
+        if let Some(metric) = &metric {
+            args.extend(["metric", metric.as_str()]);
+        }
+        let output = self.run_command("ip", &args)?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to set route to {dst} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Removes a route added by replace_route
+    pub fn delete_route(&self, dst: &str, metric: Option<u32>) -> Result<(), Error> {
+        let metric = metric.map(|metric| metric.to_string());
+        let mut args = vec!["route", "del", dst, "proto", RITA_ROUTE_PROTO];
+        if let Some(metric) = &metric {
+            args.extend(["metric", metric.as_str()]);
+        }
+        self.run_command("ip", &args)?;
+        Ok(())
+    }
+
     /// Updates the settings default route, returns true if an edit to the settings has been performed
     pub fn update_settings_route(
         &self,
@@ -258,7 +322,7 @@ impl dyn KernelInterface {
                     subnet: 32,
                     via: Some(d.via),
                     nic: d.nic.to_string(),
-                    proto: Some("static".to_string()),
+                    proto: Some(RITA_ROUTE_PROTO.to_string()),
                     metric: None,
                     src: None,
                     scope: None,
@@ -25,15 +25,19 @@ use std::str;
 mod babel;
 pub mod bridge_tools;
 mod check_cron;
+pub mod client_isolation;
 mod counter;
 mod create_wg_key;
 mod delete_tunnel;
+pub mod dhcp;
 mod dns;
 pub mod exit_client_tunnel;
 mod exit_server_tunnel;
+pub mod exit_split;
 pub mod file_io;
 mod fs_sync;
 mod get_neighbors;
+mod gpio;
 pub mod hardware_info;
 mod interface_tools;
 mod ip_addr;
@@ -43,19 +47,25 @@ mod iptables;
 mod is_openwrt;
 mod link_local_tools;
 mod manipulate_uci;
+pub mod mtu;
 mod netfilter;
 pub mod netns;
+mod nft_enforcement;
 pub mod open_tunnel;
 mod openwrt_ubus;
 pub mod opkg_feeds;
 mod ping_check;
+pub mod rita_owned;
 mod set_system_password;
 mod setup_wg_if;
+pub mod storage_type;
+mod sysctl;
 pub mod time;
 mod traffic_control;
 mod udp_socket_table;
 pub mod upgrade;
 pub mod wg_iface_counter;
+pub mod wg_netlink;
 
 use althea_types::error::AltheaTypesError;
 use oping::PingError;
@@ -66,6 +76,7 @@ pub use crate::exit_server_tunnel::ExitClient;
 pub use crate::ip_route::DefaultRoute;
 pub use crate::ip_route::IpRoute;
 pub use crate::ip_route::ToSubnet;
+pub use crate::storage_type::StorageType;
 
 use std::fmt::Result as FormatResult;
 use std::io::Error as IoError;
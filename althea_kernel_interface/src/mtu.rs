@@ -0,0 +1,353 @@
+//! Tunnel MTU management. Traffic through wg_exit is encapsulated again on every hop, so a client behind
+//! radios with a smaller MTU than the rest of the path will see large packets silently dropped. These
+//! functions set explicit TCP MSS clamps derived from the configured tunnel mtu and probe a tunnel for
+//! the blackholes that result when that mtu is too large.
+
+use crate::KernelInterface;
+use crate::KernelInterfaceError as Error;
+use std::net::IpAddr;
+
+/// ipv4 header (20) plus tcp header (20)
+const IPV4_TCP_OVERHEAD: usize = 40;
+/// ipv6 header (40) plus tcp header (20)
+const IPV6_TCP_OVERHEAD: usize = 60;
+/// ipv4 header (20) plus icmp header (8)
+const IPV4_ICMP_OVERHEAD: usize = 28;
+/// ipv6 header (40) plus icmpv6 header (8)
+const IPV6_ICMP_OVERHEAD: usize = 48;
+/// The smallest mtu any ipv6 link may have, also a sane floor for probing ipv4
+pub const MINIMUM_PROBE_MTU: usize = 1280;
+/// Table we create for mss clamps on nftables systems that are not running fw4
+const RITA_MSS_TABLE: &str = "rita_mss";
+/// One rule per address family and direction
+const CLAMP_RULES_PER_INTERFACE: usize = 4;
+
+/// An mss clamp rule found in a firewall listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ClampRule {
+    /// nft rule handle, or the iptables arguments that delete the rule
+    id: Vec<String>,
+    ipv6: bool,
+    mss: usize,
+}
+
+/// Result of probing a tunnel for an mtu blackhole
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MtuProbeResult {
+    /// Packets of the full tunnel mtu make it through
+    Ok { mtu: usize },
+    /// Small packets make it through but full sized ones do not, largest_working is the
+    /// biggest packet that was seen to pass and is a safe value for the tunnel mtu
+    Blackhole { mtu: usize, largest_working: usize },
+    /// Not even minimum sized packets make it through, the tunnel is down rather than misconfigured
+    Unreachable,
+}
+
+/// The TCP MSS that fits inside a packet of the given mtu
+pub fn mss_for_mtu(mtu: usize, ipv6: bool) -> usize {
+    let overhead = if ipv6 {
+        IPV6_TCP_OVERHEAD
+    } else {
+        IPV4_TCP_OVERHEAD
+    };
+    mtu.saturating_sub(overhead)
+}
+
+/// The icmp echo payload size that produces a packet of exactly the given mtu
+fn ping_payload_for_mtu(mtu: usize, ipv6: bool) -> usize {
+    let overhead = if ipv6 {
+        IPV6_ICMP_OVERHEAD
+    } else {
+        IPV4_ICMP_OVERHEAD
+    };
+    mtu.saturating_sub(overhead)
+}
+
+/// The mss clamp rules for an interface in `nft -a list chain` output, identified by handle
+fn nft_clamp_rules(listing: &str, interface: &str) -> Vec<ClampRule> {
+    let iif = format!("iifname \"{interface}\"");
+    let oif = format!("oifname \"{interface}\"");
+    listing
+        .lines()
+        .filter(|line| (line.contains(&iif) || line.contains(&oif)) && line.contains("maxseg"))
+        .filter_map(|line| {
+            let (rule, handle) = line.split_once("# handle ")?;
+            let mss = rule.split("size set ").nth(1)?.trim().parse().ok()?;
+            Some(ClampRule {
+                id: vec![handle.trim().to_string()],
+                ipv6: rule.contains("nfproto ipv6"),
+                mss,
+            })
+        })
+        .collect()
+}
+
+/// The mss clamp rules for an interface in `iptables -S` output, identified by the arguments
+/// that delete them
+fn iptables_clamp_rules(listing: &str, interface: &str, ipv6: bool) -> Vec<ClampRule> {
+    listing
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .filter(|rule| {
+            rule.first() == Some(&"-A")
+                && rule.contains(&"TCPMSS")
+                && rule
+                    .windows(2)
+                    .any(|w| (w[0] == "-o" || w[0] == "-i") && w[1] == interface)
+        })
+        .filter_map(|rule| {
+            let mss = rule
+                .windows(2)
+                .find(|w| w[0] == "--set-mss")
+                .and_then(|w| w[1].parse().ok())?;
+            let mut id = vec!["-t".to_string(), "mangle".to_string(), "-D".to_string()];
+            id.extend(rule[1..].iter().map(|s| s.to_string()));
+            Some(ClampRule { id, ipv6, mss })
+        })
+        .collect()
+}
+
+/// True if the rules are exactly the expected number of clamps, all at the mss for this mtu
+fn clamps_current(rules: &[ClampRule], mtu: usize, expected_rules: usize) -> bool {
+    rules.len() == expected_rules
+        && rules
+            .iter()
+            .all(|rule| rule.mss == mss_for_mtu(mtu, rule.ipv6))
+}
+
+impl dyn KernelInterface {
+    /// Clamps the MSS of tcp connections forwarded in or out of the given interface to fit inside
+    /// the provided mtu. Unlike --clamp-mss-to-pmtu this does not depend on path mtu discovery working
+    /// which is exactly what fails in a blackhole. Clamps left over from a different mtu are replaced
+    pub fn set_mss_clamp(&self, interface: &str, mtu: usize) -> Result<(), Error> {
+        if self.does_nftables_exist() {
+            return self.set_nft_mss_clamp(interface, mtu);
+        }
+        for (command, ipv6) in [("iptables", false), ("ip6tables", true)] {
+            let rules = self.iptables_clamps(command, interface, ipv6)?;
+            if clamps_current(&rules, mtu, 2) {
+                continue;
+            }
+            self.delete_clamps(command, &rules)?;
+            let mss = mss_for_mtu(mtu, ipv6).to_string();
+            for direction in ["-o", "-i"] {
+                self.add_iptables_rule(
+                    command,
+                    &[
+                        "-t",
+                        "mangle",
+                        "-I",
+                        "FORWARD",
+                        direction,
+                        interface,
+                        "-p",
+                        "tcp",
+                        "--tcp-flags",
+                        "SYN,RST",
+                        "SYN",
+                        "-j",
+                        "TCPMSS",
+                        "--set-mss",
+                        &mss,
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the mss clamp rules set_mss_clamp added for an interface
+    pub fn clear_mss_clamp(&self, interface: &str) -> Result<(), Error> {
+        if self.does_nftables_exist() {
+            let (table, chain) = self.nft_clamp_chain()?;
+            let rules = self.nft_clamps(table, chain, interface)?;
+            return self.delete_nft_clamps(table, chain, &rules);
+        }
+        for (command, ipv6) in [("iptables", false), ("ip6tables", true)] {
+            let rules = self.iptables_clamps(command, interface, ipv6)?;
+            self.delete_clamps(command, &rules)?;
+        }
+        Ok(())
+    }
+
+    fn iptables_clamps(
+        &self,
+        command: &str,
+        interface: &str,
+        ipv6: bool,
+    ) -> Result<Vec<ClampRule>, Error> {
+        let out = self.run_command(command, &["-t", "mangle", "-S", "FORWARD"])?;
+        let listing = String::from_utf8(out.stdout)?;
+        Ok(iptables_clamp_rules(&listing, interface, ipv6))
+    }
+
+    fn delete_clamps(&self, command: &str, rules: &[ClampRule]) -> Result<(), Error> {
+        for rule in rules {
+            let deletion: Vec<&str> = rule.id.iter().map(|s| s.as_str()).collect();
+            self.run_command(command, &deletion)?;
+        }
+        Ok(())
+    }
+
+    /// The nft table and chain mss clamps go in. OpenWrt's fw4 provides a mangle_forward chain,
+    /// anywhere else we create our own table hooked at mangle priority
+    fn nft_clamp_chain(&self) -> Result<(&'static str, &'static str), Error> {
+        let out = self.run_command("nft", &["list", "tables"])?;
+        let tables = String::from_utf8(out.stdout)?;
+        if tables.lines().any(|line| line.trim() == "table inet fw4") {
+            return Ok(("fw4", "mangle_forward"));
+        }
+        // both of these are no-ops when the table and chain already exist
+        self.run_command("nft", &["add", "table", "inet", RITA_MSS_TABLE])?;
+        self.run_command(
+            "nft",
+            &[
+                "add",
+                "chain",
+                "inet",
+                RITA_MSS_TABLE,
+                "forward",
+                "{ type filter hook forward priority mangle; }",
+            ],
+        )?;
+        Ok((RITA_MSS_TABLE, "forward"))
+    }
+
+    fn nft_clamps(
+        &self,
+        table: &str,
+        chain: &str,
+        interface: &str,
+    ) -> Result<Vec<ClampRule>, Error> {
+        let out = self.run_command("nft", &["-a", "list", "chain", "inet", table, chain])?;
+        let listing = String::from_utf8(out.stdout)?;
+        Ok(nft_clamp_rules(&listing, interface))
+    }
+
+    fn delete_nft_clamps(
+        &self,
+        table: &str,
+        chain: &str,
+        rules: &[ClampRule],
+    ) -> Result<(), Error> {
+        for rule in rules {
+            for handle in &rule.id {
+                self.run_command(
+                    "nft",
+                    &["delete", "rule", "inet", table, chain, "handle", handle],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_nft_mss_clamp(&self, interface: &str, mtu: usize) -> Result<(), Error> {
+        let (table, chain) = self.nft_clamp_chain()?;
+        let rules = self.nft_clamps(table, chain, interface)?;
+        if clamps_current(&rules, mtu, CLAMP_RULES_PER_INTERFACE) {
+            return Ok(());
+        }
+        self.delete_nft_clamps(table, chain, &rules)?;
+        for (family, ipv6) in [("ipv4", false), ("ipv6", true)] {
+            let mss = mss_for_mtu(mtu, ipv6).to_string();
+            for direction in ["oifname", "iifname"] {
+                self.run_command(
+                    "nft",
+                    &[
+                        "add", "rule", "inet", table, chain, "meta", "nfproto", family, direction,
+                        interface, "tcp", "flags", "syn", "tcp", "option", "maxseg", "size", "set",
+                        &mss,
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a single don't fragment ping of exactly mtu bytes to target over interface
+    fn ping_at_mtu(&self, target: IpAddr, interface: &str, mtu: usize) -> Result<bool, Error> {
+        let payload = ping_payload_for_mtu(mtu, target.is_ipv6()).to_string();
+        let target = target.to_string();
+        let output = self.run_command(
+            "ping",
+            &[
+                "-M", "do", "-c", "1", "-W", "1", "-s", &payload, "-I", interface, &target,
+            ],
+        )?;
+        Ok(output.status.success())
+    }
+
+    /// Probes a tunnel for an mtu blackhole by sending don't fragment pings of the full mtu to a host
+    /// on the other end. If those fail a binary search finds the largest size that does work so that
+    /// the user can be told what to set the tunnel mtu to. Requires a ping that supports -M (iputils)
+    pub fn check_mtu_blackhole(
+        &self,
+        target: IpAddr,
+        interface: &str,
+        mtu: usize,
+    ) -> Result<MtuProbeResult, Error> {
+        if self.ping_at_mtu(target, interface, mtu)? {
+            return Ok(MtuProbeResult::Ok { mtu });
+        }
+        if mtu <= MINIMUM_PROBE_MTU || !self.ping_at_mtu(target, interface, MINIMUM_PROBE_MTU)? {
+            return Ok(MtuProbeResult::Unreachable);
+        }
+
+        // invariant: low works, high does not
+        let mut low = MINIMUM_PROBE_MTU;
+        let mut high = mtu;
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if self.ping_at_mtu(target, interface, mid)? {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(MtuProbeResult::Blackhole {
+            mtu,
+            largest_working: low,
+        })
+    }
+}
+
+#[test]
+fn test_mss_for_mtu() {
+    assert_eq!(mss_for_mtu(1340, false), 1300);
+    assert_eq!(mss_for_mtu(1340, true), 1280);
+    assert_eq!(mss_for_mtu(1500, false), 1460);
+    assert_eq!(mss_for_mtu(20, true), 0);
+    assert_eq!(ping_payload_for_mtu(1500, false), 1472);
+    assert_eq!(ping_payload_for_mtu(1280, true), 1232);
+}
+
+#[test]
+fn test_clamp_rules() {
+    let nft = "table inet fw4 {\n\tchain mangle_forward { # handle 40\n\
+        \t\tmeta nfproto ipv4 oifname \"wg_exit\" tcp flags syn tcp option maxseg size set 1380 # handle 51\n\
+        \t\tmeta nfproto ipv4 oifname \"wg_exit_split\" tcp flags syn tcp option maxseg size set 1340 # handle 52\n\
+        \t\tmeta nfproto ipv4 iifname \"wg_exit_split\" tcp flags syn tcp option maxseg size set 1340 # handle 53\n\
+        \t}\n}\n";
+    let split = nft_clamp_rules(nft, "wg_exit_split");
+    assert_eq!(split.len(), 2);
+    assert_eq!(split[0].id, vec!["52"]);
+    assert_eq!(split[1].id, vec!["53"]);
+    assert_eq!(nft_clamp_rules(nft, "wg_exit").len(), 1);
+    // only the ipv4 clamps are present so this interface still needs its ipv6 ones
+    assert!(!clamps_current(&split, 1380, CLAMP_RULES_PER_INTERFACE));
+
+    let iptables = "-P FORWARD ACCEPT\n\
+        -A FORWARD -o wg_exit_split -p tcp -m tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1340\n\
+        -A FORWARD -i wg_exit_split -p tcp -m tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1340\n\
+        -A FORWARD -i wg_exit -p tcp -m tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1380\n";
+    let rules = iptables_clamp_rules(iptables, "wg_exit_split", false);
+    assert_eq!(rules.len(), 2);
+    assert_eq!(
+        rules[0].id.join(" "),
+        "-t mangle -D FORWARD -o wg_exit_split -p tcp -m tcp --tcp-flags SYN,RST SYN -j TCPMSS \
+         --set-mss 1340"
+    );
+    assert!(clamps_current(&rules, 1380, 2));
+    // a changed mtu makes the existing clamps stale
+    assert!(!clamps_current(&rules, 1420, 2));
+}
@@ -119,7 +119,26 @@ impl dyn KernelInterface {
         Ok(false)
     }
 
-    fn get_reject_rule_handle(&self) -> Result<Option<u32>, KernelInterfaceError> {
+    /// fw4 only has a chain for a zone while the zone exists, like forward_guest for the guest network
+    pub fn does_nft_chain_exist(&self, chain: &str) -> Result<bool, KernelInterfaceError> {
+        let out = self.run_command("nft", &["list", "chain", "inet", "fw4", chain])?;
+        Ok(out.status.success())
+    }
+
+    fn get_reject_rule_handle(&self, chain: &str) -> Result<Option<u32>, KernelInterfaceError> {
+        let out = self.run_command("nft", &["-a", "list", "chain", "inet", "fw4", chain])?;
+        let out = out.stdout;
+        let out = String::from_utf8(out).expect("fix command");
+        for line in out.lines() {
+            // only the bare reject, the kill switch also rejects but only for some interfaces
+            if line.trim().starts_with("reject") {
+                return Ok(parse_nft_handle(line));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_kill_switch_rule_handle(&self) -> Result<Option<u32>, KernelInterfaceError> {
         let out = self.run_command(
             "nft",
             &["-a", "list", "chain", "inet", "fw4", "forward_lan"],
@@ -127,17 +146,8 @@ impl dyn KernelInterface {
         let out = out.stdout;
         let out = String::from_utf8(out).expect("fix command");
         for line in out.lines() {
-            if line.contains("reject") {
-                let handle: Vec<&str> = line.split(' ').collect();
-                match handle.last() {
-                    Some(a) => match (*a).parse() {
-                        Ok(b) => return Ok(Some(b)),
-                        Err(_) => {
-                            return Ok(None);
-                        }
-                    },
-                    None => return Ok(None),
-                }
+            if line.contains("oifname != \"wg_exit\"") && line.contains("reject") {
+                return Ok(parse_nft_handle(line));
             }
         }
         Ok(None)
@@ -151,18 +161,54 @@ impl dyn KernelInterface {
         Ok(false)
     }
 
-    pub fn insert_reject_rule(&self) -> Result<(), KernelInterfaceError> {
-        if self.get_reject_rule_handle()?.is_none() {
+    pub fn insert_reject_rule(&self, chain: &str) -> Result<(), KernelInterfaceError> {
+        if self.get_reject_rule_handle(chain)?.is_none() {
+            self.run_command("nft", &["insert", "rule", "inet", "fw4", chain, "reject"])?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_reject_rule(&self, chain: &str) -> Result<(), KernelInterfaceError> {
+        if let Some(handle) = self.get_reject_rule_handle(chain)? {
+            self.run_command(
+                "nft",
+                &[
+                    "delete",
+                    "rule",
+                    "inet",
+                    "fw4",
+                    chain,
+                    "handle",
+                    &handle.to_string(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Rejects lan traffic forwarded to any interface but wg_exit, see set_client_kill_switch
+    pub fn insert_nft_kill_switch_rule(&self) -> Result<(), KernelInterfaceError> {
+        if self.get_kill_switch_rule_handle()?.is_none() {
             self.run_command(
                 "nft",
-                &["insert", "rule", "inet", "fw4", "forward_lan", "reject"],
+                &[
+                    "insert",
+                    "rule",
+                    "inet",
+                    "fw4",
+                    "forward_lan",
+                    "oifname",
+                    "!=",
+                    "wg_exit",
+                    "reject",
+                ],
             )?;
         }
         Ok(())
     }
 
-    pub fn delete_reject_rule(&self) -> Result<(), KernelInterfaceError> {
-        if let Some(handle) = self.get_reject_rule_handle()? {
+    pub fn delete_nft_kill_switch_rule(&self) -> Result<(), KernelInterfaceError> {
+        if let Some(handle) = self.get_kill_switch_rule_handle()? {
             self.run_command(
                 "nft",
                 &[
@@ -179,6 +225,38 @@ impl dyn KernelInterface {
         Ok(())
     }
 
+    pub fn is_nft_guest_forward_rule_present(&self) -> Result<bool, KernelInterfaceError> {
+        let out = self.run_command("nft", &["list", "chain", "inet", "fw4", "forward_guest"])?;
+        let out = String::from_utf8(out.stdout).expect("fix command");
+        // counters and comments may follow the match, only the match and verdict identify the rule
+        Ok(out
+            .lines()
+            .any(|line| line.contains("oifname \"wg_exit\"") && line.contains("accept")))
+    }
+
+    pub fn insert_nft_guest_forward_rule(&self) -> Result<(), KernelInterfaceError> {
+        if !self.is_nft_guest_forward_rule_present()? {
+            self.run_command(
+                "nft",
+                &[
+                    "insert",
+                    "rule",
+                    "inet",
+                    "fw4",
+                    "forward_guest",
+                    "oifname",
+                    "wg_exit",
+                    "accept",
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn is_nft_kill_switch_rule_present(&self) -> Result<bool, KernelInterfaceError> {
+        Ok(self.get_kill_switch_rule_handle()?.is_some())
+    }
+
     pub fn init_nat_chain(&self, ex_nic: &str) -> Result<(), KernelInterfaceError> {
         if !self.is_nat_table_present()? {
             self.create_nat_table(ex_nic)?;
@@ -358,3 +436,24 @@ impl dyn KernelInterface {
         }
     }
 }
+
+/// Gets the handle from a line of `nft -a list` output, it is always the last word
+fn parse_nft_handle(line: &str) -> Option<u32> {
+    line.split(' ')
+        .last()
+        .and_then(|handle| handle.parse().ok())
+}
+
+#[test]
+fn test_parse_nft_handle() {
+    assert_eq!(parse_nft_handle("\t\treject # handle 42"), Some(42));
+    assert_eq!(
+        parse_nft_handle("\t\toifname != \"wg_exit\" reject # handle 7"),
+        Some(7)
+    );
+    assert_eq!(
+        parse_nft_handle("\tchain forward_lan { # handle 3"),
+        Some(3)
+    );
+    assert_eq!(parse_nft_handle("\t\treject"), None);
+}
@@ -0,0 +1,219 @@
+//! Exit enforcement on nftables instead of tc. Every enforced client gets a pair of named limits in our
+//! own table, one for each direction, and policing rules that match the client's ipv4 address and ipv6
+//! subnet against them so both families share the one limit like they share a tc class. Rules are tagged
+//! with a comment carrying the client ip and limit so that the enforcement currently programmed can be
+//! read back without parsing the way nft prints rates. Traffic to and from the enforcement exempt
+//! destinations is accepted ahead of the policing rules, like the exempt tc filters. The table is replaced
+//! in a single nft transaction so clients are never briefly unenforced while it is reprogrammed.
+
+use crate::KernelInterface;
+use crate::KernelInterfaceError as Error;
+use ipnetwork::IpNetwork;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::Ipv4Addr;
+
+pub const NFT_ENFORCEMENT_TABLE: &str = "rita_enforcement";
+const NFT_ENFORCEMENT_CHAIN: &str = "forward";
+const NFT_ENFORCEMENT_COMMENT: &str = "rita_enforce";
+/// The ruleset is handed to nft -f from here
+const NFT_ENFORCEMENT_FILE: &str = "/tmp/rita-enforcement.nft";
+
+/// The rate nft polices a limit in kbit at, nft rates are in bytes
+fn nft_rate_kbytes(limit_kbit: u32) -> u32 {
+    (limit_kbit / 8).max(1)
+}
+
+/// Renders the nft script that replaces our table with exactly these clients and limits in kbit,
+/// clients with an ipv6 subnet have it limited along with their ipv4 address
+fn render_nft_enforcement(
+    limits: &HashMap<Ipv4Addr, u32>,
+    ipv6: &HashMap<Ipv4Addr, IpNetwork>,
+    exemptions: &HashSet<IpNetwork>,
+) -> String {
+    let table = format!("inet {NFT_ENFORCEMENT_TABLE}");
+    // adding first makes the delete safe when the table doesn't exist yet
+    let mut script = format!(
+        "add table {table}\ndelete table {table}\nadd table {table}\n\
+         add chain {table} {NFT_ENFORCEMENT_CHAIN} {{ type filter hook forward priority 0 ; }}\n"
+    );
+    let rule = format!("add rule {table} {NFT_ENFORCEMENT_CHAIN}");
+    let mut exempt: Vec<&IpNetwork> = exemptions.iter().collect();
+    exempt.sort();
+    for dest in exempt {
+        let family = match dest {
+            IpNetwork::V4(_) => "ip",
+            IpNetwork::V6(_) => "ip6",
+        };
+        for direction in ["saddr", "daddr"] {
+            script.push_str(&format!("{rule} {family} {direction} {dest} accept\n"));
+        }
+    }
+    let mut clients: Vec<(&Ipv4Addr, &u32)> = limits.iter().collect();
+    clients.sort();
+    for (ip, limit) in clients {
+        let kbytes = nft_rate_kbytes(*limit);
+        let comment = format!("comment \"{NFT_ENFORCEMENT_COMMENT} {ip} {limit}\"");
+        for direction in ["daddr", "saddr"] {
+            let name = format!("c{}_{direction}", u32::from(*ip));
+            script.push_str(&format!(
+                "add limit {table} {name} {{ rate over {kbytes} kbytes/second ; }}\n"
+            ));
+            script.push_str(&format!(
+                "{rule} ip {direction} {ip} limit name \"{name}\" drop {comment}\n"
+            ));
+            if let Some(subnet) = ipv6.get(ip) {
+                script.push_str(&format!(
+                    "{rule} ip6 {direction} {subnet} limit name \"{name}\" drop {comment}\n"
+                ));
+            }
+        }
+    }
+    script
+}
+
+/// Client ip to limit in kbit from the output of nft list table, read from our rule comments
+fn parse_nft_enforcement(output: &str) -> HashMap<Ipv4Addr, u32> {
+    let mut ret = HashMap::new();
+    let prefix = format!("comment \"{NFT_ENFORCEMENT_COMMENT} ");
+    for line in output.lines() {
+        let comment = match line.find(&prefix) {
+            Some(start) => &line[start + prefix.len()..],
+            None => continue,
+        };
+        let mut words = comment.trim_end_matches('"').split_ascii_whitespace();
+        if let (Some(Ok(ip)), Some(Ok(limit))) = (
+            words.next().map(|ip| ip.parse()),
+            words
+                .next()
+                .map(|limit| limit.trim_end_matches('"').parse()),
+        ) {
+            ret.insert(ip, limit);
+        }
+    }
+    ret
+}
+
+impl dyn KernelInterface {
+    /// The limit in kbit of every client enforced on nftables, empty if our table doesn't exist
+    pub fn get_nft_enforcement(&self) -> Result<HashMap<Ipv4Addr, u32>, Error> {
+        let output = self.run_command("nft", &["list", "table", "inet", NFT_ENFORCEMENT_TABLE])?;
+        if !output.status.success() {
+            return Ok(HashMap::new());
+        }
+        Ok(parse_nft_enforcement(&String::from_utf8(output.stdout)?))
+    }
+
+    /// What set_nft_enforcement() ends up policing each client at when given these limits in kbit
+    pub fn planned_nft_enforcement(
+        &self,
+        limits: &HashMap<Ipv4Addr, u32>,
+    ) -> HashMap<Ipv4Addr, u32> {
+        limits
+            .iter()
+            .map(|(ip, limit)| (*ip, nft_rate_kbytes(*limit) * 8))
+            .collect()
+    }
+
+    /// Replaces the nftables enforcement with exactly these clients and limits in kbit, ipv6 maps
+    /// clients to the ipv6 subnet that is limited along with their ipv4 address. Traffic to and from
+    /// exemptions is never limited
+    pub fn set_nft_enforcement(
+        &self,
+        limits: &HashMap<Ipv4Addr, u32>,
+        ipv6: &HashMap<Ipv4Addr, IpNetwork>,
+        exemptions: &HashSet<IpNetwork>,
+    ) -> Result<(), Error> {
+        fs::write(
+            NFT_ENFORCEMENT_FILE,
+            render_nft_enforcement(limits, ipv6, exemptions),
+        )?;
+        let output = self.run_command("nft", &["-f", NFT_ENFORCEMENT_FILE])?;
+        if !output.status.success() {
+            let res = String::from_utf8(output.stderr)?;
+            return Err(Error::TrafficControlError(format!(
+                "Failed to program nft enforcement! {res:?}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Removes all nftables enforcement
+    pub fn delete_nft_enforcement(&self) -> Result<(), Error> {
+        self.run_command("nft", &["delete", "table", "inet", NFT_ENFORCEMENT_TABLE])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_nft_enforcement() {
+        let ip: Ipv4Addr = "172.168.1.5".parse().unwrap();
+        let other: Ipv4Addr = "172.168.1.9".parse().unwrap();
+        let limits: HashMap<Ipv4Addr, u32> = [(ip, 1000), (other, 8000)].into_iter().collect();
+        let ipv6: HashMap<Ipv4Addr, IpNetwork> = [(ip, "fd00::1:0/112".parse().unwrap())]
+            .into_iter()
+            .collect();
+        let script = render_nft_enforcement(&limits, &ipv6, &HashSet::new());
+        let name = format!("c{}_daddr", u32::from(ip));
+        assert!(script.contains(&format!(
+            "add limit inet rita_enforcement {name} {{ rate over 125 kbytes/second ; }}"
+        )));
+        // both families are policed by the same limit
+        assert!(script.contains(&format!(
+            "ip daddr 172.168.1.5 limit name \"{name}\" drop comment \"rita_enforce 172.168.1.5 1000\""
+        )));
+        assert!(script.contains(&format!(
+            "ip6 daddr fd00::1:0/112 limit name \"{name}\" drop comment \"rita_enforce 172.168.1.5 1000\""
+        )));
+        assert_eq!(script.matches("ip6 ").count(), 2);
+        assert_eq!(script.matches("add rule").count(), 6);
+        // the parser reads the comments the script leaves on the rules
+        assert_eq!(parse_nft_enforcement(&script), limits);
+    }
+
+    #[test]
+    fn test_render_nft_exemptions() {
+        let ip: Ipv4Addr = "172.168.1.5".parse().unwrap();
+        let limits: HashMap<Ipv4Addr, u32> = [(ip, 1000)].into_iter().collect();
+        let exemptions: HashSet<IpNetwork> = [
+            "1.2.3.0/24".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ]
+        .into_iter()
+        .collect();
+        let script = render_nft_enforcement(&limits, &HashMap::new(), &exemptions);
+        let first_limit = script.find("limit name").unwrap();
+        for accept in [
+            "ip saddr 1.2.3.0/24 accept",
+            "ip daddr 1.2.3.0/24 accept",
+            "ip6 saddr 2001:db8::/32 accept",
+            "ip6 daddr 2001:db8::/32 accept",
+        ] {
+            // the exemptions are accepted before any client is policed
+            assert!(script.find(accept).unwrap() < first_limit);
+        }
+        assert_eq!(parse_nft_enforcement(&script), limits);
+    }
+
+    #[test]
+    fn test_parse_nft_enforcement() {
+        let output = "table inet rita_enforcement {
+	chain forward {
+		type filter hook forward priority filter; policy accept;
+		ip daddr 172.168.1.5 limit name \"c2896691461_daddr\" drop comment \"rita_enforce 172.168.1.5 1000\"
+		ip saddr 172.168.1.5 limit name \"c2896691461_saddr\" drop comment \"rita_enforce 172.168.1.5 1000\"
+		ip daddr 172.168.1.9 limit name \"c2896691465_daddr\" drop comment \"rita_enforce 172.168.1.9 8000\"
+	}
+}";
+        let limits = parse_nft_enforcement(output);
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits[&"172.168.1.5".parse().unwrap()], 1000);
+        assert_eq!(limits[&"172.168.1.9".parse().unwrap()], 8000);
+        assert_eq!(nft_rate_kbytes(1000) * 8, 1000);
+        assert_eq!(nft_rate_kbytes(4), 1);
+    }
+}
@@ -83,6 +83,8 @@ pub struct TunnelOpenArgs<'a> {
     /// the default route that we use to get to the internet if we are a gateway, only used to handle
     /// default route considerations on the gateway
     pub settings_default_route: &'a mut Option<DefaultRoute>,
+    /// Wireguard persistent keepalive interval in seconds, 0 disables it
+    pub persistent_keepalive: u16,
 }
 
 impl dyn KernelInterface {
@@ -121,7 +123,7 @@ impl dyn KernelInterface {
                 "allowed-ips",
                 &allowed_addresses,
                 "persistent-keepalive",
-                "5",
+                &args.persistent_keepalive.to_string(),
             ],
         )?;
         if !output.stderr.is_empty() {
@@ -298,6 +300,7 @@ fe80::433:25ff:fe8c:e1ea dev eth0 lladdr 1a:32:06:78:05:0a STALE
         own_ip_v2: None,
         external_nic: None,
         settings_default_route: &mut Some(def_route),
+        persistent_keepalive: 5,
     };
 
     KI.open_tunnel(args).unwrap();
@@ -0,0 +1,250 @@
+//! Tags for the kernel state Rita creates, so that it can be told apart from state owned by the rest of
+//! the system. Routes are installed with their own route protocol number, policy rules use fwmarks from a
+//! reserved range and tunnels use the wg interface name prefix. When Rita crashes whatever it had set up
+//! is left behind, the startup sweep uses these tags to find and remove anything that isn't wanted.
+
+use super::KernelInterface;
+use crate::KernelInterfaceError as Error;
+use std::collections::HashSet;
+
+/// Route protocol number of every route Rita installs, `ip route show proto 77` lists them
+pub const RITA_ROUTE_PROTO: &str = "77";
+/// Fwmarks Rita uses for policy routing are RITA_FWMARK_BASE plus a 16 bit index
+pub const RITA_FWMARK_BASE: u32 = 0x5249_0000;
+pub const RITA_FWMARK_MASK: u32 = 0xffff_0000;
+/// Interfaces Rita creates are the numbered mesh tunnels, wg0, wg1 and so on, and the exit tunnels
+pub const RITA_INTERFACE_PREFIX: &str = "wg";
+const RITA_EXIT_INTERFACES: [&str; 3] = ["wg_exit", "wg_exit_v2", "wg_exit_split"];
+
+pub fn rita_fwmark(index: u16) -> u32 {
+    RITA_FWMARK_BASE | index as u32
+}
+
+pub fn is_rita_fwmark(mark: u32) -> bool {
+    mark & RITA_FWMARK_MASK == RITA_FWMARK_BASE
+}
+
+pub fn is_rita_interface(name: &str) -> bool {
+    if RITA_EXIT_INTERFACES.contains(&name) {
+        return true;
+    }
+    match name.strip_prefix(RITA_INTERFACE_PREFIX) {
+        Some(num) => !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// The Rita owned kernel state that should survive a sweep, anything tagged as Rita's that isn't listed
+/// here is removed. At startup nothing has been set up yet so this is empty
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RitaOwnedState {
+    pub interfaces: HashSet<String>,
+    /// Route destinations as printed by `ip route`, "default" or an address with an optional prefix
+    pub routes: HashSet<String>,
+    pub fwmarks: HashSet<u32>,
+}
+
+/// What a sweep removed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SweepReport {
+    pub interfaces: Vec<String>,
+    pub routes: Vec<String>,
+    pub rules: Vec<String>,
+}
+
+/// A route from `ip route show proto 77`, with enough of it to delete exactly that route
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OwnedRoute {
+    dst: String,
+    dev: Option<String>,
+    metric: Option<String>,
+}
+
+/// A policy rule matching on one of our fwmarks, rules are deleted by priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OwnedRule {
+    priority: u32,
+    fwmark: u32,
+}
+
+fn token_after<'a>(tokens: &[&'a str], key: &str) -> Option<&'a str> {
+    tokens
+        .iter()
+        .position(|t| *t == key)
+        .and_then(|i| tokens.get(i + 1))
+        .copied()
+}
+
+fn parse_owned_routes(out: &str) -> Vec<OwnedRoute> {
+    out.lines()
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let dst = tokens.first()?;
+            Some(OwnedRoute {
+                dst: dst.to_string(),
+                dev: token_after(&tokens, "dev").map(|d| d.to_string()),
+                metric: token_after(&tokens, "metric").map(|m| m.to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Parses lines like "1000:	from all fwmark 0x52490001/0xffffffff lookup 100" from `ip rule show`,
+/// only rules matching on a Rita fwmark are returned
+fn parse_owned_rules(out: &str) -> Vec<OwnedRule> {
+    out.lines()
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let priority = tokens.first()?.trim_end_matches(':').parse().ok()?;
+            let mark = token_after(&tokens, "fwmark")?.split('/').next()?;
+            let fwmark = u32::from_str_radix(mark.trim_start_matches("0x"), 16).ok()?;
+            if is_rita_fwmark(fwmark) {
+                Some(OwnedRule { priority, fwmark })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+impl dyn KernelInterface {
+    fn sweep_routes(&self, family: &str, desired: &RitaOwnedState) -> Result<Vec<String>, Error> {
+        let output =
+            self.run_command("ip", &[family, "route", "show", "proto", RITA_ROUTE_PROTO])?;
+        let mut removed = Vec::new();
+        for route in parse_owned_routes(&String::from_utf8(output.stdout)?) {
+            if desired.routes.contains(&route.dst) {
+                continue;
+            }
+            let mut args = vec![
+                family,
+                "route",
+                "del",
+                route.dst.as_str(),
+                "proto",
+                RITA_ROUTE_PROTO,
+            ];
+            if let Some(dev) = &route.dev {
+                args.extend(["dev", dev.as_str()]);
+            }
+            if let Some(metric) = &route.metric {
+                args.extend(["metric", metric.as_str()]);
+            }
+            let output = self.run_command("ip", &args)?;
+            if output.status.success() {
+                removed.push(route.dst);
+            } else {
+                warn!(
+                    "Failed to remove stale route {:?} {}",
+                    route,
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+        Ok(removed)
+    }
+
+    fn sweep_rules(&self, family: &str, desired: &RitaOwnedState) -> Result<Vec<String>, Error> {
+        let output = self.run_command("ip", &[family, "rule", "show"])?;
+        let mut removed = Vec::new();
+        for rule in parse_owned_rules(&String::from_utf8(output.stdout)?) {
+            if desired.fwmarks.contains(&rule.fwmark) {
+                continue;
+            }
+            let priority = rule.priority.to_string();
+            let output = self.run_command("ip", &[family, "rule", "del", "priority", &priority])?;
+            if output.status.success() {
+                removed.push(format!("{priority}: fwmark {:#x}", rule.fwmark));
+            } else {
+                warn!(
+                    "Failed to remove stale rule {:?} {}",
+                    rule,
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes every Rita owned interface, route and rule that is not in the desired state, used at
+    /// startup to clear out whatever a previous run left behind
+    pub fn sweep_rita_owned_state(&self, desired: &RitaOwnedState) -> Result<SweepReport, Error> {
+        let mut report = SweepReport::default();
+        // routes go before interfaces so that each delete can still name its device
+        for family in ["-4", "-6"] {
+            report.routes.extend(self.sweep_routes(family, desired)?);
+            report.rules.extend(self.sweep_rules(family, desired)?);
+        }
+        for iface in self.get_interfaces()? {
+            if is_rita_interface(&iface) && !desired.interfaces.contains(&iface) {
+                match self.del_interface(&iface) {
+                    Ok(()) => report.interfaces.push(iface),
+                    Err(e) => warn!("Failed to remove stale interface {} {:?}", iface, e),
+                }
+            }
+        }
+        info!(
+            "Swept {} interfaces, {} routes and {} rules left by a previous run",
+            report.interfaces.len(),
+            report.routes.len(),
+            report.rules.len()
+        );
+        Ok(report)
+    }
+}
+
+#[test]
+fn test_is_rita_interface() {
+    assert!(is_rita_interface("wg0"));
+    assert!(is_rita_interface("wg42"));
+    assert!(is_rita_interface("wg_exit"));
+    assert!(is_rita_interface("wg_exit_v2"));
+    assert!(is_rita_interface("wg_exit_split"));
+    assert!(!is_rita_interface("wg"));
+    assert!(!is_rita_interface("wgvpn"));
+    assert!(!is_rita_interface("eth0"));
+    assert!(!is_rita_interface("br-lan"));
+}
+
+#[test]
+fn test_parse_owned_routes() {
+    let out = "default via 172.168.0.1 dev wg_exit
+1.2.3.4 via 192.168.1.1 dev eth0 metric 100
+10.0.0.0/8 dev eth1
+";
+    let routes = parse_owned_routes(out);
+    assert_eq!(routes.len(), 3);
+    assert_eq!(
+        routes[0],
+        OwnedRoute {
+            dst: "default".to_string(),
+            dev: Some("wg_exit".to_string()),
+            metric: None,
+        }
+    );
+    assert_eq!(routes[1].metric, Some("100".to_string()));
+    assert_eq!(routes[2].dst, "10.0.0.0/8");
+}
+
+#[test]
+fn test_parse_owned_rules() {
+    let out = "0:\tfrom all lookup local
+1000:\tfrom all fwmark 0x52490001/0xffffffff lookup 100
+1001:\tfrom all fwmark 0x1 lookup 200
+1002:\tfrom all fwmark 0x52490002 lookup 101
+32766:\tfrom all lookup main
+";
+    assert_eq!(
+        parse_owned_rules(out),
+        vec![
+            OwnedRule {
+                priority: 1000,
+                fwmark: rita_fwmark(1),
+            },
+            OwnedRule {
+                priority: 1002,
+                fwmark: rita_fwmark(2),
+            },
+        ]
+    );
+}
@@ -23,6 +23,33 @@ impl dyn KernelInterface {
         Ok(peers)
     }
 
+    /// Sets the persistent keepalive interval in seconds for a peer, 0 turns it off
+    pub fn set_peer_keepalive(
+        &self,
+        iface_name: &str,
+        peer: &WgKey,
+        keepalive: u16,
+    ) -> Result<(), Error> {
+        let output = self.run_command(
+            "wg",
+            &[
+                "set",
+                iface_name,
+                "peer",
+                &peer.to_string(),
+                "persistent-keepalive",
+                &keepalive.to_string(),
+            ],
+        )?;
+        if !output.stderr.is_empty() {
+            return Err(KernelInterfaceError::RuntimeError(format!(
+                "received error setting keepalive on {iface_name}: {}",
+                String::from_utf8(output.stderr)?
+            )));
+        }
+        Ok(())
+    }
+
     /// checks the existing interfaces to find an interface name that isn't in use.
     /// then calls iproute2 to set up a new interface with that name
     pub fn create_blank_wg_numbered_wg_interface(&self) -> Result<String, Error> {
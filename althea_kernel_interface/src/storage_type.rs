@@ -0,0 +1,110 @@
+use super::KernelInterface;
+use crate::file_io::get_lines;
+use crate::KernelInterfaceError as Error;
+
+/// The kind of storage a file lives on, decides how much we can afford to write to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageType {
+    /// Raw NOR flash under jffs2, small, slow to erase and with the least write endurance
+    NorFlash,
+    /// Raw NAND flash under ubifs
+    NandFlash,
+    /// A disk, SD card or eMMC with its own wear leveling
+    Disk,
+    /// tmpfs or ramfs, free to write but gone on reboot
+    Ram,
+}
+
+/// Finds the mount a path is on, the one with the longest mount point that contains it
+fn find_mount<'a>(mounts: &'a [String], path: &str) -> Option<(&'a str, &'a str)> {
+    let mut best: Option<(&str, &str)> = None;
+    for line in mounts {
+        let mut fields = line.split_whitespace();
+        let (_device, mount_point, fs_type) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(device), Some(mount_point), Some(fs_type)) => (device, mount_point, fs_type),
+            _ => continue,
+        };
+        let contains = mount_point == "/"
+            || path == mount_point
+            || path.starts_with(&format!("{}/", mount_point.trim_end_matches('/')));
+        // later mounts shadow earlier ones on the same mount point
+        if contains && best.map_or(true, |(point, _)| mount_point.len() >= point.len()) {
+            best = Some((mount_point, fs_type));
+        }
+    }
+    best
+}
+
+/// Works out the storage type of a path from the lines of /proc/mounts
+fn parse_storage_type(mounts: &[String], path: &str) -> Option<StorageType> {
+    let (_, fs_type) = find_mount(mounts, path)?;
+    match fs_type {
+        "jffs2" => Some(StorageType::NorFlash),
+        "ubifs" => Some(StorageType::NandFlash),
+        "tmpfs" | "ramfs" => Some(StorageType::Ram),
+        // OpenWrt keeps writes to / on an overlay whose upper layer is mounted at /overlay
+        "overlay" | "overlayfs" => match find_mount(mounts, "/overlay") {
+            Some(("/overlay", "jffs2")) => Some(StorageType::NorFlash),
+            Some(("/overlay", "ubifs")) => Some(StorageType::NandFlash),
+            Some(("/overlay", "tmpfs")) => Some(StorageType::Ram),
+            _ => Some(StorageType::Disk),
+        },
+        _ => Some(StorageType::Disk),
+    }
+}
+
+impl dyn KernelInterface {
+    /// Detects the kind of storage the given file or directory is on using /proc/mounts
+    pub fn get_storage_type(&self, path: &str) -> Result<StorageType, Error> {
+        let mounts = get_lines("/proc/mounts")?;
+        match parse_storage_type(&mounts, path) {
+            Some(storage) => Ok(storage),
+            None => Err(Error::ParseError(format!("No mount found for {path}"))),
+        }
+    }
+}
+
+#[test]
+fn test_parse_storage_type() {
+    let openwrt: Vec<String> = "/dev/root /rom squashfs ro,relatime 0 0
+proc /proc proc rw,nosuid,nodev,noexec,noatime 0 0
+tmpfs /tmp tmpfs rw,nosuid,nodev,noatime 0 0
+/dev/mtdblock6 /overlay jffs2 rw,noatime 0 0
+overlayfs:/overlay / overlay rw,noatime,lowerdir=/,upperdir=/overlay/upper,workdir=/overlay/work 0 0"
+        .lines()
+        .map(|l| l.to_string())
+        .collect();
+    assert_eq!(
+        parse_storage_type(&openwrt, "/etc/rita-usage-tracker.bincode"),
+        Some(StorageType::NorFlash)
+    );
+    assert_eq!(
+        parse_storage_type(&openwrt, "/tmp/usage"),
+        Some(StorageType::Ram)
+    );
+    let nand: Vec<String> = openwrt
+        .iter()
+        .map(|l| l.replace("/dev/mtdblock6 /overlay jffs2", "ubi0_1 /overlay ubifs"))
+        .collect();
+    assert_eq!(
+        parse_storage_type(&nand, "/etc/rita-usage-tracker.bincode"),
+        Some(StorageType::NandFlash)
+    );
+
+    let server: Vec<String> = vec![
+        "/dev/nvme0n1p2 / ext4 rw,relatime 0 0".to_string(),
+        "tmpfs /tmp tmpfs rw 0 0".to_string(),
+        "/dev/sda1 /tmpdata ext4 rw 0 0".to_string(),
+    ];
+    assert_eq!(
+        parse_storage_type(&server, "/etc/rita-usage-tracker.bincode"),
+        Some(StorageType::Disk)
+    );
+    // a mount point that is only a prefix of a directory name doesn't contain it
+    assert_eq!(
+        parse_storage_type(&server, "/tmpdata/usage"),
+        Some(StorageType::Disk)
+    );
+    assert_eq!(parse_storage_type(&[], "/etc"), None);
+}
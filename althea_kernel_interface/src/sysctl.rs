@@ -0,0 +1,87 @@
+//! Reading and setting kernel parameters and loading kernel modules, used by the exit preflight checks
+
+use crate::KernelInterface;
+use crate::KernelInterfaceError as Error;
+use std::fs;
+use std::path::Path;
+
+/// True if modules.builtin lists the module, entries are paths such as
+/// kernel/net/netfilter/nf_conntrack.ko and module names treat - and _ the same
+fn builtin_modules_contains(builtin: &str, module: &str) -> bool {
+    let module = module.replace('-', "_");
+    builtin.lines().any(|line| {
+        line.trim()
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.strip_suffix(".ko"))
+            .map(|name| name.replace('-', "_") == module)
+            .unwrap_or(false)
+    })
+}
+
+impl dyn KernelInterface {
+    /// The current value of a sysctl, for example net.ipv4.ip_forward
+    pub fn get_sysctl(&self, key: &str) -> Result<String, Error> {
+        let output = self.run_command("sysctl", &["-n", key])?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to read {key} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(String::from_utf8(output.stdout)?.trim().to_string())
+    }
+
+    /// Sets a sysctl until the next reboot
+    pub fn set_sysctl(&self, key: &str, value: &str) -> Result<(), Error> {
+        let output = self.run_command("sysctl", &["-w", &format!("{key}={value}")])?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to set {key} to {value} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// True if the module is loaded or built into the kernel. Built in modules only show up under
+    /// /sys/module if they have parameters, so modules.builtin of the running kernel is checked too
+    pub fn is_module_loaded(&self, module: &str) -> bool {
+        if Path::new("/sys/module").join(module).exists() {
+            return true;
+        }
+        let release = match fs::read_to_string("/proc/sys/kernel/osrelease") {
+            Ok(release) => release,
+            Err(_) => return false,
+        };
+        match fs::read_to_string(format!("/lib/modules/{}/modules.builtin", release.trim())) {
+            Ok(builtin) => builtin_modules_contains(&builtin, module),
+            Err(_) => false,
+        }
+    }
+
+    pub fn load_module(&self, module: &str) -> Result<(), Error> {
+        let output = self.run_command("modprobe", &[module])?;
+        if !output.status.success() {
+            return Err(Error::RuntimeError(format!(
+                "Failed to load {module} {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_modules_contains() {
+        let builtin = "kernel/net/netfilter/nf_conntrack.ko\nkernel/net/sched/sch-cake.ko\n";
+        assert!(builtin_modules_contains(builtin, "nf_conntrack"));
+        assert!(builtin_modules_contains(builtin, "sch_cake"));
+        assert!(!builtin_modules_contains(builtin, "wireguard"));
+        assert!(!builtin_modules_contains(builtin, "conntrack"));
+    }
+}
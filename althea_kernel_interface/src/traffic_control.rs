@@ -9,8 +9,22 @@ use ipnetwork::IpNetwork;
 use crate::KernelInterface;
 use crate::KernelInterfaceError as Error;
 
+use std::collections::{HashMap, HashSet};
 use std::net::Ipv4Addr;
 
+/// Class id that traffic from enforcement exempt destinations is placed into, get_class_id takes
+/// a modulo of 9999 so this can never collide with a client class
+pub const EXEMPT_CLASS_ID: u32 = 9999;
+/// Filter priorities for the exempt destination filters, tc evaluates lower priorities first so these
+/// must be below the client filter priorities for exempt traffic to skip the client classes. Tc only
+/// allows a single protocol per priority so ipv4 and ipv6 each get their own
+const EXEMPT_FILTER_PRIO_V4: &str = "1";
+const EXEMPT_FILTER_PRIO_V6: &str = "2";
+/// Filter priorities for the per client flows. Flows created before these were set got the priority
+/// tc auto assigns, which is also above the exempt priorities
+const CLIENT_FILTER_PRIO_V4: &str = "10";
+const CLIENT_FILTER_PRIO_V6: &str = "11";
+
 impl dyn KernelInterface {
     /// Determines if the provided interface has a configured qdisc
     pub fn has_qdisc(&self, iface_name: &str) -> Result<bool, Error> {
@@ -67,7 +81,11 @@ impl dyn KernelInterface {
 
     /// Determines if the provided flow is assigned
     pub fn has_class(&self, ip: Ipv4Addr, iface_name: &str) -> Result<bool, Error> {
-        let class_id = self.get_class_id(ip);
+        self.has_class_id(self.get_class_id(ip), iface_name)
+    }
+
+    /// Determines if a class with the given id exists on the interface
+    pub fn has_class_id(&self, class_id: u32, iface_name: &str) -> Result<bool, Error> {
         let result = self.run_command("tc", &["class", "show", "dev", iface_name])?;
 
         if !result.status.success() {
@@ -89,6 +107,45 @@ impl dyn KernelInterface {
         Ok(false)
     }
 
+    /// The ceil in kbit of every htb class on the interface by class id, as tc reports it
+    pub fn get_class_limits(&self, iface_name: &str) -> Result<HashMap<u32, u32>, Error> {
+        let result = self.run_command("tc", &["class", "show", "dev", iface_name])?;
+
+        if !result.status.success() {
+            let res = String::from_utf8(result.stderr)?;
+            return Err(Error::TrafficControlError(format!(
+                "Failed to list classes on {iface_name}! {res:?}"
+            )));
+        }
+
+        Ok(parse_class_limits(&String::from_utf8(result.stdout)?))
+    }
+
+    /// What set_class_limit() ends up enforcing on each of these clients when given these limits in
+    /// kbit. Clients whose class ids collide share one class, so the last limit set applies to all of them
+    pub fn planned_class_limits(
+        &self,
+        limits: &HashMap<Ipv4Addr, u32>,
+        client_ips: &[Ipv4Addr],
+    ) -> HashMap<Ipv4Addr, u32> {
+        let mut limited: Vec<(&Ipv4Addr, &u32)> = limits.iter().collect();
+        limited.sort();
+        let mut classes = HashMap::new();
+        for (ip, limit) in limited {
+            if let Some(kbit) = parse_tc_rate(&format!("{limit}kbit")) {
+                classes.insert(self.get_class_id(*ip), kbit);
+            }
+        }
+        client_ips
+            .iter()
+            .filter_map(|ip| {
+                classes
+                    .get(&self.get_class_id(*ip))
+                    .map(|kbit| (*ip, *kbit))
+            })
+            .collect()
+    }
+
     /// Determines if the provided interface has a configured qdisc
     pub fn has_limit(&self, iface_name: &str) -> Result<bool, Error> {
         let result = self.run_command("tc", &["qdisc", "show", "dev", iface_name])?;
@@ -382,6 +439,8 @@ impl dyn KernelInterface {
                 "1:",
                 "protocol",
                 "ipv6",
+                "prio",
+                CLIENT_FILTER_PRIO_V6,
                 "u32",
                 "match",
                 "ip6",
@@ -419,6 +478,8 @@ impl dyn KernelInterface {
                 "1:",
                 "protocol",
                 "ip",
+                "prio",
+                CLIENT_FILTER_PRIO_V4,
                 "u32",
                 "match",
                 "ip",
@@ -439,6 +500,91 @@ impl dyn KernelInterface {
         }
     }
 
+    /// Programs the set of destinations that are exempt from exit enforcement. Traffic from these
+    /// destinations towards clients is classified into an effectively unlimited class ahead of the
+    /// per client flows, so enforced clients can still reach payment processors and the like.
+    /// Any previously programmed exemptions are replaced.
+    pub fn set_enforcement_exemptions(
+        &self,
+        iface_name: &str,
+        destinations: &HashSet<IpNetwork>,
+    ) -> Result<(), Error> {
+        let modifier = if self.has_class_id(EXEMPT_CLASS_ID, iface_name)? {
+            "change"
+        } else {
+            "add"
+        };
+        let output = self.run_command(
+            "tc",
+            &[
+                "class",
+                modifier,
+                "dev",
+                iface_name,
+                "parent",
+                "1:",
+                "classid",
+                &format!("1:{EXEMPT_CLASS_ID}"),
+                "htb",
+                "rate",
+                "10gbit",
+            ],
+        )?;
+        if !output.status.success() {
+            let res = String::from_utf8(output.stderr)?;
+            return Err(Error::TrafficControlError(format!(
+                "Failed to setup exempt class! {res:?}"
+            )));
+        }
+
+        // these fail if there are no filters at the given priority, which is fine
+        for prio in [EXEMPT_FILTER_PRIO_V4, EXEMPT_FILTER_PRIO_V6] {
+            self.run_command(
+                "tc",
+                &[
+                    "filter", "del", "dev", iface_name, "parent", "1:", "prio", prio,
+                ],
+            )?;
+        }
+
+        for dest in destinations {
+            let (protocol, prio, match_type) = match dest {
+                IpNetwork::V4(_) => ("ip", EXEMPT_FILTER_PRIO_V4, "ip"),
+                IpNetwork::V6(_) => ("ipv6", EXEMPT_FILTER_PRIO_V6, "ip6"),
+            };
+            let output = self.run_command(
+                "tc",
+                &[
+                    "filter",
+                    "add",
+                    "dev",
+                    iface_name,
+                    "parent",
+                    "1:",
+                    "protocol",
+                    protocol,
+                    "prio",
+                    prio,
+                    "u32",
+                    "match",
+                    match_type,
+                    "src",
+                    &dest.to_string(),
+                    "flowid",
+                    &format!("1:{EXEMPT_CLASS_ID}"),
+                ],
+            )?;
+            if !output.status.success() {
+                let res = String::from_utf8(output.stderr)?;
+                return Err(Error::TrafficControlError(format!(
+                    "Failed to create exempt filter for {dest}! {res:?}"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     /// deletes the interface qdisc
     pub fn delete_qdisc(&self, iface_name: &str) -> Result<(), Error> {
         let output = self.run_command("tc", &["qdisc", "del", "dev", iface_name, "root"])?;
@@ -452,6 +598,143 @@ impl dyn KernelInterface {
     }
 }
 
+/// Parses a rate as tc prints it, for example 8Mbit or 1500Kbit, into kbit
+fn parse_tc_rate(rate: &str) -> Option<u32> {
+    let split = rate.find(|c: char| !c.is_ascii_digit())?;
+    let (value, unit) = rate.split_at(split);
+    let value: u64 = value.parse().ok()?;
+    let kbit = match unit.to_ascii_lowercase().as_str() {
+        "bit" => value / 1000,
+        "kbit" => value,
+        "mbit" => value * 1000,
+        "gbit" => value * 1_000_000,
+        _ => return None,
+    };
+    u32::try_from(kbit).ok()
+}
+
+/// Class id to ceil from the output of tc class show, lines look like
+/// class htb 1:1234 root prio 0 rate 8Mbit ceil 8Mbit burst 1600b cburst 1600b
+fn parse_class_limits(output: &str) -> HashMap<u32, u32> {
+    let mut ret = HashMap::new();
+    for line in output.lines() {
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        let class_id = words
+            .iter()
+            .position(|w| *w == "htb")
+            .and_then(|i| words.get(i + 1))
+            .and_then(|id| id.strip_prefix("1:"))
+            .and_then(|id| id.parse().ok());
+        let ceil = words
+            .iter()
+            .position(|w| *w == "ceil")
+            .and_then(|i| words.get(i + 1))
+            .and_then(|rate| parse_tc_rate(rate));
+        if let (Some(class_id), Some(ceil)) = (class_id, ceil) {
+            ret.insert(class_id, ceil);
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_parse_class_limits() {
+    assert_eq!(parse_tc_rate("8Mbit"), Some(8000));
+    assert_eq!(parse_tc_rate("1500Kbit"), Some(1500));
+    assert_eq!(parse_tc_rate("10Gbit"), Some(10_000_000));
+    assert_eq!(parse_tc_rate("8Mbps"), None);
+    let output = "class htb 1:1234 root prio 0 rate 8Mbit ceil 8Mbit burst 1600b cburst 1600b \n\
+                  class htb 1:9999 root prio 0 rate 10Gbit ceil 10Gbit burst 0b cburst 0b \n\
+                  class htb 1:42 root prio 0 rate 500Kbit ceil 500Kbit burst 1600b cburst 1600b";
+    let limits = parse_class_limits(output);
+    assert_eq!(limits.len(), 3);
+    assert_eq!(limits[&1234], 8000);
+    assert_eq!(limits[&42], 500);
+}
+
+#[test]
+fn test_planned_class_limits() {
+    use crate::KI;
+    let a: Ipv4Addr = "172.168.0.2".parse().unwrap();
+    // shares a class id with a
+    let b = Ipv4Addr::from(u32::from(a) + 9999);
+    let c: Ipv4Addr = "172.168.0.3".parse().unwrap();
+    let limits: HashMap<Ipv4Addr, u32> = [(c, 1000)].into_iter().collect();
+    let planned = KI.planned_class_limits(&limits, &[a, b, c]);
+    assert_eq!(planned, limits);
+
+    let limits: HashMap<Ipv4Addr, u32> = [(a, 1000), (b, 2000)].into_iter().collect();
+    let planned = KI.planned_class_limits(&limits, &[a, b, c]);
+    assert_eq!(planned.len(), 2);
+    assert_eq!(planned[&a], planned[&b]);
+}
+
+#[test]
+fn test_exempt_filters_come_first() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    let added = Arc::new(Mutex::new(Vec::new()));
+    let record = added.clone();
+    KI.set_mock(Box::new(move |program, args| {
+        assert_eq!(program, "tc");
+        if args[..2] == ["filter", "add"] {
+            let arg = |name: &str| {
+                let i = args.iter().position(|a| a == name).unwrap();
+                args[i + 1].clone()
+            };
+            let prio: u32 = arg("prio").parse().unwrap();
+            record
+                .lock()
+                .unwrap()
+                .push((arg("protocol"), prio, arg("flowid")));
+        }
+        Ok(Output {
+            stdout: b"".to_vec(),
+            stderr: b"".to_vec(),
+            status: ExitStatus::from_raw(0),
+        })
+    }));
+
+    let ip: Ipv4Addr = "172.168.1.5".parse().unwrap();
+    KI.create_flow_by_ip("wg_exit", ip).unwrap();
+    KI.create_flow_by_ipv6("wg_exit", "fd00::1:0/112".parse().unwrap(), ip)
+        .unwrap();
+    let exemptions: HashSet<IpNetwork> = [
+        "1.2.3.0/24".parse().unwrap(),
+        "2001:db8::/32".parse().unwrap(),
+    ]
+    .into_iter()
+    .collect();
+    KI.set_enforcement_exemptions("wg_exit", &exemptions)
+        .unwrap();
+
+    let added = added.lock().unwrap();
+    assert_eq!(added.len(), 4);
+    let exempt_flow = format!("1:{EXEMPT_CLASS_ID}");
+    for protocol in ["ip", "ipv6"] {
+        let prios = |exempt: bool| -> Vec<u32> {
+            added
+                .iter()
+                .filter(|(p, _, flow)| p == protocol && (*flow == exempt_flow) == exempt)
+                .map(|(_, prio, _)| *prio)
+                .collect()
+        };
+        let (exempt, client) = (prios(true), prios(false));
+        assert_eq!((exempt.len(), client.len()), (1, 1));
+        // tc evaluates lower priorities first
+        assert!(exempt[0] < client[0]);
+    }
+    // every filter has a priority of its own so one family never shares a priority with the other
+    let mut prios: Vec<u32> = added.iter().map(|(_, prio, _)| *prio).collect();
+    prios.sort();
+    prios.dedup();
+    assert_eq!(prios.len(), 4);
+}
+
 #[test]
 fn get_id() {
     use crate::KI;
@@ -38,6 +38,7 @@ impl dyn KernelInterface {
             OpkgCommand::Install {
                 packages,
                 arguments,
+                ..
             } => {
                 let mut args = arguments;
                 args.insert(0, "install".to_string());
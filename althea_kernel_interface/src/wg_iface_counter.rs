@@ -8,12 +8,21 @@ use althea_types::WgKey;
 use regex::Regex;
 use std::collections::HashMap;
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, Eq, PartialEq, Default)]
 pub struct WgUsage {
     pub upload: u64,
     pub download: u64,
 }
 
+/// The counters of every peer on an interface along with the index of the interface they were read
+/// from. Deleting and recreating an interface restarts every counter at zero, comparing the index
+/// lets callers tell that apart from the counters simply not moving
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WgCounterSnapshot {
+    pub ifindex: u32,
+    pub peers: HashMap<WgKey, WgUsage>,
+}
+
 pub fn prepare_usage_history<S: ::std::hash::BuildHasher>(
     counters: &HashMap<WgKey, WgUsage, S>,
     usage_history: &mut HashMap<WgKey, WgUsage, S>,
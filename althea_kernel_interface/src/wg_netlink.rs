@@ -0,0 +1,875 @@
+//! A minimal in process implementation of the WireGuard generic netlink protocol. The exit programs
+//! thousands of peers every loop tick and shelling out to the `wg` binary for every operation is a large
+//! portion of that loop's runtime. This module keeps a persistent netlink socket open and speaks the
+//! same protocol `wg` does, it only implements the subset of operations the exit hot loop requires.
+//!
+//! Protocol constants are taken from linux/netlink.h, linux/genetlink.h and linux/wireguard.h
+
+use crate::exit_server_tunnel::ExitClient;
+use crate::wg_iface_counter::{WgCounterSnapshot, WgUsage};
+use crate::{KernelInterface, KernelInterfaceError as Error};
+use althea_types::WgKey;
+use ipnetwork::IpNetwork;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NLMSG_HDR_LEN: usize = 16;
+const GENL_HDR_LEN: usize = 4;
+const NLA_HDR_LEN: usize = 4;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 0x2;
+const NLMSG_DONE: u16 = 0x3;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | (1 << 14));
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+
+const WGDEVICE_A_IFINDEX: u16 = 1;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_PEERS: u16 = 8;
+
+const WGPEER_F_REMOVE_ME: u32 = 1 << 0;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 1 << 1;
+
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+/// Max number of peers placed in a single set device message, keeps messages well under
+/// the size of a page so that the kernel never has to reject them
+const PEERS_PER_MESSAGE: usize = 32;
+/// Size of the buffer used to read replies, wireguard pages dump replies to fit in this
+const RECV_BUFFER_SIZE: usize = 65536;
+/// How long we will wait for the kernel to respond before giving up
+const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    /// The persistent netlink connection, dropped and reopened whenever an operation fails
+    static ref WG_NETLINK: Mutex<Option<WgNetlink>> = Mutex::new(None);
+}
+
+/// The subset of a peer's state that the exit loop and traffic watchers care about
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WgPeerInfo {
+    pub public_key: WgKey,
+    /// None if no endpoint has been configured or learned for this peer
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNetwork>,
+    /// None if this peer has never completed a handshake
+    pub last_handshake: Option<SystemTime>,
+    /// Bytes received from this peer since it was added to the interface
+    pub rx_bytes: u64,
+    /// Bytes sent to this peer since it was added to the interface
+    pub tx_bytes: u64,
+}
+
+fn nla_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Builds a single generic netlink message, nested attributes are opened with begin_nest and
+/// must be closed in order with end_nest
+struct NlMsgBuilder {
+    buf: Vec<u8>,
+    nests: Vec<usize>,
+}
+
+impl NlMsgBuilder {
+    fn new(msg_type: u16, flags: u16, seq: u32, cmd: u8, version: u8) -> Self {
+        let mut buf = Vec::with_capacity(4096);
+        // nlmsg_len is filled in by finish()
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&msg_type.to_ne_bytes());
+        buf.extend_from_slice(&flags.to_ne_bytes());
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        // port id, zero lets the kernel fill it in
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.push(cmd);
+        buf.push(version);
+        buf.extend_from_slice(&0u16.to_ne_bytes());
+        NlMsgBuilder {
+            buf,
+            nests: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) {
+        let len = NLA_HDR_LEN + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(nla_align(self.buf.len()), 0);
+    }
+
+    fn attr_u16(&mut self, kind: u16, val: u16) {
+        self.attr(kind, &val.to_ne_bytes())
+    }
+
+    fn attr_u32(&mut self, kind: u16, val: u32) {
+        self.attr(kind, &val.to_ne_bytes())
+    }
+
+    fn attr_str(&mut self, kind: u16, val: &str) {
+        let mut data = val.as_bytes().to_vec();
+        data.push(0);
+        self.attr(kind, &data)
+    }
+
+    fn begin_nest(&mut self, kind: u16) {
+        self.nests.push(self.buf.len());
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf
+            .extend_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+    }
+
+    fn end_nest(&mut self) {
+        let start = self.nests.pop().expect("Unbalanced netlink nest");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        assert!(self.nests.is_empty());
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Splits a buffer of netlink attributes into (type, payload) pairs, stops at the first
+/// malformed attribute rather than reading past the end of the buffer
+fn parse_attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut ret = Vec::new();
+    while buf.len() >= NLA_HDR_LEN {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HDR_LEN || len > buf.len() {
+            break;
+        }
+        ret.push((kind, &buf[NLA_HDR_LEN..len]));
+        buf = &buf[nla_align(len).min(buf.len())..];
+    }
+    ret
+}
+
+/// Encodes an endpoint as the sockaddr_in or sockaddr_in6 struct the kernel expects
+fn encode_sockaddr(addr: SocketAddr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(28);
+    match addr {
+        SocketAddr::V4(a) => {
+            out.extend_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            out.extend_from_slice(&a.port().to_be_bytes());
+            out.extend_from_slice(&a.ip().octets());
+            out.extend_from_slice(&[0u8; 8]);
+        }
+        SocketAddr::V6(a) => {
+            out.extend_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+            out.extend_from_slice(&a.port().to_be_bytes());
+            out.extend_from_slice(&a.flowinfo().to_be_bytes());
+            out.extend_from_slice(&a.ip().octets());
+            out.extend_from_slice(&a.scope_id().to_ne_bytes());
+        }
+    }
+    out
+}
+
+/// Decodes the sockaddr_in or sockaddr_in6 struct the kernel reports a peer endpoint as
+fn decode_sockaddr(data: &[u8]) -> Option<SocketAddr> {
+    if data.len() < 2 {
+        return None;
+    }
+    let family = u16::from_ne_bytes([data[0], data[1]]) as i32;
+    match family {
+        libc::AF_INET if data.len() >= 8 => {
+            let port = u16::from_be_bytes([data[2], data[3]]);
+            let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        libc::AF_INET6 if data.len() >= 24 => {
+            let port = u16::from_be_bytes([data[2], data[3]]);
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&data[8..24]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        _ => None,
+    }
+}
+
+/// Parses the nested list of allowed ips of a single peer
+fn parse_allowed_ips(data: &[u8]) -> Vec<IpNetwork> {
+    let mut ret = Vec::new();
+    for (_, allowed_ip) in parse_attrs(data) {
+        let mut ip = None;
+        let mut prefix = None;
+        for (kind, data) in parse_attrs(allowed_ip) {
+            match kind {
+                WGALLOWEDIP_A_IPADDR if data.len() == 4 => {
+                    ip = Some(IpAddr::from(Ipv4Addr::new(
+                        data[0], data[1], data[2], data[3],
+                    )))
+                }
+                WGALLOWEDIP_A_IPADDR if data.len() == 16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(data);
+                    ip = Some(IpAddr::from(Ipv6Addr::from(octets)))
+                }
+                WGALLOWEDIP_A_CIDR_MASK if data.len() == 1 => prefix = Some(data[0]),
+                _ => {}
+            }
+        }
+        if let (Some(ip), Some(prefix)) = (ip, prefix) {
+            if let Ok(net) = IpNetwork::new(ip, prefix) {
+                ret.push(net);
+            }
+        }
+    }
+    ret
+}
+
+fn add_allowed_ip(msg: &mut NlMsgBuilder, ip: IpAddr, prefix: u8) {
+    // the index of each list element is ignored by the kernel
+    msg.begin_nest(0);
+    match ip {
+        IpAddr::V4(ip) => {
+            msg.attr_u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET as u16);
+            msg.attr(WGALLOWEDIP_A_IPADDR, &ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            msg.attr_u16(WGALLOWEDIP_A_FAMILY, libc::AF_INET6 as u16);
+            msg.attr(WGALLOWEDIP_A_IPADDR, &ip.octets());
+        }
+    }
+    msg.attr(WGALLOWEDIP_A_CIDR_MASK, &[prefix]);
+    msg.end_nest();
+}
+
+/// Adds a peer entry equivalent to `wg set <if> peer <key> endpoint <mesh_ip:port> allowed-ips <ips>`
+fn add_client_peer(msg: &mut NlMsgBuilder, c: &ExitClient) {
+    msg.begin_nest(0);
+    msg.attr(WGPEER_A_PUBLIC_KEY, c.public_key.as_ref());
+    msg.attr_u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS);
+    msg.attr(
+        WGPEER_A_ENDPOINT,
+        &encode_sockaddr(SocketAddr::new(c.mesh_ip, c.port)),
+    );
+    msg.begin_nest(WGPEER_A_ALLOWEDIPS);
+    let host_prefix = if c.internal_ip.is_ipv4() { 32 } else { 128 };
+    add_allowed_ip(msg, c.internal_ip, host_prefix);
+    if let Some(ipv6) = c.internet_ipv6 {
+        add_allowed_ip(msg, ipv6.ip(), ipv6.prefix());
+    }
+    msg.end_nest();
+    msg.end_nest();
+}
+
+fn add_removed_peer(msg: &mut NlMsgBuilder, key: &WgKey) {
+    msg.begin_nest(0);
+    msg.attr(WGPEER_A_PUBLIC_KEY, key.as_ref());
+    msg.attr_u32(WGPEER_A_FLAGS, WGPEER_F_REMOVE_ME);
+    msg.end_nest();
+}
+
+fn parse_u64(data: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = data.try_into().ok()?;
+    Some(u64::from_ne_bytes(bytes))
+}
+
+/// Parses the interface index out of the attributes of a get device reply, every part of a
+/// multi part dump carries it
+fn parse_device_ifindex(attrs: &[u8]) -> Option<u32> {
+    parse_attrs(attrs)
+        .into_iter()
+        .find(|(kind, data)| *kind == WGDEVICE_A_IFINDEX && data.len() == 4)
+        .map(|(_, data)| u32::from_ne_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+/// Parses the peers out of the attributes of a single get device reply
+fn parse_device_peers(attrs: &[u8]) -> Vec<WgPeerInfo> {
+    let mut ret = Vec::new();
+    for (kind, data) in parse_attrs(attrs) {
+        if kind != WGDEVICE_A_PEERS {
+            continue;
+        }
+        for (_, peer) in parse_attrs(data) {
+            let mut public_key = None;
+            let mut endpoint = None;
+            let mut allowed_ips = Vec::new();
+            let mut last_handshake = None;
+            let mut rx_bytes = 0;
+            let mut tx_bytes = 0;
+            for (kind, data) in parse_attrs(peer) {
+                match kind {
+                    WGPEER_A_RX_BYTES => rx_bytes = parse_u64(data).unwrap_or(0),
+                    WGPEER_A_TX_BYTES => tx_bytes = parse_u64(data).unwrap_or(0),
+                    WGPEER_A_ENDPOINT => endpoint = decode_sockaddr(data),
+                    WGPEER_A_ALLOWEDIPS => allowed_ips = parse_allowed_ips(data),
+                    WGPEER_A_PUBLIC_KEY if data.len() == 32 => {
+                        let mut key = [0u8; 32];
+                        key.copy_from_slice(data);
+                        public_key = Some(WgKey::from(key));
+                    }
+                    // struct __kernel_timespec, two 64 bit fields
+                    WGPEER_A_LAST_HANDSHAKE_TIME if data.len() == 16 => {
+                        let mut secs = [0u8; 8];
+                        let mut nanos = [0u8; 8];
+                        secs.copy_from_slice(&data[0..8]);
+                        nanos.copy_from_slice(&data[8..16]);
+                        let secs = i64::from_ne_bytes(secs);
+                        let nanos = i64::from_ne_bytes(nanos);
+                        if secs > 0 {
+                            last_handshake = Some(
+                                UNIX_EPOCH
+                                    + Duration::new(
+                                        secs as u64,
+                                        nanos.clamp(0, 999_999_999) as u32,
+                                    ),
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(public_key) = public_key {
+                ret.push(WgPeerInfo {
+                    public_key,
+                    endpoint,
+                    allowed_ips,
+                    last_handshake,
+                    rx_bytes,
+                    tx_bytes,
+                });
+            }
+        }
+    }
+    ret
+}
+
+struct NetlinkSocket {
+    fd: RawFd,
+}
+
+impl NetlinkSocket {
+    fn open() -> Result<Self, Error> {
+        // safety: plain libc calls, the fd is owned by the returned struct and closed on drop
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let socket = NetlinkSocket { fd };
+
+            let mut addr: libc::sockaddr_nl = mem::zeroed();
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            if libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let timeout = libc::timeval {
+                tv_sec: RECV_TIMEOUT.as_secs() as libc::time_t,
+                tv_usec: 0,
+            };
+            if libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(socket)
+        }
+    }
+
+    fn send(&self, msg: &[u8]) -> Result<(), Error> {
+        // safety: msg is a valid buffer of the given length
+        let res = unsafe { libc::send(self.fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        // safety: buf is a valid mutable buffer of the given length
+        let res =
+            unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(res as usize)
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        // safety: we own this fd and nothing else closes it
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+struct WgNetlink {
+    socket: NetlinkSocket,
+    family_id: u16,
+    seq: u32,
+}
+
+impl WgNetlink {
+    fn connect() -> Result<Self, Error> {
+        let mut conn = WgNetlink {
+            socket: NetlinkSocket::open()?,
+            family_id: GENL_ID_CTRL,
+            seq: 0,
+        };
+        let seq = conn.next_seq();
+        let mut msg = NlMsgBuilder::new(
+            GENL_ID_CTRL,
+            NLM_F_REQUEST | NLM_F_ACK,
+            seq,
+            CTRL_CMD_GETFAMILY,
+            1,
+        );
+        msg.attr_str(CTRL_ATTR_FAMILY_NAME, WG_GENL_NAME);
+        let replies = conn.transact(msg.finish(), seq)?;
+        for reply in replies {
+            if reply.len() < GENL_HDR_LEN {
+                continue;
+            }
+            for (kind, data) in parse_attrs(&reply[GENL_HDR_LEN..]) {
+                if kind == CTRL_ATTR_FAMILY_ID && data.len() >= 2 {
+                    conn.family_id = u16::from_ne_bytes([data[0], data[1]]);
+                    return Ok(conn);
+                }
+            }
+        }
+        Err(Error::RuntimeError(
+            "Wireguard generic netlink family not found, is the module loaded?".to_string(),
+        ))
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    /// Sends a message and collects the payload of every reply until the request is acked
+    /// or a dump is completed, kernel errors are returned as errors
+    fn transact(&mut self, msg: Vec<u8>, seq: u32) -> Result<Vec<Vec<u8>>, Error> {
+        self.socket.send(&msg)?;
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let len = self.socket.recv(&mut buf)?;
+            if len == 0 {
+                return Err(Error::RuntimeError("Netlink socket closed".to_string()));
+            }
+            let mut data = &buf[..len];
+            while data.len() >= NLMSG_HDR_LEN {
+                let msg_len = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                let msg_type = u16::from_ne_bytes([data[4], data[5]]);
+                let msg_seq = u32::from_ne_bytes([data[8], data[9], data[10], data[11]]);
+                if msg_len < NLMSG_HDR_LEN || msg_len > data.len() {
+                    return Err(Error::ParseError("Truncated netlink message".to_string()));
+                }
+                let payload = &data[NLMSG_HDR_LEN..msg_len];
+                data = &data[nla_align(msg_len).min(data.len())..];
+
+                // stale reply to some earlier request that timed out
+                if msg_seq != seq {
+                    continue;
+                }
+                match msg_type {
+                    NLMSG_ERROR => {
+                        if payload.len() < 4 {
+                            return Err(Error::ParseError("Short netlink error".to_string()));
+                        }
+                        let code =
+                            i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                        if code == 0 {
+                            return Ok(replies);
+                        }
+                        return Err(io::Error::from_raw_os_error(-code).into());
+                    }
+                    NLMSG_DONE => return Ok(replies),
+                    _ => replies.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+
+    fn get_peers(&mut self, ifname: &str) -> Result<Vec<WgPeerInfo>, Error> {
+        Ok(self.get_device(ifname)?.1)
+    }
+
+    /// Dumps the interface index and every peer of the given interface
+    fn get_device(&mut self, ifname: &str) -> Result<(Option<u32>, Vec<WgPeerInfo>), Error> {
+        let seq = self.next_seq();
+        let mut msg = NlMsgBuilder::new(
+            self.family_id,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_DUMP,
+            seq,
+            WG_CMD_GET_DEVICE,
+            WG_GENL_VERSION,
+        );
+        msg.attr_str(WGDEVICE_A_IFNAME, ifname);
+        let mut ifindex = None;
+        let mut peers = Vec::new();
+        for reply in self.transact(msg.finish(), seq)? {
+            if reply.len() >= GENL_HDR_LEN {
+                ifindex = ifindex.or_else(|| parse_device_ifindex(&reply[GENL_HDR_LEN..]));
+                peers.extend(parse_device_peers(&reply[GENL_HDR_LEN..]));
+            }
+        }
+        Ok((ifindex, peers))
+    }
+
+    fn set_device(&mut self, msg: NlMsgBuilder, seq: u32) -> Result<(), Error> {
+        self.transact(msg.finish(), seq)?;
+        Ok(())
+    }
+
+    fn new_set_device(&mut self, ifname: &str) -> (NlMsgBuilder, u32) {
+        let seq = self.next_seq();
+        let mut msg = NlMsgBuilder::new(
+            self.family_id,
+            NLM_F_REQUEST | NLM_F_ACK,
+            seq,
+            WG_CMD_SET_DEVICE,
+            WG_GENL_VERSION,
+        );
+        msg.attr_str(WGDEVICE_A_IFNAME, ifname);
+        (msg, seq)
+    }
+
+    fn set_listen_config(
+        &mut self,
+        ifname: &str,
+        listen_port: u16,
+        private_key: &WgKey,
+    ) -> Result<(), Error> {
+        let (mut msg, seq) = self.new_set_device(ifname);
+        msg.attr(WGDEVICE_A_PRIVATE_KEY, private_key.as_ref());
+        msg.attr_u16(WGDEVICE_A_LISTEN_PORT, listen_port);
+        self.set_device(msg, seq)
+    }
+
+    /// Adds or updates the given clients, replacing their endpoint and allowed ips
+    fn upsert_peers<'a>(
+        &mut self,
+        ifname: &str,
+        clients: impl IntoIterator<Item = &'a ExitClient>,
+    ) -> Result<(), Error> {
+        let clients: Vec<&ExitClient> = clients.into_iter().collect();
+        for chunk in clients.chunks(PEERS_PER_MESSAGE) {
+            let (mut msg, seq) = self.new_set_device(ifname);
+            msg.begin_nest(WGDEVICE_A_PEERS);
+            for c in chunk {
+                add_client_peer(&mut msg, c);
+            }
+            msg.end_nest();
+            trace!("Sending {} byte wg netlink update", msg.len());
+            self.set_device(msg, seq)?;
+        }
+        Ok(())
+    }
+
+    fn remove_peers(&mut self, ifname: &str, keys: &[WgKey]) -> Result<(), Error> {
+        for chunk in keys.chunks(PEERS_PER_MESSAGE) {
+            let (mut msg, seq) = self.new_set_device(ifname);
+            msg.begin_nest(WGDEVICE_A_PEERS);
+            for key in chunk {
+                warn!("Removing no longer authorized peer {}", key);
+                add_removed_peer(&mut msg, key);
+            }
+            msg.end_nest();
+            self.set_device(msg, seq)?;
+        }
+        Ok(())
+    }
+
+    /// Equivalent to the shell based set_exit_wg_config, sets the listen port and key, then adds or
+    /// updates every client and finally removes any peers that are no longer clients
+    fn set_exit_config(
+        &mut self,
+        ifname: &str,
+        clients: &HashSet<ExitClient>,
+        listen_port: u16,
+        private_key: &WgKey,
+    ) -> Result<(), Error> {
+        self.set_listen_config(ifname, listen_port, private_key)?;
+        self.upsert_peers(ifname, clients)?;
+
+        let client_pubkeys: HashSet<WgKey> = clients.iter().map(|c| c.public_key).collect();
+        let stale: Vec<WgKey> = self
+            .get_peers(ifname)?
+            .into_iter()
+            .map(|p| p.public_key)
+            .filter(|k| !client_pubkeys.contains(k))
+            .collect();
+        info!("{} has {} stale peers", ifname, stale.len());
+        self.remove_peers(ifname, &stale)
+    }
+}
+
+/// Runs an operation against the persistent connection, opening it if required. Any failure drops the
+/// connection so that the next call starts from a clean socket
+fn with_wg_netlink<T>(op: impl FnOnce(&mut WgNetlink) -> Result<T, Error>) -> Result<T, Error> {
+    let mut conn = WG_NETLINK.lock().unwrap();
+    if conn.is_none() {
+        *conn = Some(WgNetlink::connect()?);
+    }
+    let res = op(conn.as_mut().unwrap());
+    if res.is_err() {
+        *conn = None;
+    }
+    res
+}
+
+impl dyn KernelInterface {
+    /// Netlink version of set_exit_wg_config, falls back to the shell implementation if the netlink
+    /// path fails for any reason
+    pub fn set_exit_wg_config_netlink(
+        &self,
+        clients: &HashSet<ExitClient>,
+        listen_port: u16,
+        private_key_path: &str,
+        if_name: &str,
+    ) -> Result<(), Error> {
+        let res = std::fs::read_to_string(private_key_path)
+            .map_err(Error::from)
+            .and_then(|key| Ok(key.trim().parse::<WgKey>()?))
+            .and_then(|key| {
+                with_wg_netlink(|conn| conn.set_exit_config(if_name, clients, listen_port, &key))
+            });
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("Wg netlink setup of {if_name} failed with {e}, falling back to wg binary");
+                self.set_exit_wg_config(clients, listen_port, private_key_path, if_name)
+            }
+        }
+    }
+
+    /// Applies a precomputed change to the peers of an exit interface, only the given clients are
+    /// touched so peers that are already correct see no churn. Falls back to the shell implementation
+    /// if the netlink path fails for any reason
+    pub fn update_exit_wg_peers_netlink(
+        &self,
+        upsert: &[ExitClient],
+        remove: &[WgKey],
+        listen_port: u16,
+        private_key_path: &str,
+        if_name: &str,
+    ) -> Result<(), Error> {
+        let res = std::fs::read_to_string(private_key_path)
+            .map_err(Error::from)
+            .and_then(|key| Ok(key.trim().parse::<WgKey>()?))
+            .and_then(|key| {
+                with_wg_netlink(|conn| {
+                    conn.set_listen_config(if_name, listen_port, &key)?;
+                    conn.upsert_peers(if_name, upsert)?;
+                    conn.remove_peers(if_name, remove)
+                })
+            });
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("Wg netlink update of {if_name} failed with {e}, falling back to wg binary");
+                self.update_exit_wg_peers(upsert, remove, listen_port, private_key_path, if_name)
+            }
+        }
+    }
+
+    /// Dumps the configured state of every peer on an interface, falls back to parsing `wg show dump`
+    /// if the netlink path fails for any reason
+    pub fn get_wg_peers_netlink(&self, ifname: &str) -> Result<Vec<WgPeerInfo>, Error> {
+        match with_wg_netlink(|conn| conn.get_peers(ifname)) {
+            Ok(peers) => Ok(peers),
+            Err(e) => {
+                warn!(
+                    "Wg netlink peer dump of {ifname} failed with {e}, falling back to wg binary"
+                );
+                self.get_wg_peers_dump(ifname)
+            }
+        }
+    }
+
+    /// Netlink version of get_last_active_handshake_time, falls back to the shell implementation if
+    /// the netlink path fails for any reason
+    pub fn get_last_active_handshake_time_netlink(
+        &self,
+        ifname: &str,
+    ) -> Result<Vec<(WgKey, SystemTime)>, Error> {
+        match with_wg_netlink(|conn| conn.get_peers(ifname)) {
+            Ok(peers) => Ok(peers
+                .into_iter()
+                .filter_map(|p| p.last_handshake.map(|t| (p.public_key, t)))
+                .collect()),
+            Err(e) => {
+                warn!("Wg netlink handshake dump of {ifname} failed with {e}, falling back to wg binary");
+                self.get_last_active_handshake_time(ifname)
+            }
+        }
+    }
+
+    /// Reads the byte counters of every peer on an interface in a single netlink dump, along with the
+    /// interface index so that a recreated interface can be told apart from one that saw no traffic.
+    /// Falls back to parsing `wg show` if the netlink path fails for any reason
+    pub fn read_wg_counter_snapshot(&self, ifname: &str) -> Result<WgCounterSnapshot, Error> {
+        // a fresh socket rather than the persistent one, netlink sockets belong to the network
+        // namespace they were opened in and the integration tests run each client in its own
+        let res = WgNetlink::connect().and_then(|mut conn| conn.get_device(ifname));
+        match res {
+            Ok((Some(ifindex), peers)) => Ok(WgCounterSnapshot {
+                ifindex,
+                peers: peers
+                    .into_iter()
+                    .map(|p| {
+                        (
+                            p.public_key,
+                            WgUsage {
+                                upload: p.tx_bytes,
+                                download: p.rx_bytes,
+                            },
+                        )
+                    })
+                    .collect::<HashMap<WgKey, WgUsage>>(),
+            }),
+            res => {
+                if let Err(e) = res {
+                    warn!("Wg netlink counter dump of {ifname} failed with {e}, falling back to wg binary");
+                }
+                let peers = self.read_wg_counters(ifname)?;
+                Ok(WgCounterSnapshot {
+                    ifindex: self.get_ifindex(ifname)? as u32,
+                    peers,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_attr_encoding() {
+        let mut msg = NlMsgBuilder::new(0x20, NLM_F_REQUEST, 7, WG_CMD_SET_DEVICE, 1);
+        msg.attr_str(WGDEVICE_A_IFNAME, "wg_exit");
+        msg.begin_nest(WGDEVICE_A_PEERS);
+        msg.attr(WGPEER_A_FLAGS, &[1, 2, 3]);
+        msg.end_nest();
+        let out = msg.finish();
+
+        assert_eq!(out.len() % 4, 0);
+        assert_eq!(
+            u32::from_ne_bytes([out[0], out[1], out[2], out[3]]) as usize,
+            out.len()
+        );
+        let attrs = parse_attrs(&out[NLMSG_HDR_LEN + GENL_HDR_LEN..]);
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs[0], (WGDEVICE_A_IFNAME, &b"wg_exit\0"[..]));
+        assert_eq!(attrs[1].0, WGDEVICE_A_PEERS);
+        // the padding of the nested attribute is not part of its payload
+        assert_eq!(
+            parse_attrs(attrs[1].1),
+            vec![(WGPEER_A_FLAGS, &[1u8, 2, 3][..])]
+        );
+    }
+
+    #[test]
+    fn test_parse_device_peers() {
+        let key: WgKey = "88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs="
+            .parse()
+            .unwrap();
+        let mut msg = NlMsgBuilder::new(0x20, 0, 1, WG_CMD_GET_DEVICE, 1);
+        msg.begin_nest(WGDEVICE_A_PEERS);
+        msg.begin_nest(0);
+        msg.attr(WGPEER_A_PUBLIC_KEY, key.as_ref());
+        msg.attr(
+            WGPEER_A_ENDPOINT,
+            &encode_sockaddr("[fd00::1337]:59999".parse().unwrap()),
+        );
+        msg.begin_nest(WGPEER_A_ALLOWEDIPS);
+        add_allowed_ip(&mut msg, "172.168.1.5".parse().unwrap(), 32);
+        add_allowed_ip(&mut msg, "2001:db8:1::".parse().unwrap(), 64);
+        msg.end_nest();
+        let mut timespec = 1_536_936_247i64.to_ne_bytes().to_vec();
+        timespec.extend_from_slice(&0i64.to_ne_bytes());
+        msg.attr(WGPEER_A_LAST_HANDSHAKE_TIME, &timespec);
+        msg.attr(WGPEER_A_RX_BYTES, &15_403_040u64.to_ne_bytes());
+        msg.attr(WGPEER_A_TX_BYTES, &(u64::MAX - 5).to_ne_bytes());
+        msg.end_nest();
+        msg.end_nest();
+        msg.attr_u32(WGDEVICE_A_IFINDEX, 12);
+        let out = msg.finish();
+
+        let attrs = &out[NLMSG_HDR_LEN + GENL_HDR_LEN..];
+        let peers = parse_device_peers(attrs);
+        assert_eq!(
+            peers,
+            vec![WgPeerInfo {
+                public_key: key,
+                endpoint: Some("[fd00::1337]:59999".parse().unwrap()),
+                allowed_ips: vec![
+                    "172.168.1.5/32".parse().unwrap(),
+                    "2001:db8:1::/64".parse().unwrap()
+                ],
+                last_handshake: Some(UNIX_EPOCH + Duration::from_secs(1_536_936_247)),
+                rx_bytes: 15_403_040,
+                tx_bytes: u64::MAX - 5,
+            }]
+        );
+        assert_eq!(parse_device_ifindex(attrs), Some(12));
+    }
+
+    #[test]
+    fn test_parse_attrs_truncated() {
+        // claims to be longer than the buffer
+        let buf = [40u8, 0, 1, 0, 0, 0, 0, 0];
+        assert!(parse_attrs(&buf).is_empty());
+    }
+}
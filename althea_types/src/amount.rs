@@ -0,0 +1,167 @@
+//! A typed wrapper for amounts of the 18 decimal tokens we pay in (eth, xdai, althea). Raw Uint256 values are
+//! passed around all over the payment code and nothing stops a gwei value being added to a wei value, an Amount
+//! is always stored in wei and can only be built by naming the unit the input is in.
+
+use num256::Uint256;
+use num_traits::{CheckedAdd, CheckedMul, CheckedSub, Zero};
+use std::fmt;
+use std::fmt::Display;
+
+/// Units an Amount can be created from or displayed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AmountUnit {
+    Wei,
+    Gwei,
+    Ether,
+}
+
+impl AmountUnit {
+    /// Number of decimal places between wei and this unit
+    pub fn decimals(self) -> u32 {
+        match self {
+            AmountUnit::Wei => 0,
+            AmountUnit::Gwei => 9,
+            AmountUnit::Ether => 18,
+        }
+    }
+
+    /// Number of wei in one of this unit
+    pub fn wei_per_unit(self) -> Uint256 {
+        10u128.pow(self.decimals()).into()
+    }
+}
+
+/// An amount of an 18 decimal token, always stored in wei
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(Uint256);
+
+impl Amount {
+    pub fn zero() -> Amount {
+        Amount(Uint256::zero())
+    }
+
+    pub fn from_wei(wei: Uint256) -> Amount {
+        Amount(wei)
+    }
+
+    /// Converts a value in the given unit to an Amount, None if the result does not fit in a Uint256
+    pub fn new(value: Uint256, unit: AmountUnit) -> Option<Amount> {
+        value.checked_mul(&unit.wei_per_unit()).map(Amount)
+    }
+
+    pub fn from_gwei(gwei: u64) -> Amount {
+        // a u64 times 10^9 can not overflow a Uint256
+        Amount::new(gwei.into(), AmountUnit::Gwei).unwrap()
+    }
+
+    pub fn from_ether(ether: u64) -> Amount {
+        // a u64 times 10^18 can not overflow a Uint256
+        Amount::new(ether.into(), AmountUnit::Ether).unwrap()
+    }
+
+    /// The raw value in wei, for handing to web30 and other interfaces that take a Uint256
+    pub fn wei(&self) -> Uint256 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn checked_add(&self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(&other.0).map(Amount)
+    }
+
+    pub fn checked_sub(&self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(&other.0).map(Amount)
+    }
+
+    /// Returns zero instead of underflowing
+    pub fn saturating_sub(&self, other: Amount) -> Amount {
+        self.checked_sub(other).unwrap_or_else(Amount::zero)
+    }
+
+    /// Multiplies by a unitless quantity, for example a gas price by an amount of gas
+    pub fn checked_mul(&self, quantity: u64) -> Option<Amount> {
+        self.0.checked_mul(&quantity.into()).map(Amount)
+    }
+
+    /// Formats this amount as a decimal number of the given unit, trailing zeros are dropped
+    /// so one and a half ether displays as 1.5
+    pub fn display_as(&self, unit: AmountUnit) -> String {
+        let per_unit = unit.wei_per_unit();
+        let whole = self.0 / per_unit;
+        let fraction = self.0 % per_unit;
+        if fraction.is_zero() {
+            return whole.to_string();
+        }
+        let fraction = format!(
+            "{:0>width$}",
+            fraction.to_string(),
+            width = unit.decimals() as usize
+        );
+        format!("{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} wei", self.0)
+    }
+}
+
+impl From<Amount> for Uint256 {
+    fn from(value: Amount) -> Self {
+        value.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_units() {
+        assert_eq!(Amount::from_gwei(1).wei(), 1_000_000_000u64.into());
+        assert_eq!(
+            Amount::from_ether(2).wei(),
+            2_000_000_000_000_000_000u128.into()
+        );
+        assert_eq!(
+            Amount::new(21000u32.into(), AmountUnit::Gwei),
+            Some(Amount::from_gwei(21000))
+        );
+        let max = Uint256::from(u128::MAX) * Uint256::from(u128::MAX);
+        assert_eq!(Amount::new(max, AmountUnit::Gwei), None);
+    }
+
+    #[test]
+    fn test_amount_arithmetic() {
+        let gas_price = Amount::from_gwei(10);
+        let cost = gas_price.checked_mul(21000).unwrap();
+        assert_eq!(cost.wei(), 210_000_000_000_000u64.into());
+        assert_eq!(cost.checked_sub(Amount::from_ether(1)), None);
+        assert_eq!(cost.saturating_sub(Amount::from_ether(1)), Amount::zero());
+        assert_eq!(
+            cost.checked_add(cost).unwrap(),
+            gas_price.checked_mul(42000).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_amount_display() {
+        let amount = Amount::from_wei(1_500_000_000_000_000_000u128.into());
+        assert_eq!(amount.display_as(AmountUnit::Ether), "1.5");
+        assert_eq!(amount.display_as(AmountUnit::Gwei), "1500000000");
+        assert_eq!(
+            Amount::from_gwei(21).display_as(AmountUnit::Ether),
+            "0.000000021"
+        );
+        assert_eq!(Amount::from_gwei(1).to_string(), "1000000000 wei");
+        assert_eq!(
+            serde_json::to_string(&Amount::from_gwei(1)).unwrap(),
+            serde_json::to_string(&Uint256::from(1_000_000_000u64)).unwrap()
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Audit records of antenna forwarding sessions. When the user allows it the forwarding client keeps
+//! who asked for a session, which antenna it reached, how long it lasted and how many bytes moved, and
+//! hands the record to the operator server signed with the router's eth key. Payloads are never part
+//! of a record, only their sizes
+
+use crate::error::AltheaTypesError;
+use crate::wg_key::WgKey;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+use std::net::IpAddr;
+
+/// Metadata of one antenna forwarding session
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AntennaSessionRecord {
+    /// Random id of this record, the operator server can drop records it has already seen
+    pub id: u64,
+    /// The router that forwarded the antenna
+    pub router: WgKey,
+    /// Who asked the operator server for the session, None for older servers that don't say
+    pub requested_by: Option<String>,
+    pub antenna_ip: IpAddr,
+    pub antenna_port: u16,
+    /// Unix timestamps in seconds
+    pub start: u64,
+    pub end: u64,
+    /// Bytes written to and read from the antenna over all streams of the session
+    pub bytes_to_antenna: u64,
+    pub bytes_from_antenna: u64,
+    /// Connections opened to the antenna
+    pub streams: u64,
+    /// Why the session could not be set up, if it couldn't
+    pub error: Option<String>,
+}
+
+impl AntennaSessionRecord {
+    pub fn duration_secs(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+
+    fn signing_message(&self) -> Vec<u8> {
+        format!(
+            "althea antenna session {}:{}:{}:{}:{}:{}:{}:{}:{}:{}:{}",
+            self.id,
+            self.router,
+            self.requested_by.as_deref().unwrap_or(""),
+            self.antenna_ip,
+            self.antenna_port,
+            self.start,
+            self.end,
+            self.bytes_to_antenna,
+            self.bytes_from_antenna,
+            self.streams,
+            self.error.as_deref().unwrap_or("")
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(self, key: PrivateKey) -> SignedAntennaSessionRecord {
+        let signature = key.sign_ethereum_msg(&self.signing_message());
+        SignedAntennaSessionRecord {
+            record: self,
+            signature,
+        }
+    }
+}
+
+/// A session record and the router's signature over it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedAntennaSessionRecord {
+    pub record: AntennaSessionRecord,
+    pub signature: Signature,
+}
+
+impl SignedAntennaSessionRecord {
+    /// Returns the address that signed this record, which should be the eth address of the router
+    pub fn signer(&self) -> Result<Address, AltheaTypesError> {
+        let hash = get_ethereum_msg_hash(&self.record.signing_message());
+        match self.signature.recover(&hash) {
+            Ok(address) => Ok(address),
+            Err(e) => Err(AltheaTypesError::AntennaSessionError(format!(
+                "Invalid antenna session signature {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_antenna_session_signature() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let record = AntennaSessionRecord {
+            id: 7,
+            router: "V9I9yrxAqFqLV+9GeT5pnXPwk4Cxgfvl30Fv8khVGsM="
+                .parse()
+                .unwrap(),
+            requested_by: Some("installer@example.com".to_string()),
+            antenna_ip: "192.168.1.20".parse().unwrap(),
+            antenna_port: 443,
+            start: 1_700_000_000,
+            end: 1_700_000_600,
+            bytes_to_antenna: 20_000,
+            bytes_from_antenna: 1_500_000,
+            streams: 12,
+            error: None,
+        };
+        assert_eq!(record.duration_secs(), 600);
+        let signed = record.sign(key);
+        assert_eq!(signed.signer().unwrap(), key.to_address());
+
+        let json = serde_json::to_string(&signed).unwrap();
+        let decoded: SignedAntennaSessionRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, signed);
+
+        // under reporting the bytes moved changes the signer
+        let mut tampered = decoded;
+        tampered.record.bytes_from_antenna = 0;
+        assert_ne!(tampered.signer().ok(), Some(key.to_address()));
+    }
+}
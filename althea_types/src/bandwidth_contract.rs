@@ -0,0 +1,174 @@
+//! Bandwidth contracts let an operator selling fixed rate plans have the router enforce the plan itself
+//! rather than relying on shaping at the exit. The operator signs a contract naming the router, the plan
+//! rates, an optional data cap and when the plan ends, and hands it to the router in the checkin response.
+//! The router checks the signature against its own operator address, shapes its own traffic to the plan
+//! and reports back in following checkins what it applied and how much of the cap is used.
+
+use crate::error::AltheaTypesError;
+use crate::wg_key::WgKey;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+
+/// The contents of a bandwidth contract, this is what the operator signs. Rates are in mbps
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct BandwidthContract {
+    /// Set by the operator, a contract with a new id replaces the current one
+    pub id: u64,
+    /// The router this contract is for, routers refuse contracts for any other key
+    pub router: WgKey,
+    /// Plan rate to the users of this router, None is unlimited
+    pub download_mbps: Option<usize>,
+    /// Plan rate from the users of this router, None is unlimited
+    pub upload_mbps: Option<usize>,
+    /// Bytes up and down together the plan allows between start and expiry, None is uncapped
+    pub data_cap: Option<u64>,
+    /// Rate both directions are shaped to once the data cap is used up
+    pub over_cap_mbps: usize,
+    /// Unix timestamp in seconds the plan starts at, usage before it doesn't count against the cap
+    pub start: u64,
+    /// Unix timestamp in seconds the plan ends at, after which the router goes back to unshaped
+    pub expiry: u64,
+}
+
+/// Where a contract is at, as reported back to the operator
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum BandwidthContractState {
+    /// Not started yet
+    Pending,
+    /// Shaped to the plan rates
+    Active,
+    /// The data cap is used up, shaped to over_cap_mbps
+    Capped,
+    Expired,
+}
+
+impl BandwidthContract {
+    fn signing_message(&self) -> Vec<u8> {
+        format!(
+            "althea bandwidth contract {}:{}:{}:{}:{}:{}:{}:{}",
+            self.id,
+            self.router,
+            self.download_mbps.unwrap_or(0),
+            self.upload_mbps.unwrap_or(0),
+            self.data_cap.unwrap_or(0),
+            self.over_cap_mbps,
+            self.start,
+            self.expiry
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(self, key: PrivateKey) -> SignedBandwidthContract {
+        let signature = key.sign_ethereum_msg(&self.signing_message());
+        SignedBandwidthContract {
+            contract: self,
+            signature,
+        }
+    }
+
+    pub fn state(&self, now: u64, used_bytes: u64) -> BandwidthContractState {
+        if now >= self.expiry {
+            BandwidthContractState::Expired
+        } else if now < self.start {
+            BandwidthContractState::Pending
+        } else if self.data_cap.map(|cap| used_bytes >= cap).unwrap_or(false) {
+            BandwidthContractState::Capped
+        } else {
+            BandwidthContractState::Active
+        }
+    }
+
+    /// The (download, upload) limits in mbps to shape to in the given state, None is unlimited
+    pub fn limits(&self, state: BandwidthContractState) -> (Option<usize>, Option<usize>) {
+        match state {
+            BandwidthContractState::Active => (self.download_mbps, self.upload_mbps),
+            BandwidthContractState::Capped => (Some(self.over_cap_mbps), Some(self.over_cap_mbps)),
+            BandwidthContractState::Pending | BandwidthContractState::Expired => (None, None),
+        }
+    }
+}
+
+/// A bandwidth contract and the operator signature over it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedBandwidthContract {
+    pub contract: BandwidthContract,
+    pub signature: Signature,
+}
+
+impl SignedBandwidthContract {
+    /// Returns the address that signed this contract
+    pub fn signer(&self) -> Result<Address, AltheaTypesError> {
+        let hash = get_ethereum_msg_hash(&self.contract.signing_message());
+        match self.signature.recover(&hash) {
+            Ok(address) => Ok(address),
+            Err(e) => Err(AltheaTypesError::BandwidthContractError(format!(
+                "Invalid bandwidth contract signature {e}"
+            ))),
+        }
+    }
+}
+
+/// What the router is doing about its contract, sent with every operator checkin while it has one
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BandwidthContractReport {
+    /// The contract being enforced, None if the router has none
+    pub id: Option<u64>,
+    pub state: Option<BandwidthContractState>,
+    /// Bytes up and down together since the contract started
+    pub used_bytes: u64,
+    /// The limits in mbps currently applied by the router
+    pub applied_download_mbps: Option<usize>,
+    pub applied_upload_mbps: Option<usize>,
+    /// If the limits are applied and the measured client throughput is within them
+    pub compliant: bool,
+    /// Why the last contract sent was refused or the limits couldn't be applied
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bandwidth_contract() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let contract = BandwidthContract {
+            id: 3,
+            router: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            download_mbps: Some(50),
+            upload_mbps: Some(10),
+            data_cap: Some(1_000_000),
+            over_cap_mbps: 1,
+            start: 1000,
+            expiry: 2000,
+        };
+        let signed = contract.sign(key);
+        assert_eq!(signed.signer().unwrap(), key.to_address());
+        let mut tampered = signed.clone();
+        tampered.contract.download_mbps = Some(500);
+        assert_ne!(tampered.signer().ok(), Some(key.to_address()));
+
+        assert_eq!(contract.state(999, 0), BandwidthContractState::Pending);
+        assert_eq!(contract.state(1000, 0), BandwidthContractState::Active);
+        assert_eq!(
+            contract.limits(contract.state(1500, 999_999)),
+            (Some(50), Some(10))
+        );
+        assert_eq!(
+            contract.limits(contract.state(1500, 1_000_000)),
+            (Some(1), Some(1))
+        );
+        assert_eq!(contract.state(2000, 0), BandwidthContractState::Expired);
+        assert_eq!(
+            contract.limits(BandwidthContractState::Expired),
+            (None, None)
+        );
+    }
+}
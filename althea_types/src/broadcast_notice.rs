@@ -0,0 +1,120 @@
+//! Notices an operator broadcasts to every router it manages, things like "maintenance tonight at 2am".
+//! The operator signs each notice and hands it out in checkin responses, routers that can't reach the
+//! operator server pick notices up from their neighbors. Since a notice may have passed through any number
+//! of routers before it arrives, each router checks the signature against its own operator address.
+
+use crate::error::AltheaTypesError;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+
+/// Notices longer than this are refused, they are shown as is in the router dashboard
+pub const MAX_NOTICE_LEN: usize = 512;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastNoticeLevel {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// The contents of a notice, this is what the operator signs
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct BroadcastNotice {
+    /// Set by the operator, routers show each id once
+    pub id: u64,
+    /// The operator the notice is from, routers managed by anyone else ignore it
+    pub operator: Address,
+    pub level: BroadcastNoticeLevel,
+    pub message: String,
+    /// Unix timestamp in seconds after which the notice is taken down and no longer passed on
+    pub expiry: u64,
+}
+
+impl BroadcastNotice {
+    fn signing_message(&self) -> Vec<u8> {
+        format!(
+            "althea broadcast notice {}:{}:{:?}:{}:{}",
+            self.id, self.operator, self.level, self.expiry, self.message
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(self, key: PrivateKey) -> SignedBroadcastNotice {
+        let signature = key.sign_ethereum_msg(&self.signing_message());
+        SignedBroadcastNotice {
+            notice: self,
+            signature,
+        }
+    }
+}
+
+/// A notice and the operator signature over it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedBroadcastNotice {
+    pub notice: BroadcastNotice,
+    pub signature: Signature,
+}
+
+impl SignedBroadcastNotice {
+    /// Checks that the notice was signed by the operator it names and is within MAX_NOTICE_LEN
+    pub fn verify(&self) -> Result<(), AltheaTypesError> {
+        if self.notice.message.chars().count() > MAX_NOTICE_LEN {
+            return Err(AltheaTypesError::BroadcastNoticeError(format!(
+                "Notice {} is longer than {} characters",
+                self.notice.id, MAX_NOTICE_LEN
+            )));
+        }
+        let hash = get_ethereum_msg_hash(&self.notice.signing_message());
+        match self.signature.recover(&hash) {
+            Ok(address) if address == self.notice.operator => Ok(()),
+            Ok(_) => Err(AltheaTypesError::BroadcastNoticeError(format!(
+                "Notice {} was not signed by {}",
+                self.notice.id, self.notice.operator
+            ))),
+            Err(e) => Err(AltheaTypesError::BroadcastNoticeError(format!(
+                "Invalid notice signature {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broadcast_notice_signature() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let notice = BroadcastNotice {
+            id: 1,
+            operator: key.to_address(),
+            level: BroadcastNoticeLevel::Warning,
+            message: "Maintenance tonight at 2am".to_string(),
+            expiry: 1_700_000_000,
+        };
+        let signed = notice.clone().sign(key);
+        assert!(signed.verify().is_ok());
+
+        let mut tampered = signed;
+        tampered.notice.message = "Send your keys to the operator".to_string();
+        assert!(tampered.verify().is_err());
+
+        // signed by someone other than the operator it names
+        let other: PrivateKey =
+            "0x0000000000000000000000000000000000000000000000000000000000000002"
+                .parse()
+                .unwrap();
+        assert!(notice.clone().sign(other).verify().is_err());
+
+        let long = BroadcastNotice {
+            message: "x".repeat(MAX_NOTICE_LEN + 1),
+            ..notice
+        };
+        assert!(long.sign(key).verify().is_err());
+    }
+}
@@ -0,0 +1,26 @@
+//! What a firmware build can do and has turned on, put together at startup. Remote debugging can't tell
+//! from a version string which features a build was compiled with or which subsystems a router's config
+//! enables, so routers and exits report this on /capabilities and in their operator checkins.
+
+use crate::interop::SystemChain;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    pub version: String,
+    pub git_hash: String,
+    /// client, gateway or exit
+    pub role: String,
+    /// Cargo features the build was compiled with
+    pub build_features: Vec<String>,
+    /// Optional subsystems by name and whether the config turns them on
+    pub subsystems: BTreeMap<String, bool>,
+    /// Exit protocol versions this build speaks
+    pub exit_protocols: Vec<u32>,
+    /// Chains this build pays and withdraws on
+    pub chains: Vec<SystemChain>,
+    /// What programs exit enforcement into the kernel, None on routers
+    pub enforcement_backend: Option<String>,
+    /// nftables or iptables
+    pub firewall: String,
+}
@@ -6,12 +6,36 @@ use std::fmt::Result as FormatResult;
 #[derive(Clone, Debug)]
 pub enum AltheaTypesError {
     WgParseError(DecodeError),
+    VoucherError(String),
+    HeartbeatError(String),
+    SealedBoxError(String),
+    IdentityParseError(String),
+    ReconciliationError(String),
+    SpeedTestError(String),
+    ExitRegistryError(String),
+    SignupProofError(String),
+    AntennaSessionError(String),
+    BandwidthContractError(String),
+    BroadcastNoticeError(String),
+    ExtensionError(String),
 }
 
 impl fmt::Display for AltheaTypesError {
     fn fmt(&self, f: &mut fmt::Formatter) -> FormatResult {
         match self {
             AltheaTypesError::WgParseError(val) => write!(f, "Failed to parse WgKey with {val}"),
+            AltheaTypesError::VoucherError(val) => write!(f, "{val}"),
+            AltheaTypesError::HeartbeatError(val) => write!(f, "{val}"),
+            AltheaTypesError::SealedBoxError(val) => write!(f, "{val}"),
+            AltheaTypesError::IdentityParseError(val) => write!(f, "{val}"),
+            AltheaTypesError::ReconciliationError(val) => write!(f, "{val}"),
+            AltheaTypesError::SpeedTestError(val) => write!(f, "{val}"),
+            AltheaTypesError::ExitRegistryError(val) => write!(f, "{val}"),
+            AltheaTypesError::SignupProofError(val) => write!(f, "{val}"),
+            AltheaTypesError::AntennaSessionError(val) => write!(f, "{val}"),
+            AltheaTypesError::BandwidthContractError(val) => write!(f, "{val}"),
+            AltheaTypesError::BroadcastNoticeError(val) => write!(f, "{val}"),
+            AltheaTypesError::ExtensionError(val) => write!(f, "{val}"),
         }
     }
 }
@@ -0,0 +1,124 @@
+//! Types for bootstrapping a new exit from an existing member of its cluster. Every exit in a cluster shares
+//! the wg_exit keys, ports and pricing so that clients can roam between them, a replacement exit fetches these
+//! from a member over the member's admin api instead of having them copied by hand.
+//!
+//! The request names the new exit's mesh wg key, the member only answers if that key is registered as an
+//! exit on chain and seals the config to it, so only the new exit can open the reply. The new exit in turn
+//! only accepts a reply sealed by a key registered as an exit.
+
+use crate::error::AltheaTypesError;
+use crate::regions::{CountryPrice, Regions};
+use crate::sealed_box::{open_json, seal_json, SealHeader};
+use crate::wg_key::WgKey;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+
+/// The settings every exit in a cluster shares
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExitClusterConfig {
+    /// The wg_exit keypair, clients build their tunnel to the public key
+    pub wg_public_key: WgKey,
+    pub wg_private_key: WgKey,
+    pub exit_hello_port: u16,
+    pub wg_tunnel_port: u16,
+    pub wg_v2_tunnel_port: u16,
+    pub exit_price: u64,
+    pub own_internal_ip: Ipv4Addr,
+    pub netmask: u8,
+    pub client_subnet_size: Option<u8>,
+    pub enable_enforcement: bool,
+    pub enforcement_exempt_destinations: Vec<String>,
+    pub min_client_version: Option<String>,
+    pub min_client_version_deadline: Option<u64>,
+    pub tunnel_mtu: usize,
+    pub allowed_countries: HashSet<Regions>,
+    #[serde(default)]
+    pub country_prices: Vec<CountryPrice>,
+}
+
+/// Sent by the new exit to the member it bootstraps from
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ExitClusterBootstrapRequest {
+    /// The new exit's mesh wg key, the reply is sealed to it
+    pub wg_key: WgKey,
+}
+
+/// Wrapper for secure box containing an exit cluster config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct EncryptedExitClusterConfig {
+    /// The mesh wg key of the member that sealed this
+    pub pubkey: WgKey,
+    pub nonce: [u8; 24],
+    pub encrypted_config: Vec<u8>,
+    #[serde(flatten)]
+    pub header: SealHeader,
+}
+
+impl EncryptedExitClusterConfig {
+    pub fn seal(
+        config: &ExitClusterConfig,
+        our_publickey: WgKey,
+        our_secretkey: &SecretKey,
+        their_publickey: &PublicKey,
+    ) -> EncryptedExitClusterConfig {
+        let sealed = seal_json(config, their_publickey, our_secretkey);
+        EncryptedExitClusterConfig {
+            pubkey: our_publickey,
+            nonce: sealed.nonce,
+            encrypted_config: sealed.ciphertext,
+            header: sealed.header,
+        }
+    }
+
+    pub fn open(&self, our_secretkey: &SecretKey) -> Result<ExitClusterConfig, AltheaTypesError> {
+        let (config, _) = open_json(
+            &self.header,
+            &self.nonce,
+            &self.encrypted_config,
+            &self.pubkey.into(),
+            &[our_secretkey.clone()],
+        )?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sodiumoxide::crypto::box_;
+
+    #[test]
+    fn test_exit_cluster_config_seal() {
+        let (member_public, member_secret) = box_::gen_keypair();
+        let (new_public, new_secret) = box_::gen_keypair();
+        let (_, other_secret) = box_::gen_keypair();
+        let config = ExitClusterConfig {
+            wg_public_key: WgKey::from(member_public.0),
+            wg_private_key: WgKey::from(member_secret.0),
+            exit_hello_port: 4875,
+            wg_tunnel_port: 59999,
+            wg_v2_tunnel_port: 59998,
+            exit_price: 50,
+            own_internal_ip: Ipv4Addr::new(172, 168, 0, 254),
+            netmask: 16,
+            client_subnet_size: None,
+            enable_enforcement: true,
+            enforcement_exempt_destinations: Vec::new(),
+            min_client_version: None,
+            min_client_version_deadline: None,
+            tunnel_mtu: 1500,
+            allowed_countries: HashSet::new(),
+            country_prices: Vec::new(),
+        };
+        let sealed = EncryptedExitClusterConfig::seal(
+            &config,
+            WgKey::from(member_public.0),
+            &member_secret,
+            &new_public,
+        );
+        assert_eq!(sealed.open(&new_secret).unwrap(), config);
+        assert!(sealed.open(&other_secret).is_err());
+    }
+}
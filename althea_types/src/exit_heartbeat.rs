@@ -0,0 +1,114 @@
+//! A small udp heartbeat clients send to their exit every few seconds. Wireguard handshakes take minutes
+//! to age out so this lets the exit notice a client has gone offline in seconds instead.
+//!
+//! The packet format matches the operator heartbeat, the sender WgKey, a nonce and a LibSodium box of the
+//! ExitHeartbeatMessage sealed to the exit's wg key. This consumes 32 bytes, 24 bytes and to the end of the
+//! packet. The box authenticates the sender, the timestamp is used to reject replayed packets
+
+use crate::error::AltheaTypesError;
+use crate::wg_key::WgKey;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::Nonce;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
+
+/// The port exits listen on for client heartbeats, reached over the exit tunnel
+pub const EXIT_HEARTBEAT_PORT: u16 = 4878;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExitHeartbeatMessage {
+    /// Unix timestamp in seconds when this heartbeat was sent
+    pub timestamp: u64,
+    /// The version of Rita the client is running
+    pub version: String,
+}
+
+impl ExitHeartbeatMessage {
+    /// Seals this message to the exit and builds the packet to send
+    pub fn encrypt(
+        &self,
+        our_publickey: WgKey,
+        our_secretkey: &SecretKey,
+        their_publickey: &PublicKey,
+    ) -> Vec<u8> {
+        // serde will only fail under specific circumstances with specific structs
+        // given the fixed nature of this struct this is safe
+        let plaintext = serde_json::to_vec(self).unwrap();
+        let nonce = box_::gen_nonce();
+        let ciphertext = box_::seal(&plaintext, &nonce, their_publickey, our_secretkey);
+
+        let mut packet = Vec::with_capacity(KEY_LEN + NONCE_LEN + ciphertext.len());
+        packet.extend_from_slice(our_publickey.as_ref());
+        packet.extend_from_slice(&nonce.0);
+        packet.extend_from_slice(&ciphertext);
+        packet
+    }
+
+    /// Opens a heartbeat packet, returning the sender key and the message
+    pub fn decrypt(
+        packet: &[u8],
+        our_secretkey: &SecretKey,
+    ) -> Result<(WgKey, ExitHeartbeatMessage), AltheaTypesError> {
+        if packet.len() <= KEY_LEN + NONCE_LEN {
+            return Err(AltheaTypesError::HeartbeatError(
+                "Heartbeat packet too short".to_string(),
+            ));
+        }
+        let mut key: [u8; KEY_LEN] = [0; KEY_LEN];
+        key.clone_from_slice(&packet[0..KEY_LEN]);
+        let their_wg_key = WgKey::from(key);
+        let mut nonce: [u8; NONCE_LEN] = [0; NONCE_LEN];
+        nonce.clone_from_slice(&packet[KEY_LEN..KEY_LEN + NONCE_LEN]);
+
+        let plaintext = match box_::open(
+            &packet[KEY_LEN + NONCE_LEN..],
+            &Nonce(nonce),
+            &their_wg_key.into(),
+            our_secretkey,
+        ) {
+            Ok(plaintext) => plaintext,
+            Err(_) => {
+                return Err(AltheaTypesError::HeartbeatError(format!(
+                    "Could not decrypt heartbeat from {their_wg_key}"
+                )))
+            }
+        };
+        match serde_json::from_slice(&plaintext) {
+            Ok(message) => Ok((their_wg_key, message)),
+            Err(e) => Err(AltheaTypesError::HeartbeatError(format!(
+                "Could not deserialize heartbeat from {their_wg_key} {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_heartbeat_round_trip() {
+        let (client_public, client_secret) = box_::gen_keypair();
+        let (exit_public, exit_secret) = box_::gen_keypair();
+        let (_, other_secret) = box_::gen_keypair();
+        let client_wg_key = WgKey::from(client_public.0);
+
+        let message = ExitHeartbeatMessage {
+            timestamp: 1_700_000_000,
+            version: "test".to_string(),
+        };
+        let packet = message.encrypt(client_wg_key, &client_secret, &exit_public);
+
+        let (sender, decrypted) = ExitHeartbeatMessage::decrypt(&packet, &exit_secret).unwrap();
+        assert_eq!(sender, client_wg_key);
+        assert_eq!(decrypted, message);
+
+        // someone other than the exit can not open it
+        assert!(ExitHeartbeatMessage::decrypt(&packet, &other_secret).is_err());
+        // and truncated packets are rejected
+        assert!(ExitHeartbeatMessage::decrypt(&packet[0..40], &exit_secret).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! Operators can publish a registry of their exits at a url of their choosing so that routers pick up new
+//! exits without every config being edited by hand. The registry is signed with an eth key the router is
+//! configured to trust. The registry is signed exactly as it was serialized, since the regions and payment
+//! types of an exit are sets with no stable serialized order.
+
+use crate::error::AltheaTypesError;
+use crate::ExitIdentity;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExitRegistry {
+    /// Increases with every published registry, routers never go back to an older one
+    pub version: u64,
+    /// Unix timestamp in seconds when the registry was published
+    pub timestamp: u64,
+    pub exits: Vec<ExitIdentity>,
+}
+
+impl ExitRegistry {
+    pub fn sign(&self, key: PrivateKey) -> SignedExitRegistry {
+        let registry = serde_json::to_string(self).expect("Failed to serialize exit registry!");
+        let signature = key.sign_ethereum_msg(registry.as_bytes());
+        SignedExitRegistry {
+            registry,
+            signature,
+        }
+    }
+}
+
+/// What is served at the registry url, the json encoded registry and the signature over it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedExitRegistry {
+    pub registry: String,
+    pub signature: Signature,
+}
+
+impl SignedExitRegistry {
+    /// Returns the address that signed this registry
+    pub fn signer(&self) -> Result<Address, AltheaTypesError> {
+        let hash = get_ethereum_msg_hash(self.registry.as_bytes());
+        match self.signature.recover(&hash) {
+            Ok(address) => Ok(address),
+            Err(e) => Err(AltheaTypesError::ExitRegistryError(format!(
+                "Invalid exit registry signature {e}"
+            ))),
+        }
+    }
+
+    /// Checks the registry was signed by the trusted key and only then parses it
+    pub fn open(&self, trusted: Address) -> Result<ExitRegistry, AltheaTypesError> {
+        if self.signer()? != trusted {
+            return Err(AltheaTypesError::ExitRegistryError(
+                "Exit registry not signed by the trusted key".to_string(),
+            ));
+        }
+        match serde_json::from_str(&self.registry) {
+            Ok(registry) => Ok(registry),
+            Err(e) => Err(AltheaTypesError::ExitRegistryError(format!(
+                "Invalid exit registry {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_exit_registry_signature() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let registry = ExitRegistry {
+            version: 3,
+            timestamp: 1_700_000_000,
+            exits: vec![ExitIdentity {
+                mesh_ip: "fd00::1337".parse().unwrap(),
+                wg_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                eth_addr: "0x0000000000000000000000000000000000000002"
+                    .parse()
+                    .unwrap(),
+                registration_port: 4875,
+                wg_exit_listen_port: 59998,
+                allowed_regions: HashSet::new(),
+                payment_types: HashSet::new(),
+            }],
+        };
+        let signed = registry.sign(key);
+        assert_eq!(signed.open(key.to_address()).unwrap(), registry);
+
+        let other: Address = "0x0000000000000000000000000000000000000003"
+            .parse()
+            .unwrap();
+        assert!(signed.open(other).is_err());
+
+        let mut tampered = signed;
+        tampered.registry = tampered.registry.replace("4875", "4876");
+        assert!(tampered.open(key.to_address()).is_err());
+    }
+}
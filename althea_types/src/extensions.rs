@@ -0,0 +1,167 @@
+//! Key value metadata carried alongside identities in the messages nodes exchange, so that a new feature
+//! can ship data to its peers without another breaking protocol change. Nodes ignore keys they don't
+//! know and older nodes ignore the extensions entirely, in json as an unknown field and in udp hellos as
+//! trailing bytes. The map is size bounded so a hello with extensions still fits the receive buffer of
+//! older nodes, extensions over the bounds are refused when set and dropped when received.
+
+use crate::error::AltheaTypesError;
+use crate::interop::LocalIdentity;
+use std::collections::BTreeMap;
+
+/// Most keys a set of extensions may have
+pub const MAX_EXTENSIONS: usize = 8;
+/// Longest key, in bytes
+pub const MAX_EXTENSION_KEY_LEN: usize = 32;
+/// Most bytes of keys and values together
+pub const MAX_EXTENSIONS_BYTES: usize = 160;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "BTreeMap<String, Vec<u8>>", into = "BTreeMap<String, Vec<u8>>")]
+pub struct Extensions(BTreeMap<String, Vec<u8>>);
+
+impl Extensions {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn size(&self) -> usize {
+        self.0.iter().map(|(k, v)| k.len() + v.len()).sum()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.0.get(key).map(|v| v.as_slice())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<u8>)> {
+        self.0.iter()
+    }
+
+    /// Sets a key, refused if the extensions would go over the bounds
+    pub fn insert(&mut self, key: String, value: Vec<u8>) -> Result<(), AltheaTypesError> {
+        if key.is_empty() || key.len() > MAX_EXTENSION_KEY_LEN {
+            return Err(AltheaTypesError::ExtensionError(format!(
+                "Extension keys have to be 1 to {MAX_EXTENSION_KEY_LEN} bytes"
+            )));
+        }
+        let replaced = self.0.get(&key).map(|v| key.len() + v.len()).unwrap_or(0);
+        if !self.0.contains_key(&key) && self.0.len() >= MAX_EXTENSIONS {
+            return Err(AltheaTypesError::ExtensionError(format!(
+                "At most {MAX_EXTENSIONS} extensions can be set"
+            )));
+        }
+        if self.size() - replaced + key.len() + value.len() > MAX_EXTENSIONS_BYTES {
+            return Err(AltheaTypesError::ExtensionError(format!(
+                "Extensions are limited to {MAX_EXTENSIONS_BYTES} bytes"
+            )));
+        }
+        self.0.insert(key, value);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.0.remove(key)
+    }
+}
+
+/// Keeps what fits the bounds, in key order, a peer sending more than we accept shouldn't cost us the
+/// message it came with
+impl From<BTreeMap<String, Vec<u8>>> for Extensions {
+    fn from(map: BTreeMap<String, Vec<u8>>) -> Self {
+        let mut extensions = Extensions::default();
+        for (key, value) in map {
+            let _ = extensions.insert(key, value);
+        }
+        extensions
+    }
+}
+
+impl From<Extensions> for BTreeMap<String, Vec<u8>> {
+    fn from(extensions: Extensions) -> Self {
+        extensions.0
+    }
+}
+
+/// A LocalIdentity as sent in http hellos, with the sender's extensions alongside. Older nodes send and
+/// expect a plain LocalIdentity, which parses as one without extensions
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct ExtendedLocalIdentity {
+    #[serde(flatten)]
+    pub identity: LocalIdentity,
+    #[serde(default, skip_serializing_if = "Extensions::is_empty")]
+    pub extensions: Extensions,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interop::Identity;
+
+    #[test]
+    fn test_extension_bounds() {
+        let mut extensions = Extensions::default();
+        assert!(extensions.insert(String::new(), vec![1]).is_err());
+        assert!(extensions.insert("k".repeat(33), vec![1]).is_err());
+        extensions.insert("a".to_string(), vec![0; 100]).unwrap();
+        assert!(extensions.insert("b".to_string(), vec![0; 100]).is_err());
+        // replacing a value only counts the new one
+        extensions.insert("a".to_string(), vec![0; 150]).unwrap();
+        extensions.remove("a");
+        for i in 0..MAX_EXTENSIONS {
+            extensions.insert(i.to_string(), vec![1]).unwrap();
+        }
+        assert!(extensions.insert("x".to_string(), vec![1]).is_err());
+        assert_eq!(extensions.get("0"), Some(&[1u8][..]));
+
+        // over the bounds on the wire, what fits is kept
+        let mut map = BTreeMap::new();
+        map.insert("a".to_string(), vec![0; 150]);
+        map.insert("b".to_string(), vec![0; 100]);
+        map.insert("c".to_string(), vec![0; 5]);
+        let json = serde_json::to_string(&map).unwrap();
+        let received: Extensions = serde_json::from_str(&json).unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(received.get("b").is_none());
+    }
+
+    #[test]
+    fn test_extended_local_identity() {
+        let identity = LocalIdentity {
+            wg_port: 60000,
+            have_tunnel: None,
+            global: Identity::new(
+                "fd00::1".parse().unwrap(),
+                "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"
+                    .parse()
+                    .unwrap(),
+                "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                None,
+            ),
+        };
+        // from an older node
+        let plain = serde_json::to_string(&identity).unwrap();
+        let parsed: ExtendedLocalIdentity = serde_json::from_str(&plain).unwrap();
+        assert_eq!(parsed.identity, identity);
+        assert!(parsed.extensions.is_empty());
+
+        // to an older node, with a key from a newer one
+        let mut extended = ExtendedLocalIdentity {
+            identity,
+            extensions: Extensions::default(),
+        };
+        extended
+            .extensions
+            .insert("future".to_string(), vec![1, 2, 3])
+            .unwrap();
+        let json = serde_json::to_string(&extended).unwrap();
+        let old: LocalIdentity = serde_json::from_str(&json).unwrap();
+        assert_eq!(old, identity);
+        let new: ExtendedLocalIdentity = serde_json::from_str(&json).unwrap();
+        assert_eq!(new, extended);
+    }
+}
@@ -1,6 +1,12 @@
+use crate::error::AltheaTypesError;
 use crate::regions::Regions;
+use crate::sealed_box::{open_json, seal_json, SealHeader};
 use crate::{contact_info::ContactType, wg_key::WgKey, BillingDetails, InstallationDetails};
-use crate::{ClientExtender, UsageTrackerFlat, UsageTrackerTransfer, WifiDevice};
+use crate::{
+    BandwidthContractReport, Capabilities, ClientExtender, Extensions, SignedAntennaSessionRecord,
+    SignedBandwidthContract, SignedBroadcastNotice, SignedSpeedTest, SignupChallenge, SignupProof,
+    SpeedTestResult, UsageTrackerFlat, UsageTrackerTransfer, WifiDevice,
+};
 use arrayvec::ArrayString;
 use babel_monitor::structs::Route;
 use babel_monitor::structs::{BabeldConfig, Neighbor};
@@ -8,14 +14,18 @@ use clarity::Address;
 use deep_space::Address as AltheaAddress;
 use ipnetwork::IpNetwork;
 use num256::Uint256;
+use serde::de::DeserializeOwned;
 use serde::de::Error;
-use serde::{Deserialize, Deserializer, Serializer};
-use std::collections::hash_map::DefaultHasher;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
 use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 
@@ -52,6 +62,9 @@ impl Display for Identity {
 
 pub const ALTHEA_PREFIX: &str = "althea";
 
+/// Length of the canonical byte representation of an Identity, three 32 byte words
+pub const IDENTITY_CANONICAL_LEN: usize = 96;
+
 impl Identity {
     pub fn new(
         mesh_ip: IpAddr,
@@ -74,17 +87,84 @@ impl Identity {
         AltheaAddress::from_slice(self.eth_address.as_bytes(), ALTHEA_PREFIX).unwrap()
     }
 
+    /// A stable byte representation of this identity, identical on every version and platform. The
+    /// layout matches the abi encoding of the (uint128 mesh_ip, uint256 wg_key, address eth_address)
+    /// struct the registration contract stores. Ipv4 mesh ips are encoded as ipv4 mapped ipv6 addresses
+    /// and like Eq and Hash the nickname is not included
+    pub fn canonical_bytes(&self) -> [u8; IDENTITY_CANONICAL_LEN] {
+        let mesh_ip = match self.mesh_ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let mut out = [0u8; IDENTITY_CANONICAL_LEN];
+        out[16..32].copy_from_slice(&mesh_ip.octets());
+        out[32..64].copy_from_slice(self.wg_public_key.as_ref());
+        out[76..96].copy_from_slice(self.eth_address.as_bytes());
+        out
+    }
+
+    /// Parses the output of canonical_bytes, the nickname is always None
+    pub fn from_canonical_bytes(input: &[u8]) -> Result<Identity, AltheaTypesError> {
+        if input.len() != IDENTITY_CANONICAL_LEN {
+            return Err(AltheaTypesError::IdentityParseError(format!(
+                "Expected {} bytes got {}",
+                IDENTITY_CANONICAL_LEN,
+                input.len()
+            )));
+        }
+        if input[0..16].iter().any(|b| *b != 0) || input[64..76].iter().any(|b| *b != 0) {
+            return Err(AltheaTypesError::IdentityParseError(
+                "Non zero padding in canonical identity".to_string(),
+            ));
+        }
+        let mut mesh_ip = [0u8; 16];
+        mesh_ip.copy_from_slice(&input[16..32]);
+        let mesh_ip = Ipv6Addr::from(mesh_ip);
+        let mesh_ip = match mesh_ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(mesh_ip),
+        };
+        let mut wg_public_key = [0u8; 32];
+        wg_public_key.copy_from_slice(&input[32..64]);
+        let eth_address = match Address::from_slice(&input[76..96]) {
+            Ok(a) => a,
+            Err(e) => {
+                return Err(AltheaTypesError::IdentityParseError(format!(
+                    "Bad eth address in canonical identity {e}"
+                )))
+            }
+        };
+        Ok(Identity {
+            mesh_ip,
+            eth_address,
+            wg_public_key: wg_public_key.into(),
+            nickname: None,
+        })
+    }
+
+    /// Sha256 of the canonical bytes, use this rather than the Hash impl whenever a hash of an
+    /// identity leaves this process, std hashers are not stable across Rust versions
+    pub fn canonical_digest(&self) -> [u8; 32] {
+        sha256::hash(&self.canonical_bytes()).0
+    }
+
+    /// The canonical digest as lowercase hex
+    pub fn canonical_digest_hex(&self) -> String {
+        self.canonical_digest()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
     pub fn get_hash(&self) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        hasher.finish()
+        u64::from_be_bytes(self.get_hash_array())
     }
 
     pub fn get_hash_array(&self) -> [u8; 8] {
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        let bits = hasher.finish();
-        bits.to_be_bytes()
+        let digest = self.canonical_digest();
+        let mut out = [0u8; 8];
+        out.copy_from_slice(&digest[0..8]);
+        out
     }
 }
 
@@ -261,6 +341,10 @@ pub enum ExitState {
         #[serde(default)]
         email_code: Option<String>,
         phone_code: Option<String>,
+        /// Set by exits that enforce a minimum client version, lets the client ui warn
+        /// the user before service is cut off
+        #[serde(default)]
+        version_status: Option<ClientVersionStatus>,
     },
     /// we are currently registered and operating, update this state
     /// incase the exit for example wants to assign us a new ip
@@ -268,9 +352,115 @@ pub enum ExitState {
         general_details: ExitDetails,
         our_details: ExitClientDetails,
         message: String,
+        /// Set by exits that enforce a minimum client version, lets the client ui warn
+        /// the user before service is cut off
+        #[serde(default)]
+        version_status: Option<ClientVersionStatus>,
+        /// The exit protocol version the exit picked from the ones we advertised, None
+        /// for exits that predate negotiation
+        #[serde(default)]
+        protocol_version: Option<u32>,
+        /// Set by an exit that is being decommissioned, the client should move to the replacement
+        /// before the deadline
+        #[serde(default)]
+        migration: Option<ExitMigration>,
     },
     /// we have been denied
-    Denied { message: String },
+    Denied {
+        message: String,
+        /// Machine readable reason for the denial, older exits do not send this
+        #[serde(default)]
+        code: Option<ExitDenialCode>,
+    },
+}
+
+/// Reasons an exit may deny a client, sent alongside the human readable message
+/// so that the client ui can react to specific cases
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ExitDenialCode {
+    /// The exit could not decrypt or parse the request
+    BadRequest,
+    /// The request did not come from the mesh ip it claims
+    IpMismatch,
+    /// The client is not in a region this exit serves
+    RegionNotAllowed,
+    /// The registration server rejected the signup or could not be reached
+    RegistrationFailed,
+    /// The client version is below the minimum this exit serves and the grace period is over
+    VersionTooOld,
+    /// The client and exit have no exit protocol version in common
+    UnsupportedProtocol,
+    /// The exit operator has banned this client
+    Denylisted,
+    /// The exit has been decommissioned, its clients should use the replacement it advertised
+    Migrated,
+}
+
+/// The original exit protocol, clients tunnel over the wg_exit interface using the exit's
+/// legacy wireguard key (beta 19 routers)
+pub const EXIT_PROTOCOL_V1: u32 = 1;
+/// Clients tunnel over the wg_exit_v2 interface using the exit's mesh wireguard key (beta 20 routers)
+pub const EXIT_PROTOCOL_V2: u32 = 2;
+
+/// Picks the highest exit protocol version supported by both the client and the exit, returns
+/// None if they have none in common
+pub fn negotiate_exit_protocol(client_versions: &[u32], exit_versions: &[u32]) -> Option<u32> {
+    client_versions
+        .iter()
+        .filter(|v| exit_versions.contains(v))
+        .max()
+        .copied()
+}
+
+/// Whether the client version is acceptable to the exit
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+#[serde(tag = "status")]
+pub enum ClientVersionStatus {
+    Supported,
+    /// The client is below the minimum version and will be refused service
+    /// after the deadline, it should update before then
+    Deprecated {
+        minimum_version: String,
+        deadline: Option<SystemTime>,
+    },
+}
+
+/// An exit being decommissioned advertises the exit its clients should move to, service is tightened
+/// as the deadline nears so that clients which don't move on their own still leave in time
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+pub struct ExitMigration {
+    pub replacement: ExitIdentity,
+    /// When the exit stops serving clients
+    pub deadline: SystemTime,
+    /// Set once clients still on the exit are being throttled
+    pub throttled: bool,
+}
+
+/// Parses a version string in the x.y.z format used by our Cargo.toml files, suffixes such
+/// as -beta1 are ignored
+pub fn parse_client_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split(['-', '+']).next()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().unwrap_or("0").parse().ok()?;
+    let patch = parts.next().unwrap_or("0").parse().ok()?;
+    Some((major, minor, patch))
+}
+
+/// Returns true if the given version is older than the minimum version, clients that do not
+/// report a version or report one we can not parse predate version reporting and are considered
+/// older than any minimum
+pub fn client_version_below(version: Option<&str>, minimum: &str) -> bool {
+    let minimum = match parse_client_version(minimum) {
+        Some(v) => v,
+        // a bad minimum in the config should not lock everyone out
+        None => return false,
+    };
+    match version.and_then(parse_client_version) {
+        Some(v) => v < minimum,
+        None => true,
+    }
 }
 
 impl ExitState {
@@ -318,6 +508,20 @@ pub struct ExitClientIdentity {
     pub wg_port: u16,
     pub global: Identity,
     pub reg_details: ExitRegistrationDetails,
+    /// The router version stored in semver format as found in the Cargo.toml, used by
+    /// exits to enforce a minimum client version
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Exit protocol versions this client can speak, the exit picks one and reports it back
+    /// in the registered state. Empty for clients that predate negotiation
+    #[serde(default)]
+    pub supported_protocol_versions: Vec<u32>,
+    /// Proof of work for exits that advertise a signup challenge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signup_proof: Option<SignupProof>,
+    /// Metadata for features newer than this struct, see crate::extensions
+    #[serde(default, skip_serializing_if = "Extensions::is_empty")]
+    pub extensions: Extensions,
 }
 
 /// Wrapper for secure box containing an exit client identity
@@ -326,6 +530,41 @@ pub struct EncryptedExitClientIdentity {
     pub pubkey: WgKey,
     pub nonce: [u8; 24],
     pub encrypted_exit_client_id: Vec<u8>,
+    #[serde(flatten)]
+    pub header: SealHeader,
+}
+
+impl EncryptedExitClientIdentity {
+    /// Seals our identity to the exit
+    pub fn seal(
+        id: &ExitClientIdentity,
+        our_publickey: WgKey,
+        our_secretkey: &SecretKey,
+        exit_publickey: &PublicKey,
+    ) -> EncryptedExitClientIdentity {
+        let sealed = seal_json(id, exit_publickey, our_secretkey);
+        EncryptedExitClientIdentity {
+            pubkey: our_publickey,
+            nonce: sealed.nonce,
+            encrypted_exit_client_id: sealed.ciphertext,
+            header: sealed.header,
+        }
+    }
+
+    /// Opens a client identity with whichever of our keys it was sealed to, returning
+    /// the identity and the key that opened it
+    pub fn open(
+        &self,
+        our_secretkeys: &[SecretKey],
+    ) -> Result<(ExitClientIdentity, SecretKey), AltheaTypesError> {
+        open_json(
+            &self.header,
+            &self.nonce,
+            &self.encrypted_exit_client_id,
+            &self.pubkey.into(),
+            our_secretkeys,
+        )
+    }
 }
 
 /// Wrapper for secure box containing an exit state
@@ -333,6 +572,38 @@ pub struct EncryptedExitClientIdentity {
 pub struct EncryptedExitState {
     pub nonce: [u8; 24],
     pub encrypted_exit_state: Vec<u8>,
+    #[serde(flatten)]
+    pub header: SealHeader,
+}
+
+impl EncryptedExitState {
+    pub fn seal(
+        state: &ExitState,
+        our_secretkey: &SecretKey,
+        their_publickey: &PublicKey,
+    ) -> EncryptedExitState {
+        let sealed = seal_json(state, their_publickey, our_secretkey);
+        EncryptedExitState {
+            nonce: sealed.nonce,
+            encrypted_exit_state: sealed.ciphertext,
+            header: sealed.header,
+        }
+    }
+
+    pub fn open(
+        &self,
+        their_publickey: &PublicKey,
+        our_secretkey: &SecretKey,
+    ) -> Result<ExitState, AltheaTypesError> {
+        let (state, _) = open_json(
+            &self.header,
+            &self.nonce,
+            &self.encrypted_exit_state,
+            their_publickey,
+            &[our_secretkey.clone()],
+        )?;
+        Ok(state)
+    }
 }
 
 /// Wrapper for secure box containing a list of ips
@@ -340,6 +611,39 @@ pub struct EncryptedExitState {
 pub struct EncryptedExitList {
     pub nonce: [u8; 24],
     pub exit_list: Vec<u8>,
+    #[serde(flatten)]
+    pub header: SealHeader,
+}
+
+impl EncryptedExitList {
+    /// Seals either version of the exit list
+    pub fn seal<T: Serialize>(
+        list: &T,
+        our_secretkey: &SecretKey,
+        their_publickey: &PublicKey,
+    ) -> EncryptedExitList {
+        let sealed = seal_json(list, their_publickey, our_secretkey);
+        EncryptedExitList {
+            nonce: sealed.nonce,
+            exit_list: sealed.ciphertext,
+            header: sealed.header,
+        }
+    }
+
+    pub fn open<T: DeserializeOwned>(
+        &self,
+        their_publickey: &PublicKey,
+        our_secretkey: &SecretKey,
+    ) -> Result<T, AltheaTypesError> {
+        let (list, _) = open_json(
+            &self.header,
+            &self.nonce,
+            &self.exit_list,
+            their_publickey,
+            &[our_secretkey.clone()],
+        )?;
+        Ok(list)
+    }
 }
 
 /// Struct returned when hitting exit_list endpoint
@@ -354,6 +658,10 @@ pub struct ExitList {
 #[derive(Default, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub struct ExitListV2 {
     pub exit_list: Vec<ExitIdentity>,
+    /// Relative preference for each entry of exit_list, in the same order. The exit sorts the list for the
+    /// requesting client, higher weights first, empty when the list comes from an older exit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weights: Vec<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
@@ -378,6 +686,9 @@ pub struct ExitDetails {
     pub description: String,
     #[serde(default = "default_verif_mode")]
     pub verif_mode: ExitVerifMode,
+    /// Set when the exit wants a proof of work with signups, older exits never do
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signup_challenge: Option<SignupChallenge>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
@@ -386,6 +697,17 @@ pub struct ExitClientDetails {
     pub internet_ipv6_subnet: Option<IpNetwork>,
 }
 
+/// What a node tells its neighbors about itself so their dashboards can show more than a key,
+/// both fields are empty unless the node opted in to sharing them
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Default)]
+pub struct PeerLabel {
+    /// The router model
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub nickname: Option<String>,
+}
+
 /// This is all the data we need to give a neighbor to open a wg connection
 /// this is also known as a "hello" packet or message
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
@@ -458,6 +780,7 @@ impl From<UpdateTypeLegacy> for UpdateType {
                             commands.push(OpkgCommand::Install {
                                 packages: item.packages.unwrap(),
                                 arguments: item.arguments.unwrap_or_default(),
+                                sha256: BTreeMap::new(),
                             })
                         }
                         OpkgCommandTypeLegacy::Update => commands.push(OpkgCommand::Update {
@@ -479,6 +802,10 @@ pub enum OpkgCommand {
     Install {
         packages: Vec<String>,
         arguments: Vec<String>,
+        /// Lowercase hex sha256 hashes of packages given as urls, keyed by the url. Those packages
+        /// may be fetched from a mesh neighbor's artifact cache and are verified before install
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        sha256: BTreeMap<String, String>,
     },
     Remove {
         packages: Vec<String>,
@@ -504,6 +831,10 @@ pub enum UpdateTypeLegacy {
 pub struct SysupgradeCommand {
     pub url: String,
     pub flags: Option<Vec<String>>,
+    /// Lowercase hex sha256 hash of the image, when provided the image may be fetched from
+    /// a mesh neighbor's artifact cache instead of the url and is always verified before use
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Hash, Clone, Debug, Eq, PartialEq)]
@@ -630,6 +961,11 @@ pub enum OperatorAction {
         add_list: Vec<String>,
         drop_list: Vec<String>,
     },
+    /// Runs a throughput test from this router to another one over the mesh, see
+    /// althea_types::speed_test. The result is reported in a following checkin
+    SpeedTest {
+        test: SignedSpeedTest,
+    },
 }
 
 /// Operator update that we get from the operator server during our checkin
@@ -706,6 +1042,14 @@ pub struct OperatorUpdateMessage {
     /// side how much history we need to send in with the next checkin cycle
     #[serde(default = "default_ops_last_seen_usage_hour")]
     pub ops_last_seen_usage_hour: u64,
+    /// A plan for the router to shape itself to, None leaves the current contract in place, a
+    /// contract ends at its expiry
+    #[serde(default)]
+    pub bandwidth_contract: Option<SignedBandwidthContract>,
+    /// Notices for the router dashboard, routers pass them on to neighbors that can't reach the
+    /// operator server until they expire
+    #[serde(default)]
+    pub broadcast_notices: Vec<SignedBroadcastNotice>,
 }
 
 /// Serializes a ContactType as a string
@@ -821,6 +1165,20 @@ pub struct OperatorCheckinMessage {
     /// fault value.
     #[serde(default)]
     pub rita_uptime: Duration,
+    /// Results of operator requested speed tests that have finished since the last checkin
+    #[serde(default)]
+    pub speed_test_results: Vec<SpeedTestResult>,
+    /// Audit records of antenna forwarding sessions that have ended since the last checkin, only
+    /// kept if the user enabled operator.record_antenna_sessions
+    #[serde(default)]
+    pub antenna_sessions: Vec<SignedAntennaSessionRecord>,
+    /// What the router is doing about the bandwidth contract the operator sent, None if it never
+    /// got one
+    #[serde(default)]
+    pub bandwidth_contract: Option<BandwidthContractReport>,
+    /// What this firmware build can do, see capabilities.rs
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
 }
 
 /// The message and exit sends to the operator server to checkin, this allows us to customize
@@ -836,6 +1194,9 @@ pub struct OperatorExitCheckinMessage {
     pub exit_uptime: Duration,
     /// Number of users online
     pub users_online: Option<u32>,
+    /// What this exit build can do, see capabilities.rs
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
 }
 
 /// Operator update that we get from the operator server during our checkin
@@ -905,6 +1266,10 @@ pub struct HardwareInfo {
     // Info about the max connections, number of rows in conntrack table and current number of connections made by router
     #[serde(default)]
     pub conntrack: Option<ConntrackInfo>,
+    /// Extended telemetry used by operators to plan maintenance, only populated if the user
+    /// has not opted out of sharing it
+    #[serde(default)]
+    pub telemetry: Option<HardwareTelemetry>,
 }
 
 fn default_kernel_version() -> String {
@@ -930,6 +1295,70 @@ pub struct SensorReading {
     pub crit: Option<u64>,
 }
 
+/// Extended hardware telemetry sent to operator tools to help plan maintenance
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub struct HardwareTelemetry {
+    /// The board model as reported by the hardware itself, unlike HardwareInfo::model
+    /// this is not set by the firmware builder. Read from /tmp/sysinfo/model or the device tree
+    pub board_model: Option<String>,
+    /// Memory in kilobytes available for new allocations without swapping, this accounts for
+    /// reclaimable caches unlike allocated_memory. Parsed from MemAvailable in /proc/meminfo
+    pub available_memory: Option<u64>,
+    /// Usage of the persistent filesystems on this device
+    pub storage: Vec<StorageUsage>,
+    /// Wear indicators for flash storage devices that report them
+    pub flash_wear: Vec<FlashWear>,
+}
+
+/// Usage of a single mounted filesystem, parsed from df
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StorageUsage {
+    pub mount_point: String,
+    /// Size of the filesystem in kilobytes
+    pub total: u64,
+    /// Used space in kilobytes
+    pub used: u64,
+}
+
+/// Basic device health for the dashboard and operator tools, every field is None where the device
+/// doesn't report it
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub load_avg_one_minute: Option<f32>,
+    pub load_avg_five_minute: Option<f32>,
+    pub load_avg_fifteen_minute: Option<f32>,
+    pub logical_processors: Option<u32>,
+    /// Total memory in kilobytes
+    pub memory_total: Option<u64>,
+    /// Memory in kilobytes available for new allocations, see HardwareTelemetry::available_memory
+    pub memory_available: Option<u64>,
+    pub uptime_secs: Option<u64>,
+    /// The hottest temperature sensor in thousandths of a degree celsius, as the kernel reports it
+    pub temperature: Option<u64>,
+    /// Size of the persistent filesystem in kilobytes, /overlay on OpenWrt
+    pub flash_total: Option<u64>,
+    /// Free space on the persistent filesystem in kilobytes
+    pub flash_free: Option<u64>,
+}
+
+/// Wear information for a flash storage device, which fields are populated depends on the
+/// type of flash. eMMC devices report life time estimates while raw nand under UBI reports
+/// erase counts and bad blocks
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FlashWear {
+    /// Device name, for example mmcblk0 or ubi0
+    pub device: String,
+    /// eMMC life time estimate for type A and type B memory, in steps of 10% of life time used
+    /// so 0x01 means 0-10% used and 0x0B means the estimated life time has been exceeded
+    pub life_time_estimate: Option<(u8, u8)>,
+    /// eMMC pre end of life info, 1 is normal, 2 is warning (80% of reserved blocks used) and 3 is urgent
+    pub pre_eol_info: Option<u8>,
+    /// Highest erase count of any block on a UBI device
+    pub max_erase_count: Option<u64>,
+    /// Number of bad physical eraseblocks on a UBI device
+    pub bad_block_count: Option<u64>,
+}
+
 /// Struct that hold information about the ethernet interfaces, i.e. whether a link is
 /// up and the speed of the link\
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1028,6 +1457,7 @@ pub struct AuthorizedKeys {
 
 #[cfg(test)]
 mod test {
+    use arrayvec::ArrayString;
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct DummyStruct {
@@ -1051,4 +1481,114 @@ mod test {
         let data = bincode::serialize(&entry).unwrap();
         let _try_bincode: DummyStruct = bincode::deserialize(&data).unwrap();
     }
+
+    #[test]
+    fn test_client_version_below() {
+        use crate::{client_version_below, parse_client_version};
+        assert_eq!(parse_client_version("0.21.5"), Some((0, 21, 5)));
+        assert_eq!(parse_client_version("v1.2"), Some((1, 2, 0)));
+        assert_eq!(parse_client_version("0.22.0-beta1"), Some((0, 22, 0)));
+        assert!(client_version_below(Some("0.21.5"), "0.22.0"));
+        assert!(!client_version_below(Some("0.22.0"), "0.22.0"));
+        assert!(!client_version_below(Some("1.0.0"), "0.22.0"));
+        assert!(client_version_below(None, "0.22.0"));
+        assert!(!client_version_below(Some("0.1.0"), "not a version"));
+    }
+
+    #[test]
+    fn test_identity_canonical_bytes() {
+        use crate::Identity;
+        let id = Identity {
+            mesh_ip: "fd00::1337".parse().unwrap(),
+            eth_address: "0x52af7358f572812088ecf214e821ba45361e49bd"
+                .parse()
+                .unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            nickname: Some(ArrayString::from("nick").unwrap()),
+        };
+        let expected = "00000000000000000000000000000000fd000000000000000000000000001337\
+                        f017821319ed84b7b9a2ed0461e7398cda89fcf76e675c76a3b9695c93a98179\
+                        00000000000000000000000052af7358f572812088ecf214e821ba45361e49bd";
+        let bytes: String = id
+            .canonical_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(bytes, expected);
+        assert_eq!(
+            id.canonical_digest_hex(),
+            "6ca5c085a0dae2c42480b5a002497984313f34118c16c3e4ec708166729ce0b2"
+        );
+        assert_eq!(
+            id.get_hash_array(),
+            [0x6c, 0xa5, 0xc0, 0x85, 0xa0, 0xda, 0xe2, 0xc4]
+        );
+
+        // the nickname is not part of the canonical form
+        let parsed = Identity::from_canonical_bytes(&id.canonical_bytes()).unwrap();
+        assert_eq!(parsed, id);
+        assert_eq!(parsed.nickname, None);
+
+        let v4 = Identity {
+            mesh_ip: "10.0.0.1".parse().unwrap(),
+            ..id
+        };
+        assert_eq!(
+            &v4.canonical_bytes()[16..32],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1]
+        );
+        assert_eq!(
+            Identity::from_canonical_bytes(&v4.canonical_bytes())
+                .unwrap()
+                .mesh_ip,
+            v4.mesh_ip
+        );
+
+        let mut bad_padding = id.canonical_bytes();
+        bad_padding[0] = 1;
+        assert!(Identity::from_canonical_bytes(&bad_padding).is_err());
+        assert!(Identity::from_canonical_bytes(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_exit_protocol() {
+        use crate::{negotiate_exit_protocol, EXIT_PROTOCOL_V1, EXIT_PROTOCOL_V2};
+        let exit = [EXIT_PROTOCOL_V1, EXIT_PROTOCOL_V2];
+        assert_eq!(
+            negotiate_exit_protocol(&[EXIT_PROTOCOL_V1, EXIT_PROTOCOL_V2], &exit),
+            Some(EXIT_PROTOCOL_V2)
+        );
+        assert_eq!(
+            negotiate_exit_protocol(&[EXIT_PROTOCOL_V1], &exit),
+            Some(EXIT_PROTOCOL_V1)
+        );
+        assert_eq!(
+            negotiate_exit_protocol(&[EXIT_PROTOCOL_V2, 3], &exit),
+            Some(EXIT_PROTOCOL_V2)
+        );
+        assert_eq!(negotiate_exit_protocol(&[3], &exit), None);
+        assert_eq!(negotiate_exit_protocol(&[], &exit), None);
+    }
+
+    #[test]
+    fn test_old_denied_state_deserialize() {
+        use crate::{ExitDenialCode, ExitState};
+        let old = r#"{"state":"Denied","message":"no"}"#;
+        let state: ExitState = serde_json::from_str(old).unwrap();
+        assert_eq!(
+            state,
+            ExitState::Denied {
+                message: "no".to_string(),
+                code: None
+            }
+        );
+        let new = ExitState::Denied {
+            message: "too old".to_string(),
+            code: Some(ExitDenialCode::VersionTooOld),
+        };
+        let round: ExitState = serde_json::from_str(&serde_json::to_string(&new).unwrap()).unwrap();
+        assert_eq!(round, new);
+    }
 }
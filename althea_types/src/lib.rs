@@ -1,19 +1,49 @@
 #[macro_use]
 extern crate serde_derive;
 
+pub mod amount;
+pub mod antenna_session;
+pub mod bandwidth_contract;
+pub mod broadcast_notice;
+pub mod capabilities;
 pub mod contact_info;
 pub mod error;
+pub mod exit_cluster;
+pub mod exit_heartbeat;
+pub mod exit_registry;
+pub mod extensions;
 pub mod interop;
 pub mod monitoring;
+pub mod reconciliation;
 pub mod regions;
+pub mod rpc;
+pub mod sealed_box;
+pub mod signup_challenge;
+pub mod speed_test;
+pub mod unix_time;
 pub mod user_info;
+pub mod voucher;
 pub mod wg_key;
 pub mod wifi_info;
 
+pub use crate::amount::*;
+pub use crate::antenna_session::*;
+pub use crate::bandwidth_contract::*;
+pub use crate::broadcast_notice::*;
+pub use crate::capabilities::*;
 pub use crate::contact_info::*;
+pub use crate::exit_cluster::*;
+pub use crate::exit_heartbeat::*;
+pub use crate::exit_registry::*;
+pub use crate::extensions::*;
 pub use crate::interop::*;
 pub use crate::monitoring::*;
+pub use crate::reconciliation::*;
+pub use crate::sealed_box::*;
+pub use crate::signup_challenge::*;
+pub use crate::speed_test::*;
 pub use crate::user_info::*;
+pub use crate::voucher::*;
 pub use crate::wg_key::WgKey;
 pub use crate::wifi_info::*;
 pub use std::str::FromStr;
@@ -0,0 +1,216 @@
+//! Neighbors periodically swap signed summaries of what they believe they owe each other and have paid
+//! each other, so that billing disagreements can be spotted and debugged from either side. Summaries are
+//! signed with the node's eth key so a neighbor can't put words in someone else's mouth.
+//!
+//! Once a payment is validated the node that was paid sends the payer a receipt signed the same way,
+//! so that the payer can later prove the payment was seen and credited.
+
+use crate::error::AltheaTypesError;
+use crate::Identity;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+use num256::{Int256, Uint256};
+
+/// One node's view of its billing relationship with a neighbor
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DebtSummary {
+    /// The node making this summary
+    pub from: Identity,
+    /// The neighbor it is about
+    pub to: Identity,
+    /// What from owes to (positive) or to owes from (negative), as in debt keeper
+    pub debt: Int256,
+    /// Total payments from has sent to
+    pub total_payment_sent: Uint256,
+    /// Total payments from has received from to
+    pub total_payment_received: Uint256,
+    /// Unix timestamp in seconds when the summary was made
+    pub timestamp: u64,
+}
+
+impl DebtSummary {
+    /// The message that is signed, numbers are encoded as decimal strings so that this does not
+    /// depend on the internal representation of the num256 types
+    fn signing_message(&self) -> Vec<u8> {
+        format!(
+            "althea debt summary {}:{}:{}:{}:{}:{}",
+            self.from.eth_address,
+            self.to.eth_address,
+            self.debt,
+            self.total_payment_sent,
+            self.total_payment_received,
+            self.timestamp
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(self, key: PrivateKey) -> SignedDebtSummary {
+        let signature = key.sign_ethereum_msg(&self.signing_message());
+        SignedDebtSummary {
+            summary: self,
+            signature,
+        }
+    }
+}
+
+/// A debt summary and the signature of the node that made it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedDebtSummary {
+    pub summary: DebtSummary,
+    pub signature: Signature,
+}
+
+impl SignedDebtSummary {
+    /// Returns the address that signed this summary
+    pub fn signer(&self) -> Result<Address, AltheaTypesError> {
+        let hash = get_ethereum_msg_hash(&self.summary.signing_message());
+        match self.signature.recover(&hash) {
+            Ok(address) => Ok(address),
+            Err(e) => Err(AltheaTypesError::ReconciliationError(format!(
+                "Invalid debt summary signature {e}"
+            ))),
+        }
+    }
+
+    /// Checks that the summary was signed by the node it claims to be from
+    pub fn verify(&self) -> Result<(), AltheaTypesError> {
+        if self.signer()? == self.summary.from.eth_address {
+            Ok(())
+        } else {
+            Err(AltheaTypesError::ReconciliationError(
+                "Debt summary not signed by its sender".to_string(),
+            ))
+        }
+    }
+}
+
+/// The paid node's confirmation that it validated a payment and credited it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PaymentReceipt {
+    /// The node that was paid and made this receipt
+    pub from: Identity,
+    /// The node that paid
+    pub to: Identity,
+    pub txid: Uint256,
+    pub amount: Uint256,
+    /// What to owes from (positive) or from owes to (negative) once the payment was credited, from
+    /// the point of view of from as in debt keeper
+    pub debt: Int256,
+    /// Unix timestamp in seconds when the payment was validated
+    pub timestamp: u64,
+}
+
+impl PaymentReceipt {
+    /// The message that is signed, see DebtSummary::signing_message
+    fn signing_message(&self) -> Vec<u8> {
+        format!(
+            "althea payment receipt {}:{}:{:#066x}:{}:{}:{}",
+            self.from.eth_address,
+            self.to.eth_address,
+            self.txid,
+            self.amount,
+            self.debt,
+            self.timestamp
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(self, key: PrivateKey) -> SignedPaymentReceipt {
+        let signature = key.sign_ethereum_msg(&self.signing_message());
+        SignedPaymentReceipt {
+            receipt: self,
+            signature,
+        }
+    }
+}
+
+/// A payment receipt and the signature of the node that was paid
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedPaymentReceipt {
+    pub receipt: PaymentReceipt,
+    pub signature: Signature,
+}
+
+impl SignedPaymentReceipt {
+    /// Checks that the receipt was signed by the node that was paid
+    pub fn verify(&self) -> Result<(), AltheaTypesError> {
+        let hash = get_ethereum_msg_hash(&self.receipt.signing_message());
+        match self.signature.recover(&hash) {
+            Ok(address) if address == self.receipt.from.eth_address => Ok(()),
+            Ok(_) => Err(AltheaTypesError::ReconciliationError(
+                "Payment receipt not signed by the node that was paid".to_string(),
+            )),
+            Err(e) => Err(AltheaTypesError::ReconciliationError(format!(
+                "Invalid payment receipt signature {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debt_summary_signature() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let from = Identity::new(
+            "fd00::1".parse().unwrap(),
+            key.to_address(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let to = Identity::new(
+            "fd00::2".parse().unwrap(),
+            "0x0000000000000000000000000000000000000002"
+                .parse()
+                .unwrap(),
+            "bvM10HW73yePrxdtCQQ4U20W5ogogdiZtUihrPc/oGY="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let summary = DebtSummary {
+            from,
+            to,
+            debt: Int256::from(-5000),
+            total_payment_sent: 100u32.into(),
+            total_payment_received: 2000u32.into(),
+            timestamp: 1_700_000_000,
+        }
+        .sign(key);
+        assert!(summary.verify().is_ok());
+
+        let mut tampered = summary.clone();
+        tampered.summary.debt = Int256::from(5000);
+        assert!(tampered.verify().is_err());
+
+        // signed by someone other than the sender
+        let mut forged = summary;
+        forged.summary.from = forged.summary.to;
+        assert!(forged.verify().is_err());
+
+        let receipt = PaymentReceipt {
+            from,
+            to,
+            txid: 0xabcdu32.into(),
+            amount: 2000u32.into(),
+            debt: Int256::from(-3000),
+            timestamp: 1_700_000_000,
+        }
+        .sign(key);
+        assert!(receipt.verify().is_ok());
+        let mut tampered = receipt.clone();
+        tampered.receipt.amount = 20_000u32.into();
+        assert!(tampered.verify().is_err());
+        let mut forged = receipt;
+        forged.receipt.from = forged.receipt.to;
+        assert!(forged.verify().is_err());
+    }
+}
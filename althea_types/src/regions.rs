@@ -8,6 +8,13 @@ use std::{
     str::FromStr,
 };
 
+/// Price in wei per byte an exit charges clients in a country instead of its default exit_price
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct CountryPrice {
+    pub country: Regions,
+    pub price: u64,
+}
+
 /// An enum representation of the Regions supported by althea exits
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub enum Regions {
@@ -0,0 +1,168 @@
+//! Types for the JSON-RPC 2.0 machine api served at /api/v1/rpc on the router dashboard port. Fleet scripts
+//! can use these to build requests and read responses instead of scraping the dashboard routes, every
+//! method and its params are listed in RpcCall.
+
+use clarity::Address;
+use num256::Uint256;
+use serde_json::Value;
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// Standard JSON-RPC error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The method ran and failed, data holds the status code and message the dashboard route gave
+pub const METHOD_FAILED: i64 = -32000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    /// Requests without an id are notifications and get no response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+    pub id: Value,
+}
+
+impl JsonRpcResponse {
+    pub fn result(id: Value, result: Value) -> JsonRpcResponse {
+        JsonRpcResponse {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+
+    pub fn error(id: Value, code: i64, message: String, data: Option<Value>) -> JsonRpcResponse {
+        JsonRpcResponse {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            result: None,
+            error: Some(JsonRpcError {
+                code,
+                message,
+                data,
+            }),
+            id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawParams {
+    pub address: Address,
+    /// Withdraws the whole balance if not set
+    #[serde(default)]
+    pub amount: Option<Uint256>,
+}
+
+/// Every method the machine api supports, parsed from a request's method and params
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", content = "params")]
+pub enum RpcCall {
+    /// Same result as GET /info
+    #[serde(rename = "status")]
+    Status,
+    /// Same result as GET /neighbors
+    #[serde(rename = "neighbors")]
+    Neighbors,
+    /// Same result as GET /debts
+    #[serde(rename = "debts")]
+    Debts,
+    #[serde(rename = "withdraw")]
+    Withdraw(WithdrawParams),
+    /// The full settings json
+    #[serde(rename = "settings.get")]
+    SettingsGet,
+    /// Merges the given json into the settings, like POST /settings
+    #[serde(rename = "settings.set")]
+    SettingsSet(Value),
+    /// Installs the firmware update offered by operator tools, like POST /router/update
+    #[serde(rename = "update.trigger")]
+    UpdateTrigger,
+}
+
+impl RpcCall {
+    pub const METHODS: [&'static str; 7] = [
+        "status",
+        "neighbors",
+        "debts",
+        "withdraw",
+        "settings.get",
+        "settings.set",
+        "update.trigger",
+    ];
+
+    /// Parses a request's method and params, methods without params accept null or a missing params
+    pub fn from_request(request: &JsonRpcRequest) -> Result<RpcCall, serde_json::Error> {
+        let mut call = serde_json::Map::new();
+        call.insert("method".to_string(), Value::String(request.method.clone()));
+        match &request.params {
+            None | Some(Value::Null) => {}
+            Some(params) => {
+                call.insert("params".to_string(), params.clone());
+            }
+        }
+        serde_json::from_value(Value::Object(call))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(method: &str, params: Option<Value>) -> JsonRpcRequest {
+        JsonRpcRequest {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: method.to_string(),
+            params,
+            id: Some(json!(1)),
+        }
+    }
+
+    #[test]
+    fn test_parse_rpc_call() {
+        assert_eq!(
+            RpcCall::from_request(&request("status", None)).unwrap(),
+            RpcCall::Status
+        );
+        assert_eq!(
+            RpcCall::from_request(&request("settings.get", Some(Value::Null))).unwrap(),
+            RpcCall::SettingsGet
+        );
+        let withdraw = RpcCall::from_request(&request(
+            "withdraw",
+            Some(json!({"address": "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"})),
+        ))
+        .unwrap();
+        assert!(matches!(
+            withdraw,
+            RpcCall::Withdraw(WithdrawParams { amount: None, .. })
+        ));
+        assert!(RpcCall::from_request(&request("withdraw", None)).is_err());
+        assert!(RpcCall::from_request(&request("reboot", None)).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! Versioned sealed envelopes for the payloads clients and exits exchange over the exit rpc endpoints.
+//! Each envelope carries a small header naming the envelope version, the algorithm and an id for the
+//! recipient key it was sealed to, so that either side can rotate keys or move to a new algorithm without
+//! another round of special cases in the endpoints.
+//!
+//! The header is flattened into the existing wire structs. Payloads from peers that predate it deserialize
+//! as version 0 and are opened exactly as before, while older peers simply ignore the extra fields. Nonces
+//! are random 24 byte values generated for every seal, large enough that collisions are not a concern.
+
+use crate::error::AltheaTypesError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::Nonce;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
+use sodiumoxide::crypto::hash::sha256;
+
+/// Envelopes sealed before the header existed
+pub const LEGACY_ENVELOPE_VERSION: u8 = 0;
+/// The envelope version we produce
+pub const SEALED_ENVELOPE_VERSION: u8 = 1;
+/// The largest ciphertext we try to open, far above any payload we exchange. Larger ones are refused
+/// before any work is done on them
+pub const MAX_SEALED_PAYLOAD_LEN: usize = 1024 * 1024;
+
+/// Algorithms an envelope may be sealed with
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SealAlgorithm {
+    /// LibSodium crypto_box, x25519 key agreement with xsalsa20poly1305
+    #[default]
+    Curve25519XSalsa20Poly1305,
+}
+
+/// Header sent alongside every sealed payload
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct SealHeader {
+    #[serde(default)]
+    pub envelope_version: u8,
+    #[serde(default)]
+    pub algorithm: SealAlgorithm,
+    /// Identifies the recipient key this payload was sealed to, lets a receiver holding
+    /// several keys pick the right one instead of trying each in turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<u32>,
+}
+
+/// The parts of a sealed payload, wire structs copy these into their own fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedPayload {
+    pub header: SealHeader,
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
+
+/// A short identifier for a public key, the first four bytes of its sha256 hash
+pub fn key_id(key: &PublicKey) -> u32 {
+    let hash = sha256::hash(&key.0);
+    u32::from_be_bytes([hash.0[0], hash.0[1], hash.0[2], hash.0[3]])
+}
+
+/// Serializes the payload to json and seals it to their_publickey with a fresh random nonce
+pub fn seal_json<T: Serialize>(
+    payload: &T,
+    their_publickey: &PublicKey,
+    our_secretkey: &SecretKey,
+) -> SealedPayload {
+    seal_json_with_nonce(payload, box_::gen_nonce(), their_publickey, our_secretkey)
+}
+
+fn seal_json_with_nonce<T: Serialize>(
+    payload: &T,
+    nonce: Nonce,
+    their_publickey: &PublicKey,
+    our_secretkey: &SecretKey,
+) -> SealedPayload {
+    // serde only fails to serialize maps with non string keys, none of our payloads contain them
+    let plaintext = serde_json::to_vec(payload).expect("Failed to serialize sealed payload!");
+    let ciphertext = box_::seal(&plaintext, &nonce, their_publickey, our_secretkey);
+    SealedPayload {
+        header: SealHeader {
+            envelope_version: SEALED_ENVELOPE_VERSION,
+            algorithm: SealAlgorithm::Curve25519XSalsa20Poly1305,
+            key_id: Some(key_id(their_publickey)),
+        },
+        nonce: nonce.0,
+        ciphertext,
+    }
+}
+
+/// Opens a payload sealed by their_publickey. The key id selects which of our keys to use, legacy
+/// envelopes without one are tried against each key in turn. Returns the payload along with the key
+/// that opened it so that the reply can be sealed with the same key
+pub fn open_json<T: DeserializeOwned>(
+    header: &SealHeader,
+    nonce: &[u8; 24],
+    ciphertext: &[u8],
+    their_publickey: &PublicKey,
+    our_secretkeys: &[SecretKey],
+) -> Result<(T, SecretKey), AltheaTypesError> {
+    if header.envelope_version > SEALED_ENVELOPE_VERSION {
+        return Err(AltheaTypesError::SealedBoxError(format!(
+            "Unsupported envelope version {}",
+            header.envelope_version
+        )));
+    }
+    if ciphertext.len() > MAX_SEALED_PAYLOAD_LEN {
+        return Err(AltheaTypesError::SealedBoxError(format!(
+            "Sealed payload of {} bytes is too large",
+            ciphertext.len()
+        )));
+    }
+    let candidates: Vec<&SecretKey> = match header.key_id {
+        Some(id) => our_secretkeys
+            .iter()
+            .filter(|k| key_id(&k.public_key()) == id)
+            .collect(),
+        None => our_secretkeys.iter().collect(),
+    };
+    if candidates.is_empty() {
+        return Err(AltheaTypesError::SealedBoxError(format!(
+            "Payload sealed to unknown key id {:?}",
+            header.key_id
+        )));
+    }
+
+    let nonce = Nonce(*nonce);
+    for key in candidates {
+        let plaintext = match header.algorithm {
+            SealAlgorithm::Curve25519XSalsa20Poly1305 => {
+                box_::open(ciphertext, &nonce, their_publickey, key)
+            }
+        };
+        if let Ok(plaintext) = plaintext {
+            return match serde_json::from_slice(&plaintext) {
+                Ok(payload) => Ok((payload, key.clone())),
+                Err(e) => Err(AltheaTypesError::SealedBoxError(format!(
+                    "Could not deserialize sealed payload {e}"
+                ))),
+            };
+        }
+    }
+    Err(AltheaTypesError::SealedBoxError(
+        "Could not decrypt sealed payload".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncryptedExitState;
+    use crate::ExitState;
+
+    const CLIENT_SECRET: &str = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+    const EXIT_SECRET: &str = "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb";
+    const EXIT_OLD_SECRET: &str =
+        "1111111111111111111111111111111111111111111111111111111111111111";
+    const NONCE: &str = "69696ee955b62b73cd62bda875fc73d68219e0036b7a0b37";
+    const PLAINTEXT: &str = r#"{"state":"New"}"#;
+    /// crypto_box of PLAINTEXT from the client key to the exit key with NONCE
+    const CIPHERTEXT: &str = "d47f4538c7ad680db90d357b647ef9f14bbc172e159d858437a00dc9ae3507";
+    /// key_id of the exit public key
+    const EXIT_KEY_ID: u32 = 0xf35e_5616;
+
+    fn hex(input: &str) -> Vec<u8> {
+        (0..input.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&input[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn secret(input: &str) -> SecretKey {
+        SecretKey::from_slice(&hex(input)).unwrap()
+    }
+
+    #[test]
+    fn test_seal_fixed_vector() {
+        let client = secret(CLIENT_SECRET);
+        let exit = secret(EXIT_SECRET);
+        let nonce = Nonce::from_slice(&hex(NONCE)).unwrap();
+
+        assert_eq!(key_id(&exit.public_key()), EXIT_KEY_ID);
+        let sealed = seal_json_with_nonce(&ExitState::New, nonce, &exit.public_key(), &client);
+        assert_eq!(sealed.ciphertext, hex(CIPHERTEXT));
+        assert_eq!(sealed.header.key_id, Some(EXIT_KEY_ID));
+        assert_eq!(sealed.header.envelope_version, SEALED_ENVELOPE_VERSION);
+        assert_eq!(
+            box_::open(&sealed.ciphertext, &nonce, &client.public_key(), &exit).unwrap(),
+            PLAINTEXT.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_open_picks_key_by_id() {
+        let client = secret(CLIENT_SECRET);
+        let exit = secret(EXIT_SECRET);
+        let old_exit = secret(EXIT_OLD_SECRET);
+
+        let sealed = seal_json(&ExitState::New, &exit.public_key(), &client);
+        let (state, key): (ExitState, SecretKey) = open_json(
+            &sealed.header,
+            &sealed.nonce,
+            &sealed.ciphertext,
+            &client.public_key(),
+            &[old_exit.clone(), exit.clone()],
+        )
+        .unwrap();
+        assert_eq!(state, ExitState::New);
+        assert_eq!(key, exit);
+
+        // a key id we do not hold is refused without trying to decrypt
+        assert!(open_json::<ExitState>(
+            &sealed.header,
+            &sealed.nonce,
+            &sealed.ciphertext,
+            &client.public_key(),
+            &[old_exit],
+        )
+        .is_err());
+
+        let mut future = sealed.header;
+        future.envelope_version = SEALED_ENVELOPE_VERSION + 1;
+        assert!(open_json::<ExitState>(
+            &future,
+            &sealed.nonce,
+            &sealed.ciphertext,
+            &client.public_key(),
+            &[exit],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_open_legacy_payload() {
+        let client = secret(CLIENT_SECRET);
+        let exit = secret(EXIT_SECRET);
+        let old_exit = secret(EXIT_OLD_SECRET);
+
+        // an exit state as sent by exits that predate the envelope header
+        let legacy = format!(
+            r#"{{"nonce":{:?},"encrypted_exit_state":{:?}}}"#,
+            hex(NONCE),
+            hex(CIPHERTEXT)
+        );
+        let legacy: EncryptedExitState = serde_json::from_str(&legacy).unwrap();
+        assert_eq!(legacy.header.envelope_version, LEGACY_ENVELOPE_VERSION);
+        assert_eq!(legacy.header.key_id, None);
+
+        let (state, key): (ExitState, SecretKey) = open_json(
+            &legacy.header,
+            &legacy.nonce,
+            &legacy.encrypted_exit_state,
+            &client.public_key(),
+            &[old_exit, exit.clone()],
+        )
+        .unwrap();
+        assert_eq!(state, ExitState::New);
+        assert_eq!(key, exit);
+    }
+
+    #[test]
+    fn test_open_oversized_payload() {
+        let client = secret(CLIENT_SECRET);
+        let exit = secret(EXIT_SECRET);
+        let header = SealHeader::default();
+        assert!(open_json::<ExitState>(
+            &header,
+            &[0; 24],
+            &vec![0; MAX_SEALED_PAYLOAD_LEN + 1],
+            &client.public_key(),
+            &[exit],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_fuzz_open() {
+        use crate::{EncryptedExitClientIdentity, EncryptedExitList};
+        use rand::Rng;
+
+        let client = secret(CLIENT_SECRET);
+        let exit = secret(EXIT_SECRET);
+        let mut rng = rand::thread_rng();
+        let sealed = EncryptedExitState::seal(&ExitState::New, &exit, &client.public_key());
+        for _ in 0..5000 {
+            let mut mutated = sealed.clone();
+            match rng.gen_range(0..5) {
+                0 => {
+                    let i = rng.gen_range(0..mutated.encrypted_exit_state.len());
+                    mutated.encrypted_exit_state[i] ^= rng.gen_range(1..=255u8);
+                }
+                1 => {
+                    let len = rng.gen_range(0..mutated.encrypted_exit_state.len());
+                    mutated.encrypted_exit_state.truncate(len);
+                }
+                2 => mutated.nonce[rng.gen_range(0..24)] ^= rng.gen_range(1..=255u8),
+                3 => {
+                    let id = sealed.header.key_id.unwrap();
+                    mutated.header.key_id = Some(id.wrapping_add(rng.gen_range(1..=u32::MAX)));
+                }
+                _ => mutated.header.envelope_version = rng.gen_range(2..=255),
+            }
+            // any change has to be caught, never opened as some other payload
+            assert!(mutated.open(&exit.public_key(), &client).is_err());
+        }
+
+        // the wire structs are parsed from whatever the other side sends
+        for _ in 0..5000 {
+            let len = rng.gen_range(0..256);
+            let junk: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let _ = serde_json::from_slice::<EncryptedExitClientIdentity>(&junk);
+            let _ = serde_json::from_slice::<EncryptedExitState>(&junk);
+            let _ = serde_json::from_slice::<EncryptedExitList>(&junk);
+            let fields = format!(
+                r#"{{"nonce":{:?},"encrypted_exit_state":{:?},"envelope_version":{},"key_id":{}}}"#,
+                (0..24).map(|_| rng.gen::<u8>()).collect::<Vec<u8>>(),
+                junk,
+                rng.gen::<u8>(),
+                rng.gen::<u32>()
+            );
+            if let Ok(state) = serde_json::from_str::<EncryptedExitState>(&fields) {
+                assert!(state.open(&exit.public_key(), &client).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! A lightweight proof of work exits can ask of clients signing up, to make scripted mass signups against
+//! email verified exits expensive without getting in the way of a single router signing up once. The exit
+//! advertises a difficulty in its ExitDetails, the client searches for a nonce such that the sha256 hash of
+//! its wg key, a timestamp and the nonce starts with that many zero bits and sends it with its signup. The
+//! proof is bound to the key signing up and only accepted for a while after the timestamp, so it can't be
+//! precomputed in bulk or handed around.
+
+use crate::error::AltheaTypesError;
+use crate::WgKey;
+use sodiumoxide::crypto::hash::sha256;
+
+/// Difficulties above this would take a router far too long to solve, exits cap what they ask for here
+pub const MAX_SIGNUP_DIFFICULTY: u8 = 28;
+/// How far a proof timestamp may be from the exit's clock, in seconds
+pub const SIGNUP_PROOF_MAX_AGE: u64 = 3600;
+
+/// Advertised by an exit that wants a proof of work with signups
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct SignupChallenge {
+    /// Leading zero bits the proof hash has to have
+    pub difficulty: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct SignupProof {
+    /// Unix time in seconds the proof was made at
+    pub timestamp: u64,
+    pub nonce: u64,
+}
+
+fn proof_hash(key: &WgKey, timestamp: u64, nonce: u64) -> [u8; 32] {
+    let mut bytes = key.as_ref().to_vec();
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    bytes.extend_from_slice(&nonce.to_be_bytes());
+    sha256::hash(&bytes).0
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+impl SignupChallenge {
+    /// Searches for a proof for this key, takes about 2^difficulty hashes
+    pub fn solve(&self, key: &WgKey, timestamp: u64) -> SignupProof {
+        let difficulty = self.difficulty.min(MAX_SIGNUP_DIFFICULTY) as u32;
+        let mut nonce = 0;
+        while leading_zero_bits(&proof_hash(key, timestamp, nonce)) < difficulty {
+            nonce += 1;
+        }
+        SignupProof { timestamp, nonce }
+    }
+
+    /// Checks a proof sent with a signup for this key at unix time now
+    pub fn verify(
+        &self,
+        key: &WgKey,
+        proof: Option<SignupProof>,
+        now: u64,
+    ) -> Result<(), AltheaTypesError> {
+        let proof = match proof {
+            Some(proof) => proof,
+            None => {
+                return Err(AltheaTypesError::SignupProofError(
+                    "This exit requires a signup proof of work, please update your router"
+                        .to_string(),
+                ))
+            }
+        };
+        if proof.timestamp.abs_diff(now) > SIGNUP_PROOF_MAX_AGE {
+            return Err(AltheaTypesError::SignupProofError(
+                "The signup proof of work is expired, check your router's clock".to_string(),
+            ));
+        }
+        let difficulty = self.difficulty.min(MAX_SIGNUP_DIFFICULTY) as u32;
+        if leading_zero_bits(&proof_hash(key, proof.timestamp, proof.nonce)) < difficulty {
+            return Err(AltheaTypesError::SignupProofError(
+                "Invalid signup proof of work".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signup_proof() {
+        assert_eq!(leading_zero_bits(&[0, 0x10, 0xff]), 11);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+
+        let key: WgKey = [7; 32].into();
+        let other: WgKey = [8; 32].into();
+        let challenge = SignupChallenge { difficulty: 12 };
+        let proof = challenge.solve(&key, 1_000_000);
+        assert!(challenge.verify(&key, Some(proof), 1_000_000).is_ok());
+        assert!(challenge.verify(&key, Some(proof), 1_000_600).is_ok());
+        assert!(challenge.verify(&key, None, 1_000_000).is_err());
+        assert!(challenge
+            .verify(&key, Some(proof), 1_000_000 + SIGNUP_PROOF_MAX_AGE + 1)
+            .is_err());
+        // proofs are bound to the key they were made for
+        assert!(challenge.verify(&other, Some(proof), 1_000_000).is_err());
+    }
+}
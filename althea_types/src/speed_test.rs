@@ -0,0 +1,136 @@
+//! Operator initiated throughput tests between two routers. The operator server signs a test naming the
+//! router that sends and the router that receives and hands it to the sender as an operator action. The
+//! sender presents it to the receiver over the mesh, both check the signature against the speed test key
+//! their operator configured, a dedicated key rather than the operator address, so only routers managed by
+//! the same operator take part, and each caps the test to its own limits
+
+use crate::error::AltheaTypesError;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+
+/// The contents of a speed test, this is what the operator signs
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct SpeedTest {
+    /// Random id of this test, routers only run each id once
+    pub id: u64,
+    /// Mesh ip of the router that sends the data
+    pub sender: IpAddr,
+    /// Mesh ip of the router that receives it and measures the throughput
+    pub receiver: IpAddr,
+    /// How long to send for in seconds
+    pub duration_secs: u64,
+    /// Rate to send at in bytes per second, None to send as fast as the routers allow
+    pub rate_limit: Option<u64>,
+    /// Unix timestamp in seconds after which this test is refused
+    pub expiry: u64,
+}
+
+impl SpeedTest {
+    fn signing_message(&self) -> Vec<u8> {
+        format!(
+            "althea speed test {}:{}:{}:{}:{}:{}",
+            self.id,
+            self.sender,
+            self.receiver,
+            self.duration_secs,
+            self.rate_limit.unwrap_or(0),
+            self.expiry
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(self, key: PrivateKey) -> SignedSpeedTest {
+        let signature = key.sign_ethereum_msg(&self.signing_message());
+        SignedSpeedTest {
+            test: self,
+            signature,
+        }
+    }
+}
+
+/// A speed test and the signature of the operator's speed test key over it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedSpeedTest {
+    pub test: SpeedTest,
+    pub signature: Signature,
+}
+
+/// Hashed by the test alone so that this can be carried in an OperatorAction, signatures over the same
+/// test only differ if they are from different signers
+impl Hash for SignedSpeedTest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.test.hash(state);
+    }
+}
+
+impl SignedSpeedTest {
+    /// Returns the address that signed this test
+    pub fn signer(&self) -> Result<Address, AltheaTypesError> {
+        let hash = get_ethereum_msg_hash(&self.test.signing_message());
+        match self.signature.recover(&hash) {
+            Ok(address) => Ok(address),
+            Err(e) => Err(AltheaTypesError::SpeedTestError(format!(
+                "Invalid speed test signature {e}"
+            ))),
+        }
+    }
+}
+
+/// Returned by the receiver when it accepts a test, the sender connects to port on the receiver's mesh ip
+/// and sends for the duration and at the rate given here, which may be lower than the operator asked for
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SpeedTestOffer {
+    pub port: u16,
+    pub duration_secs: u64,
+    pub rate_limit: u64,
+}
+
+/// The outcome of a speed test, reported by the sender in its next operator checkin
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SpeedTestResult {
+    pub id: u64,
+    pub sender: IpAddr,
+    pub receiver: IpAddr,
+    /// Bytes that reached the receiver and how long it spent receiving them
+    pub bytes: u64,
+    pub duration_ms: u64,
+    /// Throughput measured by the receiver, None if the test failed
+    pub mbps: Option<f32>,
+    /// Why the test failed, if it did
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_test_signature() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let signed = SpeedTest {
+            id: 7,
+            sender: "fd00::1".parse().unwrap(),
+            receiver: "fd00::2".parse().unwrap(),
+            duration_secs: 10,
+            rate_limit: Some(1_000_000),
+            expiry: 1_700_000_000,
+        }
+        .sign(key);
+        assert_eq!(signed.signer().unwrap(), key.to_address());
+
+        let json = serde_json::to_string(&signed).unwrap();
+        let decoded: SignedSpeedTest = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, signed);
+
+        // pointing the test at a different router changes the signer
+        let mut tampered = decoded;
+        tampered.test.receiver = "fd00::3".parse().unwrap();
+        assert_ne!(tampered.signer().ok(), Some(key.to_address()));
+    }
+}
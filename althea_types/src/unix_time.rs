@@ -0,0 +1,31 @@
+//! Unix timestamps in whole seconds, the form rita keeps on disk and sends to peers. A clock set before
+//! 1970 reads as the epoch rather than failing, a router that boots without a clock still runs and
+//! fixes its timestamps once ntp catches up.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time from the unix epoch to time, zero for a time before the epoch
+pub fn since_unix_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Seconds from the unix epoch to time, zero for a time before the epoch
+pub fn unix_secs(time: SystemTime) -> u64 {
+    since_unix_epoch(time).as_secs()
+}
+
+/// Seconds since the unix epoch
+pub fn now_secs() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_secs() {
+        assert_eq!(unix_secs(UNIX_EPOCH + Duration::from_millis(1500)), 1);
+        assert_eq!(unix_secs(UNIX_EPOCH - Duration::from_secs(10)), 0);
+    }
+}
@@ -0,0 +1,139 @@
+//! Prepaid vouchers let operators sell service credit to users who don't want to deal with crypto.
+//! The operator signs a voucher with their eth key and hands the resulting code to the user, who enters
+//! it on their router dashboard. The router passes it along to the exit which checks the signature,
+//! records the redemption so it can't be used twice, and credits the user
+
+use crate::error::AltheaTypesError;
+use crate::Identity;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+use num256::Uint256;
+use sodiumoxide::randombytes::randombytes_into;
+
+/// The contents of a voucher, this is what the operator signs
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Voucher {
+    /// Random id of this voucher, used by the exit to prevent double spends
+    pub id: u64,
+    /// The amount of credit this voucher is worth, in wei of the debt keeper denom
+    pub amount: Uint256,
+    /// Unix timestamp in seconds after which this voucher can no longer be redeemed
+    pub expiry: u64,
+}
+
+impl Voucher {
+    /// A voucher worth amount that expires at expiry, with a random id
+    pub fn new(amount: Uint256, expiry: u64) -> Voucher {
+        let mut id = [0u8; 8];
+        randombytes_into(&mut id);
+        Voucher {
+            id: u64::from_le_bytes(id),
+            amount,
+            expiry,
+        }
+    }
+
+    /// The message that is signed by the operator, the amount is encoded as a decimal
+    /// string so that this does not depend on the internal representation of Uint256
+    fn signing_message(&self) -> Vec<u8> {
+        format!("althea voucher {}:{}:{}", self.id, self.amount, self.expiry).into_bytes()
+    }
+
+    pub fn sign(self, key: PrivateKey) -> SignedVoucher {
+        let signature = key.sign_ethereum_msg(&self.signing_message());
+        SignedVoucher {
+            voucher: self,
+            signature,
+        }
+    }
+}
+
+/// A voucher and the operator signature over it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedVoucher {
+    pub voucher: Voucher,
+    pub signature: Signature,
+}
+
+impl SignedVoucher {
+    /// Returns the address that signed this voucher
+    pub fn signer(&self) -> Result<Address, AltheaTypesError> {
+        let hash = get_ethereum_msg_hash(&self.voucher.signing_message());
+        match self.signature.recover(&hash) {
+            Ok(address) => Ok(address),
+            Err(e) => Err(AltheaTypesError::VoucherError(format!(
+                "Invalid voucher signature {e}"
+            ))),
+        }
+    }
+
+    /// Encodes this voucher as a url safe code that can be handed to a user
+    pub fn to_code(&self) -> String {
+        let bytes = bincode::serialize(self).expect("Failed to serialize voucher!");
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn from_code(code: &str) -> Result<SignedVoucher, AltheaTypesError> {
+        let bytes = match base64::decode_config(code.trim(), base64::URL_SAFE_NO_PAD) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return Err(AltheaTypesError::VoucherError(format!(
+                    "Invalid voucher code {e}"
+                )))
+            }
+        };
+        match bincode::deserialize(&bytes) {
+            Ok(voucher) => Ok(voucher),
+            Err(e) => Err(AltheaTypesError::VoucherError(format!(
+                "Invalid voucher code {e}"
+            ))),
+        }
+    }
+}
+
+/// Sent by a router to it's exit to redeem a voucher, the credit is applied to the client
+/// identity included in the message
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VoucherRedemption {
+    pub client: Identity,
+    pub voucher: SignedVoucher,
+}
+
+/// Returned by the exit after a successful redemption
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VoucherRedemptionResult {
+    pub id: u64,
+    pub amount: Uint256,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voucher_code_round_trip() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let voucher = Voucher {
+            id: 42,
+            amount: 1_000_000_000_000_000_000u128.into(),
+            expiry: 1_700_000_000,
+        }
+        .sign(key);
+
+        let code = voucher.to_code();
+        let decoded = SignedVoucher::from_code(&code).unwrap();
+        assert_eq!(decoded, voucher);
+        assert_eq!(decoded.signer().unwrap(), key.to_address());
+
+        // tampering with the amount should change the signer
+        let mut tampered = decoded;
+        tampered.voucher.amount = 2_000_000_000_000_000_000u128.into();
+        assert_ne!(tampered.signer().ok(), Some(key.to_address()));
+
+        assert!(SignedVoucher::from_code("not a voucher").is_err());
+    }
+}
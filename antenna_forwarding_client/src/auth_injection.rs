@@ -0,0 +1,208 @@
+//! Adds operator supplied credentials to http requests forwarded to an antenna. Forwarded streams are raw
+//! tcp, so each stream from the server is followed request by request: headers are buffered until complete,
+//! any authorization header the browser sent is replaced with ours and the body is passed through untouched
+//! using its content length. Anything that is not plain http/1.x, such as a tls session to an https antenna
+//! or a chunked request body, is passed through unmodified for the rest of the stream.
+
+use antenna_forwarding_protocol::HttpAuthInjection;
+use std::collections::HashMap;
+
+/// Requests with larger headers than this are passed through untouched
+const MAX_HEADER_LEN: usize = 16 * 1024;
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StreamState {
+    /// Waiting for the end of a request's headers, holds what has arrived so far
+    Headers(Vec<u8>),
+    /// Inside a request body with this many bytes left
+    Body(usize),
+    /// Not something we can safely rewrite, forward as is
+    Passthrough,
+}
+
+pub struct AuthInjector {
+    authorization: String,
+    streams: HashMap<u64, StreamState>,
+}
+
+impl AuthInjector {
+    /// Returns None if the injection rule is not a single valid header value
+    pub fn new(auth: HttpAuthInjection) -> Option<AuthInjector> {
+        if !auth.is_valid() {
+            return None;
+        }
+        Some(AuthInjector {
+            authorization: auth.authorization,
+            streams: HashMap::new(),
+        })
+    }
+
+    /// Takes data from the server for the given stream and returns what should be written to the
+    /// antenna, which may be empty while a request's headers are incomplete
+    pub fn process(&mut self, stream_id: u64, mut input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len() + self.authorization.len() + 32);
+        let mut state = self
+            .streams
+            .remove(&stream_id)
+            .unwrap_or_else(|| StreamState::Headers(Vec::new()));
+        while !input.is_empty() {
+            state = match state {
+                StreamState::Passthrough => {
+                    out.extend_from_slice(input);
+                    input = &[];
+                    StreamState::Passthrough
+                }
+                StreamState::Body(remaining) => {
+                    let take = remaining.min(input.len());
+                    out.extend_from_slice(&input[..take]);
+                    input = &input[take..];
+                    if remaining == take {
+                        StreamState::Headers(Vec::new())
+                    } else {
+                        StreamState::Body(remaining - take)
+                    }
+                }
+                StreamState::Headers(mut buf) => {
+                    let old_len = buf.len();
+                    let start = old_len.saturating_sub(HEADER_END.len() - 1);
+                    buf.extend_from_slice(input);
+                    if !buf[0].is_ascii_uppercase() {
+                        out.extend_from_slice(&buf);
+                        input = &[];
+                        StreamState::Passthrough
+                    } else if let Some(pos) = find(&buf[start..], HEADER_END) {
+                        // the end of the headers can not have been in what we already held, so
+                        // everything after it is still in input and is handled by the next state
+                        let end = start + pos + HEADER_END.len();
+                        input = &input[end - old_len..];
+                        match self.rewrite_headers(&buf[..end]) {
+                            Some((headers, next)) => {
+                                out.extend_from_slice(&headers);
+                                next
+                            }
+                            None => {
+                                out.extend_from_slice(&buf[..end]);
+                                StreamState::Passthrough
+                            }
+                        }
+                    } else if buf.len() > MAX_HEADER_LEN {
+                        out.extend_from_slice(&buf);
+                        input = &[];
+                        StreamState::Passthrough
+                    } else {
+                        input = &[];
+                        StreamState::Headers(buf)
+                    }
+                }
+            };
+        }
+        self.streams.insert(stream_id, state);
+        out
+    }
+
+    /// Returns the headers with our authorization and the state for what follows them, None if this
+    /// does not look like an http/1.x request we can rewrite
+    fn rewrite_headers(&self, headers: &[u8]) -> Option<(Vec<u8>, StreamState)> {
+        let text = std::str::from_utf8(headers).ok()?;
+        let mut lines = text.split("\r\n");
+        let request_line = lines.next()?;
+        if !request_line.contains(" HTTP/1.") {
+            return None;
+        }
+        let mut out = format!(
+            "{request_line}\r\nAuthorization: {}\r\n",
+            self.authorization
+        );
+        let mut next = StreamState::Headers(Vec::new());
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+                None => return None,
+            };
+            match name.as_str() {
+                "authorization" => continue,
+                "content-length" => next = StreamState::Body(value.parse().ok()?),
+                "transfer-encoding" => next = StreamState::Passthrough,
+                _ => {}
+            }
+            out.push_str(line);
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\n");
+        if next == StreamState::Body(0) {
+            next = StreamState::Headers(Vec::new());
+        }
+        Some((out.into_bytes(), next))
+    }
+
+    pub fn forget(&mut self, stream_id: u64) {
+        self.streams.remove(&stream_id);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn injector() -> AuthInjector {
+        AuthInjector::new(HttpAuthInjection {
+            authorization: "Basic dWJudDp1Ym50".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_invalid_rule() {
+        assert!(AuthInjector::new(HttpAuthInjection {
+            authorization: "Basic x\r\nX-Evil: 1".to_string(),
+        })
+        .is_none());
+    }
+
+    #[test]
+    fn test_inject_split_request() {
+        let mut injector = injector();
+        let first = b"POST /upload HTTP/1.1\r\nHost: 192.168.1.20\r\nAuthori";
+        let second = b"zation: Basic b2xk\r\nContent-Length: 4\r\n\r\nab";
+        assert!(injector.process(1, first).is_empty());
+        let out = injector.process(1, second);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "POST /upload HTTP/1.1\r\nAuthorization: Basic dWJudDp1Ym50\r\nHost: 192.168.1.20\r\nContent-Length: 4\r\n\r\nab"
+        );
+        // the rest of the body passes through untouched
+        assert_eq!(injector.process(1, b"cd"), b"cd");
+        // and the next request on the connection is rewritten too
+        let out = injector.process(1, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "GET / HTTP/1.1\r\nAuthorization: Basic dWJudDp1Ym50\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut injector = injector();
+        // a tls client hello
+        let hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01];
+        assert_eq!(injector.process(2, &hello), hello);
+        assert_eq!(
+            injector.process(2, b"GET / HTTP/1.1\r\n\r\n"),
+            b"GET / HTTP/1.1\r\n\r\n"
+        );
+
+        // chunked bodies are not followed, the rest of the stream is left alone
+        let request = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\n";
+        let out = injector.process(3, request);
+        assert!(out.ends_with(b"\r\n\r\n4\r\nabcd\r\n"));
+        assert_eq!(
+            injector.process(3, b"GET / HTTP/1.1\r\n\r\n"),
+            b"GET / HTTP/1.1\r\n\r\n"
+        );
+    }
+}
@@ -11,12 +11,14 @@ use althea_kernel_interface::KernelInterface;
 use althea_kernel_interface::LinuxCommandRunner;
 use althea_types::Identity;
 use althea_types::WgKey;
-use antenna_forwarding_protocol::process_streams;
+use antenna_forwarding_protocol::process_streams_limited;
+use antenna_forwarding_protocol::rate_limit::TokenBucket;
 use antenna_forwarding_protocol::write_all_spinlock;
 use antenna_forwarding_protocol::ExternalStream;
 use antenna_forwarding_protocol::ForwardingProtocolMessage;
 use antenna_forwarding_protocol::NET_TIMEOUT;
 use antenna_forwarding_protocol::SPINLOCK_TIME;
+use bytes::BytesMut;
 use oping::Ping;
 use rand::Rng;
 use std::collections::HashMap;
@@ -31,8 +33,13 @@ use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
+mod auth_injection;
 mod error;
+mod session_record;
+use auth_injection::AuthInjector;
 pub use error::AntennaForwardingError;
+pub use session_record::{get_session_records, load_session_records, remove_session_records};
+use session_record::{SessionRecorder, SessionStats};
 
 lazy_static! {
     pub static ref KI: Box<dyn KernelInterface> = Box::new(LinuxCommandRunner {});
@@ -44,19 +51,51 @@ const SLEEP_TIME: Duration = Duration::from_secs(20);
 const PING_TIMEOUT: Duration = Duration::from_millis(100);
 /// the amount of time with no activity before we close a forwarding session
 const FORWARD_TIMEOUT: Duration = Duration::from_secs(600);
+/// the longest we will keep flushing open streams after the server asks us to close
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// a drain ends early once no stream has moved data for this long
+const DRAIN_IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Everything the forwarding proxy needs to check in with the server and forward antennas
+#[derive(Debug, Clone)]
+pub struct AntennaForwardingConfig {
+    pub checkin_address: String,
+    pub our_id: Identity,
+    pub server_public_key: WgKey,
+    pub our_public_key: WgKey,
+    pub our_private_key: WgKey,
+    pub interfaces_to_search: HashSet<String>,
+    /// If antenna credentials sent by the server with a forward request are added to forwarded
+    /// http requests
+    pub allow_auth_injection: bool,
+    /// Sessions are limited to the rate in the forward request if the server sends one,
+    /// otherwise to this
+    pub default_rate_limit: Option<u64>,
+    /// If an audit record of every session is queued, see get_session_records
+    pub record_sessions: bool,
+    /// Where queued session records are kept until the operator has them
+    pub session_records_file: String,
+}
 
 /// Starts a thread that will check in with the provided server repeatedly and forward antennas
-/// when the right signal is received. The type bound is so that you can use custom hashers and
-/// may not really be worth keeping around.
-pub fn start_antenna_forwarding_proxy<S: 'static + std::marker::Send + ::std::hash::BuildHasher>(
-    checkin_address: String,
-    our_id: Identity,
-    server_public_key: WgKey,
-    _our_public_key: WgKey,
-    our_private_key: WgKey,
-    interfaces_to_search: HashSet<String, S>,
-) {
+/// when the right signal is received
+pub fn start_antenna_forwarding_proxy(config: AntennaForwardingConfig) {
     info!("Starting antenna forwarding proxy!");
+    let AntennaForwardingConfig {
+        checkin_address,
+        our_id,
+        server_public_key,
+        our_public_key,
+        our_private_key,
+        interfaces_to_search,
+        allow_auth_injection,
+        default_rate_limit,
+        record_sessions,
+        session_records_file,
+    } = config;
+    if record_sessions {
+        load_session_records(&session_records_file);
+    }
     // The last resolved IP address for the forwarding proxy. In the case that we suddenly
     // stop getting successful DNS responses we will fall back to the last successful response
     // this covers a pretty small edge case of a failed major DNS server. For example a cloudflare
@@ -115,8 +154,41 @@ pub fn start_antenna_forwarding_proxy<S: 'static + std::marker::Send + ::std::ha
                             ip,
                             server_port: _server_port,
                             antenna_port,
+                            auth,
+                            rate_limit,
+                            requested_by,
                         }) => {
                             info!("Got forwarding message, forwarding {}", ip);
+                            let recorder = record_sessions.then(|| {
+                                SessionRecorder::start(
+                                    our_public_key,
+                                    requested_by.clone(),
+                                    *ip,
+                                    *antenna_port,
+                                )
+                            });
+                            // a limit of zero means no limit
+                            let limiter = rate_limit
+                                .or(default_rate_limit)
+                                .filter(|rate| *rate > 0)
+                                .map(|rate| {
+                                    info!("Limiting forwarding session to {} bytes/s", rate);
+                                    TokenBucket::new(rate)
+                                });
+                            let injector = match auth {
+                                Some(auth) if allow_auth_injection => {
+                                    let injector = AuthInjector::new(auth.clone());
+                                    if injector.is_none() {
+                                        warn!("Ignoring invalid antenna credentials");
+                                    }
+                                    injector
+                                }
+                                Some(_) => {
+                                    info!("Antenna credential injection is disabled, ignoring");
+                                    None
+                                }
+                                None => None,
+                            };
                             // if there are other messages in this batch safely form a slice
                             // to pass on
                             let slice = if messages.len() > 1 {
@@ -128,9 +200,25 @@ pub fn start_antenna_forwarding_proxy<S: 'static + std::marker::Send + ::std::ha
                             // setup networking and process the rest of the messages in this batch
                             match setup_networking(*ip, *antenna_port, &interfaces_to_search) {
                                 Ok(antenna_sockaddr) => {
-                                    forward_connections(antenna_sockaddr, server_stream, slice);
+                                    let stats = forward_connections(
+                                        antenna_sockaddr,
+                                        server_stream,
+                                        slice,
+                                        injector,
+                                        limiter,
+                                    );
+                                    if let Some(recorder) = recorder {
+                                        recorder.finish(stats, None);
+                                    }
+                                }
+                                Err(e) => {
+                                    let error = format!("{e:?}");
+                                    if let Some(recorder) = recorder {
+                                        recorder
+                                            .finish(SessionStats::default(), Some(error.clone()));
+                                    }
+                                    send_error_message(&mut server_stream, error)
                                 }
-                                Err(e) => send_error_message(&mut server_stream, format!("{e:?}")),
                             }
                         }
                         Some(ForwardingProtocolMessage::ForwardingCloseMessage) => {}
@@ -148,133 +236,243 @@ pub fn start_antenna_forwarding_proxy<S: 'static + std::marker::Send + ::std::ha
     });
 }
 
-/// Processes an array of messages and takes the appropriate actions
-/// returns if the forwarder should shutdown becuase a shutdown message
-/// was found in the message batch.
-fn process_messages(
-    input: &[ForwardingProtocolMessage],
-    streams: &mut HashMap<u64, ExternalStream>,
-    server_stream: &mut TcpStream,
-    last_message: &mut Instant,
+/// The state of one forwarding session, from the forward message until the server closes it
+struct ForwardingSession {
     antenna_sockaddr: SocketAddr,
-) -> bool {
-    for item in input {
-        match item {
-            // why would the server ID themselves to us?
-            ForwardingProtocolMessage::IdentificationMessage { .. } => {
-                error!("Why did the server identify?")
+    streams: HashMap<u64, ExternalStream>,
+    last_message: Instant,
+    /// only lives as long as this session, credentials are never written anywhere
+    injector: Option<AuthInjector>,
+    limiter: Option<TokenBucket>,
+    stats: SessionStats,
+}
+
+impl ForwardingSession {
+    fn new(
+        antenna_sockaddr: SocketAddr,
+        injector: Option<AuthInjector>,
+        limiter: Option<TokenBucket>,
+    ) -> ForwardingSession {
+        ForwardingSession {
+            antenna_sockaddr,
+            streams: HashMap::new(),
+            last_message: Instant::now(),
+            injector,
+            limiter,
+            stats: SessionStats::default(),
+        }
+    }
+
+    /// Processes an array of messages and takes the appropriate actions
+    /// returns if the forwarder should drain and shutdown becuase a shutdown
+    /// message was found in the message batch. While draining no new streams
+    /// are opened, data for streams we already have is still delivered
+    fn process_messages(&mut self, input: &[ForwardingProtocolMessage], draining: bool) -> bool {
+        let mut close_requested = false;
+        for item in input {
+            match item {
+                // why would the server ID themselves to us?
+                ForwardingProtocolMessage::IdentificationMessage { .. } => {
+                    error!("Why did the server identify?")
+                }
+                // two forward messages?
+                ForwardingProtocolMessage::ForwardMessage { .. } => {
+                    error!("Got second forward message?")
+                }
+                // the server doesn't send us error messages, what would we do with it?
+                ForwardingProtocolMessage::ErrorMessage { .. } => {
+                    error!("Server sent us an error message?")
+                }
+                ForwardingProtocolMessage::ConnectionCloseMessage { stream_id } => {
+                    trace!("Got close message for stream {}", stream_id);
+                    self.last_message = Instant::now();
+                    if let Some(injector) = self.injector.as_mut() {
+                        injector.forget(*stream_id);
+                    }
+                    if let Some(stream) = self.streams.get(stream_id) {
+                        let _res = stream.stream.shutdown(Shutdown::Both);
+                        self.streams.remove(stream_id);
+                    } else {
+                        error!("Tried to remove stream {} that we did not have", stream_id);
+                    }
+                }
+                ForwardingProtocolMessage::ConnectionDataMessage { stream_id, payload } => {
+                    trace!(
+                        "Got connection message for stream {} payload {} bytes",
+                        stream_id,
+                        payload.len()
+                    );
+                    self.last_message = Instant::now();
+                    self.deliver(*stream_id, payload, draining || close_requested);
+                }
+                ForwardingProtocolMessage::ForwardingCloseMessage => {
+                    trace!("Got halt message");
+                    // keep going, data after the close in this batch still belongs to open streams
+                    close_requested = true;
+                }
+                // we don't use this yet
+                ForwardingProtocolMessage::KeepAliveMessage => {}
+                // only the client sends acks
+                ForwardingProtocolMessage::ForwardingCloseAckMessage => {
+                    error!("Server sent us a close ack?")
+                }
+            }
+        }
+        close_requested
+    }
+
+    /// Writes a payload from the server to its antenna stream, dialing the antenna for streams we
+    /// have not seen yet unless the session is closing
+    fn deliver(&mut self, stream_id: u64, payload: &[u8], closing: bool) {
+        let injected;
+        let payload: &[u8] = match self.injector.as_mut() {
+            Some(injector) => {
+                injected = injector.process(stream_id, payload);
+                &injected
             }
-            // two forward messages?
-            ForwardingProtocolMessage::ForwardMessage { .. } => {
-                error!("Got second forward message?")
+            None => payload,
+        };
+        if let Some(antenna_stream) = self.streams.get_mut(&stream_id) {
+            match write_all_spinlock(&mut antenna_stream.stream, payload) {
+                Ok(_) => self.stats.bytes_to_antenna += payload.len() as u64,
+                Err(e) => error!(
+                    "Failed to write to antenna stream id {} with {:?}",
+                    stream_id, e
+                ),
             }
-            // the server doesn't send us error messages, what would we do with it?
-            ForwardingProtocolMessage::ErrorMessage { .. } => {
-                error!("Server sent us an error message?")
+            if let Some(limiter) = self.limiter.as_mut() {
+                limiter.consume(payload.len());
             }
-            ForwardingProtocolMessage::ConnectionCloseMessage { stream_id } => {
-                trace!("Got close message for stream {}", stream_id);
-                *last_message = Instant::now();
-                if let Some(stream) = streams.get(stream_id) {
-                    let _res = stream.stream.shutdown(Shutdown::Both);
-                    streams.remove(stream_id);
-                } else {
-                    error!("Tried to remove stream {} that we did not have", stream_id);
-                }
+            antenna_stream.last_message = Instant::now();
+        } else if closing {
+            trace!("Not opening stream {} while draining", stream_id);
+            if let Some(injector) = self.injector.as_mut() {
+                injector.forget(stream_id);
             }
-            ForwardingProtocolMessage::ConnectionDataMessage { stream_id, payload } => {
-                trace!(
-                    "Got connection message for stream {} payload {} bytes",
-                    stream_id,
-                    payload.len()
-                );
-                *last_message = Instant::now();
-                if let Some(antenna_stream) = streams.get_mut(stream_id) {
-                    if let Err(e) = write_all_spinlock(&mut antenna_stream.stream, payload) {
-                        error!(
-                            "Failed to write to antenna stream id {} with {:?}",
-                            stream_id, e
-                        );
-                    }
-                } else {
-                    trace!("Opening stream for {}", stream_id);
-                    // we don't have a stream, we need to dial out to the server now
-                    if let Ok(mut new_stream) = TcpStream::connect(antenna_sockaddr) {
-                        match write_all_spinlock(&mut new_stream, payload) {
-                            Ok(_) => {
-                                streams.insert(
-                                    *stream_id,
-                                    ExternalStream {
-                                        stream: new_stream,
-                                        last_message: Instant::now(),
-                                    },
-                                );
-                            }
-                            Err(e) => error!(
-                                "Failed to write to antenna stream id {} with {:?}",
-                                stream_id, e
-                            ),
+        } else {
+            trace!("Opening stream for {}", stream_id);
+            // we don't have a stream, we need to dial out to the server now
+            if let Ok(mut new_stream) = TcpStream::connect(self.antenna_sockaddr) {
+                match write_all_spinlock(&mut new_stream, payload) {
+                    Ok(_) => {
+                        if let Some(limiter) = self.limiter.as_mut() {
+                            limiter.consume(payload.len());
                         }
+                        self.stats.bytes_to_antenna += payload.len() as u64;
+                        self.stats.streams += 1;
+                        self.streams.insert(
+                            stream_id,
+                            ExternalStream {
+                                stream: new_stream,
+                                last_message: Instant::now(),
+                            },
+                        );
                     }
+                    Err(e) => error!(
+                        "Failed to write to antenna stream id {} with {:?}",
+                        stream_id, e
+                    ),
                 }
             }
-            ForwardingProtocolMessage::ForwardingCloseMessage => {
-                trace!("Got halt message");
-                // we have a close lets get out of here.
-                for stream in streams.values_mut() {
-                    let _ = stream.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Called once the server has asked us to close. Flushes data in both directions for the streams
+    /// that are still open, until they all close, go idle for DRAIN_IDLE_TIMEOUT or DRAIN_TIMEOUT passes,
+    /// so that an upload in progress is not truncated. Then acks the close and shuts everything down
+    fn drain_and_close(&mut self, server_stream: &mut TcpStream, read_buf: &mut BytesMut) {
+        info!("Draining {} forwarded streams", self.streams.len());
+        let start = Instant::now();
+        while !self.streams.is_empty() && start.elapsed() < DRAIN_TIMEOUT {
+            self.stats.bytes_from_antenna +=
+                process_streams_limited(&mut self.streams, server_stream, self.limiter.as_mut());
+            match ForwardingProtocolMessage::read_messages_with_buffer(server_stream, read_buf) {
+                Ok(vec) => {
+                    self.process_messages(&vec, true);
+                }
+                Err(e) => {
+                    warn!("Server connection failed while draining {:?}", e);
+                    break;
                 }
-                let _ = server_stream.shutdown(Shutdown::Both);
-                return true;
             }
-            // we don't use this yet
-            ForwardingProtocolMessage::KeepAliveMessage => {}
+            let idle = self
+                .streams
+                .values()
+                .map(|s| s.last_message)
+                .chain(std::iter::once(self.last_message))
+                .all(|t| t.elapsed() > DRAIN_IDLE_TIMEOUT);
+            if idle {
+                break;
+            }
+            thread::sleep(self.sleep_time());
+        }
+        for stream in self.streams.values_mut() {
+            let _ = stream.stream.shutdown(Shutdown::Both);
+        }
+        self.streams.clear();
+        let _ = write_all_spinlock(
+            server_stream,
+            &ForwardingProtocolMessage::new_forwarding_close_ack_message().get_message(),
+        );
+        let _ = server_stream.shutdown(Shutdown::Both);
+        info!(
+            "Forwarding session closed after {:?} drain",
+            start.elapsed()
+        );
+    }
+
+    /// When the session is over its rate limit we wait out the debt before touching the sockets again,
+    /// the unread data backs up in tcp and slows down whoever is sending it
+    fn sleep_time(&mut self) -> Duration {
+        match self.limiter.as_mut() {
+            Some(limiter) => limiter.wait_time().max(SPINLOCK_TIME),
+            None => SPINLOCK_TIME,
         }
     }
-    false
 }
 
 /// Actually forwards the connection by managing the reading and writing from
-/// various tcp sockets
+/// various tcp sockets, returns how much moved through the session
 fn forward_connections(
     antenna_sockaddr: SocketAddr,
     server_stream: TcpStream,
     first_round_input: &[ForwardingProtocolMessage],
-) {
+    injector: Option<AuthInjector>,
+    limiter: Option<TokenBucket>,
+) -> SessionStats {
     trace!("Forwarding connections!");
+    let mut session = ForwardingSession::new(antenna_sockaddr, injector, limiter);
     let mut server_stream = server_stream;
-    let mut streams: HashMap<u64, ExternalStream> = HashMap::new();
-    let mut last_message = Instant::now();
-    process_messages(
-        first_round_input,
-        &mut streams,
-        &mut server_stream,
-        &mut last_message,
-        antenna_sockaddr,
-    );
+    // reused across reads so that we aren't allocating a new buffer every loop
+    let mut read_buf = BytesMut::new();
+    if session.process_messages(first_round_input, false) {
+        session.drain_and_close(&mut server_stream, &mut read_buf);
+        return session.stats;
+    }
 
-    while let Ok(vec) = ForwardingProtocolMessage::read_messages(&mut server_stream) {
+    while let Ok(vec) =
+        ForwardingProtocolMessage::read_messages_with_buffer(&mut server_stream, &mut read_buf)
+    {
         if !vec.is_empty() {
             trace!("In forwarding loop! got {} messages", vec.len());
         }
-        process_streams(&mut streams, &mut server_stream);
-        let should_shutdown = process_messages(
-            &vec,
-            &mut streams,
+        session.stats.bytes_from_antenna += process_streams_limited(
+            &mut session.streams,
             &mut server_stream,
-            &mut last_message,
-            antenna_sockaddr,
+            session.limiter.as_mut(),
         );
-        if should_shutdown {
+        if session.process_messages(&vec, false) {
+            session.drain_and_close(&mut server_stream, &mut read_buf);
             break;
         }
 
-        if Instant::now() - last_message > FORWARD_TIMEOUT {
+        if Instant::now() - session.last_message > FORWARD_TIMEOUT {
             error!("Fowarding session timed out!");
             break;
         }
-        thread::sleep(SPINLOCK_TIME);
+        thread::sleep(session.sleep_time());
     }
+    session.stats
 }
 
 /// handles the setup of networking to the selected antenna, including finding it and the like
@@ -0,0 +1,151 @@
+//! Audit records of forwarding sessions, see althea_types::antenna_session. Records are only kept when
+//! the proxy is started with record_sessions, and wait here until the operator checkin signs and
+//! ships them. Only the sizes of what moves through a session are counted, never its contents. Pending
+//! records are kept in a file so that a reboot before the next checkin does not lose them, it is only
+//! written when a session ends or the operator takes records, both of which are rare
+
+use althea_types::unix_time::now_secs;
+use althea_types::AntennaSessionRecord;
+use althea_types::WgKey;
+use std::collections::VecDeque;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// Records kept while the operator server can't be reached, the oldest are dropped first
+const MAX_PENDING_SESSION_RECORDS: usize = 64;
+
+lazy_static! {
+    static ref SESSION_RECORDS: Arc<RwLock<SessionRecords>> =
+        Arc::new(RwLock::new(SessionRecords::default()));
+}
+
+/// Records waiting for the operator and the file they are kept in, None until the proxy starts
+#[derive(Debug, Default)]
+struct SessionRecords {
+    file: Option<String>,
+    records: VecDeque<AntennaSessionRecord>,
+}
+
+impl SessionRecords {
+    fn save(&self) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let serialized = serde_json::to_vec(&self.records).expect("Failed to serialize sessions!");
+        if let Err(e) = fs::write(file, serialized) {
+            error!("Failed to save antenna session records {:?}", e);
+        }
+    }
+}
+
+/// Loads records left over from before a restart and keeps records in this file from now on
+pub fn load_session_records(file: &str) {
+    let records: VecDeque<AntennaSessionRecord> = match fs::read(file) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(records) => records,
+            Err(e) => {
+                error!("Failed to deserialize antenna session records {:?}", e);
+                VecDeque::new()
+            }
+        },
+        Err(e) => {
+            info!("No antenna session records loaded {:?}", e);
+            VecDeque::new()
+        }
+    };
+    let mut session_records = SESSION_RECORDS.write().unwrap();
+    session_records.file = Some(file.to_string());
+    for record in records {
+        push_record(&mut session_records.records, record);
+    }
+}
+
+/// Counters for a session in progress
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    pub bytes_to_antenna: u64,
+    pub bytes_from_antenna: u64,
+    pub streams: u64,
+}
+
+/// A session being recorded, its record is queued by finish()
+pub struct SessionRecorder {
+    record: AntennaSessionRecord,
+}
+
+impl SessionRecorder {
+    pub fn start(
+        router: WgKey,
+        requested_by: Option<String>,
+        antenna_ip: IpAddr,
+        antenna_port: u16,
+    ) -> SessionRecorder {
+        let start = now_secs();
+        SessionRecorder {
+            record: AntennaSessionRecord {
+                id: rand::random(),
+                router,
+                requested_by,
+                antenna_ip,
+                antenna_port,
+                start,
+                end: start,
+                bytes_to_antenna: 0,
+                bytes_from_antenna: 0,
+                streams: 0,
+                error: None,
+            },
+        }
+    }
+
+    pub fn finish(self, stats: SessionStats, error: Option<String>) {
+        let record = AntennaSessionRecord {
+            end: now_secs(),
+            bytes_to_antenna: stats.bytes_to_antenna,
+            bytes_from_antenna: stats.bytes_from_antenna,
+            streams: stats.streams,
+            error,
+            ..self.record
+        };
+        info!(
+            "Recorded antenna forwarding session {} to {} for {:?}, {}s",
+            record.id,
+            record.antenna_ip,
+            record.requested_by,
+            record.duration_secs()
+        );
+        let mut session_records = SESSION_RECORDS.write().unwrap();
+        push_record(&mut session_records.records, record);
+        session_records.save();
+    }
+}
+
+fn push_record(records: &mut VecDeque<AntennaSessionRecord>, record: AntennaSessionRecord) {
+    if records.len() >= MAX_PENDING_SESSION_RECORDS {
+        records.pop_front();
+    }
+    records.push_back(record);
+}
+
+/// Records waiting to be sent to the operator
+pub fn get_session_records() -> Vec<AntennaSessionRecord> {
+    SESSION_RECORDS
+        .read()
+        .unwrap()
+        .records
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Drops records once the operator has them
+pub fn remove_session_records(ids: &[u64]) {
+    let mut session_records = SESSION_RECORDS.write().unwrap();
+    let before = session_records.records.len();
+    session_records.records.retain(|r| !ids.contains(&r.id));
+    if session_records.records.len() != before {
+        session_records.save();
+    }
+}
@@ -0,0 +1,59 @@
+//! Compares parsing a buffer full of connection data messages by copying each payload
+//! out of the buffer against slicing the payloads out of a shared buffer
+
+use antenna_forwarding_protocol::ForwardingProtocolMessage;
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+const MESSAGE_COUNT: usize = 64;
+const PAYLOAD_SIZE: usize = 64 * 1024;
+
+fn get_test_buffer() -> Vec<u8> {
+    let mut out = Vec::new();
+    for stream_id in 0..MESSAGE_COUNT as u64 {
+        let message = ForwardingProtocolMessage::new_connection_data_message(
+            stream_id,
+            vec![7; PAYLOAD_SIZE],
+        );
+        out.extend_from_slice(&message.get_message());
+    }
+    out
+}
+
+fn parse_copy(buf: &[u8]) -> Vec<ForwardingProtocolMessage> {
+    let mut messages = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let (bytes, msg) = ForwardingProtocolMessage::read_message(&buf[pos..]).unwrap();
+        messages.push(msg);
+        pos += bytes;
+    }
+    messages
+}
+
+fn parse_zero_copy(buf: &Bytes) -> Vec<ForwardingProtocolMessage> {
+    let mut buf = buf.clone();
+    let mut messages = Vec::new();
+    while !buf.is_empty() {
+        let (bytes, msg) = ForwardingProtocolMessage::read_message_bytes(&buf).unwrap();
+        messages.push(msg);
+        let _ = buf.split_to(bytes);
+    }
+    messages
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let buf = get_test_buffer();
+    let shared = Bytes::from(buf.clone());
+
+    let mut group = c.benchmark_group("parse_data_messages");
+    group.throughput(Throughput::Bytes(buf.len() as u64));
+    group.bench_function("copy", |b| b.iter(|| parse_copy(black_box(&buf))));
+    group.bench_function("zero_copy", |b| {
+        b.iter(|| parse_zero_copy(black_box(&shared)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
@@ -16,8 +16,11 @@ extern crate lazy_static;
 
 use althea_types::Identity;
 use althea_types::WgKey;
+use bytes::Bytes;
+use bytes::BytesMut;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::box_::Nonce;
+use sodiumoxide::crypto::box_::MACBYTES;
 use sodiumoxide::crypto::box_::NONCEBYTES;
 use std::collections::HashMap;
 use std::error::Error;
@@ -31,12 +34,16 @@ use std::io::Write;
 use std::net::IpAddr;
 use std::net::Shutdown;
 use std::net::TcpStream;
+use std::ops::Range;
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
 mod error;
+pub mod liveness;
+pub mod rate_limit;
 pub use error::AntennaForwardingError;
+use rate_limit::TokenBucket;
 
 /// The amount of time to sleep a thread that's spinlocking on somthing
 pub const SPINLOCK_TIME: Duration = Duration::from_millis(100);
@@ -51,6 +58,16 @@ pub const HEADER_LEN: usize = 22;
 /// from an antenna or a client
 pub const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
 
+/// The size of each read when filling a message buffer from a stream
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest packet length we accept, a header promising more than this is an error rather than
+/// something to keep buffering for. Data read from an antenna is split to fit
+pub const MAX_PACKET_LEN: u32 = 16 * 1024 * 1024;
+
+/// The largest payload of a single connection data message, the stream id takes the other 8 bytes
+const MAX_DATA_PAYLOAD_LEN: usize = MAX_PACKET_LEN as usize - 8;
+
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub enum ForwardingProtocolError {
     SliceTooSmall { expected: u32, actual: u32 },
@@ -156,6 +173,50 @@ pub fn read_till_block(input: &mut TcpStream) -> Result<Vec<u8>, IoError> {
     }
 }
 
+/// Reads from a tcpstream until it blocks or max bytes have been read, whatever is left
+/// stays in the socket buffer and pushes back on the sender
+pub fn read_till_block_limited(input: &mut TcpStream, max: usize) -> Result<Vec<u8>, IoError> {
+    input.set_nonblocking(true)?;
+    let mut out = Vec::new();
+    match input.by_ref().take(max as u64).read_to_end(&mut out) {
+        Ok(_bytes) => Ok(out),
+        Err(e) => {
+            if e.kind() == WouldBlock {
+                Ok(out)
+            } else {
+                error!("Broken! {:?}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Reads the entire contents of a tcpstream into the provided buffer until it blocks, this
+/// allows a single buffer to be reused across many reads rather than allocating a new one each time
+pub fn read_till_block_into(input: &mut TcpStream, buf: &mut BytesMut) -> Result<(), IoError> {
+    input.set_nonblocking(true)?;
+    loop {
+        let start = buf.len();
+        buf.resize(start + READ_CHUNK_SIZE, 0);
+        match input.read(&mut buf[start..]) {
+            Ok(0) => {
+                buf.truncate(start);
+                return Ok(());
+            }
+            Ok(bytes) => buf.truncate(start + bytes),
+            Err(e) => {
+                buf.truncate(start);
+                if e.kind() == WouldBlock {
+                    return Ok(());
+                } else {
+                    error!("Broken! {:?}", e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
 pub struct ExternalStream {
     pub stream: TcpStream,
     pub last_message: Instant,
@@ -164,10 +225,26 @@ pub struct ExternalStream {
 /// This function processes the antenna streams, meaning it handles taking messages from
 /// known streams, packaging them, and sending them down the line to the server. It also handles
 /// details like closing those streams when they hangup and notifying the other end.
+/// Returns how many bytes were read from the streams
 pub fn process_streams<S: ::std::hash::BuildHasher>(
     streams: &mut HashMap<u64, ExternalStream, S>,
     server_stream: &mut TcpStream,
-) {
+) -> u64 {
+    process_streams_limited(streams, server_stream, None)
+}
+
+/// process_streams with an optional bandwidth limit, the bytes available in the limiter are
+/// split evenly between the streams so that one bulk transfer can't starve the rest
+pub fn process_streams_limited<S: ::std::hash::BuildHasher>(
+    streams: &mut HashMap<u64, ExternalStream, S>,
+    server_stream: &mut TcpStream,
+    mut limiter: Option<&mut TokenBucket>,
+) -> u64 {
+    let mut bytes_read = 0;
+    let share = match limiter.as_mut() {
+        Some(limiter) if !streams.is_empty() => Some(limiter.available() / streams.len()),
+        _ => None,
+    };
     let mut streams_to_remove: Vec<u64> = Vec::new();
     // First we we have to iterate over all of these connections
     // and read to send messages up the server pipe. We need to do
@@ -176,18 +253,32 @@ pub fn process_streams<S: ::std::hash::BuildHasher>(
     for (stream_id, antenna_stream) in streams.iter_mut() {
         // in theory we will figure out if the connection is closed here
         // and then send a closed message
-        match read_till_block(&mut antenna_stream.stream) {
+        let res = match share {
+            // out of budget, leave the data in the socket until the bucket refills
+            Some(0) => Ok(Vec::new()),
+            Some(share) => read_till_block_limited(&mut antenna_stream.stream, share),
+            None => read_till_block(&mut antenna_stream.stream),
+        };
+        match res {
             Ok(bytes) => {
+                if let Some(limiter) = limiter.as_mut() {
+                    limiter.consume(bytes.len());
+                }
+                bytes_read += bytes.len() as u64;
                 if !bytes.is_empty() {
                     trace!(
                         "Got {} bytes for stream id {} from antenna/client",
                         bytes.len(),
                         stream_id
                     );
-                    let msg =
-                        ForwardingProtocolMessage::new_connection_data_message(*stream_id, bytes);
-                    if let Err(e) = write_all_spinlock(server_stream, &msg.get_message()) {
-                        error!("Failed to write with stream {} with {:?}", *stream_id, e);
+                    for chunk in bytes.chunks(MAX_DATA_PAYLOAD_LEN) {
+                        let msg = ForwardingProtocolMessage::new_connection_data_message(
+                            *stream_id,
+                            chunk.to_vec(),
+                        );
+                        if let Err(e) = write_all_spinlock(server_stream, &msg.get_message()) {
+                            error!("Failed to write with stream {} with {:?}", *stream_id, e);
+                        }
                     }
                     antenna_stream.last_message = Instant::now();
                 }
@@ -224,6 +315,32 @@ pub fn process_streams<S: ::std::hash::BuildHasher>(
     for i in streams_to_remove {
         streams.remove(&i);
     }
+    bytes_read
+}
+
+/// An http authorization header value, such as `Basic <base64 user:pass>`, that the client adds to
+/// every http request it forwards to the antenna so operators do not have to type antenna passwords.
+/// The client only holds it in memory for the forwarding session, Debug never prints the value
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct HttpAuthInjection {
+    pub authorization: String,
+}
+
+impl HttpAuthInjection {
+    /// The value must be a single header value, anything that could break out of the header is refused
+    pub fn is_valid(&self) -> bool {
+        !self.authorization.is_empty()
+            && !self
+                .authorization
+                .chars()
+                .any(|c| c == '\r' || c == '\n' || c == '\0')
+    }
+}
+
+impl fmt::Debug for HttpAuthInjection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "HttpAuthInjection {{ authorization: <redacted> }}")
+    }
 }
 
 /// All valid packet types for the forwarding protocool, two of these
@@ -237,8 +354,15 @@ pub enum ForwardingProtocolMessage {
     /// for the checkin message (type 0). Used because it's
     /// much easier to extend than a hard bytes protocol
     /// this is only sent client -> server the server is
-    /// identified implicitly
-    IdentificationMessage { id: Box<Identity> },
+    /// identified implicitly. The digest is the canonical
+    /// identity digest in hex, servers should key clients
+    /// on it rather than on the json form of the id which
+    /// has changed between versions. Older clients do not send it
+    IdentificationMessage {
+        id: Box<Identity>,
+        #[serde(default)]
+        digest: Option<String>,
+    },
     /// The serialized struct sent as the payload
     /// for the Forward message (type 1) this is what
     /// the server sends the client when it would like an
@@ -249,6 +373,19 @@ pub enum ForwardingProtocolMessage {
         ip: IpAddr,
         server_port: u16,
         antenna_port: u16,
+        /// Credentials to add to http requests sent to the antenna, the
+        /// forward message is sealed to the client so they are never sent
+        /// in the clear. Older servers do not send it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth: Option<HttpAuthInjection>,
+        /// Bandwidth limit for the session in bytes per second, applied by the
+        /// client to traffic in both directions. Older servers do not send it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rate_limit: Option<u64>,
+        /// Who asked for the session, kept in the client's session audit
+        /// record if it keeps one. Older servers do not send it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        requested_by: Option<String>,
     },
     /// The serialized struct sent as the payload
     /// for the Error message (type 2) this is what is sent
@@ -262,14 +399,21 @@ pub enum ForwardingProtocolMessage {
     /// Used for messages relating to an established
     /// stream pair. This is message does not contain a struct
     /// and is not extensible, this makes the payload much more
-    /// compact than being sent through serde
-    ConnectionDataMessage { stream_id: u64, payload: Vec<u8> },
+    /// compact than being sent through serde. The payload is a
+    /// reference counted view into the buffer it was parsed from
+    /// so bulk transfers do not copy every payload
+    ConnectionDataMessage { stream_id: u64, payload: Bytes },
     /// This struct is serialized and set as the payload to close
     /// the connection
     ForwardingCloseMessage,
     /// Used to determine the liveness of each end, not currently used
     /// sent as serialized struct and is extensible
     KeepAliveMessage,
+    /// Sent by the client in reply to a ForwardingCloseMessage once
+    /// it has finished draining the forwarded streams, the server
+    /// should not close the connection to the client before this
+    /// arrives or a timeout passes. Older clients never send it
+    ForwardingCloseAckMessage,
 }
 
 impl ForwardingProtocolMessage {
@@ -287,10 +431,15 @@ impl ForwardingProtocolMessage {
     pub const CONNECTION_DATA_MESSAGE_TYPE: u16 = 4;
     pub const FORWARDING_CLOSE_MESSAGE_TYPE: u16 = 5;
     pub const KEEPALIVE_MESSAGE_TYPE: u16 = 6;
+    pub const FORWARDING_CLOSE_ACK_MESSAGE_TYPE: u16 = 7;
 
     pub fn new_identification_message(id: Identity) -> ForwardingProtocolMessage {
+        let digest = Some(id.canonical_digest_hex());
         let boxed_id = Box::new(id);
-        ForwardingProtocolMessage::IdentificationMessage { id: boxed_id }
+        ForwardingProtocolMessage::IdentificationMessage {
+            id: boxed_id,
+            digest,
+        }
     }
 
     pub fn new_forward_message(
@@ -302,6 +451,70 @@ impl ForwardingProtocolMessage {
             ip,
             server_port,
             antenna_port,
+            auth: None,
+            rate_limit: None,
+            requested_by: None,
+        }
+    }
+
+    /// A forward message asking the client to log in to the antenna with the given credentials
+    pub fn new_forward_message_with_auth(
+        ip: IpAddr,
+        server_port: u16,
+        antenna_port: u16,
+        auth: HttpAuthInjection,
+    ) -> ForwardingProtocolMessage {
+        ForwardingProtocolMessage::ForwardMessage {
+            ip,
+            server_port,
+            antenna_port,
+            auth: Some(auth),
+            rate_limit: None,
+            requested_by: None,
+        }
+    }
+
+    /// Sets the bandwidth limit of a forward message, other messages are returned unchanged
+    pub fn with_rate_limit(self, bytes_per_second: u64) -> ForwardingProtocolMessage {
+        match self {
+            ForwardingProtocolMessage::ForwardMessage {
+                ip,
+                server_port,
+                antenna_port,
+                auth,
+                requested_by,
+                ..
+            } => ForwardingProtocolMessage::ForwardMessage {
+                ip,
+                server_port,
+                antenna_port,
+                auth,
+                rate_limit: Some(bytes_per_second),
+                requested_by,
+            },
+            other => other,
+        }
+    }
+
+    /// Sets who asked for the session in a forward message, other messages are returned unchanged
+    pub fn with_requester(self, requested_by: String) -> ForwardingProtocolMessage {
+        match self {
+            ForwardingProtocolMessage::ForwardMessage {
+                ip,
+                server_port,
+                antenna_port,
+                auth,
+                rate_limit,
+                ..
+            } => ForwardingProtocolMessage::ForwardMessage {
+                ip,
+                server_port,
+                antenna_port,
+                auth,
+                rate_limit,
+                requested_by: Some(requested_by),
+            },
+            other => other,
         }
     }
 
@@ -315,9 +528,12 @@ impl ForwardingProtocolMessage {
 
     pub fn new_connection_data_message(
         stream_id: u64,
-        payload: Vec<u8>,
+        payload: impl Into<Bytes>,
     ) -> ForwardingProtocolMessage {
-        ForwardingProtocolMessage::ConnectionDataMessage { stream_id, payload }
+        ForwardingProtocolMessage::ConnectionDataMessage {
+            stream_id,
+            payload: payload.into(),
+        }
     }
 
     pub fn new_forwarding_close_message() -> ForwardingProtocolMessage {
@@ -328,6 +544,10 @@ impl ForwardingProtocolMessage {
         ForwardingProtocolMessage::KeepAliveMessage
     }
 
+    pub fn new_forwarding_close_ack_message() -> ForwardingProtocolMessage {
+        ForwardingProtocolMessage::ForwardingCloseAckMessage
+    }
+
     /// helper function to de-duplcate some arms of get_message
     fn make_serde_packet(message_type: u16, payload: &ForwardingProtocolMessage) -> Vec<u8> {
         // serialize the payload first so that we know it's length
@@ -402,6 +622,9 @@ impl ForwardingProtocolMessage {
             return Err(ForwardingProtocolError::BadMagic);
         } else if packet_type != ForwardingProtocolMessage::FORWARD_MESSAGE_TYPE {
             return Err(ForwardingProtocolError::WrongPacketType);
+        } else if packet_len > MAX_PACKET_LEN || (packet_len as usize) < NONCEBYTES + MACBYTES {
+            // too short to hold the nonce and the authenticator
+            return Err(ForwardingProtocolError::InvalidLen);
         } else if packet_len as usize + HEADER_LEN > payload.len() {
             return Err(ForwardingProtocolError::SliceTooSmall {
                 actual: payload.len() as u32,
@@ -488,12 +711,18 @@ impl ForwardingProtocolMessage {
                     self,
                 )
             }
+            ForwardingProtocolMessage::ForwardingCloseAckMessage => {
+                ForwardingProtocolMessage::make_serde_packet(
+                    ForwardingProtocolMessage::FORWARDING_CLOSE_ACK_MESSAGE_TYPE,
+                    self,
+                )
+            }
         }
     }
 
-    pub fn read_message(
-        payload: &[u8],
-    ) -> Result<(usize, ForwardingProtocolMessage), ForwardingProtocolError> {
+    /// Parses and validates a packet header, returning the packet type and length if the
+    /// full packet is present in the provided slice
+    fn read_header(payload: &[u8]) -> Result<(u16, u32), ForwardingProtocolError> {
         if payload.len() < HEADER_LEN {
             return Err(ForwardingProtocolError::InvalidLen);
         }
@@ -513,8 +742,10 @@ impl ForwardingProtocolMessage {
         // this needs to be updated when new packet types are added
         if packet_magic != ForwardingProtocolMessage::MAGIC {
             return Err(ForwardingProtocolError::BadMagic);
-        } else if packet_type > 6 {
+        } else if packet_type > ForwardingProtocolMessage::FORWARDING_CLOSE_ACK_MESSAGE_TYPE {
             return Err(ForwardingProtocolError::WrongPacketType);
+        } else if packet_len > MAX_PACKET_LEN {
+            return Err(ForwardingProtocolError::InvalidLen);
         } else if packet_len as usize + HEADER_LEN > payload.len() {
             // look here for strange errors with identity packets if you're trying
             // to make them larger
@@ -531,9 +762,41 @@ impl ForwardingProtocolMessage {
                 expected: { packet_len + HEADER_LEN as u32 },
             });
         }
+        Ok((packet_type, packet_len))
+    }
+
+    /// Reads a single message from the provided slice, data message payloads are copied
+    /// out of the slice, use read_message_bytes to avoid that copy
+    pub fn read_message(
+        payload: &[u8],
+    ) -> Result<(usize, ForwardingProtocolMessage), ForwardingProtocolError> {
+        ForwardingProtocolMessage::read_message_internal(payload, |range| {
+            Bytes::copy_from_slice(&payload[range])
+        })
+    }
+
+    /// Reads a single message from the provided buffer, data message payloads are slices of
+    /// the provided buffer rather than copies
+    pub fn read_message_bytes(
+        payload: &Bytes,
+    ) -> Result<(usize, ForwardingProtocolMessage), ForwardingProtocolError> {
+        ForwardingProtocolMessage::read_message_internal(payload, |range| payload.slice(range))
+    }
+
+    /// Parses a message out of payload, get_payload is used to produce the payload of data messages
+    /// from a range of the input so that the caller can decide if that's a copy or a shared slice
+    fn read_message_internal(
+        payload: &[u8],
+        get_payload: impl FnOnce(Range<usize>) -> Bytes,
+    ) -> Result<(usize, ForwardingProtocolMessage), ForwardingProtocolError> {
+        let (packet_type, packet_len) = ForwardingProtocolMessage::read_header(payload)?;
 
         match packet_type {
-            ForwardingProtocolMessage::IDENTIFICATION_MESSAGE_TYPE => {
+            ForwardingProtocolMessage::IDENTIFICATION_MESSAGE_TYPE
+            | ForwardingProtocolMessage::ERROR_MESSAGE_TYPE
+            | ForwardingProtocolMessage::FORWARDING_CLOSE_MESSAGE_TYPE
+            | ForwardingProtocolMessage::KEEPALIVE_MESSAGE_TYPE
+            | ForwardingProtocolMessage::FORWARDING_CLOSE_ACK_MESSAGE_TYPE => {
                 let bytes_read = HEADER_LEN + packet_len as usize;
 
                 match serde_json::from_slice(&payload[HEADER_LEN..bytes_read]) {
@@ -547,16 +810,6 @@ impl ForwardingProtocolMessage {
             ForwardingProtocolMessage::FORWARD_MESSAGE_TYPE => {
                 Err(ForwardingProtocolError::WrongPacketType)
             }
-            ForwardingProtocolMessage::ERROR_MESSAGE_TYPE => {
-                let bytes_read = HEADER_LEN + packet_len as usize;
-
-                match serde_json::from_slice(&payload[HEADER_LEN..bytes_read]) {
-                    Ok(message) => Ok((bytes_read, message)),
-                    Err(serde_error) => Err(ForwardingProtocolError::SerdeError {
-                        message: serde_error.to_string(),
-                    }),
-                }
-            }
             ForwardingProtocolMessage::CONNECTION_CLOSE_MESSAGE_TYPE => {
                 if packet_len != 8 {
                     return Err(ForwardingProtocolError::InvalidLen);
@@ -574,43 +827,24 @@ impl ForwardingProtocolMessage {
                 ))
             }
             ForwardingProtocolMessage::CONNECTION_DATA_MESSAGE_TYPE => {
+                if packet_len < 8 {
+                    return Err(ForwardingProtocolError::InvalidLen);
+                }
+
                 let mut connection_id: [u8; 8] = [0; 8];
                 connection_id.clone_from_slice(&payload[HEADER_LEN..HEADER_LEN + 8]);
                 let connection_id = u64::from_be_bytes(connection_id);
 
-                let payload_bytes = packet_len as usize - 8;
-                let end = HEADER_LEN + 8 + payload_bytes;
-                let mut message_value = Vec::new();
-                message_value.extend_from_slice(&payload[HEADER_LEN + 8..end]);
+                let end = HEADER_LEN + packet_len as usize;
 
                 Ok((
                     end,
                     ForwardingProtocolMessage::new_connection_data_message(
                         connection_id,
-                        message_value,
+                        get_payload(HEADER_LEN + 8..end),
                     ),
                 ))
             }
-            ForwardingProtocolMessage::FORWARDING_CLOSE_MESSAGE_TYPE => {
-                let bytes_read = HEADER_LEN + packet_len as usize;
-
-                match serde_json::from_slice(&payload[HEADER_LEN..bytes_read]) {
-                    Ok(message) => Ok((bytes_read, message)),
-                    Err(serde_error) => Err(ForwardingProtocolError::SerdeError {
-                        message: serde_error.to_string(),
-                    }),
-                }
-            }
-            ForwardingProtocolMessage::KEEPALIVE_MESSAGE_TYPE => {
-                let bytes_read = HEADER_LEN + packet_len as usize;
-
-                match serde_json::from_slice(&payload[HEADER_LEN..bytes_read]) {
-                    Ok(message) => Ok((bytes_read, message)),
-                    Err(serde_error) => Err(ForwardingProtocolError::SerdeError {
-                        message: serde_error.to_string(),
-                    }),
-                }
-            }
             _ => Err(ForwardingProtocolError::UnknownPacketType),
         }
     }
@@ -671,13 +905,8 @@ impl ForwardingProtocolMessage {
                     "Got a forward message, recursing with {} bytes",
                     bytes.len() - bytes_read
                 );
-                ForwardingProtocolMessage::read_messages_internal(
-                    input,
-                    bytes[bytes_read..].to_vec(),
-                    vec![msg],
-                    0,
-                    None,
-                )
+                let mut buf = BytesMut::from(&bytes[bytes_read..]);
+                ForwardingProtocolMessage::read_messages_internal(input, &mut buf, vec![msg])
             }
             (Err(ForwardingProtocolError::SliceTooSmall { .. }), _) => {
                 trace!("Got partial close message");
@@ -715,97 +944,101 @@ impl ForwardingProtocolMessage {
     pub fn read_messages(
         input: &mut TcpStream,
     ) -> Result<Vec<ForwardingProtocolMessage>, AntennaForwardingError> {
-        ForwardingProtocolMessage::read_messages_internal(input, Vec::new(), Vec::new(), 0, None)
+        ForwardingProtocolMessage::read_messages_with_buffer(input, &mut BytesMut::new())
+    }
+
+    /// The same as read_messages but reads into a caller provided buffer, callers reading in a loop
+    /// should keep this buffer around so that it's allocation is reused. The buffer is always empty
+    /// when this function returns successfully
+    pub fn read_messages_with_buffer(
+        input: &mut TcpStream,
+        buf: &mut BytesMut,
+    ) -> Result<Vec<ForwardingProtocolMessage>, AntennaForwardingError> {
+        ForwardingProtocolMessage::read_messages_internal(input, buf, Vec::new())
     }
 
-    /// internal helper function designed to handle the complexities of reading off of a buffer and breaking down into messages, if we find a message
-    /// it is pushed onto the messages vec, if we have bytes left over from our read we recurse and try and parse a message out of them, if we have a
-    /// packet that promises more bytes than we have that means we need to wait for the remainder by sleeping for spinlock time. In very bad situations
-    /// the connection may actually be that slow and we use last_read_bytes to ensure that if any packets are delivered in a minute we keep trying
-    /// last read bytes is only filled out when we recurse for an unfinished message
+    /// internal helper function designed to handle the complexities of reading off of a buffer and breaking down into messages, every complete
+    /// message in the buffer is split off the front of it and parsed, message payloads reference the split off bytes rather than being copied.
+    /// If we have a packet that promises more bytes than we have that means we need to wait for the remainder by sleeping for spinlock time.
+    /// In very bad situations the connection may actually be that slow and we use last_read_bytes to ensure that if any packets are delivered
+    /// in a minute we keep trying
     fn read_messages_internal(
         input: &mut TcpStream,
-        remaining_bytes: Vec<u8>,
+        buf: &mut BytesMut,
         messages: Vec<ForwardingProtocolMessage>,
-        depth: u16,
-        last_read_bytes: Option<u32>,
     ) -> Result<Vec<ForwardingProtocolMessage>, AntennaForwardingError> {
-        // don't wait the first time in order to speed up execution
-        // if we are recursing we want to wait for the message to finish
-        // being written as the only reason we recuse is becuase we found
-        // a write in progress
         // we wait up to 60 seconds, this may seem absurdly long but on very
         // bad connections it's better to trust in the transport (TCP) to resume
         // and keep the packets flowing
         const WAIT_TIME: u16 = 600;
-        if depth > 1 && depth <= WAIT_TIME {
-            thread::sleep(SPINLOCK_TIME);
-        } else if depth > WAIT_TIME {
-            error!("Never found the end of the message");
-            return Err(AntennaForwardingError::EndNotFoundError);
-        }
-        // these should match the full list of message types defined above
         let mut messages = messages;
-        let mut remaining_bytes = remaining_bytes;
-
-        remaining_bytes.extend_from_slice(&read_till_block(input)?);
+        let mut depth: u16 = 0;
+        let mut last_read_bytes: Option<u32> = None;
+
+        loop {
+            // don't wait the first time in order to speed up execution
+            // if we are looping we want to wait for the message to finish
+            // being written as the only reason we loop is becuase we found
+            // a write in progress
+            if depth > 1 && depth <= WAIT_TIME {
+                thread::sleep(SPINLOCK_TIME);
+            } else if depth > WAIT_TIME {
+                error!("Never found the end of the message");
+                return Err(AntennaForwardingError::EndNotFoundError);
+            }
 
-        match ForwardingProtocolMessage::read_message(&remaining_bytes) {
-            Ok((bytes, msg)) => {
-                messages.push(msg);
-                let num_remaining_bytes = remaining_bytes.len() - bytes;
+            read_till_block_into(input, buf)?;
 
-                if num_remaining_bytes != 0 {
-                    trace!(
-                        "Got message of size {} recursing for remaining bytes {}",
-                        bytes,
-                        num_remaining_bytes
-                    );
-                    ForwardingProtocolMessage::read_messages_internal(
-                        input,
-                        remaining_bytes[bytes..].to_vec(),
-                        messages,
-                        depth + 1,
-                        None,
-                    )
-                } else {
-                    Ok(messages)
+            // parse every complete message currently in the buffer
+            loop {
+                if buf.is_empty() {
+                    return Ok(messages);
                 }
-            }
-            Err(e) => match e {
-                ForwardingProtocolError::SliceTooSmall { expected, actual } => {
-                    error!("Expected {} bytes, got {} bytes", expected, actual);
-                    if let Some(last_actual) = last_read_bytes {
-                        // we got some new bytes, reset the counter
-                        if actual > last_actual {
-                            return ForwardingProtocolMessage::read_messages_internal(
-                                input,
-                                remaining_bytes,
-                                messages,
-                                0,
-                                Some(actual),
-                            );
+                // a partial header, wait for the rest of it
+                let header = if buf.len() < HEADER_LEN {
+                    Err(ForwardingProtocolError::SliceTooSmall {
+                        expected: HEADER_LEN as u32,
+                        actual: buf.len() as u32,
+                    })
+                } else {
+                    ForwardingProtocolMessage::read_header(buf)
+                };
+
+                match header {
+                    Ok((_, packet_len)) => {
+                        // splitting does not copy, the frame and the rest of the buffer
+                        // share the same allocation
+                        let frame = buf.split_to(HEADER_LEN + packet_len as usize).freeze();
+                        match ForwardingProtocolMessage::read_message_bytes(&frame) {
+                            Ok((_, msg)) => messages.push(msg),
+                            Err(_) => {
+                                let mut remaining_bytes = frame.to_vec();
+                                remaining_bytes.extend_from_slice(buf);
+                                return Err(AntennaForwardingError::UnparsedBytesError {
+                                    messages,
+                                    remaining_bytes,
+                                });
+                            }
                         }
                     }
-                    ForwardingProtocolMessage::read_messages_internal(
-                        input,
-                        remaining_bytes,
-                        messages,
-                        depth + 1,
-                        Some(actual),
-                    )
-                }
-                _ => {
-                    if !remaining_bytes.is_empty() {
-                        Err(AntennaForwardingError::UnparsedBytesError {
+                    Err(ForwardingProtocolError::SliceTooSmall { expected, actual }) => {
+                        trace!("Expected {} bytes, got {} bytes", expected, actual);
+                        match last_read_bytes {
+                            // we got some new bytes, reset the counter
+                            Some(last_actual) if actual > last_actual => depth = 0,
+                            _ => depth += 1,
+                        }
+                        last_read_bytes = Some(actual);
+                        break;
+                    }
+                    Err(_) => {
+                        return Err(AntennaForwardingError::UnparsedBytesError {
                             messages,
-                            remaining_bytes,
-                        })
-                    } else {
-                        Ok(messages)
+                            remaining_bytes: buf.to_vec(),
+                        });
                     }
                 }
-            },
+            }
         }
     }
 }
@@ -815,7 +1048,12 @@ mod tests {
     use super::ForwardingProtocolMessage;
     use super::Identity;
     use super::WgKey;
+    use bytes::Bytes;
+    use bytes::BytesMut;
     use rand::Rng;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
     use std::u16::MAX as U16MAX;
 
     lazy_static! {
@@ -837,7 +1075,7 @@ mod tests {
                 .unwrap();
     }
 
-    fn get_test_id() -> Identity {
+    pub(crate) fn get_test_id() -> Identity {
         Identity {
             mesh_ip: "::1".parse().unwrap(),
             eth_address: "0x4288C538A553357Bb6c3b77Cf1A60Da6E77931F6"
@@ -902,6 +1140,31 @@ mod tests {
         assert_eq!(number_of_bytes_parsed, message_bytes.len());
     }
 
+    #[test]
+    fn test_legacy_id_message() {
+        // clients that predate the digest send only the id
+        let payload = format!(
+            r#"{{"IdentificationMessage":{{"id":{}}}}}"#,
+            serde_json::to_string(&get_test_id()).unwrap()
+        );
+        let mut message_bytes = Vec::new();
+        message_bytes.extend_from_slice(&ForwardingProtocolMessage::MAGIC.to_be_bytes());
+        message_bytes.extend_from_slice(
+            &ForwardingProtocolMessage::IDENTIFICATION_MESSAGE_TYPE.to_be_bytes(),
+        );
+        message_bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        message_bytes.extend_from_slice(payload.as_bytes());
+        let (_, parsed) =
+            ForwardingProtocolMessage::read_message(&message_bytes).expect("Failed to parse!");
+        match parsed {
+            ForwardingProtocolMessage::IdentificationMessage { id, digest } => {
+                assert_eq!(*id, get_test_id());
+                assert_eq!(digest, None);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
     #[test]
     fn test_id_message_trailing_bytes() {
         let message = ForwardingProtocolMessage::new_identification_message(get_test_id());
@@ -935,6 +1198,56 @@ mod tests {
         assert_eq!(message_bytes_parsed, message_bytes.len());
     }
 
+    #[test]
+    fn test_forward_message_rate_limit() {
+        let message = get_forward_message().with_rate_limit(125_000);
+        let message_bytes = message
+            .get_encrypted_forward_message(
+                *FORWARDING_SERVER_PRIVATE_KEY,
+                *FORWARDING_CLIENT_PUBLIC_KEY,
+            )
+            .expect("Failed to encrypt");
+        let (_, parsed) = ForwardingProtocolMessage::read_encrypted_forward_message(
+            &message_bytes,
+            *FORWARDING_SERVER_PUBLIC_KEY,
+            *FORWARDING_CLIENT_PRIVATE_KEY,
+        )
+        .expect("Failed to parse!");
+        match parsed {
+            ForwardingProtocolMessage::ForwardMessage { rate_limit, .. } => {
+                assert_eq!(rate_limit, Some(125_000))
+            }
+            m => panic!("Wrong message {m:?}"),
+        }
+
+        // older servers don't send a limit
+        let old: ForwardingProtocolMessage = serde_json::from_str(
+            r#"{"ForwardMessage":{"ip":"192.168.10.1","server_port":6823,"antenna_port":443}}"#,
+        )
+        .unwrap();
+        assert_eq!(old, get_forward_message());
+        // the requester is carried alongside the limit
+        match get_forward_message()
+            .with_rate_limit(1)
+            .with_requester("ops".to_string())
+        {
+            ForwardingProtocolMessage::ForwardMessage {
+                rate_limit,
+                requested_by,
+                ..
+            } => {
+                assert_eq!(rate_limit, Some(1));
+                assert_eq!(requested_by.as_deref(), Some("ops"));
+            }
+            m => panic!("Wrong message {m:?}"),
+        }
+        // and other messages are left alone
+        assert_eq!(
+            ForwardingProtocolMessage::new_keepalive_message().with_rate_limit(1),
+            ForwardingProtocolMessage::new_keepalive_message()
+        );
+    }
+
     #[test]
     fn test_forward_message_trailing_bytes() {
         let message = get_forward_message();
@@ -1075,6 +1388,16 @@ mod tests {
         assert_eq!(size, actual_message_length);
     }
 
+    #[test]
+    fn test_forwarding_close_ack_message() {
+        let message = ForwardingProtocolMessage::new_forwarding_close_ack_message();
+        let out = message.get_message();
+        let (size, parsed) =
+            ForwardingProtocolMessage::read_message(&out).expect("Failed to parse!");
+        assert_eq!(parsed, message);
+        assert_eq!(size, out.len());
+    }
+
     #[test]
     fn test_multiple_message_types() {
         let mut multi_message = Vec::new();
@@ -1140,6 +1463,57 @@ mod tests {
         assert!(ForwardingProtocolMessage::read_message(&junk).is_err());
     }
 
+    #[test]
+    fn test_connection_message_zero_copy() {
+        let message = ForwardingProtocolMessage::new_connection_data_message(
+            get_random_stream_id(),
+            get_random_long_test_vector(),
+        );
+        let out = Bytes::from(message.get_message());
+        let (size, parsed) =
+            ForwardingProtocolMessage::read_message_bytes(&out).expect("Failed to parse!");
+        assert_eq!(parsed, message);
+        assert_eq!(size, out.len());
+        match parsed {
+            ForwardingProtocolMessage::ConnectionDataMessage { payload, .. } => {
+                // the payload should point into the original buffer
+                let range = out.as_ptr() as usize..out.as_ptr() as usize + out.len();
+                assert!(range.contains(&(payload.as_ptr() as usize)));
+            }
+            _ => panic!("Wrong message type!"),
+        }
+    }
+
+    #[test]
+    fn test_read_messages_with_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut reader, _) = listener.accept().unwrap();
+
+        let message_a = ForwardingProtocolMessage::new_connection_data_message(
+            get_random_stream_id(),
+            get_random_long_test_vector(),
+        );
+        let message_b =
+            ForwardingProtocolMessage::new_connection_close_message(get_random_stream_id());
+        let message_c = ForwardingProtocolMessage::new_keepalive_message();
+        let mut out = message_a.get_message();
+        out.extend_from_slice(&message_b.get_message());
+        out.extend_from_slice(&message_c.get_message());
+        writer.write_all(&out).unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut parsed = Vec::new();
+        while parsed.len() < 3 {
+            parsed.extend(
+                ForwardingProtocolMessage::read_messages_with_buffer(&mut reader, &mut buf)
+                    .expect("Failed to read!"),
+            );
+        }
+        assert_eq!(parsed, vec![message_a, message_b, message_c]);
+        assert!(buf.is_empty());
+    }
+
     #[test]
     fn test_multiple_big_connection_messages() {
         let message_a = ForwardingProtocolMessage::new_connection_data_message(
@@ -1167,4 +1541,169 @@ mod tests {
             .expect("Failed to parse!");
         assert_eq!(parsed, message_c);
     }
+
+    /// A header with our magic and the given type and length
+    fn header(packet_type: u16, packet_len: u32) -> Vec<u8> {
+        let mut out = ForwardingProtocolMessage::MAGIC.to_be_bytes().to_vec();
+        out.extend_from_slice(&packet_type.to_be_bytes());
+        out.extend_from_slice(&packet_len.to_be_bytes());
+        out
+    }
+
+    /// Every valid message type, serialized
+    fn get_valid_packets() -> Vec<Vec<u8>> {
+        vec![
+            ForwardingProtocolMessage::new_identification_message(get_test_id()).get_message(),
+            ForwardingProtocolMessage::new_error_message("test".to_string()).get_message(),
+            ForwardingProtocolMessage::new_connection_close_message(get_random_stream_id())
+                .get_message(),
+            ForwardingProtocolMessage::new_connection_data_message(
+                get_random_stream_id(),
+                vec![1, 2, 3],
+            )
+            .get_message(),
+            ForwardingProtocolMessage::new_forwarding_close_message().get_message(),
+            ForwardingProtocolMessage::new_keepalive_message().get_message(),
+            ForwardingProtocolMessage::new_forwarding_close_ack_message().get_message(),
+            get_forward_message()
+                .get_encrypted_forward_message(
+                    *FORWARDING_SERVER_PRIVATE_KEY,
+                    *FORWARDING_CLIENT_PUBLIC_KEY,
+                )
+                .unwrap(),
+        ]
+    }
+
+    /// Parses the input every way a peer's bytes are parsed, none of them may panic or claim to have
+    /// read more than they were given
+    fn parse_all(input: &[u8]) {
+        if let Ok((read, _)) = ForwardingProtocolMessage::read_message(input) {
+            assert!(read <= input.len());
+        }
+        if let Ok((read, _)) =
+            ForwardingProtocolMessage::read_message_bytes(&Bytes::copy_from_slice(input))
+        {
+            assert!(read <= input.len());
+        }
+        if let Ok((read, _)) = ForwardingProtocolMessage::read_encrypted_forward_message(
+            input,
+            *FORWARDING_SERVER_PUBLIC_KEY,
+            *FORWARDING_CLIENT_PRIVATE_KEY,
+        ) {
+            assert!(read <= input.len());
+        }
+    }
+
+    #[test]
+    fn test_fuzz_mutated_messages() {
+        let mut rng = rand::thread_rng();
+        for packet in get_valid_packets() {
+            for _ in 0..2000 {
+                let mut mutated = packet.clone();
+                match rng.gen_range(0..4) {
+                    // flip some bytes, the header more often than the body
+                    0 => {
+                        for _ in 0..rng.gen_range(1..4) {
+                            let i = if rng.gen() {
+                                rng.gen_range(0..super::HEADER_LEN)
+                            } else {
+                                rng.gen_range(0..mutated.len())
+                            };
+                            mutated[i] = rng.gen();
+                        }
+                    }
+                    1 => mutated.truncate(rng.gen_range(0..packet.len())),
+                    // a random length
+                    2 => mutated[18..super::HEADER_LEN]
+                        .copy_from_slice(&rng.gen::<u32>().to_be_bytes()),
+                    // a random type with a valid length
+                    _ => mutated[16..18].copy_from_slice(&rng.gen_range(0u16..10).to_be_bytes()),
+                }
+                parse_all(&mutated);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_random_headers() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20000 {
+            let packet_len: u32 = if rng.gen() {
+                rng.gen_range(0..64)
+            } else {
+                rng.gen()
+            };
+            let mut input = header(rng.gen_range(0..10), packet_len);
+            let body_len = rng.gen_range(0..128);
+            input.extend((0..body_len).map(|_| rng.gen::<u8>()));
+            parse_all(&input);
+        }
+    }
+
+    #[test]
+    fn test_oversized_length_rejected() {
+        let input = header(
+            ForwardingProtocolMessage::CONNECTION_DATA_MESSAGE_TYPE,
+            u32::MAX,
+        );
+        // waiting for the rest of this would buffer 4GB
+        assert_eq!(
+            ForwardingProtocolMessage::read_message(&input),
+            Err(super::ForwardingProtocolError::InvalidLen)
+        );
+        let input = header(ForwardingProtocolMessage::FORWARD_MESSAGE_TYPE, u32::MAX);
+        assert_eq!(
+            ForwardingProtocolMessage::read_encrypted_forward_message(
+                &input,
+                *FORWARDING_SERVER_PUBLIC_KEY,
+                *FORWARDING_CLIENT_PRIVATE_KEY,
+            ),
+            Err(super::ForwardingProtocolError::InvalidLen)
+        );
+    }
+
+    #[test]
+    fn test_short_forward_message() {
+        // shorter than a nonce, used to panic slicing out the ciphertext
+        let mut input = header(ForwardingProtocolMessage::FORWARD_MESSAGE_TYPE, 4);
+        input.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            ForwardingProtocolMessage::read_encrypted_forward_message(
+                &input,
+                *FORWARDING_SERVER_PUBLIC_KEY,
+                *FORWARDING_CLIENT_PRIVATE_KEY,
+            ),
+            Err(super::ForwardingProtocolError::InvalidLen)
+        );
+    }
+
+    #[test]
+    fn test_read_messages_oversized_length() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut reader, _) = listener.accept().unwrap();
+
+        let keepalive = ForwardingProtocolMessage::new_keepalive_message();
+        let mut out = keepalive.get_message();
+        out.extend_from_slice(&header(
+            ForwardingProtocolMessage::CONNECTION_DATA_MESSAGE_TYPE,
+            super::MAX_PACKET_LEN + 1,
+        ));
+        writer.write_all(&out).unwrap();
+
+        // the reader must give up right away rather than wait for 16MB that will never come
+        let mut buf = BytesMut::new();
+        let mut parsed = Vec::new();
+        loop {
+            match ForwardingProtocolMessage::read_messages_with_buffer(&mut reader, &mut buf) {
+                Ok(messages) => parsed.extend(messages),
+                Err(super::AntennaForwardingError::UnparsedBytesError { messages, .. }) => {
+                    parsed.extend(messages);
+                    break;
+                }
+                Err(e) => panic!("Unexpected error {e}"),
+            }
+        }
+        assert_eq!(parsed, vec![keepalive]);
+    }
 }
@@ -0,0 +1,206 @@
+//! Router liveness tracking for the forwarding server. Routers check in with an identification message and
+//! then hold the connection open with keepalives, so the server hears from every connected router every few
+//! seconds. The table here records when each router was last heard from so operator tooling can ask whether
+//! a router was seen in the last N minutes without waiting for the next checkin, and lets callers register
+//! hooks that fire when a router comes online or goes quiet. Routers are keyed on the canonical identity
+//! digest, the same key servers use for forwarding.
+
+use althea_types::Identity;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// What the server knows about when a router was last heard from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouterLiveness {
+    pub id: Identity,
+    /// Canonical identity digest in hex
+    pub digest: String,
+    pub first_seen: SystemTime,
+    /// Last checkin or keepalive
+    pub last_seen: SystemTime,
+    pub last_checkin: SystemTime,
+    /// False once the router has been quiet for longer than the table's offline threshold
+    pub online: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LivenessEvent {
+    /// A router checked in for the first time or after being offline
+    Online(RouterLiveness),
+    /// A router has not been heard from for longer than the offline threshold
+    Offline(RouterLiveness),
+}
+
+pub type LivenessHook = Box<dyn Fn(&LivenessEvent) + Send + Sync>;
+
+pub struct LivenessTable {
+    routers: HashMap<String, RouterLiveness>,
+    offline_after: Duration,
+    hooks: Vec<LivenessHook>,
+}
+
+impl LivenessTable {
+    /// Routers not heard from for offline_after are reported offline by sweep()
+    pub fn new(offline_after: Duration) -> LivenessTable {
+        LivenessTable {
+            routers: HashMap::new(),
+            offline_after,
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Registers a callback run for every online and offline transition, hooks run on the thread
+    /// updating the table and should hand off anything slow
+    pub fn add_hook(&mut self, hook: LivenessHook) {
+        self.hooks.push(hook);
+    }
+
+    fn notify(&self, event: LivenessEvent) {
+        for hook in self.hooks.iter() {
+            hook(&event);
+        }
+    }
+
+    /// Records an identification message
+    pub fn checkin(&mut self, id: Identity, now: SystemTime) {
+        let digest = id.canonical_digest_hex();
+        let entry = self
+            .routers
+            .entry(digest.clone())
+            .or_insert_with(|| RouterLiveness {
+                id,
+                digest,
+                first_seen: now,
+                last_seen: now,
+                last_checkin: now,
+                online: false,
+            });
+        entry.id = id;
+        entry.last_seen = now;
+        entry.last_checkin = now;
+        if !entry.online {
+            entry.online = true;
+            let event = LivenessEvent::Online(entry.clone());
+            self.notify(event);
+        }
+    }
+
+    /// Records a keepalive on the connection of a router that has checked in, returns false if the
+    /// digest is unknown, in which case the router must check in again
+    pub fn keepalive(&mut self, digest: &str, now: SystemTime) -> bool {
+        match self.routers.get_mut(digest) {
+            Some(entry) => {
+                entry.last_seen = now;
+                if !entry.online {
+                    entry.online = true;
+                    let event = LivenessEvent::Online(entry.clone());
+                    self.notify(event);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks routers that have gone quiet as offline and runs the hooks for them, should be called
+    /// periodically by the server
+    pub fn sweep(&mut self, now: SystemTime) {
+        let offline_after = self.offline_after;
+        let mut events = Vec::new();
+        for entry in self.routers.values_mut() {
+            if entry.online && !seen_within(entry, offline_after, now) {
+                entry.online = false;
+                events.push(LivenessEvent::Offline(entry.clone()));
+            }
+        }
+        for event in events {
+            self.notify(event);
+        }
+    }
+
+    /// Forgets routers not heard from for longer than max_age
+    pub fn prune(&mut self, max_age: Duration, now: SystemTime) {
+        self.routers
+            .retain(|_, entry| seen_within(entry, max_age, now));
+    }
+
+    pub fn get(&self, digest: &str) -> Option<&RouterLiveness> {
+        self.routers.get(digest)
+    }
+
+    /// True if the router with this digest was heard from within window
+    pub fn was_seen_within(&self, digest: &str, window: Duration, now: SystemTime) -> bool {
+        self.routers
+            .get(digest)
+            .map(|entry| seen_within(entry, window, now))
+            .unwrap_or(false)
+    }
+
+    /// Every router heard from within window
+    pub fn seen_since(&self, window: Duration, now: SystemTime) -> Vec<RouterLiveness> {
+        self.routers
+            .values()
+            .filter(|entry| seen_within(entry, window, now))
+            .cloned()
+            .collect()
+    }
+}
+
+fn seen_within(entry: &RouterLiveness, window: Duration, now: SystemTime) -> bool {
+    match now.duration_since(entry.last_seen) {
+        Ok(elapsed) => elapsed <= window,
+        // last seen is in the future, the clock moved backwards
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::get_test_id;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_liveness_table() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let mut table = LivenessTable::new(Duration::from_secs(120));
+        table.add_hook(Box::new(move |event| {
+            recorded.lock().unwrap().push(event.clone())
+        }));
+
+        let id = get_test_id();
+        let digest = id.canonical_digest_hex();
+        let start = SystemTime::now();
+        assert!(!table.keepalive(&digest, start));
+        table.checkin(id, start);
+        assert!(table.keepalive(&digest, start + Duration::from_secs(30)));
+        assert_eq!(events.lock().unwrap().len(), 1);
+
+        let now = start + Duration::from_secs(90);
+        assert!(table.was_seen_within(&digest, Duration::from_secs(60), now));
+        assert!(!table.was_seen_within(&digest, Duration::from_secs(30), now));
+        assert_eq!(table.seen_since(Duration::from_secs(60), now).len(), 1);
+
+        // quiet for longer than the threshold, reported offline exactly once
+        let later = start + Duration::from_secs(300);
+        table.sweep(later);
+        table.sweep(later);
+        assert!(!table.get(&digest).unwrap().online);
+        {
+            let events = events.lock().unwrap();
+            assert_eq!(events.len(), 2);
+            assert!(matches!(events[1], LivenessEvent::Offline(_)));
+        }
+
+        // a keepalive brings it back
+        assert!(table.keepalive(&digest, later));
+        assert!(matches!(
+            events.lock().unwrap()[2],
+            LivenessEvent::Online(_)
+        ));
+
+        table.prune(Duration::from_secs(60), later + Duration::from_secs(3600));
+        assert!(table.get(&digest).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+//! A token bucket used to cap the bandwidth of a forwarding session. A large transfer such as an antenna
+//! firmware download can otherwise fill a thin backhaul and starve customer traffic. The bucket holds up
+//! to one second of traffic, writes are allowed to run it into debt and the forwarding loop then waits the
+//! debt off before reading more, which lets tcp backpressure slow the far end down.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: u64,
+    /// Available bytes, negative when a write has overdrawn the bucket
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_second: u64) -> TokenBucket {
+        let rate = bytes_per_second.max(1);
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last_refill {
+            let elapsed = (now - self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+            self.last_refill = now;
+        }
+    }
+
+    fn available_at(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        self.tokens.max(0.0) as usize
+    }
+
+    /// Bytes that can be sent right now without going over the limit
+    pub fn available(&mut self) -> usize {
+        self.available_at(Instant::now())
+    }
+
+    fn consume_at(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+
+    /// Records bytes that were sent, this may overdraw the bucket
+    pub fn consume(&mut self, bytes: usize) {
+        self.consume_at(bytes, Instant::now())
+    }
+
+    fn wait_time_at(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+
+    /// How long until the bucket is out of debt
+    pub fn wait_time(&mut self) -> Duration {
+        self.wait_time_at(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.last_refill;
+        // starts with a second of burst
+        assert_eq!(bucket.available_at(start), 1000);
+        bucket.consume_at(600, start);
+        assert_eq!(bucket.available_at(start), 400);
+        assert_eq!(bucket.wait_time_at(start), Duration::ZERO);
+
+        // overdraw by 500 bytes, half a second at this rate
+        bucket.consume_at(900, start);
+        assert_eq!(bucket.available_at(start), 0);
+        assert_eq!(bucket.wait_time_at(start), Duration::from_millis(500));
+
+        let later = start + Duration::from_millis(750);
+        assert_eq!(bucket.wait_time_at(later), Duration::ZERO);
+        assert_eq!(bucket.available_at(later), 250);
+
+        // never refills past the burst size
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.available_at(much_later), 1000);
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let mut bucket = TokenBucket::new(0);
+        assert_eq!(bucket.rate(), 1);
+        assert_eq!(bucket.available(), 1);
+    }
+}
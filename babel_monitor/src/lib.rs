@@ -12,6 +12,7 @@ extern crate serde_derive;
 extern crate log;
 
 pub mod parsing;
+pub mod pool;
 pub mod structs;
 
 use crate::parsing::{read_babel_sync, validate_preamble};
@@ -24,6 +25,7 @@ use std::io::Read;
 use std::io::Write;
 use std::iter::Iterator;
 use std::net::IpAddr;
+use std::net::Shutdown;
 use std::net::SocketAddr;
 use std::net::TcpStream;
 use std::str::FromStr;
@@ -75,7 +77,8 @@ where
 }
 
 /// Opens a tcpstream to the babel management socket using a standard timeout
-/// for both the open and read operations
+/// for both the open and read operations, most callers should take a connection
+/// from the pool instead, see pool::get_babel_stream
 pub fn open_babel_stream(
     babel_port: u16,
     timeout: Duration,
@@ -120,6 +123,9 @@ fn read_babel(
             thread::sleep(SLEEP_TIME);
             return read_babel(stream, previous_contents, depth + 1);
         } else {
+            // we don't know how much of the reply is left on the wire, the connection can't be
+            // used again, see pool::is_healthy
+            let _ = stream.shutdown(Shutdown::Both);
             return Err(e.into());
         }
     }
@@ -150,7 +156,8 @@ fn read_babel(
     if depth > 50 {
         // prevent infinite recursion in error cases
         warn!("Babel read timed out! {}", output);
-        return Err(BabelMonitorError::ReadFailed(
+        let _ = stream.shutdown(Shutdown::Both);
+        return Err(BabelMonitorError::BabelDown(
             "Babel read timed out!".to_string(),
         ));
     } else if full_buffer {
@@ -188,7 +195,10 @@ pub fn run_command(stream: &mut TcpStream, cmd: &str) -> Result<String, BabelMon
             info!("Command write succeeded, returning output");
             read_babel(stream, String::new(), 0)
         }
-        Err(e) => Err(BabelMonitorError::CommandFailed(cmd, format!("{e:?}"))),
+        Err(e) => {
+            let _ = stream.shutdown(Shutdown::Both);
+            Err(BabelMonitorError::CommandFailed(cmd, format!("{e:?}")))
+        }
     }
 }
 
@@ -296,6 +306,21 @@ pub fn monitor(
     Ok(())
 }
 
+/// Sets the receive cost babel uses for neighbors on the given interface, higher values make routes
+/// through the interface less attractive. The interface must already be monitored
+pub fn set_interface_rxcost(
+    stream: &mut TcpStream,
+    iface: &str,
+    rxcost: u16,
+) -> Result<(), BabelMonitorError> {
+    let command = format!("interface {iface} rxcost {rxcost}");
+    let result = run_command(stream, &command)?;
+
+    trace!("Babel set rxcost {} on {}", rxcost, iface);
+    let _out = result;
+    Ok(())
+}
+
 pub fn redistribute_ip(
     stream: &mut TcpStream,
     ip: &IpAddr,
@@ -339,6 +364,7 @@ pub fn parse_routes(stream: &mut TcpStream) -> Result<Vec<Route>, BabelMonitorEr
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parsing::{get_billing_routes, get_installed_route};
 
     static TABLE: &str =
 "local fee 1024\n\
@@ -451,6 +477,33 @@ ok\n";
 
         let route = routes.first().unwrap();
         assert_eq!(route.price, 3072);
+        assert_eq!(route.src_prefix, None);
+    }
+
+    #[test]
+    fn source_specific_route_parse() {
+        static SOURCE_SPECIFIC_TABLE: &str = "add route 241fee0 prefix fd00::5/128 from ::/0 installed yes \
+id e6:95:6e:ff:fe:44:c4:12 metric 400 price 3000 fee 300 refmetric 217 full-path-rtt 39.874 via fe80::1 if wg36\n\
+add route 241fee1 prefix fd00::5/128 from 2001:db8::/48 installed yes id e6:95:6e:ff:fe:44:c4:12 \
+metric 200 price 9000 fee 900 refmetric 100 full-path-rtt 20.1 via fe80::2 if wg37\n\
+add route 241fee2 prefix fd00::6/128 from 2001:db8::/48 installed yes id e6:95:6e:ff:fe:44:c4:13 \
+metric 200 price 5000 fee 500 refmetric 100 full-path-rtt 20.1 via fe80::2 if wg37\n\
+ok\n";
+        let routes = parse_routes_sync(SOURCE_SPECIFIC_TABLE.to_string()).unwrap();
+        assert_eq!(routes.len(), 3);
+        assert_eq!(routes[0].src_prefix, None);
+        assert_eq!(routes[1].src_prefix, Some("2001:db8::/48".parse().unwrap()));
+
+        // traffic to fd00::5 is billed by the route from anywhere even though the source specific
+        // one has the better metric
+        let billing = get_billing_routes(&routes);
+        assert_eq!(billing.len(), 2);
+        let dest: IpAddr = "fd00::5".parse().unwrap();
+        assert_eq!(billing[&dest].price, 3000);
+        // a destination with only a source specific route still gets billed
+        let dest: IpAddr = "fd00::6".parse().unwrap();
+        assert_eq!(billing[&dest].price, 5000);
+        assert_eq!(get_installed_route(&dest, &routes).unwrap().id, "241fee2");
     }
 
     #[test]
@@ -7,6 +7,7 @@ use crate::structs::Interface;
 use crate::structs::Neighbor;
 use crate::structs::{BabelMonitorError, Route};
 use ipnetwork::IpNetwork;
+use std::collections::HashMap;
 use std::iter::Iterator;
 use std::net::IpAddr;
 use std::str::{self};
@@ -216,6 +217,11 @@ pub fn parse_routes_sync(babel_out: String) -> Result<Vec<Route>, BabelMonitorEr
                     Ok(value) => value,
                     Err(_) => continue,
                 },
+                // babel reports from ::/0 or 0.0.0.0/0 for routes that aren't source specific
+                src_prefix: match find_and_parse_babel_val::<IpNetwork>("from", entry) {
+                    Ok(value) if value.prefix() > 0 => Some(value),
+                    _ => None,
+                },
             };
 
             vector.push(route);
@@ -279,20 +285,38 @@ pub fn do_we_have_route(mesh_ip: &IpAddr, routes: &[Route]) -> Result<bool, Babe
     }
     Ok(false)
 }
-/// Returns the installed route to a given destination
-pub fn get_installed_route(mesh_ip: &IpAddr, routes: &[Route]) -> Result<Route, BabelMonitorError> {
-    let mut exit_route = None;
+/// The installed route to each mesh host address that traffic to it should be billed by. With source
+/// specific routing babel may install several routes to one destination, one per source prefix, and
+/// which one a packet takes depends on where it came from. Traffic is only counted per destination, so
+/// the route that applies to the widest range of sources is used, the one from anywhere if there is one.
+pub fn get_billing_routes(routes: &[Route]) -> HashMap<IpAddr, &Route> {
+    let mut billing_routes: HashMap<IpAddr, &Route> = HashMap::new();
     for route in routes.iter() {
         // Only ip6
         if let IpNetwork::V6(ref ip) = route.prefix {
             // Only host addresses and installed routes
-            if ip.prefix() == 128 && route.installed && IpAddr::V6(ip.ip()) == *mesh_ip {
-                exit_route = Some(route);
-                break;
+            if ip.prefix() != 128 || !route.installed {
+                continue;
+            }
+            let preferred = match billing_routes.get(&IpAddr::V6(ip.ip())) {
+                Some(current) => {
+                    (route.source_specificity(), route.metric)
+                        < (current.source_specificity(), current.metric)
+                }
+                None => true,
+            };
+            if preferred {
+                billing_routes.insert(IpAddr::V6(ip.ip()), route);
             }
         }
     }
-    match exit_route {
+    billing_routes
+}
+
+/// Returns the installed route to a given destination, see get_billing_routes() for which one is picked
+/// when there are source specific routes to it
+pub fn get_installed_route(mesh_ip: &IpAddr, routes: &[Route]) -> Result<Route, BabelMonitorError> {
+    match get_billing_routes(routes).remove(mesh_ip) {
         Some(v) => Ok(v.clone()),
         None => Err(BabelMonitorError::NoRoute(
             "No installed route to that destination!".to_string(),
@@ -0,0 +1,201 @@
+//! A small pool of babel management connections. Opening a connection costs a tcp handshake and a
+//! preamble read, and the fast loop, slow loop, dashboard and exit billing all talk to babel every
+//! few seconds, so connections are handed back here after use and reused while they stay healthy.
+//! A connection is checked before it is handed out again, one that babel closed or that still has
+//! output from an earlier command waiting on it is thrown away, read_babel shuts a connection down
+//! on any io error for this reason. Failing to connect is returned as BabelMonitorError::BabelDown
+//! so that callers can tell babel being down from babel answering with something we can't parse.
+//! Callers run on the async runtime, so nothing here sleeps between attempts.
+
+use crate::open_babel_stream;
+use crate::structs::BabelMonitorError;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Idle connections kept at most. babeld accepts only 4 local connections (MAX_LOCAL_SOCKETS) and
+/// refuses the rest, connections in use count against that too, so most of them are left for those
+const MAX_IDLE: usize = 2;
+/// Idle connections older than this are closed rather than reused
+const MAX_IDLE_TIME: Duration = Duration::from_secs(60);
+/// Attempts at a query, each on a new connection, when babel stops answering partway through
+const QUERY_ATTEMPTS: u32 = 2;
+
+static IDLE: Mutex<Vec<IdleStream>> = Mutex::new(Vec::new());
+
+struct IdleStream {
+    port: u16,
+    idle_since: Instant,
+    stream: TcpStream,
+}
+
+/// A connection to babel, returned to the pool when dropped. Derefs to the TcpStream so it can be
+/// passed to every babel_monitor function as is
+pub struct PooledBabelStream {
+    port: u16,
+    stream: Option<TcpStream>,
+}
+
+impl Deref for PooledBabelStream {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        self.stream.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBabelStream {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        self.stream.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBabelStream {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let mut idle = IDLE.lock().unwrap();
+            if idle.len() < MAX_IDLE {
+                idle.push(IdleStream {
+                    port: self.port,
+                    idle_since: Instant::now(),
+                    stream,
+                });
+            }
+        }
+    }
+}
+
+/// A connection can be reused if babel hasn't closed it and nothing is waiting to be read on it,
+/// leftover output would be taken as the reply to the next command
+fn is_healthy(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut buf = [0u8; 1];
+    let healthy = match stream.peek(&mut buf) {
+        // zero bytes if babel or read_babel closed it, anything else is leftover output
+        Ok(_) => false,
+        Err(e) => e.kind() == ErrorKind::WouldBlock,
+    };
+    healthy && stream.set_nonblocking(false).is_ok()
+}
+
+fn take_idle(babel_port: u16) -> Option<TcpStream> {
+    let mut idle = IDLE.lock().unwrap();
+    idle.retain(|i| i.idle_since.elapsed() < MAX_IDLE_TIME);
+    while let Some(pos) = idle.iter().position(|i| i.port == babel_port) {
+        let candidate = idle.swap_remove(pos);
+        if is_healthy(&candidate.stream) {
+            return Some(candidate.stream);
+        }
+        trace!("Dropping a stale babel connection");
+    }
+    None
+}
+
+/// Takes a healthy connection from the pool or opens a new one. The timeout applies to connecting
+/// and to every read and write
+pub fn get_babel_stream(
+    babel_port: u16,
+    timeout: Duration,
+) -> Result<PooledBabelStream, BabelMonitorError> {
+    if let Some(stream) = take_idle(babel_port) {
+        if stream.set_read_timeout(Some(timeout)).is_ok()
+            && stream.set_write_timeout(Some(timeout)).is_ok()
+        {
+            return Ok(PooledBabelStream {
+                port: babel_port,
+                stream: Some(stream),
+            });
+        }
+    }
+
+    match open_babel_stream(babel_port, timeout) {
+        Ok(stream) => Ok(PooledBabelStream {
+            port: babel_port,
+            stream: Some(stream),
+        }),
+        Err(e) if e.is_babel_down() => Err(BabelMonitorError::BabelDown(format!(
+            "No answer on port {babel_port}, {e}"
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs a query against babel on a pooled connection. If babel stops answering partway through
+/// the query is run again right away on a new connection, so it must be safe to repeat. Errors
+/// other than babel being down are returned right away
+pub fn query_babel<T>(
+    babel_port: u16,
+    timeout: Duration,
+    mut query: impl FnMut(&mut TcpStream) -> Result<T, BabelMonitorError>,
+) -> Result<T, BabelMonitorError> {
+    let mut attempt = 0;
+    loop {
+        let mut stream = get_babel_stream(babel_port, timeout)?;
+        match query(&mut *stream) {
+            Ok(v) => return Ok(v),
+            Err(e) if e.is_babel_down() && attempt + 1 < QUERY_ATTEMPTS => {
+                warn!("Babel stopped answering {}, retrying", e);
+                drop(stream);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::BabelErrorKind;
+    use std::io::Write;
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_is_healthy() {
+        let (client, _server) = connected_pair();
+        assert!(is_healthy(&client));
+
+        // output nobody read is still waiting
+        let (client, mut server) = connected_pair();
+        server.write_all(b"ok\n").unwrap();
+        server.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!is_healthy(&client));
+
+        // babel went away
+        let (client, server) = connected_pair();
+        drop(server);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!is_healthy(&client));
+
+        // shut down by read_babel after an error
+        let (client, _server) = connected_pair();
+        client.shutdown(Shutdown::Both).unwrap();
+        assert!(!is_healthy(&client));
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let refused: BabelMonitorError =
+            std::io::Error::new(ErrorKind::ConnectionRefused, "refused").into();
+        assert!(refused.is_babel_down());
+        let parse: BabelMonitorError = "x".parse::<u16>().unwrap_err().into();
+        assert_eq!(parse.kind(), BabelErrorKind::Parse);
+        assert_eq!(
+            BabelMonitorError::NoRoute("fd00::1".to_string()).kind(),
+            BabelErrorKind::NotFound
+        );
+    }
+}
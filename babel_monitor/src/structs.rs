@@ -29,6 +29,52 @@ pub enum BabelMonitorError {
     NoRoute(String),
     MiscStringError(String),
     FromUtf8Error(FromUtf8Error),
+    /// Babel could not be reached, or stopped answering, after every retry
+    BabelDown(String),
+}
+
+/// What went wrong talking to babel, callers mostly care whether babel is running at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BabelErrorKind {
+    /// Babel isn't listening or the connection failed, retrying on a new connection may help
+    Down,
+    /// Babel answered but its output wasn't what we expected, or it refused the command
+    Parse,
+    /// What was looked up isn't in babel's tables
+    NotFound,
+    Other,
+}
+
+impl BabelMonitorError {
+    pub fn kind(&self) -> BabelErrorKind {
+        match self {
+            BabelMonitorError::BabelDown(_)
+            | BabelMonitorError::TcpError(_)
+            | BabelMonitorError::CommandFailed(_, _)
+            | BabelMonitorError::ReadFunctionError(_)
+            | BabelMonitorError::TokioError(_) => BabelErrorKind::Down,
+            BabelMonitorError::VariableNotFound(_, _)
+            | BabelMonitorError::InvalidPreamble(_)
+            | BabelMonitorError::LocalFeeNotFound(_)
+            | BabelMonitorError::ReadFailed(_)
+            | BabelMonitorError::NoTerminator(_)
+            | BabelMonitorError::BabelParseError(_)
+            | BabelMonitorError::BoolParseError(_)
+            | BabelMonitorError::ParseAddrError(_)
+            | BabelMonitorError::IntParseError(_)
+            | BabelMonitorError::FloatParseError(_)
+            | BabelMonitorError::NetworkError(_)
+            | BabelMonitorError::FromUtf8Error(_) => BabelErrorKind::Parse,
+            BabelMonitorError::NoNeighbor(_) | BabelMonitorError::NoRoute(_) => {
+                BabelErrorKind::NotFound
+            }
+            BabelMonitorError::MiscStringError(_) => BabelErrorKind::Other,
+        }
+    }
+
+    pub fn is_babel_down(&self) -> bool {
+        self.kind() == BabelErrorKind::Down
+    }
 }
 
 impl From<std::io::Error> for BabelMonitorError {
@@ -101,6 +147,7 @@ impl Display for BabelMonitorError {
             }
             BabelMonitorError::MiscStringError(a) => write!(f, "{a}",),
             BabelMonitorError::FromUtf8Error(a) => write!(f, "{a}",),
+            BabelMonitorError::BabelDown(a) => write!(f, "Babel is down: {a}",),
         }
     }
 }
@@ -126,6 +173,16 @@ pub struct Route {
     pub full_path_rtt: f32,
     pub price: u32,
     pub fee: u32,
+    /// Source prefix of a source specific route, None for routes that apply to traffic from anywhere
+    #[serde(default)]
+    pub src_prefix: Option<IpNetwork>,
+}
+
+impl Route {
+    /// How narrow the range of sources this route applies to is, 0 for routes that apply to all
+    pub fn source_specificity(&self) -> u8 {
+        self.src_prefix.map(|src| src.prefix()).unwrap_or(0)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,13 +1,11 @@
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
 
+use althea_kernel_interface::rita_owned::RitaOwnedState;
 use althea_kernel_interface::KI;
 use clarity::PrivateKey;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use regex::Regex;
 use settings::client::RitaClientSettings;
 use settings::exit::RitaExitSettingsStruct;
 use std::fmt::{Display, Formatter, Result as FmtResult};
@@ -62,25 +60,12 @@ pub fn validate_mesh_ip(ip: &IpAddr) -> bool {
     ip.is_ipv6() && !ip.is_unspecified()
 }
 
-/// Called before anything is started to delete existing wireguard per hop tunnels
+/// Called before anything is started to remove the tunnels, routes and rules a previous run left behind,
+/// nothing is set up yet so everything Rita owns is stale
 pub fn cleanup() -> Result<(), NewCluError> {
-    debug!("Cleaning up WireGuard tunnels");
-
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"^wg[0-9]+$").unwrap();
-    }
-
-    let mut interfaces: Vec<String> = Vec::new();
-
-    for i in KI.get_interfaces()? {
-        if RE.is_match(&i) {
-            interfaces.push(i);
-        }
-    }
-    interfaces.push("wg_exit".to_string());
-    interfaces.push("wg_exit_v2".to_string());
-
-    del_multiple_interfaces(interfaces);
+    debug!("Cleaning up Rita owned kernel state");
+    let report = KI.sweep_rita_owned_state(&RitaOwnedState::default())?;
+    trace!("Startup sweep removed {:?}", report);
     Ok(())
 }
 
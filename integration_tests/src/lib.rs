@@ -6,6 +6,15 @@ extern crate log;
 
 use std::time::Duration;
 
+use contract_test::run_altheadb_contract_test;
+use db_migration_test::run_db_migration_test;
+use debts::run_debts_test;
+use five_nodes::run_five_node_test_scenario;
+use mutli_exit::run_multi_exit_test;
+use payments_althea::run_althea_payments_test_scenario;
+use payments_eth::run_eth_payments_test_scenario;
+use tunnel_billing::run_tunnel_billing_test;
+
 pub mod contract_test;
 pub mod db_migration_test;
 pub mod debts;
@@ -15,7 +24,31 @@ pub mod payments_althea;
 pub mod payments_eth;
 pub mod registration_server;
 pub mod setup_utils;
+pub mod tunnel_billing;
 pub mod utils;
 
 /// The amount of time we wait for a network to stabalize before testing
 pub const SETUP_WAIT: Duration = Duration::from_secs(60);
+
+/// Runs the test scenario named by a TEST_TYPE, shared by the tester binary and cargo test
+pub async fn run_test_type(test_type: &str) {
+    if test_type == "FIVE_NODES" {
+        run_five_node_test_scenario().await;
+    } else if test_type == "DEBTS_TEST" {
+        run_debts_test().await;
+    } else if test_type == "PAYMENTS_ETH" || test_type == "ETH_PAYMENTS" {
+        run_eth_payments_test_scenario().await;
+    } else if test_type == "PAYMENTS_ALTHEA" || test_type == "ALTHEA_PAYMENTS" {
+        run_althea_payments_test_scenario().await
+    } else if test_type == "MULTI_EXIT" {
+        run_multi_exit_test().await
+    } else if test_type == "CONTRACT_TEST" {
+        run_altheadb_contract_test().await
+    } else if test_type == "MIGRATION_TEST" {
+        run_db_migration_test().await
+    } else if test_type == "TUNNEL_BILLING" {
+        run_tunnel_billing_test().await
+    } else {
+        panic!("Error unknown test type {}!", test_type);
+    }
+}
@@ -151,7 +151,7 @@ pub fn spawn_rita(
         rcsettings.network.peer_interfaces = veth_interfaces;
         rcsettings.network.babeld_settings.local_fee = local_fee;
 
-        // mirrored from rita_bin/src/client.rs
+        // mirrored from rita_bin/src/client_role.rs
         let s = clu::init(rcsettings);
         set_flag_config(config_path.into());
         settings::set_rita_client(s.clone());
@@ -245,7 +245,7 @@ pub fn spawn_rita_exit(
         let veth_exit_to_native = format!("vout-{}-o", ns);
         resettings.network.external_nic = Some(veth_exit_to_native);
 
-        // mirrored from rita_bin/src/exit.rs
+        // mirrored from rita_bin/src/exit_role.rs
         let resettings = clu::exit_init(resettings);
 
         set_flag_config(config_path.into());
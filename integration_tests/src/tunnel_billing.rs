@@ -0,0 +1,330 @@
+use crate::debts::validate_debt_increase;
+use crate::registration_server::start_registration_server;
+use crate::setup_utils::namespaces::*;
+use crate::setup_utils::rita::{thread_spawner, InstanceData};
+use crate::utils::{
+    add_exits_contract_exit_list, deploy_contracts, generate_traffic, get_default_settings,
+    get_ip_from_namespace, populate_routers_eth, query_debts, register_all_namespaces_to_exit,
+    test_all_internet_connectivity, test_reach_all, test_routes,
+};
+use althea_kernel_interface::KI;
+use althea_types::WgKey;
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long tunnels have to come up after registration before the test fails
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/*
+A client behind a relay and a gateway, the shape most production regressions show up in:
+1---------2---------3---------4
+client    relay     gateway   exit
+*/
+
+/// Runs a client through a relay and a gateway to an exit, checks that every mesh and exit tunnel
+/// is set up and that traffic to the internet and across the mesh is billed at the right rates
+pub async fn run_tunnel_billing_test() {
+    info!("Starting tunnel and billing test");
+
+    let node_config = tunnel_billing_config();
+    let namespaces = node_config.0;
+    let expected_routes = node_config.1;
+
+    info!("Waiting to deploy contracts");
+    let db_addr = deploy_contracts().await;
+
+    info!("Starting registration server");
+    start_registration_server(db_addr).await;
+
+    let (client_settings, exit_settings) = get_default_settings(namespaces.clone());
+
+    let client = namespaces.get_namespace(1).unwrap();
+    let relay = namespaces.get_namespace(2).unwrap();
+    let gateway = namespaces.get_namespace(3).unwrap();
+    let exit = namespaces.get_namespace(4).unwrap();
+    // The exit price is set to ns.cost during thread_spawner
+    let exit_price = exit.cost;
+
+    namespaces.validate();
+
+    let res = setup_ns(namespaces.clone());
+    info!("Namespaces setup: {res:?}");
+
+    let rita_identities =
+        thread_spawner(namespaces.clone(), client_settings, exit_settings, db_addr)
+            .expect("Could not spawn Rita threads");
+
+    add_exits_contract_exit_list(db_addr, rita_identities.clone()).await;
+
+    populate_routers_eth(rita_identities.clone()).await;
+
+    test_reach_all(namespaces.clone());
+
+    test_routes(namespaces.clone(), expected_routes);
+
+    info!("Registering routers to the exit");
+    register_all_namespaces_to_exit(namespaces.clone()).await;
+
+    test_all_internet_connectivity(namespaces.clone());
+
+    info!("Checking mesh and exit tunnels");
+    test_tunnels(namespaces.clone(), &rita_identities);
+
+    info!("Checking billing for internet traffic");
+    let debts_nodes = vec![client.clone(), relay.clone(), gateway.clone(), exit.clone()];
+    let before = query_debts(debts_nodes.clone(), Some(debts_nodes.clone())).await;
+    generate_traffic(client.clone(), None, "1G".to_string());
+    // Give time for rita to update debtkeeper
+    thread::sleep(Duration::from_secs(20));
+    let after = query_debts(debts_nodes.clone(), Some(debts_nodes.clone())).await;
+    // each hop is paid for everything it forwards onward, the exit is paid directly by the client
+    validate_debt_increase(
+        client.clone(),
+        relay.clone(),
+        &after,
+        &before,
+        1u32,
+        relay.cost + gateway.cost,
+    );
+    validate_debt_increase(
+        relay.clone(),
+        gateway.clone(),
+        &after,
+        &before,
+        1u32,
+        gateway.cost,
+    );
+    validate_debt_increase(client.clone(), exit, &after, &before, 1u32, exit_price);
+
+    info!("Checking billing for mesh traffic");
+    let debts_nodes = vec![client.clone(), relay.clone(), gateway.clone()];
+    let before = query_debts(debts_nodes.clone(), Some(debts_nodes.clone())).await;
+    generate_traffic(client.clone(), Some(gateway), "1G".to_string());
+    thread::sleep(Duration::from_secs(20));
+    let after = query_debts(debts_nodes.clone(), Some(debts_nodes)).await;
+    validate_debt_increase(client, relay.clone(), &after, &before, 1u32, relay.cost);
+
+    info!("Tunnel and billing test passed!");
+}
+
+/// Lists the wg peers of every interface in a namespace
+fn get_wg_peers(ns: &Namespace) -> HashMap<String, HashSet<WgKey>> {
+    let out = KI
+        .run_command(
+            "ip",
+            &[
+                "netns",
+                "exec",
+                &ns.get_name(),
+                "wg",
+                "show",
+                "all",
+                "peers",
+            ],
+        )
+        .expect("Failed to run wg show");
+    let mut peers: HashMap<String, HashSet<WgKey>> = HashMap::new();
+    for line in String::from_utf8(out.stdout).unwrap().lines() {
+        let mut fields = line.split_whitespace();
+        if let (Some(iface), Some(key)) = (fields.next(), fields.next()) {
+            if let Ok(key) = key.parse() {
+                peers.entry(iface.to_string()).or_default().insert(key);
+            }
+        }
+    }
+    peers
+}
+
+fn is_exit_tunnel(iface: &str) -> bool {
+    iface.starts_with("wg_exit")
+}
+
+/// Checks that each node has a mesh tunnel to every neighbor, each client has an exit tunnel and the
+/// exit has a tunnel to every client
+fn test_tunnels(namespaces: NamespaceInfo, identities: &InstanceData) {
+    let mesh_keys: HashMap<String, WgKey> = identities
+        .client_identities
+        .iter()
+        .chain(identities.exit_identities.iter())
+        .map(|id| (id.mesh_ip.to_string(), id.wg_public_key))
+        .collect();
+    let client_keys: HashSet<WgKey> = identities
+        .client_identities
+        .iter()
+        .map(|id| id.wg_public_key)
+        .collect();
+
+    let start = Instant::now();
+    for ns in namespaces.names.iter() {
+        let neighbors: HashSet<WgKey> = namespaces
+            .linked
+            .iter()
+            .filter_map(|(a, b)| match (*a == ns.id, *b == ns.id) {
+                (true, _) => Some(*b),
+                (_, true) => Some(*a),
+                _ => None,
+            })
+            .map(|id| {
+                let neighbor = namespaces.get_namespace(id).unwrap();
+                mesh_keys[&get_ip_from_namespace(neighbor)]
+            })
+            .collect();
+
+        loop {
+            let peers = get_wg_peers(ns);
+            let mesh_peers: HashSet<WgKey> = peers
+                .iter()
+                .filter(|(iface, _)| !is_exit_tunnel(iface))
+                .flat_map(|(_, keys)| keys.iter().copied())
+                .collect();
+            let exit_peers: HashSet<WgKey> = peers
+                .iter()
+                .filter(|(iface, _)| is_exit_tunnel(iface))
+                .flat_map(|(_, keys)| keys.iter().copied())
+                .collect();
+
+            let exit_ok = match ns.node_type {
+                NodeType::Client { .. } => exit_peers.len() == 1,
+                NodeType::Exit { .. } => client_keys.is_subset(&exit_peers),
+            };
+            if neighbors.is_subset(&mesh_peers) && exit_ok {
+                info!("Tunnels are up for {}", ns.get_name());
+                break;
+            }
+            if Instant::now() - start > TUNNEL_TIMEOUT {
+                panic!(
+                    "{} is missing tunnels, mesh peers {:?} expected {:?}, exit peers {:?}",
+                    ns.get_name(),
+                    mesh_peers,
+                    neighbors,
+                    exit_peers
+                );
+            }
+            warn!("Tunnels not yet up for {}, retrying...", ns.get_name());
+            thread::sleep(Duration::from_secs(5));
+        }
+    }
+}
+
+/// This defines the network map for the tunnel and billing scenario
+pub fn tunnel_billing_config() -> (NamespaceInfo, HashMap<Namespace, RouteHop>) {
+    let client = Namespace {
+        id: 1,
+        cost: 10_000_000,
+        node_type: NodeType::Client {
+            exit_name: "test_4".to_string(),
+        },
+    };
+    let relay = Namespace {
+        id: 2,
+        cost: 20_000_000,
+        node_type: NodeType::Client {
+            exit_name: "test_4".to_string(),
+        },
+    };
+    let gateway = Namespace {
+        id: 3,
+        cost: 15_000_000,
+        node_type: NodeType::Client {
+            exit_name: "test_4".to_string(),
+        },
+    };
+    let exit = Namespace {
+        id: 4,
+        cost: 10_000_000,
+        node_type: NodeType::Exit {
+            instance_name: "test_4".to_string(),
+        },
+    };
+
+    let nsinfo = NamespaceInfo {
+        names: vec![client.clone(), relay.clone(), gateway.clone(), exit.clone()],
+        linked: vec![(1, 2), (2, 3), (3, 4)],
+    };
+
+    // The price of a route is the sum of the fees of the nodes in between
+    let mut expected_routes = HashMap::new();
+    let client_routes = RouteHop {
+        destination: [
+            (2, PriceId { price: 0, id: 2 }),
+            (
+                3,
+                PriceId {
+                    price: 20_000_000,
+                    id: 2,
+                },
+            ),
+            (
+                4,
+                PriceId {
+                    price: 35_000_000,
+                    id: 2,
+                },
+            ),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    };
+    let relay_routes = RouteHop {
+        destination: [
+            (1, PriceId { price: 0, id: 1 }),
+            (3, PriceId { price: 0, id: 3 }),
+            (
+                4,
+                PriceId {
+                    price: 15_000_000,
+                    id: 3,
+                },
+            ),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    };
+    let gateway_routes = RouteHop {
+        destination: [
+            (
+                1,
+                PriceId {
+                    price: 20_000_000,
+                    id: 2,
+                },
+            ),
+            (2, PriceId { price: 0, id: 2 }),
+            (4, PriceId { price: 0, id: 4 }),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    };
+    let exit_routes = RouteHop {
+        destination: [
+            (
+                1,
+                PriceId {
+                    price: 35_000_000,
+                    id: 3,
+                },
+            ),
+            (
+                2,
+                PriceId {
+                    price: 15_000_000,
+                    id: 3,
+                },
+            ),
+            (3, PriceId { price: 0, id: 3 }),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    };
+    expected_routes.insert(client, client_routes);
+    expected_routes.insert(relay, relay_routes);
+    expected_routes.insert(gateway, gateway_routes);
+    expected_routes.insert(exit, exit_routes);
+
+    (nsinfo, expected_routes)
+}
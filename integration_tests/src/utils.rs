@@ -411,6 +411,8 @@ pub fn get_default_settings(
                     exit_id: exit_id.exit_id,
                     registration_port: exit.exit_network.exit_hello_port,
                     wg_exit_listen_port: exit.exit_network.wg_v2_tunnel_port,
+                    tunnel_mtu: None,
+                    persistent_keepalive: None,
                     info: althea_types::ExitState::New,
                 },
             );
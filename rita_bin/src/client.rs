@@ -4,8 +4,8 @@
 //! vpn system integrated into the Althea network design, as well as API endpoints for a management
 //! dashboard of router functions like wifi, which the exit is not expected to have.
 //!
-//! This file initializes the dashboard endpoints for the client as well as the common and client
-//! specific actors.
+//! This file parses the command line, the startup itself is in rita_bin::client_role which rita_multi
+//! shares.
 
 #![warn(clippy::all)]
 #![allow(clippy::pedantic)]
@@ -17,49 +17,15 @@ use jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate log;
-
-use althea_kernel_interface::KernelInterface;
-use althea_kernel_interface::LinuxCommandRunner;
 use docopt::Docopt;
-use rita_client::dashboard::start_client_dashboard;
+use rita_bin::client_role::start_client;
+use rita_bin::set_shutdown_handler;
 use rita_client::get_client_usage;
-use rita_client::rita_loop::start_antenna_forwarder;
-use rita_client::rita_loop::start_rita_client_loops;
-use rita_client::rita_loop::update_dns_conf;
-use rita_client::rita_loop::update_system_time;
 use rita_client::Args;
-use rita_common::debt_keeper::save_debt_on_shutdown;
-use rita_common::logging::enable_remote_logging;
-use rita_common::rita_loop::start_core_rita_endpoints;
-use rita_common::rita_loop::start_rita_common_loops;
-use rita_common::rita_loop::write_to_disk::save_to_disk_loop;
-use rita_common::rita_loop::write_to_disk::SettingsOnDisk;
-use rita_common::usage_tracker::save_usage_on_shutdown;
-use rita_common::utils::apply_babeld_settings_defaults;
-use rita_common::utils::env_vars_contains;
-use settings::client::RitaClientSettings;
-use settings::save_settings_on_shutdown;
-use settings::FileWrite;
-
-lazy_static! {
-    pub static ref KI: Box<dyn KernelInterface> = Box::new(LinuxCommandRunner {});
-}
 
 fn main() {
     //Setup a SIGTERM hadler
-    ctrlc::set_handler(move || {
-        info!("received Ctrl+C!");
-        save_debt_on_shutdown();
-        save_usage_on_shutdown();
-        save_settings_on_shutdown();
-
-        std::process::exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+    set_shutdown_handler();
 
     let args: Args = Docopt::new(get_client_usage(
         env!("CARGO_PKG_VERSION"),
@@ -68,119 +34,5 @@ fn main() {
     .and_then(|d| d.deserialize())
     .unwrap_or_else(|e| e.exit());
 
-    let settings_file = args.flag_config;
-    println!("Settings file {}", settings_file.display());
-
-    // load the settings file, setup a thread to save it out every so often
-    // and populate the memory cache of settings used throughout the program
-    let settings: RitaClientSettings = {
-        RitaClientSettings::new_watched(settings_file.clone()).unwrap();
-        let mut s = settings::get_rita_client();
-
-        settings::set_flag_config(settings_file.clone());
-
-        // start migrations //
-
-        // handle babel migration for old settings files
-        // this can be removed after all routers are upgraded paste Beta 21RC4 or Beta 20 RC31
-        if let Some(local_fee) = s.payment.local_fee {
-            s.network.babeld_settings.local_fee = local_fee;
-            s.payment.local_fee = None;
-        }
-        if let Some(metric_factor) = s.network.metric_factor {
-            s.network.babeld_settings.metric_factor = metric_factor;
-            s.network.metric_factor = None;
-        }
-
-        // update the Althea L1 chain rpc url, this can be removed after all routers are upgraded past Beta 21 RC6
-        // replace routine is used so we don't blast any local config changes to use different rpc urls
-        s.payment.althea_grpc_list = s
-            .payment
-            .althea_grpc_list
-            .iter()
-            .map(|url| url.replace("http://althea.zone", "http://rpc.althea.zone"))
-            .collect();
-
-        // end migrations //
-
-        let s = clu::init(s);
-
-        s.write(settings_file).unwrap();
-        settings::set_rita_client(s.clone());
-        println!("Look the client settings! {s:?}");
-        s
-    };
-
-    // Because Rita clears and sets up new Wireguard Tunnels on every restart Babel, which was attached and listening to
-    // the old tunnels is now in an incorrect state. We must either restart babel or empty it's interfaces list so that the newly
-    // created wireguard tunnels can be re-added by this instance of Rita. Due to errors in babel (see git history there)
-    // restarting is the way to go as removing dead interfaces often does not work
-    KI.restart_babel();
-    apply_babeld_settings_defaults(
-        settings.network.babel_port,
-        settings.network.babeld_settings,
-    );
-
-    // On Linux static builds we need to probe ssl certs path to be able to
-    // do TLS stuff.
-    openssl_probe::init_ssl_cert_env_vars();
-
-    // we should remote log if there's an operator address or if logging is enabled
-    let should_remote_log = settings.log.enabled || settings.operator.operator_address.is_some();
-    // if remote logging is disabled, or the NO_REMOTE_LOG env var is set we should use the
-    // local logger and log to std-out. Note we don't care what is actually set in NO_REMOTE_LOG
-    // just that it is set
-    if !should_remote_log || env_vars_contains("NO_REMOTE_LOG") {
-        env_logger::init();
-    } else {
-        let log = settings.log.clone();
-        let key = settings
-            .network
-            .wg_public_key
-            .expect("Tried to init remote logging without WgKey!");
-
-        let res =
-            enable_remote_logging("rita".to_string(), log.dest_url, log.level, key.to_string());
-
-        println!("logging status {res:?}");
-    }
-
-    if cfg!(feature = "development") {
-        println!("Warning!");
-        println!("This build is meant only for development purposes.");
-        println!("Running this on production is unsupported and not safe!");
-    }
-
-    // If we are an an OpenWRT device try and rescue it from update issues
-    if KI.is_openwrt() && KI.check_cron().is_err() {
-        error!("Failed to setup cron!");
-    }
-
-    info!(
-        "crate ver {}, git hash {}",
-        env!("CARGO_PKG_VERSION"),
-        env!("GIT_HASH")
-    );
-    trace!("Starting with Identity: {:?}", settings.get_identity());
-
-    let system = actix_async::System::new();
-
-    start_rita_common_loops();
-    start_rita_client_loops();
-    save_to_disk_loop(SettingsOnDisk::RitaClientSettings(Box::new(
-        settings::get_rita_client(),
-    )));
-    start_core_rita_endpoints(4);
-    start_client_dashboard(settings.network.rita_dashboard_port);
-    start_antenna_forwarder(settings);
-
-    // utility and rescue fucntions, these perform some upgrade or check
-    update_dns_conf();
-    update_system_time();
-
-    if let Err(e) = system.run() {
-        error!("Starting client failed with {}", e);
-    }
-
-    info!("Started Rita Client!");
+    start_client(args.flag_config, false);
 }
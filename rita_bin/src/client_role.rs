@@ -0,0 +1,164 @@
+//! Startup for the client and gateway roles, the dashboard, exit management and router specific
+//! loops on top of everything in rita_common.
+
+use crate::{print_startup_info, start_common_subsystems, start_logging};
+use rita_client::dashboard::start_client_dashboard;
+use rita_client::exit_manager::SUPPORTED_EXIT_PROTOCOL_VERSIONS;
+use rita_client::rita_loop::start_antenna_forwarder;
+use rita_client::rita_loop::start_rita_client_loops;
+use rita_client::rita_loop::update_dns_conf;
+use rita_client::rita_loop::update_system_time;
+use rita_client::snmp::start_snmp_agent;
+use rita_common::login_lockout::start_unlock_button_watcher;
+use rita_common::rita_loop::set_gateway;
+use rita_common::rita_loop::write_to_disk::SettingsOnDisk;
+use rita_common::utils::apply_babeld_settings_defaults;
+use rita_common::KI;
+use settings::client::RitaClientSettings;
+use settings::role::RitaRole;
+use settings::FileWrite;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Loads the client settings, runs the settings migrations and populates the memory cache of
+/// settings used throughout the program
+fn load_client_settings(settings_file: PathBuf) -> RitaClientSettings {
+    RitaClientSettings::new_watched(settings_file.clone()).unwrap();
+    let mut s = settings::get_rita_client();
+
+    settings::set_flag_config(settings_file.clone());
+
+    // start migrations //
+
+    // handle babel migration for old settings files
+    // this can be removed after all routers are upgraded paste Beta 21RC4 or Beta 20 RC31
+    if let Some(local_fee) = s.payment.local_fee {
+        s.network.babeld_settings.local_fee = local_fee;
+        s.payment.local_fee = None;
+    }
+    if let Some(metric_factor) = s.network.metric_factor {
+        s.network.babeld_settings.metric_factor = metric_factor;
+        s.network.metric_factor = None;
+    }
+
+    // update the Althea L1 chain rpc url, this can be removed after all routers are upgraded past Beta 21 RC6
+    // replace routine is used so we don't blast any local config changes to use different rpc urls
+    s.payment.althea_grpc_list = s
+        .payment
+        .althea_grpc_list
+        .iter()
+        .map(|url| url.replace("http://althea.zone", "http://rpc.althea.zone"))
+        .collect();
+
+    // end migrations //
+
+    let s = clu::init(s);
+
+    s.write(settings_file).unwrap();
+    settings::set_rita_client(s.clone());
+    println!("Look the client settings! {s:?}");
+    s
+}
+
+/// The optional client subsystems and whether these settings turn them on, for the startup banner
+fn client_subsystems(settings: &RitaClientSettings) -> BTreeMap<String, bool> {
+    [
+        ("operator", settings.operator.operator_address.is_some()),
+        (
+            "broadcast_notice_gossip",
+            settings.operator.gossip_broadcast_notices,
+        ),
+        (
+            "bandwidth_contract",
+            settings.operator.bandwidth_contract.is_some(),
+        ),
+        ("exit_kill_switch", settings.exit_client.kill_switch),
+        (
+            "exit_registry",
+            settings.exit_client.exit_registry.is_some(),
+        ),
+        ("exit_split", settings.exit_client.exit_split.is_some()),
+        ("guest_network", settings.guest_network.is_some()),
+        ("snmp", settings.snmp.is_some()),
+        ("remote_logging", settings.log.enabled),
+    ]
+    .iter()
+    .map(|(name, enabled)| (name.to_string(), *enabled))
+    .collect()
+}
+
+/// Starts a client router, as a gateway from the start if gateway is set rather than once the wan
+/// port is seen up
+pub fn start_client(settings_file: PathBuf, gateway: bool) {
+    println!("Settings file {}", settings_file.display());
+    let settings = load_client_settings(settings_file);
+
+    // Because Rita clears and sets up new Wireguard Tunnels on every restart Babel, which was attached and listening to
+    // the old tunnels is now in an incorrect state. We must either restart babel or empty it's interfaces list so that the newly
+    // created wireguard tunnels can be re-added by this instance of Rita. Due to errors in babel (see git history there)
+    // restarting is the way to go as removing dead interfaces often does not work
+    KI.restart_babel();
+    apply_babeld_settings_defaults(
+        settings.network.babel_port,
+        settings.network.babeld_settings,
+    );
+
+    // On Linux static builds we need to probe ssl certs path to be able to
+    // do TLS stuff.
+    openssl_probe::init_ssl_cert_env_vars();
+
+    // we should remote log if there's an operator address or if logging is enabled
+    let should_remote_log = settings.log.enabled || settings.operator.operator_address.is_some();
+    let log = settings.log.clone();
+    start_logging(
+        should_remote_log,
+        "rita",
+        log.dest_url,
+        log.level,
+        settings.network.wg_public_key,
+    );
+    print_startup_info(
+        if gateway {
+            RitaRole::Gateway
+        } else {
+            RitaRole::Client
+        },
+        client_subsystems(&settings),
+        SUPPORTED_EXIT_PROTOCOL_VERSIONS.to_vec(),
+        None,
+    );
+
+    // If we are an an OpenWRT device try and rescue it from update issues
+    if KI.is_openwrt() && KI.check_cron().is_err() {
+        error!("Failed to setup cron!");
+    }
+
+    trace!("Starting with Identity: {:?}", settings.get_identity());
+
+    if gateway {
+        info!("Starting as a gateway");
+        set_gateway(true);
+    }
+
+    let system = actix_async::System::new();
+
+    start_common_subsystems(
+        SettingsOnDisk::RitaClientSettings(Box::new(settings::get_rita_client())),
+        4,
+    );
+    start_rita_client_loops();
+    start_client_dashboard(settings.network.rita_dashboard_port);
+    start_unlock_button_watcher();
+    start_snmp_agent();
+    start_antenna_forwarder(settings);
+
+    // utility and rescue fucntions, these perform some upgrade or check
+    update_dns_conf();
+    update_system_time();
+
+    if let Err(e) = system.run() {
+        error!("Starting client failed with {}", e);
+    }
+
+    info!("Started Rita Client!");
+}
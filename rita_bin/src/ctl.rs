@@ -0,0 +1,109 @@
+//! rita_ctl, offline administration for rita and rita_exit. These commands work on the config file and
+//! on disk state directly, so they are meant to be run while the daemon is stopped, a running daemon
+//! would overwrite any change to its config on its next save. See rita_bin::offline.
+
+#![warn(clippy::all)]
+#![allow(clippy::pedantic)]
+#![forbid(unsafe_code)]
+
+use docopt::{ArgvMap, Docopt};
+use rita_bin::offline::{
+    backup_list, backup_restore, db_check, rotate_key, settings_get, settings_set, sign_voucher,
+    usage_dump, OfflineConfig,
+};
+use rita_common::dashboard::own_info::READABLE_VERSION;
+use settings::client::default_config_path;
+use std::path::PathBuf;
+
+fn get_ctl_usage(version: &str, git_hash: &str) -> String {
+    format!(
+        "Usage:
+    rita_ctl [--config=<settings>] settings get [<path>]
+    rita_ctl [--config=<settings>] settings set <path> <value>
+    rita_ctl [--config=<settings>] validate
+    rita_ctl [--config=<settings>] key rotate
+    rita_ctl [--config=<settings>] usage dump
+    rita_ctl [--config=<settings>] db check
+    rita_ctl [--config=<settings>] backup list
+    rita_ctl [--config=<settings>] backup restore [<object>]
+    rita_ctl voucher sign <key_file> <amount> <days>
+Options:
+    -c, --config=<settings>   Name of config file, client or exit
+Settings paths are dotted, for example network.babel_port, values are json or plain strings.
+Vouchers are signed with the operator eth private key in key_file, amount is in wei.
+About:
+    Version {READABLE_VERSION} - {version}
+    git hash {git_hash}"
+    )
+}
+
+fn run(args: &ArgvMap, config_file: PathBuf) -> Result<(), String> {
+    if args.get_bool("voucher") && args.get_bool("sign") {
+        let voucher = sign_voucher(
+            args.get_str("<key_file>"),
+            args.get_str("<amount>"),
+            args.get_str("<days>"),
+        )?;
+        println!(
+            "Voucher {} for {} wei, expires at {}",
+            voucher.voucher.id, voucher.voucher.amount, voucher.voucher.expiry
+        );
+        println!("{}", voucher.to_code());
+        return Ok(());
+    }
+    let mut config = OfflineConfig::load(&config_file)?;
+    if args.get_bool("settings") && args.get_bool("get") {
+        let path = match args.get_str("<path>") {
+            "" => None,
+            path => Some(path),
+        };
+        println!("{}", settings_get(&config, path)?);
+    } else if args.get_bool("settings") && args.get_bool("set") {
+        let path = args.get_str("<path>");
+        settings_set(&mut config, config_file, path, args.get_str("<value>"))?;
+        println!("Set {path}");
+    } else if args.get_bool("validate") {
+        let problems = config.problems();
+        if !problems.is_empty() {
+            return Err(problems.join("\n"));
+        }
+        println!("{} is valid", config_file.display());
+    } else if args.get_bool("key") && args.get_bool("rotate") {
+        let public = rotate_key(&mut config, config_file)?;
+        println!("New wg public key {public}");
+    } else if args.get_bool("usage") && args.get_bool("dump") {
+        println!("{}", usage_dump(&config)?);
+    } else if args.get_bool("db") && args.get_bool("check") {
+        for finding in db_check(&config)? {
+            println!("{finding}");
+        }
+    } else if args.get_bool("backup") && args.get_bool("list") {
+        for line in backup_list(&config)? {
+            println!("{line}");
+        }
+    } else if args.get_bool("backup") && args.get_bool("restore") {
+        let object = match args.get_str("<object>") {
+            "" => None,
+            object => Some(object),
+        };
+        for line in backup_restore(&config, object)? {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Docopt::new(get_ctl_usage(env!("CARGO_PKG_VERSION"), env!("GIT_HASH")))
+        .and_then(|d| d.parse())
+        .unwrap_or_else(|e| e.exit());
+
+    let config_file = match args.get_str("--config") {
+        "" => default_config_path(),
+        path => PathBuf::from(path),
+    };
+    if let Err(e) = run(&args, config_file) {
+        println!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,226 @@
+//! Startup for the exit role, the client database, exit loops and the exit's client facing, dashboard
+//! and admin endpoints on top of everything in rita_common.
+
+use crate::{print_startup_info, start_common_subsystems, start_logging};
+use actix_rt::time::Instant;
+use actix_rt::System;
+use althea_types::Identity;
+use rita_client_registration::client_db::get_all_regsitered_clients;
+use rita_common::rita_loop::get_web3_server;
+use rita_common::rita_loop::write_to_disk::SettingsOnDisk;
+use rita_common::utils::apply_babeld_settings_defaults;
+use rita_exit::admin_api::start_rita_exit_admin_api;
+use rita_exit::cluster::bootstrap_from_cluster;
+use rita_exit::database::EXIT_SUPPORTED_PROTOCOL_VERSIONS;
+use rita_exit::heartbeat::start_exit_heartbeat_listener;
+use rita_exit::operator_update::update_loop::start_operator_update_loop;
+use rita_exit::preflight::run_preflight_checks;
+use rita_exit::rita_loop::start_rita_exit_endpoints;
+use rita_exit::rita_loop::start_rita_exit_loop;
+use rita_exit::start_rita_exit_dashboard;
+use settings::exit::{ExitClientStore, RitaExitSettingsStruct};
+use settings::role::RitaRole;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const STARTUP_RETRY_TIME: Duration = Duration::from_secs(10);
+
+/// Checks for exit configs that don't make sense, the exit refuses to start with them
+pub fn check_exit_config(exit_settings: &RitaExitSettingsStruct) -> Result<(), String> {
+    if !exit_settings.allowed_countries.is_empty()
+        && exit_settings.exit_network.geoip_api_key.is_none()
+    {
+        return Err("GEOIP enforcement configured but not api key provided!".to_string());
+    }
+
+    // check wg_exit_v2 port is valid
+    if exit_settings.exit_network.wg_v2_tunnel_port >= 59999 {
+        return Err(format!(
+            "wg_v2_tunnel_port {} is out of range",
+            exit_settings.exit_network.wg_v2_tunnel_port
+        ));
+    }
+    Ok(())
+}
+
+/// used to crash the exit on first startup if config does not make sense
+/// as is usually desirable for cloud infrastruture
+fn sanity_check_config() {
+    if let Err(e) = check_exit_config(&settings::get_rita_exit()) {
+        panic!("{}", e);
+    }
+}
+
+/// Loads the exit settings and populates the memory cache of settings used throughout the program
+fn load_exit_settings(settings_file: PathBuf) -> RitaExitSettingsStruct {
+    let settings = RitaExitSettingsStruct::new_watched(settings_file.clone()).unwrap();
+
+    settings::set_flag_config(settings_file);
+
+    let settings = clu::exit_init(settings);
+    settings::set_rita_exit(settings.clone());
+    // a new exit in a cluster takes its keys and ports from a member before they are checked
+    let settings = bootstrap_cluster_config(settings);
+    sanity_check_config();
+    println!("Look the exit settings! {settings:?}");
+    settings
+}
+
+/// The optional exit subsystems and whether these settings turn them on, for the startup banner
+fn exit_subsystems(settings: &RitaExitSettingsStruct) -> BTreeMap<String, bool> {
+    let exit_network = &settings.exit_network;
+    [
+        ("admin_api", exit_network.admin_api.is_some()),
+        (
+            "cluster_bootstrap",
+            exit_network.cluster_bootstrap.is_some(),
+        ),
+        ("sharding", exit_network.sharding.is_some()),
+        ("enforcement", exit_network.enable_enforcement),
+        (
+            "enforcement_shadow",
+            exit_network.enforcement_shadow.is_some(),
+        ),
+        ("billing_dry_run", exit_network.billing_dry_run),
+        ("geoip", !settings.allowed_countries.is_empty()),
+        (
+            "memory_client_store",
+            exit_network.client_store == ExitClientStore::Memory,
+        ),
+        ("remote_logging", settings.remote_log),
+    ]
+    .iter()
+    .map(|(name, enabled)| (name.to_string(), *enabled))
+    .collect()
+}
+
+pub fn start_exit(settings_file: PathBuf) {
+    let settings = load_exit_settings(settings_file);
+    apply_babeld_settings_defaults(
+        settings.network.babel_port,
+        settings.network.babeld_settings,
+    );
+
+    // On Linux static builds we need to probe ssl certs path to be able to
+    // do TLS stuff.
+    openssl_probe::init_ssl_cert_env_vars();
+
+    // An exit setting dictating if this exit operator wants to log remotely or locally
+    start_logging(
+        settings.remote_log,
+        "rita_exit",
+        "https://stats.altheamesh.com:9999/compressed_sink".to_string(),
+        "INFO".to_string(),
+        settings.network.wg_public_key,
+    );
+    print_startup_info(
+        RitaRole::Exit,
+        exit_subsystems(&settings),
+        EXIT_SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+        Some(format!("{:?}", settings.exit_network.enforcement_backend).to_lowercase()),
+    );
+    trace!("Starting with Identity: {:?}", settings.get_identity());
+
+    // Exits require the ability to query the blockchain to setup the user list, they also need to
+    // have a backend database contract to store user data. This function checks that both of those
+    // are correct so that we can fail quickly if they are not. Demo exits keep clients in memory and
+    // need neither.
+    let clients = match settings.exit_network.client_store {
+        ExitClientStore::Contract => check_startup_balance_and_contract(),
+        ExitClientStore::Memory => {
+            warn!("Exit clients are kept in memory, this is NOT supported in production");
+            Vec::new()
+        }
+    };
+
+    // Now that we have migrated to async across the board I'm not actually sure if this is needed
+    // previously with pre-async actix this would initialize the thread that many actix functions required
+    // but now each individual thread spawns it's own system, this may simply be redundant.
+    let system = actix_async::System::new();
+
+    let workers = settings.workers as usize;
+    start_common_subsystems(
+        SettingsOnDisk::RitaExitSettingsStruct(Box::new(settings::get_rita_exit())),
+        workers,
+    );
+    // fix what we can of the OS settings before any client is set up
+    run_preflight_checks();
+    start_rita_exit_loop(clients);
+    start_operator_update_loop();
+    start_rita_exit_endpoints(workers);
+    start_exit_heartbeat_listener();
+    start_rita_exit_dashboard();
+    start_rita_exit_admin_api();
+
+    if let Err(e) = system.run() {
+        error!("Starting Exit failed with {}", e);
+    }
+
+    info!("Started rita Exit");
+}
+
+/// Fetches the cluster config if this exit is in bootstrap mode, an exit that can't get it would hand
+/// clients the wrong keys so we crash instead
+fn bootstrap_cluster_config(settings: RitaExitSettingsStruct) -> RitaExitSettingsStruct {
+    let bootstrap = match settings.exit_network.cluster_bootstrap {
+        Some(bootstrap) => bootstrap,
+        None => return settings,
+    };
+    let runner = System::new();
+    if let Err(e) = runner.block_on(bootstrap_from_cluster(bootstrap)) {
+        println!("Failed to bootstrap from the exit cluster {e}");
+        std::process::exit(1);
+    }
+    settings::get_rita_exit()
+}
+
+/// This functions checks the Exits balance before starting, this is required since the exit must
+/// be able to query the blockchain to setup the user list.
+fn check_startup_balance_and_contract() -> Vec<Identity> {
+    let runner = System::new();
+    runner.block_on(async move {
+        let payment_settings = settings::get_rita_common().payment;
+        let our_address = payment_settings.eth_address.expect("No address!");
+        let full_node = get_web3_server();
+        let web3 = web30::client::Web3::new(&full_node, Duration::from_secs(5));
+        let mut res = web3.eth_get_balance(our_address).await;
+        let start = Instant::now();
+        while res.is_err() {
+            error!("Failed to get balance, trying again, we must check this before starting");
+            res = web3.eth_get_balance(our_address).await;
+
+            if Instant::now() - start > STARTUP_RETRY_TIME {
+                println!("Could not successfully query the ETH node {}", full_node);
+                std::process::exit(1);
+            }
+        }
+        let balance = res.unwrap();
+        if balance == 0u8.into() {
+            println!(
+                "Rita Exit requires a balance to start, please fund your address {} and restart",
+                our_address
+            );
+            std::process::exit(1);
+        }
+
+        let contract_address = settings::get_rita_exit()
+            .exit_network
+            .registered_users_contract_addr;
+        let mut users = get_all_regsitered_clients(&web3, our_address, contract_address).await;
+        let start = Instant::now();
+        while users.is_err() {
+            error!("Failed to get users, we must get these before starting!");
+            users = get_all_regsitered_clients(&web3, our_address, contract_address).await;
+
+            if Instant::now() - start > STARTUP_RETRY_TIME {
+                println!(
+                    "Could not successfully query contract {} check you are on the right chain!",
+                    contract_address
+                );
+                std::process::exit(1);
+            }
+        }
+        users.unwrap()
+    })
+}
@@ -12,6 +12,7 @@ extern crate log;
 
 use rita_client::extender::get_device_mac;
 use rita_client::extender::ExtenderUpdate;
+use rita_common::logging::enable_local_logging;
 use rita_common::logging::enable_remote_logging;
 use rita_common::utils::env_vars_contains;
 use rita_extender::dashboard::start_extender_dashboard;
@@ -53,7 +54,9 @@ fn main() {
     // local logger and log to std-out. Note we don't care what is actually set in NO_REMOTE_LOG
     // just that it is set
     if !should_remote_log || env_vars_contains("NO_REMOTE_LOG") {
-        env_logger::init();
+        if let Err(e) = enable_local_logging() {
+            println!("Failed to enable local logging {e:?}");
+        }
     } else {
         let res = enable_remote_logging("rita_extender".to_string(), logging_url, level, wgkey);
 
@@ -0,0 +1,145 @@
+//! Startup code shared by the rita binaries. The client and exit each still ship as a binary of their
+//! own, rita_multi can start either one from the same firmware image with the role picked at startup
+//! from the command line or the config file, see settings::role. The pieces every role needs, the
+//! shutdown handler, logging and the common loops and endpoints, are set up here so that there is one
+//! path through them whichever role is started.
+
+#![warn(clippy::all)]
+#![allow(clippy::pedantic)]
+#![forbid(unsafe_code)]
+
+#[macro_use]
+extern crate log;
+
+pub mod client_role;
+pub mod exit_role;
+pub mod offline;
+
+use althea_types::{Capabilities, WgKey};
+use rita_common::capabilities::{firewall_backend, set_capabilities};
+use rita_common::debt_keeper::save_debt_on_shutdown;
+use rita_common::logging::enable_local_logging;
+use rita_common::logging::enable_remote_logging;
+use rita_common::notifications::flush_notifications;
+use rita_common::reconciliation::receipts::flush_payment_receipts;
+use rita_common::rita_loop::start_core_rita_endpoints;
+use rita_common::rita_loop::start_rita_common_loops;
+use rita_common::rita_loop::write_to_disk::save_to_disk_loop;
+use rita_common::rita_loop::write_to_disk::SettingsOnDisk;
+use rita_common::usage_tracker::save_usage_on_shutdown;
+use rita_common::utils::env_vars_contains;
+use rita_exit::promotions::save_promotions;
+use settings::role::RitaRole;
+use settings::save_settings_on_shutdown;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Saves debts, usage, payment receipts, notifications, settings and on exits the applied promotions on SIGTERM
+pub fn set_shutdown_handler() {
+    ctrlc::set_handler(move || {
+        info!("received Ctrl+C!");
+        save_debt_on_shutdown();
+        save_usage_on_shutdown();
+        flush_payment_receipts(true);
+        flush_notifications(true);
+        save_settings_on_shutdown();
+        if settings::check_if_exit() {
+            save_promotions();
+        }
+
+        std::process::exit(0);
+    })
+    .expect("Error setting Ctrl-C handler");
+}
+
+/// Logs to the given url if remote logging is wanted, otherwise to stdout. Setting the NO_REMOTE_LOG
+/// env var to anything forces local logging
+pub fn start_logging(
+    should_remote_log: bool,
+    name: &str,
+    dest_url: String,
+    level: String,
+    key: Option<WgKey>,
+) {
+    if !should_remote_log || env_vars_contains("NO_REMOTE_LOG") {
+        if let Err(e) = enable_local_logging() {
+            println!("Failed to enable local logging {e:?}");
+        }
+    } else {
+        let key = key.expect("Tried to init remote logging without WgKey!");
+        let res = enable_remote_logging(name.to_string(), dest_url, level, key.to_string());
+        println!("logging status {res:?}");
+    }
+}
+
+/// Cargo features this binary was built with
+fn build_features() -> Vec<String> {
+    let features = [
+        ("server", cfg!(feature = "server")),
+        ("jemalloc", cfg!(feature = "jemalloc")),
+        ("dash_debug", cfg!(feature = "dash_debug")),
+        ("operator_debug", cfg!(feature = "operator_debug")),
+        ("dev_env", cfg!(feature = "dev_env")),
+        ("development", cfg!(feature = "development")),
+        (
+            "legacy_integration_test",
+            cfg!(feature = "legacy_integration_test"),
+        ),
+        ("optools_dev_env", cfg!(feature = "optools_dev_env")),
+    ];
+    features
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Printed by every role once logging is up, fills in the capability registry from what the role
+/// passes in and logs it as the startup banner
+pub fn print_startup_info(
+    role: RitaRole,
+    subsystems: BTreeMap<String, bool>,
+    exit_protocols: Vec<u32>,
+    enforcement_backend: Option<String>,
+) {
+    if cfg!(feature = "development") {
+        println!("Warning!");
+        println!("This build is meant only for development purposes.");
+        println!("Running this on production is unsupported and not safe!");
+    }
+
+    let payment = settings::get_rita_common().payment;
+    let mut chains = vec![payment.system_chain];
+    if payment.withdraw_chain != payment.system_chain {
+        chains.push(payment.withdraw_chain);
+    }
+    set_capabilities(Capabilities {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_hash: env!("GIT_HASH").to_string(),
+        role: role.to_string(),
+        build_features: build_features(),
+        subsystems,
+        exit_protocols,
+        chains,
+        enforcement_backend,
+        firewall: firewall_backend(),
+    });
+}
+
+/// Starts the loops and endpoints every role runs, must be called from within the actix system the
+/// role runs in
+pub fn start_common_subsystems(settings: SettingsOnDisk, workers: usize) {
+    start_rita_common_loops();
+    save_to_disk_loop(settings);
+    start_core_rita_endpoints(workers);
+}
+
+/// Starts rita in the given role, does not return until the actix system stops
+pub fn start_role(role: RitaRole, settings_file: PathBuf) {
+    println!("Starting as {role}");
+    match role {
+        RitaRole::Client => client_role::start_client(settings_file, false),
+        RitaRole::Gateway => client_role::start_client(settings_file, true),
+        RitaRole::Exit => exit_role::start_exit(settings_file),
+    }
+}
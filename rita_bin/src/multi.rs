@@ -0,0 +1,63 @@
+//! A single rita binary for firmware images that want one build for every role. The role, client,
+//! gateway or exit, is taken from --role, then from the `role` key of the config file and otherwise
+//! from whether the config file has an exit_network section. Startup from there is exactly what the
+//! rita or rita_exit binary would do.
+
+#![warn(clippy::all)]
+#![allow(clippy::pedantic)]
+#![forbid(unsafe_code)]
+
+#[cfg(feature = "jemalloc")]
+use jemallocator::Jemalloc;
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: Jemalloc = Jemalloc;
+
+use docopt::Docopt;
+use rita_bin::{set_shutdown_handler, start_role};
+use rita_common::dashboard::own_info::READABLE_VERSION;
+use settings::client::default_config_path;
+use settings::role::{detect_role, RitaRole};
+use std::path::PathBuf;
+
+fn get_multi_usage(version: &str, git_hash: &str) -> String {
+    format!(
+        "Usage: rita_multi [--config=<settings>] [--role=<role>]
+Options:
+    -c, --config=<settings>   Name of config file
+    -r, --role=<role>         client, gateway or exit, read from the config file when not given
+About:
+    Version {READABLE_VERSION} - {version}
+    git hash {git_hash}"
+    )
+}
+
+fn main() {
+    //Setup a SIGTERM hadler
+    set_shutdown_handler();
+
+    let args = Docopt::new(get_multi_usage(env!("CARGO_PKG_VERSION"), env!("GIT_HASH")))
+        .and_then(|d| d.parse())
+        .unwrap_or_else(|e| e.exit());
+
+    let settings_file = match args.get_str("--config") {
+        "" => default_config_path(),
+        path => PathBuf::from(path),
+    };
+    let role = match args.get_str("--role") {
+        "" => detect_role(&settings_file).map_err(|e| {
+            format!(
+                "Could not read the role from {} {e:?}",
+                settings_file.display()
+            )
+        }),
+        role => role.parse::<RitaRole>(),
+    };
+    match role {
+        Ok(role) => start_role(role, settings_file),
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Offline administration for rita_ctl, maintenance that has to happen while the daemon is down. Every
+//! command works on the config file and the state rita keeps on disk, parsing them with the same types
+//! and checks the daemon uses so that anything rita_ctl accepts the daemon will start with.
+
+use crate::exit_role::check_exit_config;
+use actix_rt::System;
+use althea_types::unix_time::now_secs;
+use althea_types::{SignedVoucher, Voucher, WgKey};
+use clarity::PrivateKey;
+use rita_client_registration::client_db::get_all_regsitered_clients;
+use rita_common::rita_loop::get_web3_server;
+use rita_common::usage_tracker::load_usage_tracker_from_disk;
+use rita_common::KI;
+use rita_exit::backup::{download_backup, list_backups, restore_backup_files};
+use rita_exit::client_overrides::ClientOverride;
+use rita_exit::consistency::find_conflicts;
+use rita_exit::denylist::DenylistEntry;
+use rita_exit::isolation::IsolatedClient;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use settings::client::RitaClientSettings;
+use settings::exit::{ExitBackupSettings, ExitClientStore, RitaExitSettingsStruct};
+use settings::role::{detect_role, RitaRole};
+use settings::FileWrite;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A config file as the daemon would parse it
+#[derive(Debug, Clone)]
+pub enum OfflineConfig {
+    Client(Box<RitaClientSettings>),
+    Exit(Box<RitaExitSettingsStruct>),
+}
+
+impl OfflineConfig {
+    pub fn load(path: &Path) -> Result<OfflineConfig, String> {
+        if !path.exists() {
+            return Err(format!("No config file at {}", path.display()));
+        }
+        let role = detect_role(path).map_err(|e| format!("Failed to read config {e:?}"))?;
+        let file_name = path.to_string_lossy();
+        match role {
+            RitaRole::Client | RitaRole::Gateway => RitaClientSettings::new(&file_name)
+                .map(|s| OfflineConfig::Client(Box::new(s)))
+                .map_err(|e| format!("Invalid client config {e:?}")),
+            RitaRole::Exit => RitaExitSettingsStruct::new(&file_name)
+                .map(|s| OfflineConfig::Exit(Box::new(s)))
+                .map_err(|e| format!("Invalid exit config {e:?}")),
+        }
+    }
+
+    pub fn write(&self, path: PathBuf) -> Result<(), String> {
+        let res = match self {
+            OfflineConfig::Client(s) => s.write(path),
+            OfflineConfig::Exit(s) => s.write(path),
+        };
+        res.map_err(|e| format!("Failed to write config {e:?}"))
+    }
+
+    pub fn to_json(&self) -> Result<Value, String> {
+        let res = match self {
+            OfflineConfig::Client(s) => serde_json::to_value(s),
+            OfflineConfig::Exit(s) => serde_json::to_value(s),
+        };
+        res.map_err(|e| format!("Failed to serialize config {e:?}"))
+    }
+
+    /// Merges a json object into the config the way the dashboard settings endpoint does
+    pub fn merge(&mut self, changes: Value) -> Result<(), String> {
+        let res = match self {
+            OfflineConfig::Client(s) => s.merge(changes),
+            OfflineConfig::Exit(s) => s.merge(changes),
+        };
+        res.map_err(|e| format!("Invalid setting {e:?}"))
+    }
+
+    /// Problems the daemon would refuse to start with or would silently fix on startup
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let network = match self {
+            OfflineConfig::Client(s) => &s.network,
+            OfflineConfig::Exit(s) => {
+                if let Err(e) = check_exit_config(s) {
+                    problems.push(e);
+                }
+                &s.network
+            }
+        };
+        if let Some(mesh_ip) = network.mesh_ip {
+            if !clu::validate_mesh_ip(&mesh_ip) {
+                problems.push(format!(
+                    "mesh_ip {mesh_ip} is invalid, a new one will be generated"
+                ));
+            }
+        }
+        if network.wg_public_key.is_none() != network.wg_private_key.is_none() {
+            problems.push(
+                "Only one of wg_public_key and wg_private_key is set, a new keypair will be generated"
+                    .to_string(),
+            );
+        }
+        problems
+    }
+
+    /// Makes this config the one settings::get_rita_common() returns, for the commands that reuse
+    /// daemon code that reads settings
+    fn make_current(&self) {
+        match self {
+            OfflineConfig::Client(s) => settings::set_rita_client(*s.clone()),
+            OfflineConfig::Exit(s) => settings::set_rita_exit(*s.clone()),
+        }
+    }
+}
+
+/// Turns a dotted path like network.babel_port and a value into the json object merged into the config
+pub fn setting_change(path: &str, value: Value) -> Value {
+    path.rsplit('.').fold(value, |inner, key| {
+        let mut object = serde_json::Map::new();
+        object.insert(key.to_string(), inner);
+        Value::Object(object)
+    })
+}
+
+/// Values that aren't valid json are taken as strings, so that keys and urls don't need quoting
+pub fn parse_setting_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+/// Prints a setting, or the whole config if path is None
+pub fn settings_get(config: &OfflineConfig, path: Option<&str>) -> Result<String, String> {
+    let json = config.to_json()?;
+    let value = match path {
+        Some(path) => {
+            let pointer = format!("/{}", path.replace('.', "/"));
+            json.pointer(&pointer)
+                .cloned()
+                .ok_or_else(|| format!("No setting {path}"))?
+        }
+        None => json,
+    };
+    serde_json::to_string_pretty(&value).map_err(|e| format!("{e:?}"))
+}
+
+/// Changes a setting and writes the config back, only if the result still parses and validates
+pub fn settings_set(
+    config: &mut OfflineConfig,
+    config_file: PathBuf,
+    path: &str,
+    value: &str,
+) -> Result<(), String> {
+    config.merge(setting_change(path, parse_setting_value(value)))?;
+    let problems = config.problems();
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+    config.write(config_file)
+}
+
+/// Replaces the mesh wireguard keypair with a freshly generated one, the private key file is
+/// written from the config when the daemon starts
+pub fn rotate_key(config: &mut OfflineConfig, config_file: PathBuf) -> Result<String, String> {
+    let keypair = KI
+        .create_wg_keypair()
+        .map_err(|e| format!("Failed to generate keys {e:?}"))?;
+    let network = match config {
+        OfflineConfig::Client(s) => &mut s.network,
+        OfflineConfig::Exit(s) => {
+            println!("Warning: the exit's mesh key is part of its identity, clients and other exits have to be told about the new key");
+            &mut s.network
+        }
+    };
+    network.wg_public_key = Some(keypair.public);
+    network.wg_private_key = Some(keypair.private);
+    config.write(config_file)?;
+    Ok(keypair.public.to_string())
+}
+
+/// Signs a prepaid voucher worth amount wei of the debt keeper denom, redeemable for the next days days,
+/// with the operator eth private key in key_file. Exits only accept it if their
+/// exit_network.voucher_signer is the address of that key
+pub fn sign_voucher(key_file: &str, amount: &str, days: &str) -> Result<SignedVoucher, String> {
+    let key: PrivateKey = fs::read_to_string(key_file)
+        .map_err(|e| format!("Failed to read {key_file} {e}"))?
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid private key in {key_file} {e:?}"))?;
+    let amount: u128 = amount
+        .parse()
+        .map_err(|e| format!("Invalid amount {amount} {e}"))?;
+    let days: u64 = days
+        .parse()
+        .map_err(|e| format!("Invalid number of days {days} {e}"))?;
+    let expiry = now_secs().saturating_add(days.saturating_mul(86_400));
+    Ok(Voucher::new(amount.into(), expiry).sign(key))
+}
+
+/// The usage history and payments in usage_tracker_file as json
+pub fn usage_dump(config: &OfflineConfig) -> Result<String, String> {
+    config.make_current();
+    let usage = load_usage_tracker_from_disk();
+    serde_json::to_string_pretty(&usage).map_err(|e| format!("{e:?}"))
+}
+
+fn check_json_file<T: DeserializeOwned>(path: &str) -> Result<Option<T>, String> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| format!("{path} is corrupt {e}")),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Checks the exit's on disk lists and the registered clients in the registration contract,
+/// returns a line per finding
+pub fn db_check(config: &OfflineConfig) -> Result<Vec<String>, String> {
+    let exit = match config {
+        OfflineConfig::Exit(s) => s,
+        OfflineConfig::Client(_) => return Err("db check only applies to exits".to_string()),
+    };
+    let exit_network = &exit.exit_network;
+    let mut findings = Vec::new();
+
+    match check_json_file::<Vec<DenylistEntry>>(&exit_network.client_denylist_file) {
+        Ok(Some(list)) => findings.push(format!("Denylist has {} entries", list.len())),
+        Ok(None) => findings.push("No denylist file".to_string()),
+        Err(e) => findings.push(e),
+    }
+    match check_json_file::<HashSet<u64>>(&exit_network.redeemed_vouchers_file) {
+        Ok(Some(redeemed)) => findings.push(format!("{} vouchers redeemed", redeemed.len())),
+        Ok(None) => findings.push("No redeemed vouchers file".to_string()),
+        Err(e) => findings.push(e),
+    }
+    match check_json_file::<Vec<IsolatedClient>>(&exit_network.client_isolation_file) {
+        Ok(Some(isolated)) => {
+            findings.push(format!("{} clients isolated", isolated.len()));
+            findings.extend(isolation_problems(&isolated));
+        }
+        Ok(None) => findings.push("No client isolation file".to_string()),
+        Err(e) => findings.push(e),
+    }
+    match check_json_file::<Vec<ClientOverride>>(&exit_network.client_overrides_file) {
+        Ok(Some(overrides)) => findings.push(format!("{} client overrides", overrides.len())),
+        Ok(None) => findings.push("No client overrides file".to_string()),
+        Err(e) => findings.push(e),
+    }
+    match check_json_file::<HashMap<WgKey, u32>>(&exit_network.protocol_versions_file) {
+        Ok(Some(versions)) => findings.push(format!(
+            "{} clients negotiated a protocol version",
+            versions.len()
+        )),
+        Ok(None) => findings.push("No protocol versions file".to_string()),
+        Err(e) => findings.push(e),
+    }
+
+    if exit_network.client_store == ExitClientStore::Memory {
+        findings.push("Clients are kept in memory, there are none to check".to_string());
+        return Ok(findings);
+    }
+    config.make_current();
+    let our_address = exit
+        .payment
+        .eth_address
+        .ok_or_else(|| "No eth address configured".to_string())?;
+    let contract = exit_network.registered_users_contract_addr;
+    let clients = System::new().block_on(async move {
+        let web3 = web30::client::Web3::new(&get_web3_server(), Duration::from_secs(5));
+        get_all_regsitered_clients(&web3, our_address, contract).await
+    });
+    match clients {
+        Ok(clients) => {
+            findings.push(format!("{} clients registered", clients.len()));
+            let (conflicts, quarantined) = find_conflicts(&clients);
+            for conflict in conflicts {
+                findings.push(format!("Conflicting registrations {conflict:?}"));
+            }
+            if !quarantined.is_empty() {
+                findings.push(format!(
+                    "{} registrations would be quarantined",
+                    quarantined.len()
+                ));
+            }
+        }
+        Err(e) => findings.push(format!(
+            "Failed to get registered clients from {contract} {e:?}"
+        )),
+    }
+    Ok(findings)
+}
+
+fn backup_settings(config: &OfflineConfig) -> Result<ExitBackupSettings, String> {
+    match config {
+        OfflineConfig::Exit(s) => s
+            .exit_network
+            .backup
+            .clone()
+            .ok_or_else(|| "Backups are not configured".to_string()),
+        OfflineConfig::Client(_) => Err("Backups only apply to exits".to_string()),
+    }
+}
+
+/// The backups in the bucket, a line each
+pub fn backup_list(config: &OfflineConfig) -> Result<Vec<String>, String> {
+    let settings = backup_settings(config)?;
+    let backups = System::new()
+        .block_on(async move { list_backups(&settings).await })
+        .map_err(|e| e.to_string())?;
+    Ok(backups
+        .into_iter()
+        .map(|b| format!("{} created {} {} bytes", b.object, b.created, b.size))
+        .collect())
+}
+
+/// Downloads a backup, the newest if object is None, and writes the state files in it back. Returns a
+/// line per restored file
+pub fn backup_restore(config: &OfflineConfig, object: Option<&str>) -> Result<Vec<String>, String> {
+    let settings = backup_settings(config)?;
+    let object = object.map(|o| o.to_string());
+    let (object, backup) = System::new()
+        .block_on(async move { download_backup(&settings, object).await })
+        .map_err(|e| e.to_string())?;
+    let mut lines = vec![format!(
+        "Backup {} created {} with {} clients",
+        object,
+        backup.created,
+        backup.clients.len()
+    )];
+    for path in restore_backup_files(&backup).map_err(|e| e.to_string())? {
+        lines.push(format!("Restored {path}"));
+    }
+    Ok(lines)
+}
+
+/// Isolated clients sharing a routing table or public address would break each other's isolation
+fn isolation_problems(isolated: &[IsolatedClient]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut tables = HashMap::new();
+    let mut addresses = HashMap::new();
+    for client in isolated {
+        if let Some(other) = tables.insert(client.table, client.wg_key) {
+            problems.push(format!(
+                "{} and {} share isolation table {}",
+                other, client.wg_key, client.table
+            ));
+        }
+        if let Some(public_ipv4) = client.public_ipv4 {
+            if let Some(other) = addresses.insert(public_ipv4, client.wg_key) {
+                problems.push(format!(
+                    "{} and {} share public address {}",
+                    other, client.wg_key, public_ipv4
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setting_change() {
+        assert_eq!(
+            setting_change("network.babel_port", parse_setting_value("6872")),
+            serde_json::json!({"network": {"babel_port": 6872}})
+        );
+        assert_eq!(
+            setting_change("log.dest_url", parse_setting_value("https://logs.example")),
+            serde_json::json!({"log": {"dest_url": "https://logs.example"}})
+        );
+        assert_eq!(parse_setting_value("true"), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_isolation_problems() {
+        let client = |key: u8, table, public_ipv4: Option<&str>| IsolatedClient {
+            wg_key: [key; 32].into(),
+            table,
+            public_ipv4: public_ipv4.map(|ip| ip.parse().unwrap()),
+            added: 0,
+        };
+        assert!(
+            isolation_problems(&[client(1, 1000, Some("203.0.113.1")), client(2, 1001, None)])
+                .is_empty()
+        );
+        assert_eq!(
+            isolation_problems(&[
+                client(1, 1000, Some("203.0.113.1")),
+                client(2, 1000, Some("203.0.113.1"))
+            ])
+            .len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,350 @@
+//! Client side enforcement of operator bandwidth contracts, see althea_types::bandwidth_contract. A contract
+//! the operator sends in a checkin is checked and kept in operator.bandwidth_contract so it outlasts a
+//! restart. Every client loop tick the limits for where the contract is at are worked out, download is
+//! shaped on br-lan together with the user's own bandwidth limit and upload on wg_exit, and tc is only run
+//! when those limits change. While an exit split is active the upload limit is shared between wg_exit and
+//! wg_exit_split by the split weight so that the two together stay within it. Usage against the data cap comes from the usage tracker's client history, so
+//! it is counted by the hour.
+
+use crate::exit_manager::exit_split::get_exit_split_status;
+use crate::RitaClientError;
+use althea_kernel_interface::exit_split::SPLIT_EXIT_INTERFACE;
+use althea_types::unix_time::now_secs;
+use althea_types::{
+    BandwidthContract, BandwidthContractReport, BandwidthContractState, SignedBandwidthContract,
+    WgKey,
+};
+use clarity::Address;
+use rita_common::usage_tracker::get_current_throughput;
+use rita_common::usage_tracker::get_usage_data_map;
+use rita_common::usage_tracker::structs::UsageType;
+use rita_common::KI;
+use settings::client::RitaClientSettings;
+use std::sync::{Arc, RwLock};
+
+/// Measured throughput may be this much over the contract before the router counts as not compliant,
+/// the shaper is not exact and the throughput sample includes protocol overhead
+const COMPLIANCE_TOLERANCE: f64 = 1.1;
+
+lazy_static! {
+    /// The limits last applied, so that tc is only run when they change
+    static ref APPLIED: Arc<RwLock<Option<AppliedLimits>>> = Arc::new(RwLock::new(None));
+    /// Why the last contract the operator sent was refused
+    static ref REFUSED: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AppliedLimits {
+    download: Option<usize>,
+    upload: Option<usize>,
+    /// Percent of new flows going to the secondary exit, 0 without an active split
+    split_weight: u8,
+    /// False if tc failed, the limits are tried again next tick
+    ok: bool,
+}
+
+/// The lower of two limits where None is unlimited
+fn min_limit(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (Some(a), None) => Some(a),
+        (None, b) => b,
+    }
+}
+
+fn validate_contract(
+    signed: &SignedBandwidthContract,
+    operator: Option<Address>,
+    our_key: Option<WgKey>,
+    now: u64,
+) -> Result<(), RitaClientError> {
+    let invalid = |message: String| Err(RitaClientError::MiscStringError(message));
+    let contract = signed.contract;
+    match (signed.signer(), operator) {
+        (Ok(signer), Some(operator)) if signer == operator => {}
+        (Ok(_), _) => {
+            return invalid(format!(
+                "Bandwidth contract {} was not signed by this router's operator",
+                contract.id
+            ))
+        }
+        (Err(e), _) => return invalid(e.to_string()),
+    }
+    if Some(contract.router) != our_key {
+        return invalid(format!(
+            "Bandwidth contract {} is not for this router",
+            contract.id
+        ));
+    }
+    if contract.expiry <= now || contract.expiry <= contract.start {
+        return invalid(format!("Bandwidth contract {} has expired", contract.id));
+    }
+    if contract.download_mbps == Some(0)
+        || contract.upload_mbps == Some(0)
+        || contract.over_cap_mbps == 0
+    {
+        return invalid(format!(
+            "Bandwidth contract {} would cut the router off entirely",
+            contract.id
+        ));
+    }
+    Ok(())
+}
+
+/// Called with the contract from an operator checkin, a contract with the id of the current one is
+/// ignored, anything else replaces it if it is valid
+pub fn receive_bandwidth_contract(
+    signed: SignedBandwidthContract,
+    rita_client: &mut RitaClientSettings,
+) {
+    let current = rita_client
+        .operator
+        .bandwidth_contract
+        .as_ref()
+        .map(|c| c.contract.id);
+    if current == Some(signed.contract.id) {
+        return;
+    }
+    match validate_contract(
+        &signed,
+        rita_client.operator.operator_address,
+        rita_client.network.wg_public_key,
+        now_secs(),
+    ) {
+        Ok(()) => {
+            info!("Received bandwidth contract {:?}", signed.contract);
+            rita_client.operator.bandwidth_contract = Some(signed);
+            *REFUSED.write().unwrap() = None;
+        }
+        Err(e) => {
+            warn!("Refused bandwidth contract {}", e);
+            *REFUSED.write().unwrap() = Some(e.to_string());
+        }
+    }
+}
+
+/// Bytes up and down the router has used during the contract
+fn used_bytes(contract: &BandwidthContract) -> u64 {
+    let start_hour = contract.start / 3600;
+    let end_hour = contract.expiry / 3600;
+    get_usage_data_map(UsageType::Client)
+        .iter()
+        .filter(|(hour, _)| **hour >= start_hour && **hour <= end_hour)
+        .map(|(_, usage)| usage.up + usage.down)
+        .sum()
+}
+
+/// Where the current contract is at, with its usage and the (download, upload) limits it asks for
+fn contract_limits(
+    contract: Option<&BandwidthContract>,
+    now: u64,
+) -> Option<(BandwidthContractState, u64, Option<usize>, Option<usize>)> {
+    contract.map(|contract| {
+        let used = used_bytes(contract);
+        let state = contract.state(now, used);
+        let (download, upload) = contract.limits(state);
+        (state, used, download, upload)
+    })
+}
+
+/// The download limit for br-lan, the lower of the user's own limit and the contract
+pub fn effective_download_limit(user_limit: Option<usize>) -> Option<usize> {
+    let rita_client = settings::get_rita_client_snapshot();
+    let contract = rita_client
+        .operator
+        .bandwidth_contract
+        .as_ref()
+        .map(|c| &c.contract);
+    let download = contract_limits(contract, now_secs()).and_then(|(_, _, download, _)| download);
+    min_limit(user_limit, download)
+}
+
+/// Shares an upload limit between wg_exit and wg_exit_split by the percent of flows sent to the
+/// split, each gets at least 1mbps
+fn split_upload(upload: Option<usize>, split_weight: u8) -> (Option<usize>, Option<usize>) {
+    match upload {
+        Some(upload) if split_weight > 0 => {
+            let weight = usize::from(split_weight.min(100));
+            let split = (upload * weight / 100).max(1);
+            let primary = (upload * (100 - weight) / 100).max(1);
+            (Some(primary), Some(split))
+        }
+        _ => (upload, upload),
+    }
+}
+
+/// Forgets what was applied so that the limits are applied again next tick, for when wg_exit is
+/// set up again and loses its qdisc
+pub fn reset_applied_limits() {
+    *APPLIED.write().unwrap() = None;
+}
+
+/// Run every client loop tick, shapes the router to its bandwidth contract
+pub fn tick_bandwidth_contract() {
+    let rita_client = settings::get_rita_client_snapshot();
+    let contract = rita_client
+        .operator
+        .bandwidth_contract
+        .as_ref()
+        .map(|c| &c.contract);
+    let (download, upload) = match contract_limits(contract, now_secs()) {
+        Some((_, _, download, upload)) => (download, upload),
+        None => (None, None),
+    };
+    let download = min_limit(rita_client.network.user_bandwidth_limit, download);
+    let split = get_exit_split_status();
+    let split_weight = if split.active { split.weight } else { 0 };
+
+    let mut applied = APPLIED.write().unwrap();
+    if let Some(last) = *applied {
+        if last.ok
+            && last.download == download
+            && last.upload == upload
+            && last.split_weight == split_weight
+        {
+            return;
+        }
+    }
+    // nothing has been shaped for a contract yet, leave the qdiscs the rest of rita set up alone
+    if applied.is_none() && contract.is_none() {
+        return;
+    }
+    info!(
+        "Applying bandwidth contract limits download {:?} upload {:?}",
+        download, upload
+    );
+    let mut ok = true;
+    if let Err(e) = KI.set_codel_shaping("br-lan", download) {
+        error!("Failed to shape br-lan for the bandwidth contract {:?}", e);
+        ok = false;
+    }
+    let (primary_upload, secondary_upload) = split_upload(upload, split_weight);
+    if let Err(e) = KI.set_codel_shaping("wg_exit", primary_upload) {
+        warn!("Failed to shape wg_exit for the bandwidth contract {:?}", e);
+        ok = false;
+    }
+    if split_weight > 0 {
+        if let Err(e) = KI.set_codel_shaping(SPLIT_EXIT_INTERFACE, secondary_upload) {
+            warn!(
+                "Failed to shape {} for the bandwidth contract {:?}",
+                SPLIT_EXIT_INTERFACE, e
+            );
+            ok = false;
+        }
+    }
+    *applied = Some(AppliedLimits {
+        download,
+        upload,
+        split_weight,
+        ok,
+    });
+}
+
+/// If the measured throughput in bytes per second fits the limits in mbps, None is unlimited
+fn within_limits(throughput: Option<u64>, download: Option<usize>, upload: Option<usize>) -> bool {
+    match (throughput, download, upload) {
+        (Some(throughput), Some(download), Some(upload)) => {
+            let allowed = (download + upload) as f64 * 125_000.0 * COMPLIANCE_TOLERANCE;
+            throughput as f64 <= allowed
+        }
+        _ => true,
+    }
+}
+
+/// Sent with the operator checkin, None if the operator never sent this router a contract
+pub fn get_bandwidth_contract_report() -> Option<BandwidthContractReport> {
+    let rita_client = settings::get_rita_client_snapshot();
+    let signed = rita_client.operator.bandwidth_contract.as_ref();
+    let error = REFUSED.read().unwrap().clone();
+    if signed.is_none() && error.is_none() {
+        return None;
+    }
+    let limits = contract_limits(signed.map(|c| &c.contract), now_secs());
+    let applied = *APPLIED.read().unwrap();
+    let (applied_download_mbps, applied_upload_mbps, applied_ok) = match applied {
+        Some(applied) => (applied.download, applied.upload, applied.ok),
+        None => (None, None, false),
+    };
+    let compliant = match limits {
+        Some((_, _, download, upload)) => {
+            let download = min_limit(rita_client.network.user_bandwidth_limit, download);
+            applied_ok
+                && applied_download_mbps == download
+                && applied_upload_mbps == upload
+                && within_limits(get_current_throughput(UsageType::Client), download, upload)
+        }
+        None => true,
+    };
+    let error = match (error, applied_ok, applied.is_some()) {
+        (Some(e), _, _) => Some(e),
+        (None, false, true) => Some("Failed to apply the contract limits".to_string()),
+        (None, _, _) => None,
+    };
+    Some(BandwidthContractReport {
+        id: signed.map(|c| c.contract.id),
+        state: limits.map(|(state, _, _, _)| state),
+        used_bytes: limits.map(|(_, used, _, _)| used).unwrap_or(0),
+        applied_download_mbps,
+        applied_upload_mbps,
+        compliant,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::PrivateKey;
+
+    #[test]
+    fn test_validate_contract() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let router: WgKey = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap();
+        let contract = BandwidthContract {
+            id: 1,
+            router,
+            download_mbps: Some(25),
+            upload_mbps: Some(5),
+            data_cap: None,
+            over_cap_mbps: 1,
+            start: 1000,
+            expiry: 2000,
+        };
+        let operator = Some(key.to_address());
+        assert!(validate_contract(&contract.sign(key), operator, Some(router), 1500).is_ok());
+        // a contract can be sent ahead of its start
+        assert!(validate_contract(&contract.sign(key), operator, Some(router), 500).is_ok());
+        assert!(validate_contract(&contract.sign(key), operator, Some(router), 2000).is_err());
+        assert!(validate_contract(&contract.sign(key), None, Some(router), 1500).is_err());
+        let other: WgKey = "V9I9yrxAqFqLV+9GeT5pnXPwk4Cxgfvl30Fv8khVGsM="
+            .parse()
+            .unwrap();
+        assert!(validate_contract(&contract.sign(key), operator, Some(other), 1500).is_err());
+        let cut_off = BandwidthContract {
+            upload_mbps: Some(0),
+            ..contract
+        };
+        assert!(validate_contract(&cut_off.sign(key), operator, Some(router), 1500).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(min_limit(Some(10), Some(20)), Some(10));
+        assert_eq!(min_limit(None, Some(20)), Some(20));
+        assert_eq!(min_limit(None, None), None);
+        // 10mbps is 1.25MB/s
+        assert!(within_limits(Some(1_250_000), Some(8), Some(2)));
+        assert!(!within_limits(Some(2_000_000), Some(8), Some(2)));
+        assert!(within_limits(Some(2_000_000), None, Some(2)));
+        assert!(within_limits(None, Some(8), Some(2)));
+
+        assert_eq!(split_upload(Some(10), 0), (Some(10), Some(10)));
+        assert_eq!(split_upload(Some(10), 30), (Some(7), Some(3)));
+        assert_eq!(split_upload(Some(2), 10), (Some(1), Some(1)));
+        assert_eq!(split_upload(None, 30), (None, None));
+    }
+}
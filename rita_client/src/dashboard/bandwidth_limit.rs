@@ -1,6 +1,7 @@
 //! Beta 16 introduces a feature where users can select their own self imposed router bandwidth limit
 //! these dashboard endpoints facilitate users setting that value.
 
+use crate::bandwidth_contract::{effective_download_limit, get_bandwidth_contract_report};
 use actix_web_async::http::StatusCode;
 use actix_web_async::HttpResponse;
 use actix_web_async::{web::Path, HttpRequest};
@@ -11,6 +12,11 @@ pub async fn get_bandwidth_limit(_req: HttpRequest) -> HttpResponse {
     HttpResponse::Ok().json(val)
 }
 
+/// The operator's bandwidth contract for this router and how it is being applied, if there is one
+pub async fn get_bandwidth_contract() -> HttpResponse {
+    HttpResponse::Ok().json(get_bandwidth_contract_report())
+}
+
 pub async fn set_bandwidth_limit(path: Path<String>) -> HttpResponse {
     let value = path.into_inner();
     debug!("Set bandwidth limit!");
@@ -23,7 +29,11 @@ pub async fn set_bandwidth_limit(path: Path<String>) -> HttpResponse {
     } else {
         return HttpResponse::BadRequest().finish();
     }
-    let _res = KI.set_codel_shaping("br-lan", network.user_bandwidth_limit);
+    // an operator bandwidth contract may limit the router further than the user does
+    let _res = KI.set_codel_shaping(
+        "br-lan",
+        effective_download_limit(network.user_bandwidth_limit),
+    );
     rita_client.network = network;
     settings::set_rita_client(rita_client);
 
@@ -0,0 +1,45 @@
+//! Endpoints for the dns resolver settings, see crate::dns
+
+use crate::dns::{apply_dns_settings, validate_dns_settings};
+use actix_web_async::http::StatusCode;
+use actix_web_async::{web::Json, HttpRequest, HttpResponse};
+use rita_common::{RitaCommonError, KI};
+use settings::dns::DnsSettings;
+
+pub async fn get_dns_settings(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(settings::get_rita_client().dns)
+}
+
+/// Sets and applies the dns settings, on failure the previous settings are restored
+pub async fn set_dns_settings(new_settings: Json<DnsSettings>) -> HttpResponse {
+    let new_settings = new_settings.into_inner();
+    if let Err(e) = validate_dns_settings(&new_settings) {
+        return HttpResponse::BadRequest().json(format!("{e}"));
+    }
+
+    let mut rita_client = settings::get_rita_client();
+    let old_settings = rita_client.dns.clone();
+    rita_client.dns = new_settings;
+    settings::set_rita_client(rita_client);
+
+    if KI.is_openwrt() {
+        if let Err(e) = apply_dns_settings() {
+            error!("Failed to apply dns settings {:?}", e);
+            let mut rita_client = settings::get_rita_client();
+            rita_client.dns = old_settings;
+            settings::set_rita_client(rita_client);
+            for config in ["dhcp", "stubby", "https-dns-proxy"] {
+                if let Err(e) = KI.uci_revert(config) {
+                    trace!("Failed to revert {} {:?}", config, e);
+                }
+            }
+            return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}"));
+        }
+    }
+
+    if let Err(e) = settings::write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+    HttpResponse::Ok().json(())
+}
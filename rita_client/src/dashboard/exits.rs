@@ -1,31 +1,42 @@
 //! The Exit info endpoint gathers infromation about exit status and presents it to the dashbaord.
 
-use crate::exit_manager::{exit_setup_request, set_selected_exit};
+use crate::exit_manager::exit_split::{
+    get_exit_split_status, traffic_share, validate_split, ExitSplitStatus,
+};
+use crate::exit_manager::{exit_setup_request, get_current_exit, set_selected_exit};
 use crate::heartbeat::get_selected_exit_server;
 use crate::RitaClientError;
 use actix_web_async::http::StatusCode;
 use actix_web_async::{web::Json, web::Path, HttpRequest, HttpResponse};
+use althea_kernel_interface::KernelInterfaceError;
 use althea_types::ExitState;
-use babel_monitor::open_babel_stream;
 use babel_monitor::parse_routes;
 use babel_monitor::parsing::do_we_have_route;
+use babel_monitor::pool::get_babel_stream;
+use babel_monitor::structs::Route;
 
 use rita_common::RitaCommonError;
 use rita_common::KI;
-use settings::client::{ExitServer, SelectedExit};
+use settings::client::{ExitServer, ExitSplitSettings, SelectedExit};
 use settings::write_config;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::thread;
 use std::time::Duration;
 
 #[derive(Serialize)]
 pub struct ExitInfo {
     nickname: String,
     exit_settings: ExitServer,
-    is_selected: bool,
+    pub is_selected: bool,
     have_route: bool,
     is_reachable: bool,
     is_tunnel_working: bool,
+    /// The mtu and keepalive wg_exit uses with this exit, after per exit overrides
+    tunnel_mtu: usize,
+    persistent_keepalive: u16,
+    /// Percent of new flows this exit gets, None if it gets none, see /exits/split
+    traffic_share: Option<u8>,
 }
 
 pub struct GetExitInfo;
@@ -59,55 +70,69 @@ fn is_tunnel_working(exit: &ExitServer, current_exit: Option<ExitServer>) -> boo
 }
 
 pub fn dashboard_get_exit_info() -> Result<Vec<ExitInfo>, RitaClientError> {
-    let babel_port = settings::get_rita_client().network.babel_port;
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
-        Ok(mut stream) => {
-            match parse_routes(&mut stream) {
-                Ok(routes) => {
-                    let route_table_sample = routes;
-                    let mut output = Vec::new();
-                    let rita_client = settings::get_rita_client();
-                    let exit_client = rita_client.exit_client;
-                    let current_exit = get_selected_exit_server();
-
-                    for exit in exit_client.exits.clone().into_iter() {
-                        let selected = is_selected(&exit.1, current_exit.clone());
-                        info!("Trying to get exit: {}", exit.0.clone());
-                        let route_ip = exit.0;
-                        let have_route = do_we_have_route(&route_ip, &route_table_sample)?;
-
-                        // failed pings block for one second, so we should be sure it's at least reasonable
-                        // to expect the pings to work before issuing them.
-                        let reachable = if have_route {
-                            KI.ping_check(&route_ip, EXIT_PING_TIMEOUT, None)?
-                        } else {
-                            false
-                        };
-                        let tunnel_working = match (have_route, selected) {
-                            (true, true) => is_tunnel_working(&exit.1, current_exit.clone()),
-                            _ => false,
-                        };
-
-                        output.push(ExitInfo {
-                            nickname: exit.0.to_string(),
-                            exit_settings: exit.1.clone(),
-                            is_selected: selected,
-                            have_route,
-                            is_reachable: reachable,
-                            is_tunnel_working: tunnel_working,
-                        })
-                    }
-
-                    Ok(output)
-                }
-
-                Err(e) => Err(RitaClientError::MiscStringError(format!("{e}"))),
-            }
-        }
+    let babel_port = settings::get_rita_client_snapshot().network.babel_port;
+    match get_babel_stream(babel_port, Duration::from_secs(5)) {
+        Ok(mut stream) => match parse_routes(&mut stream) {
+            Ok(routes) => exit_info_from_routes(&routes),
+            Err(e) => Err(RitaClientError::MiscStringError(format!("{e}"))),
+        },
         Err(e) => Err(RitaClientError::MiscStringError(format!("{e}"))),
     }
 }
 
+/// Builds the exit status list from an already parsed route table. The pings to each exit run in
+/// parallel, on slow routers pinging a long exit list one at a time takes seconds
+pub fn exit_info_from_routes(
+    route_table_sample: &[Route],
+) -> Result<Vec<ExitInfo>, RitaClientError> {
+    let rita_client = settings::get_rita_client_snapshot();
+    let exit_client = &rita_client.exit_client;
+    let current_exit = get_selected_exit_server();
+
+    let mut pings = Vec::new();
+    for (route_ip, exit) in exit_client.exits.clone().into_iter() {
+        let selected = is_selected(&exit, current_exit.clone());
+        info!("Trying to get exit: {}", route_ip);
+        let have_route = do_we_have_route(&route_ip, route_table_sample)?;
+        let current_exit = current_exit.clone();
+        let ping = thread::spawn(move || -> Result<(bool, bool), KernelInterfaceError> {
+            // failed pings block for one second, so we should be sure it's at least reasonable
+            // to expect the pings to work before issuing them.
+            let reachable = if have_route {
+                KI.ping_check(&route_ip, EXIT_PING_TIMEOUT, None)?
+            } else {
+                false
+            };
+            let tunnel_working = match (have_route, selected) {
+                (true, true) => is_tunnel_working(&exit, current_exit),
+                _ => false,
+            };
+            Ok((reachable, tunnel_working))
+        });
+        pings.push((route_ip, selected, have_route, ping));
+    }
+
+    let mut output = Vec::new();
+    for (route_ip, selected, have_route, ping) in pings {
+        let (reachable, tunnel_working) = ping.join().map_err(|_| {
+            RitaClientError::MiscStringError(format!("Pinging exit {route_ip} panicked"))
+        })??;
+        let exit = &exit_client.exits[&route_ip];
+        output.push(ExitInfo {
+            nickname: route_ip.to_string(),
+            exit_settings: exit.clone(),
+            is_selected: selected,
+            have_route,
+            is_reachable: reachable,
+            is_tunnel_working: tunnel_working,
+            tunnel_mtu: exit_client.tunnel_mtu_for(exit),
+            persistent_keepalive: exit_client.persistent_keepalive_for(exit),
+            traffic_share: traffic_share(route_ip, selected),
+        })
+    }
+    Ok(output)
+}
+
 pub async fn add_exits(new_exits: Json<HashMap<IpAddr, ExitServer>>) -> HttpResponse {
     debug!("/exits POST hit with {:?}", new_exits);
     let mut rita_client = settings::get_rita_client();
@@ -235,3 +260,51 @@ pub async fn verify_on_exit_with_code(path: Path<(String, String)>) -> HttpRespo
     }
     HttpResponse::Ok().json(ret)
 }
+
+pub async fn get_exit_split(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_exit_split_status())
+}
+
+/// Sends `weight` percent of new flows to the secondary exit, the exit manager sets the split up on
+/// its next tick
+pub async fn set_exit_split(path: Path<(IpAddr, u8)>) -> HttpResponse {
+    let (secondary, weight) = path.into_inner();
+    debug!("/exits/split/{}/{} hit", secondary, weight);
+    let split = ExitSplitSettings { secondary, weight };
+
+    let mut rita_client = settings::get_rita_client();
+    if let Err(e) = validate_split(
+        &split,
+        get_current_exit(),
+        &rita_client.exit_client.exits,
+        rita_client.exit_client.kill_switch,
+    ) {
+        return HttpResponse::build(StatusCode::BAD_REQUEST).json(format!("{e}"));
+    }
+    rita_client.exit_client.exit_split = Some(split);
+    settings::set_rita_client(rita_client);
+
+    if let Err(e) = write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+    HttpResponse::Ok().json(ExitSplitStatus {
+        secondary: Some(secondary),
+        weight,
+        ..get_exit_split_status()
+    })
+}
+
+/// Sends all traffic to the selected exit again
+pub async fn disable_exit_split(_req: HttpRequest) -> HttpResponse {
+    debug!("/exits/split/disable hit");
+    let mut rita_client = settings::get_rita_client();
+    rita_client.exit_client.exit_split = None;
+    settings::set_rita_client(rita_client);
+
+    if let Err(e) = write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+    HttpResponse::Ok().json(())
+}
@@ -0,0 +1,47 @@
+//! Endpoints for the guest network, see crate::guest_network
+
+use crate::guest_network::{apply_guest_network, revert_guest_network};
+use crate::RitaClientError;
+use actix_web_async::http::StatusCode;
+use actix_web_async::{web::Json, HttpRequest, HttpResponse};
+use rita_common::{RitaCommonError, KI};
+use settings::guest_network::GuestNetworkSettings;
+
+pub async fn get_guest_network(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(settings::get_rita_client().guest_network)
+}
+
+fn set_guest_network_internal(new_settings: Option<GuestNetworkSettings>) -> HttpResponse {
+    if !KI.is_openwrt() {
+        return HttpResponse::BadRequest().json("The guest network is only supported on OpenWrt");
+    }
+    if let Err(e) = apply_guest_network(new_settings.as_ref()) {
+        error!("Failed to apply guest network {:?}", e);
+        revert_guest_network();
+        return match e {
+            RitaClientError::MiscStringError(_) | RitaClientError::ValidationError(_) => {
+                HttpResponse::BadRequest().json(format!("{e}"))
+            }
+            _ => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}")),
+        };
+    }
+
+    let mut rita_client = settings::get_rita_client();
+    rita_client.guest_network = new_settings;
+    settings::set_rita_client(rita_client);
+    if let Err(e) = settings::write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+    HttpResponse::Ok().json(())
+}
+
+/// Provisions the guest network or updates it, the network and wifi restart to apply it
+pub async fn set_guest_network(new_settings: Json<GuestNetworkSettings>) -> HttpResponse {
+    set_guest_network_internal(Some(new_settings.into_inner()))
+}
+
+/// Removes the guest network, its usage history is kept
+pub async fn disable_guest_network(_req: HttpRequest) -> HttpResponse {
+    set_guest_network_internal(None)
+}
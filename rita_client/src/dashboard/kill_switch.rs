@@ -0,0 +1,69 @@
+//! Endpoints for the exit kill switch, which keeps lan traffic from ever going out the local gateway
+//! uplink unencrypted by only forwarding it into wg_exit
+
+use crate::exit_manager::apply_kill_switch;
+use actix_web_async::http::StatusCode;
+use actix_web_async::{web::Path, HttpRequest, HttpResponse};
+use rita_common::{RitaCommonError, KI};
+use std::time::Duration;
+
+/// Wireguard drops a session this long after the last handshake, past this the tunnel is down
+const EXIT_TUNNEL_TIMEOUT: Duration = Duration::from_secs(180);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KillSwitchStatus {
+    /// If the kill switch is turned on in the settings
+    pub enabled: bool,
+    /// If the firewall rules are actually in place
+    pub active: bool,
+    /// If wg_exit has a live session with the exit
+    pub tunnel_up: bool,
+    /// True when lan traffic is currently being dropped because the tunnel is down
+    pub blocking: bool,
+}
+
+fn exit_tunnel_up() -> bool {
+    match KI.get_last_handshake_time("wg_exit") {
+        Ok(handshakes) => handshakes.iter().any(|(_, time)| match time.elapsed() {
+            Ok(elapsed) => elapsed < EXIT_TUNNEL_TIMEOUT,
+            // a handshake in the future means the clock moved back, it is still recent
+            Err(_) => true,
+        }),
+        Err(_) => false,
+    }
+}
+
+pub async fn get_kill_switch(_req: HttpRequest) -> HttpResponse {
+    let enabled = settings::get_rita_client().exit_client.kill_switch;
+    let active = match KI.is_client_kill_switch_set() {
+        Ok(active) => active,
+        Err(e) => {
+            error!("Failed to check kill switch rules {:?}", e);
+            false
+        }
+    };
+    let tunnel_up = exit_tunnel_up();
+    HttpResponse::Ok().json(KillSwitchStatus {
+        enabled,
+        active,
+        tunnel_up,
+        blocking: active && !tunnel_up,
+    })
+}
+
+/// Turns the kill switch on or off and applies it immediately
+pub async fn set_kill_switch(path: Path<bool>) -> HttpResponse {
+    let enabled = path.into_inner();
+
+    let mut rita_client = settings::get_rita_client();
+    rita_client.exit_client.kill_switch = enabled;
+    settings::set_rita_client(rita_client);
+
+    apply_kill_switch();
+
+    if let Err(e) = settings::write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+    HttpResponse::Ok().json(())
+}
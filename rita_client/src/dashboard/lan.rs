@@ -0,0 +1,540 @@
+//! Endpoints for viewing and changing the lan addressing and the dhcp server on it, including static
+//! reservations. Changes are checked against the exit and wan subnets since a lan overlapping either of
+//! them would break routing out of the router
+
+use crate::dashboard::devices_on_lan::mac_serialize;
+use crate::exit_manager::get_exit_tunnel_mtu;
+use crate::RitaClientError;
+use actix_web_async::http::StatusCode;
+use actix_web_async::{web::Json, HttpRequest, HttpResponse};
+use ipnetwork::Ipv4Network;
+use mac_address::MacAddress;
+use rita_common::KI;
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::Duration;
+
+const LAN_IP_KEY: &str = "network.lan.ipaddr";
+const LAN_NETMASK_KEY: &str = "network.lan.netmask";
+const DHCP_START_KEY: &str = "dhcp.lan.start";
+const DHCP_LIMIT_KEY: &str = "dhcp.lan.limit";
+const DHCP_LEASE_TIME_KEY: &str = "dhcp.lan.leasetime";
+const DNSMASQ_SERVERS_KEY: &str = "dhcp.@dnsmasq[0].server";
+
+/// OpenWrt defaults for when the dhcp options are not set
+const DEFAULT_DHCP_START: u32 = 100;
+const DEFAULT_DHCP_LIMIT: u32 = 150;
+const DEFAULT_LEASE_TIME: &str = "12h";
+
+/// The largest and smallest lan allowed, a /30 has room for the router and a single device
+const MIN_LAN_PREFIX: u8 = 16;
+const MAX_LAN_PREFIX: u8 = 30;
+
+/// The exit's dns server, lan clients are pointed at it through the router, see update_dns_conf
+const EXIT_DNS_SERVER: Ipv4Addr = Ipv4Addr::new(172, 168, 0, 254);
+
+/// Reservations past this are not read, only bounds the loop removing a device's reservations
+const MAX_RESERVATIONS: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LanConfig {
+    /// The router's address on the lan, handed out as the gateway and dns server
+    pub router_ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// Offset from the start of the subnet of the first address handed out
+    pub dhcp_start: u32,
+    /// The number of addresses handed out
+    pub dhcp_limit: u32,
+    /// A number with an optional s, m, h, d or w suffix, or infinite
+    pub lease_time: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LeaseInfo {
+    /// Unix time the lease expires at, 0 for leases that never expire
+    pub expires: u64,
+    #[serde(serialize_with = "mac_serialize")]
+    pub mac: MacAddress,
+    pub ip: IpAddr,
+    pub hostname: Option<String>,
+}
+
+/// A device that always gets the same address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StaticReservation {
+    pub mac: String,
+    pub ip: Ipv4Addr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReservationToRemove {
+    pub mac: String,
+}
+
+fn invalid(message: String) -> RitaClientError {
+    RitaClientError::MiscStringError(message)
+}
+
+fn lan_network(router_ip: Ipv4Addr, netmask: Ipv4Addr) -> Result<Ipv4Network, RitaClientError> {
+    let network = Ipv4Network::with_netmask(router_ip, netmask)
+        .map_err(|e| invalid(format!("Invalid lan netmask {netmask} {e}")))?;
+    if !(MIN_LAN_PREFIX..=MAX_LAN_PREFIX).contains(&network.prefix()) {
+        return Err(invalid(format!(
+            "Lan prefix must be between /{MIN_LAN_PREFIX} and /{MAX_LAN_PREFIX}"
+        )));
+    }
+    if router_ip == network.network() || router_ip == network.broadcast() {
+        return Err(invalid(format!(
+            "{router_ip} can not be used as an address in {network}"
+        )));
+    }
+    Ok(network)
+}
+
+fn valid_lease_time(lease_time: &str) -> bool {
+    if lease_time == "infinite" {
+        return true;
+    }
+    let digits = lease_time.trim_end_matches(['s', 'm', 'h', 'd', 'w']);
+    // at most one suffix
+    lease_time.len() - digits.len() <= 1
+        && !digits.is_empty()
+        && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Subnets the lan must not overlap, with a description for error messages
+pub(crate) fn reserved_subnets() -> Vec<(String, Ipv4Network)> {
+    let rita_client = settings::get_rita_client();
+    let mut reserved = vec![(
+        "exit dns server".to_string(),
+        Ipv4Network::from(EXIT_DNS_SERVER),
+    )];
+    for exit in rita_client.exit_client.exits.values() {
+        if let Some(details) = exit.info.general_details() {
+            if let IpAddr::V4(ip) = details.server_internal_ip {
+                if let Ok(network) = Ipv4Network::new(ip, details.netmask) {
+                    reserved.push(("exit internal subnet".to_string(), network));
+                }
+            }
+        }
+    }
+    if let Some(IpAddr::V4(ip)) = rita_client.network.mesh_ip {
+        reserved.push(("mesh ip".to_string(), Ipv4Network::from(ip)));
+    }
+    if let Some(nic) = rita_client.network.external_nic {
+        if let Ok(addresses) = KI.get_ip_from_iface(&nic) {
+            for (ip, prefix) in addresses {
+                if let Ok(network) = Ipv4Network::new(ip, prefix) {
+                    reserved.push(("wan subnet".to_string(), network));
+                }
+            }
+        }
+    }
+    reserved
+}
+
+/// Checks a reservation is usable on this lan, returns the parsed mac
+fn validate_reservation(
+    reservation: &StaticReservation,
+    network: Ipv4Network,
+    router_ip: Ipv4Addr,
+) -> Result<MacAddress, RitaClientError> {
+    let mac: MacAddress = reservation
+        .mac
+        .parse()
+        .map_err(|_| invalid(format!("Invalid mac address {}", reservation.mac)))?;
+    let ip = reservation.ip;
+    if !network.contains(ip)
+        || ip == router_ip
+        || ip == network.network()
+        || ip == network.broadcast()
+    {
+        return Err(invalid(format!(
+            "{ip} is not a usable device address on the lan {network}"
+        )));
+    }
+    if let Some(name) = &reservation.name {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(invalid(format!("Invalid device name {name}")));
+        }
+    }
+    Ok(mac)
+}
+
+fn validate_lan_config(
+    config: &LanConfig,
+    reserved: &[(String, Ipv4Network)],
+    reservations: &[StaticReservation],
+) -> Result<(), RitaClientError> {
+    let network = lan_network(config.router_ip, config.netmask)?;
+    if config.dhcp_start == 0 || config.dhcp_limit == 0 {
+        return Err(invalid(
+            "Dhcp start and limit must be at least 1".to_string(),
+        ));
+    }
+    // the last address in the subnet is the broadcast address
+    if u64::from(config.dhcp_start) + u64::from(config.dhcp_limit) > u64::from(network.size() - 1) {
+        return Err(invalid(format!(
+            "Dhcp range does not fit in the lan {network}"
+        )));
+    }
+    if !valid_lease_time(&config.lease_time) {
+        return Err(invalid(format!("Invalid lease time {}", config.lease_time)));
+    }
+    for (description, subnet) in reserved {
+        if network.overlaps(*subnet) {
+            return Err(invalid(format!(
+                "Lan {network} conflicts with the {description} {subnet}"
+            )));
+        }
+    }
+    for reservation in reservations {
+        validate_reservation(reservation, network, config.router_ip)?;
+    }
+    Ok(())
+}
+
+fn get_lan_config_internal() -> Result<LanConfig, RitaClientError> {
+    let ipaddr = KI.get_uci_var(LAN_IP_KEY)?;
+    // newer OpenWrt versions may store the address in cidr notation with no netmask option
+    let (router_ip, netmask) = match ipaddr.parse::<Ipv4Network>() {
+        Ok(network) if ipaddr.contains('/') => (network.ip(), network.mask()),
+        _ => (ipaddr.parse()?, KI.get_uci_var(LAN_NETMASK_KEY)?.parse()?),
+    };
+    Ok(LanConfig {
+        router_ip,
+        netmask,
+        dhcp_start: match KI.get_uci_var(DHCP_START_KEY) {
+            Ok(start) => start.parse()?,
+            Err(_) => DEFAULT_DHCP_START,
+        },
+        dhcp_limit: match KI.get_uci_var(DHCP_LIMIT_KEY) {
+            Ok(limit) => limit.parse()?,
+            Err(_) => DEFAULT_DHCP_LIMIT,
+        },
+        lease_time: KI
+            .get_uci_var(DHCP_LEASE_TIME_KEY)
+            .unwrap_or_else(|_| DEFAULT_LEASE_TIME.to_string()),
+    })
+}
+
+/// The subnet the lan currently uses
+pub(crate) fn get_lan_network() -> Result<Ipv4Network, RitaClientError> {
+    let config = get_lan_config_internal()?;
+    lan_network(config.router_ip, config.netmask)
+}
+
+/// Gets the uci sections of the dhcp host reservations along with the reservation
+fn get_reservation_sections() -> Result<Vec<(String, StaticReservation)>, RitaClientError> {
+    let config = KI.uci_show(Some("dhcp"))?;
+    let mut reservations = Vec::new();
+    for (section, kind) in config.iter() {
+        // section declarations have no option after the section name
+        if kind != "host" || section.matches('.').count() != 1 {
+            continue;
+        }
+        let (mac, ip) = match (
+            config.get(&format!("{section}.mac")),
+            config.get(&format!("{section}.ip")),
+        ) {
+            (Some(mac), Some(ip)) => (mac, ip),
+            _ => continue,
+        };
+        let ip = match ip.parse() {
+            Ok(ip) => ip,
+            Err(_) => continue,
+        };
+        reservations.push((
+            section.clone(),
+            StaticReservation {
+                // a host can list several macs, the first is the one we manage
+                mac: mac
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                ip,
+                name: config.get(&format!("{section}.name")).cloned(),
+            },
+        ));
+    }
+    reservations.sort_by_key(|(_, reservation)| reservation.ip);
+    Ok(reservations)
+}
+
+fn get_reservations() -> Result<Vec<StaticReservation>, RitaClientError> {
+    Ok(get_reservation_sections()?
+        .into_iter()
+        .map(|(_, reservation)| reservation)
+        .collect())
+}
+
+/// Deletes every reservation for this mac, deleting shifts the index of anonymous sections so the
+/// sections are looked up again after each one
+fn remove_reservations_for(mac: MacAddress) -> Result<bool, RitaClientError> {
+    let mut removed = false;
+    for _ in 0..MAX_RESERVATIONS {
+        let section = get_reservation_sections()?
+            .into_iter()
+            .find(|(_, reservation)| reservation.mac.parse::<MacAddress>().ok() == Some(mac));
+        match section {
+            Some((section, _)) => {
+                KI.del_uci_var(&section)?;
+                removed = true;
+            }
+            None => break,
+        }
+    }
+    Ok(removed)
+}
+
+fn add_reservation(reservation: StaticReservation) -> Result<(), RitaClientError> {
+    let config = get_lan_config_internal()?;
+    let network = lan_network(config.router_ip, config.netmask)?;
+    let mac = validate_reservation(&reservation, network, config.router_ip)?;
+    if let Some(other) = get_reservations()?
+        .into_iter()
+        .find(|r| r.ip == reservation.ip && r.mac.parse::<MacAddress>().ok() != Some(mac))
+    {
+        return Err(invalid(format!(
+            "{} is already reserved for {}",
+            other.ip, other.mac
+        )));
+    }
+
+    remove_reservations_for(mac)?;
+    KI.add_uci_var("dhcp", "host")?;
+    KI.set_uci_var("dhcp.@host[-1].mac", &mac.to_string())?;
+    KI.set_uci_var("dhcp.@host[-1].ip", &reservation.ip.to_string())?;
+    if let Some(name) = &reservation.name {
+        KI.set_uci_var("dhcp.@host[-1].name", name)?;
+    }
+    KI.uci_commit("dhcp")?;
+    KI.openwrt_reset_dnsmasq()?;
+    Ok(())
+}
+
+fn set_lan_config_internal(config: LanConfig) -> Result<(), RitaClientError> {
+    let old_config = get_lan_config_internal()?;
+    validate_lan_config(&config, &reserved_subnets(), &get_reservations()?)?;
+
+    KI.set_uci_var(LAN_IP_KEY, &config.router_ip.to_string())?;
+    KI.set_uci_var(LAN_NETMASK_KEY, &config.netmask.to_string())?;
+    KI.set_uci_var(DHCP_START_KEY, &config.dhcp_start.to_string())?;
+    KI.set_uci_var(DHCP_LIMIT_KEY, &config.dhcp_limit.to_string())?;
+    KI.set_uci_var(DHCP_LEASE_TIME_KEY, &config.lease_time)?;
+
+    // the router advertises itself as the lan dns server, see update_dns_conf, move that to the new address
+    if old_config.router_ip != config.router_ip {
+        if let Ok(servers) = KI.get_uci_var(DNSMASQ_SERVERS_KEY) {
+            let old_ip = old_config.router_ip.to_string();
+            let new_ip = config.router_ip.to_string();
+            let servers: Vec<&str> = servers
+                .split_whitespace()
+                .map(|s| if s == old_ip { new_ip.as_str() } else { s })
+                .collect();
+            KI.set_uci_list(DNSMASQ_SERVERS_KEY, &servers)?;
+        }
+    }
+
+    KI.uci_commit("network")?;
+    KI.uci_commit("dhcp")?;
+    Ok(())
+}
+
+pub async fn get_lan_config(_req: HttpRequest) -> HttpResponse {
+    match get_lan_config_internal() {
+        Ok(config) => HttpResponse::Ok().json(config),
+        Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}")),
+    }
+}
+
+/// Sets the lan addressing and dhcp range. The network is restarted right after responding, if the
+/// router ip changed the dashboard is only reachable at the new address afterwards
+pub async fn set_lan_config(config: Json<LanConfig>) -> HttpResponse {
+    if let Err(e) = set_lan_config_internal(config.into_inner()) {
+        if let RitaClientError::MiscStringError(_) = e {
+            return HttpResponse::BadRequest().json(format!("{e}"));
+        }
+        for config in ["network", "dhcp"] {
+            if let Err(e) = KI.uci_revert(config) {
+                trace!("Failed to revert {} {:?}", config, e);
+            }
+        }
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}"));
+    }
+
+    // give the response time to get out before the lan goes down
+    thread::spawn(|| {
+        thread::sleep(Duration::from_secs(1));
+        if let Err(e) = KI.openwrt_reset_network() {
+            error!("Failed to restart the network {:?}", e);
+        }
+        if let Err(e) = KI.openwrt_reset_dnsmasq() {
+            error!("Failed to restart dnsmasq {:?}", e);
+        }
+        // restarting the network invalidates the nat rules
+        if let Err(e) = KI.create_client_nat_rules(get_exit_tunnel_mtu()) {
+            error!("Failed to restore client nat {:?}", e);
+        }
+    });
+    HttpResponse::Ok().json(())
+}
+
+pub async fn get_dhcp_leases(_req: HttpRequest) -> HttpResponse {
+    match KI.get_dhcp_leases() {
+        Ok(leases) => HttpResponse::Ok().json(
+            leases
+                .into_iter()
+                .map(|lease| LeaseInfo {
+                    expires: lease.expires,
+                    mac: lease.mac,
+                    ip: lease.ip,
+                    hostname: lease.hostname,
+                })
+                .collect::<Vec<LeaseInfo>>(),
+        ),
+        Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}")),
+    }
+}
+
+pub async fn get_dhcp_reservations(_req: HttpRequest) -> HttpResponse {
+    match get_reservations() {
+        Ok(reservations) => HttpResponse::Ok().json(reservations),
+        Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}")),
+    }
+}
+
+/// Adds a static reservation, replacing any existing one for the same device
+pub async fn add_dhcp_reservation(reservation: Json<StaticReservation>) -> HttpResponse {
+    match add_reservation(reservation.into_inner()) {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(e) => {
+            if let Err(e) = KI.uci_revert("dhcp") {
+                trace!("Failed to revert dhcp {:?}", e);
+            }
+            match e {
+                RitaClientError::MiscStringError(_) => {
+                    HttpResponse::BadRequest().json(format!("{e}"))
+                }
+                _ => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}")),
+            }
+        }
+    }
+}
+
+pub async fn remove_dhcp_reservation(reservation: Json<ReservationToRemove>) -> HttpResponse {
+    let mac: MacAddress = match reservation.mac.parse() {
+        Ok(mac) => mac,
+        Err(_) => {
+            return HttpResponse::BadRequest()
+                .json(format!("Invalid mac address {}", reservation.mac))
+        }
+    };
+    let res = match remove_reservations_for(mac) {
+        Ok(true) => KI
+            .uci_commit("dhcp")
+            .and_then(|_| KI.openwrt_reset_dnsmasq())
+            .map_err(RitaClientError::from),
+        Ok(false) => return HttpResponse::NotFound().json(format!("No reservation for {mac}")),
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(e) => {
+            if let Err(e) = KI.uci_revert("dhcp") {
+                trace!("Failed to revert dhcp {:?}", e);
+            }
+            HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(router_ip: &str, netmask: &str, start: u32, limit: u32) -> LanConfig {
+        LanConfig {
+            router_ip: router_ip.parse().unwrap(),
+            netmask: netmask.parse().unwrap(),
+            dhcp_start: start,
+            dhcp_limit: limit,
+            lease_time: "12h".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate_lan_config() {
+        let reserved = vec![(
+            "exit internal subnet".to_string(),
+            "172.168.0.0/16".parse().unwrap(),
+        )];
+        assert!(validate_lan_config(
+            &config("192.168.10.1", "255.255.255.0", 100, 150),
+            &reserved,
+            &[]
+        )
+        .is_ok());
+        // range runs into the broadcast address
+        assert!(validate_lan_config(
+            &config("192.168.10.1", "255.255.255.0", 100, 156),
+            &reserved,
+            &[]
+        )
+        .is_err());
+        // not a contiguous netmask
+        assert!(validate_lan_config(
+            &config("192.168.10.1", "255.0.255.0", 100, 150),
+            &reserved,
+            &[]
+        )
+        .is_err());
+        // overlaps the exit subnet
+        assert!(validate_lan_config(
+            &config("172.168.10.1", "255.255.255.0", 100, 150),
+            &reserved,
+            &[]
+        )
+        .is_err());
+        let mut bad_lease = config("192.168.10.1", "255.255.255.0", 100, 150);
+        bad_lease.lease_time = "12hh".to_string();
+        assert!(validate_lan_config(&bad_lease, &reserved, &[]).is_err());
+
+        // an existing reservation would end up outside the new lan
+        let reservation = StaticReservation {
+            mac: "aa:bb:cc:dd:ee:ff".to_string(),
+            ip: "192.168.10.20".parse().unwrap(),
+            name: Some("printer".to_string()),
+        };
+        assert!(validate_lan_config(
+            &config("192.168.10.1", "255.255.255.0", 100, 150),
+            &reserved,
+            &[reservation.clone()]
+        )
+        .is_ok());
+        assert!(validate_lan_config(
+            &config("192.168.20.1", "255.255.255.0", 100, 150),
+            &reserved,
+            &[reservation]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_valid_lease_time() {
+        assert!(valid_lease_time("12h"));
+        assert!(valid_lease_time("3600"));
+        assert!(valid_lease_time("infinite"));
+        assert!(!valid_lease_time("h"));
+        assert!(!valid_lease_time(""));
+        assert!(!valid_lease_time("12hm"));
+        assert!(!valid_lease_time("1 2h"));
+    }
+}
@@ -9,11 +9,15 @@ pub mod backup_created;
 pub mod bandwidth_limit;
 pub mod contact_info;
 pub mod devices_on_lan;
+pub mod dns;
 pub mod eth_private_key;
 pub mod exits;
 pub mod extender_checkin;
+pub mod guest_network;
 pub mod installation_details;
 pub mod interfaces;
+pub mod kill_switch;
+pub mod lan;
 pub mod localization;
 pub mod logging;
 pub mod mesh_ip;
@@ -23,8 +27,15 @@ pub mod operator;
 pub mod prices;
 pub mod remote_access;
 pub mod router;
+pub mod rpc;
+pub mod speedtest;
+pub mod summary;
 pub mod system_chain;
+pub mod tunnel_mtu;
+pub mod ui;
 pub mod usage;
+pub mod user_rules;
+pub mod vouchers;
 pub mod wifi;
 
 use std::thread;
@@ -33,11 +44,15 @@ use crate::dashboard::auth::*;
 use crate::dashboard::backup_created::*;
 use crate::dashboard::bandwidth_limit::*;
 use crate::dashboard::contact_info::*;
+use crate::dashboard::dns::*;
 use crate::dashboard::eth_private_key::*;
 use crate::dashboard::exits::*;
 use crate::dashboard::extender_checkin::*;
+use crate::dashboard::guest_network::*;
 use crate::dashboard::installation_details::*;
 use crate::dashboard::interfaces::*;
+use crate::dashboard::kill_switch::*;
+use crate::dashboard::lan::*;
 use crate::dashboard::localization::*;
 use crate::dashboard::logging::*;
 use crate::dashboard::mesh_ip::*;
@@ -47,16 +62,30 @@ use crate::dashboard::operator::*;
 use crate::dashboard::prices::*;
 use crate::dashboard::remote_access::*;
 use crate::dashboard::router::*;
+use crate::dashboard::rpc::*;
+use crate::dashboard::speedtest::*;
+use crate::dashboard::summary::*;
 use crate::dashboard::system_chain::*;
+use crate::dashboard::tunnel_mtu::*;
+use crate::dashboard::ui::*;
 use crate::dashboard::usage::*;
+use crate::dashboard::user_rules::*;
+use crate::dashboard::vouchers::*;
 use crate::dashboard::wifi::*;
 use actix_async::System;
+use actix_web_async::middleware::DefaultHeaders;
 use actix_web_async::{web, App, HttpServer};
+use rita_common::capabilities::get_capabilities_endpoint;
 use rita_common::dashboard::babel::*;
 use rita_common::dashboard::debts::*;
 use rita_common::dashboard::development::*;
+use rita_common::dashboard::logging::*;
+use rita_common::dashboard::mesh_loops::*;
+use rita_common::dashboard::mesh_mtu::*;
 use rita_common::dashboard::nickname::*;
+use rita_common::dashboard::notifications::*;
 use rita_common::dashboard::own_info::*;
+use rita_common::dashboard::payment_pause::*;
 use rita_common::dashboard::settings::*;
 use rita_common::dashboard::token_bridge::*;
 use rita_common::dashboard::usage::*;
@@ -64,6 +93,7 @@ use rita_common::dashboard::wallet::*;
 use rita_common::dashboard::wg_key::*;
 use rita_common::middleware;
 use rita_common::network_endpoints::*;
+use rita_common::path_diagnostics::get_path_diagnostics;
 
 use self::devices_on_lan::get_devices_lan_endpoint;
 
@@ -74,144 +104,18 @@ pub fn start_client_dashboard(rita_dashboard_port: u16) {
         runner.block_on(async move {
             let _res = HttpServer::new(|| {
                 App::new()
+                    .wrap(middleware::CsrfMiddlewareFactory)
                     .wrap(middleware::AuthMiddlewareFactory)
                     .wrap(middleware::HeadersMiddlewareFactory)
-                    .route("/backup_created", web::get().to(get_backup_created))
-                    .route(
-                        "/backup_created/{status}",
-                        web::post().to(set_backup_created),
-                    )
-                    .route("/operator", web::get().to(get_operator))
-                    .route("/operator/{address}", web::post().to(change_operator))
-                    .route("/operator/remove", web::post().to(remove_operator))
-                    .route("/operator_fee", web::get().to(get_operator_fee))
-                    .route("/operator_fee/{fee}", web::post().to(set_operator_fee))
-                    .route("/operator_debt", web::get().to(get_operator_debt))
-                    .route("/debts", web::get().to(get_debts))
-                    .route("/debts/reset", web::post().to(reset_debt))
-                    .route("/exits", web::get().to(get_exit_info))
-                    .route("/exits", web::post().to(add_exits))
-                    .route("/exits/{name}/register", web::post().to(register_to_exit))
-                    .route("/exits/{name}/reset", web::post().to(reset_exit))
-                    .route("/exits/{name}/select", web::post().to(select_exit))
-                    .route(
-                        "/extender_checkin",
-                        web::post().to(extender_checkin_handler),
-                    )
-                    .route("/local_fee", web::get().to(get_local_fee))
-                    .route("/local_fee/{fee}", web::post().to(set_local_fee))
-                    .route("/metric_factor", web::get().to(get_metric_factor))
-                    .route("/metric_factor/{factor}", web::post().to(set_metric_factor))
-                    .route("/lan_devices", web::get().to(get_devices_lan_endpoint))
-                    .route(
-                        "/exits/{name}/verify/{code}",
-                        web::post().to(verify_on_exit_with_code),
-                    )
-                    .route("/info", web::get().to(get_own_info))
-                    .route("/interfaces", web::get().to(get_interfaces_endpoint))
-                    .route("/interfaces", web::post().to(set_interfaces_endpoint))
-                    .route("/interfaces/mesh", web::get().to(wlan_mesh_get))
-                    .route(
-                        "/interfaces/lightclient",
-                        web::get().to(wlan_lightclient_get),
-                    )
-                    .route("/interfaces/mesh/{enabled}", web::post().to(wlan_mesh_set))
-                    .route(
-                        "/interfaces/lightclient/{enabled}",
-                        web::post().to(wlan_lightclient_set),
-                    )
-                    .route("/eth_private_key", web::get().to(get_eth_private_key))
-                    .route("/mesh_ip", web::get().to(get_mesh_ip))
-                    .route("/neighbors", web::get().to(get_neighbor_info))
-                    .route("/routes", web::get().to(get_routes))
-                    .route("/remote_logging/enabled", web::get().to(get_remote_logging))
-                    .route(
-                        "/remote_logging/enabled/{enabled}",
-                        web::post().to(remote_logging),
-                    )
-                    .route(
-                        "/remote_logging/level",
-                        web::get().to(get_remote_logging_level),
-                    )
-                    .route(
-                        "/remote_logging/level/{level}",
-                        web::post().to(remote_logging_level),
-                    )
-                    .route("/settings", web::get().to(get_settings))
-                    .route("/settings", web::post().to(set_settings))
-                    .route("/version", web::get().to(version))
-                    .route("/wg_public_key", web::get().to(get_wg_public_key))
-                    .route("/wifi_settings", web::post().to(set_wifi_multi))
-                    .route(
-                        "/wifi_settings/get_channels/{radio}",
-                        web::get().to(get_allowed_wifi_channels),
-                    )
-                    .route(
-                        "/wifi_settings/get_encryption/{radio}",
-                        web::get().to(get_allowed_encryption_modes),
-                    )
-                    .route("/wifi_settings", web::get().to(get_wifi_config))
-                    .route("/withdraw/{address}/{amount}", web::post().to(withdraw))
-                    .route("/withdraw_all/{address}", web::post().to(withdraw_all))
-                    .route(
-                        "/auto_price/enabled/{status}",
-                        web::post().to(set_auto_pricing),
-                    )
-                    .route("/auto_price/enabled", web::get().to(auto_pricing_status))
-                    .route("/prices", web::get().to(get_prices))
-                    .route(
-                        "/blockchain/set/{chain_id}",
-                        web::post().to(set_system_blockchain_endpoint),
-                    )
-                    .route("/blockchain/get", web::get().to(get_system_blockchain))
-                    .route("/nickname/get", web::get().to(get_nickname))
-                    .route("/nickname/set", web::post().to(set_nickname))
-                    .route(
-                        "/low_balance_notification",
-                        web::get().to(get_low_balance_notification),
-                    )
-                    .route(
-                        "/low_balance_notification/{status}",
-                        web::post().to(set_low_balance_notification),
-                    )
-                    .route("/usage/relay", web::get().to(get_relay_usage))
-                    .route("/usage/client", web::get().to(get_client_usage))
-                    .route("/usage/payments", web::get().to(get_payments))
-                    .route("/token_bridge/status", web::get().to(get_bridge_status))
-                    .route("/router/reboot", web::post().to(reboot_router))
-                    .route("/router/update", web::post().to(update_router))
-                    .route("/router/password", web::post().to(set_pass))
-                    .route("/remote_access", web::get().to(get_remote_access_status))
-                    .route(
-                        "/remote_access/{status}",
-                        web::post().to(set_remote_access_status),
-                    )
-                    .route("/wipe", web::post().to(wipe))
-                    .route("/localization", web::get().to(get_localization))
-                    .route(
-                        "/installation_details",
-                        web::post().to(set_installation_details),
-                    )
-                    .route(
-                        "/installation_details",
-                        web::get().to(get_installation_details),
-                    )
-                    .route("/billing_details", web::get().to(get_billing_details))
-                    .route("/billing_details", web::post().to(set_billing_details))
-                    .route("/bandwidth_limit", web::get().to(get_bandwidth_limit))
-                    .route(
-                        "/bandwidth_limit/{limit}",
-                        web::post().to(set_bandwidth_limit),
-                    )
-                    .route(
-                        "/operator_setup/{enabled}",
-                        web::post().to(set_display_operator_setup),
-                    )
-                    .route("/operator_setup", web::get().to(display_operator_setup))
-                    .route("/phone", web::get().to(get_phone_number))
-                    .route("/phone", web::post().to(set_phone_number))
-                    .route("/email", web::get().to(get_email))
-                    .route("/email", web::post().to(set_email))
+                    .wrap(DefaultHeaders::new().add((
+                        DASHBOARD_API_VERSION_HEADER,
+                        DASHBOARD_API_VERSION.to_string(),
+                    )))
+                    .route("/api/version", web::get().to(get_api_version))
+                    .service(web::scope("/api/v1").configure(configure_dashboard_api))
+                    .route("/ui", web::get().to(serve_ui))
+                    .route("/ui/{path:.*}", web::get().to(serve_ui))
+                    .configure(configure_dashboard_api)
             })
             .workers(1)
             .bind(format!("[::0]:{rita_dashboard_port}"))
@@ -222,3 +126,248 @@ pub fn start_client_dashboard(rita_dashboard_port: u16) {
         });
     });
 }
+
+/// Every json endpoint, registered both under /api/v1 and at the root for uis that predate api
+/// versioning
+fn configure_dashboard_api(cfg: &mut web::ServiceConfig) {
+    cfg.route("/backup_created", web::get().to(get_backup_created))
+        .route(
+            "/backup_created/{status}",
+            web::post().to(set_backup_created),
+        )
+        .route("/operator", web::get().to(get_operator))
+        .route("/operator/{address}", web::post().to(change_operator))
+        .route("/operator/remove", web::post().to(remove_operator))
+        .route("/operator_fee", web::get().to(get_operator_fee))
+        .route("/operator_fee/{fee}", web::post().to(set_operator_fee))
+        .route("/operator_debt", web::get().to(get_operator_debt))
+        .route("/debts", web::get().to(get_debts))
+        .route("/debts/reset", web::post().to(reset_debt))
+        .route(
+            "/debts/reconciliation",
+            web::get().to(get_debt_reconciliation),
+        )
+        .route("/exits", web::get().to(get_exit_info))
+        .route("/exits", web::post().to(add_exits))
+        .route("/exits/{name}/register", web::post().to(register_to_exit))
+        .route("/exits/{name}/reset", web::post().to(reset_exit))
+        .route("/exits/{name}/select", web::post().to(select_exit))
+        .route("/exits/tunnel_mtu", web::get().to(get_tunnel_mtu))
+        .route("/exits/tunnel_mtu/{mtu}", web::post().to(set_tunnel_mtu))
+        .route("/exits/tunnel_mtu/check", web::get().to(check_tunnel_mtu))
+        .route("/exits/kill_switch", web::get().to(get_kill_switch))
+        .route("/exits/split", web::get().to(get_exit_split))
+        .route("/exits/split/disable", web::post().to(disable_exit_split))
+        .route(
+            "/exits/split/{secondary}/{weight}",
+            web::post().to(set_exit_split),
+        )
+        .route("/guest_network", web::get().to(get_guest_network))
+        .route("/guest_network", web::post().to(set_guest_network))
+        .route(
+            "/guest_network/disable",
+            web::post().to(disable_guest_network),
+        )
+        .route("/dns", web::get().to(get_dns_settings))
+        .route("/dns", web::post().to(set_dns_settings))
+        .route(
+            "/exits/kill_switch/{enabled}",
+            web::post().to(set_kill_switch),
+        )
+        .route(
+            "/extender_checkin",
+            web::post().to(extender_checkin_handler),
+        )
+        .route("/local_fee", web::get().to(get_local_fee))
+        .route("/local_fee/{fee}", web::post().to(set_local_fee))
+        .route("/metric_factor", web::get().to(get_metric_factor))
+        .route("/metric_factor/{factor}", web::post().to(set_metric_factor))
+        .route("/lan_devices", web::get().to(get_devices_lan_endpoint))
+        .route("/lan", web::get().to(get_lan_config))
+        .route("/lan", web::post().to(set_lan_config))
+        .route("/lan/dhcp/leases", web::get().to(get_dhcp_leases))
+        .route(
+            "/lan/dhcp/reservations",
+            web::get().to(get_dhcp_reservations),
+        )
+        .route(
+            "/lan/dhcp/reservations",
+            web::post().to(add_dhcp_reservation),
+        )
+        .route(
+            "/lan/dhcp/reservations/remove",
+            web::post().to(remove_dhcp_reservation),
+        )
+        .route("/routes", web::get().to(get_static_routes))
+        .route("/routes", web::post().to(add_static_route_endpoint))
+        .route(
+            "/routes/remove",
+            web::post().to(remove_static_route_endpoint),
+        )
+        .route("/firewall/rules", web::get().to(get_firewall_rules))
+        .route("/firewall/rules", web::post().to(add_firewall_rule))
+        .route(
+            "/firewall/rules/remove",
+            web::post().to(remove_firewall_rule),
+        )
+        .route(
+            "/exits/{name}/verify/{code}",
+            web::post().to(verify_on_exit_with_code),
+        )
+        .route("/info", web::get().to(get_own_info))
+        .route("/dashboard_summary", web::get().to(get_dashboard_summary))
+        .route("/interfaces", web::get().to(get_interfaces_endpoint))
+        .route("/interfaces", web::post().to(set_interfaces_endpoint))
+        .route("/interfaces/mesh", web::get().to(wlan_mesh_get))
+        .route(
+            "/interfaces/lightclient",
+            web::get().to(wlan_lightclient_get),
+        )
+        .route("/interfaces/mesh/{enabled}", web::post().to(wlan_mesh_set))
+        .route(
+            "/interfaces/lightclient/{enabled}",
+            web::post().to(wlan_lightclient_set),
+        )
+        .route("/eth_private_key", web::get().to(get_eth_private_key))
+        .route("/mesh_ip", web::get().to(get_mesh_ip))
+        .route("/neighbors", web::get().to(get_neighbor_info))
+        .route(
+            "/neighbors/{id}/flaps",
+            web::get().to(get_neighbor_route_flaps),
+        )
+        .route(
+            "/neighbors/penalties",
+            web::get().to(get_neighbor_penalties),
+        )
+        .route("/routes", web::get().to(get_routes))
+        .route("/rpc", web::post().to(rpc))
+        .route(
+            "/diagnostics/path/{dest}",
+            web::get().to(get_path_diagnostics),
+        )
+        .route("/logging/levels", web::get().to(get_log_levels))
+        .route(
+            "/logging/level/{module}/{level}",
+            web::post().to(set_log_level),
+        )
+        .route("/remote_logging/enabled", web::get().to(get_remote_logging))
+        .route(
+            "/remote_logging/enabled/{enabled}",
+            web::post().to(remote_logging),
+        )
+        .route(
+            "/remote_logging/level",
+            web::get().to(get_remote_logging_level),
+        )
+        .route(
+            "/remote_logging/level/{level}",
+            web::post().to(remote_logging_level),
+        )
+        .route("/speedtest", web::post().to(start_speedtest))
+        .route("/settings", web::get().to(get_settings))
+        .route("/settings", web::post().to(set_settings))
+        .route("/version", web::get().to(version))
+        .route("/capabilities", web::get().to(get_capabilities_endpoint))
+        .route("/wg_public_key", web::get().to(get_wg_public_key))
+        .route("/wifi_settings", web::post().to(set_wifi_multi))
+        .route(
+            "/wifi_settings/get_channels/{radio}",
+            web::get().to(get_allowed_wifi_channels),
+        )
+        .route(
+            "/wifi_settings/get_encryption/{radio}",
+            web::get().to(get_allowed_encryption_modes),
+        )
+        .route("/wifi_settings", web::get().to(get_wifi_config))
+        .route("/withdraw/{address}/{amount}", web::post().to(withdraw))
+        .route("/withdraw_all/{address}", web::post().to(withdraw_all))
+        .route(
+            "/auto_price/enabled/{status}",
+            web::post().to(set_auto_pricing),
+        )
+        .route("/auto_price/enabled", web::get().to(auto_pricing_status))
+        .route("/prices", web::get().to(get_prices))
+        .route(
+            "/blockchain/set/{chain_id}",
+            web::post().to(set_system_blockchain_endpoint),
+        )
+        .route("/blockchain/get", web::get().to(get_system_blockchain))
+        .route("/nickname/get", web::get().to(get_nickname))
+        .route("/nickname/set", web::post().to(set_nickname))
+        .route(
+            "/low_balance_notification",
+            web::get().to(get_low_balance_notification),
+        )
+        .route(
+            "/low_balance_notification/{status}",
+            web::post().to(set_low_balance_notification),
+        )
+        .route("/usage/relay", web::get().to(get_relay_usage))
+        .route("/usage/client", web::get().to(get_client_usage))
+        .route("/usage/guest", web::get().to(get_guest_usage_endpoint))
+        .route(
+            "/usage/guest/summary",
+            web::get().to(get_guest_usage_summary_endpoint),
+        )
+        .route("/usage/payments", web::get().to(get_payments))
+        .route("/payments/pause", web::get().to(get_payment_pause))
+        .route("/payments/pause", web::post().to(pause_payments_endpoint))
+        .route("/payments/resume", web::post().to(resume_payments_endpoint))
+        .route("/earnings", web::get().to(get_earnings))
+        .route("/mesh_loops", web::get().to(get_mesh_loops))
+        .route(
+            "/mesh_loops/{ifname}/release",
+            web::post().to(release_mesh_loop),
+        )
+        .route("/tunnels/mtu", web::get().to(get_mesh_mtus))
+        .route("/notifications", web::get().to(get_notifications_endpoint))
+        .route(
+            "/notifications/{id}/read",
+            web::post().to(mark_notification_read_endpoint),
+        )
+        .route(
+            "/notifications/read_all",
+            web::post().to(mark_all_notifications_read_endpoint),
+        )
+        .route(
+            "/accounting/summary/{year}",
+            web::get().to(get_annual_summary_endpoint),
+        )
+        .route("/voucher/redeem", web::post().to(redeem_voucher))
+        .route("/token_bridge/status", web::get().to(get_bridge_status))
+        .route("/router/reboot", web::post().to(reboot_router))
+        .route("/router/update", web::post().to(update_router))
+        .route("/router/password", web::post().to(set_pass))
+        .route("/remote_access", web::get().to(get_remote_access_status))
+        .route(
+            "/remote_access/{status}",
+            web::post().to(set_remote_access_status),
+        )
+        .route("/wipe", web::post().to(wipe))
+        .route("/localization", web::get().to(get_localization))
+        .route(
+            "/installation_details",
+            web::post().to(set_installation_details),
+        )
+        .route(
+            "/installation_details",
+            web::get().to(get_installation_details),
+        )
+        .route("/billing_details", web::get().to(get_billing_details))
+        .route("/billing_details", web::post().to(set_billing_details))
+        .route("/bandwidth_limit", web::get().to(get_bandwidth_limit))
+        .route("/bandwidth_contract", web::get().to(get_bandwidth_contract))
+        .route(
+            "/bandwidth_limit/{limit}",
+            web::post().to(set_bandwidth_limit),
+        )
+        .route(
+            "/operator_setup/{enabled}",
+            web::post().to(set_display_operator_setup),
+        )
+        .route("/operator_setup", web::get().to(display_operator_setup))
+        .route("/phone", web::get().to(get_phone_number))
+        .route("/phone", web::post().to(set_phone_number))
+        .route("/email", web::get().to(get_email))
+        .route("/email", web::post().to(set_email));
+}
@@ -1,15 +1,16 @@
 use actix_web_async::http::StatusCode;
 use actix_web_async::{HttpRequest, HttpResponse};
 use althea_types::Identity;
-use arrayvec::ArrayString;
+use babel_monitor::parse_routes;
 use babel_monitor::parsing::get_installed_route;
 use babel_monitor::parsing::get_route_via_neigh;
+use babel_monitor::pool::get_babel_stream;
 use babel_monitor::structs::Route;
-use babel_monitor::{open_babel_stream, parse_routes};
 
 use num256::{Int256, Uint256};
 use rita_common::debt_keeper::{dump, NodeDebtData};
 use rita_common::network_monitor::{get_stats, IfaceStats, Stats};
+use rita_common::peer_labels::get_neighbor_label;
 use rita_common::tunnel_manager::{tm_get_neighbors, Neighbor};
 use std::collections::HashMap;
 use std::time::Duration;
@@ -22,6 +23,8 @@ const BABEL_TIMEOUT: Duration = Duration::from_secs(5);
 #[derive(Serialize)]
 pub struct NodeInfo {
     pub nickname: String,
+    /// The router model, if the neighbor shares it
+    pub device: Option<String>,
     // TODO: Remove this once the dashboard no longer depends on it.
     pub ip: String,
     pub id: Identity,
@@ -36,8 +39,8 @@ pub struct NodeInfo {
 }
 
 pub async fn get_routes(_req: HttpRequest) -> HttpResponse {
-    let babel_port = settings::get_rita_client().network.babel_port;
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
+    let babel_port = settings::get_rita_client_snapshot().network.babel_port;
+    match get_babel_stream(babel_port, Duration::from_secs(5)) {
         Ok(mut stream) => match parse_routes(&mut stream) {
             Ok(routes) => HttpResponse::Ok().json(routes),
             Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
@@ -54,19 +57,13 @@ pub async fn get_routes(_req: HttpRequest) -> HttpResponse {
 /// coordinated with the frontend.
 /// The routes info might also belong in /exits or a dedicated /routes endpoint
 pub async fn get_neighbor_info(_req: HttpRequest) -> HttpResponse {
-    let debts = dump();
-    let neighbors = tm_get_neighbors();
-    let combined_list = merge_debts_and_neighbors(neighbors, debts);
-    let babel_port = settings::get_rita_client().network.babel_port;
+    let babel_port = settings::get_rita_client_snapshot().network.babel_port;
 
-    match open_babel_stream(babel_port, BABEL_TIMEOUT) {
+    match get_babel_stream(babel_port, BABEL_TIMEOUT) {
         Ok(mut stream) => {
             let routes = parse_routes(&mut stream);
             if let Ok(routes) = routes {
-                let route_table_sample = routes;
-                let stats = get_stats();
-                let output = generate_neighbors_list(stats, route_table_sample, combined_list);
-                HttpResponse::Ok().json(output)
+                HttpResponse::Ok().json(neighbor_info_from_routes(routes))
             } else {
                 HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!(
                     "{}",
@@ -81,6 +78,12 @@ pub async fn get_neighbor_info(_req: HttpRequest) -> HttpResponse {
     }
 }
 
+/// The neighbor list from an already parsed route table
+pub fn neighbor_info_from_routes(route_table_sample: Vec<Route>) -> Vec<NodeInfo> {
+    let combined_list = merge_debts_and_neighbors(tm_get_neighbors(), dump());
+    generate_neighbors_list(get_stats(), route_table_sample, combined_list)
+}
+
 /// generates a list of neighbors coorelated with the quality of the route to the exit they provide
 fn generate_neighbors_list(
     stats: Stats,
@@ -90,14 +93,18 @@ fn generate_neighbors_list(
     let mut output = Vec::new();
 
     for (identity, (debt_info, neigh)) in debts.iter() {
-        let nickname = match identity.nickname {
-            Some(val) => val,
-            None => ArrayString::<32>::from("No Nickname").unwrap(),
+        let label = get_neighbor_label(&identity.wg_public_key).unwrap_or_default();
+        let nickname = match (identity.nickname, label.nickname) {
+            (Some(val), _) => val.to_string(),
+            (None, Some(val)) => val,
+            (None, None) => "No Nickname".to_string(),
         };
+        let device = label.device;
         let maybe_route = get_installed_route(&identity.mesh_ip, &route_table_sample);
         if maybe_route.is_err() {
             output.push(nonviable_node_info(
                 nickname,
+                device,
                 u16::max_value(),
                 identity.mesh_ip.to_string(),
                 *identity,
@@ -120,6 +127,7 @@ fn generate_neighbors_list(
             if maybe_exit_route.is_err() {
                 output.push(nonviable_node_info(
                     nickname,
+                    device,
                     neigh_route.metric,
                     identity.mesh_ip.to_string(),
                     *identity,
@@ -131,7 +139,8 @@ fn generate_neighbors_list(
             let exit_route = maybe_exit_route.unwrap();
 
             output.push(NodeInfo {
-                nickname: nickname.to_string(),
+                nickname,
+                device,
                 ip: identity.mesh_ip.to_string(),
                 id: *identity,
                 route_metric_to_exit: exit_route.metric,
@@ -146,6 +155,7 @@ fn generate_neighbors_list(
         } else {
             output.push(nonviable_node_info(
                 nickname,
+                device,
                 neigh_route.metric,
                 identity.mesh_ip.to_string(),
                 *identity,
@@ -176,14 +186,16 @@ fn merge_debts_and_neighbors(
 }
 
 fn nonviable_node_info(
-    nickname: ArrayString<32>,
+    nickname: String,
+    device: Option<String>,
     neigh_metric: u16,
     ip: String,
     id: Identity,
     speed_limit: Option<usize>,
 ) -> NodeInfo {
     NodeInfo {
-        nickname: nickname.to_string(),
+        nickname,
+        device,
         ip,
         id,
         total_payments: 0u32.into(),
@@ -52,6 +52,7 @@ fn test_set_router_update_instruction() {
     let test = UpdateType::Sysupgrade(SysupgradeCommand {
         url: "dummyurl.com".to_string(),
         flags: None,
+        sha256: None,
     });
     set_router_update_instruction(Some(test.clone()));
     let str = &*UPDATE_INSTRUCTION.read().unwrap();
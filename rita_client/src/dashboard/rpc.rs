@@ -0,0 +1,149 @@
+//! The JSON-RPC 2.0 machine api, see althea_types::rpc. Each method is answered by the dashboard handler for
+//! the same operation so the two can't drift apart, the handler's response becomes the result, or an error
+//! if the handler did not succeed. Batches are supported, notifications run but get no response.
+
+use crate::dashboard::neighbors::get_neighbor_info;
+use crate::dashboard::router::update_router;
+use actix_web_async::body::to_bytes;
+use actix_web_async::web::{Bytes, Json, Path};
+use actix_web_async::{HttpRequest, HttpResponse};
+use althea_types::rpc::{
+    JsonRpcError, JsonRpcRequest, JsonRpcResponse, RpcCall, WithdrawParams, INTERNAL_ERROR,
+    INVALID_PARAMS, INVALID_REQUEST, JSONRPC_VERSION, METHOD_FAILED, METHOD_NOT_FOUND, PARSE_ERROR,
+};
+use rita_common::dashboard::debts::get_debts;
+use rita_common::dashboard::own_info::get_own_info;
+use rita_common::dashboard::settings::{get_settings, set_settings};
+use rita_common::dashboard::wallet::{withdraw, withdraw_all};
+use serde_json::{json, Value};
+
+pub async fn rpc(req: HttpRequest, body: Bytes) -> HttpResponse {
+    let value: Value = match serde_json::from_slice(&body) {
+        Ok(value) => value,
+        Err(e) => {
+            return HttpResponse::Ok().json(JsonRpcResponse::error(
+                Value::Null,
+                PARSE_ERROR,
+                format!("Invalid json {e}"),
+                None,
+            ))
+        }
+    };
+    match value {
+        Value::Array(calls) if calls.is_empty() => HttpResponse::Ok().json(JsonRpcResponse::error(
+            Value::Null,
+            INVALID_REQUEST,
+            "Empty batch".to_string(),
+            None,
+        )),
+        Value::Array(calls) => {
+            let mut responses = Vec::new();
+            for call in calls {
+                if let Some(response) = handle_call(&req, call).await {
+                    responses.push(response);
+                }
+            }
+            if responses.is_empty() {
+                HttpResponse::NoContent().finish()
+            } else {
+                HttpResponse::Ok().json(responses)
+            }
+        }
+        call => match handle_call(&req, call).await {
+            Some(response) => HttpResponse::Ok().json(response),
+            None => HttpResponse::NoContent().finish(),
+        },
+    }
+}
+
+/// Runs one call, returns None for notifications
+async fn handle_call(req: &HttpRequest, call: Value) -> Option<JsonRpcResponse> {
+    let request: JsonRpcRequest = match serde_json::from_value(call) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(JsonRpcResponse::error(
+                Value::Null,
+                INVALID_REQUEST,
+                format!("Invalid request {e}"),
+                None,
+            ))
+        }
+    };
+    if request.jsonrpc != JSONRPC_VERSION {
+        return Some(JsonRpcResponse::error(
+            request.id.unwrap_or(Value::Null),
+            INVALID_REQUEST,
+            format!("Unsupported jsonrpc version {}", request.jsonrpc),
+            None,
+        ));
+    }
+    let result = match RpcCall::from_request(&request) {
+        Ok(call) => run_call(req, call).await,
+        Err(e) if RpcCall::METHODS.contains(&request.method.as_str()) => Err(JsonRpcError {
+            code: INVALID_PARAMS,
+            message: format!("Invalid params {e}"),
+            data: None,
+        }),
+        Err(_) => Err(JsonRpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("No method {}", request.method),
+            data: Some(json!(RpcCall::METHODS)),
+        }),
+    };
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => JsonRpcResponse::result(id, result),
+        Err(e) => JsonRpcResponse::error(id, e.code, e.message, e.data),
+    })
+}
+
+async fn run_call(req: &HttpRequest, call: RpcCall) -> Result<Value, JsonRpcError> {
+    debug!("Rpc call {:?}", call);
+    let response = match call {
+        RpcCall::Status => get_own_info(req.clone()).await,
+        RpcCall::Neighbors => get_neighbor_info(req.clone()).await,
+        RpcCall::Debts => get_debts(req.clone()).await,
+        RpcCall::Withdraw(WithdrawParams {
+            address,
+            amount: Some(amount),
+        }) => withdraw(Path::from((address, amount))).await,
+        RpcCall::Withdraw(WithdrawParams {
+            address,
+            amount: None,
+        }) => withdraw_all(Path::from(address)).await,
+        RpcCall::SettingsGet => get_settings(req.clone()).await,
+        RpcCall::SettingsSet(settings) => set_settings(Json(settings)).await,
+        RpcCall::UpdateTrigger => update_router(req.clone()).await,
+    };
+    response_to_result(response).await
+}
+
+/// The json body of a dashboard response, bodies that are not json become a string
+async fn response_to_result(response: HttpResponse) -> Result<Value, JsonRpcError> {
+    let status = response.status();
+    let body = match to_bytes(response.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            return Err(JsonRpcError {
+                code: INTERNAL_ERROR,
+                message: format!("Failed to read response {e}"),
+                data: None,
+            })
+        }
+    };
+    let value = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).to_string()))
+    };
+    if status.is_success() {
+        Ok(value)
+    } else {
+        Err(JsonRpcError {
+            code: METHOD_FAILED,
+            message: format!("Failed with status {status}"),
+            data: Some(json!({ "status": status.as_u16(), "body": value })),
+        })
+    }
+}
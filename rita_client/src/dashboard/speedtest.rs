@@ -0,0 +1,138 @@
+//! An on demand bandwidth test against our selected exit, run over the wg tunnel so that users can tell
+//! whether a slow connection is their own wifi or the path to the exit. The exit throttles how often a
+//! client may test and caps the amount of data moved, we additionally refuse to run tests back to back.
+
+use crate::heartbeat::get_selected_exit_server;
+use actix_web_async::http::StatusCode;
+use actix_web_async::{HttpRequest, HttpResponse};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Bytes moved in each direction, well under the cap the exit enforces
+const SPEEDTEST_BYTES: usize = 4 * 1024 * 1024;
+/// Number of round trips averaged for the latency measurement
+const LATENCY_SAMPLES: u32 = 5;
+/// Minimum time between tests started from this router
+const SPEEDTEST_MIN_INTERVAL: Duration = Duration::from_secs(60);
+const SPEEDTEST_TIMEOUT: Duration = Duration::from_secs(30);
+const LATENCY_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref LAST_SPEEDTEST: Arc<RwLock<Option<Instant>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SpeedtestResult {
+    /// Throughput from the exit to us in mbit/s
+    pub download_mbps: f64,
+    /// Throughput from us to the exit in mbit/s
+    pub upload_mbps: f64,
+    /// Average http round trip to the exit in milliseconds
+    pub latency_ms: f64,
+}
+
+fn mbps(bytes: usize, elapsed: Duration) -> f64 {
+    (bytes as f64 * 8.0) / elapsed.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+}
+
+async fn run_speedtest(base_url: &str) -> Result<SpeedtestResult, String> {
+    let client = awc::Client::default();
+
+    let response = client
+        .post(format!("{base_url}/speedtest/start"))
+        .timeout(LATENCY_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| format!("Could not contact the exit {e}"))?;
+    if !response.status().is_success() {
+        return Err(format!("Exit refused the test {}", response.status()));
+    }
+
+    let mut total = Duration::ZERO;
+    for _ in 0..LATENCY_SAMPLES {
+        let start = Instant::now();
+        client
+            .get(format!("{base_url}/time"))
+            .timeout(LATENCY_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| format!("Latency probe failed {e}"))?;
+        total += start.elapsed();
+    }
+    let latency_ms = total.as_secs_f64() * 1000.0 / LATENCY_SAMPLES as f64;
+
+    let start = Instant::now();
+    let mut response = client
+        .get(format!("{base_url}/speedtest/download/{SPEEDTEST_BYTES}"))
+        .timeout(SPEEDTEST_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| format!("Download test failed {e}"))?;
+    let downloaded = response
+        .body()
+        .limit(SPEEDTEST_BYTES)
+        .await
+        .map_err(|e| format!("Download test failed {e}"))?
+        .len();
+    let download_mbps = mbps(downloaded, start.elapsed());
+
+    let start = Instant::now();
+    let mut response = client
+        .post(format!("{base_url}/speedtest/upload"))
+        .timeout(SPEEDTEST_TIMEOUT)
+        .send_body(vec![0u8; SPEEDTEST_BYTES])
+        .await
+        .map_err(|e| format!("Upload test failed {e}"))?;
+    let uploaded: usize = response
+        .json()
+        .await
+        .map_err(|e| format!("Upload test failed {e}"))?;
+    let upload_mbps = mbps(uploaded, start.elapsed());
+
+    Ok(SpeedtestResult {
+        download_mbps,
+        upload_mbps,
+        latency_ms,
+    })
+}
+
+pub async fn start_speedtest(_req: HttpRequest) -> HttpResponse {
+    let exit = match get_selected_exit_server() {
+        Some(exit) => exit,
+        None => return HttpResponse::BadRequest().json("No exit selected"),
+    };
+    let exit_internal_addr = match exit.info.general_details() {
+        Some(details) => details.server_internal_ip,
+        None => return HttpResponse::BadRequest().json("Not registered with the selected exit"),
+    };
+
+    {
+        let mut last = LAST_SPEEDTEST.write().unwrap();
+        if let Some(last) = *last {
+            if last.elapsed() < SPEEDTEST_MIN_INTERVAL {
+                return HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+                    .json("A bandwidth test was run recently, please wait a minute");
+            }
+        }
+        *last = Some(Instant::now());
+    }
+
+    let base_url = format!("http://{}:{}", exit_internal_addr, exit.registration_port);
+
+    match run_speedtest(&base_url).await {
+        Ok(result) => {
+            info!("Bandwidth test to {} finished {:?}", base_url, result);
+            HttpResponse::Ok().json(result)
+        }
+        Err(e) => {
+            warn!("Bandwidth test to {} failed {}", base_url, e);
+            HttpResponse::build(StatusCode::BAD_GATEWAY).json(e)
+        }
+    }
+}
+
+#[test]
+fn test_mbps() {
+    assert_eq!(mbps(1_250_000, Duration::from_secs(1)), 10.0);
+    assert_eq!(mbps(SPEEDTEST_BYTES, Duration::from_secs(2)), 16.777216);
+}
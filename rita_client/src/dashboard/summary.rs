@@ -0,0 +1,154 @@
+//! Everything the dashboard status page shows in one request. On the slow cpus most routers have each
+//! request the page makes costs noticeable wall clock time, and the separate endpoints each open their
+//! own babel stream and ping the exits one after another. Here the route table is read once, the exit
+//! pings run in parallel with building the neighbor list, and every list is cut to a size the page can
+//! actually show so that a big mesh doesn't produce a response the router takes seconds to serialize.
+
+use crate::dashboard::exits::{exit_info_from_routes, ExitInfo};
+use crate::dashboard::neighbors::{neighbor_info_from_routes, NodeInfo};
+use actix_web_async::web::Query;
+use actix_web_async::HttpResponse;
+use babel_monitor::parse_routes;
+use babel_monitor::pool::get_babel_stream;
+use rita_common::dashboard::own_info::{own_info, OwnInfo};
+use rita_common::notifications::{get_notifications, Notification, MAX_NOTIFICATIONS};
+use std::thread;
+use std::time::Duration;
+
+const BABEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const DEFAULT_SUMMARY_NEIGHBORS: usize = 16;
+pub const MAX_SUMMARY_NEIGHBORS: usize = 64;
+pub const DEFAULT_SUMMARY_EXITS: usize = 8;
+pub const MAX_SUMMARY_EXITS: usize = 32;
+pub const DEFAULT_SUMMARY_NOTIFICATIONS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct SummaryQuery {
+    #[serde(default)]
+    pub max_neighbors: Option<usize>,
+    #[serde(default)]
+    pub max_exits: Option<usize>,
+    #[serde(default)]
+    pub max_notifications: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct DashboardSummary {
+    pub own_info: OwnInfo,
+    /// Best route metric first
+    pub neighbors: Vec<NodeInfo>,
+    pub total_neighbors: usize,
+    /// The selected exit first
+    pub exits: Vec<ExitInfo>,
+    pub total_exits: usize,
+    pub unread_notifications: usize,
+    /// Newest unread notifications first
+    pub notifications: Vec<Notification>,
+    /// Sections that could not be gathered, the rest of the summary is still returned
+    pub errors: Vec<String>,
+}
+
+/// Cuts a list down to the requested length, or the default if none was requested, never beyond max.
+/// Returns how long the list was
+fn trim<T>(list: &mut Vec<T>, requested: Option<usize>, default: usize, max: usize) -> usize {
+    let total = list.len();
+    list.truncate(requested.unwrap_or(default).min(max));
+    total
+}
+
+pub async fn get_dashboard_summary(query: Query<SummaryQuery>) -> HttpResponse {
+    trace!("/dashboard_summary hit");
+    let query = query.into_inner();
+    let mut errors = Vec::new();
+
+    let babel_port = settings::get_rita_client_snapshot().network.babel_port;
+    let routes = match get_babel_stream(babel_port, BABEL_TIMEOUT) {
+        Ok(mut stream) => match parse_routes(&mut stream) {
+            Ok(routes) => Some(routes),
+            Err(e) => {
+                errors.push(format!("Could not get babel routes {e}"));
+                None
+            }
+        },
+        Err(e) => {
+            errors.push(format!("Could not open babel stream {e}"));
+            None
+        }
+    };
+
+    let (mut neighbors, mut exits) = match routes {
+        Some(routes) => {
+            let exit_routes = routes.clone();
+            // the exit pings are mostly spent waiting, build the neighbor list meanwhile
+            let exits = thread::spawn(move || exit_info_from_routes(&exit_routes));
+            let neighbors = neighbor_info_from_routes(routes);
+            let exits = match exits.join() {
+                Ok(Ok(exits)) => exits,
+                Ok(Err(e)) => {
+                    errors.push(format!("Could not get exit info {e}"));
+                    Vec::new()
+                }
+                Err(_) => {
+                    errors.push("Getting exit info panicked".to_string());
+                    Vec::new()
+                }
+            };
+            (neighbors, exits)
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+
+    neighbors.sort_by_key(|n| n.route_metric);
+    let total_neighbors = trim(
+        &mut neighbors,
+        query.max_neighbors,
+        DEFAULT_SUMMARY_NEIGHBORS,
+        MAX_SUMMARY_NEIGHBORS,
+    );
+    exits.sort_by_key(|e| !e.is_selected);
+    let total_exits = trim(
+        &mut exits,
+        query.max_exits,
+        DEFAULT_SUMMARY_EXITS,
+        MAX_SUMMARY_EXITS,
+    );
+    let (unread_notifications, mut notifications) = get_notifications(true);
+    trim(
+        &mut notifications,
+        query.max_notifications,
+        DEFAULT_SUMMARY_NOTIFICATIONS,
+        MAX_NOTIFICATIONS,
+    );
+
+    HttpResponse::Ok().json(DashboardSummary {
+        own_info: own_info(),
+        neighbors,
+        total_neighbors,
+        exits,
+        total_exits,
+        unread_notifications,
+        notifications,
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim() {
+        let mut list: Vec<usize> = (0..100).collect();
+        assert_eq!(trim(&mut list, None, 16, 64), 100);
+        assert_eq!(list.len(), 16);
+
+        let mut list: Vec<usize> = (0..100).collect();
+        trim(&mut list, Some(1000), 16, 64);
+        assert_eq!(list.len(), 64);
+
+        let mut list: Vec<usize> = (0..3).collect();
+        assert_eq!(trim(&mut list, Some(10), 16, 64), 3);
+        assert_eq!(list, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,65 @@
+//! Endpoints for viewing and changing the wg_exit mtu and for checking the exit tunnel for mtu blackholes,
+//! where small packets make it to the exit but full sized ones are silently dropped somewhere on the path
+
+use crate::exit_manager::get_exit_tunnel_mtu;
+use crate::heartbeat::get_selected_exit_server;
+use actix_web_async::http::StatusCode;
+use actix_web_async::{web::Path, HttpRequest, HttpResponse};
+use althea_kernel_interface::mtu::MINIMUM_PROBE_MTU;
+use rita_common::{RitaCommonError, KI};
+
+pub async fn get_tunnel_mtu(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(settings::get_rita_client().exit_client.tunnel_mtu)
+}
+
+/// Sets the default wg_exit mtu and applies it immediately unless the selected exit overrides it. Note that mss clamps are only ever lowered, so
+/// raising the mtu only takes full effect for tcp after the next reboot
+pub async fn set_tunnel_mtu(path: Path<usize>) -> HttpResponse {
+    let mtu = path.into_inner();
+    // wg_exit carries ipv6, which can not work over links smaller than this
+    if mtu < MINIMUM_PROBE_MTU {
+        return HttpResponse::BadRequest()
+            .json(format!("Tunnel mtu must be at least {MINIMUM_PROBE_MTU}"));
+    }
+
+    let mut rita_client = settings::get_rita_client();
+    rita_client.exit_client.tunnel_mtu = mtu;
+    settings::set_rita_client(rita_client);
+
+    let mtu = get_exit_tunnel_mtu();
+    if KI.get_mtu("wg_exit").is_ok() {
+        if let Err(e) = KI.set_mtu("wg_exit", mtu) {
+            error!("Failed to set wg_exit mtu {:?}", e);
+        }
+        if let Err(e) = KI.set_mss_clamp("wg_exit", mtu) {
+            error!("Failed to set wg_exit mss clamp {:?}", e);
+        }
+    }
+
+    if let Err(e) = settings::write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+    HttpResponse::Ok().json(())
+}
+
+/// Probes the tunnel to the selected exit at the configured mtu, reporting the largest working packet
+/// size when full sized packets are being dropped
+pub async fn check_tunnel_mtu(_req: HttpRequest) -> HttpResponse {
+    let exit = match get_selected_exit_server() {
+        Some(exit) => exit,
+        None => return HttpResponse::BadRequest().json("No exit selected"),
+    };
+    let details = match exit.info.general_details() {
+        Some(details) => details.clone(),
+        None => return HttpResponse::BadRequest().json("No details for the selected exit yet"),
+    };
+    let mtu = settings::get_rita_client()
+        .exit_client
+        .tunnel_mtu_for(&exit);
+
+    match KI.check_mtu_blackhole(details.server_internal_ip, "wg_exit", mtu) {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}")),
+    }
+}
@@ -0,0 +1,155 @@
+//! Serves a dashboard ui bundled on the router filesystem under /ui and reports which json api versions
+//! this daemon speaks. The ui used to be deployed separately from rita, so an older ui could be pointed at a
+//! newer daemon without either noticing; a ui served from here ships with the daemon it talks to, and one
+//! hosted elsewhere can check /api/version before using the /api/v1 routes.
+
+use actix_web_async::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use actix_web_async::{HttpRequest, HttpResponse};
+use rita_common::dashboard::own_info::READABLE_VERSION;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The json api version served under /api/v1, bumped when routes change in a way an old ui would misread
+pub const DASHBOARD_API_VERSION: u32 = 1;
+/// Every dashboard response carries this header so a ui can detect a daemon it was not built for
+pub const DASHBOARD_API_VERSION_HEADER: &str = "X-Rita-Api-Version";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DashboardApiVersion {
+    /// The newest api version this daemon serves
+    pub api_version: u32,
+    pub supported_api_versions: Vec<u32>,
+    pub rita_version: String,
+    pub readable_version: String,
+    /// True if a bundled ui is served under /ui
+    pub ui_available: bool,
+}
+
+pub async fn get_api_version(_req: HttpRequest) -> HttpResponse {
+    let ui_dir = settings::get_rita_client().network.rita_dashboard_ui_dir;
+    HttpResponse::Ok().json(DashboardApiVersion {
+        api_version: DASHBOARD_API_VERSION,
+        supported_api_versions: vec![DASHBOARD_API_VERSION],
+        rita_version: env!("CARGO_PKG_VERSION").to_string(),
+        readable_version: READABLE_VERSION.to_string(),
+        ui_available: ui_dir.map(|d| Path::new(&d).is_dir()).unwrap_or(false),
+    })
+}
+
+/// Maps a request path under /ui to a file in the ui directory, refusing anything that could
+/// escape it
+fn ui_file_path(ui_dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let relative = Path::new(request_path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    if relative.as_os_str().is_empty() {
+        return Some(ui_dir.join("index.html"));
+    }
+    Some(ui_dir.join(relative))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// index.html names the other assets, so it is always revalidated to pick up a new ui after an
+/// upgrade, other assets may be reused for a while without asking
+fn cache_control(path: &Path) -> &'static str {
+    if path.extension().and_then(|e| e.to_str()) == Some("html") {
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    }
+}
+
+/// Serves files from rita_dashboard_ui_dir. Paths without a file extension that do not exist are
+/// answered with index.html so that the ui's own routes survive a page reload
+pub async fn serve_ui(req: HttpRequest) -> HttpResponse {
+    let ui_dir = match settings::get_rita_client().network.rita_dashboard_ui_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => return HttpResponse::NotFound().json("No dashboard ui is installed"),
+    };
+    let request_path = req.match_info().get("path").unwrap_or("");
+    let mut path = match ui_file_path(&ui_dir, request_path) {
+        Some(path) => path,
+        None => return HttpResponse::BadRequest().json("Invalid path"),
+    };
+    if !path.is_file() && path.extension().is_none() {
+        path = ui_dir.join("index.html");
+    }
+
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|m| m.as_secs())
+        .unwrap_or(0);
+    let etag = format!("\"{:x}-{:x}\"", modified, metadata.len());
+
+    if let Some(tag) = req.headers().get(IF_NONE_MATCH) {
+        if tag.as_bytes() == etag.as_bytes() {
+            return HttpResponse::NotModified()
+                .insert_header((ETAG, etag))
+                .insert_header((CACHE_CONTROL, cache_control(&path)))
+                .finish();
+        }
+    }
+
+    match fs::read(&path) {
+        Ok(contents) => HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, content_type(&path)))
+            .insert_header((CACHE_CONTROL, cache_control(&path)))
+            .insert_header((ETAG, etag))
+            .body(contents),
+        Err(e) => {
+            error!("Failed to read dashboard ui file {:?} {:?}", path, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ui_file_path() {
+        let dir = Path::new("/www/ui");
+        assert_eq!(
+            ui_file_path(dir, ""),
+            Some(PathBuf::from("/www/ui/index.html"))
+        );
+        assert_eq!(
+            ui_file_path(dir, "static/js/main.js"),
+            Some(PathBuf::from("/www/ui/static/js/main.js"))
+        );
+        assert_eq!(ui_file_path(dir, "../../etc/rita.toml"), None);
+        assert_eq!(ui_file_path(dir, "static/../../etc/shadow"), None);
+        assert_eq!(
+            ui_file_path(dir, "/etc/shadow"),
+            Some(PathBuf::from("/www/ui/etc/shadow"))
+        );
+    }
+}
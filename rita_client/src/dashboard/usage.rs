@@ -1,3 +1,4 @@
+use crate::guest_network::{get_guest_usage, get_guest_usage_summary};
 use actix_web_async::{HttpRequest, HttpResponse};
 use rita_common::usage_tracker::get_usage_data;
 use rita_common::usage_tracker::structs::UsageType;
@@ -13,3 +14,15 @@ pub async fn get_relay_usage(_req: HttpRequest) -> HttpResponse {
 
     HttpResponse::Ok().json(get_usage_data(UsageType::Relay))
 }
+
+pub async fn get_guest_usage_endpoint(_req: HttpRequest) -> HttpResponse {
+    trace!("/usage/guest hit");
+
+    HttpResponse::Ok().json(get_guest_usage())
+}
+
+pub async fn get_guest_usage_summary_endpoint(_req: HttpRequest) -> HttpResponse {
+    trace!("/usage/guest/summary hit");
+
+    HttpResponse::Ok().json(get_guest_usage_summary())
+}
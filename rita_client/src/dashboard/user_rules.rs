@@ -0,0 +1,132 @@
+//! Endpoints for user static routes and firewall rules, see crate::user_rules
+
+use crate::user_rules::{
+    add_static_route, apply_firewall_rules, remove_static_route, revert_firewall_rules,
+    validate_user_routes,
+};
+use crate::RitaClientError;
+use actix_web_async::http::StatusCode;
+use actix_web_async::{web::Json, HttpRequest, HttpResponse};
+use ipnetwork::IpNetwork;
+use rita_common::{RitaCommonError, KI};
+use settings::user_rules::{FirewallRule, StaticRoute};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteToRemove {
+    pub destination: IpNetwork,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FirewallRuleToRemove {
+    pub name: String,
+}
+
+fn error_response(e: RitaClientError) -> HttpResponse {
+    match e {
+        RitaClientError::MiscStringError(_) | RitaClientError::ValidationError(_) => {
+            HttpResponse::BadRequest().json(format!("{e}"))
+        }
+        _ => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}")),
+    }
+}
+
+fn save_config() -> HttpResponse {
+    if let Err(e) = settings::write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+    HttpResponse::Ok().json(())
+}
+
+pub async fn get_static_routes(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(settings::get_rita_client().static_routes)
+}
+
+/// Adds a static route, replacing any existing route to the same destination
+pub async fn add_static_route_endpoint(route: Json<StaticRoute>) -> HttpResponse {
+    let route = route.into_inner();
+    let mut rita_client = settings::get_rita_client();
+    let mut routes = rita_client.static_routes.clone();
+    let old = routes
+        .iter()
+        .position(|r| r.destination == route.destination)
+        .map(|i| routes.remove(i));
+    routes.push(route.clone());
+    if let Err(e) = validate_user_routes(&routes) {
+        return error_response(e);
+    }
+
+    if let Some(old) = old {
+        if let Err(e) = remove_static_route(&old) {
+            warn!("Failed to remove replaced route {:?} {:?}", old, e);
+        }
+    }
+    if let Err(e) = add_static_route(&route) {
+        error!("Failed to add static route {:?} {:?}", route, e);
+        return error_response(e);
+    }
+
+    rita_client.static_routes = routes;
+    settings::set_rita_client(rita_client);
+    save_config()
+}
+
+pub async fn remove_static_route_endpoint(route: Json<RouteToRemove>) -> HttpResponse {
+    let destination = route.into_inner().destination;
+    let mut rita_client = settings::get_rita_client();
+    let route = match rita_client
+        .static_routes
+        .iter()
+        .position(|r| r.destination == destination)
+    {
+        Some(i) => rita_client.static_routes.remove(i),
+        None => return HttpResponse::NotFound().json(format!("No route to {destination}")),
+    };
+    if let Err(e) = remove_static_route(&route) {
+        // the route may already be gone with its interface, it won't be added back either way
+        warn!("Failed to remove static route {:?} {:?}", route, e);
+    }
+
+    settings::set_rita_client(rita_client);
+    save_config()
+}
+
+pub async fn get_firewall_rules(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(settings::get_rita_client().firewall_rules)
+}
+
+fn set_firewall_rules(rules: Vec<FirewallRule>) -> HttpResponse {
+    if !KI.is_openwrt() {
+        return HttpResponse::BadRequest().json("Firewall rules are only supported on OpenWrt");
+    }
+    if let Err(e) = apply_firewall_rules(&rules) {
+        error!("Failed to apply firewall rules {:?}", e);
+        revert_firewall_rules();
+        return error_response(e);
+    }
+
+    let mut rita_client = settings::get_rita_client();
+    rita_client.firewall_rules = rules;
+    settings::set_rita_client(rita_client);
+    save_config()
+}
+
+/// Adds a firewall rule, replacing any existing rule with the same name. Reloads the firewall
+pub async fn add_firewall_rule(rule: Json<FirewallRule>) -> HttpResponse {
+    let rule = rule.into_inner();
+    let mut rules = settings::get_rita_client().firewall_rules;
+    rules.retain(|r| r.name != rule.name);
+    rules.push(rule);
+    set_firewall_rules(rules)
+}
+
+pub async fn remove_firewall_rule(rule: Json<FirewallRuleToRemove>) -> HttpResponse {
+    let name = rule.into_inner().name;
+    let mut rules = settings::get_rita_client().firewall_rules;
+    let count = rules.len();
+    rules.retain(|r| r.name != name);
+    if rules.len() == count {
+        return HttpResponse::NotFound().json(format!("No firewall rule named {name}"));
+    }
+    set_firewall_rules(rules)
+}
@@ -0,0 +1,75 @@
+//! Prepaid vouchers let users add credit without touching crypto, the user enters the code the operator
+//! gave them here and we pass it along to our exit which validates and records the redemption
+
+use crate::heartbeat::get_selected_exit_server;
+use actix_web_async::http::StatusCode;
+use actix_web_async::web::Json;
+use actix_web_async::HttpResponse;
+use althea_types::SignedVoucher;
+use althea_types::VoucherRedemption;
+use althea_types::VoucherRedemptionResult;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VoucherCode {
+    pub code: String,
+}
+
+pub async fn redeem_voucher(code: Json<VoucherCode>) -> HttpResponse {
+    let voucher = match SignedVoucher::from_code(&code.into_inner().code) {
+        Ok(voucher) => voucher,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+    let our_id = match settings::get_rita_client().get_identity() {
+        Some(id) => id,
+        None => {
+            return HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE)
+                .json("Identity is not ready")
+        }
+    };
+    let exit = match get_selected_exit_server() {
+        Some(exit) => exit,
+        None => return HttpResponse::BadRequest().json("No exit selected"),
+    };
+    let exit_internal_addr = match exit.info.general_details() {
+        Some(details) => details.server_internal_ip,
+        None => return HttpResponse::BadRequest().json("Not registered with the selected exit"),
+    };
+    let exit_port = exit.registration_port;
+    let request = format!("http://{exit_internal_addr}:{exit_port}/redeem_voucher");
+
+    let client = awc::Client::default();
+    let response = client
+        .post(request.clone())
+        .timeout(Duration::from_secs(5))
+        .send_json(&VoucherRedemption {
+            client: our_id,
+            voucher,
+        })
+        .await;
+    let mut response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            error!(
+                "Voucher redemption request to {} failed with {:?}",
+                request, e
+            );
+            return HttpResponse::build(StatusCode::BAD_GATEWAY)
+                .json("Could not contact the exit to redeem voucher");
+        }
+    };
+    if !response.status().is_success() {
+        let message: String = response.json().await.unwrap_or_default();
+        return HttpResponse::BadRequest().json(message);
+    }
+    match response.json::<VoucherRedemptionResult>().await {
+        Ok(result) => {
+            info!("Redeemed voucher {} for {}", result.id, result.amount);
+            HttpResponse::Ok().json(result)
+        }
+        Err(e) => {
+            error!("Failed to parse voucher redemption result {:?}", e);
+            HttpResponse::build(StatusCode::BAD_GATEWAY).json("Invalid response from the exit")
+        }
+    }
+}
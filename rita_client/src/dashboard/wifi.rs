@@ -14,6 +14,7 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
+use crate::exit_manager::get_exit_tunnel_mtu;
 use crate::RitaClientError;
 
 /// legal in the US and around the world, don't allow odd channels
@@ -81,7 +82,7 @@ pub struct WifiDevice {
 /// A string of characters which we don't let users use because of corrupted UCI configs
 static FORBIDDEN_CHARS: &str = "'/\"\\";
 
-static MINIMUM_PASS_CHARS: usize = 8;
+pub static MINIMUM_PASS_CHARS: usize = 8;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Copy)]
 pub enum EncryptionModes {
@@ -241,7 +242,7 @@ pub fn reset_wifi_pass() -> Result<(), RitaClientError> {
     KI.fs_sync()?;
 
     // we have invalidated the old nat rules, update them
-    KI.create_client_nat_rules()?;
+    KI.create_client_nat_rules(get_exit_tunnel_mtu())?;
 
     Ok(())
 }
@@ -423,7 +424,7 @@ pub fn set_wifi_multi_internal(wifi_changes: Vec<WifiToken>) -> HttpResponse {
         });
     }
     // we have invalidated the old nat rules, update them
-    if let Err(e) = KI.create_client_nat_rules() {
+    if let Err(e) = KI.create_client_nat_rules(get_exit_tunnel_mtu()) {
         return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(ErrorJsonResponse {
             error: format!("{e}"),
         });
@@ -611,7 +612,7 @@ pub async fn get_allowed_wifi_channels(radio: Path<String>) -> HttpResponse {
 /// This function checks that a supplied string is non-empty and doesn't contain any of the
 /// `FORBIDDEN_CHARS`. If everything's alright the string itself is moved and returned for
 /// convenience.
-fn validate_config_value(s: &str) -> Result<(), ValidationError> {
+pub fn validate_config_value(s: &str) -> Result<(), ValidationError> {
     if s.is_empty() {
         return Err(ValidationError::Empty);
     }
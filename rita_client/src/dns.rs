@@ -0,0 +1,246 @@
+//! Manages the dns resolver the router offers its lan. dnsmasq does the resolving and caching, this module
+//! points it at the configured upstream. The exit upstream is the long standing behavior, see update_dns_conf
+//! in rita_loop, the others forward to the given servers directly or through a local stubby (dns over tls) or
+//! https-dns-proxy (dns over https) instance. Those packages have to be in the firmware to be used.
+
+use crate::RitaClientError;
+use althea_kernel_interface::KI;
+use settings::dns::{DnsSettings, DnsTlsServer, DnsUpstream};
+use std::path::Path;
+
+const DNSMASQ_SERVERS_KEY: &str = "dhcp.@dnsmasq[0].server";
+const DNSMASQ_NORESOLV_KEY: &str = "dhcp.@dnsmasq[0].noresolv";
+const DNSMASQ_CACHE_SIZE_KEY: &str = "dhcp.@dnsmasq[0].cachesize";
+
+/// dnsmasq refuses cache sizes larger than this
+pub const MAX_DNS_CACHE_SIZE: u32 = 10000;
+
+const STUBBY_LISTEN_ADDRESS: &str = "127.0.0.1@5453";
+const STUBBY_DNSMASQ_SERVER: &str = "127.0.0.1#5453";
+/// Stubby has no way to delete every resolver at once, this bounds the loop doing it one at a time
+const MAX_STUBBY_RESOLVERS: usize = 32;
+
+const HTTPS_DNS_PROXY_SECTION: &str = "https-dns-proxy.@https-dns-proxy[0]";
+const HTTPS_DNS_PROXY_PORT: &str = "5053";
+const HTTPS_DNS_PROXY_DNSMASQ_SERVER: &str = "127.0.0.1#5053";
+
+/// Checks that a value can be written to uci without breaking the config file
+fn validate_uci_value(value: &str) -> Result<(), RitaClientError> {
+    if value.is_empty() {
+        return Err(RitaClientError::MiscStringError(
+            "Empty dns setting".to_string(),
+        ));
+    }
+    if value
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || c == '\'' || c == '"')
+    {
+        return Err(RitaClientError::MiscStringError(format!(
+            "Invalid character in dns setting {value}"
+        )));
+    }
+    Ok(())
+}
+
+pub fn validate_dns_settings(settings: &DnsSettings) -> Result<(), RitaClientError> {
+    if let Some(cache_size) = settings.cache_size {
+        if cache_size > MAX_DNS_CACHE_SIZE {
+            return Err(RitaClientError::MiscStringError(format!(
+                "Dns cache size can be at most {MAX_DNS_CACHE_SIZE}"
+            )));
+        }
+    }
+    match &settings.upstream {
+        DnsUpstream::Exit => {}
+        DnsUpstream::Custom { servers } => {
+            if servers.is_empty() {
+                return Err(RitaClientError::MiscStringError(
+                    "At least one dns server is required".to_string(),
+                ));
+            }
+            if let Some(server) = servers
+                .iter()
+                .find(|s| s.is_unspecified() || s.is_multicast())
+            {
+                return Err(RitaClientError::MiscStringError(format!(
+                    "Invalid dns server {server}"
+                )));
+            }
+        }
+        DnsUpstream::Tls { servers } => {
+            if servers.is_empty() {
+                return Err(RitaClientError::MiscStringError(
+                    "At least one dns server is required".to_string(),
+                ));
+            }
+            for server in servers {
+                validate_uci_value(&server.auth_name)?;
+            }
+        }
+        DnsUpstream::Https { url } => {
+            validate_uci_value(url)?;
+            if !url.starts_with("https://") {
+                return Err(RitaClientError::MiscStringError(format!(
+                    "Dns over https url must start with https:// got {url}"
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_service_installed(service: &str) -> Result<(), RitaClientError> {
+    if !Path::new(&format!("/etc/init.d/{service}")).exists() {
+        return Err(RitaClientError::MiscStringError(format!(
+            "{service} is not installed on this router"
+        )));
+    }
+    Ok(())
+}
+
+/// Forwards every dnsmasq query to these servers and stops it from using resolv.conf
+fn set_dnsmasq_servers(servers: &[&str]) -> Result<(), RitaClientError> {
+    KI.set_uci_list(DNSMASQ_SERVERS_KEY, servers)?;
+    KI.set_uci_var(DNSMASQ_NORESOLV_KEY, "1")?;
+    Ok(())
+}
+
+fn set_stubby_resolvers(servers: &[DnsTlsServer]) -> Result<(), RitaClientError> {
+    check_service_installed("stubby")?;
+    for _ in 0..MAX_STUBBY_RESOLVERS {
+        if KI.del_uci_var("stubby.@resolver[0]").is_err() {
+            break;
+        }
+    }
+    KI.set_uci_var("stubby.global.manual", "0")?;
+    KI.set_uci_list("stubby.global.listen_address", &[STUBBY_LISTEN_ADDRESS])?;
+    for server in servers {
+        KI.add_uci_var("stubby", "resolver")?;
+        KI.set_uci_var("stubby.@resolver[-1].address", &server.address.to_string())?;
+        KI.set_uci_var("stubby.@resolver[-1].tls_auth_name", &server.auth_name)?;
+    }
+    KI.uci_commit("stubby")?;
+    KI.refresh_initd("stubby")?;
+    Ok(())
+}
+
+fn set_https_dns_proxy(url: &str) -> Result<(), RitaClientError> {
+    check_service_installed("https-dns-proxy")?;
+    KI.set_uci_var(&format!("{HTTPS_DNS_PROXY_SECTION}.resolver_url"), url)?;
+    KI.set_uci_var(
+        &format!("{HTTPS_DNS_PROXY_SECTION}.listen_addr"),
+        "127.0.0.1",
+    )?;
+    KI.set_uci_var(
+        &format!("{HTTPS_DNS_PROXY_SECTION}.listen_port"),
+        HTTPS_DNS_PROXY_PORT,
+    )?;
+    KI.uci_commit("https-dns-proxy")?;
+    KI.refresh_initd("https-dns-proxy")?;
+    Ok(())
+}
+
+/// Configures dnsmasq for the dns settings and restarts it if anything changed, only works on OpenWrt
+pub fn apply_dns_settings() -> Result<(), RitaClientError> {
+    let settings = settings::get_rita_client().dns;
+    let mut changed = true;
+    match &settings.upstream {
+        DnsUpstream::Exit => {
+            // noresolv is only ever set by the other upstreams, undo them so dnsmasq goes back to
+            // the resolv.conf servers which point at the exit
+            if KI.get_uci_var(DNSMASQ_NORESOLV_KEY).unwrap_or_default() == "1" {
+                KI.del_uci_var(DNSMASQ_NORESOLV_KEY)?;
+                if let Err(e) = KI.del_uci_var(DNSMASQ_SERVERS_KEY) {
+                    trace!("No dnsmasq servers to delete {:?}", e);
+                }
+            } else {
+                changed = false;
+            }
+        }
+        DnsUpstream::Custom { servers } => {
+            let servers: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+            let servers: Vec<&str> = servers.iter().map(|s| s.as_str()).collect();
+            set_dnsmasq_servers(&servers)?;
+        }
+        DnsUpstream::Tls { servers } => {
+            set_stubby_resolvers(servers)?;
+            set_dnsmasq_servers(&[STUBBY_DNSMASQ_SERVER])?;
+        }
+        DnsUpstream::Https { url } => {
+            set_https_dns_proxy(url)?;
+            set_dnsmasq_servers(&[HTTPS_DNS_PROXY_DNSMASQ_SERVER])?;
+        }
+    }
+    if let Some(cache_size) = settings.cache_size {
+        let cache_size = cache_size.to_string();
+        if KI.get_uci_var(DNSMASQ_CACHE_SIZE_KEY).unwrap_or_default() != cache_size {
+            KI.set_uci_var(DNSMASQ_CACHE_SIZE_KEY, &cache_size)?;
+            changed = true;
+        }
+    }
+    if changed {
+        KI.uci_commit("dhcp")?;
+        KI.openwrt_reset_dnsmasq()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_dns_settings() {
+        assert!(validate_dns_settings(&DnsSettings::default()).is_ok());
+        assert!(validate_dns_settings(&DnsSettings {
+            upstream: DnsUpstream::Custom {
+                servers: vec!["9.9.9.9".parse().unwrap()],
+            },
+            cache_size: Some(1000),
+        })
+        .is_ok());
+        assert!(validate_dns_settings(&DnsSettings {
+            upstream: DnsUpstream::Custom {
+                servers: Vec::new()
+            },
+            cache_size: None,
+        })
+        .is_err());
+        assert!(validate_dns_settings(&DnsSettings {
+            upstream: DnsUpstream::Exit,
+            cache_size: Some(MAX_DNS_CACHE_SIZE + 1),
+        })
+        .is_err());
+        assert!(validate_dns_settings(&DnsSettings {
+            upstream: DnsUpstream::Tls {
+                servers: vec![DnsTlsServer {
+                    address: "1.1.1.1".parse().unwrap(),
+                    auth_name: "cloudflare-dns.com".to_string(),
+                }],
+            },
+            cache_size: None,
+        })
+        .is_ok());
+        assert!(validate_dns_settings(&DnsSettings {
+            upstream: DnsUpstream::Https {
+                url: "https://dns.quad9.net/dns-query".to_string(),
+            },
+            cache_size: None,
+        })
+        .is_ok());
+        assert!(validate_dns_settings(&DnsSettings {
+            upstream: DnsUpstream::Https {
+                url: "http://dns.quad9.net/dns-query".to_string(),
+            },
+            cache_size: None,
+        })
+        .is_err());
+        assert!(validate_dns_settings(&DnsSettings {
+            upstream: DnsUpstream::Https {
+                url: "https://dns.quad9.net/dns-query' evil".to_string(),
+            },
+            cache_size: None,
+        })
+        .is_err());
+    }
+}
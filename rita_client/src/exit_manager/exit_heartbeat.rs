@@ -0,0 +1,52 @@
+//! Sends a small signed udp heartbeat to our exit over the exit tunnel every exit loop tick, this lets
+//! the exit detect outages in seconds rather than waiting for wireguard handshakes to age out. See
+//! althea_types::exit_heartbeat for the packet format
+
+use althea_types::unix_time::now_secs;
+use althea_types::ExitHeartbeatMessage;
+use althea_types::WgKey;
+use althea_types::EXIT_HEARTBEAT_PORT;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+pub fn send_exit_heartbeat(exit_internal_ip: IpAddr, exit_wg_key: WgKey) {
+    let network = settings::get_rita_client().network;
+    let (our_publickey, our_secretkey) = match (network.wg_public_key, network.wg_private_key) {
+        (Some(public), Some(private)) => (public, private.into()),
+        _ => {
+            warn!("No wg keys, can't send exit heartbeat");
+            return;
+        }
+    };
+
+    let message = ExitHeartbeatMessage {
+        timestamp: now_secs(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let packet = message.encrypt(our_publickey, &our_secretkey, &exit_wg_key.into());
+
+    let local = match exit_internal_ip {
+        IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        IpAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = match UdpSocket::bind(local) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't bind exit heartbeat socket {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.set_write_timeout(Some(Duration::from_millis(100))) {
+        trace!("Failed to set socket timeout {:?}, skipping!", e);
+        return;
+    }
+    let remote = SocketAddr::new(exit_internal_ip, EXIT_HEARTBEAT_PORT);
+    match socket.send_to(&packet, remote) {
+        Ok(bytes) => trace!("Sent {} exit heartbeat bytes to {}", bytes, remote),
+        Err(e) => warn!("Failed to send exit heartbeat to {} with {:?}", remote, e),
+    }
+}
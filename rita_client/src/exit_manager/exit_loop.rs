@@ -1,11 +1,14 @@
-use super::exit_switcher::{get_babel_routes, set_best_exit};
+use super::exit_heartbeat::send_exit_heartbeat;
+use super::exit_registry::update_exit_registry;
+use super::exit_split::{query_secondary_exit_debt, tick_exit_split};
+use super::exit_switcher::{follow_exit_migration, get_babel_routes, set_best_exit};
 use super::ExitManager;
 use crate::exit_manager::time_sync::maybe_set_local_to_exit_time;
 use crate::exit_manager::{
-    add_exits_to_exit_server_list, correct_default_route, exit_status_request, get_client_pub_ipv6,
-    get_current_exit, get_exit_list, get_full_selected_exit, get_ready_to_switch_exits,
-    get_routes_hashmap, has_exit_changed, linux_setup_exit_tunnel, remove_nat, restore_nat,
-    set_exit_list,
+    add_exits_to_exit_server_list, apply_kill_switch, correct_default_route, exit_status_request,
+    get_client_pub_ipv6, get_current_exit, get_exit_list, get_full_selected_exit,
+    get_ready_to_switch_exits, get_routes_hashmap, has_exit_changed, linux_setup_exit_tunnel,
+    remove_nat, restore_nat, set_exit_list,
 };
 use crate::traffic_watcher::{query_exit_debts, QueryExitDebts};
 use actix_async::System as AsyncSystem;
@@ -14,6 +17,7 @@ use althea_types::ExitState;
 use futures::future::join_all;
 use futures::join;
 use rita_common::blockchain_oracle::low_balance;
+use rita_common::scheduler::Schedule;
 use rita_common::KI;
 
 use std::thread;
@@ -23,6 +27,8 @@ const EXIT_LOOP_SPEED: Duration = Duration::from_secs(5);
 /// How often we make a exit status request for registered exits. Prevents us from bogging up exit processing
 /// power
 const STATUS_REQUEST_QUERY: Duration = Duration::from_secs(600);
+/// Longest we back off exit status requests for while every exit fails them
+const MAX_STATUS_REQUEST_BACKOFF: Duration = Duration::from_secs(3600);
 
 /// This asnyc loop runs functions related to Exit management.
 pub fn start_exit_manager_loop() {
@@ -45,7 +51,9 @@ pub fn start_exit_manager_loop() {
                         // and manages the exit state machine in general. This includes
                         // updates to the local ip and description from the exit side
                         info!("Exit_Switcher: exit manager tick");
-                        let client_can_use_free_tier = { settings::get_rita_client().payment.client_can_use_free_tier };
+                        let client_can_use_free_tier = settings::get_rita_client_snapshot().payment.client_can_use_free_tier;
+                        apply_kill_switch();
+                        update_exit_registry(em_state).await;
                         //  Get mut rita client to setup exits
                         let rita_client = settings::get_rita_client();
                         let current_exit = get_current_exit();
@@ -65,7 +73,7 @@ pub fn start_exit_manager_loop() {
                             if let Some(general_details) = exit.clone().info.general_details() {
                                 info!("We have details for the selected exit!");
                                 // Logic to determnine what the best exit is and if we should switch
-                                let babel_port = settings::get_rita_client().network.babel_port;
+                                let babel_port = settings::get_rita_client_snapshot().network.babel_port;
                                 let routes = match get_babel_routes(babel_port) {
                                     Ok(a) => a,
                                     Err(_) => {
@@ -85,9 +93,7 @@ pub fn start_exit_manager_loop() {
                                     Err(e) => {
                                         error!("Exit_Switcher: Unable to get exit list: {:?}", e);
 
-                                        ExitListV2 {
-                                            exit_list: Vec::new(),
-                                        }
+                                        ExitListV2::default()
                                     }
                                 };
                                 info!(
@@ -113,7 +119,7 @@ pub fn start_exit_manager_loop() {
                                 info!("Exit_Switcher: Calling set best exit");
                                 trace!("Using exit list: {:?}", exit_list);
                                 let selected_exit =
-                                    match set_best_exit(get_ready_to_switch_exits(exit_list.clone()), ip_route_hashmap) {
+                                    match set_best_exit(get_ready_to_switch_exits(exit_list.clone()), ip_route_hashmap.clone()) {
                                         Ok(a) => Some(a),
                                         Err(e) => {
                                             warn!("Found no exit yet : {}", e);
@@ -121,6 +127,8 @@ pub fn start_exit_manager_loop() {
                                             continue;
                                         }
                                     };
+                                // an exit being decommissioned tells us where to go, that takes precedence over the metrics
+                                let selected_exit = follow_exit_migration(&ip_route_hashmap).or(selected_exit);
                                 info!("Exit_Switcher: After selecting best exit this tick, we have selected_exit_details: {:?}", get_full_selected_exit());
 
                                 // Set last state vairables
@@ -204,8 +212,9 @@ pub fn start_exit_manager_loop() {
                                     let exit_internal_addr = general_details.clone().server_internal_ip;
                                     let exit_port = exit.registration_port;
                                     let exit_id = exit.exit_id;
-                                    let babel_port = settings::get_rita_client().network.babel_port;
+                                    let babel_port = settings::get_rita_client_snapshot().network.babel_port;
                                     info!("We are signed up for the selected exit!");
+                                    send_exit_heartbeat(exit_internal_addr, exit_id.wg_public_key);
                                     let routes = match get_babel_routes(babel_port) {
                                         Ok(a) => a,
                                         Err(_) => {
@@ -222,14 +231,22 @@ pub fn start_exit_manager_loop() {
                                         exit_port,
                                     })
                                     .await;
+                                    query_secondary_exit_debt().await;
                                 }
                             }
                         }
                     }
+                        // sends a share of new flows to the secondary exit, if one is configured
+                        tick_exit_split(get_current_exit());
+
                         // code that manages requesting details to exits, run in parallel becuse they respond slowly
                         let mut general_requests = Vec::new();
                         let mut status_requests = Vec::new();
-                        let mut exit_status_requested = false;
+                        let mut checkin_requests = Vec::new();
+                        let checkin_due = em_state
+                            .status_schedule
+                            .get_or_insert_with(|| Schedule::new("exit_status", STATUS_REQUEST_QUERY, MAX_STATUS_REQUEST_BACKOFF))
+                            .is_due();
                         let servers = { settings::get_rita_client().exit_client.exits };
                         for (k, s) in servers {
                             match s.info {
@@ -247,15 +264,10 @@ pub fn start_exit_manager_loop() {
                                 },
                                 ExitState::Registered { .. } => {
                                     trace!("Exit {} is in state Registered, calling status request", k);
-                                    // Make a status request every STATUS_REQUEST_QUERY seconds 
-                                    if let Some(last_query) = em_state.last_status_request {
-                                        if Instant::now() - last_query > STATUS_REQUEST_QUERY {
-                                            exit_status_requested = true;
-                                            status_requests.push(exit_status_request(k));
-                                        }
-                                    } else {
-                                        exit_status_requested = true;
-                                        status_requests.push(exit_status_request(k));
+                                    // Make a status request every STATUS_REQUEST_QUERY seconds, at this router's
+                                    // slot so that registered clients don't all check in with the exit at once
+                                    if checkin_due {
+                                        checkin_requests.push(exit_status_request(k));
                                     }
                                 },
                                 _ => {
@@ -263,10 +275,15 @@ pub fn start_exit_manager_loop() {
                                 }
                             }
                         }
-                        if exit_status_requested {
-                            em_state.last_status_request = Some(Instant::now());
+                        let (_, _, checkins) = join!(join_all(general_requests), join_all(status_requests), join_all(checkin_requests));
+                        if let Some(schedule) = em_state.status_schedule.as_mut() {
+                            if checkins.iter().any(|r| r.is_ok()) {
+                                schedule.succeeded();
+                            } else if !checkins.is_empty() {
+                                warn!("Every exit status request failed, backing off");
+                                schedule.failed();
+                            }
                         }
-                        join!(join_all(general_requests), join_all(status_requests));
 
                         // This block runs after an exit manager tick (an exit is selected),
                         // and looks at the ipv6 subnet assigned to our router in the ExitState struct
@@ -0,0 +1,241 @@
+//! Fetches the operator's signed exit registry, see althea_types::exit_registry, and merges the exits in
+//! it into our exit list. Nothing from a fetch is used unless the whole registry checks out, a registry
+//! that is badly signed, malformed, empty or older than the one we last took is thrown away and we keep
+//! going with the exits we already have until the operator publishes a good one. The version we took
+//! is saved with the settings so it holds across reboots.
+
+use super::ExitManager;
+use crate::rita_loop::CLIENT_LOOP_TIMEOUT;
+use crate::RitaClientError;
+use althea_types::{exit_identity_to_id, ExitRegistry, ExitState, SignedExitRegistry};
+use settings::client::ExitServer;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+async fn fetch_exit_registry(url: &str) -> Result<SignedExitRegistry, RitaClientError> {
+    let client = awc::Client::default();
+    let mut response = match client.get(url).timeout(CLIENT_LOOP_TIMEOUT).send().await {
+        Ok(a) => a,
+        Err(e) => return Err(RitaClientError::SendRequestError(e.to_string())),
+    };
+    if !response.status().is_success() {
+        return Err(RitaClientError::MiscStringError(format!(
+            "Exit registry request failed with {}",
+            response.status()
+        )));
+    }
+    Ok(response.json().await?)
+}
+
+/// Checks a registry is newer than the one we last took and sane enough to merge
+fn check_registry(registry: &ExitRegistry, last_version: Option<u64>) -> Result<(), String> {
+    if let Some(last_version) = last_version {
+        if registry.version < last_version {
+            return Err(format!(
+                "Registry version {} is older than {}",
+                registry.version, last_version
+            ));
+        }
+    }
+    if registry.exits.is_empty() {
+        return Err("Registry has no exits".to_string());
+    }
+    let mut mesh_ips = HashSet::new();
+    let mut wg_keys = HashSet::new();
+    for exit in registry.exits.iter() {
+        if !clu::validate_mesh_ip(&exit.mesh_ip) {
+            return Err(format!("Invalid exit mesh ip {}", exit.mesh_ip));
+        }
+        if exit.registration_port == 0 || exit.wg_exit_listen_port == 0 {
+            return Err(format!("Exit {} has no ports", exit.mesh_ip));
+        }
+        if !mesh_ips.insert(exit.mesh_ip) || !wg_keys.insert(exit.wg_key) {
+            return Err(format!("Exit {} is listed twice", exit.mesh_ip));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the registry's exits we don't know about and updates the ports of those we do. An exit listed
+/// with a different identity than ours has been rekeyed and has to be signed up with again. Returns
+/// whether anything changed
+fn merge_registry(exits: &mut HashMap<IpAddr, ExitServer>, registry: &ExitRegistry) -> bool {
+    let mut changed = false;
+    for entry in registry.exits.iter() {
+        let exit_id = exit_identity_to_id(entry.clone());
+        match exits.get_mut(&entry.mesh_ip) {
+            Some(exit) => {
+                if exit.exit_id != exit_id {
+                    info!("Exit {} was rekeyed by the registry", entry.mesh_ip);
+                    exit.exit_id = exit_id;
+                    exit.info = ExitState::New;
+                    changed = true;
+                }
+                if exit.registration_port != entry.registration_port
+                    || exit.wg_exit_listen_port != entry.wg_exit_listen_port
+                {
+                    exit.registration_port = entry.registration_port;
+                    exit.wg_exit_listen_port = entry.wg_exit_listen_port;
+                    changed = true;
+                }
+            }
+            None => {
+                info!("Adding exit {} from the registry", entry.mesh_ip);
+                exits.insert(
+                    entry.mesh_ip,
+                    ExitServer {
+                        exit_id,
+                        registration_port: entry.registration_port,
+                        wg_exit_listen_port: entry.wg_exit_listen_port,
+                        tunnel_mtu: None,
+                        persistent_keepalive: None,
+                        info: ExitState::New,
+                    },
+                );
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Called every exit manager tick, fetches the registry once every refresh_interval
+pub async fn update_exit_registry(em_state: &mut ExitManager) {
+    let registry_settings = match settings::get_rita_client().exit_client.exit_registry {
+        Some(registry_settings) => registry_settings,
+        None => return,
+    };
+    if let Some(last_fetch) = em_state.last_registry_fetch {
+        if last_fetch.elapsed() < Duration::from_secs(registry_settings.refresh_interval) {
+            return;
+        }
+    }
+    em_state.last_registry_fetch = Some(Instant::now());
+
+    let signed = match fetch_exit_registry(&registry_settings.url).await {
+        Ok(signed) => signed,
+        Err(e) => {
+            warn!("Failed to fetch the exit registry {}", e);
+            return;
+        }
+    };
+    let registry = match signed.open(registry_settings.signer) {
+        Ok(registry) => registry,
+        Err(e) => {
+            error!("Rejected exit registry, keeping our exits {}", e);
+            return;
+        }
+    };
+    if let Err(e) = check_registry(&registry, registry_settings.version) {
+        error!("Rejected exit registry, keeping our exits {}", e);
+        return;
+    }
+
+    let mut rita_client = settings::get_rita_client();
+    let merged = merge_registry(&mut rita_client.exit_client.exits, &registry);
+    if merged {
+        info!("Merged exit registry version {}", registry.version);
+    }
+    if merged || registry_settings.version != Some(registry.version) {
+        if let Some(registry_settings) = rita_client.exit_client.exit_registry.as_mut() {
+            registry_settings.version = Some(registry.version);
+        }
+        settings::set_rita_client(rita_client);
+        if let Err(e) = settings::write_config() {
+            error!("Failed to save exit registry version {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::ExitIdentity;
+
+    fn exit(mesh_ip: &str, key: &str, port: u16) -> ExitIdentity {
+        ExitIdentity {
+            mesh_ip: mesh_ip.parse().unwrap(),
+            wg_key: key.parse().unwrap(),
+            eth_addr: "0x0000000000000000000000000000000000000002"
+                .parse()
+                .unwrap(),
+            registration_port: port,
+            wg_exit_listen_port: 59998,
+            allowed_regions: HashSet::new(),
+            payment_types: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn test_check_and_merge_registry() {
+        let first = exit(
+            "fd00::1",
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=",
+            4875,
+        );
+        let second = exit(
+            "fd00::2",
+            "bvM10HW73yePrxdtCQQ4U20W5ogogdiZtUihrPc/oGY=",
+            4875,
+        );
+        let registry = ExitRegistry {
+            version: 2,
+            timestamp: 0,
+            exits: vec![first.clone(), second],
+        };
+        assert!(check_registry(&registry, None).is_ok());
+        assert!(check_registry(&registry, Some(2)).is_ok());
+        assert!(check_registry(&registry, Some(3)).is_err());
+        let duplicate = ExitRegistry {
+            version: 3,
+            timestamp: 0,
+            exits: vec![first.clone(), first.clone()],
+        };
+        assert!(check_registry(&duplicate, None).is_err());
+        let empty = ExitRegistry {
+            version: 3,
+            timestamp: 0,
+            exits: Vec::new(),
+        };
+        assert!(check_registry(&empty, None).is_err());
+
+        let mut exits = HashMap::new();
+        assert!(merge_registry(&mut exits, &registry));
+        assert_eq!(exits.len(), 2);
+        // nothing changes the second time around
+        assert!(!merge_registry(&mut exits, &registry));
+
+        // an exit keeps its state when only its port moves
+        exits.get_mut(&first.mesh_ip).unwrap().info = ExitState::Denied {
+            message: String::new(),
+            code: None,
+        };
+        let moved = ExitRegistry {
+            version: 3,
+            timestamp: 0,
+            exits: vec![exit(
+                "fd00::1",
+                "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=",
+                4876,
+            )],
+        };
+        assert!(merge_registry(&mut exits, &moved));
+        let merged = &exits[&first.mesh_ip];
+        assert_eq!(merged.registration_port, 4876);
+        assert!(matches!(merged.info, ExitState::Denied { .. }));
+
+        // but starts over when it was rekeyed
+        let rekeyed = ExitRegistry {
+            version: 4,
+            timestamp: 0,
+            exits: vec![exit(
+                "fd00::1",
+                "bvM10HW73yePrxdtCQQ4U20W5ogogdiZtUihrPc/oGY=",
+                4876,
+            )],
+        };
+        assert!(merge_registry(&mut exits, &rekeyed));
+        assert_eq!(exits[&first.mesh_ip].info, ExitState::New);
+    }
+}
@@ -0,0 +1,263 @@
+//! Sends a share of the lan traffic through a second exit so that operators can move load between exits
+//! gradually instead of switching every router at once. The selected exit stays the primary, it carries
+//! ipv6, billing and heartbeats, and exit_client.exit_split names a secondary exit we are registered with
+//! and the percent of new flows to send to it. Each exit manager tick the split is checked against the
+//! current exits and set up or taken down in the kernel, see althea_kernel_interface::exit_split, and
+//! only when it changes. The secondary exit bills us for its share like the selected exit does, we pay
+//! whatever it reports through /client_debt. The kill switch only lets traffic out over wg_exit so the
+//! two can't be used together.
+
+use crate::bandwidth_contract::reset_applied_limits;
+use crate::traffic_watcher::query_split_exit_debt;
+use crate::RitaClientError;
+use althea_kernel_interface::exit_split::ExitSplitConfig;
+use rita_common::KI;
+use settings::client::{ExitServer, ExitSplitSettings, RitaClientSettings};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    static ref EXIT_SPLIT: Arc<RwLock<ExitSplitState>> =
+        Arc::new(RwLock::new(ExitSplitState::default()));
+}
+
+#[derive(Default)]
+struct ExitSplitState {
+    /// The config last set up in the kernel, None if the split is down
+    applied: Option<ExitSplitConfig>,
+    status: ExitSplitStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExitSplitStatus {
+    pub primary: Option<IpAddr>,
+    pub secondary: Option<IpAddr>,
+    /// Percent of new flows sent to the secondary exit
+    pub weight: u8,
+    /// If the split is set up and the secondary exit is carrying traffic
+    pub active: bool,
+    /// Why the configured split isn't active
+    pub error: Option<String>,
+}
+
+/// Checks a split against the exits we know, `primary` is the selected exit
+pub fn validate_split(
+    split: &ExitSplitSettings,
+    primary: Option<IpAddr>,
+    exits: &HashMap<IpAddr, ExitServer>,
+    kill_switch: bool,
+) -> Result<(), RitaClientError> {
+    let invalid = |message: String| Err(RitaClientError::MiscStringError(message));
+    if split.weight == 0 || split.weight >= 100 {
+        return invalid(format!("Split weight {} is not 1 to 99", split.weight));
+    }
+    if kill_switch {
+        return invalid("The kill switch only allows traffic out over wg_exit".to_string());
+    }
+    match primary {
+        None => return invalid("No exit is selected".to_string()),
+        Some(primary) if primary == split.secondary => {
+            return invalid(format!("{} is already the selected exit", split.secondary))
+        }
+        Some(_) => {}
+    }
+    match exits.get(&split.secondary) {
+        None => invalid(format!("Unknown exit {}", split.secondary)),
+        Some(exit) if exit.info.our_details().is_none() => {
+            invalid(format!("Not registered with exit {}", split.secondary))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// The kernel config for a split that passed validate_split
+fn split_config(
+    split: &ExitSplitSettings,
+    exit: &ExitServer,
+    rita_client: &RitaClientSettings,
+) -> Option<ExitSplitConfig> {
+    let our_details = exit.info.our_details()?;
+    Some(ExitSplitConfig {
+        endpoint: SocketAddr::new(exit.exit_id.mesh_ip, exit.wg_exit_listen_port),
+        pubkey: exit.exit_id.wg_public_key,
+        private_key_path: rita_client.network.wg_private_key_path.clone(),
+        // the port under wg_exit's, mesh tunnels start above it
+        listen_port: rita_client.exit_client.wg_listen_port.saturating_sub(1),
+        local_ip: our_details.client_internal_ip,
+        mtu: rita_client.exit_client.tunnel_mtu_for(exit),
+        persistent_keepalive: rita_client.exit_client.persistent_keepalive_for(exit),
+        weight: split.weight,
+    })
+}
+
+/// Run every exit manager tick with the selected exit, sets up, changes or takes down the split
+pub fn tick_exit_split(primary: Option<IpAddr>) {
+    let rita_client = settings::get_rita_client_snapshot();
+    let exit_client = &rita_client.exit_client;
+    let split = exit_client.exit_split;
+    let desired = match split {
+        None => Ok(None),
+        Some(split) => validate_split(&split, primary, &exit_client.exits, exit_client.kill_switch)
+            .map(|()| split_config(&split, &exit_client.exits[&split.secondary], &rita_client)),
+    };
+
+    let mut state = EXIT_SPLIT.write().unwrap();
+    let error = match desired {
+        Ok(Some(config)) => {
+            if state.applied.as_ref() == Some(&config) {
+                None
+            } else {
+                info!(
+                    "Sending {}% of new flows to exit {}",
+                    config.weight, config.endpoint
+                );
+                // a new wg_exit_split has no qdisc and the split changes the upload shares
+                reset_applied_limits();
+                match KI.set_exit_split(&config) {
+                    Ok(()) => {
+                        state.applied = Some(config);
+                        None
+                    }
+                    Err(e) => {
+                        error!("Failed to set up the exit split {:?}", e);
+                        state.applied = None;
+                        Some(format!("Failed to set up the split {e}"))
+                    }
+                }
+            }
+        }
+        Ok(None) => {
+            take_down_split(&mut state);
+            None
+        }
+        Err(e) => {
+            take_down_split(&mut state);
+            Some(e.to_string())
+        }
+    };
+    if let Some(e) = &error {
+        warn!("Exit split is not active {}", e);
+    }
+    state.status = ExitSplitStatus {
+        primary,
+        secondary: split.map(|s| s.secondary),
+        weight: split.map(|s| s.weight).unwrap_or(0),
+        active: state.applied.is_some(),
+        error,
+    };
+}
+
+fn take_down_split(state: &mut ExitSplitState) {
+    if state.applied.take().is_some() {
+        info!("Sending all traffic to the selected exit again");
+        reset_applied_limits();
+        if let Err(e) = KI.clear_exit_split() {
+            error!("Failed to take down the exit split {:?}", e);
+        }
+    }
+}
+
+/// Run every exit manager tick after the selected exit's debts, gets our debt from the secondary exit
+/// while the split is active
+pub async fn query_secondary_exit_debt() {
+    let secondary = match get_exit_split_status() {
+        ExitSplitStatus {
+            active: true,
+            secondary: Some(secondary),
+            ..
+        } => secondary,
+        _ => return,
+    };
+    let exits = settings::get_rita_client().exit_client.exits;
+    let exit = match exits.get(&secondary) {
+        Some(exit) => exit.clone(),
+        None => return,
+    };
+    query_split_exit_debt(exit.exit_id, exit.registration_port).await;
+}
+
+pub fn get_exit_split_status() -> ExitSplitStatus {
+    EXIT_SPLIT.read().unwrap().status.clone()
+}
+
+/// The percent of new flows an exit gets, None for exits that get none
+pub fn traffic_share(exit: IpAddr, selected: bool) -> Option<u8> {
+    let status = get_exit_split_status();
+    match (status.active, selected) {
+        (true, true) => Some(100 - status.weight),
+        (true, false) if status.secondary == Some(exit) => Some(status.weight),
+        (false, true) => Some(100),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::{ExitClientDetails, ExitDetails, ExitState, ExitVerifMode, Identity};
+
+    fn exit(info: ExitState) -> ExitServer {
+        ExitServer {
+            exit_id: Identity {
+                mesh_ip: "fd00::5".parse().unwrap(),
+                eth_address: "0x0000000000000000000000000000000000000001"
+                    .parse()
+                    .unwrap(),
+                wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                nickname: None,
+            },
+            registration_port: 4875,
+            wg_exit_listen_port: 59998,
+            tunnel_mtu: None,
+            persistent_keepalive: None,
+            info,
+        }
+    }
+
+    #[test]
+    fn test_validate_split() {
+        let primary: IpAddr = "fd00::4".parse().unwrap();
+        let secondary: IpAddr = "fd00::5".parse().unwrap();
+        let registered = ExitState::Registered {
+            general_details: ExitDetails {
+                server_internal_ip: "172.168.0.1".parse().unwrap(),
+                netmask: 16,
+                wg_exit_port: 59998,
+                exit_price: 0,
+                exit_currency: althea_types::SystemChain::Xdai,
+                description: String::new(),
+                verif_mode: ExitVerifMode::Off,
+                signup_challenge: None,
+            },
+            our_details: ExitClientDetails {
+                client_internal_ip: "172.168.0.2".parse().unwrap(),
+                internet_ipv6_subnet: None,
+            },
+            message: String::new(),
+            version_status: None,
+            protocol_version: Some(2),
+            migration: None,
+        };
+        let mut exits = HashMap::new();
+        exits.insert(secondary, exit(registered));
+        let split = ExitSplitSettings {
+            secondary,
+            weight: 25,
+        };
+        assert!(validate_split(&split, Some(primary), &exits, false).is_ok());
+        assert!(validate_split(&split, Some(primary), &exits, true).is_err());
+        assert!(validate_split(&split, None, &exits, false).is_err());
+        assert!(validate_split(&split, Some(secondary), &exits, false).is_err());
+        let heavy = ExitSplitSettings {
+            secondary,
+            weight: 100,
+        };
+        assert!(validate_split(&heavy, Some(primary), &exits, false).is_err());
+
+        exits.insert(secondary, exit(ExitState::New));
+        assert!(validate_split(&split, Some(primary), &exits, false).is_err());
+    }
+}
@@ -11,11 +11,16 @@
 //! 4.) Switch only if another exit has been considered better than our current exit for an extended period of time.
 //!
 //! See doc comment for 'set_best_exit' for a more detailed description of workflow
-use crate::exit_manager::{get_full_selected_exit, reset_exit_blacklist, set_selected_exit};
+use crate::exit_manager::{
+    get_current_exit, get_full_selected_exit, reset_exit_blacklist, set_selected_exit,
+};
 use crate::rita_loop::CLIENT_LOOP_TIMEOUT;
 use crate::RitaClientError;
+use althea_types::ExitState;
 use althea_types::Identity;
-use babel_monitor::{open_babel_stream, parse_routes, structs::Route};
+use babel_monitor::pool::get_babel_stream;
+use babel_monitor::{parse_routes, structs::Route};
+use rita_common::events::{publish_event, RitaEvent};
 use rita_common::FAST_LOOP_SPEED;
 use settings::client::ExitSwitchingCode;
 use settings::client::SelectedExit;
@@ -354,6 +359,12 @@ fn set_exit_state(
         }
         ExitSwitchingCode::SwitchExit => {
             // We swtich to the new exit
+            if let Some(to) = exit_metrics.best_exit {
+                publish_event(RitaEvent::ExitSwitched {
+                    from: exit_metrics.cur_exit,
+                    to,
+                });
+            }
             set_selected_exit(SelectedExit {
                 selected_id: exit_metrics.best_exit,
                 selected_id_metric: Some(exit_metrics.best_exit_met),
@@ -382,6 +393,64 @@ fn set_exit_state(
     }
 }
 
+/// Moves us to the replacement our exit advertised when it is being decommissioned, as soon as we are
+/// registered with the replacement and babel has a route to it. Unlike a normal switch this doesn't wait
+/// for the replacement to be the better exit, the old one is going away regardless. Returns the exit we
+/// switched to
+pub fn follow_exit_migration(route_hashmap: &HashMap<IpAddr, Route>) -> Option<IpAddr> {
+    let current_exit = get_current_exit()?;
+    let exits = settings::get_rita_client().exit_client.exits;
+    let migration = match &exits.get(&current_exit)?.info {
+        ExitState::Registered {
+            migration: Some(migration),
+            ..
+        } => migration.clone(),
+        _ => return None,
+    };
+    let to = migration.replacement.mesh_ip;
+    if to == current_exit {
+        return None;
+    }
+    if !matches!(
+        exits.get(&to).map(|e| &e.info),
+        Some(ExitState::Registered { .. })
+    ) {
+        info!(
+            "Exit_Switcher: {} is migrating to {}, waiting until we are registered there",
+            current_exit, to
+        );
+        return None;
+    }
+    let route = match route_hashmap.get(&to) {
+        Some(route) => route,
+        None => {
+            warn!(
+                "Exit_Switcher: {} is migrating to {} but we have no route to it",
+                current_exit, to
+            );
+            return None;
+        }
+    };
+
+    info!(
+        "Exit_Switcher: following the migration of {} to {}",
+        current_exit, to
+    );
+    publish_event(RitaEvent::ExitSwitched {
+        from: Some(current_exit),
+        to,
+    });
+    set_selected_exit(SelectedExit {
+        selected_id: Some(to),
+        selected_id_metric: Some(route.metric),
+        selected_id_degradation: None,
+        tracking_exit: Some(to),
+    });
+    METRIC_VALUES.write().unwrap().clear();
+    reset_exit_tracking(&mut EXIT_TRACKER.write().unwrap());
+    Some(to)
+}
+
 /// This function loops through all the routes advertised through babel and searches for 3 particular exits:
 ///
 /// 1.) Current Exit we are connected to, if there is one
@@ -713,7 +782,7 @@ fn calculate_average(vals: Vec<u16>) -> u16 {
 /// Simple helper function that opens a babel stream to get all routes related to us. We can use these routes to
 /// check which ips are exits and thereby register or setup exits
 pub fn get_babel_routes(babel_port: u16) -> Result<Vec<Route>, RitaClientError> {
-    let mut stream = match open_babel_stream(babel_port, CLIENT_LOOP_TIMEOUT) {
+    let mut stream = match get_babel_stream(babel_port, CLIENT_LOOP_TIMEOUT) {
         Ok(a) => a,
         Err(_) => {
             return Err(RitaClientError::MiscStringError(
@@ -973,6 +1042,7 @@ mod tests {
             full_path_rtt: 10.0,
             price: 10,
             fee: 10,
+            src_prefix: None,
         };
 
         let exit2 = Route {
@@ -987,6 +1057,7 @@ mod tests {
             full_path_rtt: 10.0,
             price: 10,
             fee: 10,
+            src_prefix: None,
         };
 
         let exit3 = Route {
@@ -1001,6 +1072,7 @@ mod tests {
             full_path_rtt: 10.0,
             price: 10,
             fee: 10,
+            src_prefix: None,
         };
 
         let not_exit = Route {
@@ -1015,6 +1087,7 @@ mod tests {
             full_path_rtt: 10.0,
             price: 10,
             fee: 10,
+            src_prefix: None,
         };
 
         //let routes = vec![exit1, exit2, exit3, not_exit];
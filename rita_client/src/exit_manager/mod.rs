@@ -16,43 +16,54 @@
 //!
 //! Signup is complete and the user may use the connection
 
+pub mod exit_heartbeat;
 pub mod exit_loop;
+pub mod exit_registry;
+pub mod exit_split;
 pub mod exit_switcher;
 pub mod time_sync;
 
+use crate::bandwidth_contract::{effective_download_limit, reset_applied_limits};
 use crate::heartbeat::get_selected_exit_server;
 use crate::rita_loop::CLIENT_LOOP_TIMEOUT;
 use crate::RitaClientError;
+use actix_web_async::web;
 use actix_web_async::Result;
 use althea_kernel_interface::{
     exit_client_tunnel::ClientExitTunnelConfig, DefaultRoute, KernelInterfaceError,
 };
 use althea_types::exit_identity_to_id;
+use althea_types::ClientVersionStatus;
 use althea_types::ExitClientDetails;
 use althea_types::ExitListV2;
 use althea_types::Identity;
+use althea_types::SignupProof;
 use althea_types::WgKey;
+use althea_types::EXIT_PROTOCOL_V2;
 use althea_types::{EncryptedExitClientIdentity, EncryptedExitState};
 use althea_types::{EncryptedExitList, ExitDetails};
 use althea_types::{ExitClientIdentity, ExitRegistrationDetails, ExitState};
 use babel_monitor::structs::Route;
 use ipnetwork::IpNetwork;
+use rita_common::extensions::local_extensions;
+use rita_common::scheduler::Schedule;
 use rita_common::KI;
 use settings::client::{ExitServer, SelectedExit};
 use settings::get_rita_client;
 use settings::set_rita_client;
-use sodiumoxide::crypto::box_;
-use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::Nonce;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
 use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 /// The number of times ExitSwitcher will try to connect to an unresponsive exit before blacklisting its ip
 const MAX_BLACKLIST_STRIKES: u16 = 100;
 
+/// Exit protocol versions we can speak, this client only tunnels over wg_exit_v2
+pub const SUPPORTED_EXIT_PROTOCOL_VERSIONS: [u32; 1] = [EXIT_PROTOCOL_V2];
+
 lazy_static! {
     pub static ref SELECTED_EXIT_DETAILS: Arc<RwLock<SelectedExitDetails>> =
         Arc::new(RwLock::new(SelectedExitDetails::default()));
@@ -99,7 +110,10 @@ pub struct ExitManager {
     pub exit_list: ExitListV2,
     /// Store last exit here, when we see an exit change, we reset wg tunnels
     pub last_exit_state: LastExitStates,
-    pub last_status_request: Option<Instant>,
+    /// When registered exits are next asked for our status, None until the first tick
+    pub status_schedule: Option<Schedule>,
+    /// When we last fetched the operator's exit registry
+    pub last_registry_fetch: Option<Instant>,
 }
 
 /// This functions sets the exit list ONLY IF the list arguments provived is not empty. This is need for the following edge case:
@@ -155,6 +169,7 @@ fn linux_setup_exit_tunnel(
     }
 
     let selected_exit = get_selected_exit_server().expect("There should be a selected exit here");
+    let tunnel_mtu = rita_client.exit_client.tunnel_mtu_for(&selected_exit);
     let args = ClientExitTunnelConfig {
         endpoint: SocketAddr::new(
             selected_exit.exit_id.mesh_ip,
@@ -166,7 +181,11 @@ fn linux_setup_exit_tunnel(
         local_ip: our_details.client_internal_ip,
         netmask: general_details.netmask,
         rita_hello_port: network.rita_hello_port,
-        user_specified_speed: network.user_bandwidth_limit,
+        user_specified_speed: effective_download_limit(network.user_bandwidth_limit),
+        mtu: tunnel_mtu,
+        persistent_keepalive: rita_client
+            .exit_client
+            .persistent_keepalive_for(&selected_exit),
     };
 
     info!("Args while setting up wg_exit on client are: {:?}", args);
@@ -175,14 +194,25 @@ fn linux_setup_exit_tunnel(
     settings::set_rita_client(rita_client);
 
     KI.set_client_exit_tunnel_config(args, local_mesh_ip)?;
+    // wg_exit was just set up again without the contract's upload limit
+    reset_applied_limits();
     KI.set_route_to_tunnel(&general_details.server_internal_ip)?;
     KI.set_ipv6_route_to_tunnel()?;
 
-    KI.create_client_nat_rules()?;
+    KI.create_client_nat_rules(tunnel_mtu)?;
 
     Ok(())
 }
 
+/// The mtu wg_exit is set to, the selected exit's override if it has one
+pub fn get_exit_tunnel_mtu() -> usize {
+    let rita_client = settings::get_rita_client_snapshot();
+    match get_selected_exit_server() {
+        Some(exit) => rita_client.exit_client.tunnel_mtu_for(&exit),
+        None => rita_client.exit_client.tunnel_mtu,
+    }
+}
+
 fn restore_nat() {
     if let Err(e) = KI.restore_client_nat() {
         error!("Failed to restore client nat! {:?}", e);
@@ -195,6 +225,15 @@ fn remove_nat() {
     }
 }
 
+/// Makes sure the kill switch firewall rules match the settings, run every tick since a firewall
+/// reload drops them
+pub fn apply_kill_switch() {
+    let enabled = settings::get_rita_client_snapshot().exit_client.kill_switch;
+    if let Err(e) = KI.set_client_kill_switch(enabled) {
+        error!("Failed to apply exit kill switch {:?}", e);
+    }
+}
+
 fn encrypt_exit_client_id(
     exit_pubkey: &PublicKey,
     id: ExitClientIdentity,
@@ -206,17 +245,7 @@ fn encrypt_exit_client_id(
         .expect("No private key?")
         .into();
 
-    let plaintext = serde_json::to_string(&id)
-        .expect("Failed to serialize ExitState!")
-        .into_bytes();
-    let nonce = box_::gen_nonce();
-    let ciphertext = box_::seal(&plaintext, &nonce, exit_pubkey, &our_secretkey);
-
-    EncryptedExitClientIdentity {
-        nonce: nonce.0,
-        pubkey: our_publickey,
-        encrypted_exit_client_id: ciphertext,
-    }
+    EncryptedExitClientIdentity::seal(&id, our_publickey, &our_secretkey, exit_pubkey)
 }
 
 /// Blacklist an exit ip from being selected. This prevents rogue ip within the selected subnet to cause
@@ -279,30 +308,15 @@ fn decrypt_exit_state(
         .wg_private_key
         .expect("No private key?")
         .into();
-    let ciphertext = exit_state.encrypted_exit_state;
-    let nonce = Nonce(exit_state.nonce);
-    let decrypted_exit_state: ExitState =
-        match box_::open(&ciphertext, &nonce, &exit_pubkey, &our_secretkey) {
-            Ok(decrypted_bytes) => match String::from_utf8(decrypted_bytes) {
-                Ok(json_string) => match serde_json::from_str(&json_string) {
-                    Ok(exit_state) => exit_state,
-                    Err(e) => {
-                        return Err(e.into());
-                    }
-                },
-                Err(e) => {
-                    error!("Could not deserialize exit state with {:?}", e);
-                    return Err(e.into());
-                }
-            },
-            Err(_) => {
-                error!("Could not decrypt exit state");
-                return Err(RitaClientError::MiscStringError(
-                    "Could not decrypt exit state".to_string(),
-                ));
-            }
-        };
-    Ok(decrypted_exit_state)
+    match exit_state.open(&exit_pubkey, &our_secretkey) {
+        Ok(exit_state) => Ok(exit_state),
+        Err(e) => {
+            error!("Could not open exit state with {}", e);
+            Err(RitaClientError::MiscStringError(format!(
+                "Could not open exit state with {e}"
+            )))
+        }
+    }
 }
 
 /// When we retrieve an exit list from an exit, add the compatible exits to the exit server list.
@@ -316,6 +330,8 @@ pub fn add_exits_to_exit_server_list(list: ExitListV2) {
             exit_id: exit_identity_to_id(e.clone()),
             registration_port: e.registration_port,
             wg_exit_listen_port: e.wg_exit_listen_port,
+            tunnel_mtu: None,
+            persistent_keepalive: None,
             info: ExitState::New,
         });
     }
@@ -370,6 +386,62 @@ async fn send_exit_setup_request(
     }
 }
 
+/// Fetches the exit's details from its unencrypted info endpoint, for exits we haven't heard from yet
+async fn get_exit_details(exit: &ExitServer) -> Result<ExitDetails, RitaClientError> {
+    let endpoint = format!(
+        "http://[{}]:{}/exit_info",
+        exit.exit_id.mesh_ip, exit.registration_port
+    );
+    let client = awc::Client::default();
+    let mut response = match client
+        .get(&endpoint)
+        .timeout(CLIENT_LOOP_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => return Err(RitaClientError::SendRequestError(e.to_string())),
+    };
+    match response.json().await? {
+        ExitState::GotInfo {
+            general_details, ..
+        } => Ok(general_details),
+        state => Err(RitaClientError::MiscStringError(format!(
+            "Unexpected exit info response {state:?}"
+        ))),
+    }
+}
+
+/// Solves the exit's signup proof of work if it asks for one. This takes a while on a router so it
+/// runs on the blocking thread pool rather than stalling the exit manager
+async fn solve_signup_challenge(
+    exit: &ExitServer,
+    our_key: WgKey,
+) -> Result<Option<SignupProof>, RitaClientError> {
+    let details = match exit.info.general_details() {
+        Some(details) => details.clone(),
+        None => get_exit_details(exit).await?,
+    };
+    let challenge = match details.signup_challenge {
+        Some(challenge) => challenge,
+        None => return Ok(None),
+    };
+    info!(
+        "Exit {} asks for a signup proof of work with difficulty {}",
+        exit.exit_id.mesh_ip, challenge.difficulty
+    );
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match web::block(move || challenge.solve(&our_key, now)).await {
+        Ok(proof) => Ok(Some(proof)),
+        Err(e) => Err(RitaClientError::MiscStringError(format!(
+            "Failed to solve signup challenge {e}"
+        ))),
+    }
+}
+
 async fn send_exit_status_request(
     exit_pubkey: WgKey,
     to: &SocketAddr,
@@ -436,17 +508,24 @@ pub async fn exit_setup_request(code: Option<String>) -> Result<(), RitaClientEr
                 // Send a verification code if we have one
                 reg_details.phone_code = code;
 
+                let global = match settings::get_rita_client().get_identity() {
+                    Some(id) => id,
+                    None => {
+                        return Err(RitaClientError::MiscStringError(
+                            "Identity has no mesh IP ready yet".to_string(),
+                        ));
+                    }
+                };
+                let signup_proof = solve_signup_challenge(&exit, global.wg_public_key).await?;
+
                 let ident = ExitClientIdentity {
-                    global: match settings::get_rita_client().get_identity() {
-                        Some(id) => id,
-                        None => {
-                            return Err(RitaClientError::MiscStringError(
-                                "Identity has no mesh IP ready yet".to_string(),
-                            ));
-                        }
-                    },
+                    global,
                     wg_port: exit_client.wg_listen_port,
                     reg_details,
+                    version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                    supported_protocol_versions: SUPPORTED_EXIT_PROTOCOL_VERSIONS.to_vec(),
+                    signup_proof,
+                    extensions: local_extensions(),
                 };
 
                 let endpoint = SocketAddr::new(exit.exit_id.mesh_ip, exit.registration_port);
@@ -471,10 +550,10 @@ pub async fn exit_setup_request(code: Option<String>) -> Result<(), RitaClientEr
                 set_rita_client(rita_client);
                 return Ok(());
             }
-            ExitState::Denied { message } => {
+            ExitState::Denied { message, code } => {
                 warn!(
-                    "Exit {} is in ExitState DENIED with {}, not able to be setup",
-                    exit.exit_id.mesh_ip, message
+                    "Exit {} is in ExitState DENIED with {} {:?}, not able to be setup",
+                    exit.exit_id.mesh_ip, message, code
                 );
             }
             ExitState::Registered { .. } => {
@@ -522,6 +601,10 @@ async fn exit_status_request(exit: IpAddr) -> Result<(), RitaClientError> {
         },
         wg_port: settings::get_rita_client().exit_client.wg_listen_port,
         reg_details,
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        supported_protocol_versions: SUPPORTED_EXIT_PROTOCOL_VERSIONS.to_vec(),
+        signup_proof: None,
+        extensions: local_extensions(),
     };
 
     let endpoint = SocketAddr::new(current_exit.exit_id.mesh_ip, current_exit.registration_port);
@@ -542,6 +625,36 @@ async fn exit_status_request(exit: IpAddr) -> Result<(), RitaClientError> {
     settings::set_rita_client(rita_client);
 
     trace!("Got exit status response {:?}", exit_response);
+    if let ExitState::Registered {
+        version_status:
+            Some(ClientVersionStatus::Deprecated {
+                minimum_version, ..
+            }),
+        ..
+    } = &exit_response
+    {
+        warn!(
+            "Exit {} reports our version {} is deprecated, please update to {} or newer",
+            exit,
+            env!("CARGO_PKG_VERSION"),
+            minimum_version
+        );
+    }
+    if let ExitState::Registered {
+        migration: Some(migration),
+        ..
+    } = exit_response
+    {
+        warn!(
+            "Exit {} is being retired, moving to {} before {:?}",
+            exit, migration.replacement.mesh_ip, migration.deadline
+        );
+        // the replacement may not be in our config yet, once added it's registered with like any other
+        add_exits_to_exit_server_list(ExitListV2 {
+            exit_list: vec![migration.replacement],
+            weights: Vec::new(),
+        });
+    }
     Ok(())
 }
 
@@ -574,6 +687,10 @@ async fn get_exit_list(exit: IpAddr) -> Result<ExitListV2, RitaClientError> {
         },
         wg_port: settings::get_rita_client().exit_client.wg_listen_port,
         reg_details,
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        supported_protocol_versions: SUPPORTED_EXIT_PROTOCOL_VERSIONS.to_vec(),
+        signup_proof: None,
+        extensions: local_extensions(),
     };
 
     let exit_server = current_exit.exit_id.mesh_ip;
@@ -629,29 +746,15 @@ fn decrypt_exit_list(
         .wg_private_key
         .expect("No private key?")
         .into();
-    let ciphertext = exit_list.exit_list;
-    let nonce = Nonce(exit_list.nonce);
-    let ret: ExitListV2 = match box_::open(&ciphertext, &nonce, &exit_pubkey, &our_secretkey) {
-        Ok(decrypted_bytes) => match String::from_utf8(decrypted_bytes) {
-            Ok(json_string) => match serde_json::from_str(&json_string) {
-                Ok(ip_list) => ip_list,
-                Err(e) => {
-                    return Err(e.into());
-                }
-            },
-            Err(e) => {
-                error!("Could not deserialize exit state with {:?}", e);
-                return Err(e.into());
-            }
-        },
-        Err(_) => {
-            error!("Could not decrypt exit state");
-            return Err(RitaClientError::MiscStringError(
-                "Could not decrypt exit state".to_string(),
-            ));
+    match exit_list.open(&exit_pubkey, &our_secretkey) {
+        Ok(list) => Ok(list),
+        Err(e) => {
+            error!("Could not open exit list with {}", e);
+            Err(RitaClientError::MiscStringError(format!(
+                "Could not open exit list with {e}"
+            )))
         }
-    };
-    Ok(ret)
+    }
 }
 
 fn correct_default_route(input: Option<DefaultRoute>) -> bool {
@@ -673,16 +776,21 @@ fn get_routes_hashmap(routes: Vec<Route>) -> HashMap<IpAddr, Route> {
     ret
 }
 
-/// Exits are ready to switch to when they are in the Registered State, we return list of exits that are
+/// Exits are ready to switch to when they are in the Registered State, we return list of exits that are.
+/// Exits that are migrating their clients elsewhere are left out unless we are still on them, so that we
+/// don't switch back once we have moved
 pub fn get_ready_to_switch_exits(exit_list: ExitListV2) -> Vec<Identity> {
     let exits = get_rita_client().exit_client.exits;
+    let current_exit = get_current_exit();
 
     let mut ret = vec![];
     for exit in exit_list.exit_list {
         match exits.get(&exit.mesh_ip) {
             Some(server) => {
-                if let ExitState::Registered { .. } = server.info {
-                    ret.push(exit_identity_to_id(exit));
+                if let ExitState::Registered { migration, .. } = &server.info {
+                    if migration.is_none() || current_exit == Some(exit.mesh_ip) {
+                        ret.push(exit_identity_to_id(exit));
+                    }
                 }
             }
             None => {
@@ -694,14 +802,12 @@ pub fn get_ready_to_switch_exits(exit_list: ExitListV2) -> Vec<Identity> {
 }
 
 pub fn get_client_pub_ipv6() -> Option<IpNetwork> {
-    let rita_settings = settings::get_rita_client();
+    let rita_settings = settings::get_rita_client_snapshot();
     let current_exit = get_current_exit();
     if let Some(exit) = current_exit {
         let exit_ser = rita_settings.exit_client.exits.get(&exit);
         if let Some(exit_ser) = exit_ser {
-            let exit_info = exit_ser.info.clone();
-
-            if let ExitState::Registered { our_details, .. } = exit_info {
+            if let ExitState::Registered { our_details, .. } = &exit_ser.info {
                 return our_details.internet_ipv6_subnet;
             }
         }
@@ -752,6 +858,8 @@ mod tests {
 
             registration_port: 3452,
             wg_exit_listen_port: 59998,
+            tunnel_mtu: None,
+            persistent_keepalive: None,
 
             info: ExitState::New,
         };
@@ -763,6 +871,7 @@ mod tests {
             exit_currency: SystemChain::Xdai,
             description: "".to_string(),
             verif_mode: ExitVerifMode::Off,
+            signup_challenge: None,
         };
         let mut last_states = LastExitStates::default();
 
@@ -792,6 +901,9 @@ mod tests {
                 internet_ipv6_subnet: None,
             },
             message: "".to_string(),
+            version_status: None,
+            protocol_version: None,
+            migration: None,
         };
         assert!(has_exit_changed(
             last_states.clone(),
@@ -810,6 +922,9 @@ mod tests {
                 internet_ipv6_subnet: None,
             },
             message: "".to_string(),
+            version_status: None,
+            protocol_version: None,
+            migration: None,
         };
         assert!(has_exit_changed(
             last_states.clone(),
@@ -0,0 +1,432 @@
+//! Provisions an isolated guest network, its own bridge, subnet, ssid on every radio and firewall zone, so
+//! hosts can share their connection without exposing their lan. Guests can only reach the router for dhcp
+//! and dns and the internet through the exit tunnel. Guest traffic is part of the client usage billed by the
+//! exit, it is also tracked here on its own so hosts can see what their guests cost them.
+
+use crate::dashboard::wifi::{validate_config_value, MINIMUM_PASS_CHARS};
+use crate::exit_manager::get_exit_tunnel_mtu;
+use crate::RitaClientError;
+use althea_kernel_interface::KI;
+use althea_types::{convert_map_to_flat_usage_data, IndexedUsageHour, Usage};
+use num256::Uint256;
+use rita_common::usage_tracker::structs::UsageType;
+use rita_common::usage_tracker::{get_current_hour, get_usage_data_map};
+use settings::guest_network::GuestNetworkSettings;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+
+pub const GUEST_BRIDGE: &str = "br-guest";
+const GUEST_NETMASK: &str = "255.255.255.0";
+const GUEST_LEASE_TIME: &str = "1h";
+
+/// Hours of guest usage kept, a little over a month like the main usage history
+const MAX_GUEST_USAGE_HOURS: usize = 24 * 32;
+
+/// Uci sections this module owns, besides the per radio wifi interfaces
+const GUEST_SECTIONS: [&str; 5] = [
+    "network.guest",
+    "dhcp.guest",
+    "firewall.guest_zone",
+    "firewall.guest_dhcp",
+    "firewall.guest_dns",
+];
+
+lazy_static! {
+    static ref GUEST_USAGE: Arc<RwLock<GuestUsageTracker>> =
+        Arc::new(RwLock::new(GuestUsageTracker::default()));
+}
+
+#[derive(Debug, Default)]
+struct GuestUsageTracker {
+    /// Bridge counters at the last tick, bytes received from guests then bytes sent to them
+    last_counters: Option<(u64, u64)>,
+    /// Usage per hour since the unix epoch, loaded from disk on the first tick
+    history: Option<HashMap<u64, Usage>>,
+    /// History has guest usage that is not on disk yet
+    dirty: bool,
+    /// Hour the history was last written to disk in
+    saved_hour: Option<u64>,
+}
+
+/// What the guests have used and what that cost at the prices paid at the time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GuestUsageSummary {
+    pub up: u64,
+    pub down: u64,
+    /// In wei
+    pub cost: Uint256,
+}
+
+fn validate_guest_network(settings: &GuestNetworkSettings) -> Result<(), RitaClientError> {
+    validate_config_value(&settings.ssid)?;
+    if let Some(password) = &settings.password {
+        if password.len() < MINIMUM_PASS_CHARS {
+            return Err(RitaClientError::MiscStringError(format!(
+                "Guest password must be at least {MINIMUM_PASS_CHARS} characters"
+            )));
+        }
+        validate_config_value(password)?;
+    }
+    if let Some(ifname) = &settings.wired_ifname {
+        validate_config_value(ifname)?;
+    }
+    if settings.bandwidth_limit == Some(0) {
+        return Err(RitaClientError::MiscStringError(
+            "Guest bandwidth limit must be at least 1 mbit/s".to_string(),
+        ));
+    }
+    let ip = settings.router_ip;
+    if !ip.is_private() || ip.octets()[3] == 0 || ip.octets()[3] == 255 {
+        return Err(RitaClientError::MiscStringError(format!(
+            "{ip} is not a usable private address for the guest network"
+        )));
+    }
+    Ok(())
+}
+
+/// Checks the guest subnet against the lan, the rest of the addressing conflicts are the same as for the
+/// lan and are checked there
+fn check_lan_conflict(router_ip: Ipv4Addr) -> Result<(), RitaClientError> {
+    let lan_ip: Ipv4Addr = match KI
+        .get_uci_var("network.lan.ipaddr")?
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .parse()
+    {
+        Ok(ip) => ip,
+        Err(_) => return Ok(()),
+    };
+    if lan_ip.octets()[..3] == router_ip.octets()[..3] {
+        return Err(RitaClientError::MiscStringError(format!(
+            "Guest network {router_ip}/24 overlaps the lan"
+        )));
+    }
+    Ok(())
+}
+
+/// The names of every radio, like radio0
+fn get_radios() -> Result<Vec<String>, RitaClientError> {
+    Ok(KI
+        .uci_show(Some("wireless"))?
+        .into_iter()
+        .filter(|(_, kind)| kind == "wifi-device")
+        .filter_map(|(key, _)| key.strip_prefix("wireless.").map(|s| s.to_string()))
+        .collect())
+}
+
+fn remove_guest_sections() -> Result<(), RitaClientError> {
+    for section in GUEST_SECTIONS {
+        if let Err(e) = KI.del_uci_var(section) {
+            trace!("No {} to remove {:?}", section, e);
+        }
+    }
+    for radio in get_radios()? {
+        if let Err(e) = KI.del_uci_var(&format!("wireless.guest_{radio}")) {
+            trace!("No guest wifi on {} to remove {:?}", radio, e);
+        }
+    }
+    Ok(())
+}
+
+fn set_guest_sections(settings: &GuestNetworkSettings) -> Result<(), RitaClientError> {
+    KI.set_uci_var("network.guest", "interface")?;
+    KI.set_uci_var("network.guest.type", "bridge")?;
+    KI.set_uci_var("network.guest.proto", "static")?;
+    KI.set_uci_var("network.guest.ipaddr", &settings.router_ip.to_string())?;
+    KI.set_uci_var("network.guest.netmask", GUEST_NETMASK)?;
+    // openwrt only creates a bridge with no ports when asked to
+    KI.set_uci_var("network.guest.bridge_empty", "1")?;
+    if let Some(ifname) = &settings.wired_ifname {
+        KI.set_uci_var("network.guest.ifname", ifname)?;
+    }
+
+    KI.set_uci_var("dhcp.guest", "dhcp")?;
+    KI.set_uci_var("dhcp.guest.interface", "guest")?;
+    KI.set_uci_var("dhcp.guest.start", "100")?;
+    KI.set_uci_var("dhcp.guest.limit", "150")?;
+    KI.set_uci_var("dhcp.guest.leasetime", GUEST_LEASE_TIME)?;
+
+    // guests can only talk to the router for addresses and names, forwarding is limited to wg_exit
+    // by create_guest_forward_rules
+    KI.set_uci_var("firewall.guest_zone", "zone")?;
+    KI.set_uci_var("firewall.guest_zone.name", "guest")?;
+    KI.set_uci_var("firewall.guest_zone.network", "guest")?;
+    KI.set_uci_var("firewall.guest_zone.input", "REJECT")?;
+    KI.set_uci_var("firewall.guest_zone.output", "ACCEPT")?;
+    KI.set_uci_var("firewall.guest_zone.forward", "REJECT")?;
+    KI.set_uci_var("firewall.guest_dhcp", "rule")?;
+    KI.set_uci_var("firewall.guest_dhcp.name", "Allow-Guest-DHCP")?;
+    KI.set_uci_var("firewall.guest_dhcp.src", "guest")?;
+    KI.set_uci_var("firewall.guest_dhcp.proto", "udp")?;
+    KI.set_uci_var("firewall.guest_dhcp.dest_port", "67-68")?;
+    KI.set_uci_var("firewall.guest_dhcp.target", "ACCEPT")?;
+    KI.set_uci_var("firewall.guest_dns", "rule")?;
+    KI.set_uci_var("firewall.guest_dns.name", "Allow-Guest-DNS")?;
+    KI.set_uci_var("firewall.guest_dns.src", "guest")?;
+    KI.set_uci_var("firewall.guest_dns.proto", "tcpudp")?;
+    KI.set_uci_var("firewall.guest_dns.dest_port", "53")?;
+    KI.set_uci_var("firewall.guest_dns.target", "ACCEPT")?;
+
+    for radio in get_radios()? {
+        let section = format!("wireless.guest_{radio}");
+        KI.set_uci_var(&section, "wifi-iface")?;
+        KI.set_uci_var(&format!("{section}.device"), &radio)?;
+        KI.set_uci_var(&format!("{section}.mode"), "ap")?;
+        KI.set_uci_var(&format!("{section}.network"), "guest")?;
+        KI.set_uci_var(&format!("{section}.ssid"), &settings.ssid)?;
+        // keeps guests from reaching each other
+        KI.set_uci_var(&format!("{section}.isolate"), "1")?;
+        match &settings.password {
+            Some(password) => {
+                KI.set_uci_var(&format!("{section}.encryption"), "psk2")?;
+                KI.set_uci_var(&format!("{section}.key"), password)?;
+            }
+            None => {
+                KI.set_uci_var(&format!("{section}.encryption"), "none")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Provisions the guest network for these settings, or removes it when there are none. Restarts the
+/// network, wifi and firewall so expect a short outage
+pub fn apply_guest_network(settings: Option<&GuestNetworkSettings>) -> Result<(), RitaClientError> {
+    if let Some(settings) = settings {
+        validate_guest_network(settings)?;
+        check_lan_conflict(settings.router_ip)?;
+    }
+
+    // start from a clean slate so removed options like the wired interface don't linger
+    remove_guest_sections()?;
+    if let Some(settings) = settings {
+        set_guest_sections(settings)?;
+    }
+    for config in ["network", "dhcp", "firewall", "wireless"] {
+        KI.uci_commit(config)?;
+    }
+
+    KI.openwrt_reset_network()?;
+    KI.openwrt_reset_wireless()?;
+    KI.refresh_initd("firewall")?;
+    KI.openwrt_reset_dnsmasq()?;
+
+    // we have invalidated the old nat rules, update them
+    KI.create_client_nat_rules(get_exit_tunnel_mtu())?;
+    if let Some(settings) = settings {
+        KI.create_guest_forward_rules()?;
+        KI.set_codel_shaping(GUEST_BRIDGE, settings.bandwidth_limit)?;
+    }
+    Ok(())
+}
+
+/// Undoes any uci changes made by a failed apply_guest_network()
+pub fn revert_guest_network() {
+    for config in ["network", "dhcp", "firewall", "wireless"] {
+        if let Err(e) = KI.uci_revert(config) {
+            trace!("Failed to revert {} {:?}", config, e);
+        }
+    }
+}
+
+fn load_guest_usage(path: &str) -> HashMap<u64, Usage> {
+    match fs::read_to_string(path) {
+        Ok(contents) => match serde_json::from_str(&contents) {
+            Ok(usage) => usage,
+            Err(e) => {
+                error!("Failed to parse guest usage file {:?}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_guest_usage(path: &str, history: &HashMap<u64, Usage>) {
+    match serde_json::to_string(history) {
+        Ok(contents) => {
+            if let Err(e) = fs::write(path, contents) {
+                error!("Failed to save guest usage {:?}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize guest usage {:?}", e),
+    }
+}
+
+/// Adds a counter sample to the history, returns true if the history should be written to disk. That
+/// is at most once an hour and only if guests used something since the last write, hours without any
+/// guest traffic are not recorded at all so an idle guest network never touches the flash
+fn record_guest_usage(
+    tracker: &mut GuestUsageTracker,
+    counters: (u64, u64),
+    hour: u64,
+    price: u32,
+) -> bool {
+    let last = tracker.last_counters.replace(counters);
+    let history = tracker.history.get_or_insert_with(HashMap::new);
+    // the first sample and counter resets from the bridge being recreated only set the baseline
+    match last {
+        Some((last_up, last_down)) if counters.0 >= last_up && counters.1 >= last_down => {
+            let (up, down) = (counters.0 - last_up, counters.1 - last_down);
+            if up > 0 || down > 0 {
+                let entry = history.entry(hour).or_insert(Usage {
+                    up: 0,
+                    down: 0,
+                    price,
+                });
+                entry.up += up;
+                entry.down += down;
+                while history.len() > MAX_GUEST_USAGE_HOURS {
+                    if let Some(oldest) = history.keys().min().cloned() {
+                        history.remove(&oldest);
+                    }
+                }
+                tracker.dirty = true;
+            }
+        }
+        _ => return false,
+    }
+    if tracker.dirty && tracker.saved_hour != Some(hour) {
+        tracker.dirty = false;
+        tracker.saved_hour = Some(hour);
+        return true;
+    }
+    false
+}
+
+/// Run every client loop tick, restores the guest forwarding rule if a firewall reload dropped it and
+/// records guest usage from the bridge counters
+pub fn tick_guest_network() {
+    let settings = match settings::get_rita_client().guest_network {
+        Some(settings) => settings,
+        None => return,
+    };
+    match KI.is_guest_forward_rule_present() {
+        Ok(true) => {}
+        Ok(false) => {
+            if let Err(e) = KI.create_guest_forward_rules() {
+                error!("Failed to set guest forwarding rules {:?}", e);
+            }
+        }
+        Err(e) => error!("Failed to check guest forwarding rules {:?}", e),
+    }
+
+    let counters = match KI.get_per_interface_usage() {
+        Ok(usage) => match usage.into_iter().find(|i| i.interface_name == GUEST_BRIDGE) {
+            // what the bridge receives guests uploaded, what it transmits they downloaded
+            Some(stats) => (stats.recieve_bytes, stats.transmit_bytes),
+            None => return,
+        },
+        Err(e) => {
+            warn!("Failed to get guest usage {:?}", e);
+            return;
+        }
+    };
+    let hour = match get_current_hour() {
+        Ok(hour) => hour,
+        Err(e) => {
+            error!("System time is set earlier than unix epoch {:?}", e);
+            return;
+        }
+    };
+    // guests pay what the router pays for the hour
+    let price = get_usage_data_map(UsageType::Client)
+        .get(&hour)
+        .map(|usage| usage.price)
+        .unwrap_or(0);
+
+    let mut tracker = GUEST_USAGE.write().unwrap();
+    if tracker.history.is_none() {
+        tracker.history = Some(load_guest_usage(&settings.usage_file));
+    }
+    if record_guest_usage(&mut tracker, counters, hour, price) {
+        if let Some(history) = &tracker.history {
+            save_guest_usage(&settings.usage_file, history);
+        }
+    }
+}
+
+fn get_guest_usage_map() -> HashMap<u64, Usage> {
+    let tracker = GUEST_USAGE.read().unwrap();
+    match &tracker.history {
+        Some(history) => history.clone(),
+        None => match settings::get_rita_client().guest_network {
+            Some(settings) => load_guest_usage(&settings.usage_file),
+            None => HashMap::new(),
+        },
+    }
+}
+
+pub fn get_guest_usage() -> VecDeque<IndexedUsageHour> {
+    convert_map_to_flat_usage_data(get_guest_usage_map())
+}
+
+pub fn get_guest_usage_summary() -> GuestUsageSummary {
+    let mut summary = GuestUsageSummary {
+        up: 0,
+        down: 0,
+        cost: Uint256::from(0u8),
+    };
+    for usage in get_guest_usage_map().values() {
+        summary.up += usage.up;
+        summary.down += usage.down;
+        summary.cost += Uint256::from(usage.up + usage.down) * Uint256::from(usage.price);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_guest_usage() {
+        let mut tracker = GuestUsageTracker::default();
+        // the first sample is only a baseline
+        assert!(!record_guest_usage(&mut tracker, (1000, 5000), 10, 7));
+        assert!(record_guest_usage(&mut tracker, (1500, 9000), 10, 7));
+        assert!(!record_guest_usage(&mut tracker, (1600, 9100), 10, 8));
+        let history = tracker.history.clone().unwrap();
+        assert_eq!(
+            history[&10],
+            Usage {
+                up: 600,
+                down: 4100,
+                price: 7
+            }
+        );
+        // the bridge was recreated, counting starts over without a bogus sample
+        assert!(!record_guest_usage(&mut tracker, (10, 10), 11, 7));
+        assert!(record_guest_usage(&mut tracker, (20, 30), 11, 7));
+        assert_eq!(tracker.history.clone().unwrap()[&11].down, 20);
+        // an idle hour is neither recorded nor written
+        assert!(!record_guest_usage(&mut tracker, (20, 30), 12, 7));
+        assert!(!tracker.history.clone().unwrap().contains_key(&12));
+        assert!(record_guest_usage(&mut tracker, (25, 30), 12, 7));
+    }
+
+    #[test]
+    fn test_validate_guest_network() {
+        let mut settings = GuestNetworkSettings {
+            ssid: "Guests".to_string(),
+            password: None,
+            router_ip: Ipv4Addr::new(192, 168, 50, 1),
+            wired_ifname: None,
+            bandwidth_limit: Some(10),
+            usage_file: "/tmp/guest".to_string(),
+        };
+        assert!(validate_guest_network(&settings).is_ok());
+        settings.password = Some("short".to_string());
+        assert!(validate_guest_network(&settings).is_err());
+        settings.password = Some("longenough".to_string());
+        assert!(validate_guest_network(&settings).is_ok());
+        settings.router_ip = Ipv4Addr::new(8, 8, 8, 1);
+        assert!(validate_guest_network(&settings).is_err());
+        settings.router_ip = Ipv4Addr::new(192, 168, 50, 1);
+        settings.ssid = "bad'ssid".to_string();
+        assert!(validate_guest_network(&settings).is_err());
+    }
+}
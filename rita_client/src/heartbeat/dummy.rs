@@ -28,6 +28,7 @@ pub fn dummy_route() -> Route {
         full_path_rtt: 200.0,
         price: 100,
         fee: 100,
+        src_prefix: None,
     }
 }
 
@@ -82,5 +83,6 @@ pub fn dummy_selected_exit_details() -> ExitDetails {
         exit_currency: althea_types::SystemChain::Ethereum,
         description: "".to_string(),
         verif_mode: althea_types::ExitVerifMode::Off,
+        signup_challenge: None,
     }
 }
@@ -139,21 +139,21 @@ fn send_udp_heartbeat() {
     let dns_request = heartbeat_url.to_socket_addrs();
 
     // Check for the basics first, before doing any of the hard futures work
-    let mut our_id: Identity = if settings::get_rita_client().get_identity().is_some() {
-        trace!(
-            "Got identity: {} ",
-            settings::get_rita_client().get_identity().unwrap()
-        );
-        settings::get_rita_client().get_identity().unwrap()
-    } else {
-        trace!("Could not get identity!");
-        return;
+    let mut our_id: Identity = match settings::get_rita_client_snapshot().get_identity() {
+        Some(id) => {
+            trace!("Got identity: {} ", id);
+            id
+        }
+        None => {
+            trace!("Could not get identity!");
+            return;
+        }
     };
     let mut selected_exit_details: ExitDetails = dummy_selected_exit_details();
 
     if !cfg!(feature = "operator_debug") {
         if let (Some(id), Some(exit)) = (
-            settings::get_rita_client().get_identity(),
+            settings::get_rita_client_snapshot().get_identity(),
             get_selected_exit_server(),
         ) {
             let exit_info = exit.info;
@@ -275,10 +275,9 @@ fn get_selected_exit_route(route_dump: &[Route]) -> Result<Route, BabelMonitorEr
 }
 
 pub fn get_selected_exit_server() -> Option<ExitServer> {
-    let rita_client = settings::get_rita_client();
-    let exit_client = rita_client.exit_client;
+    let rita_client = settings::get_rita_client_snapshot();
     let exit = match get_current_exit() {
-        Some(ip) => exit_client.exits.get(&ip),
+        Some(ip) => rita_client.exit_client.exits.get(&ip),
         None => None,
     };
 
@@ -7,16 +7,21 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod bandwidth_contract;
 pub mod dashboard;
+pub mod dns;
 mod error;
 pub mod exit_manager;
 pub mod extender;
+pub mod guest_network;
 pub mod heartbeat;
 pub mod logging;
 pub mod operator_fee_manager;
 pub mod operator_update;
 pub mod rita_loop;
+pub mod snmp;
 pub mod traffic_watcher;
+pub mod user_rules;
 pub use error::RitaClientError;
 use rita_common::READABLE_VERSION;
 use std::path::PathBuf;
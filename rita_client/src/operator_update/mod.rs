@@ -3,6 +3,7 @@ pub mod tests;
 pub mod update_loop;
 pub mod updater;
 extern crate openssh_keys;
+use crate::bandwidth_contract::{get_bandwidth_contract_report, receive_bandwidth_contract};
 use crate::dashboard::system_chain::set_system_blockchain;
 use crate::exit_manager::{get_client_pub_ipv6, get_current_exit};
 use crate::rita_loop::is_gateway_client;
@@ -11,13 +12,20 @@ use crate::{
     RitaClientError,
 };
 use althea_kernel_interface::hardware_info::get_hardware_info;
-use althea_types::{get_sequence_num, UsageTrackerTransfer};
+use althea_kernel_interface::hardware_info::get_hardware_telemetry;
+use althea_types::{get_sequence_num, SignedAntennaSessionRecord, UsageTrackerTransfer};
 use althea_types::{
     AuthorizedKeys, BillingDetails, ContactStorage, ContactType, CurExitInfo, ExitConnection,
     HardwareInfo, OperatorAction, OperatorCheckinMessage, OperatorUpdateMessage,
 };
+use antenna_forwarding_client::{get_session_records, remove_session_records};
 use num256::Uint256;
+use rita_common::broadcast_notices::{receive_notice, record_operator_checkin};
+use rita_common::capabilities::get_capabilities;
 use rita_common::rita_loop::is_gateway;
+use rita_common::speed_test::{
+    get_speed_test_results, remove_speed_test_results, start_speed_test,
+};
 use rita_common::tunnel_manager::neighbor_status::get_neighbor_status;
 use rita_common::tunnel_manager::shaping::flag_reset_shaper;
 use rita_common::usage_tracker::structs::UsageType::{self, Client, Relay};
@@ -52,8 +60,8 @@ lazy_static! {
     static ref RITA_UPTIME: Instant = Instant::now();
 }
 
-/// Operator update has a randomized exponential backoff, meaning if checkins fail
-/// we will back off and try again after a longer interval
+/// Operator update has a jittered exponential backoff, meaning if checkins fail
+/// we will back off and try again after a longer interval, see rita_common::scheduler
 const TARGET_UPDATE_FREQUENCY: Duration = Duration::from_secs(5);
 /// This is the cap for the exponential backoff, no matter how many consecutive checkins fail
 /// we will not go above this amount of time
@@ -115,7 +123,12 @@ pub async fn operator_update(
     // disable hardware info sending if logging is disabled
     let hardware_info = match logging_enabled {
         true => match get_hardware_info(rita_client.network.device.clone()) {
-            Ok(info) => Some(extend_hardware_info(info)),
+            Ok(mut info) => {
+                if rita_client.operator.share_hardware_telemetry {
+                    info.telemetry = Some(get_hardware_telemetry());
+                }
+                Some(extend_hardware_info(info))
+            }
             Err(e) => {
                 error!("Failed to get hardware info with {:?}", e);
                 None
@@ -139,6 +152,19 @@ pub async fn operator_update(
         client_pub_ipv6: get_client_pub_ipv6(),
     });
 
+    let speed_test_results = get_speed_test_results();
+    let speed_test_ids: Vec<u64> = speed_test_results.iter().map(|r| r.id).collect();
+    // signed so that the operator can hold the router to its audit records
+    let antenna_sessions: Vec<SignedAntennaSessionRecord> =
+        match rita_client.payment.eth_private_key {
+            Some(key) => get_session_records()
+                .into_iter()
+                .map(|record| record.sign(key))
+                .collect(),
+            None => Vec::new(),
+        };
+    let antenna_session_ids: Vec<u64> = antenna_sessions.iter().map(|s| s.record.id).collect();
+
     let client = awc::Client::default();
     let response = client
         .post(url)
@@ -159,6 +185,10 @@ pub async fn operator_update(
             user_bandwidth_usage_v2: prepare_usage_data_for_upload(ops_last_seen_usage_hour)?,
             client_mbps: get_current_throughput(UsageType::Client),
             relay_mbps: get_current_throughput(UsageType::Relay),
+            speed_test_results,
+            antenna_sessions,
+            bandwidth_contract: get_bandwidth_contract_report(),
+            capabilities: get_capabilities(),
         })
         .await;
 
@@ -182,6 +212,15 @@ pub async fn operator_update(
         }
     };
 
+    // the operator has these now
+    remove_speed_test_results(&speed_test_ids);
+    remove_session_records(&antenna_session_ids);
+
+    record_operator_checkin();
+    for notice in new_settings.broadcast_notices.iter() {
+        receive_notice(notice.clone());
+    }
+
     let mut rita_client = rita_client;
 
     let update = check_contacts_update(
@@ -334,6 +373,13 @@ fn perform_operator_update(
             let res = update_authorized_keys(add_list, drop_list, key_file);
             info!("Update auth_keys result is  {:?}", res);
         }
+        Some(OperatorAction::SpeedTest { test }) => {
+            info!(
+                "Received a speed test to {} from op tools",
+                test.test.receiver
+            );
+            start_speed_test(test);
+        }
         None => {}
     }
     if let Some(shaper_settings) = new_settings.shaper_settings {
@@ -342,6 +388,9 @@ fn perform_operator_update(
     if let Some(babeld_settings) = new_settings.babeld_settings {
         network.babeld_settings = babeld_settings;
     }
+    if let Some(contract) = new_settings.bandwidth_contract {
+        receive_bandwidth_contract(contract, &mut rita_client);
+    }
     rita_client.network = network;
     settings::set_rita_client(rita_client);
     trace!("Successfully completed OperatorUpdate");
@@ -3,8 +3,11 @@ mod test {
     use crate::operator_update::contains_forbidden_key;
     use crate::operator_update::prepare_usage_data_for_upload;
     use crate::operator_update::update_authorized_keys;
+    use crate::operator_update::updater::fetch_opkg_packages;
+    use althea_types::OpkgCommand;
     use serde_json::json;
     use serde_json::Value;
+    use std::collections::BTreeMap;
     use std::fs::File;
     use std::io::{BufRead, BufReader, Write};
     use std::{fs, io::Error, path::Path};
@@ -135,4 +138,34 @@ mod test {
     fn test_prepare_usage_data_for_upload() {
         assert_eq!(prepare_usage_data_for_upload(None).unwrap(), None);
     }
+    #[test]
+    fn test_opkg_installs_cached_packages_as_ipk() {
+        let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let cached = std::env::temp_dir().join(format!("{hash}-{}", std::process::id()));
+        fs::write(&cached, b"abc").unwrap();
+        let url = "https://updates.example.com/rita_1.0.0_mipsel.ipk".to_string();
+        let mut sha256 = BTreeMap::new();
+        sha256.insert(url.clone(), hash.to_string());
+        let command = OpkgCommand::Install {
+            packages: vec![url, "althea-rust-binaries".to_string()],
+            arguments: Vec::new(),
+            sha256,
+        };
+
+        let cached_path = cached.to_string_lossy().to_string();
+        let (command, created) =
+            fetch_opkg_packages(command, |_, _| Ok(cached_path.clone())).unwrap();
+        let packages = match command {
+            OpkgCommand::Install { packages, .. } => packages,
+            _ => panic!("Expected an install command"),
+        };
+        // opkg takes a path that doesn't end in .ipk for a package name
+        assert!(packages[0].ends_with(".ipk"));
+        assert_eq!(fs::read(&packages[0]).unwrap(), b"abc");
+        assert_eq!(packages[1], "althea-rust-binaries");
+        assert_eq!(created, vec![packages[0].clone()]);
+
+        fs::remove_file(&packages[0]).unwrap();
+        fs::remove_file(&cached).unwrap();
+    }
 }
@@ -2,21 +2,117 @@
 //! versus updating operator tools on the status of this router which is the context of 'update' in the rest
 //! of this module
 
+use actix_async::System;
 use althea_kernel_interface::KernelInterfaceError;
+use althea_types::OpkgCommand;
+use althea_types::SysupgradeCommand;
 use althea_types::UpdateType;
+use rita_common::artifact_cache::fetch_artifact;
 use rita_common::KI;
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+/// Fetches an artifact through the mesh artifact cache, returns the path of the verified local copy
+fn fetch_verified(url: String, hash: String) -> Result<String, KernelInterfaceError> {
+    // we may be called from within an async context, so the fetch gets it's own thread and runtime
+    let res = thread::spawn(move || {
+        let runner = System::new();
+        runner.block_on(async move { fetch_artifact(&url, &hash).await })
+    })
+    .join();
+    match res {
+        Ok(Ok(path)) => Ok(path.to_string_lossy().to_string()),
+        Ok(Err(e)) => Err(KernelInterfaceError::RuntimeError(format!(
+            "Failed to fetch update artifact {e}"
+        ))),
+        Err(_) => Err(KernelInterfaceError::RuntimeError(
+            "Update artifact fetch panicked".to_string(),
+        )),
+    }
+}
+
+/// If the operator provided a hash for the image fetch it through the mesh artifact cache and
+/// point sysupgrade at the verified local copy, otherwise sysupgrade downloads the url itself
+fn fetch_sysupgrade_image(
+    command: SysupgradeCommand,
+) -> Result<SysupgradeCommand, KernelInterfaceError> {
+    match command.sha256.clone() {
+        Some(hash) => Ok(SysupgradeCommand {
+            url: fetch_verified(command.url.clone(), hash)?,
+            ..command
+        }),
+        None => Ok(command),
+    }
+}
+
+/// opkg only installs a local file if its name ends in .ipk and takes anything else for a package
+/// name. Cached artifacts are named by their hash alone, so the verified copy is linked in next to
+/// itself as <hash>.ipk, returns the path of the link
+fn link_as_ipk(path: &str) -> Result<String, KernelInterfaceError> {
+    let ipk = Path::new(path).with_extension("ipk");
+    let _ = fs::remove_file(&ipk);
+    if fs::hard_link(path, &ipk).is_err() {
+        fs::copy(path, &ipk)?;
+    }
+    Ok(ipk.to_string_lossy().to_string())
+}
+
+/// Packages the operator provided a hash for are fetched with fetch, normally through the mesh
+/// artifact cache, and installed from the verified local copy, opkg downloads the rest itself.
+/// Also returns the package files we created, to be removed once opkg is done with them
+pub(crate) fn fetch_opkg_packages(
+    command: OpkgCommand,
+    fetch: impl Fn(String, String) -> Result<String, KernelInterfaceError>,
+) -> Result<(OpkgCommand, Vec<String>), KernelInterfaceError> {
+    match command {
+        OpkgCommand::Install {
+            packages,
+            arguments,
+            sha256,
+        } => {
+            let mut local = Vec::new();
+            let mut created = Vec::new();
+            for package in packages {
+                match sha256.get(&package) {
+                    Some(hash) => {
+                        let ipk = link_as_ipk(&fetch(package, hash.clone())?)?;
+                        created.push(ipk.clone());
+                        local.push(ipk);
+                    }
+                    None => local.push(package),
+                }
+            }
+            Ok((
+                OpkgCommand::Install {
+                    packages: local,
+                    arguments,
+                    sha256,
+                },
+                created,
+            ))
+        }
+        command => Ok((command, Vec::new())),
+    }
+}
 
 /// Updates the system, including Rita and other packages by performing either a sysupgrade or opkg install
 pub fn update_system(instruction: UpdateType) -> Result<(), KernelInterfaceError> {
     if KI.is_openwrt() {
         match instruction {
-            UpdateType::Sysupgrade(command) => match KI.perform_sysupgrade(command) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e),
-            },
+            UpdateType::Sysupgrade(command) => {
+                match KI.perform_sysupgrade(fetch_sysupgrade_image(command)?) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
             UpdateType::Opkg(commands) => {
                 for cmd in commands {
+                    let (cmd, created) = fetch_opkg_packages(cmd, fetch_verified)?;
                     let res = KI.perform_opkg(cmd);
+                    for file in created {
+                        let _ = fs::remove_file(file);
+                    }
                     match res {
                         Ok(o) => match o.status.code() {
                             Some(0) => info!("opkg completed successfully! {:?}", o),
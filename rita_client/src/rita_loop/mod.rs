@@ -4,25 +4,30 @@
 //! This loop manages exit signup based on the settings configuration state and deploys an exit vpn
 //! tunnel if the signup was successful on the selected exit.
 
+use crate::bandwidth_contract::tick_bandwidth_contract;
+use crate::dns::apply_dns_settings;
 use crate::exit_manager::get_current_exit;
 use crate::get_interfaces;
+use crate::guest_network::tick_guest_network;
 use crate::heartbeat::get_selected_exit_server;
 use crate::heartbeat::send_heartbeat_loop;
 use crate::heartbeat::HEARTBEAT_SERVER_KEY;
 use crate::operator_fee_manager::tick_operator_payments;
+use crate::user_rules::tick_static_routes;
 use crate::InterfaceMode;
 use actix_async::System as AsyncSystem;
 use althea_kernel_interface::hardware_info::get_hardware_info;
 use althea_kernel_interface::KernelInterfaceError;
 use althea_kernel_interface::KI;
 use althea_types::ExitState;
-use antenna_forwarding_client::start_antenna_forwarding_proxy;
+use antenna_forwarding_client::{start_antenna_forwarding_proxy, AntennaForwardingConfig};
 use rand::Rng;
 use rita_common::rita_loop::set_gateway;
 use rita_common::tunnel_manager::tm_get_neighbors;
 use rita_common::usage_tracker::get_current_hour;
 use rita_common::usage_tracker::get_last_saved_usage_hour;
 use settings::client::RitaClientSettings;
+use settings::dns::DnsUpstream;
 use settings::get_rita_common;
 use std::collections::HashMap;
 use std::fs;
@@ -82,11 +87,8 @@ pub fn set_gateway_client(input: bool) {
 /// and needs info to assist them. The logging setting may be inspected to disable metrics
 /// not required for a normal operator
 pub fn metrics_permitted() -> bool {
-    settings::get_rita_client().log.enabled
-        || settings::get_rita_client()
-            .operator
-            .operator_address
-            .is_some()
+    let rita_client = settings::get_rita_client_snapshot();
+    rita_client.log.enabled || rita_client.operator.operator_address.is_some()
 }
 
 /// Rita loop thread spawning function, this function contains all the rita client functions
@@ -133,6 +135,27 @@ pub fn start_rita_client_loop() {
                         start.elapsed().subsec_millis()
                     );
 
+                    tick_guest_network();
+                    info!(
+                        "Rita Client loop guest network completed in {}s {}ms",
+                        start.elapsed().as_secs(),
+                        start.elapsed().subsec_millis()
+                    );
+
+                    tick_static_routes();
+                    info!(
+                        "Rita Client loop static routes completed in {}s {}ms",
+                        start.elapsed().as_secs(),
+                        start.elapsed().subsec_millis()
+                    );
+
+                    tick_bandwidth_contract();
+                    info!(
+                        "Rita Client loop bandwidth contract completed in {}s {}ms",
+                        start.elapsed().as_secs(),
+                        start.elapsed().subsec_millis()
+                    );
+
                     // if you have additional async functions to run please add them here
                     // in order to reuse the runner
                     let runner = AsyncSystem::new();
@@ -223,16 +246,20 @@ pub fn start_antenna_forwarder(settings: RitaClientSettings) {
         }
 
         let our_id = settings.get_identity().unwrap();
+        let operator = settings.operator;
         let network = settings.network;
-        let interfaces = network.peer_interfaces.clone();
-        start_antenna_forwarding_proxy(
-            url.to_string(),
+        start_antenna_forwarding_proxy(AntennaForwardingConfig {
+            checkin_address: url.to_string(),
             our_id,
-            *HEARTBEAT_SERVER_KEY,
-            network.wg_public_key.unwrap(),
-            network.wg_private_key.unwrap(),
-            interfaces,
-        );
+            server_public_key: *HEARTBEAT_SERVER_KEY,
+            our_public_key: network.wg_public_key.unwrap(),
+            our_private_key: network.wg_private_key.unwrap(),
+            interfaces_to_search: network.peer_interfaces,
+            allow_auth_injection: operator.allow_antenna_auth_injection,
+            default_rate_limit: operator.antenna_forwarding_rate_limit,
+            record_sessions: operator.record_antenna_sessions,
+            session_records_file: operator.antenna_sessions_file,
+        });
     }
 }
 
@@ -330,7 +357,7 @@ fn manage_babeld_logs() {
 
 /// This code handles updating the dns servers for a router, modifying /etc/resolv.conf to ensure it forwards to
 /// the exit local dns server and also modificing /etc/config/dhcp to ensure we advertise the althea router itself (192.168.10.1)
-/// as a dns resolver
+/// as a dns resolver. When another dns upstream is configured dnsmasq is pointed at that instead, see crate::dns
 pub fn update_dns_conf() {
     let resolv_path = "/etc/resolv.conf";
     let updated_config = "nameserver 172.168.0.254\nnameserver 1.0.0.1\nnameserver 8.8.8.8\nnameserver 74.82.42.42\nnameserver 149.112.112.10\nnameserver 64.6.65.6"
@@ -372,6 +399,12 @@ pub fn update_dns_conf() {
         const DHCP_DNS_LIST_KEY: &str = "dhcp.@dnsmasq[0].server";
         const LAN_IP_KEY: &str = "network.lan.ipaddr";
 
+        if let Err(e) = apply_dns_settings() {
+            error!("Failed to apply dns settings {:?}", e);
+        }
+        // the other upstreams replace the server list below entirely
+        let exit_upstream = settings::get_rita_client().dns.upstream == DnsUpstream::Exit;
+
         // this config value is the list of servers dnsmasq uses for resolving client requests
         // if it does not start with the exit internal nameserver add it. An empty value is acceptable
         // since dnsmasq simply uses resolv.conf servers which we update above in that case.
@@ -379,6 +412,7 @@ pub fn update_dns_conf() {
             parse_list_to_ip(KI.get_uci_var(DHCP_DNS_LIST_KEY)),
             maybe_parse_ip(KI.get_uci_var(LAN_IP_KEY)),
         ) {
+            _ if !exit_upstream => {}
             (Ok(dns_server_list), Ok(router_internal_ip)) => {
                 // an empty list uses the system resolver, this is acceptable since we just set the system resolver to
                 // point at the exit internal ip above
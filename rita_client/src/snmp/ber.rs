@@ -0,0 +1,307 @@
+//! Just enough BER encoding and decoding to speak SNMPv2c, malformed input decodes to None
+
+pub const INTEGER: u8 = 0x02;
+pub const OCTET_STRING: u8 = 0x04;
+pub const NULL: u8 = 0x05;
+pub const OBJECT_IDENTIFIER: u8 = 0x06;
+pub const SEQUENCE: u8 = 0x30;
+pub const GAUGE32: u8 = 0x42;
+pub const TIMETICKS: u8 = 0x43;
+pub const COUNTER64: u8 = 0x46;
+pub const NO_SUCH_OBJECT: u8 = 0x80;
+pub const END_OF_MIB_VIEW: u8 = 0x82;
+
+pub const GET_REQUEST: u8 = 0xa0;
+pub const GET_NEXT_REQUEST: u8 = 0xa1;
+pub const GET_RESPONSE: u8 = 0xa2;
+pub const SET_REQUEST: u8 = 0xa3;
+pub const GET_BULK_REQUEST: u8 = 0xa5;
+
+/// The version field of an SNMPv2c message
+pub const SNMP_V2C: i64 = 1;
+/// Error status for set requests, everything here is read only
+pub const NOT_WRITABLE: i64 = 17;
+
+pub type Oid = Vec<u32>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnmpValue {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Counter64(u64),
+    Null,
+    NoSuchObject,
+    EndOfMibView,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnmpMessage {
+    pub version: i64,
+    pub community: Vec<u8>,
+    pub pdu_type: u8,
+    pub request_id: i64,
+    /// Holds non repeaters for get bulk requests
+    pub error_status: i64,
+    /// Holds max repetitions for get bulk requests
+    pub error_index: i64,
+    pub varbinds: Vec<(Oid, SnmpValue)>,
+}
+
+/// Reads one tag length value, returning the tag, the value and whatever follows
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+fn expect_tlv(input: &[u8], expected: u8) -> Option<(&[u8], &[u8])> {
+    let (tag, value, rest) = read_tlv(input)?;
+    if tag == expected {
+        Some((value, rest))
+    } else {
+        None
+    }
+}
+
+fn decode_integer(value: &[u8]) -> Option<i64> {
+    if value.is_empty() || value.len() > 8 {
+        return None;
+    }
+    let sign = if value[0] & 0x80 != 0 { -1i64 } else { 0 };
+    Some(value.iter().fold(sign, |acc, b| (acc << 8) | *b as i64))
+}
+
+fn decode_unsigned(value: &[u8]) -> Option<u64> {
+    if value.is_empty() || value.len() > 9 {
+        return None;
+    }
+    Some(value.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+}
+
+fn decode_oid(value: &[u8]) -> Option<Oid> {
+    let (&first, rest) = value.split_first()?;
+    let mut oid = vec![(first / 40) as u32, (first % 40) as u32];
+    let mut arc: u32 = 0;
+    for b in rest {
+        arc = arc.checked_mul(128)? | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            oid.push(arc);
+            arc = 0;
+        }
+    }
+    Some(oid)
+}
+
+fn decode_value(tag: u8, value: &[u8]) -> Option<SnmpValue> {
+    Some(match tag {
+        INTEGER => SnmpValue::Integer(decode_integer(value)?),
+        OCTET_STRING => SnmpValue::OctetString(value.to_vec()),
+        GAUGE32 => SnmpValue::Gauge32(decode_unsigned(value)? as u32),
+        TIMETICKS => SnmpValue::TimeTicks(decode_unsigned(value)? as u32),
+        COUNTER64 => SnmpValue::Counter64(decode_unsigned(value)?),
+        NO_SUCH_OBJECT => SnmpValue::NoSuchObject,
+        END_OF_MIB_VIEW => SnmpValue::EndOfMibView,
+        // requests carry null values, anything else we don't serve is treated the same
+        _ => SnmpValue::Null,
+    })
+}
+
+pub fn decode_message(input: &[u8]) -> Option<SnmpMessage> {
+    let (message, _) = expect_tlv(input, SEQUENCE)?;
+    let (version, rest) = expect_tlv(message, INTEGER)?;
+    let (community, rest) = expect_tlv(rest, OCTET_STRING)?;
+    let (pdu_type, pdu, _) = read_tlv(rest)?;
+    let (request_id, rest) = expect_tlv(pdu, INTEGER)?;
+    let (error_status, rest) = expect_tlv(rest, INTEGER)?;
+    let (error_index, rest) = expect_tlv(rest, INTEGER)?;
+    let (mut list, _) = expect_tlv(rest, SEQUENCE)?;
+
+    let mut varbinds = Vec::new();
+    while !list.is_empty() {
+        let (varbind, rest) = expect_tlv(list, SEQUENCE)?;
+        let (oid, value) = expect_tlv(varbind, OBJECT_IDENTIFIER)?;
+        let (tag, value, _) = read_tlv(value)?;
+        varbinds.push((decode_oid(oid)?, decode_value(tag, value)?));
+        list = rest;
+    }
+
+    Some(SnmpMessage {
+        version: decode_integer(version)?,
+        community: community.to_vec(),
+        pdu_type,
+        request_id: decode_integer(request_id)?,
+        error_status: decode_integer(error_status)?,
+        error_index: decode_integer(error_index)?,
+        varbinds,
+    })
+}
+
+fn encode_tlv(tag: u8, value: &[u8], out: &mut Vec<u8>) {
+    out.push(tag);
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(value);
+}
+
+/// Two's complement big endian with redundant leading bytes removed
+fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1 {
+        let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+/// Unsigned types get a leading zero when their high bit is set so they don't read as negative
+fn encode_unsigned(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes
+        .iter()
+        .take_while(|b| **b == 0)
+        .count()
+        .min(bytes.len() - 1);
+    let mut out = Vec::new();
+    if bytes[skip] & 0x80 != 0 {
+        out.push(0);
+    }
+    out.extend_from_slice(&bytes[skip..]);
+    out
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match oid {
+        [first, second, rest @ ..] => {
+            out.push((first * 40 + second) as u8);
+            for arc in rest {
+                let mut chunks = vec![(arc & 0x7f) as u8];
+                let mut arc = arc >> 7;
+                while arc > 0 {
+                    chunks.push(0x80 | (arc & 0x7f) as u8);
+                    arc >>= 7;
+                }
+                out.extend(chunks.iter().rev());
+            }
+        }
+        [first] => out.push((first * 40) as u8),
+        [] => {}
+    }
+    out
+}
+
+fn encode_value(value: &SnmpValue, out: &mut Vec<u8>) {
+    match value {
+        SnmpValue::Integer(v) => encode_tlv(INTEGER, &encode_integer(*v), out),
+        SnmpValue::OctetString(v) => encode_tlv(OCTET_STRING, v, out),
+        SnmpValue::Gauge32(v) => encode_tlv(GAUGE32, &encode_unsigned(*v as u64), out),
+        SnmpValue::TimeTicks(v) => encode_tlv(TIMETICKS, &encode_unsigned(*v as u64), out),
+        SnmpValue::Counter64(v) => encode_tlv(COUNTER64, &encode_unsigned(*v), out),
+        SnmpValue::Null => encode_tlv(NULL, &[], out),
+        SnmpValue::NoSuchObject => encode_tlv(NO_SUCH_OBJECT, &[], out),
+        SnmpValue::EndOfMibView => encode_tlv(END_OF_MIB_VIEW, &[], out),
+    }
+}
+
+pub fn encode_message(message: &SnmpMessage) -> Vec<u8> {
+    let mut list = Vec::new();
+    for (oid, value) in message.varbinds.iter() {
+        let mut varbind = Vec::new();
+        encode_tlv(OBJECT_IDENTIFIER, &encode_oid(oid), &mut varbind);
+        encode_value(value, &mut varbind);
+        encode_tlv(SEQUENCE, &varbind, &mut list);
+    }
+
+    let mut pdu = Vec::new();
+    encode_tlv(INTEGER, &encode_integer(message.request_id), &mut pdu);
+    encode_tlv(INTEGER, &encode_integer(message.error_status), &mut pdu);
+    encode_tlv(INTEGER, &encode_integer(message.error_index), &mut pdu);
+    encode_tlv(SEQUENCE, &list, &mut pdu);
+
+    let mut body = Vec::new();
+    encode_tlv(INTEGER, &encode_integer(message.version), &mut body);
+    encode_tlv(OCTET_STRING, &message.community, &mut body);
+    encode_tlv(message.pdu_type, &pdu, &mut body);
+
+    let mut out = Vec::new();
+    encode_tlv(SEQUENCE, &body, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integer_encoding() {
+        assert_eq!(encode_integer(0), vec![0x00]);
+        assert_eq!(encode_integer(127), vec![0x7f]);
+        assert_eq!(encode_integer(128), vec![0x00, 0x80]);
+        assert_eq!(encode_integer(-1), vec![0xff]);
+        assert_eq!(encode_integer(-129), vec![0xff, 0x7f]);
+        for v in [0, 1, -1, 255, 256, -256, i32::MAX as i64, i64::MIN] {
+            assert_eq!(decode_integer(&encode_integer(v)), Some(v));
+        }
+        assert_eq!(encode_unsigned(u64::MAX).len(), 9);
+        assert_eq!(decode_unsigned(&encode_unsigned(u64::MAX)), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_oid_encoding() {
+        let oid = vec![1, 3, 6, 1, 4, 1, 2680, 1];
+        assert_eq!(encode_oid(&oid), vec![0x2b, 6, 1, 4, 1, 0x94, 0x78, 1]);
+        assert_eq!(decode_oid(&encode_oid(&oid)), Some(oid));
+    }
+
+    #[test]
+    fn test_decode_get_request() {
+        // snmpget -v2c -c public <host> 1.3.6.1.2.1.1.1.0
+        let packet = [
+            0x30, 0x29, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', 0xa0,
+            0x1c, 0x02, 0x04, 0x12, 0x34, 0x56, 0x78, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30,
+            0x0e, 0x30, 0x0c, 0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00, 0x05,
+            0x00,
+        ];
+        let message = decode_message(&packet).unwrap();
+        assert_eq!(message.version, SNMP_V2C);
+        assert_eq!(message.community, b"public".to_vec());
+        assert_eq!(message.pdu_type, GET_REQUEST);
+        assert_eq!(message.request_id, 0x12345678);
+        assert_eq!(
+            message.varbinds,
+            vec![(vec![1, 3, 6, 1, 2, 1, 1, 1, 0], SnmpValue::Null)]
+        );
+        // encoding what we decoded gives back the same packet
+        assert_eq!(encode_message(&message), packet.to_vec());
+        assert_eq!(decode_message(&packet[..20]), None);
+    }
+}
@@ -0,0 +1,350 @@
+//! A read only SNMPv2c agent so that the SNMP based monitoring most WISPs already run can poll routers without
+//! a custom integration. It serves a small custom MIB, described in docs/ALTHEA-ROUTER-MIB.txt, covering
+//! interface counters, the neighbor count, the balance and the state of the exit. Althea has no registered
+//! enterprise number so the MIB lives in the experimental arc. The agent only runs if the snmp section is
+//! present in the config, and answers nothing but get, get next and get bulk requests carrying the
+//! configured community.
+
+pub mod ber;
+
+use crate::exit_manager::get_current_exit;
+use crate::heartbeat::get_selected_exit_server;
+use crate::snmp::ber::{
+    decode_message, encode_message, Oid, SnmpMessage, SnmpValue, GET_BULK_REQUEST,
+    GET_NEXT_REQUEST, GET_REQUEST, GET_RESPONSE, NOT_WRITABLE, SET_REQUEST, SNMP_V2C,
+};
+use crate::RitaClientError;
+use althea_types::ExitState;
+use rita_common::blockchain_oracle::{get_oracle_balance, low_balance};
+use rita_common::tunnel_manager::tm_get_neighbors;
+use rita_common::KI;
+use settings::snmp::SnmpSettings;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Instant;
+
+/// 1.3.6.1.3 is the experimental arc
+pub const ALTHEA_MIB_ROOT: [u32; 6] = [1, 3, 6, 1, 3, 1337];
+/// Get bulk responses are cut off here to stay within a single udp packet
+const MAX_BULK_VARBINDS: usize = 64;
+const MAX_PACKET_SIZE: usize = 65507;
+
+lazy_static! {
+    static ref AGENT_START: Instant = Instant::now();
+}
+
+fn mib_oid(suffix: &[u32]) -> Oid {
+    let mut oid = ALTHEA_MIB_ROOT.to_vec();
+    oid.extend_from_slice(suffix);
+    oid
+}
+
+fn string_value(value: impl ToString) -> SnmpValue {
+    SnmpValue::OctetString(value.to_string().into_bytes())
+}
+
+/// TruthValue from SNMPv2-TC
+fn truth_value(value: bool) -> SnmpValue {
+    SnmpValue::Integer(if value { 1 } else { 2 })
+}
+
+fn exit_state_value(state: Option<&ExitState>) -> SnmpValue {
+    SnmpValue::Integer(match state {
+        None => 1,
+        Some(ExitState::New) => 2,
+        Some(ExitState::GotInfo { .. }) => 3,
+        Some(ExitState::Pending { .. }) => 4,
+        Some(ExitState::Registered { .. }) => 5,
+        Some(ExitState::Denied { .. }) => 6,
+    })
+}
+
+/// Every object the agent serves, sorted by oid. Built fresh for each request, which is cheap next to
+/// how rarely monitoring polls
+fn mib_snapshot() -> Vec<(Oid, SnmpValue)> {
+    let rita_client = settings::get_rita_client();
+    let mut objects = vec![
+        (mib_oid(&[1, 1, 0]), string_value(env!("CARGO_PKG_VERSION"))),
+        (
+            mib_oid(&[1, 2, 0]),
+            string_value(
+                rita_client
+                    .network
+                    .wg_public_key
+                    .map(|k| k.to_string())
+                    .unwrap_or_default(),
+            ),
+        ),
+        (
+            mib_oid(&[1, 3, 0]),
+            string_value(
+                rita_client
+                    .network
+                    .mesh_ip
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default(),
+            ),
+        ),
+        (
+            mib_oid(&[1, 4, 0]),
+            SnmpValue::TimeTicks((AGENT_START.elapsed().as_millis() / 10) as u32),
+        ),
+        (
+            mib_oid(&[2, 1, 0]),
+            SnmpValue::Gauge32(tm_get_neighbors().len() as u32),
+        ),
+        // the balance is in wei, which overflows every numeric SNMP type
+        (
+            mib_oid(&[3, 1, 0]),
+            string_value(
+                get_oracle_balance()
+                    .map(|b| b.to_string())
+                    .unwrap_or_default(),
+            ),
+        ),
+        (mib_oid(&[3, 2, 0]), truth_value(low_balance())),
+    ];
+
+    let exit = get_selected_exit_server();
+    objects.push((
+        mib_oid(&[4, 1, 0]),
+        string_value(
+            get_current_exit()
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+        ),
+    ));
+    objects.push((
+        mib_oid(&[4, 2, 0]),
+        exit_state_value(exit.as_ref().map(|e| &e.info)),
+    ));
+
+    match KI.get_per_interface_usage() {
+        Ok(mut interfaces) => {
+            // rows are numbered by name, so an index can change when interfaces come and go
+            interfaces.sort_by(|a, b| a.interface_name.cmp(&b.interface_name));
+            objects.push((
+                mib_oid(&[5, 1, 0]),
+                SnmpValue::Gauge32(interfaces.len() as u32),
+            ));
+            for (i, iface) in interfaces.iter().enumerate() {
+                let index = i as u32 + 1;
+                let columns = [
+                    string_value(&iface.interface_name),
+                    SnmpValue::Counter64(iface.recieve_bytes),
+                    SnmpValue::Counter64(iface.transmit_bytes),
+                    SnmpValue::Counter64(iface.recieve_packets),
+                    SnmpValue::Counter64(iface.transmit_packets),
+                    SnmpValue::Counter64(iface.recieve_errors),
+                    SnmpValue::Counter64(iface.transmit_errors),
+                    SnmpValue::Counter64(iface.recieve_dropped),
+                ];
+                for (column, value) in columns.into_iter().enumerate() {
+                    objects.push((mib_oid(&[5, 2, 1, column as u32 + 1, index]), value));
+                }
+            }
+        }
+        Err(e) => warn!("Failed to read interface counters for snmp {:?}", e),
+    }
+
+    objects.sort_by(|a, b| a.0.cmp(&b.0));
+    objects
+}
+
+fn get_exact(objects: &[(Oid, SnmpValue)], oid: &Oid) -> SnmpValue {
+    match objects.binary_search_by(|(o, _)| o.cmp(oid)) {
+        Ok(i) => objects[i].1.clone(),
+        Err(_) => SnmpValue::NoSuchObject,
+    }
+}
+
+fn get_next(objects: &[(Oid, SnmpValue)], oid: &Oid) -> (Oid, SnmpValue) {
+    match objects.iter().find(|(o, _)| o > oid) {
+        Some((o, v)) => (o.clone(), v.clone()),
+        None => (oid.clone(), SnmpValue::EndOfMibView),
+    }
+}
+
+/// Answers a request against the given objects, None if it should be ignored
+fn respond(
+    request: SnmpMessage,
+    objects: &[(Oid, SnmpValue)],
+    community: &[u8],
+) -> Option<SnmpMessage> {
+    if request.version != SNMP_V2C || request.community != community {
+        return None;
+    }
+    let mut response = SnmpMessage {
+        pdu_type: GET_RESPONSE,
+        error_status: 0,
+        error_index: 0,
+        varbinds: Vec::new(),
+        ..request.clone()
+    };
+    match request.pdu_type {
+        GET_REQUEST => {
+            for (oid, _) in request.varbinds {
+                let value = get_exact(objects, &oid);
+                response.varbinds.push((oid, value));
+            }
+        }
+        GET_NEXT_REQUEST => {
+            for (oid, _) in request.varbinds {
+                response.varbinds.push(get_next(objects, &oid));
+            }
+        }
+        GET_BULK_REQUEST => {
+            let non_repeaters = request.error_status.max(0) as usize;
+            let max_repetitions = request.error_index.max(0) as usize;
+            let (singles, repeaters) = request
+                .varbinds
+                .split_at(non_repeaters.min(request.varbinds.len()));
+            for (oid, _) in singles {
+                response.varbinds.push(get_next(objects, oid));
+            }
+            let mut cursors: Vec<Oid> = repeaters.iter().map(|(oid, _)| oid.clone()).collect();
+            for _ in 0..max_repetitions {
+                if cursors.is_empty() || response.varbinds.len() >= MAX_BULK_VARBINDS {
+                    break;
+                }
+                for cursor in cursors.iter_mut() {
+                    let (oid, value) = get_next(objects, cursor);
+                    *cursor = oid.clone();
+                    response.varbinds.push((oid, value));
+                }
+            }
+            response.varbinds.truncate(MAX_BULK_VARBINDS);
+        }
+        SET_REQUEST => {
+            response.error_status = NOT_WRITABLE;
+            response.error_index = 1;
+            response.varbinds = request.varbinds;
+        }
+        _ => return None,
+    }
+    Some(response)
+}
+
+/// Starts the SNMP agent if the snmp settings section is present
+pub fn start_snmp_agent() {
+    let settings: SnmpSettings = match settings::get_rita_client().snmp {
+        Some(settings) => settings,
+        None => return,
+    };
+    lazy_static::initialize(&AGENT_START);
+    thread::spawn(move || {
+        while let Err(e) = {
+            let settings = settings.clone();
+            thread::spawn(move || {
+                if let Err(e) = run_snmp_agent(settings) {
+                    error!("Snmp agent failed with {:?}", e);
+                }
+            })
+            .join()
+        } {
+            error!("Snmp agent paniced! Respawning {:?}", e);
+        }
+    });
+}
+
+fn run_snmp_agent(settings: SnmpSettings) -> Result<(), RitaClientError> {
+    let socket = match UdpSocket::bind(&settings.bind_address) {
+        Ok(socket) => socket,
+        Err(e) => {
+            return Err(RitaClientError::MiscStringError(format!(
+                "Could not bind snmp agent to {} {:?}",
+                settings.bind_address, e
+            )))
+        }
+    };
+    info!("Snmp agent listening on {}", settings.bind_address);
+    let community = settings.community.into_bytes();
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("Snmp agent failed to receive {:?}", e);
+                continue;
+            }
+        };
+        let request = match decode_message(&buf[..len]) {
+            Some(request) => request,
+            None => {
+                trace!("Ignoring malformed snmp packet from {}", from);
+                continue;
+            }
+        };
+        let response = match respond(request, &mib_snapshot(), &community) {
+            Some(response) => response,
+            None => continue,
+        };
+        if let Err(e) = socket.send_to(&encode_message(&response), from) {
+            warn!("Snmp agent failed to reply to {} {:?}", from, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(pdu_type: u8, oids: Vec<Oid>) -> SnmpMessage {
+        SnmpMessage {
+            version: SNMP_V2C,
+            community: b"secret".to_vec(),
+            pdu_type,
+            request_id: 7,
+            error_status: 0,
+            error_index: 0,
+            varbinds: oids.into_iter().map(|o| (o, SnmpValue::Null)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_respond() {
+        let objects = vec![
+            (mib_oid(&[1, 1, 0]), string_value("1.0")),
+            (mib_oid(&[2, 1, 0]), SnmpValue::Gauge32(3)),
+            (mib_oid(&[5, 2, 1, 2, 1]), SnmpValue::Counter64(10)),
+        ];
+
+        let res = respond(
+            request(GET_REQUEST, vec![mib_oid(&[2, 1, 0]), mib_oid(&[9])]),
+            &objects,
+            b"secret",
+        )
+        .unwrap();
+        assert_eq!(res.pdu_type, GET_RESPONSE);
+        assert_eq!(res.request_id, 7);
+        assert_eq!(res.varbinds[0].1, SnmpValue::Gauge32(3));
+        assert_eq!(res.varbinds[1].1, SnmpValue::NoSuchObject);
+
+        // walking from the root visits every object in order then ends
+        let res = respond(
+            request(GET_NEXT_REQUEST, vec![ALTHEA_MIB_ROOT.to_vec()]),
+            &objects,
+            b"secret",
+        )
+        .unwrap();
+        assert_eq!(res.varbinds[0].0, mib_oid(&[1, 1, 0]));
+        let mut bulk = request(GET_BULK_REQUEST, vec![mib_oid(&[1, 1, 0])]);
+        bulk.error_index = 5;
+        let res = respond(bulk, &objects, b"secret").unwrap();
+        assert_eq!(res.varbinds.len(), 5);
+        assert_eq!(res.varbinds[1].1, SnmpValue::Counter64(10));
+        assert_eq!(res.varbinds[2].1, SnmpValue::EndOfMibView);
+
+        assert_eq!(
+            respond(request(GET_REQUEST, vec![]), &objects, b"public"),
+            None
+        );
+        let res = respond(
+            request(SET_REQUEST, vec![mib_oid(&[1, 1, 0])]),
+            &objects,
+            b"secret",
+        )
+        .unwrap();
+        assert_eq!(res.error_status, NOT_WRITABLE);
+    }
+}
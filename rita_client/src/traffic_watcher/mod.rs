@@ -11,9 +11,10 @@
 //!
 //! So this module contains two major components.
 //!
-//! TrafficWatcher monitors system traffic by interfacing with KernelInterface to create and check
-//! iptables and ip counters on each per hop tunnel (the WireGuard tunnel between two devices). These counts
-//! are then stored and used to compute the usage amounts displayed to the user.
+//! TrafficWatcher monitors the exit tunnel by reading WireGuard's own per peer byte counters over netlink.
+//! Each snapshot is compared with the last, tracking which interface and exit the counters came from so that
+//! usage stays monotonic when the tunnel is rebuilt. These counts are then stored and used to compute the
+//! usage amounts displayed to the user.
 //!
 //! QueryExitDebts asks the exit what it thinks this particular client owes (over the secure channel of the exit tunnel)
 //! validating if this number is correct is difficult, because the exit is serving us with a total debt while our local
@@ -23,7 +24,8 @@
 
 use crate::rita_loop::is_gateway_client;
 use crate::RitaClientError;
-use althea_types::Identity;
+use althea_kernel_interface::wg_iface_counter::WgUsage;
+use althea_types::{Identity, WgKey};
 use babel_monitor::parsing::get_installed_route;
 use babel_monitor::structs::BabelMonitorError;
 use babel_monitor::structs::Route;
@@ -69,16 +71,60 @@ pub fn get_traffic_watcher_write_ref(
     input.get_mut(&netns).unwrap()
 }
 
+/// The largest amount of traffic we believe can pass over the exit tunnel in one round, a counter that
+/// goes backwards by less than this has wrapped, otherwise the counters were reset underneath us
+const MAX_ROUND_BYTES: u64 = 1 << 40;
+
 #[derive(Default, Clone)]
 pub struct TrafficWatcher {
-    // last read download
-    last_read_input: u64,
-    // last read upload
-    last_read_output: u64,
+    /// the exit tunnel counters as of the last round, None until the first read
+    last_counters: Option<TunnelCounters>,
     /// cached exit destination price value
     last_exit_dest_price: u128,
 }
 
+/// The wg_exit counters for the exit peer and the tunnel they were read from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct TunnelCounters {
+    ifindex: u32,
+    exit: WgKey,
+    usage: WgUsage,
+}
+
+/// How much a single counter has moved since it was last read, see usage_since
+fn counter_since(last: u64, current: u64) -> u64 {
+    if current >= last {
+        return current - last;
+    }
+    let wrapped = current.wrapping_sub(last);
+    if wrapped <= MAX_ROUND_BYTES {
+        wrapped
+    } else {
+        warn!(
+            "Exit tunnel counter went from {} to {}, it was reset",
+            last, current
+        );
+        current
+    }
+}
+
+/// Usage since the last round. A new interface or a new exit peer starts its counters at zero so all
+/// of its traffic is new usage, on the same tunnel the counters only ever move forward, wrapping
+/// around if needed
+fn usage_since(last: Option<&TunnelCounters>, current: &TunnelCounters) -> WgUsage {
+    match last {
+        Some(last) if last.ifindex == current.ifindex && last.exit == current.exit => WgUsage {
+            upload: counter_since(last.usage.upload, current.usage.upload),
+            download: counter_since(last.usage.download, current.usage.download),
+        },
+        Some(_) => {
+            warn!("Exit tunnel was rebuilt, counting from its fresh counters");
+            current.usage
+        }
+        None => current.usage,
+    }
+}
+
 /// Used to request what the exits thinks this clients debts are. We will compare
 /// this value to our own computation and alert to any large discrepencies, but in
 /// general we have to trust the exit. In a pay per forward system nodes within the
@@ -205,6 +251,42 @@ pub async fn query_exit_debts(msg: QueryExitDebts) {
     }
 }
 
+/// Gets our debt from the secondary exit of an exit split, see exit_manager::exit_split. Its share of the
+/// traffic leaves through wg_exit_split so the local calculation, which reads wg_exit, can't cover it and
+/// only the exit's own count is used. The exit is asked over the mesh since both exits may use the same
+/// internal ip
+pub async fn query_split_exit_debt(exit_id: Identity, exit_port: u16) {
+    if is_gateway_client() {
+        return;
+    }
+    let our_id = settings::get_rita_client().get_identity();
+    let request = format!("http://[{}]:{exit_port}/client_debt", exit_id.mesh_ip);
+    let client = awc::Client::default();
+    let response = client
+        .post(request.clone())
+        .timeout(Duration::from_secs(5))
+        .send_json(&our_id)
+        .await;
+    let mut response = match response {
+        Ok(a) => a,
+        Err(e) => {
+            error!(
+                "Split exit debts request to {} failed with {:?}",
+                request, e
+            );
+            return;
+        }
+    };
+    match response.json::<Int256>().await {
+        Ok(debt) if debt >= Int256::zero() => traffic_replace(Traffic {
+            from: exit_id,
+            amount: debt,
+        }),
+        Ok(debt) => warn!("Split exit {} says it owes us {}", exit_id.mesh_ip, debt),
+        Err(e) => error!("Unable to get split exit debt json with : {}", e),
+    }
+}
+
 /// Returns the babel route to a given mesh ip with the properly capped price
 fn find_exit_route_capped(
     exit_mesh_ip: IpAddr,
@@ -230,21 +312,26 @@ pub fn local_traffic_calculation(
     let exit_route = find_exit_route_capped(exit.mesh_ip, routes)?;
     info!("Exit metric: {}", exit_route.metric);
 
-    let counter = match KI.read_wg_counters("wg_exit") {
-        Ok(res) => {
-            if res.len() > 1 {
+    let counters = match KI.read_wg_counter_snapshot("wg_exit") {
+        Ok(snapshot) => {
+            if snapshot.peers.len() > 1 {
                 warn!("wg_exit client tunnel has multiple peers!");
-            } else if res.is_empty() {
-                warn!("No peers on wg_exit why is client traffic watcher running?");
-                return Err(RitaClientError::MiscStringError(
-                    "No peers on wg_exit".to_string(),
-                ));
             }
-            // unwrap is safe because we check that len is not equal to zero
-            // then we toss the exit's wg key as we don't need it
-            // create an iterator, take the last (and only) value, then grab the
-            // counter and not the key from the hashmap entry
-            let ret = *res.iter().last().unwrap().1;
+            // the only peer on wg_exit is the exit, under its exit tunnel key
+            let (exit_key, usage) = match snapshot.peers.iter().last() {
+                Some(peer) => peer,
+                None => {
+                    warn!("No peers on wg_exit why is client traffic watcher running?");
+                    return Err(RitaClientError::MiscStringError(
+                        "No peers on wg_exit".to_string(),
+                    ));
+                }
+            };
+            let ret = TunnelCounters {
+                ifindex: snapshot.ifindex,
+                exit: *exit_key,
+                usage: *usage,
+            };
             info!("We determined local counters as: {:?}", ret);
             ret
         }
@@ -257,19 +344,10 @@ pub fn local_traffic_calculation(
         }
     };
 
-    // bandwidth usage should always increase if it doesn't the interface has been
-    // deleted and recreated and we need to reset our usage, also protects from negatives
-    if history.last_read_input > counter.download || history.last_read_output > counter.upload {
-        warn!("Exit tunnel reset resetting counters");
-        history.last_read_input = 0;
-        history.last_read_output = 0;
-    }
-
-    let input = counter.download - history.last_read_input;
-    let output = counter.upload - history.last_read_output;
-
-    history.last_read_input = counter.download;
-    history.last_read_output = counter.upload;
+    let usage = usage_since(history.last_counters.as_ref(), &counters);
+    history.last_counters = Some(counters);
+    let input = usage.download;
+    let output = usage.upload;
 
     info!("{:?} bytes downloaded from exit this round", &input);
     info!("{:?} bytes uploaded to exit this round", &output);
@@ -342,3 +420,77 @@ pub fn local_traffic_calculation(
 pub fn get_exit_dest_price() -> u128 {
     get_traffic_watcher().last_exit_dest_price
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters(ifindex: u32, exit: WgKey, download: u64, upload: u64) -> TunnelCounters {
+        TunnelCounters {
+            ifindex,
+            exit,
+            usage: WgUsage { upload, download },
+        }
+    }
+
+    fn usage(download: u64, upload: u64) -> WgUsage {
+        WgUsage { upload, download }
+    }
+
+    #[test]
+    fn test_usage_since_same_tunnel() {
+        let exit: WgKey = "88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs="
+            .parse()
+            .unwrap();
+        let first = counters(7, exit, 1000, 200);
+        assert_eq!(usage_since(None, &first), usage(1000, 200));
+        assert_eq!(
+            usage_since(Some(&first), &counters(7, exit, 1500, 200)),
+            usage(500, 0)
+        );
+    }
+
+    #[test]
+    fn test_usage_since_rollover() {
+        let exit: WgKey = "88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs="
+            .parse()
+            .unwrap();
+        let last = counters(7, exit, u64::MAX - 99, 50);
+        assert_eq!(
+            usage_since(Some(&last), &counters(7, exit, 400, 60)),
+            usage(500, 10)
+        );
+        // too far back to be a wrap, the counters restarted on the same interface
+        let last = counters(7, exit, 1 << 50, 50);
+        assert_eq!(
+            usage_since(Some(&last), &counters(7, exit, 300, 60)),
+            usage(300, 10)
+        );
+    }
+
+    #[test]
+    fn test_usage_since_interface_recreated() {
+        let exit: WgKey = "88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs="
+            .parse()
+            .unwrap();
+        let other_exit: WgKey = "bvM10HW73yePrxdtCQQ4U20W5ogogdiZtUihrPc/oGY="
+            .parse()
+            .unwrap();
+        let last = counters(7, exit, 5000, 900);
+        // rebuilt tunnel that already passed more traffic than the old one had
+        assert_eq!(
+            usage_since(Some(&last), &counters(9, exit, 6000, 1000)),
+            usage(6000, 1000)
+        );
+        // rebuilt tunnel with less traffic
+        assert_eq!(
+            usage_since(Some(&last), &counters(9, exit, 100, 10)),
+            usage(100, 10)
+        );
+        // same interface switched to another exit
+        assert_eq!(
+            usage_since(Some(&last), &counters(7, other_exit, 100, 10)),
+            usage(100, 10)
+        );
+    }
+}
@@ -0,0 +1,536 @@
+//! Static routes and firewall rules added by the user from the dashboard, for the few custom routes and port
+//! openings power users need without dropping to ssh. Both are kept in the settings, routes are re-added every
+//! tick since an interface restart drops them, firewall rules are uci sections prefixed with rita_user_ so they
+//! survive firewall reloads and are never confused with the ones the firmware or Rita set up. Anything that
+//! would step on what Rita manages, the default route, the mesh, exit and lan subnets, the wireguard
+//! interfaces and the ports Rita listens on, is refused.
+
+use crate::dashboard::lan::{get_lan_network, reserved_subnets};
+use crate::exit_manager::get_exit_tunnel_mtu;
+use crate::RitaClientError;
+use althea_kernel_interface::KI;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use settings::user_rules::{FirewallRule, StaticRoute};
+use std::net::Ipv6Addr;
+
+/// Routes are re-added every tick, this bounds the commands that takes
+pub const MAX_STATIC_ROUTES: usize = 32;
+pub const MAX_FIREWALL_RULES: usize = 64;
+
+const USER_SECTION_PREFIX: &str = "rita_user_";
+/// Uci section names are limited to letters, numbers and _
+const MAX_RULE_NAME_CHARS: usize = 32;
+/// Linux limits interface names to 15 characters
+const MAX_INTERFACE_CHARS: usize = 15;
+
+/// What user routes and rules have to stay clear of
+#[derive(Debug, Clone, Default)]
+struct ManagedNetwork {
+    /// Subnets with a description for error messages
+    subnets: Vec<(String, IpNetwork)>,
+    /// Ports Rita or the services it manages listen on
+    ports: Vec<(String, u16)>,
+    /// The first per hop tunnel port, every port from here up may be taken by a tunnel
+    wg_start_port: u16,
+    lan: Option<Ipv4Network>,
+}
+
+fn invalid(message: String) -> RitaClientError {
+    RitaClientError::MiscStringError(message)
+}
+
+fn managed_network() -> ManagedNetwork {
+    let rita_client = settings::get_rita_client();
+    let network = &rita_client.network;
+    let mut subnets: Vec<(String, IpNetwork)> = reserved_subnets()
+        .into_iter()
+        .map(|(description, subnet)| (description, IpNetwork::V4(subnet)))
+        .collect();
+    if let Ok(mesh) = Ipv6Network::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0), 8) {
+        subnets.push(("mesh".to_string(), IpNetwork::V6(mesh)));
+    }
+    let lan = get_lan_network().ok();
+    if let Some(lan) = lan {
+        subnets.push(("lan".to_string(), IpNetwork::V4(lan)));
+    }
+    if let Some(guest) = &rita_client.guest_network {
+        if let Ok(guest) = Ipv4Network::new(guest.router_ip, 24) {
+            subnets.push(("guest network".to_string(), IpNetwork::V4(guest)));
+        }
+    }
+    ManagedNetwork {
+        subnets,
+        ports: vec![
+            ("dns".to_string(), 53),
+            ("babel".to_string(), network.babel_port),
+            ("rita hello".to_string(), network.rita_hello_port),
+            ("rita contact".to_string(), network.rita_contact_port),
+            ("rita dashboard".to_string(), network.rita_dashboard_port),
+            (
+                "exit tunnel".to_string(),
+                rita_client.exit_client.wg_listen_port,
+            ),
+        ],
+        wg_start_port: network.wg_start_port,
+        lan,
+    }
+}
+
+fn overlaps(a: IpNetwork, b: IpNetwork) -> bool {
+    match (a, b) {
+        (IpNetwork::V4(a), IpNetwork::V4(b)) => a.overlaps(b),
+        (IpNetwork::V6(a), IpNetwork::V6(b)) => a.overlaps(b),
+        _ => false,
+    }
+}
+
+fn valid_interface_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_INTERFACE_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+fn valid_uci_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_RULE_NAME_CHARS
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn validate_static_route(
+    route: &StaticRoute,
+    managed: &ManagedNetwork,
+) -> Result<(), RitaClientError> {
+    let destination = route.destination;
+    if destination.prefix() == 0 {
+        return Err(invalid(
+            "The default route is managed by Rita and can not be set".to_string(),
+        ));
+    }
+    if destination.ip() != destination.network() {
+        return Err(invalid(format!(
+            "Route destination {destination} is not a network address, use {}/{}",
+            destination.network(),
+            destination.prefix()
+        )));
+    }
+    if route.gateway.is_none() && route.interface.is_none() {
+        return Err(invalid(format!(
+            "Route to {destination} needs a gateway or an interface"
+        )));
+    }
+    if let Some(gateway) = route.gateway {
+        if gateway.is_ipv4() != destination.is_ipv4() {
+            return Err(invalid(format!(
+                "Gateway {gateway} is not the same address family as {destination}"
+            )));
+        }
+    }
+    if let Some(interface) = &route.interface {
+        if !valid_interface_name(interface) {
+            return Err(invalid(format!("Invalid interface name {interface}")));
+        }
+        if interface.starts_with("wg") {
+            return Err(invalid(format!(
+                "Routes over {interface} are managed by Rita"
+            )));
+        }
+    }
+    for (description, subnet) in managed.subnets.iter() {
+        if overlaps(destination, *subnet) {
+            return Err(invalid(format!(
+                "Route to {destination} conflicts with the {description} {subnet}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks every route and that no two of them are for the same destination
+fn validate_static_routes(
+    routes: &[StaticRoute],
+    managed: &ManagedNetwork,
+) -> Result<(), RitaClientError> {
+    if routes.len() > MAX_STATIC_ROUTES {
+        return Err(invalid(format!(
+            "At most {MAX_STATIC_ROUTES} static routes can be set"
+        )));
+    }
+    for (i, route) in routes.iter().enumerate() {
+        validate_static_route(route, managed)?;
+        if routes[..i]
+            .iter()
+            .any(|other| other.destination == route.destination)
+        {
+            return Err(invalid(format!(
+                "There is already a route to {}",
+                route.destination
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn check_port(port: u16, managed: &ManagedNetwork) -> Result<(), RitaClientError> {
+    if port == 0 {
+        return Err(invalid("Port 0 is not a valid port".to_string()));
+    }
+    if let Some((description, _)) = managed.ports.iter().find(|(_, p)| *p == port) {
+        return Err(invalid(format!(
+            "Port {port} is used by {description} and is managed by Rita"
+        )));
+    }
+    if port >= managed.wg_start_port {
+        return Err(invalid(format!(
+            "Ports from {} up are reserved for Rita's tunnels",
+            managed.wg_start_port
+        )));
+    }
+    Ok(())
+}
+
+fn validate_firewall_rule(
+    rule: &FirewallRule,
+    managed: &ManagedNetwork,
+) -> Result<(), RitaClientError> {
+    if !valid_uci_name(&rule.name) {
+        return Err(invalid(format!(
+            "Invalid rule name {}, use up to {MAX_RULE_NAME_CHARS} letters, numbers or _",
+            rule.name
+        )));
+    }
+    if !valid_uci_name(&rule.src_zone) {
+        return Err(invalid(format!("Invalid firewall zone {}", rule.src_zone)));
+    }
+    // a forward takes the port away from the router as well, so both kinds are checked the same way
+    check_port(rule.port, managed)?;
+    match rule.forward_to {
+        Some(ip) => {
+            let lan = match managed.lan {
+                Some(lan) => lan,
+                None => {
+                    return Err(invalid(
+                        "Could not get the lan subnet to check the forward against".to_string(),
+                    ))
+                }
+            };
+            if !lan.contains(ip) || ip == lan.network() || ip == lan.broadcast() {
+                return Err(invalid(format!(
+                    "{ip} is not a device address on the lan {lan}"
+                )));
+            }
+            if rule.forward_port == Some(0) {
+                return Err(invalid("Port 0 is not a valid port".to_string()));
+            }
+        }
+        None => {
+            if rule.forward_port.is_some() {
+                return Err(invalid(format!(
+                    "Rule {} has a forward port but no device to forward to",
+                    rule.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks every rule and that no two of them share a name or open the same port
+fn validate_firewall_rules(
+    rules: &[FirewallRule],
+    managed: &ManagedNetwork,
+) -> Result<(), RitaClientError> {
+    if rules.len() > MAX_FIREWALL_RULES {
+        return Err(invalid(format!(
+            "At most {MAX_FIREWALL_RULES} firewall rules can be set"
+        )));
+    }
+    for (i, rule) in rules.iter().enumerate() {
+        validate_firewall_rule(rule, managed)?;
+        for other in rules[..i].iter() {
+            if other.name == rule.name {
+                return Err(invalid(format!(
+                    "There is already a rule named {}",
+                    rule.name
+                )));
+            }
+            if other.src_zone == rule.src_zone
+                && other.port == rule.port
+                && other.protocol.overlaps(rule.protocol)
+            {
+                return Err(invalid(format!(
+                    "Rule {} conflicts with rule {} for port {}",
+                    rule.name, other.name, rule.port
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_user_routes(routes: &[StaticRoute]) -> Result<(), RitaClientError> {
+    validate_static_routes(routes, &managed_network())
+}
+
+pub fn validate_user_firewall_rules(rules: &[FirewallRule]) -> Result<(), RitaClientError> {
+    validate_firewall_rules(rules, &managed_network())
+}
+
+pub fn add_static_route(route: &StaticRoute) -> Result<(), RitaClientError> {
+    KI.replace_route(
+        &route.destination.to_string(),
+        route.gateway,
+        route.interface.as_deref(),
+        route.metric,
+    )?;
+    Ok(())
+}
+
+pub fn remove_static_route(route: &StaticRoute) -> Result<(), RitaClientError> {
+    KI.delete_route(&route.destination.to_string(), route.metric)?;
+    Ok(())
+}
+
+/// Run every client loop tick, puts back any user route an interface restart removed
+pub fn tick_static_routes() {
+    for route in settings::get_rita_client().static_routes.iter() {
+        if let Err(e) = add_static_route(route) {
+            warn!("Failed to set static route {:?} {:?}", route, e);
+        }
+    }
+}
+
+/// The names of every firewall zone
+fn get_firewall_zones() -> Result<Vec<String>, RitaClientError> {
+    let config = KI.uci_show(Some("firewall"))?;
+    Ok(config
+        .iter()
+        // section declarations have no option after the section name
+        .filter(|(section, kind)| *kind == "zone" && section.matches('.').count() == 1)
+        .filter_map(|(section, _)| config.get(&format!("{section}.name")).cloned())
+        .collect())
+}
+
+fn set_firewall_rule_section(rule: &FirewallRule) -> Result<(), RitaClientError> {
+    let section = format!("firewall.{USER_SECTION_PREFIX}{}", rule.name);
+    let port = rule.port.to_string();
+    match rule.forward_to {
+        Some(ip) => {
+            KI.set_uci_var(&section, "redirect")?;
+            KI.set_uci_var(&format!("{section}.src_dport"), &port)?;
+            KI.set_uci_var(&format!("{section}.dest"), "lan")?;
+            KI.set_uci_var(&format!("{section}.dest_ip"), &ip.to_string())?;
+            KI.set_uci_var(
+                &format!("{section}.dest_port"),
+                &rule.forward_port.unwrap_or(rule.port).to_string(),
+            )?;
+            KI.set_uci_var(&format!("{section}.target"), "DNAT")?;
+        }
+        None => {
+            KI.set_uci_var(&section, "rule")?;
+            KI.set_uci_var(&format!("{section}.dest_port"), &port)?;
+            KI.set_uci_var(&format!("{section}.target"), "ACCEPT")?;
+        }
+    }
+    KI.set_uci_var(&format!("{section}.name"), &rule.name)?;
+    KI.set_uci_var(&format!("{section}.src"), &rule.src_zone)?;
+    KI.set_uci_var(&format!("{section}.proto"), rule.protocol.as_uci())?;
+    Ok(())
+}
+
+/// Replaces the user firewall sections with these rules and reloads the firewall
+pub fn apply_firewall_rules(rules: &[FirewallRule]) -> Result<(), RitaClientError> {
+    validate_user_firewall_rules(rules)?;
+    let zones = get_firewall_zones()?;
+    if let Some(rule) = rules.iter().find(|rule| !zones.contains(&rule.src_zone)) {
+        return Err(invalid(format!(
+            "There is no firewall zone {}",
+            rule.src_zone
+        )));
+    }
+
+    // start from a clean slate so removed rules don't linger
+    let config = KI.uci_show(Some("firewall"))?;
+    for section in config.keys() {
+        if section.matches('.').count() == 1
+            && section.starts_with(&format!("firewall.{USER_SECTION_PREFIX}"))
+        {
+            KI.del_uci_var(section)?;
+        }
+    }
+    for rule in rules {
+        set_firewall_rule_section(rule)?;
+    }
+    KI.uci_commit("firewall")?;
+    KI.refresh_initd("firewall")?;
+
+    // we have invalidated the old nat rules, update them
+    let rita_client = settings::get_rita_client();
+    KI.create_client_nat_rules(get_exit_tunnel_mtu())?;
+    if rita_client.guest_network.is_some() {
+        KI.create_guest_forward_rules()?;
+    }
+    Ok(())
+}
+
+/// Undoes any uci changes made by a failed apply_firewall_rules()
+pub fn revert_firewall_rules() {
+    if let Err(e) = KI.uci_revert("firewall") {
+        trace!("Failed to revert firewall {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use settings::user_rules::FirewallProtocol;
+
+    fn managed() -> ManagedNetwork {
+        ManagedNetwork {
+            subnets: vec![
+                (
+                    "exit internal subnet".to_string(),
+                    "172.168.0.0/16".parse().unwrap(),
+                ),
+                ("mesh".to_string(), "fd00::/8".parse().unwrap()),
+                ("lan".to_string(), "192.168.10.0/24".parse().unwrap()),
+            ],
+            ports: vec![("rita dashboard".to_string(), 4877)],
+            wg_start_port: 60000,
+            lan: Some("192.168.10.0/24".parse().unwrap()),
+        }
+    }
+
+    fn route(destination: &str, gateway: Option<&str>, interface: Option<&str>) -> StaticRoute {
+        StaticRoute {
+            destination: destination.parse().unwrap(),
+            gateway: gateway.map(|g| g.parse().unwrap()),
+            interface: interface.map(|i| i.to_string()),
+            metric: None,
+        }
+    }
+
+    fn rule(name: &str, protocol: FirewallProtocol, port: u16) -> FirewallRule {
+        FirewallRule {
+            name: name.to_string(),
+            src_zone: "wan".to_string(),
+            protocol,
+            port,
+            forward_to: None,
+            forward_port: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_static_routes() {
+        let managed = managed();
+        assert!(validate_static_routes(
+            &[
+                route("10.20.0.0/16", Some("192.168.10.2"), None),
+                route("2001:db8::/32", None, Some("eth0.2")),
+            ],
+            &managed
+        )
+        .is_ok());
+        // default route
+        assert!(
+            validate_static_routes(&[route("0.0.0.0/0", Some("10.0.0.1"), None)], &managed)
+                .is_err()
+        );
+        // host bits set
+        assert!(
+            validate_static_routes(&[route("10.20.0.1/16", Some("10.0.0.1"), None)], &managed)
+                .is_err()
+        );
+        // nowhere to send it
+        assert!(validate_static_routes(&[route("10.20.0.0/16", None, None)], &managed).is_err());
+        // mixed families
+        assert!(
+            validate_static_routes(&[route("10.20.0.0/16", Some("fe80::1"), None)], &managed)
+                .is_err()
+        );
+        assert!(
+            validate_static_routes(&[route("10.20.0.0/16", None, Some("wg_exit"))], &managed)
+                .is_err()
+        );
+        assert!(
+            validate_static_routes(&[route("172.168.4.0/24", None, Some("eth0"))], &managed)
+                .is_err()
+        );
+        assert!(
+            validate_static_routes(&[route("fd00:1::/64", None, Some("eth0"))], &managed).is_err()
+        );
+        assert!(
+            validate_static_routes(&[route("192.168.0.0/16", None, Some("eth0"))], &managed)
+                .is_err()
+        );
+        assert!(validate_static_routes(
+            &[
+                route("10.20.0.0/16", None, Some("eth0")),
+                route("10.20.0.0/16", None, Some("eth1")),
+            ],
+            &managed
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_firewall_rules() {
+        let managed = managed();
+        let mut forward = rule("camera", FirewallProtocol::Tcp, 8080);
+        forward.forward_to = Some("192.168.10.20".parse().unwrap());
+        forward.forward_port = Some(80);
+        assert!(validate_firewall_rules(
+            &[
+                rule("ssh", FirewallProtocol::Tcp, 22),
+                rule("game", FirewallProtocol::Udp, 22),
+                forward.clone(),
+            ],
+            &managed
+        )
+        .is_ok());
+        assert!(
+            validate_firewall_rules(&[rule("bad-name", FirewallProtocol::Tcp, 22)], &managed)
+                .is_err()
+        );
+        assert!(
+            validate_firewall_rules(&[rule("zero", FirewallProtocol::Tcp, 0)], &managed).is_err()
+        );
+        assert!(
+            validate_firewall_rules(&[rule("dash", FirewallProtocol::Tcp, 4877)], &managed)
+                .is_err()
+        );
+        assert!(
+            validate_firewall_rules(&[rule("tunnel", FirewallProtocol::Udp, 60001)], &managed)
+                .is_err()
+        );
+        // duplicate names and overlapping ports
+        assert!(validate_firewall_rules(
+            &[
+                rule("ssh", FirewallProtocol::Tcp, 22),
+                rule("ssh", FirewallProtocol::Tcp, 2222),
+            ],
+            &managed
+        )
+        .is_err());
+        assert!(validate_firewall_rules(
+            &[
+                rule("ssh", FirewallProtocol::Tcp, 22),
+                rule("both", FirewallProtocol::TcpUdp, 22),
+            ],
+            &managed
+        )
+        .is_err());
+        // forwards must stay on the lan
+        let mut outside = forward.clone();
+        outside.forward_to = Some("192.168.11.20".parse().unwrap());
+        assert!(validate_firewall_rules(&[outside], &managed).is_err());
+        let mut broadcast = forward.clone();
+        broadcast.forward_to = Some("192.168.10.255".parse().unwrap());
+        assert!(validate_firewall_rules(&[broadcast], &managed).is_err());
+        let mut no_device = rule("stray", FirewallProtocol::Tcp, 22);
+        no_device.forward_port = Some(22);
+        assert!(validate_firewall_rules(&[no_device], &managed).is_err());
+    }
+}
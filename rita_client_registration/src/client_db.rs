@@ -80,7 +80,12 @@ pub async fn add_users_to_registered_list(
     options: Vec<SendTxOption>,
 ) -> Result<Uint256, Web3Error> {
     let mut encoded_clients = Vec::new();
+    // the same router may be queued twice under different nicknames or serializations
+    let mut seen = HashSet::new();
     for user in users {
+        if !seen.insert(user.canonical_digest()) {
+            continue;
+        }
         if let IpAddr::V6(mesh_ip_v6) = user.mesh_ip {
             encoded_clients.push(AbiToken::Struct(vec![
                 AbiToken::Uint(u128::from(mesh_ip_v6).into()),
@@ -799,6 +804,24 @@ mod tests {
         event_bytes
     }
 
+    #[test]
+    fn test_parse_canonical_identity() {
+        // the canonical identity bytes use the same layout as the contract struct
+        let id = Identity {
+            mesh_ip: "fd00::1337".parse().unwrap(),
+            eth_address: "0x52af7358f572812088ecf214e821ba45361e49bd"
+                .parse()
+                .unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            nickname: None,
+        };
+        let parsed = parse_identity_abi(to_evm_words(id.canonical_bytes().to_vec())).unwrap();
+        assert_eq!(parsed, id);
+        assert_eq!(parsed.canonical_digest(), id.canonical_digest());
+    }
+
     #[test]
     fn fuzz_pase_identity_abi() {
         let start = Instant::now();
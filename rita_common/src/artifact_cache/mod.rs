@@ -0,0 +1,337 @@
+//! A mesh local cache for firmware images and other large downloads. Routers on expensive backhaul would
+//! otherwise each download the same sysupgrade image from the internet. Nodes with a cache directory
+//! configured, normally gateways, keep the artifacts they download addressed by their sha256 hash and
+//! advertise the hashes they hold to neighbors over the rita contact port. Before going out to the internet
+//! a node asks each of it's neighbors for the artifact. Anything fetched, from a neighbor or otherwise, is
+//! verified against the operator provided hash before it is used or cached. Sysupgrade images and opkg
+//! packages the operator gives a hash for go through here. Artifacts are streamed to disk as they
+//! download and hashed on the way, a router never holds a whole image in memory.
+
+use crate::tunnel_manager::tm_get_neighbors;
+use crate::RitaCommonError;
+use actix_web_async::web::{self, Path};
+use actix_web_async::{HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path as FsPath, PathBuf};
+use std::time::Duration;
+
+/// The largest artifact we will download, sysupgrade images are usually well under this
+pub const MAX_ARTIFACT_SIZE: usize = 64 * 1024 * 1024;
+/// The number of artifacts kept in the cache directory, the oldest are removed first
+const MAX_CACHED_ARTIFACTS: usize = 4;
+/// Where artifacts are written when this node does not have a cache directory configured
+const DOWNLOAD_DIR: &str = "/tmp";
+/// Artifacts are hashed, written and served this much at a time, never held in memory whole
+const CHUNK_SIZE: usize = 64 * 1024;
+const NEIGHBOR_LIST_TIMEOUT: Duration = Duration::from_secs(2);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Returns the lowercase hex sha256 hash of the provided bytes
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+/// The lowercase hex sha256 hash of a file, read a chunk at a time
+fn sha256_file(path: &FsPath) -> Result<String, RitaCommonError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// sha256_file off the async runtime, hashing a whole image blocks for a while on slow flash
+async fn blocking_sha256_file(path: PathBuf) -> Result<String, RitaCommonError> {
+    match web::block(move || sha256_file(&path)).await {
+        Ok(hash) => hash,
+        Err(e) => Err(RitaCommonError::MiscStringError(format!(
+            "Failed to hash artifact {e}"
+        ))),
+    }
+}
+
+/// Hashes are used as file names so we must be strict about what we accept
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    settings::get_rita_common()
+        .network
+        .artifact_cache_dir
+        .map(PathBuf::from)
+}
+
+/// Lists the hashes of every artifact in our cache, this is what we advertise to neighbors
+pub fn list_cached_artifacts() -> Vec<String> {
+    let dir = match cache_dir() {
+        Some(dir) => dir,
+        None => return Vec::new(),
+    };
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| is_valid_hash(name))
+            .collect(),
+        Err(e) => {
+            warn!("Failed to read artifact cache {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Removes the oldest artifacts from the cache directory until we are within MAX_CACHED_ARTIFACTS
+fn prune_cache(dir: &PathBuf) -> Result<(), RitaCommonError> {
+    let mut artifacts = Vec::new();
+    for entry in fs::read_dir(dir)?.filter_map(|entry| entry.ok()) {
+        let is_artifact = entry
+            .file_name()
+            .to_str()
+            .map(is_valid_hash)
+            .unwrap_or(false);
+        if is_artifact {
+            artifacts.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    artifacts.sort();
+    while artifacts.len() > MAX_CACHED_ARTIFACTS {
+        let (_, path) = artifacts.remove(0);
+        info!("Removing {:?} from the artifact cache", path);
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Streams a download into partial, hashing it on the way
+async fn download_to(url: &str, hash: &str, partial: &FsPath) -> Result<(), RitaCommonError> {
+    let client = awc::Client::default();
+    let mut response = match client.get(url).timeout(DOWNLOAD_TIMEOUT).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(RitaCommonError::MiscStringError(format!(
+                "Failed to download {url} with {e}"
+            )))
+        }
+    };
+    if !response.status().is_success() {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Failed to download {url} got status {}",
+            response.status()
+        )));
+    }
+    let mut file = File::create(partial)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = response.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return Err(RitaCommonError::MiscStringError(format!(
+                    "Failed to download {url} with {e}"
+                )))
+            }
+        };
+        size += chunk.len();
+        if size > MAX_ARTIFACT_SIZE {
+            return Err(RitaCommonError::MiscStringError(format!(
+                "Artifact from {url} is larger than {MAX_ARTIFACT_SIZE} bytes"
+            )));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk)?;
+    }
+    file.sync_all()?;
+    if to_hex(&hasher.finalize()) != hash {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Artifact from {url} does not match expected hash {hash}"
+        )));
+    }
+    Ok(())
+}
+
+/// Where a download to path is written until it is verified, unique per download so that two fetches
+/// of the same artifact don't write into each other's file
+fn partial_path(path: &FsPath) -> PathBuf {
+    path.with_extension(format!("{:016x}.part", rand::random::<u64>()))
+}
+
+/// Downloads an artifact to path, which only appears once the download is complete and verified
+async fn download(url: &str, hash: &str, path: &FsPath) -> Result<(), RitaCommonError> {
+    let partial = partial_path(path);
+    let res = match download_to(url, hash, &partial).await {
+        Ok(()) => fs::rename(&partial, path).map_err(RitaCommonError::from),
+        Err(e) => Err(e),
+    };
+    if res.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    res
+}
+
+/// Asks each neighbor if they have the artifact and downloads it to path from the first one that
+/// does, returns false if none did
+async fn fetch_from_neighbors(hash: &str, path: &FsPath) -> bool {
+    let contact_port = settings::get_rita_common().network.rita_contact_port;
+    for neighbor in tm_get_neighbors() {
+        let base = format!(
+            "http://[{}]:{}/artifacts",
+            neighbor.identity.global.mesh_ip, contact_port
+        );
+        let client = awc::Client::default();
+        let list: Vec<String> = match client
+            .get(&base)
+            .timeout(NEIGHBOR_LIST_TIMEOUT)
+            .send()
+            .await
+        {
+            Ok(mut response) => response.json().await.unwrap_or_default(),
+            Err(_) => continue,
+        };
+        if !list.iter().any(|h| h == hash) {
+            continue;
+        }
+        info!("Fetching artifact {} from neighbor {}", hash, base);
+        match download(&format!("{base}/{hash}"), hash, path).await {
+            Ok(()) => return true,
+            Err(e) => warn!("Failed to fetch artifact from neighbor {} {:?}", base, e),
+        }
+    }
+    false
+}
+
+/// Gets the artifact with the provided sha256 hash, from our own cache, a mesh neighbor or finally
+/// the provided url. Returns the path of the verified artifact on disk
+pub async fn fetch_artifact(url: &str, sha256: &str) -> Result<PathBuf, RitaCommonError> {
+    let hash = sha256.to_lowercase();
+    if !is_valid_hash(&hash) {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Invalid artifact hash {sha256}"
+        )));
+    }
+    let cache = cache_dir();
+    let dir = cache.clone().unwrap_or_else(|| PathBuf::from(DOWNLOAD_DIR));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(&hash);
+
+    if path.exists() {
+        if blocking_sha256_file(path.clone()).await? == hash {
+            info!("Using cached artifact {}", hash);
+            return Ok(path);
+        }
+        warn!("Cached artifact {} is corrupt, removing", hash);
+        fs::remove_file(&path)?;
+    }
+
+    if !fetch_from_neighbors(&hash, &path).await {
+        info!(
+            "Artifact {} not found on the mesh, downloading {}",
+            hash, url
+        );
+        download(url, &hash, &path).await?;
+    }
+    if let Some(dir) = cache {
+        prune_cache(&dir)?;
+    }
+    Ok(path)
+}
+
+/// Advertises the artifacts we have cached to our neighbors
+pub async fn get_artifact_list(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(list_cached_artifacts())
+}
+
+/// The file a chunk at a time, ending after the first error
+fn file_chunks(file: File) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> {
+    stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        match file.read(&mut buf) {
+            Ok(0) => None,
+            Ok(read) => {
+                buf.truncate(read);
+                Some((Ok(Bytes::from(buf)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+/// Serves a cached artifact to a neighbor
+pub async fn get_artifact(hash: Path<String>) -> HttpResponse {
+    let hash = hash.into_inner();
+    let dir = match cache_dir() {
+        Some(dir) => dir,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if !is_valid_hash(&hash) {
+        return HttpResponse::BadRequest().finish();
+    }
+    match File::open(dir.join(&hash)) {
+        Ok(file) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .streaming(file_chunks(file)),
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_is_valid_hash() {
+        assert!(is_valid_hash(&sha256_hex(b"abc")));
+        assert!(!is_valid_hash("../../etc/passwd"));
+        assert!(!is_valid_hash(
+            "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD"
+        ));
+        assert!(!is_valid_hash("ba7816bf"));
+    }
+
+    #[test]
+    fn test_sha256_file() {
+        let path = std::env::temp_dir().join(format!("artifact-test-{}", std::process::id()));
+        // larger than a chunk so that the hash is built up over several reads
+        let bytes: Vec<u8> = (0..CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(sha256_file(&path).unwrap(), sha256_hex(&bytes));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_partial_path() {
+        let path = PathBuf::from("/tmp").join(sha256_hex(b"abc"));
+        let first = partial_path(&path);
+        assert_ne!(first, partial_path(&path));
+        assert_eq!(first.parent(), path.parent());
+        assert_eq!(first.extension().unwrap(), "part");
+        // never mistaken for a cached artifact
+        assert!(!is_valid_hash(first.file_name().unwrap().to_str().unwrap()));
+    }
+}
@@ -2,8 +2,15 @@
 //! relating to the blockchain being used. First and foremost is maintaining an updated
 //! balance and nonce as well as computing more complicated things like the closing and
 //! payment threshold based on gas prices.
+//!
+//! On eth based chains it also watches for reorgs deeper than the depth at which payment_validator
+//! considers a payment final, by remembering the hash of a block that deep and checking whether the
+//! full node still has the same block at that height on the next update. payment_validator then
+//! checks the payments it validated again.
 
 use crate::debt_keeper::normalize_payment_amount;
+use crate::events::{publish_event, RitaEvent};
+use crate::payment_validator::BLOCKS_TO_CONFIRM;
 use crate::rita_loop::fast_loop::FAST_LOOP_TIMEOUT;
 use crate::rita_loop::get_altheal1_server;
 use crate::rita_loop::get_web3_server;
@@ -49,6 +56,10 @@ pub struct BlockchainOracle {
     /// ignore the update, none if not yet set
     pub last_seen_block: Option<Uint256>,
     pub last_updated: Option<Instant>,
+    /// The height and hash of a block BLOCKS_TO_CONFIRM below the last seen block, eth chains only
+    pub checkpoint: Option<(Uint256, Uint256)>,
+    /// Set when the checkpoint block was replaced, until payment_validator takes it
+    pub reorg_detected: bool,
 }
 
 /// payment_threshold : This is the amount at which a router will make a payment. Below this value, the router will not may a payment since
@@ -56,8 +67,9 @@ pub struct BlockchainOracle {
 /// in the config, currently the default value is set to 0.3 * 1eth constant (1 dollar), which is 30 cents. When this is larger, the router pays less often and
 /// vice versa.
 pub fn get_pay_thresh() -> Int256 {
-    let payment = settings::get_rita_common().payment;
-    payment.payment_threshold
+    settings::get_rita_common_snapshot()
+        .payment()
+        .payment_threshold
 }
 
 /// close_threshold : This is a multiple of payment_threshold and determines how many payments a router can miss before enforcing it.
@@ -73,12 +85,27 @@ pub fn calculate_close_thresh() -> Int256 {
     sign_flip * CLOSE_THRESH_MULT.into() * pay_thresh
 }
 
+/// throttle_threshold : A percentage of the close threshold, set by throttle_threshold_percent. Neighbors that
+/// owe more than this are throttled as a warning before they are enforced upon at the close threshold.
+/// None if throttling is disabled
+pub fn calculate_throttle_thresh() -> Option<Int256> {
+    let percent = settings::get_rita_common_snapshot()
+        .payment()
+        .throttle_threshold_percent;
+    if percent == 0 || percent >= 100 {
+        return None;
+    }
+    Some(calculate_close_thresh() * i32::from(percent).into() / 100i32.into())
+}
+
 impl BlockchainOracle {
     pub fn new() -> Self {
         BlockchainOracle {
             balance: None,
             last_seen_block: None,
             last_updated: None,
+            checkpoint: None,
+            reorg_detected: false,
         }
     }
 }
@@ -108,6 +135,11 @@ fn set_oracle_last_seen_block(block: Uint256) {
     ORACLE.write().unwrap().last_seen_block = Some(block)
 }
 
+/// True once for every reorg deeper than BLOCKS_TO_CONFIRM seen since the last call
+pub fn take_oracle_reorg() -> bool {
+    std::mem::take(&mut ORACLE.write().unwrap().reorg_detected)
+}
+
 pub fn set_oracle_last_updated(update: Instant) {
     ORACLE.write().unwrap().last_updated = Some(update)
 }
@@ -232,6 +264,7 @@ async fn update_blockchain_info_gnosis(our_address: Address, web3: Web3, full_no
             }
             set_oracle_last_seen_block(latest_block);
             set_oracle_last_updated(Instant::now());
+            check_for_reorg(&web3, latest_block).await;
         }
         Err(e) => {
             warn!("Failed to get latest block number with {:?}", e);
@@ -246,6 +279,42 @@ async fn update_blockchain_info_gnosis(our_address: Address, web3: Web3, full_no
     }
 }
 
+/// Checks that the checkpoint block is still in the chain and moves the checkpoint up to
+/// BLOCKS_TO_CONFIRM below latest_block
+async fn check_for_reorg(web3: &Web3, latest_block: Uint256) {
+    let checkpoint = ORACLE.read().unwrap().checkpoint;
+    if let Some((height, hash)) = checkpoint {
+        match web3.eth_get_concise_block_by_number(height).await {
+            Ok(block) => {
+                if block.hash != hash {
+                    warn!(
+                        "Block {} changed from {:#066x} to {:#066x}, the chain reorganized past our confirmation depth",
+                        height, hash, block.hash
+                    );
+                    ORACLE.write().unwrap().reorg_detected = true;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to check block {} for reorgs {:?}", height, e);
+                return;
+            }
+        }
+    }
+
+    let confirmations = Uint256::from(BLOCKS_TO_CONFIRM);
+    if latest_block < confirmations {
+        return;
+    }
+    let height = latest_block - confirmations;
+    if checkpoint.map(|(checkpoint_height, _)| checkpoint_height) == Some(height) {
+        return;
+    }
+    match web3.eth_get_concise_block_by_number(height).await {
+        Ok(block) => ORACLE.write().unwrap().checkpoint = Some((height, block.hash)),
+        Err(e) => warn!("Failed to get checkpoint block {} {:?}", height, e),
+    }
+}
+
 /// Gets the balance for the provided eth address and updates it
 /// in the global SETTING variable, do not use this function as a generic
 /// balance getter.
@@ -256,15 +325,25 @@ fn update_balance(full_node: &str, new_balance: Uint256) {
         "Got response from {} balance request {:?}",
         full_node, value
     );
+    let was_low = low_balance();
     set_oracle_balance(Some(value));
+    if low_balance() && !was_low {
+        publish_event(RitaEvent::BalanceLow {
+            balance: value,
+            warning_level: settings::get_rita_common_snapshot()
+                .payment()
+                .balance_warning_level,
+        });
+    }
 }
 
 /// A very simple function placed here for convinence that indicates
 /// if the system should go into low balance mode
 pub fn low_balance() -> bool {
-    let payment_settings = settings::get_rita_common().payment;
     let balance = get_oracle_balance();
-    let balance_warning_level = payment_settings.balance_warning_level;
+    let balance_warning_level = settings::get_rita_common_snapshot()
+        .payment()
+        .balance_warning_level;
 
     match balance {
         Some(val) => val < balance_warning_level,
@@ -0,0 +1,274 @@
+//! Operator broadcast notices, see althea_types::broadcast_notice. Notices arrive in the operator checkin
+//! response or from neighbors, each one signed by our own operator is put in the notifications inbox once
+//! and taken down again when it expires. Routers that opt in with operator.gossip_broadcast_notices answer
+//! /broadcast_notices on the contact port with the notices they hold, and ask their neighbors for theirs
+//! from the slow loop when they haven't reached the operator server in a while, so that a notice reaches
+//! routers whose uplink is down, which are often the ones the notice is about. The notices held are saved
+//! next to usage_tracker_file so that a restart doesn't put them in the inbox a second time.
+
+use crate::notifications::{add_expiring_notification, NotificationSeverity};
+use crate::tunnel_manager::tm_get_neighbors;
+use crate::RitaCommonError;
+use actix_web_async::{HttpRequest, HttpResponse};
+use althea_types::unix_time::now_secs;
+use althea_types::{BroadcastNoticeLevel, Identity, SignedBroadcastNotice};
+use clarity::Address;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Neighbors are only asked for notices if the operator server hasn't answered a checkin for this long
+pub const OPERATOR_SILENCE: Duration = Duration::from_secs(600);
+/// How often neighbors are asked while the operator server can't be reached
+pub const NOTICE_REFRESH: Duration = Duration::from_secs(600);
+/// Notices held at once, a neighbor can't make us hold more than this
+pub const MAX_NOTICES: usize = 16;
+const NOTICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Notices we hold by id, kept until they expire, None until loaded from disk
+    static ref NOTICES: Arc<RwLock<Option<HashMap<u64, SignedBroadcastNotice>>>> =
+        Arc::new(RwLock::new(None));
+    /// The last time the operator server answered a checkin and the last time neighbors were asked
+    static ref NOTICE_TIMES: Arc<RwLock<(Option<Instant>, Option<Instant>)>> =
+        Arc::new(RwLock::new((None, None)));
+}
+
+fn notices_path() -> String {
+    format!(
+        "{}.notices",
+        settings::get_rita_common().network.usage_tracker_file
+    )
+}
+
+fn load_notices(path: &str) -> HashMap<u64, SignedBroadcastNotice> {
+    match fs::read(path) {
+        Ok(bytes) => match serde_json::from_slice::<Vec<SignedBroadcastNotice>>(&bytes) {
+            Ok(list) => list.into_iter().map(|n| (n.notice.id, n)).collect(),
+            Err(e) => {
+                error!("Failed to deserialize broadcast notices {:?}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_notices(path: &str, notices: &HashMap<u64, SignedBroadcastNotice>) {
+    let list: Vec<&SignedBroadcastNotice> = notices.values().collect();
+    match serde_json::to_vec(&list) {
+        Ok(bytes) => {
+            if let Err(e) = fs::write(path, bytes) {
+                warn!("Unable to save broadcast notices {:?}", e);
+            }
+        }
+        Err(e) => warn!("Unable to serialize broadcast notices {:?}", e),
+    }
+}
+
+/// Applies a change to the notices held, dropping expired ones first, and saves them if anything changed
+fn modify_notices<T>(change: impl FnOnce(&mut HashMap<u64, SignedBroadcastNotice>) -> T) -> T {
+    let path = notices_path();
+    let now = now_secs();
+    let mut notices = NOTICES.write().unwrap();
+    let notices = notices.get_or_insert_with(|| load_notices(&path));
+    let before: Vec<u64> = notices.keys().copied().collect();
+    notices.retain(|_, n| n.notice.expiry > now);
+    let ret = change(notices);
+    if notices.len() != before.len() || notices.keys().any(|id| !before.contains(id)) {
+        save_notices(&path, notices);
+    }
+    ret
+}
+
+/// Our operator and whether we pass notices on, None on exits and routers without an operator
+fn notice_settings() -> Option<(Address, bool)> {
+    if settings::check_if_exit() {
+        return None;
+    }
+    let operator = settings::get_rita_client_snapshot().operator.clone();
+    Some((
+        operator.operator_address?,
+        operator.gossip_broadcast_notices,
+    ))
+}
+
+fn validate_notice(
+    signed: &SignedBroadcastNotice,
+    operator: Option<Address>,
+    now: u64,
+) -> Result<(), RitaCommonError> {
+    if Some(signed.notice.operator) != operator {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Notice {} is not from our operator",
+            signed.notice.id
+        )));
+    }
+    if signed.notice.expiry <= now {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Notice {} has expired",
+            signed.notice.id
+        )));
+    }
+    signed
+        .verify()
+        .map_err(|e| RitaCommonError::MiscStringError(e.to_string()))
+}
+
+/// Takes a notice from the operator or a neighbor, returns true if it was new and is now in the inbox
+pub fn receive_notice(signed: SignedBroadcastNotice) -> bool {
+    let now = now_secs();
+    let operator = notice_settings().map(|(operator, _)| operator);
+    let accepted = modify_notices(|notices| {
+        if notices.contains_key(&signed.notice.id) {
+            return None;
+        }
+        if let Err(e) = validate_notice(&signed, operator, now) {
+            warn!("Refused broadcast notice {}", e);
+            return None;
+        }
+        if notices.len() >= MAX_NOTICES {
+            warn!(
+                "Holding {} broadcast notices already, dropping notice {}",
+                MAX_NOTICES, signed.notice.id
+            );
+            return None;
+        }
+        notices.insert(signed.notice.id, signed.clone());
+        Some(signed.notice)
+    });
+    let notice = match accepted {
+        Some(notice) => notice,
+        None => return false,
+    };
+    info!("Received broadcast notice {} {:?}", notice.id, notice.level);
+    let severity = match notice.level {
+        BroadcastNoticeLevel::Info => NotificationSeverity::Info,
+        BroadcastNoticeLevel::Warning => NotificationSeverity::Warning,
+        BroadcastNoticeLevel::Critical => NotificationSeverity::Critical,
+    };
+    add_expiring_notification(
+        severity,
+        "operator_notice",
+        notice.message,
+        Some(notice.expiry),
+    );
+    true
+}
+
+/// Called by the client after every successful operator checkin
+pub fn record_operator_checkin() {
+    NOTICE_TIMES.write().unwrap().0 = Some(Instant::now());
+}
+
+/// The notices we hold that haven't expired
+pub fn get_notices() -> Vec<SignedBroadcastNotice> {
+    let mut notices: Vec<SignedBroadcastNotice> =
+        modify_notices(|notices| notices.values().cloned().collect());
+    notices.sort_by_key(|n| n.notice.id);
+    notices
+}
+
+pub async fn get_broadcast_notices(_req: HttpRequest) -> HttpResponse {
+    match notice_settings() {
+        Some((_, true)) => HttpResponse::Ok().json(get_notices()),
+        _ => HttpResponse::Ok().json(Vec::<SignedBroadcastNotice>::new()),
+    }
+}
+
+async fn request_notices(
+    neighbor: Identity,
+) -> Result<Vec<SignedBroadcastNotice>, RitaCommonError> {
+    let url = format!(
+        "http://[{}]:{}/broadcast_notices",
+        neighbor.mesh_ip,
+        settings::get_rita_common_snapshot()
+            .network()
+            .rita_contact_port
+    );
+    let client = awc::Client::default();
+    let mut response = client.get(url).timeout(NOTICE_TIMEOUT).send().await?;
+    let mut notices: Vec<SignedBroadcastNotice> = response.json().await?;
+    notices.truncate(MAX_NOTICES);
+    Ok(notices)
+}
+
+/// If neighbors should be asked for notices, only while the operator server can't be reached
+fn should_ask_neighbors(last_checkin: Option<Instant>, last_asked: Option<Instant>) -> bool {
+    let operator_silent = match last_checkin {
+        Some(checkin) => checkin.elapsed() > OPERATOR_SILENCE,
+        None => true,
+    };
+    let due = match last_asked {
+        Some(asked) => asked.elapsed() > NOTICE_REFRESH,
+        None => true,
+    };
+    operator_silent && due
+}
+
+/// Called from the slow loop, asks neighbors for notices while the operator server can't be reached
+pub async fn tick_broadcast_notices() {
+    match notice_settings() {
+        Some((_, true)) => {}
+        _ => return,
+    }
+    {
+        let mut times = NOTICE_TIMES.write().unwrap();
+        if !should_ask_neighbors(times.0, times.1) {
+            return;
+        }
+        times.1 = Some(Instant::now());
+    }
+    for neighbor in tm_get_neighbors() {
+        let id = neighbor.identity.global;
+        match request_notices(id).await {
+            Ok(notices) => {
+                for notice in notices {
+                    receive_notice(notice);
+                }
+            }
+            Err(e) => trace!("No notices from neighbor {} {:?}", id.wg_public_key, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::BroadcastNotice;
+    use clarity::PrivateKey;
+
+    #[test]
+    fn test_validate_notice() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let signed = BroadcastNotice {
+            id: 4,
+            operator: key.to_address(),
+            level: BroadcastNoticeLevel::Info,
+            message: "Maintenance tonight at 2am".to_string(),
+            expiry: 2000,
+        }
+        .sign(key);
+        let operator = Some(key.to_address());
+        assert!(validate_notice(&signed, operator, 1000).is_ok());
+        assert!(validate_notice(&signed, operator, 2000).is_err());
+        assert!(validate_notice(&signed, None, 1000).is_err());
+        let other: PrivateKey =
+            "0x0000000000000000000000000000000000000000000000000000000000000002"
+                .parse()
+                .unwrap();
+        assert!(validate_notice(&signed, Some(other.to_address()), 1000).is_err());
+    }
+
+    #[test]
+    fn test_should_ask_neighbors() {
+        assert!(should_ask_neighbors(None, None));
+        // the operator answered recently
+        assert!(!should_ask_neighbors(Some(Instant::now()), None));
+        // asked recently
+        assert!(!should_ask_neighbors(None, Some(Instant::now())));
+    }
+}
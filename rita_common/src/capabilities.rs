@@ -0,0 +1,102 @@
+//! The capability registry, see althea_types::capabilities. The role filling it in at startup logs it
+//! as the startup banner, after that it is served on /capabilities and sent with operator checkins.
+
+use crate::KI;
+use actix_web_async::http::StatusCode;
+use actix_web_async::{HttpRequest, HttpResponse};
+use althea_types::Capabilities;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    static ref CAPABILITIES: Arc<RwLock<Option<Capabilities>>> = Arc::new(RwLock::new(None));
+}
+
+/// The firewall rita programs, the kernel interface picks nftables whenever it is installed
+pub fn firewall_backend() -> String {
+    if KI.does_nftables_exist() {
+        "nftables".to_string()
+    } else {
+        "iptables".to_string()
+    }
+}
+
+/// One line per item so that the banner can be grepped out of remote logs
+pub fn format_banner(capabilities: &Capabilities) -> String {
+    let mut banner = vec![
+        format!(
+            "rita {} ({}) starting as {}",
+            capabilities.version, capabilities.git_hash, capabilities.role
+        ),
+        format!(
+            "build features: [{}]",
+            capabilities.build_features.join(", ")
+        ),
+    ];
+    for (subsystem, enabled) in capabilities.subsystems.iter() {
+        let state = if *enabled { "enabled" } else { "disabled" };
+        banner.push(format!("subsystem {subsystem}: {state}"));
+    }
+    banner.push(format!(
+        "exit protocols: {:?}, chains: {:?}, firewall: {}",
+        capabilities.exit_protocols, capabilities.chains, capabilities.firewall
+    ));
+    if let Some(backend) = &capabilities.enforcement_backend {
+        banner.push(format!("enforcement backend: {backend}"));
+    }
+    banner.join("\n")
+}
+
+/// Records what this build can do and logs it as the startup banner
+pub fn set_capabilities(capabilities: Capabilities) {
+    for line in format_banner(&capabilities).lines() {
+        info!("{}", line);
+    }
+    *CAPABILITIES.write().unwrap() = Some(capabilities);
+}
+
+/// None until the role has started
+pub fn get_capabilities() -> Option<Capabilities> {
+    CAPABILITIES.read().unwrap().clone()
+}
+
+pub async fn get_capabilities_endpoint(_req: HttpRequest) -> HttpResponse {
+    match get_capabilities() {
+        Some(capabilities) => HttpResponse::Ok().json(capabilities),
+        None => {
+            HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json("Rita is still starting up")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::SystemChain;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_format_banner() {
+        let mut subsystems = BTreeMap::new();
+        subsystems.insert("exit_kill_switch".to_string(), false);
+        subsystems.insert("guest_network".to_string(), true);
+        let banner = format_banner(&Capabilities {
+            version: "0.21.5".to_string(),
+            git_hash: "abc123".to_string(),
+            role: "client".to_string(),
+            build_features: vec!["server".to_string()],
+            subsystems,
+            exit_protocols: vec![2],
+            chains: vec![SystemChain::Xdai],
+            enforcement_backend: None,
+            firewall: "nftables".to_string(),
+        });
+        assert_eq!(
+            banner,
+            "rita 0.21.5 (abc123) starting as client\n\
+             build features: [server]\n\
+             subsystem exit_kill_switch: disabled\n\
+             subsystem guest_network: enabled\n\
+             exit protocols: [2], chains: [Xdai], firewall: nftables"
+        );
+    }
+}
@@ -1,10 +1,13 @@
+use crate::network_monitor::get_neighbor_flaps;
+use crate::network_monitor::get_stability_penalties;
 use actix_web_async::http::StatusCode;
 use actix_web_async::web::Path;
 use actix_web_async::{HttpRequest, HttpResponse};
-use babel_monitor::open_babel_stream;
+use babel_monitor::pool::get_babel_stream;
 use babel_monitor::set_local_fee as babel_set_local_fee;
 use babel_monitor::set_metric_factor as babel_set_metric_factor;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::time::Duration;
 
 pub async fn get_local_fee(_req: HttpRequest) -> HttpResponse {
@@ -35,7 +38,7 @@ pub async fn set_local_fee(path: Path<u32>) -> HttpResponse {
     // themselves
     let new_fee = if new_fee > max_fee { max_fee } else { new_fee };
 
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
+    match get_babel_stream(babel_port, Duration::from_secs(5)) {
         Ok(mut stream) => {
             match babel_set_local_fee(&mut stream, new_fee) {
                 Ok(_) => {
@@ -71,7 +74,7 @@ pub async fn set_metric_factor(path: Path<u32>) -> HttpResponse {
     debug!("/metric_factor/{} POST hit", new_factor);
     let babel_port = settings::get_rita_common().network.babel_port;
 
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
+    match get_babel_stream(babel_port, Duration::from_secs(5)) {
         Ok(mut stream) => {
             match babel_set_metric_factor(&mut stream, new_factor) {
                 Ok(_) => {
@@ -101,3 +104,14 @@ pub async fn set_metric_factor(path: Path<u32>) -> HttpResponse {
         }
     }
 }
+
+/// Route adds and retractions through the neighbor with the given mesh ip over the last day,
+/// a neighbor that shows up here often has an unreliable link
+pub async fn get_neighbor_route_flaps(path: Path<IpAddr>) -> HttpResponse {
+    HttpResponse::Ok().json(get_neighbor_flaps(path.into_inner()))
+}
+
+/// Neighbors whose tunnels currently have their babel rxcost raised for unstable routes
+pub async fn get_neighbor_penalties(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_stability_penalties())
+}
@@ -1,6 +1,11 @@
+use crate::debt_keeper::bulk_debt_update;
 use crate::debt_keeper::get_debts_list;
+use crate::debt_keeper::get_shadow_debts_list;
 use crate::debt_keeper::traffic_replace;
+use crate::debt_keeper::BulkDebtChange;
+use crate::debt_keeper::BulkDebtRequest;
 use crate::debt_keeper::Traffic;
+use crate::reconciliation::get_reconciliations;
 use actix_web_async::{web::Json, HttpRequest, HttpResponse};
 use althea_types::Identity;
 
@@ -9,6 +14,17 @@ pub async fn get_debts(_req: HttpRequest) -> HttpResponse {
     HttpResponse::Ok().json(get_debts_list())
 }
 
+pub async fn get_shadow_debts(_req: HttpRequest) -> HttpResponse {
+    trace!("get_shadow_debts: Hit");
+    HttpResponse::Ok().json(get_shadow_debts_list())
+}
+
+/// The latest comparison of our books with each neighbor's, see reconciliation
+pub async fn get_debt_reconciliation(_req: HttpRequest) -> HttpResponse {
+    trace!("get_debt_reconciliation: Hit");
+    HttpResponse::Ok().json(get_reconciliations())
+}
+
 pub async fn reset_debt(user_to_forgive: Json<Identity>) -> HttpResponse {
     traffic_replace(Traffic {
         from: user_to_forgive.into_inner(),
@@ -16,3 +32,23 @@ pub async fn reset_debt(user_to_forgive: Json<Identity>) -> HttpResponse {
     });
     HttpResponse::Ok().json(())
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BulkDebtResponse {
+    pub dry_run: bool,
+    pub changes: Vec<BulkDebtChange>,
+}
+
+/// Resets or adjusts every debt matching a filter, for cleaning up after an incident. Dry runs
+/// return what would change without touching anything
+pub async fn bulk_debt_update_endpoint(request: Json<BulkDebtRequest>) -> HttpResponse {
+    let request = request.into_inner();
+    info!(
+        "Bulk debt {:?} for {:?}, dry run {}",
+        request.operation, request.filter, request.dry_run
+    );
+    HttpResponse::Ok().json(BulkDebtResponse {
+        dry_run: request.dry_run,
+        changes: bulk_debt_update(&request),
+    })
+}
@@ -0,0 +1,45 @@
+//! Endpoints for changing the log level of individual modules at runtime, see crate::logging
+
+use crate::logging::{
+    get_module_log_levels, reset_module_log_level, set_module_log_level,
+    DEFAULT_LOG_OVERRIDE_DURATION,
+};
+use actix_web_async::web::{Path, Query};
+use actix_web_async::{HttpRequest, HttpResponse};
+use log::LevelFilter;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LogLevelDuration {
+    /// Seconds until the module reverts to the default level
+    pub duration: Option<u64>,
+}
+
+pub async fn get_log_levels(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_module_log_levels())
+}
+
+/// Logs a module at the given level until the duration query parameter passes, an hour if it is
+/// not set. The level "reset" removes an override right away
+pub async fn set_log_level(
+    path: Path<(String, String)>,
+    query: Query<LogLevelDuration>,
+) -> HttpResponse {
+    let (module, level) = path.into_inner();
+    debug!("/logging/level/{}/{} hit", module, level);
+    if level.eq_ignore_ascii_case("reset") {
+        return HttpResponse::Ok().json(reset_module_log_level(&module));
+    }
+    let level: LevelFilter = match level.parse() {
+        Ok(level) => level,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(format!("Could not parse loglevel {e:?}"))
+        }
+    };
+    let duration = query
+        .duration
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_LOG_OVERRIDE_DURATION);
+    let effective = set_module_log_level(&module, level, duration);
+    HttpResponse::Ok().json(effective.to_string())
+}
@@ -0,0 +1,17 @@
+use crate::peer_listener::loop_detection::{get_quarantined_interfaces, release_interface};
+use actix_web_async::web::Path;
+use actix_web_async::HttpResponse;
+
+pub async fn get_mesh_loops() -> HttpResponse {
+    trace!("/mesh_loops hit");
+    HttpResponse::Ok().json(get_quarantined_interfaces())
+}
+
+pub async fn release_mesh_loop(ifname: Path<String>) -> HttpResponse {
+    let ifname = ifname.into_inner();
+    if release_interface(&ifname) {
+        HttpResponse::Ok().json(())
+    } else {
+        HttpResponse::NotFound().json(format!("{ifname} is not quarantined"))
+    }
+}
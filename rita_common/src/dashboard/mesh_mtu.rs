@@ -0,0 +1,7 @@
+use crate::tunnel_manager::mtu::get_tunnel_mtus;
+use actix_web_async::HttpResponse;
+
+pub async fn get_mesh_mtus() -> HttpResponse {
+    trace!("/tunnels/mtu hit");
+    HttpResponse::Ok().json(get_tunnel_mtus())
+}
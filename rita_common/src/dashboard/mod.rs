@@ -5,8 +5,13 @@
 pub mod babel;
 pub mod debts;
 pub mod development;
+pub mod logging;
+pub mod mesh_loops;
+pub mod mesh_mtu;
 pub mod nickname;
+pub mod notifications;
 pub mod own_info;
+pub mod payment_pause;
 pub mod settings;
 pub mod token_bridge;
 pub mod usage;
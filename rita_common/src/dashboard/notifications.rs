@@ -0,0 +1,42 @@
+use crate::notifications::{
+    get_notifications, mark_all_notifications_read, mark_notification_read, Notification,
+};
+use actix_web_async::web::{Path, Query};
+use actix_web_async::HttpResponse;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct NotificationsQuery {
+    /// Only list notifications that haven't been read
+    #[serde(default)]
+    pub unread: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationsResponse {
+    /// Unread notifications in the whole inbox
+    pub unread: usize,
+    /// Newest first
+    pub notifications: Vec<Notification>,
+}
+
+pub async fn get_notifications_endpoint(query: Query<NotificationsQuery>) -> HttpResponse {
+    trace!("/notifications hit");
+    let (unread, notifications) = get_notifications(query.unread);
+    HttpResponse::Ok().json(NotificationsResponse {
+        unread,
+        notifications,
+    })
+}
+
+pub async fn mark_notification_read_endpoint(id: Path<u64>) -> HttpResponse {
+    let id = id.into_inner();
+    if mark_notification_read(id) {
+        HttpResponse::Ok().json(())
+    } else {
+        HttpResponse::NotFound().json(format!("No notification with id {id}"))
+    }
+}
+
+pub async fn mark_all_notifications_read_endpoint() -> HttpResponse {
+    HttpResponse::Ok().json(mark_all_notifications_read())
+}
@@ -4,12 +4,14 @@ use crate::blockchain_oracle::{
 use crate::rita_loop::is_gateway;
 use actix_web_async::HttpRequest;
 use actix_web_async::HttpResponse;
+use althea_kernel_interface::hardware_info::get_resource_usage;
+use althea_types::ResourceUsage;
 use clarity::Address;
 use num256::{Int256, Uint256};
 
 pub static READABLE_VERSION: &str = "Beta 21 RC5";
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug, Clone)]
 pub struct OwnInfo {
     pub address: Address,
     pub balance: Option<Uint256>,
@@ -23,12 +25,19 @@ pub struct OwnInfo {
     pub version: String,
     pub is_gateway: bool,
     pub client_can_use_free_tier: bool,
+    /// Load, memory, uptime, temperature and flash space of the device
+    pub resources: ResourceUsage,
 }
 
 pub async fn get_own_info(_req: HttpRequest) -> HttpResponse {
     debug!("Get own info endpoint hit!");
-    let payment_settings = settings::get_rita_common().payment;
-    let network_settings = settings::get_rita_common().network;
+    HttpResponse::Ok().json(own_info())
+}
+
+pub fn own_info() -> OwnInfo {
+    let common = settings::get_rita_common_snapshot();
+    let payment_settings = common.payment();
+    let network_settings = common.network();
     let eth_address = payment_settings.eth_address.unwrap();
     let balance = get_oracle_balance();
     let pay_threshold = get_pay_thresh();
@@ -37,10 +46,10 @@ pub async fn get_own_info(_req: HttpRequest) -> HttpResponse {
     let local_fee = network_settings.babeld_settings.local_fee;
     let metric_factor = network_settings.babeld_settings.metric_factor;
 
-    let device = network_settings.device;
+    let device = network_settings.device.clone();
     let is_gateway = is_gateway();
 
-    let reply = OwnInfo {
+    OwnInfo {
         address: eth_address,
         balance,
         local_fee,
@@ -53,6 +62,6 @@ pub async fn get_own_info(_req: HttpRequest) -> HttpResponse {
         version: READABLE_VERSION.to_string(),
         is_gateway,
         client_can_use_free_tier,
-    };
-    HttpResponse::Ok().json(reply)
+        resources: get_resource_usage(),
+    }
 }
@@ -0,0 +1,15 @@
+use crate::payment_controller::pause::{get_payment_pause_status, pause_payments, resume_payments};
+use actix_web_async::{HttpRequest, HttpResponse};
+
+pub async fn get_payment_pause(_req: HttpRequest) -> HttpResponse {
+    trace!("/payments/pause hit");
+    HttpResponse::Ok().json(get_payment_pause_status())
+}
+
+pub async fn pause_payments_endpoint(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(pause_payments())
+}
+
+pub async fn resume_payments_endpoint(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(resume_payments())
+}
@@ -1,5 +1,9 @@
+use crate::usage_tracker::annual_summary::{get_annual_summary, summary_to_csv};
+use crate::usage_tracker::balance_history::get_earnings_timeline;
 use crate::usage_tracker::get_payments_data;
 use ::actix_web_async::HttpRequest;
+use actix_web_async::http::header::CONTENT_DISPOSITION;
+use actix_web_async::web::{Path, Query};
 use actix_web_async::HttpResponse;
 
 pub async fn get_payments(_req: HttpRequest) -> HttpResponse {
@@ -7,3 +11,79 @@ pub async fn get_payments(_req: HttpRequest) -> HttpResponse {
 
     HttpResponse::Ok().json(get_payments_data())
 }
+
+/// How far back the earnings timeline goes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EarningsRange {
+    Day,
+    #[default]
+    Week,
+    Month,
+    Year,
+}
+
+impl EarningsRange {
+    pub fn days(&self) -> u64 {
+        match self {
+            EarningsRange::Day => 1,
+            EarningsRange::Week => 7,
+            EarningsRange::Month => 30,
+            EarningsRange::Year => 365,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct EarningsQuery {
+    pub range: Option<EarningsRange>,
+}
+
+/// Daily balance, earned and spent series for the range query parameter, a week if it is not set
+pub async fn get_earnings(query: Query<EarningsQuery>) -> HttpResponse {
+    trace!("/earnings hit");
+    let range = query.range.unwrap_or_default();
+    match get_earnings_timeline(range.days()) {
+        Ok(timeline) => HttpResponse::Ok().json(timeline),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SummaryFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SummaryQuery {
+    pub format: Option<SummaryFormat>,
+}
+
+/// Earnings, spending and fees for a calendar year broken down by month, as json or as a csv download
+pub async fn get_annual_summary_endpoint(
+    path: Path<i32>,
+    query: Query<SummaryQuery>,
+) -> HttpResponse {
+    let year = path.into_inner();
+    trace!("/accounting/summary/{} hit", year);
+    if !(1970..=9999).contains(&year) {
+        return HttpResponse::BadRequest().json(format!("Invalid year {year}"));
+    }
+    let summary = match get_annual_summary(year).await {
+        Ok(summary) => summary,
+        Err(e) => return HttpResponse::InternalServerError().json(e.to_string()),
+    };
+    match query.format.unwrap_or_default() {
+        SummaryFormat::Json => HttpResponse::Ok().json(summary),
+        SummaryFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv")
+            .insert_header((
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"althea-summary-{year}.csv\""),
+            ))
+            .body(summary_to_csv(&summary)),
+    }
+}
@@ -5,6 +5,7 @@ use crate::token_bridge::Withdraw as WithdrawMsg;
 use actix_web_async::http::StatusCode;
 use actix_web_async::web::Path;
 use actix_web_async::HttpResponse;
+use althea_types::Amount;
 use althea_types::SystemChain;
 use clarity::Address;
 use num256::Uint256;
@@ -13,6 +14,13 @@ use web30::client::Web3;
 
 pub const WITHDRAW_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// The gas price hardcoded over in token bridge for xdai -> eth withdraws
+const TOKEN_BRIDGE_GAS_PRICE_GWEI: u64 = 10;
+/// Gas for the relayTokens contract call used by xdai -> eth withdraws
+const TOKEN_BRIDGE_WITHDRAW_GAS: u64 = 80000;
+/// Gas for a plain value transfer
+const TRANSFER_GAS: u64 = 21000;
+
 async fn withdraw_handler(address: Address, amount: Option<Uint256>) -> HttpResponse {
     debug!("/withdraw/{:#x}/{:?} hit", address, amount);
     let payment_settings = settings::get_rita_common().payment;
@@ -22,39 +30,54 @@ async fn withdraw_handler(address: Address, amount: Option<Uint256>) -> HttpResp
     let full_node = get_web3_server();
     let web3 = Web3::new(&full_node, WITHDRAW_TIMEOUT);
     let mut gas_price = match web3.eth_gas_price().await {
-        Ok(gp) => gp,
+        Ok(gp) => Amount::from_wei(gp),
         Err(_) => return HttpResponse::InternalServerError().finish(),
     };
 
     // if no amount is specified we are withdrawing our entire balance
     let mut amount = if let Some(amount) = amount {
-        amount
+        Amount::from_wei(amount)
     } else {
         match balance {
-            Some(value) => value,
+            Some(value) => Amount::from_wei(value),
             None => return HttpResponse::BadRequest().finish(),
         }
     };
 
-    let tx_gas: Uint256 =
-        if (system_chain, withdraw_chain) == (SystemChain::Xdai, SystemChain::Ethereum) {
-            // this is the hardcoded gas price over in token bridge so we have to use it
-            gas_price = 10_000_000_000u128.into();
-            // this is a contract call
-            80000u32.into()
-        } else {
-            21000u32.into()
-        };
+    let tx_gas = if (system_chain, withdraw_chain) == (SystemChain::Xdai, SystemChain::Ethereum) {
+        // this is the hardcoded gas price over in token bridge so we have to use it
+        gas_price = Amount::from_gwei(TOKEN_BRIDGE_GAS_PRICE_GWEI);
+        // this is a contract call
+        TOKEN_BRIDGE_WITHDRAW_GAS
+    } else {
+        TRANSFER_GAS
+    };
 
-    let tx_cost = gas_price * tx_gas;
-    match balance {
+    let tx_cost = match gas_price.checked_mul(tx_gas) {
+        Some(cost) => cost,
+        None => return HttpResponse::InternalServerError().json("Gas price overflow!"),
+    };
+    match balance.map(Amount::from_wei) {
         Some(value) => {
-            if amount + tx_cost >= value {
-                amount = value - tx_cost;
+            let leaves_enough_for_gas = match amount.checked_add(tx_cost) {
+                Some(total) => total < value,
+                None => false,
+            };
+            if !leaves_enough_for_gas {
+                amount = match value.checked_sub(tx_cost) {
+                    Some(amount) => amount,
+                    None => {
+                        return HttpResponse::BadRequest().json(format!(
+                            "Balance {} can not cover the withdraw fee {}",
+                            value, tx_cost
+                        ))
+                    }
+                };
             }
         }
         None => error!("Unable to retrieve balance for withdrawing"),
     }
+    let amount = amount.wei();
 
     match (system_chain, withdraw_chain) {
         (SystemChain::Ethereum, SystemChain::Ethereum) => {
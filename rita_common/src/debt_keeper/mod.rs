@@ -9,24 +9,28 @@
 //! Hence we need an incoming payments parameter to take money out of. This of course implies half
 //! of the excess complexity you see, managing an incoming payments pool versus a incoming debts pool
 use crate::blockchain_oracle::calculate_close_thresh;
+use crate::blockchain_oracle::calculate_throttle_thresh;
 use crate::blockchain_oracle::get_pay_thresh;
 use crate::blockchain_oracle::potential_payment_issues_detected;
+use crate::events::{publish_event, RitaEvent};
 use crate::payment_validator::ETH_PAYMENT_SEND_TIMEOUT;
 use crate::simulated_txfee_manager::add_tx_to_total;
 use crate::tunnel_manager::tm_tunnel_state_change;
 use crate::tunnel_manager::TunnelAction;
 use crate::tunnel_manager::TunnelChange;
+use crate::utils::json_file::write_atomically;
 use crate::RitaCommonError;
 use crate::KI;
+use althea_types::unix_time::now_secs;
 use althea_types::Denom;
 use althea_types::Identity;
 use althea_types::SystemChain;
 use althea_types::UnpublishedPaymentTx;
+use althea_types::WgKey;
 use num256::{Int256, Uint256};
 use num_traits::identities::Zero;
 use num_traits::CheckedMul;
 use num_traits::Signed;
-use settings::get_rita_common;
 use settings::DEBT_KEEPER_DENOM;
 use settings::DEBT_KEEPER_DENOM_DECIMAL;
 use std::collections::HashMap;
@@ -34,15 +38,24 @@ use std::fs;
 use std::fs::File;
 use std::io::Error as IOError;
 use std::io::Read;
-use std::io::Write;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::time::Instant;
 
+/// Written at the start of debts files in the current format, files without it hold the older bare
+/// bincode or json list of debts
+const DEBTS_FILE_MAGIC: &[u8] = b"RITADEBT";
+/// The most entries we write to disk, the smallest balances are dropped first so that a node that has
+/// seen a huge number of peers can't fill its disk
+const MAX_SAVED_DEBTS: usize = 20_000;
+
 lazy_static! {
     /// A locked global ref containing the state for this module. Note that the default implementation
     /// loads saved data from teh disk if it exists.
     static ref DEBT_DATA: Arc<RwLock<HashMap<u32,DebtKeeper>>> = Arc::new(RwLock::new(HashMap::new()));
+    /// Shadow ledger of debts recorded while billing is in dry run mode, these entries are never acted
+    /// on and are kept only in memory for operators to audit
+    static ref SHADOW_DEBT_DATA: Arc<RwLock<HashMap<u32, HashMap<Identity, Int256>>>> = Arc::new(RwLock::new(HashMap::new()));
 }
 
 /// Returns the default denomination for the debt keeper
@@ -103,6 +116,9 @@ pub struct NodeDebtData {
     /// case, where when we get payments from the exit there is a race condition where the
     /// exit may not update that we have paid it fast enough
     pub last_successful_payment: Option<Instant>,
+    #[serde(skip_serializing, skip_deserializing)]
+    /// The last traffic or payment in either direction since rita started, used to find idle debts
+    pub last_activity: Option<Instant>,
 }
 
 impl Default for NodeDebtData {
@@ -116,10 +132,22 @@ impl Default for NodeDebtData {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            last_activity: None,
         }
     }
 }
 impl NodeDebtData {
+    /// Whether the debt can be replaced or adjusted from outside, not while we have a payment in
+    /// flight or just after one landed, since the other side may not have counted it yet and we
+    /// could end up paying twice
+    fn debt_changeable(&self, now: Instant) -> bool {
+        match (self.payment_in_flight, self.last_successful_payment) {
+            (true, _) => false,
+            (false, Some(val)) => now.saturating_duration_since(val) > Duration::from_secs(15),
+            (false, None) => true,
+        }
+    }
+
     pub fn new() -> NodeDebtData {
         NodeDebtData {
             total_payment_received: Uint256::from(0u32),
@@ -130,6 +158,7 @@ impl NodeDebtData {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            last_activity: None,
         }
     }
 }
@@ -160,7 +189,10 @@ fn ser_to_debt_data(input: DebtDataSer) -> DebtData {
         // discard the entry, in the case that they do have some incoming payments the user
         // deserves to have that credit applied in the future so we must retain the entry and
         // reset the debt
-        if settings::get_rita_common().payment.forgive_on_reboot {
+        if settings::get_rita_common_snapshot()
+            .payment()
+            .forgive_on_reboot
+        {
             if d.debt <= Int256::zero() && d.incoming_payments == Uint256::zero() {
                 continue;
             } else if d.debt <= Int256::zero() {
@@ -172,6 +204,65 @@ fn ser_to_debt_data(input: DebtDataSer) -> DebtData {
     ret
 }
 
+/// The debts file as written to disk, after DEBTS_FILE_MAGIC
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SavedDebts {
+    /// Unix timestamp in seconds when this was written
+    saved_at: u64,
+    debts: DebtDataSer,
+}
+
+/// How much is at stake in an entry, used to pick which entries to keep when over MAX_SAVED_DEBTS
+fn debt_weight(data: &NodeDebtData) -> Uint256 {
+    data.debt.abs().to_uint256().unwrap_or_else(Uint256::zero) + data.incoming_payments
+}
+
+fn encode_debts_file(debt_data: DebtData, saved_at: u64) -> Vec<u8> {
+    let mut debts = debt_data_to_ser(debt_data);
+    if debts.len() > MAX_SAVED_DEBTS {
+        warn!(
+            "Only saving the largest {} of {} debts",
+            MAX_SAVED_DEBTS,
+            debts.len()
+        );
+        debts.sort_by_key(|(_, data)| std::cmp::Reverse(debt_weight(data)));
+        debts.truncate(MAX_SAVED_DEBTS);
+    }
+    let mut out = DEBTS_FILE_MAGIC.to_vec();
+    out.extend(bincode::serialize(&SavedDebts { saved_at, debts }).unwrap());
+    out
+}
+
+/// None if this is not a debts file in the current format
+fn decode_debts_file(bytes: &[u8]) -> Option<SavedDebts> {
+    let body = bytes.strip_prefix(DEBTS_FILE_MAGIC)?;
+    match bincode::deserialize(body) {
+        Ok(saved) => Some(saved),
+        Err(e) => {
+            error!("Failed to deserialize debts file {:?}", e);
+            None
+        }
+    }
+}
+
+/// Turns a saved debts file back into debt data. If it was saved more than max_age seconds ago the
+/// debts are too old to trust and are dropped, credit from overpayments is kept either way. A save
+/// time in the future is trusted, the clock is often wrong right after boot
+fn restore_saved_debts(saved: SavedDebts, now: u64, max_age: u64) -> DebtData {
+    let mut debts = saved.debts;
+    let age = now.saturating_sub(saved.saved_at);
+    if max_age != 0 && age > max_age {
+        warn!(
+            "Saved debts are {}s old, older than {}s, only restoring credit",
+            age, max_age
+        );
+        for (_, data) in debts.iter_mut() {
+            data.debt = Int256::zero();
+        }
+    }
+    ser_to_debt_data(debts)
+}
+
 /// used to prevent debts from growing higher than the enforcement limit in either direction
 /// if the debt is more negative or more positive than the ABS of close_threshold we set it to
 /// one more than that value
@@ -197,6 +288,9 @@ fn debt_limit(debt: Int256, close_threshold: Int256) -> Int256 {
 pub struct DebtKeeper {
     #[serde(skip_serializing, skip_deserializing)]
     last_save: Option<Instant>,
+    #[serde(skip_serializing, skip_deserializing, default = "Instant::now")]
+    /// When the debt keeper started, debts without activity since have been idle at least this long
+    started: Instant,
     debt_data: DebtData,
 }
 
@@ -272,6 +366,20 @@ pub fn payment_succeeded(
     dk.payment_succeeded(&to, amount)
 }
 
+/// Undoes payment_received for a payment a chain reorg dropped, amount is in wei
+pub fn incoming_payment_reverted(from: Identity, amount: Uint256) -> Result<(), RitaCommonError> {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    dk.incoming_payment_reverted(&from, amount)
+}
+
+/// Undoes payment_succeeded for a payment a chain reorg dropped, amount is in wei
+pub fn outgoing_payment_reverted(to: Identity, amount: Uint256) -> Result<(), RitaCommonError> {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    dk.outgoing_payment_reverted(&to, amount)
+}
+
 pub struct Traffic {
     pub from: Identity,
     pub amount: Int256,
@@ -285,6 +393,40 @@ pub fn traffic_update(traffic: Vec<Traffic>) {
     }
 }
 
+/// A variant of traffic update used when billing is in dry run mode, the traffic is accumulated
+/// into the shadow ledger and never touches the real debts, so no enforcement or payments result
+pub fn shadow_traffic_update(traffic: Vec<Traffic>) {
+    let netns = KI.check_integration_test_netns();
+    let mut shadow_data = SHADOW_DEBT_DATA.write().unwrap();
+    let shadow = shadow_data.entry(netns).or_default();
+    for t in traffic.iter() {
+        let debt = shadow.entry(t.from).or_insert_with(Int256::zero);
+        *debt += t.amount;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShadowDebtsResult {
+    pub identity: Identity,
+    /// The amount this node would owe us (negative) if billing was not in dry run mode
+    pub debt: Int256,
+}
+
+/// Returns the shadow ledger recorded while billing is in dry run mode
+pub fn get_shadow_debts_list() -> Vec<ShadowDebtsResult> {
+    let netns = KI.check_integration_test_netns();
+    match SHADOW_DEBT_DATA.read().unwrap().get(&netns) {
+        Some(shadow) => shadow
+            .iter()
+            .map(|(identity, debt)| ShadowDebtsResult {
+                identity: *identity,
+                debt: *debt,
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
 #[allow(dead_code)]
 /// Special case traffic update for client gateway corner case, see rita client traffic watcher for more
 /// details.
@@ -309,13 +451,103 @@ pub fn traffic_replace(traffic: Traffic) {
     dk.traffic_replace(&traffic.from, traffic.amount)
 }
 
+/// Which debts a bulk operation applies to, every condition that is set has to match
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DebtFilter {
+    /// Only these nodes, every node if empty
+    #[serde(default)]
+    pub wg_keys: Vec<WgKey>,
+    /// Only debts of at least this much, debts nodes owe us are negative
+    #[serde(default)]
+    pub min_debt: Option<Int256>,
+    /// Only debts of at most this much
+    #[serde(default)]
+    pub max_debt: Option<Int256>,
+    /// Only nodes without traffic or payments for at least this many seconds, nodes that had none
+    /// since rita started count as idle since then
+    #[serde(default)]
+    pub min_idle_secs: Option<u64>,
+}
+
+impl DebtFilter {
+    fn matches(
+        &self,
+        ident: &Identity,
+        data: &NodeDebtData,
+        now: Instant,
+        started: Instant,
+    ) -> bool {
+        if !self.wg_keys.is_empty() && !self.wg_keys.contains(&ident.wg_public_key) {
+            return false;
+        }
+        if self.min_debt.map(|min| data.debt < min).unwrap_or(false)
+            || self.max_debt.map(|max| data.debt > max).unwrap_or(false)
+        {
+            return false;
+        }
+        match self.min_idle_secs {
+            Some(min_idle) => {
+                let last_activity = data.last_activity.unwrap_or(started);
+                now.saturating_duration_since(last_activity) >= Duration::from_secs(min_idle)
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkDebtOperation {
+    /// Sets the debt to zero, like /debts/reset
+    Reset,
+    /// Adds amount to the debt, a positive amount forgives that much of what a node owes us
+    Adjust { amount: Int256 },
+}
+
+/// Request body for a bulk debt operation, without dry_run: false nothing is changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BulkDebtRequest {
+    #[serde(default)]
+    pub filter: DebtFilter,
+    pub operation: BulkDebtOperation,
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BulkDebtChange {
+    pub identity: Identity,
+    pub before: Int256,
+    pub after: Int256,
+    /// True if the debt was left alone because a payment to the node is in flight or just landed
+    pub skipped: bool,
+}
+
+/// Applies an operation to every debt the filter matches, or with dry_run only reports what it
+/// would change. Returns the matched debts sorted by wg key
+pub fn bulk_debt_update(request: &BulkDebtRequest) -> Vec<BulkDebtChange> {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    dk.bulk_update(&request.filter, &request.operation, request.dry_run)
+}
+
 /// Actions to be taken upon a neighbor's debt reaching either a negative or positive
 /// threshold.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Hash, Eq)]
 pub enum DebtAction {
     SuspendTunnel,
     OpenTunnel,
-    MakePayment { to: Box<Identity>, amount: Uint256 },
+    MakePayment {
+        to: Box<Identity>,
+        amount: Uint256,
+    },
+    /// Past the throttle threshold but not yet the close threshold, exits slow the client down as
+    /// a warning, everyone else keeps the tunnel open
+    ThrottleTunnel,
 }
 
 pub fn send_debt_update() -> Result<Vec<UnpublishedPaymentTx>, RitaCommonError> {
@@ -327,23 +559,34 @@ pub fn send_debt_update() -> Result<Vec<UnpublishedPaymentTx>, RitaCommonError>
     let mut debts_message = Vec::new();
     let mut payments_to_send = Vec::new();
 
-    for (k, _) in dk.debt_data.clone() {
+    for (k, before) in dk.debt_data.clone() {
         match dk.send_update(&k)? {
             DebtAction::SuspendTunnel => {
+                if before.action != DebtAction::SuspendTunnel {
+                    publish_event(RitaEvent::EnforcementStarted {
+                        neighbor: k.wg_public_key,
+                        debt: dk.get_debt_data_mut(&k).debt,
+                    });
+                }
                 debts_message.push(TunnelChange {
                     identity: k,
                     action: TunnelAction::PaymentOverdue,
                 });
             }
-            DebtAction::OpenTunnel => {
+            DebtAction::OpenTunnel | DebtAction::ThrottleTunnel => {
+                if before.action == DebtAction::SuspendTunnel {
+                    publish_event(RitaEvent::EnforcementEnded {
+                        neighbor: k.wg_public_key,
+                    });
+                }
                 debts_message.push(TunnelChange {
                     identity: k,
                     action: TunnelAction::PaidOnTime,
                 });
             }
             DebtAction::MakePayment { to, amount } => {
-                let payment = get_rita_common().payment;
-                if let SystemChain::Xdai = payment.system_chain {
+                let common = settings::get_rita_common_snapshot();
+                if let SystemChain::Xdai = common.payment().system_chain {
                     if potential_payment_issues_detected() {
                         warn!("Potential payment issue detected");
                         return Err(RitaCommonError::MiscStringError(
@@ -353,7 +596,7 @@ pub fn send_debt_update() -> Result<Vec<UnpublishedPaymentTx>, RitaCommonError>
                 }
                 payments_to_send.push(UnpublishedPaymentTx {
                     to: *to,
-                    from: match settings::get_rita_common().get_identity() {
+                    from: match common.get_identity() {
                         Some(id) => id,
                         None => {
                             return Err(RitaCommonError::MiscStringError(
@@ -431,9 +674,26 @@ impl Default for DebtKeeper {
         // if the loading process goes wrong for any reason, we just start again
         let blank_debt_keeper = DebtKeeper {
             last_save: None,
+            started: Instant::now(),
             debt_data: HashMap::new(),
         };
 
+        let payment = settings::get_rita_common().payment;
+        if payment.volatile_debts {
+            info!("Debts are volatile, not restoring them");
+            return blank_debt_keeper;
+        }
+        if let Some(saved) = fs::read(&payment.debts_file)
+            .ok()
+            .and_then(|bytes| decode_debts_file(&bytes))
+        {
+            return DebtKeeper {
+                last_save: None,
+                started: Instant::now(),
+                debt_data: restore_saved_debts(saved, now_secs(), payment.debts_max_age),
+            };
+        }
+
         let deserialized_binary =
             deserialize_from_binary(settings::get_rita_common().payment.debts_file);
         let deserialized_json =
@@ -445,16 +705,19 @@ impl Default for DebtKeeper {
             }
             (None, Some(val)) => DebtKeeper {
                 last_save: None,
+                started: Instant::now(),
                 debt_data: ser_to_debt_data(val),
             },
             (Some(val), None) => DebtKeeper {
                 last_save: None,
+                started: Instant::now(),
                 debt_data: ser_to_debt_data(val),
             },
             (Some(val), Some(_)) => {
                 log::info!("File is both binary and json");
                 DebtKeeper {
                     last_save: None,
+                    started: Instant::now(),
                     debt_data: ser_to_debt_data(val),
                 }
             }
@@ -470,6 +733,7 @@ impl DebtKeeper {
 
         DebtKeeper {
             last_save: None,
+            started: Instant::now(),
             debt_data: DebtData::new(),
         }
     }
@@ -498,6 +762,9 @@ impl DebtKeeper {
 
     fn save(&mut self) -> Result<(), IOError> {
         let mut new_settings = settings::get_rita_common();
+        if new_settings.payment.volatile_debts {
+            return Ok(());
+        }
         let mut file_path: String = new_settings.payment.debts_file;
         // convert to the serializeable format and dump to the disk
         if file_path.ends_with("json") {
@@ -508,9 +775,10 @@ impl DebtKeeper {
         new_settings.payment.debts_file = file_path;
         settings::set_rita_common(new_settings);
 
-        let serialized = bincode::serialize(&debt_data_to_ser(self.debt_data.clone())).unwrap();
-        let mut file = File::create(path)?;
-        file.write_all(&serialized)
+        write_atomically(
+            &path,
+            &encode_debts_file(self.debt_data.clone(), now_secs()),
+        )
     }
 
     fn get_debts(&self) -> DebtData {
@@ -534,6 +802,7 @@ impl DebtKeeper {
 
         peer.total_payment_sent += amount;
         peer.last_successful_payment = Some(Instant::now());
+        peer.last_activity = Some(Instant::now());
         peer.debt -= match amount.to_int256() {
             Some(val) => val,
             None => {
@@ -545,6 +814,66 @@ impl DebtKeeper {
         Ok(())
     }
 
+    /// The amount comes out of incoming payments not yet applied to the debt first, the rest is
+    /// owed again
+    fn incoming_payment_reverted(
+        &mut self,
+        ident: &Identity,
+        amount: Uint256,
+    ) -> Result<(), RitaCommonError> {
+        let debt_data = self.get_debt_data_mut(ident);
+        debt_data.total_payment_received = if debt_data.total_payment_received > amount {
+            debt_data.total_payment_received - amount
+        } else {
+            Uint256::zero()
+        };
+        let from_incoming = std::cmp::min(debt_data.incoming_payments, amount);
+        debt_data.incoming_payments -= from_incoming;
+        debt_data.debt -= match (amount - from_incoming).to_int256() {
+            Some(val) => val,
+            None => {
+                return Err(RitaCommonError::ConversionError(
+                    "Failed to convert reverted amount to Int256!".to_string(),
+                ))
+            }
+        };
+        info!(
+            "Reverted payment of {} from {}, debt is now {}",
+            amount, ident.wg_public_key, debt_data.debt
+        );
+        Ok(())
+    }
+
+    /// The payment is put back in flight so that we don't pay again until it is either mined
+    /// again or times out
+    fn outgoing_payment_reverted(
+        &mut self,
+        ident: &Identity,
+        amount: Uint256,
+    ) -> Result<(), RitaCommonError> {
+        let peer = self.get_debt_data_mut(ident);
+        peer.total_payment_sent = if peer.total_payment_sent > amount {
+            peer.total_payment_sent - amount
+        } else {
+            Uint256::zero()
+        };
+        peer.debt += match amount.to_int256() {
+            Some(val) => val,
+            None => {
+                return Err(RitaCommonError::ConversionError(
+                    "Failed to convert reverted amount to Int256!".to_string(),
+                ))
+            }
+        };
+        peer.payment_in_flight = true;
+        peer.payment_in_flight_start = Some(Instant::now());
+        info!(
+            "Reverted our payment of {} to {}, debt is now {}",
+            amount, ident.wg_public_key, peer.debt
+        );
+        Ok(())
+    }
+
     fn payment_received(
         &mut self,
         ident: &Identity,
@@ -554,6 +883,7 @@ impl DebtKeeper {
         let unsigned_zero = Uint256::zero();
 
         let debt_data = self.get_debt_data_mut(ident);
+        debt_data.last_activity = Some(Instant::now());
         info!(
             "payment received: old incoming payments for {:?}: {:?}",
             ident.mesh_ip, debt_data.incoming_payments
@@ -608,6 +938,7 @@ impl DebtKeeper {
     fn traffic_update(&mut self, ident: &Identity, amount: Int256) {
         trace!("traffic update for {} is {}", ident.mesh_ip, amount);
         let debt_data = self.get_debt_data_mut(ident);
+        debt_data.last_activity = Some(Instant::now());
 
         // we handle the incoming debit or credit versus our existing debit or credit
         // very simple
@@ -621,25 +952,58 @@ impl DebtKeeper {
         let debt_data = self.get_debt_data_mut(ident);
 
         // if we have a payment in flight we shouldn't reset the debt as
-        // we may end up double paying we also should wait 60 seconds after
+        // we may end up double paying we also should wait after
         // our last successful payment to make sure that the exit has had time
         // to check the full node, then update it's own debt keeper
-        match (
-            debt_data.payment_in_flight,
-            debt_data.last_successful_payment,
-        ) {
-            (true, _) => {}
-            (false, Some(val)) => {
-                if Instant::now() - val > Duration::from_secs(15) {
-                    debt_data.debt = amount;
-                }
-            }
-            (false, None) => debt_data.debt = amount,
+        if debt_data.debt_changeable(Instant::now()) {
+            debt_data.debt = amount;
         }
 
         trace!("debt data for {} is {:?}", ident.mesh_ip, debt_data);
     }
 
+    fn bulk_update(
+        &mut self,
+        filter: &DebtFilter,
+        operation: &BulkDebtOperation,
+        dry_run: bool,
+    ) -> Vec<BulkDebtChange> {
+        let now = Instant::now();
+        let started = self.started;
+        let mut changes: Vec<BulkDebtChange> = self
+            .debt_data
+            .iter()
+            .filter(|(ident, data)| filter.matches(ident, data, now, started))
+            .map(|(ident, data)| {
+                // dry runs and real runs skip the same debts so the dry run shows what will happen
+                let skipped = !data.debt_changeable(now);
+                let after = match (operation, skipped) {
+                    (_, true) => data.debt,
+                    (BulkDebtOperation::Reset, false) => Int256::zero(),
+                    (BulkDebtOperation::Adjust { amount }, false) => data.debt + *amount,
+                };
+                BulkDebtChange {
+                    identity: *ident,
+                    before: data.debt,
+                    after,
+                    skipped,
+                }
+            })
+            .collect();
+        changes.sort_by_key(|change| change.identity.wg_public_key.to_string());
+
+        if !dry_run {
+            for change in changes.iter().filter(|change| !change.skipped) {
+                info!(
+                    "Bulk debt {:?} changed {} from {} to {}",
+                    operation, change.identity.wg_public_key, change.before, change.after
+                );
+                self.get_debt_data_mut(&change.identity).debt = change.after;
+            }
+        }
+        changes
+    }
+
     /// This updates a neighbor's debt and outputs a DebtAction if one is necessary.
     fn send_update(&mut self, ident: &Identity) -> Result<DebtAction, RitaCommonError> {
         trace!("debt data: {:?}", self.debt_data);
@@ -655,7 +1019,8 @@ impl DebtKeeper {
             );
         }
 
-        let payment_settings = settings::get_rita_common().payment;
+        let common = settings::get_rita_common_snapshot();
+        let payment_settings = common.payment();
         let close_threshold = calculate_close_thresh();
         let pay_threshold = get_pay_thresh();
         let debt_limit_enabled = payment_settings.debt_limit_enabled;
@@ -740,6 +1105,20 @@ impl DebtKeeper {
                     return Ok(DebtAction::OpenTunnel);
                 }
 
+                // not enforced on yet, but close enough to warn them by slowing them down
+                if let (true, Some(throttle_threshold)) =
+                    (enable_enforcement, calculate_throttle_thresh())
+                {
+                    if debt_data.debt < throttle_threshold {
+                        info!(
+                            "debt {} is below throttle threshold {} for {}. throttling",
+                            debt_data.debt, throttle_threshold, ident.wg_public_key
+                        );
+                        debt_data.action = DebtAction::ThrottleTunnel;
+                        return Ok(DebtAction::ThrottleTunnel);
+                    }
+                }
+
                 debt_data.action = DebtAction::OpenTunnel;
                 Ok(DebtAction::OpenTunnel)
             }
@@ -822,6 +1201,7 @@ mod tests {
     use super::*;
     use rand::Rng;
     use settings::client::RitaClientSettings;
+    use std::io::Write;
 
     fn get_test_identity() -> Identity {
         Identity::new(
@@ -871,6 +1251,118 @@ mod tests {
         assert_eq!(d.send_update(&ident).unwrap(), DebtAction::SuspendTunnel);
     }
 
+    #[test]
+    fn test_bulk_update() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut d = DebtKeeper::new();
+        let owes_us = get_test_identity();
+        let mut we_owe = get_test_identity();
+        we_owe.mesh_ip = "2001::4".parse().unwrap();
+        we_owe.wg_public_key = [2u8; 32].into();
+        d.traffic_update(&owes_us, Int256::from(-100i64));
+        d.traffic_update(&we_owe, Int256::from(50i64));
+
+        let owing = DebtFilter {
+            max_debt: Some(Int256::zero()),
+            ..Default::default()
+        };
+        let forgive = BulkDebtOperation::Adjust {
+            amount: Int256::from(40i64),
+        };
+        // a dry run only reports
+        let changes = d.bulk_update(&owing, &forgive, true);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].identity, owes_us);
+        assert_eq!(changes[0].after, Int256::from(-60i64));
+        assert_eq!(d.get_debt_data_mut(&owes_us).debt, Int256::from(-100i64));
+
+        d.bulk_update(&owing, &forgive, false);
+        assert_eq!(d.get_debt_data_mut(&owes_us).debt, Int256::from(-60i64));
+        assert_eq!(d.get_debt_data_mut(&we_owe).debt, Int256::from(50i64));
+
+        // both just had traffic so neither is idle
+        let idle = DebtFilter {
+            min_idle_secs: Some(60),
+            ..Default::default()
+        };
+        assert!(d
+            .bulk_update(&idle, &BulkDebtOperation::Reset, false)
+            .is_empty());
+
+        let listed = DebtFilter {
+            wg_keys: vec![we_owe.wg_public_key],
+            ..Default::default()
+        };
+        let changes = d.bulk_update(&listed, &BulkDebtOperation::Reset, false);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].after, Int256::zero());
+        assert_eq!(d.get_debt_data_mut(&we_owe).debt, Int256::zero());
+
+        // a payment in flight keeps both operations off the debt, dry run or not
+        d.get_debt_data_mut(&owes_us).payment_in_flight = true;
+        let everyone = DebtFilter::default();
+        for dry_run in [true, false] {
+            let changes = d.bulk_update(&everyone, &forgive, dry_run);
+            let change = changes.iter().find(|c| c.identity == owes_us).unwrap();
+            assert!(change.skipped);
+            assert_eq!(change.after, Int256::from(-60i64));
+        }
+        assert_eq!(d.get_debt_data_mut(&owes_us).debt, Int256::from(-60i64));
+        d.get_debt_data_mut(&owes_us).payment_in_flight = false;
+
+        // after a restart nodes without activity are only idle for as long as rita has been up
+        d.get_debt_data_mut(&owes_us).last_activity = None;
+        assert!(d
+            .bulk_update(&idle, &BulkDebtOperation::Reset, true)
+            .is_empty());
+        d.started = Instant::now()
+            .checked_sub(Duration::from_secs(120))
+            .unwrap();
+        let changes = d.bulk_update(&idle, &BulkDebtOperation::Reset, true);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].identity, owes_us);
+    }
+
+    #[test]
+    fn test_throttle_before_suspend() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut client = settings::get_rita_client();
+        client.payment.payment_threshold = 10.into();
+        client.payment.throttle_threshold_percent = 50;
+        settings::set_rita_client(client);
+
+        let mut d = DebtKeeper::new();
+        let ident = get_test_identity();
+
+        // close threshold is -100 and throttling starts at -50
+        d.traffic_update(&ident, Int256::from(-20i64));
+        assert_eq!(d.send_update(&ident).unwrap(), DebtAction::OpenTunnel);
+        d.traffic_update(&ident, Int256::from(-40i64));
+        assert_eq!(d.send_update(&ident).unwrap(), DebtAction::ThrottleTunnel);
+        d.traffic_update(&ident, Int256::from(-50i64));
+        assert_eq!(d.send_update(&ident).unwrap(), DebtAction::SuspendTunnel);
+
+        // paying down below the throttle threshold lifts it
+        d.payment_received(&ident, Uint256::from(100u32)).unwrap();
+        assert_eq!(d.send_update(&ident).unwrap(), DebtAction::OpenTunnel);
+    }
+
+    #[test]
+    fn test_shadow_traffic_update() {
+        let ident = get_test_identity();
+        for _ in 0..2 {
+            shadow_traffic_update(vec![Traffic {
+                from: ident,
+                amount: Int256::from(-100i64),
+            }]);
+        }
+
+        let shadow = get_shadow_debts_list();
+        assert_eq!(shadow.len(), 1);
+        assert_eq!(shadow[0].identity, ident);
+        assert_eq!(shadow[0].debt, Int256::from(-200i64));
+    }
+
     #[test]
     fn test_single_overpay() {
         settings::set_rita_client(RitaClientSettings::default());
@@ -888,6 +1380,36 @@ mod tests {
         assert_eq!(d.send_update(&ident).unwrap(), DebtAction::OpenTunnel);
     }
 
+    #[test]
+    fn test_payment_reverted() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut d = DebtKeeper::new();
+        let ident = get_test_identity();
+
+        // they owed us 100 and overpaid by 50
+        d.traffic_update(&ident, Int256::from(-100i64));
+        d.payment_received(&ident, Uint256::from(150u64)).unwrap();
+        assert_eq!(d.get_debt_data_mut(&ident).debt, Int256::zero());
+        assert_eq!(
+            d.get_debt_data_mut(&ident).incoming_payments,
+            Uint256::from(50u64)
+        );
+        d.incoming_payment_reverted(&ident, Uint256::from(150u64))
+            .unwrap();
+        let data = d.get_debt_data_mut(&ident);
+        assert_eq!(data.debt, Int256::from(-100i64));
+        assert_eq!(data.incoming_payments, Uint256::zero());
+        assert_eq!(data.total_payment_received, Uint256::zero());
+
+        d.traffic_update(&ident, Int256::from(300i64));
+        d.payment_succeeded(&ident, Uint256::from(200u64)).unwrap();
+        d.outgoing_payment_reverted(&ident, Uint256::from(200u64))
+            .unwrap();
+        let data = d.get_debt_data_mut(&ident);
+        assert_eq!(data.debt, Int256::from(200i64));
+        assert!(data.payment_in_flight);
+    }
+
     #[test]
     fn test_single_pay() {
         settings::set_rita_client(RitaClientSettings::default());
@@ -1338,6 +1860,7 @@ mod tests {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            last_activity: None,
         };
 
         let id2 = Identity {
@@ -1360,6 +1883,7 @@ mod tests {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            last_activity: None,
         };
 
         debt_data.insert(id, node_debts);
@@ -1404,6 +1928,60 @@ mod tests {
         // println!("{:?}", x);
     }
 
+    #[test]
+    fn test_debts_file_round_trip() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut debt_data: DebtData = HashMap::new();
+        let mut owed = NodeDebtData::new();
+        owed.debt = Int256::from(500_000i64);
+        let mut credit = NodeDebtData::new();
+        credit.debt = Int256::from(700i64);
+        credit.incoming_payments = Uint256::from(1000u64);
+        let owed_id = get_random_test_identity();
+        let credit_id = get_random_test_identity();
+        debt_data.insert(owed_id, owed);
+        debt_data.insert(credit_id, credit);
+
+        let file_path = "testing_debt_atomic_saving.bincode";
+        write_atomically(file_path, &encode_debts_file(debt_data, 1_000)).unwrap();
+        let saved = decode_debts_file(&fs::read(file_path).unwrap()).unwrap();
+        remove_file(file_path).unwrap();
+        assert_eq!(saved.saved_at, 1_000);
+
+        let fresh = restore_saved_debts(saved.clone(), 2_000, 3_600);
+        assert_eq!(fresh[&owed_id].debt, Int256::from(500_000i64));
+
+        // too old, only the credit survives
+        let stale = restore_saved_debts(saved.clone(), 10_000, 3_600);
+        assert_eq!(stale[&credit_id].debt, Int256::zero());
+        assert_eq!(stale[&credit_id].incoming_payments, Uint256::from(1000u64));
+
+        // saved in the future, the clock has not been set yet
+        let early = restore_saved_debts(saved, 10, 3_600);
+        assert_eq!(early[&owed_id].debt, Int256::from(500_000i64));
+
+        // files in the older format are left to the legacy loaders
+        let legacy = bincode::serialize(&DebtDataSer::new()).unwrap();
+        assert!(decode_debts_file(&legacy).is_none());
+    }
+
+    #[test]
+    fn test_debts_file_bounded() {
+        let mut debt_data: DebtData = HashMap::new();
+        let mut largest = NodeDebtData::new();
+        largest.debt = Int256::from(-1_000_000i64);
+        let largest_id = get_random_test_identity();
+        debt_data.insert(largest_id, largest);
+        for _ in 0..MAX_SAVED_DEBTS {
+            let mut small = NodeDebtData::new();
+            small.debt = Int256::from(5i64);
+            debt_data.insert(get_random_test_identity(), small);
+        }
+        let saved = decode_debts_file(&encode_debts_file(debt_data, 0)).unwrap();
+        assert_eq!(saved.debts.len(), MAX_SAVED_DEBTS);
+        assert!(saved.debts.iter().any(|(id, _)| *id == largest_id));
+    }
+
     #[test]
     fn test_normalize_payment_amount() {
         // this is $6 in a 6 decimal of precision token where 1 unit = $1
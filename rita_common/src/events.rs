@@ -0,0 +1,416 @@
+//! An event bus for notifying operators about things they would otherwise have to notice on the dashboard,
+//! such as a neighbor being cut off for non payment, the router changing exits, the balance running low or
+//! a crash. Subsystems publish typed events with publish_event(), which never blocks, and a delivery
+//! thread hands each event to the sinks configured in network.events: a webhook, an MQTT broker and
+//! the log. Each event type can be turned off individually in network.events.enabled. Every enabled event
+//! also lands in the dashboard notifications inbox, see crate::notifications.
+
+use crate::memory_monitor::{MemoryPressureLevel, MemorySample};
+use crate::notifications::add_notification_for_event;
+use crate::RitaCommonError;
+use actix_async::System as AsyncSystem;
+use althea_types::unix_time::unix_secs;
+use althea_types::{Identity, WgKey};
+use crossbeam::channel::{bounded, Receiver, Sender};
+use num256::{Int256, Uint256};
+use settings::events::{EnabledEvents, EventSettings, MqttSinkSettings};
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::panic;
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Events published faster than they can be delivered are dropped past this many
+const MAX_QUEUED_EVENTS: usize = 64;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const MQTT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a panicking thread waits for its crash event to be delivered
+const CRASH_DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref EVENT_QUEUE: (Sender<QueuedEvent>, Receiver<QueuedEvent>) =
+        bounded(MAX_QUEUED_EVENTS);
+}
+static START_EVENT_DELIVERY: Once = Once::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RitaEvent {
+    /// A neighbor's tunnel was suspended because they owe us more than the close threshold
+    EnforcementStarted {
+        neighbor: WgKey,
+        debt: Int256,
+    },
+    /// A suspended neighbor paid and their tunnel was opened again
+    EnforcementEnded {
+        neighbor: WgKey,
+    },
+    ExitSwitched {
+        from: Option<IpAddr>,
+        to: IpAddr,
+    },
+    BalanceLow {
+        balance: Uint256,
+        warning_level: Uint256,
+    },
+    /// A thread panicked, the message includes where
+    Crash {
+        message: String,
+    },
+    /// Memory use got worse, shed is true if history was dropped to make room
+    MemoryPressure {
+        level: MemoryPressureLevel,
+        sample: MemorySample,
+        shed: bool,
+    },
+    /// Sending a payment failed after sending payments had been working, further failures aren't
+    /// published until a payment goes through again
+    PaymentFailed {
+        to: Identity,
+        amount: Uint256,
+        reason: String,
+    },
+    /// A validated payment was dropped from the chain by a reorg and its credit reversed, incoming
+    /// if it was paid to us. Both sides see the reorg and publish this on their own
+    PaymentReverted {
+        counterparty: Identity,
+        amount: Uint256,
+        txid: Uint256,
+        incoming: bool,
+    },
+}
+
+impl RitaEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RitaEvent::EnforcementStarted { .. } => "enforcement_started",
+            RitaEvent::EnforcementEnded { .. } => "enforcement_ended",
+            RitaEvent::ExitSwitched { .. } => "exit_switched",
+            RitaEvent::BalanceLow { .. } => "balance_low",
+            RitaEvent::Crash { .. } => "crash",
+            RitaEvent::MemoryPressure { .. } => "memory_pressure",
+            RitaEvent::PaymentFailed { .. } => "payment_failed",
+            RitaEvent::PaymentReverted { .. } => "payment_reverted",
+        }
+    }
+
+    fn enabled(&self, enabled: &EnabledEvents) -> bool {
+        match self {
+            RitaEvent::EnforcementStarted { .. } => enabled.enforcement_started,
+            RitaEvent::EnforcementEnded { .. } => enabled.enforcement_ended,
+            RitaEvent::ExitSwitched { .. } => enabled.exit_switched,
+            RitaEvent::BalanceLow { .. } => enabled.balance_low,
+            RitaEvent::Crash { .. } => enabled.crash,
+            RitaEvent::MemoryPressure { .. } => enabled.memory_pressure,
+            RitaEvent::PaymentFailed { .. } => enabled.payment_failed,
+            RitaEvent::PaymentReverted { .. } => enabled.payment_reverted,
+        }
+    }
+}
+
+/// What sinks are sent, the event along with when and where it happened
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EventMessage {
+    #[serde(flatten)]
+    pub event: RitaEvent,
+    /// Unix time in seconds
+    pub timestamp: u64,
+    pub identity: Option<Identity>,
+}
+
+struct QueuedEvent {
+    event: RitaEvent,
+    timestamp: SystemTime,
+    /// Signaled once the event has been handed to every sink
+    delivered: Option<Sender<()>>,
+}
+
+/// Queues an event for delivery, dropping it if the queue is full. Safe to call while holding
+/// locks, settings are only read by the delivery thread
+pub fn publish_event(event: RitaEvent) {
+    queue_event(event, None);
+}
+
+fn queue_event(event: RitaEvent, delivered: Option<Sender<()>>) {
+    let name = event.name();
+    let queued = QueuedEvent {
+        event,
+        timestamp: SystemTime::now(),
+        delivered,
+    };
+    if EVENT_QUEUE.0.try_send(queued).is_err() {
+        warn!("Event queue full, dropping {} event", name);
+    }
+}
+
+/// Starts the delivery thread and reports panics as crash events, only the first call does anything
+pub fn start_event_delivery() {
+    START_EVENT_DELIVERY.call_once(|| {
+        install_crash_hook();
+        thread::spawn(|| {
+            while let Err(e) = thread::spawn(|| loop {
+                match EVENT_QUEUE.1.recv() {
+                    Ok(queued) => deliver(queued),
+                    Err(e) => error!("Event queue closed {:?}", e),
+                }
+            })
+            .join()
+            {
+                error!("Event delivery thread paniced! Respawning {:?}", e);
+            }
+        });
+    });
+}
+
+/// Publishes a crash event when any thread panics and gives it a few seconds to go out, in case the
+/// panic takes the whole process down
+fn install_crash_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let (sender, receiver) = bounded(1);
+        queue_event(
+            RitaEvent::Crash {
+                message: info.to_string(),
+            },
+            Some(sender),
+        );
+        let _ = receiver.recv_timeout(CRASH_DELIVERY_TIMEOUT);
+    }));
+}
+
+fn deliver(queued: QueuedEvent) {
+    let common = settings::get_rita_common();
+    let settings = common.network.events;
+    let timestamp = unix_secs(queued.timestamp);
+    // events the user turned off are dropped before they reach the inbox or a sink
+    if queued.event.enabled(&settings.enabled) {
+        add_notification_for_event(&queued.event, timestamp);
+        let message = EventMessage {
+            event: queued.event,
+            timestamp,
+            identity: common.get_identity(),
+        };
+        send_to_sinks(&settings, &message);
+    }
+    if let Some(delivered) = queued.delivered {
+        let _ = delivered.try_send(());
+    }
+}
+
+fn send_to_sinks(settings: &EventSettings, message: &EventMessage) {
+    let payload = match serde_json::to_string(message) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Failed to serialize event {:?} {:?}", message, e);
+            return;
+        }
+    };
+    if settings.log {
+        info!("Event: {}", payload);
+    }
+    if let Some(url) = settings.webhook_url.clone() {
+        let runner = AsyncSystem::new();
+        let res = runner.block_on(send_to_webhook(url, message.clone()));
+        if let Err(e) = res {
+            warn!(
+                "Failed to send {} event to webhook {:?}",
+                message.event.name(),
+                e
+            );
+        }
+    }
+    if let Some(mqtt) = &settings.mqtt {
+        let client_id = mqtt.client_id.clone().unwrap_or_else(|| {
+            message
+                .identity
+                .map(|id| id.wg_public_key.to_string())
+                .unwrap_or_else(|| "rita".to_string())
+        });
+        let topic = format!("{}/{}", mqtt.topic, message.event.name());
+        if let Err(e) = mqtt_publish(mqtt, &client_id, &topic, payload.as_bytes()) {
+            warn!(
+                "Failed to send {} event to mqtt {:?}",
+                message.event.name(),
+                e
+            );
+        }
+    }
+}
+
+async fn send_to_webhook(url: String, message: EventMessage) -> Result<(), RitaCommonError> {
+    let client = awc::Client::default();
+    let response = client
+        .post(&url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .send_json(&message)
+        .await;
+    match response {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(RitaCommonError::MiscStringError(format!(
+            "Webhook {url} returned {}",
+            response.status()
+        ))),
+        Err(e) => Err(RitaCommonError::MiscStringError(format!(
+            "Webhook {url} failed with {e}"
+        ))),
+    }
+}
+
+/// MQTT encodes packet lengths 7 bits at a time, low bits first
+fn encode_remaining_length(mut len: usize, out: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_string(value: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+fn mqtt_packet(packet_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![packet_type];
+    encode_remaining_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// An MQTT 3.1.1 connect packet with a clean session
+fn mqtt_connect_packet(client_id: &str, username: Option<&str>, password: Option<&str>) -> Vec<u8> {
+    let mut flags = 0x02;
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    encode_string(b"MQTT", &mut body);
+    // protocol level 4 is MQTT 3.1.1
+    body.push(4);
+    body.push(flags);
+    // keep alive in seconds, we disconnect right after publishing
+    body.extend_from_slice(&60u16.to_be_bytes());
+    encode_string(client_id.as_bytes(), &mut body);
+    if let Some(username) = username {
+        encode_string(username.as_bytes(), &mut body);
+    }
+    if let Some(password) = password {
+        encode_string(password.as_bytes(), &mut body);
+    }
+    mqtt_packet(0x10, &body)
+}
+
+/// A QoS 0 publish packet
+fn mqtt_publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_string(topic.as_bytes(), &mut body);
+    body.extend_from_slice(payload);
+    mqtt_packet(0x30, &body)
+}
+
+/// Connects to the broker, publishes one message at QoS 0 and disconnects. Events are rare enough
+/// that holding a connection open is not worth it
+fn mqtt_publish(
+    settings: &MqttSinkSettings,
+    client_id: &str,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), RitaCommonError> {
+    let addr = match settings.broker.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => {
+            return Err(RitaCommonError::MiscStringError(format!(
+                "Could not resolve mqtt broker {}",
+                settings.broker
+            )))
+        }
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, MQTT_TIMEOUT)?;
+    stream.set_read_timeout(Some(MQTT_TIMEOUT))?;
+    stream.set_write_timeout(Some(MQTT_TIMEOUT))?;
+
+    stream.write_all(&mqtt_connect_packet(
+        client_id,
+        settings.username.as_deref(),
+        settings.password.as_deref(),
+    ))?;
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack)?;
+    if connack[0] != 0x20 || connack[3] != 0 {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Mqtt broker {} refused connection with code {}",
+            settings.broker, connack[3]
+        )));
+    }
+    stream.write_all(&mqtt_publish_packet(topic, payload))?;
+    // disconnect
+    stream.write_all(&[0xe0, 0x00])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_remaining_length() {
+        let mut out = Vec::new();
+        encode_remaining_length(0, &mut out);
+        assert_eq!(out, vec![0x00]);
+        out.clear();
+        encode_remaining_length(127, &mut out);
+        assert_eq!(out, vec![0x7f]);
+        out.clear();
+        encode_remaining_length(128, &mut out);
+        assert_eq!(out, vec![0x80, 0x01]);
+        out.clear();
+        encode_remaining_length(16_383, &mut out);
+        assert_eq!(out, vec![0xff, 0x7f]);
+    }
+
+    #[test]
+    fn test_mqtt_packets() {
+        assert_eq!(
+            mqtt_connect_packet("ab", Some("u"), None),
+            vec![
+                0x10, 17, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x82, 0, 60, 0, 2, b'a', b'b', 0, 1,
+                b'u'
+            ]
+        );
+        assert_eq!(
+            mqtt_publish_packet("a/b", b"{}"),
+            vec![0x30, 7, 0, 3, b'a', b'/', b'b', b'{', b'}']
+        );
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let message = EventMessage {
+            event: RitaEvent::ExitSwitched {
+                from: None,
+                to: "fd00::1337".parse().unwrap(),
+            },
+            timestamp: 10,
+            identity: None,
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["event"], "exit_switched");
+        assert_eq!(json["to"], "fd00::1337");
+        assert_eq!(json["timestamp"], 10);
+        assert!(!message.event.enabled(&EnabledEvents {
+            exit_switched: false,
+            ..Default::default()
+        }));
+    }
+}
@@ -0,0 +1,73 @@
+//! The extensions we attach to our hellos and exit requests and the ones our neighbors sent us, see
+//! althea_types::extensions. Features register their keys here instead of changing the hello format,
+//! and read what neighbors sent with neighbor_extensions.
+
+use crate::RitaCommonError;
+use crate::KI;
+use althea_types::{Extensions, WgKey};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+/// Neighbor keys are taken from hellos before any handshake proves them, so this bounds what a
+/// peer inventing keys can make us hold between tunnel gc runs
+const MAX_NEIGHBOR_EXTENSIONS: usize = 256;
+
+#[derive(Default)]
+struct ExtensionStore {
+    ours: Extensions,
+    neighbors: HashMap<WgKey, Extensions>,
+}
+
+lazy_static! {
+    /// Keyed by netns so that integration tests running many nodes in one process keep them apart
+    static ref EXTENSIONS: Arc<RwLock<HashMap<u32, ExtensionStore>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+fn with_extensions<T>(f: impl FnOnce(&mut ExtensionStore) -> T) -> T {
+    let netns = KI.check_integration_test_netns();
+    f(EXTENSIONS.write().unwrap().entry(netns).or_default())
+}
+
+/// Sets a key we send to neighbors and exits from now on
+pub fn set_local_extension(key: &str, value: Vec<u8>) -> Result<(), RitaCommonError> {
+    with_extensions(|store| store.ours.insert(key.to_string(), value))
+        .map_err(|e| RitaCommonError::MiscStringError(e.to_string()))
+}
+
+pub fn remove_local_extension(key: &str) {
+    with_extensions(|store| store.ours.remove(key));
+}
+
+/// What we attach to outgoing messages
+pub fn local_extensions() -> Extensions {
+    with_extensions(|store| store.ours.clone())
+}
+
+/// Keeps what a neighbor sent with its latest hello, replacing what it sent before
+pub fn record_neighbor_extensions(key: WgKey, extensions: Extensions) {
+    with_extensions(|store| {
+        if extensions.is_empty() {
+            store.neighbors.remove(&key);
+        } else if store.neighbors.len() < MAX_NEIGHBOR_EXTENSIONS
+            || store.neighbors.contains_key(&key)
+        {
+            store.neighbors.insert(key, extensions);
+        } else {
+            warn!(
+                "Too many neighbor extensions stored, ignoring those from {}",
+                key
+            );
+        }
+    });
+}
+
+/// Drops what neighbors we no longer have a tunnel to sent us, called from tunnel gc
+pub fn prune_neighbor_extensions(keep: &HashSet<WgKey>) {
+    with_extensions(|store| store.neighbors.retain(|key, _| keep.contains(key)));
+}
+
+/// What a neighbor sent with its latest hello, empty for neighbors that predate extensions
+pub fn neighbor_extensions(key: &WgKey) -> Extensions {
+    with_extensions(|store| store.neighbors.get(key).cloned().unwrap_or_default())
+}
@@ -18,18 +18,31 @@ lazy_static! {
 pub static DROPBEAR_CONFIG: &str = "/etc/config/dropbear";
 pub static DROPBEAR_AUTHORIZED_KEYS: &str = "/etc/dropbear/authorized_keys";
 
+pub mod artifact_cache;
 pub mod blockchain_oracle;
+pub mod broadcast_notices;
+pub mod capabilities;
 pub mod dashboard;
 pub mod debt_keeper;
+pub mod events;
+pub mod extensions;
 pub mod logging;
+pub mod login_lockout;
+pub mod memory_monitor;
 pub mod middleware;
 pub mod network_endpoints;
 pub mod network_monitor;
+pub mod notifications;
+pub mod path_diagnostics;
 pub mod payment_controller;
 pub mod payment_validator;
+pub mod peer_labels;
 pub mod peer_listener;
+pub mod reconciliation;
 pub mod rita_loop;
+pub mod scheduler;
 pub mod simulated_txfee_manager;
+pub mod speed_test;
 pub mod token_bridge;
 pub mod traffic_watcher;
 pub mod tunnel_manager;
@@ -1,13 +1,196 @@
+//! Logger setup for Rita. Both the local env_logger and the remote compressed logger are wrapped in a
+//! ModuleFilterLogger, which lets the dashboard change the log level of a single module at runtime for a
+//! limited time, for example to get trace logs from the tunnel manager in the field without a restart.
+//! Note that levels above the compile time maximum, info for release builds, can not be enabled this way.
+
 use std::cmp::min;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use althea_kernel_interface::hardware_info::get_memory_info;
 use compressed_log::builder::LoggerBuilder;
 use compressed_log::compression::Compression;
 use log::LevelFilter;
+use log::Log;
+use log::Metadata;
 use log::Record;
 
 use crate::RitaCommonError;
 
+/// How long a module log level override lasts if no duration is given
+pub const DEFAULT_LOG_OVERRIDE_DURATION: Duration = Duration::from_secs(60 * 60);
+pub const MAX_LOG_OVERRIDE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+lazy_static! {
+    /// Module path to the level it is logged at and when that reverts
+    static ref LOG_OVERRIDES: RwLock<HashMap<String, (LevelFilter, Instant)>> =
+        RwLock::new(HashMap::new());
+    /// The max level of the installed logger before any overrides
+    static ref BASE_MAX_LEVEL: RwLock<LevelFilter> = RwLock::new(LevelFilter::Info);
+}
+/// Lets the logger skip the override lookup in the common case where there are none
+static HAS_LOG_OVERRIDES: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogLevelOverride {
+    pub module: String,
+    pub level: String,
+    /// Seconds until the module reverts to the default level
+    pub remaining_secs: u64,
+}
+
+/// Wraps a logger, applying per module level overrides on top of the logger's own filtering
+pub struct ModuleFilterLogger {
+    /// Logs every record it is handed
+    inner: Box<dyn Log>,
+    /// The filter for modules without an override
+    default_filter: Box<dyn Fn(&Metadata) -> bool + Send + Sync>,
+}
+
+impl ModuleFilterLogger {
+    fn new(
+        inner: Box<dyn Log>,
+        default_filter: Box<dyn Fn(&Metadata) -> bool + Send + Sync>,
+    ) -> ModuleFilterLogger {
+        ModuleFilterLogger {
+            inner,
+            default_filter,
+        }
+    }
+}
+
+impl Log for ModuleFilterLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if HAS_LOG_OVERRIDES.load(Ordering::Relaxed) {
+            let overrides = LOG_OVERRIDES.read().unwrap();
+            if let Some(level) = override_for(&overrides, metadata.target(), Instant::now()) {
+                return metadata.level() <= level;
+            }
+        }
+        (self.default_filter)(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// The level of the most specific unexpired override covering this target, an override for
+/// rita_common::tunnel_manager also covers rita_common::tunnel_manager::contact_peers
+fn override_for(
+    overrides: &HashMap<String, (LevelFilter, Instant)>,
+    target: &str,
+    now: Instant,
+) -> Option<LevelFilter> {
+    overrides
+        .iter()
+        .filter(|(module, (_, expires))| {
+            *expires > now
+                && (target == module.as_str()
+                    || target
+                        .strip_prefix(module.as_str())
+                        .map(|rest| rest.starts_with("::"))
+                        .unwrap_or(false))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map(|(_, (level, _))| *level)
+}
+
+fn install_logger(
+    logger: ModuleFilterLogger,
+    max_level: LevelFilter,
+) -> Result<(), RitaCommonError> {
+    if let Err(e) = log::set_boxed_logger(Box::new(logger)) {
+        return Err(RitaCommonError::SetLoggerError(e));
+    }
+    *BASE_MAX_LEVEL.write().unwrap() = max_level;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Sets up logging to stdout, configured with RUST_LOG like env_logger::init()
+pub fn enable_local_logging() -> Result<(), RitaCommonError> {
+    let filtered = env_logger::Builder::from_default_env().build();
+    let max_level = filtered.filter();
+    let inner = env_logger::Builder::from_default_env()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    install_logger(
+        ModuleFilterLogger::new(
+            Box::new(inner),
+            Box::new(move |metadata| filtered.enabled(metadata)),
+        ),
+        max_level,
+    )
+}
+
+/// Recomputes the global max level from the base level and the unexpired overrides
+fn update_max_level() {
+    let now = Instant::now();
+    let mut overrides = LOG_OVERRIDES.write().unwrap();
+    overrides.retain(|_, (_, expires)| *expires > now);
+    HAS_LOG_OVERRIDES.store(!overrides.is_empty(), Ordering::Relaxed);
+    let max_level = overrides
+        .values()
+        .map(|(level, _)| *level)
+        .fold(*BASE_MAX_LEVEL.read().unwrap(), |a, b| a.max(b));
+    log::set_max_level(max_level);
+}
+
+/// Logs the given module and its submodules at level until the duration passes, returns the level
+/// that will actually be logged, which is capped by the compile time maximum
+pub fn set_module_log_level(module: &str, level: LevelFilter, duration: Duration) -> LevelFilter {
+    let duration = duration.min(MAX_LOG_OVERRIDE_DURATION);
+    LOG_OVERRIDES
+        .write()
+        .unwrap()
+        .insert(module.to_string(), (level, Instant::now() + duration));
+    update_max_level();
+    info!(
+        "Logging {} at {} for {}s",
+        module,
+        level,
+        duration.as_secs()
+    );
+    // wake up once the override has passed to lower the max level again
+    thread::spawn(move || {
+        thread::sleep(duration + Duration::from_secs(1));
+        update_max_level();
+    });
+    level.min(log::STATIC_MAX_LEVEL)
+}
+
+/// Returns the module to the default level, returns false if it had no override
+pub fn reset_module_log_level(module: &str) -> bool {
+    let removed = LOG_OVERRIDES.write().unwrap().remove(module).is_some();
+    update_max_level();
+    removed
+}
+
+pub fn get_module_log_levels() -> Vec<LogLevelOverride> {
+    let now = Instant::now();
+    LOG_OVERRIDES
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, (_, expires))| *expires > now)
+        .map(|(module, (level, expires))| LogLevelOverride {
+            module: module.clone(),
+            level: level.to_string(),
+            remaining_secs: expires.saturating_duration_since(now).as_secs(),
+        })
+        .collect()
+}
+
 /// enables remote logging if the user has configured it
 pub fn enable_remote_logging(
     log_label: String,
@@ -21,12 +204,10 @@ pub fn enable_remote_logging(
         Err(_) => LevelFilter::Error,
     };
 
+    // the compressed logger accepts everything, filtering happens in the ModuleFilterLogger so
+    // that overrides can raise individual modules above the configured level
     let logger = prepare_logger()
-        .set_level(level.to_level().ok_or_else(|| {
-            RitaCommonError::ConversionError(
-                "Unable to convert level filter to a level".to_string(),
-            )
-        })?)
+        .set_level(log::Level::Trace)
         .set_sink_url(log_url.as_str())
         .set_format(Box::new(move |record: &Record| {
             format!(
@@ -43,10 +224,13 @@ pub fn enable_remote_logging(
     }
     let logger = logger.unwrap();
 
-    if let Err(e) = log::set_boxed_logger(Box::new(logger)) {
-        return Err(RitaCommonError::SetLoggerError(e));
-    }
-    log::set_max_level(level);
+    install_logger(
+        ModuleFilterLogger::new(
+            Box::new(logger),
+            Box::new(move |metadata| metadata.level() <= level),
+        ),
+        level,
+    )?;
 
     println!("Remote compressed logging enabled with target {log_url}");
     Ok(())
@@ -76,3 +260,41 @@ fn prepare_logger() -> LoggerBuilder {
         LoggerBuilder::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_override_for() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        let mut overrides = HashMap::new();
+        overrides.insert("rita_common".to_string(), (LevelFilter::Debug, later));
+        overrides.insert(
+            "rita_common::tunnel_manager".to_string(),
+            (LevelFilter::Trace, later),
+        );
+        overrides.insert("rita_client".to_string(), (LevelFilter::Trace, now));
+
+        assert_eq!(
+            override_for(
+                &overrides,
+                "rita_common::tunnel_manager::contact_peers",
+                now
+            ),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(
+            override_for(&overrides, "rita_common::debt_keeper", now),
+            Some(LevelFilter::Debug)
+        );
+        // a module that only shares a prefix is not covered
+        assert_eq!(override_for(&overrides, "rita_common_extra", now), None);
+        // expired
+        assert_eq!(
+            override_for(&overrides, "rita_client::exit_manager", now),
+            None
+        );
+    }
+}
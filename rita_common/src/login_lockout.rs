@@ -0,0 +1,168 @@
+//! Tracks failed dashboard password attempts per source address. After a few free attempts an address
+//! is locked out for a period that doubles with every further failure, which makes brute forcing the
+//! router password impractical. An owner who has locked themselves out can wait, or clear every lockout
+//! by pressing the router's reset button if one is configured in network.dashboard_unlock_button.
+
+use crate::KI;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Failures allowed before an address is locked out
+pub const FREE_LOGIN_ATTEMPTS: u32 = 5;
+/// Length of the first lockout, doubled with every further failure
+pub const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+pub const MAX_LOCKOUT: Duration = Duration::from_secs(24 * 60 * 60);
+/// Addresses with no failures for this long start over with free attempts
+const FORGET_FAILURES_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+const UNLOCK_BUTTON_POLL: Duration = Duration::from_millis(250);
+
+lazy_static! {
+    static ref LOGIN_LOCKOUT: Arc<RwLock<LockoutTable>> =
+        Arc::new(RwLock::new(LockoutTable::default()));
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LoginFailures {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct LockoutTable {
+    failures: HashMap<IpAddr, LoginFailures>,
+}
+
+fn lockout_length(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(FREE_LOGIN_ATTEMPTS + 1).min(31);
+    BASE_LOCKOUT.saturating_mul(1 << doublings).min(MAX_LOCKOUT)
+}
+
+impl LockoutTable {
+    /// How long this address must wait before trying again, None if it may try now
+    pub fn locked_out(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let until = self.failures.get(&ip)?.locked_until?;
+        until.checked_duration_since(now).filter(|d| !d.is_zero())
+    }
+
+    pub fn record_failure(&mut self, ip: IpAddr, now: Instant) {
+        let entry = self.failures.entry(ip).or_insert(LoginFailures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if now.saturating_duration_since(entry.last_failure) > FORGET_FAILURES_AFTER {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last_failure = now;
+        if entry.count > FREE_LOGIN_ATTEMPTS {
+            entry.locked_until = Some(now + lockout_length(entry.count));
+        }
+    }
+
+    pub fn record_success(&mut self, ip: IpAddr) {
+        self.failures.remove(&ip);
+    }
+
+    pub fn clear(&mut self) {
+        self.failures.clear();
+    }
+
+    fn prune(&mut self, now: Instant) {
+        self.failures.retain(|_, f| {
+            now.saturating_duration_since(f.last_failure) <= FORGET_FAILURES_AFTER
+                || f.locked_until.map(|u| u > now).unwrap_or(false)
+        });
+    }
+}
+
+pub fn login_locked_out(ip: IpAddr) -> Option<Duration> {
+    LOGIN_LOCKOUT.read().unwrap().locked_out(ip, Instant::now())
+}
+
+pub fn record_login_failure(ip: IpAddr) {
+    let now = Instant::now();
+    let mut table = LOGIN_LOCKOUT.write().unwrap();
+    table.prune(now);
+    table.record_failure(ip, now);
+    if let Some(wait) = table.locked_out(ip, now) {
+        warn!(
+            "Dashboard logins from {} locked out for {}s after repeated failures",
+            ip,
+            wait.as_secs()
+        );
+    }
+}
+
+pub fn record_login_success(ip: IpAddr) {
+    LOGIN_LOCKOUT.write().unwrap().record_success(ip);
+}
+
+pub fn clear_login_lockouts() {
+    LOGIN_LOCKOUT.write().unwrap().clear();
+}
+
+/// Watches the unlock button configured in network settings and clears every lockout when it is
+/// pressed. Does nothing if no button is configured
+pub fn start_unlock_button_watcher() {
+    let button = match settings::get_rita_common().network.dashboard_unlock_button {
+        Some(button) => button,
+        None => return,
+    };
+    thread::spawn(move || {
+        let mut was_pressed = false;
+        loop {
+            match KI.is_gpio_button_pressed(button.gpio, button.active_low) {
+                Ok(pressed) => {
+                    if pressed && !was_pressed {
+                        info!("Unlock button pressed, clearing dashboard login lockouts");
+                        clear_login_lockouts();
+                    }
+                    was_pressed = pressed;
+                }
+                Err(e) => {
+                    error!("Failed to read unlock button, giving up {:?}", e);
+                    return;
+                }
+            }
+            thread::sleep(UNLOCK_BUTTON_POLL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout() {
+        let ip: IpAddr = "192.168.10.100".parse().unwrap();
+        let other: IpAddr = "192.168.10.101".parse().unwrap();
+        let start = Instant::now();
+        let mut table = LockoutTable::default();
+
+        for _ in 0..FREE_LOGIN_ATTEMPTS {
+            table.record_failure(ip, start);
+        }
+        assert_eq!(table.locked_out(ip, start), None);
+
+        table.record_failure(ip, start);
+        assert_eq!(table.locked_out(ip, start), Some(BASE_LOCKOUT));
+        assert_eq!(table.locked_out(other, start), None);
+
+        // each further failure doubles the wait
+        let later = start + BASE_LOCKOUT;
+        assert_eq!(table.locked_out(ip, later), None);
+        table.record_failure(ip, later);
+        assert_eq!(table.locked_out(ip, later), Some(BASE_LOCKOUT * 2));
+
+        assert_eq!(lockout_length(1000), MAX_LOCKOUT);
+
+        table.clear();
+        assert_eq!(table.locked_out(ip, later), None);
+    }
+}
@@ -0,0 +1,213 @@
+//! Watches our own memory use so that small routers get a warning, and a chance to recover, before the
+//! OOM killer takes rita down with no trace. Every slow loop tick the resident size of the process,
+//! available system memory and the collections that grow with the network, tunnels, usage history and
+//! payment queues, are sampled. Crossing a threshold in network.memory_monitor is logged and published
+//! as a memory_pressure event, at the critical level old usage and payment history is dropped.
+
+use crate::events::{publish_event, RitaEvent};
+use crate::tunnel_manager::tm_tunnel_count;
+use crate::usage_tracker::{get_usage_history_len, shed_usage_history};
+use settings::network::MemoryMonitorSettings;
+use std::fs;
+use std::sync::{Arc, RwLock};
+
+/// Hours of each usage history kept when shedding, a month is enough for the dashboard graphs
+const SHED_USAGE_HOURS: usize = 24 * 30;
+/// Payments kept when shedding
+const SHED_PAYMENTS: usize = 500;
+
+lazy_static! {
+    static ref MEMORY_STATE: Arc<RwLock<MemoryState>> =
+        Arc::new(RwLock::new(MemoryState::default()));
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    level: MemoryPressureLevel,
+    /// Set by the fast loop, which owns the payment controller and validator
+    payment_queue_len: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryPressureLevel {
+    #[default]
+    Normal,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemorySample {
+    /// Resident memory of this process
+    pub rss_bytes: u64,
+    pub total_bytes: u64,
+    /// MemAvailable, what can be allocated without swapping
+    pub available_bytes: u64,
+    pub tunnels: usize,
+    /// Usage hours and payments in the usage tracker
+    pub usage_history_len: usize,
+    /// Payments queued to send plus transactions held by the payment validator
+    pub payment_queue_len: usize,
+}
+
+/// Called by the fast loop after each payment tick
+pub fn record_payment_queue_len(len: usize) {
+    MEMORY_STATE.write().unwrap().payment_queue_len = len;
+}
+
+/// Finds a line like "VmRSS:     5120 kB" in /proc/self/status or /proc/meminfo and returns it in bytes
+fn parse_kb_field(contents: &str, field: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        if parts.next()? != field {
+            return None;
+        }
+        let kb: u64 = parts.next()?.parse().ok()?;
+        Some(kb * 1024)
+    })
+}
+
+fn sample_memory() -> Option<MemorySample> {
+    let status = match fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(e) => {
+            warn!("Failed to read process status for memory monitor {:?}", e);
+            return None;
+        }
+    };
+    let meminfo = match fs::read_to_string("/proc/meminfo") {
+        Ok(meminfo) => meminfo,
+        Err(e) => {
+            warn!("Failed to read meminfo for memory monitor {:?}", e);
+            return None;
+        }
+    };
+    let total_bytes = parse_kb_field(&meminfo, "MemTotal:")?;
+    Some(MemorySample {
+        rss_bytes: parse_kb_field(&status, "VmRSS:")?,
+        total_bytes,
+        // very old kernels don't report MemAvailable, MemFree is the pessimistic stand in
+        available_bytes: parse_kb_field(&meminfo, "MemAvailable:")
+            .or_else(|| parse_kb_field(&meminfo, "MemFree:"))?,
+        tunnels: tm_tunnel_count(),
+        usage_history_len: get_usage_history_len(),
+        payment_queue_len: MEMORY_STATE.read().unwrap().payment_queue_len,
+    })
+}
+
+fn percent_of(total: u64, percent: u8) -> u64 {
+    total / 100 * percent as u64
+}
+
+pub fn get_pressure_level(
+    sample: &MemorySample,
+    settings: &MemoryMonitorSettings,
+) -> MemoryPressureLevel {
+    let total = sample.total_bytes;
+    if sample.rss_bytes >= percent_of(total, settings.rss_critical_percent)
+        || sample.available_bytes <= percent_of(total, settings.available_critical_percent)
+    {
+        MemoryPressureLevel::Critical
+    } else if sample.rss_bytes >= percent_of(total, settings.rss_warning_percent)
+        || sample.available_bytes <= percent_of(total, settings.available_warning_percent)
+    {
+        MemoryPressureLevel::Warning
+    } else {
+        MemoryPressureLevel::Normal
+    }
+}
+
+/// Run every slow loop tick, logs our memory use and acts on the thresholds in network.memory_monitor
+pub fn check_memory() {
+    let sample = match sample_memory() {
+        Some(sample) => sample,
+        None => return,
+    };
+    let settings = settings::get_rita_common().network.memory_monitor;
+    let level = get_pressure_level(&sample, &settings);
+    info!("Memory {:?} {:?}", level, sample);
+
+    let mut shed = false;
+    if level == MemoryPressureLevel::Critical && settings.shed_history {
+        let removed = shed_usage_history(SHED_USAGE_HOURS, SHED_PAYMENTS);
+        if removed > 0 {
+            error!("Memory is critical, dropped {} history entries", removed);
+            shed = true;
+        }
+    }
+
+    let previous = {
+        let mut state = MEMORY_STATE.write().unwrap();
+        let previous = state.level;
+        state.level = level;
+        previous
+    };
+    if level > previous || shed {
+        warn!(
+            "Memory pressure went from {:?} to {:?} with {:?}",
+            previous, level, sample
+        );
+        publish_event(RitaEvent::MemoryPressure {
+            level,
+            sample,
+            shed,
+        });
+    } else if level < previous {
+        info!("Memory pressure eased from {:?} to {:?}", previous, level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kb_field() {
+        let status = "Name:\trita\nVmPeak:\t   20480 kB\nVmRSS:\t    5120 kB\nThreads:\t12\n";
+        assert_eq!(parse_kb_field(status, "VmRSS:"), Some(5120 * 1024));
+        assert_eq!(parse_kb_field(status, "VmSwap:"), None);
+        assert_eq!(parse_kb_field("VmRSS:\tlots kB\n", "VmRSS:"), None);
+        let meminfo = "MemTotal:          61440 kB\nMemFree:            2048 kB\nMemAvailable:      10240 kB\n";
+        assert_eq!(parse_kb_field(meminfo, "MemTotal:"), Some(61440 * 1024));
+        assert_eq!(parse_kb_field(meminfo, "MemAvailable:"), Some(10240 * 1024));
+    }
+
+    #[test]
+    fn test_pressure_level() {
+        let settings = MemoryMonitorSettings::default();
+        let mb = 1024 * 1024;
+        let mut sample = MemorySample {
+            rss_bytes: 8 * mb,
+            total_bytes: 64 * mb,
+            available_bytes: 30 * mb,
+            ..Default::default()
+        };
+        assert_eq!(
+            get_pressure_level(&sample, &settings),
+            MemoryPressureLevel::Normal
+        );
+        sample.rss_bytes = 20 * mb;
+        assert_eq!(
+            get_pressure_level(&sample, &settings),
+            MemoryPressureLevel::Warning
+        );
+        sample.rss_bytes = 8 * mb;
+        sample.available_bytes = 8 * mb;
+        assert_eq!(
+            get_pressure_level(&sample, &settings),
+            MemoryPressureLevel::Warning
+        );
+        sample.available_bytes = 2 * mb;
+        assert_eq!(
+            get_pressure_level(&sample, &settings),
+            MemoryPressureLevel::Critical
+        );
+        sample.available_bytes = 30 * mb;
+        sample.rss_bytes = 30 * mb;
+        assert_eq!(
+            get_pressure_level(&sample, &settings),
+            MemoryPressureLevel::Critical
+        );
+    }
+}
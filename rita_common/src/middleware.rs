@@ -2,7 +2,7 @@
 //! the client dashboard
 //!
 //! This middleware was setup using the example here: https://actix.rs/docs/middleware/
-//! Two middleware are setup, HttpAuthentication and Header middleware
+//! Three middleware are setup, HttpAuthentication, Header and Csrf middleware
 //! To setup middleware we implement two traits, Service and Transform for the struct in question
 //! The service trait has a fn 'call', which where we are able to take the req, modify it
 //! as necessary and convert it into a response, modify it as necessary and then return that
@@ -12,6 +12,7 @@ use actix_web_async::body::BoxBody;
 use actix_web_async::dev::{Service, Transform};
 use actix_web_async::http::header::{
     Header, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ORIGIN, REFERER, RETRY_AFTER,
 };
 use actix_web_async::http::{header, Method, StatusCode};
 use actix_web_async::HttpResponse;
@@ -24,6 +25,29 @@ use futures::future::{ok, LocalBoxFuture, Ready};
 use futures::FutureExt;
 use regex::Regex;
 
+use crate::login_lockout::{login_locked_out, record_login_failure, record_login_success};
+
+/// Header carrying the csrf token, sent on every dashboard response and required on state
+/// changing requests made from a web page
+pub const CSRF_TOKEN_HEADER: &str = "X-CSRF-Token";
+
+lazy_static! {
+    /// Random per boot token, pages on origins the browser does not let read our responses can
+    /// never learn it
+    static ref CSRF_TOKEN: String = rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+}
+
+/// True if requests from this origin may use the dashboard, either because it is the page being
+/// served from the dashboard's own address or it is in the configured allow list
+fn origin_allowed(origin: &str, host: &str, allowed_origins: &[String]) -> bool {
+    origin == format!("http://{host}")
+        || origin == format!("https://{host}")
+        || allowed_origins.iter().any(|allowed| allowed == origin)
+}
+
 pub struct HeadersMiddlewareFactory;
 
 impl<S, B> Transform<S, ServiceRequest> for HeadersMiddlewareFactory
@@ -60,18 +84,13 @@ where
         let url = conn.host();
         let re = Regex::new(r"^(.*):").unwrap();
         let url_no_port = re.captures(url).unwrap()[1].to_string();
+        let allowed_origins = settings::get_rita_common()
+            .network
+            .dashboard_allowed_origins;
 
-        let origin = match req.headers().clone().get("origin") {
-            Some(origin) => {
-                if origin == "http://althea.net" {
-                    "althea.net".to_string()
-                } else if origin == "http://althearouter.net" {
-                    "althearouter.net".to_string()
-                } else {
-                    url_no_port
-                }
-            }
-            None => url_no_port,
+        let origin = match req.headers().get(ORIGIN).and_then(|o| o.to_str().ok()) {
+            Some(origin) if origin_allowed(origin, url, &allowed_origins) => origin.to_string(),
+            _ => format!("http://{url_no_port}"),
         };
 
         trace!("our req is {:?} and origin is {:?}", req, origin);
@@ -92,7 +111,7 @@ where
                 #[cfg(not(feature = "dash_debug"))]
                 resp.headers_mut().insert(
                     ACCESS_CONTROL_ALLOW_ORIGIN,
-                    header::HeaderValue::from_str(&format!("http://{origin}")).unwrap(),
+                    header::HeaderValue::from_str(&origin).unwrap(),
                 );
                 #[cfg(feature = "dash_debug")]
                 resp.headers_mut().insert(
@@ -102,7 +121,15 @@ where
             }
             resp.headers_mut().insert(
                 ACCESS_CONTROL_ALLOW_HEADERS,
-                header::HeaderValue::from_static("authorization, content-type"),
+                header::HeaderValue::from_static("authorization, content-type, x-csrf-token"),
+            );
+            resp.headers_mut().insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                header::HeaderValue::from_static("x-csrf-token, x-rita-api-version"),
+            );
+            resp.headers_mut().insert(
+                header::HeaderName::from_static("x-csrf-token"),
+                header::HeaderValue::from_str(&CSRF_TOKEN).unwrap(),
             );
 
             Ok(resp)
@@ -111,6 +138,14 @@ where
     }
 }
 
+fn is_public_path(path: &str) -> bool {
+    path == "/exits"
+        || path == "/api/v1/exits"
+        || path == "/api/version"
+        || path == "/ui"
+        || path.starts_with("/ui/")
+}
+
 pub struct AuthMiddlewareFactory;
 
 impl<S> Transform<S, ServiceRequest> for AuthMiddlewareFactory
@@ -149,13 +184,26 @@ where
         let req_path = req.path().to_string();
 
         let auth = Authorization::<Basic>::parse(&req);
+        let peer_ip = req.peer_addr().map(|addr| addr.ip());
+
+        if password.is_some() && !is_public_path(&req_path) {
+            if let Some(wait) = peer_ip.and_then(login_locked_out) {
+                let resp = req.into_response(
+                    HttpResponse::TooManyRequests()
+                        .insert_header((RETRY_AFTER, (wait.as_secs() + 1).to_string()))
+                        .body("Too many failed login attempts"),
+                );
+                return async move { Ok(resp) }.boxed_local();
+            }
+        }
 
         let fut = self.service.call(req);
 
         async move {
             // the /exits path is exempted from authenticaiton so that the
-            // checkup.ash cron script can continue to query it without issue
-            if password.is_none() || req_path == "/exits" {
+            // checkup.ash cron script can continue to query it without issue, the bundled
+            // ui and the api version are public so the ui can load before the user logs in
+            if password.is_none() || is_public_path(&req_path) {
                 let resp = fut.await?;
                 return Ok(resp);
             }
@@ -173,15 +221,17 @@ where
                 }
             };
 
-            let auth_pass = auth.as_ref().password();
             // If the user is authenticated, convert request -> response and return, else return Authenticaiton error
-            if auth.as_ref().user_id() == "rita"
-                && auth_pass.is_some()
-                && auth_pass.unwrap() == password.unwrap()
-            {
+            if credentials_match(&auth, &password.unwrap()) {
+                if let Some(ip) = peer_ip {
+                    record_login_success(ip);
+                }
                 let resp = fut.await?;
                 Ok(resp)
             } else {
+                if let (true, Some(ip)) = (is_login_attempt(&auth), peer_ip) {
+                    record_login_failure(ip);
+                }
                 let config = Config::default();
                 Err(AuthenticationError::from(config.realm("Admin")).into())
             }
@@ -189,3 +239,222 @@ where
         .boxed_local()
     }
 }
+
+/// True if the basic auth credentials are the user rita with the given password
+fn credentials_match(auth: &Authorization<Basic>, password: &str) -> bool {
+    auth.as_ref().user_id() == "rita" && auth.as_ref().password() == Some(password)
+}
+
+/// True if basic auth credentials were actually filled in. Only wrong credentials count towards the
+/// login lockout, not requests without any like the ones browsers make before prompting for a password
+fn is_login_attempt(auth: &Authorization<Basic>) -> bool {
+    !auth.as_ref().user_id().is_empty()
+        || auth
+            .as_ref()
+            .password()
+            .map(|password| !password.is_empty())
+            .unwrap_or(false)
+}
+
+/// Basic auth on every path with a fixed password, unlike AuthMiddlewareFactory there are no public
+/// paths and no way to run without a password. Used for the exit admin api
+pub struct RequiredAuthMiddlewareFactory {
+    pub password: String,
+}
+
+impl<S> Transform<S, ServiceRequest> for RequiredAuthMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequiredAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequiredAuthMiddleware {
+            service,
+            password: self.password.clone(),
+        })
+    }
+}
+
+pub struct RequiredAuthMiddleware<S> {
+    service: S,
+    password: String,
+}
+
+impl<S> Service<ServiceRequest> for RequiredAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let peer_ip = req.peer_addr().map(|addr| addr.ip());
+        if let Some(wait) = peer_ip.and_then(login_locked_out) {
+            let resp = req.into_response(
+                HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, (wait.as_secs() + 1).to_string()))
+                    .body("Too many failed login attempts"),
+            );
+            return async move { Ok(resp) }.boxed_local();
+        }
+
+        // an empty password never matches, the admin api should not be started without one
+        let authorized = match Authorization::<Basic>::parse(&req) {
+            Ok(auth) => {
+                let matches = !self.password.is_empty() && credentials_match(&auth, &self.password);
+                if let (false, true, Some(ip)) = (matches, is_login_attempt(&auth), peer_ip) {
+                    record_login_failure(ip);
+                }
+                matches
+            }
+            Err(_) => false,
+        };
+        if !authorized {
+            let config = Config::default();
+            let err: Error = AuthenticationError::from(config.realm("Admin")).into();
+            return async move { Err(err) }.boxed_local();
+        }
+        if let Some(ip) = peer_ip {
+            record_login_success(ip);
+        }
+
+        self.service.call(req).boxed_local()
+    }
+}
+
+/// Refuses state changing requests made by web pages on other origins. Browsers always send an
+/// origin or referer with a cross site form post or fetch, so requests with neither come from
+/// tools like curl or the ops scripts, which are left alone
+pub struct CsrfMiddlewareFactory;
+
+impl<S> Transform<S, ServiceRequest> for CsrfMiddlewareFactory
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware { service })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: S,
+}
+
+/// Why a request was refused, None if it may proceed
+fn csrf_refusal(req: &ServiceRequest) -> Option<&'static str> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return None;
+    }
+    let network = settings::get_rita_common().network;
+    let headers = req.headers();
+    let origin = headers.get(ORIGIN).map(|o| o.to_str().unwrap_or(""));
+    if let Some(origin) = origin {
+        let conn = req.connection_info().clone();
+        if !origin_allowed(origin, conn.host(), &network.dashboard_allowed_origins) {
+            return Some("Origin not allowed");
+        }
+    }
+    if network.dashboard_csrf_protection && (origin.is_some() || headers.contains_key(REFERER)) {
+        let token = headers
+            .get(CSRF_TOKEN_HEADER)
+            .map(|t| t.as_bytes())
+            .unwrap_or_default();
+        if !constant_time_eq(token, CSRF_TOKEN.as_bytes()) {
+            return Some("Missing or invalid csrf token");
+        }
+    }
+    None
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl<S> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(reason) = csrf_refusal(&req) {
+            warn!("Refused dashboard request to {} {}", req.path(), reason);
+            let resp = req.into_response(HttpResponse::Forbidden().body(reason));
+            return async move { Ok(resp) }.boxed_local();
+        }
+        self.service.call(req).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed = vec!["http://althea.net".to_string()];
+        assert!(origin_allowed(
+            "http://192.168.10.1:4877",
+            "192.168.10.1:4877",
+            &allowed
+        ));
+        assert!(origin_allowed(
+            "http://althea.net",
+            "192.168.10.1:4877",
+            &allowed
+        ));
+        assert!(!origin_allowed(
+            "http://192.168.10.1",
+            "192.168.10.1:4877",
+            &allowed
+        ));
+        assert!(!origin_allowed(
+            "http://evil.example",
+            "192.168.10.1:4877",
+            &allowed
+        ));
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn test_is_login_attempt() {
+        let attempt = Authorization::from(Basic::new("rita", Some("guess")));
+        assert!(is_login_attempt(&attempt));
+        assert!(!credentials_match(&attempt, "password"));
+        let empty = Authorization::from(Basic::new("", None::<&str>));
+        assert!(!is_login_attempt(&empty));
+        let empty_password = Authorization::from(Basic::new("", Some("")));
+        assert!(!is_login_attempt(&empty_password));
+    }
+
+    #[test]
+    fn test_credentials_match() {
+        let auth = |user: &'static str, pass: Option<&'static str>| {
+            Authorization::from(Basic::new(user, pass))
+        };
+        assert!(credentials_match(&auth("rita", Some("secret")), "secret"));
+        assert!(!credentials_match(&auth("rita", Some("wrong")), "secret"));
+        assert!(!credentials_match(&auth("admin", Some("secret")), "secret"));
+        assert!(!credentials_match(&auth("rita", None), "secret"));
+    }
+}
@@ -1,7 +1,10 @@
 //! Network endptoints for common Rita functionality (such as exchanging hello messages)
 
+use crate::extensions::{local_extensions, record_neighbor_extensions};
 use crate::payment_validator::{add_to_incoming_transaction_queue, ToValidate};
 use crate::peer_listener::structs::Peer;
+use crate::reconciliation::handle_summary;
+use crate::reconciliation::receipts::handle_receipt;
 use crate::tm_identity_callback;
 use crate::tunnel_manager::id_callback::IdentityCallback;
 
@@ -9,7 +12,9 @@ use actix_web_async::http::StatusCode;
 use actix_web_async::web::Json;
 
 use actix_web_async::{HttpRequest, HttpResponse};
-use althea_types::{LocalIdentity, PaymentTx};
+use althea_types::{
+    ExtendedLocalIdentity, LocalIdentity, PaymentTx, SignedDebtSummary, SignedPaymentReceipt,
+};
 use std::collections::HashSet;
 use std::time::Instant;
 
@@ -21,6 +26,7 @@ pub async fn make_payments(item: Json<PaymentTx>) -> HttpResponse {
         payment: pmt,
         received: Instant::now(),
         timeout_block: None,
+        replaces: Vec::new(),
     };
     add_to_incoming_transaction_queue(ts);
 
@@ -38,6 +44,7 @@ pub async fn make_payments_v2(item: Json<HashSet<PaymentTx>>) -> HttpResponse {
             payment: pmt,
             received: Instant::now(),
             timeout_block: None,
+            replaces: Vec::new(),
         };
         add_to_incoming_transaction_queue(ts);
     }
@@ -48,9 +55,34 @@ pub async fn make_payments_v2(item: Json<HashSet<PaymentTx>>) -> HttpResponse {
     HttpResponse::Ok().json("Payment Received!")
 }
 
-pub async fn hello_response(item: Json<LocalIdentity>, req: HttpRequest) -> HttpResponse {
+/// A neighbor's signed debt summary, answered with ours so both sides can compare books
+pub async fn reconcile_debts(item: Json<SignedDebtSummary>) -> HttpResponse {
+    match handle_summary(item.into_inner()) {
+        Ok(ours) => HttpResponse::Ok().json(ours),
+        Err(e) => {
+            warn!("Refused debt summary {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// A neighbor's receipt for a payment we made them, kept for reconciliation
+pub async fn receive_payment_receipt(item: Json<SignedPaymentReceipt>) -> HttpResponse {
+    match handle_receipt(item.into_inner()) {
+        Ok(()) => HttpResponse::Ok().json("Receipt stored"),
+        Err(e) => {
+            warn!("Refused payment receipt {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+pub async fn hello_response(item: Json<ExtendedLocalIdentity>, req: HttpRequest) -> HttpResponse {
     trace!("In Hello response handler!!");
-    let their_id = item.into_inner();
+    let ExtendedLocalIdentity {
+        identity: their_id,
+        extensions,
+    } = item.into_inner();
 
     let err_mesg = "Malformed hello tcp packet!";
     let socket = match req.peer_addr() {
@@ -66,6 +98,7 @@ pub async fn hello_response(item: Json<LocalIdentity>, req: HttpRequest) -> Http
         ifidx: 0, // only works because we lookup ifname in kernel interface
     };
 
+    record_neighbor_extensions(their_id.global.wg_public_key, extensions);
     let tunnel = tm_identity_callback(IdentityCallback::new(their_id, peer, None));
     let tunnel = match tunnel {
         Ok(val) => val,
@@ -75,16 +108,19 @@ pub async fn hello_response(item: Json<LocalIdentity>, req: HttpRequest) -> Http
         }
     };
 
-    HttpResponse::Ok().json(LocalIdentity {
-        global: match settings::get_rita_common().get_identity() {
-            Some(id) => id,
-            None => {
-                return HttpResponse::build(StatusCode::from_u16(400u16).unwrap())
-                    .json("Identity has no mesh ip ready")
-            }
+    HttpResponse::Ok().json(ExtendedLocalIdentity {
+        identity: LocalIdentity {
+            global: match settings::get_rita_common().get_identity() {
+                Some(id) => id,
+                None => {
+                    return HttpResponse::build(StatusCode::from_u16(400u16).unwrap())
+                        .json("Identity has no mesh ip ready")
+                }
+            },
+            wg_port: tunnel.0.listen_port,
+            have_tunnel: Some(tunnel.1),
         },
-        wg_port: tunnel.0.listen_port,
-        have_tunnel: Some(tunnel.1),
+        extensions: local_extensions(),
     })
 }
 
@@ -8,6 +8,11 @@
 //! as a bird flying through the connection rather than actual bloat. The solution here would be to also collect stats
 //! on traffic over every interface and base our action off of spikes in throughput as well as spikes in latency.
 
+pub mod route_flaps;
+pub mod stability;
+
+use self::route_flaps::{NeighborFlaps, RouteFlapTracker};
+use self::stability::{PenaltyStatus, StabilityState};
 use crate::rita_loop::fast_loop::FAST_LOOP_SPEED;
 use crate::set_to_shape;
 use crate::tunnel_manager::shaping::ShapingAdjust;
@@ -20,13 +25,17 @@ use althea_types::monitoring::SAMPLE_PERIOD;
 use althea_types::RunningLatencyStats;
 use althea_types::RunningPacketLossStats;
 use althea_types::WgKey;
+use babel_monitor::set_interface_rxcost;
 use babel_monitor::structs::Neighbor as BabelNeighbor;
 use babel_monitor::structs::Route as BabelRoute;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 
 lazy_static! {
     static ref NETWORK_MONITOR: Arc<RwLock<NetworkMonitor>> =
@@ -45,6 +54,8 @@ pub struct NetworkMonitor {
     latency_history: HashMap<String, RunningLatencyStats>,
     packet_loss_history: HashMap<String, RunningPacketLossStats>,
     last_babel_dump: Option<NetworkInfo>,
+    route_flaps: RouteFlapTracker,
+    stability: StabilityState,
 }
 
 impl NetworkMonitor {
@@ -53,6 +64,8 @@ impl NetworkMonitor {
             latency_history: HashMap::new(),
             packet_loss_history: HashMap::new(),
             last_babel_dump: None,
+            route_flaps: RouteFlapTracker::default(),
+            stability: StabilityState::default(),
         }
     }
 }
@@ -142,9 +155,62 @@ pub fn update_network_info(msg: NetworkInfo) {
         &mut network_monitor.packet_loss_history,
     );
     network_stats(babel_routes, babel_neighbors);
+    let tunnels: Vec<(String, IpAddr)> = rita_neighbors
+        .iter()
+        .map(|n| (n.iface_name.clone(), n.identity.global.mesh_ip))
+        .collect();
+    network_monitor
+        .route_flaps
+        .observe(babel_routes, &tunnels, SystemTime::now());
     network_monitor.last_babel_dump = Some(msg);
 }
 
+/// Installed route adds and retractions through the neighbor with the given mesh ip over the last day
+pub fn get_neighbor_flaps(neighbor: IpAddr) -> NeighborFlaps {
+    NETWORK_MONITOR.read().unwrap().route_flaps.flaps(neighbor)
+}
+
+/// Updates stability penalties from the route flap history and sets any changed tunnel costs in babel,
+/// does nothing but restore previously penalized tunnels if no stability policy is configured
+pub fn apply_stability_penalties(stream: &mut TcpStream) {
+    let policy = settings::get_rita_common().network.stability_policy;
+    let updates = {
+        let network_monitor = &mut *(NETWORK_MONITOR.write().unwrap());
+        let tunnels: Vec<(String, IpAddr)> = match network_monitor.last_babel_dump {
+            Some(ref dump) => dump
+                .rita_neighbors
+                .iter()
+                .map(|n| (n.iface_name.clone(), n.identity.global.mesh_ip))
+                .collect(),
+            None => return,
+        };
+        network_monitor.stability.tick(
+            policy.as_ref(),
+            &network_monitor.route_flaps,
+            &tunnels,
+            SystemTime::now(),
+        )
+    };
+    for (iface, rxcost) in updates {
+        info!("Setting rxcost {} on {} for route stability", rxcost, iface);
+        if let Err(e) = set_interface_rxcost(stream, &iface, rxcost) {
+            error!("Failed to set rxcost on {} {:?}", iface, e);
+            NETWORK_MONITOR.write().unwrap().stability.forget(&iface);
+        }
+    }
+}
+
+/// Neighbors currently penalized for unstable routes
+pub fn get_stability_penalties() -> Vec<PenaltyStatus> {
+    let policy = settings::get_rita_common().network.stability_policy;
+    let network_monitor = NETWORK_MONITOR.read().unwrap();
+    network_monitor.stability.penalties(
+        policy.as_ref(),
+        &network_monitor.route_flaps,
+        SystemTime::now(),
+    )
+}
+
 /// Attempts to detect bufferbloat by looking at neighbor latency over time
 fn observe_network(
     babel_neighbors: &[BabelNeighbor],
@@ -0,0 +1,201 @@
+//! Tracks installed route churn per neighbor. Radios that drop out intermittently cause babel to retract and
+//! re-add routes through them, by the time an operator looks at the router the routes are back and nothing
+//! looks wrong. Each babel dump is compared with the previous one and every installed route that appears or
+//! disappears is recorded against the neighbor it goes through, keeping a day of history per neighbor.
+
+use babel_monitor::structs::Route as BabelRoute;
+use ipnetwork::IpNetwork;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+/// How long route events are kept
+pub const FLAP_HISTORY: Duration = Duration::from_secs(86400);
+/// Bound on stored events per neighbor, a flapping neighbor with many routes behind it can
+/// produce hundreds of events each time it drops
+const MAX_EVENTS_PER_NEIGHBOR: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteEventKind {
+    Added,
+    Retracted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RouteEvent {
+    pub time: SystemTime,
+    pub prefix: IpNetwork,
+    pub kind: RouteEventKind,
+}
+
+/// Route churn through a single neighbor over the tracked history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NeighborFlaps {
+    /// Mesh ip of the neighbor
+    pub neighbor: IpAddr,
+    pub added: usize,
+    pub retracted: usize,
+    /// Oldest first
+    pub events: Vec<RouteEvent>,
+}
+
+#[derive(Clone, Default)]
+pub struct RouteFlapTracker {
+    /// Installed routes in the last dump and the mesh ip of the neighbor each went through
+    installed: HashMap<IpNetwork, IpAddr>,
+    events: HashMap<IpAddr, VecDeque<RouteEvent>>,
+    /// The first dump only populates the installed set, it does not count as routes being added
+    initialized: bool,
+}
+
+impl RouteFlapTracker {
+    /// Compares a new babel route dump against the previous one. tunnels maps tunnel interface names
+    /// to the mesh ip of the neighbor on the other end, routes over other interfaces are ignored
+    pub fn observe(
+        &mut self,
+        routes: &[BabelRoute],
+        tunnels: &[(String, IpAddr)],
+        now: SystemTime,
+    ) {
+        let mut installed = HashMap::new();
+        for route in routes.iter().filter(|r| r.installed) {
+            if let Some((_, neighbor)) = tunnels.iter().find(|(iface, _)| *iface == route.iface) {
+                installed.insert(route.prefix, *neighbor);
+            }
+        }
+
+        if self.initialized {
+            for (prefix, neighbor) in self.installed.iter() {
+                if installed.get(prefix) != Some(neighbor) {
+                    self.record(*neighbor, *prefix, RouteEventKind::Retracted, now);
+                }
+            }
+            for (prefix, neighbor) in installed.iter() {
+                if self.installed.get(prefix) != Some(neighbor) {
+                    self.record(*neighbor, *prefix, RouteEventKind::Added, now);
+                }
+            }
+        }
+        self.installed = installed;
+        self.initialized = true;
+        self.prune(now);
+    }
+
+    fn record(
+        &mut self,
+        neighbor: IpAddr,
+        prefix: IpNetwork,
+        kind: RouteEventKind,
+        time: SystemTime,
+    ) {
+        let events = self.events.entry(neighbor).or_default();
+        if events.len() >= MAX_EVENTS_PER_NEIGHBOR {
+            events.pop_front();
+        }
+        events.push_back(RouteEvent { time, prefix, kind });
+    }
+
+    fn prune(&mut self, now: SystemTime) {
+        let is_old = |event: &RouteEvent| {
+            now.duration_since(event.time)
+                .map(|age| age > FLAP_HISTORY)
+                .unwrap_or(false)
+        };
+        for events in self.events.values_mut() {
+            while events.front().map(is_old).unwrap_or(false) {
+                events.pop_front();
+            }
+        }
+        self.events.retain(|_, events| !events.is_empty());
+    }
+
+    /// Number of route events through the neighbor at or after the given time
+    pub fn count_since(&self, neighbor: IpAddr, since: SystemTime) -> usize {
+        self.events
+            .get(&neighbor)
+            .map(|events| events.iter().filter(|e| e.time >= since).count())
+            .unwrap_or(0)
+    }
+
+    pub fn flaps(&self, neighbor: IpAddr) -> NeighborFlaps {
+        let events: Vec<RouteEvent> = self
+            .events
+            .get(&neighbor)
+            .map(|events| events.iter().cloned().collect())
+            .unwrap_or_default();
+        NeighborFlaps {
+            neighbor,
+            added: events
+                .iter()
+                .filter(|e| e.kind == RouteEventKind::Added)
+                .count(),
+            retracted: events
+                .iter()
+                .filter(|e| e.kind == RouteEventKind::Retracted)
+                .count(),
+            events,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, iface: &str) -> BabelRoute {
+        BabelRoute {
+            id: "route".to_string(),
+            iface: iface.to_string(),
+            xroute: false,
+            installed: true,
+            neigh_ip: "fe80::1".parse().unwrap(),
+            prefix: prefix.parse().unwrap(),
+            metric: 96,
+            refmetric: 0,
+            full_path_rtt: 10.0,
+            price: 0,
+            fee: 0,
+            src_prefix: None,
+        }
+    }
+
+    #[test]
+    fn test_route_flaps() {
+        let a: IpAddr = "fd00::a".parse().unwrap();
+        let b: IpAddr = "fd00::b".parse().unwrap();
+        let tunnels = vec![("wg0".to_string(), a), ("wg1".to_string(), b)];
+        let start = SystemTime::now();
+        let mut tracker = RouteFlapTracker::default();
+
+        let stable = vec![route("fd00::a/128", "wg0"), route("fd00::c/128", "wg0")];
+        tracker.observe(&stable, &tunnels, start);
+        assert_eq!(tracker.flaps(a).events.len(), 0);
+
+        // fd00::c moves to b then comes back
+        let moved = vec![route("fd00::a/128", "wg0"), route("fd00::c/128", "wg1")];
+        tracker.observe(&moved, &tunnels, start + Duration::from_secs(5));
+        tracker.observe(&stable, &tunnels, start + Duration::from_secs(10));
+
+        let flaps = tracker.flaps(a);
+        assert_eq!((flaps.added, flaps.retracted), (1, 1));
+        assert_eq!(flaps.events[0].kind, RouteEventKind::Retracted);
+        let flaps = tracker.flaps(b);
+        assert_eq!((flaps.added, flaps.retracted), (1, 1));
+        assert_eq!(flaps.events[0].kind, RouteEventKind::Added);
+
+        // a route moving off our tunnels counts as retracted from the neighbor it was through
+        let with_other = vec![route("fd00::a/128", "wg0"), route("fd00::c/128", "eth0")];
+        tracker.observe(&with_other, &tunnels, start + Duration::from_secs(15));
+        assert_eq!(tracker.flaps(a).retracted, 2);
+        assert_eq!(tracker.flaps(a).added, 1);
+
+        // a day later the history has aged out
+        tracker.observe(
+            &with_other,
+            &tunnels,
+            start + FLAP_HISTORY + Duration::from_secs(60),
+        );
+        assert_eq!(tracker.flaps(a).events.len(), 0);
+        assert_eq!(tracker.flaps(b).events.len(), 0);
+    }
+}
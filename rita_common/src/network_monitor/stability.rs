@@ -0,0 +1,331 @@
+//! Reacts to the route flaps recorded by route_flaps according to the optional StabilityPolicy. A neighbor
+//! that flaps more than the policy allows has the babel rxcost of its tunnels raised, so babel prefers other
+//! paths while one exists. The penalty is held at full strength while the neighbor keeps flapping and then
+//! decays, halving once per half life, until it is small enough to remove.
+//!
+//! Every rxcost change makes babel reroute, which route_flaps records as flaps like any other. So the cost
+//! only steps down once per half life, and route events in the settle time after we change any rxcost
+//! don't count towards the threshold, otherwise a penalty or its decay could trigger the next penalty.
+
+use super::route_flaps::RouteFlapTracker;
+use settings::network::StabilityPolicy;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+/// Penalties that have decayed below this are removed
+const MIN_PENALTY: u16 = 8;
+/// How long babel gets to reroute after we change an rxcost, route events in this time are our own doing
+const SETTLE_TIME: Duration = Duration::from_secs(60);
+
+/// A penalty currently applied to a neighbor
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PenaltyStatus {
+    pub neighbor: IpAddr,
+    /// When the neighbor was last seen over the flap threshold, decay starts from here
+    pub last_triggered: SystemTime,
+    /// rxcost currently added to the neighbor's tunnels
+    pub current_penalty: u16,
+    /// Route events through the neighbor within the policy window
+    pub recent_flaps: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct StabilityState {
+    /// Neighbor mesh ip to when it last exceeded the flap threshold
+    triggered: HashMap<IpAddr, SystemTime>,
+    /// Tunnel interface to the rxcost we last set on it, interfaces we never touched are absent
+    applied: HashMap<String, u16>,
+    /// Base rxcost of the last policy seen, used to restore tunnels if the policy is removed
+    last_base_rxcost: Option<u16>,
+    /// When we last changed an rxcost
+    last_change: Option<SystemTime>,
+}
+
+/// The penalty left after decaying for elapsed, halved once for every full half life so that the
+/// rxcost only changes once per half life
+pub fn decayed_penalty(initial: u16, half_life: Duration, elapsed: Duration) -> u16 {
+    let half_lives = elapsed.as_secs() / half_life.as_secs().max(1);
+    u32::try_from(half_lives)
+        .ok()
+        .and_then(|half_lives| initial.checked_shr(half_lives))
+        .unwrap_or(0)
+}
+
+impl StabilityState {
+    fn current_penalty(&self, policy: &StabilityPolicy, neighbor: IpAddr, now: SystemTime) -> u16 {
+        match self.triggered.get(&neighbor) {
+            Some(triggered) => {
+                let elapsed = now.duration_since(*triggered).unwrap_or_default();
+                decayed_penalty(
+                    policy.penalty,
+                    Duration::from_secs(policy.half_life_secs),
+                    elapsed,
+                )
+            }
+            None => 0,
+        }
+    }
+
+    /// Updates penalties from the flap history and returns the (interface, rxcost) pairs that need to
+    /// be set in babel. tunnels maps our tunnel interfaces to the mesh ip of the neighbor on each
+    pub fn tick(
+        &mut self,
+        policy: Option<&StabilityPolicy>,
+        flaps: &RouteFlapTracker,
+        tunnels: &[(String, IpAddr)],
+        now: SystemTime,
+    ) -> Vec<(String, u16)> {
+        let policy = match policy {
+            Some(policy) => policy,
+            None => {
+                // the policy was removed, put every tunnel we changed back how we found it
+                self.triggered.clear();
+                let base = match self.last_base_rxcost {
+                    Some(base) => base,
+                    None => return Vec::new(),
+                };
+                return self
+                    .applied
+                    .drain()
+                    .filter(|(_, rxcost)| *rxcost != base)
+                    .map(|(iface, _)| (iface, base))
+                    .collect();
+            }
+        };
+        self.last_base_rxcost = Some(policy.base_rxcost);
+
+        let mut window_start = now
+            .checked_sub(Duration::from_secs(policy.window_secs))
+            .unwrap_or(now);
+        if let Some(settled) = self.last_change.map(|change| change + SETTLE_TIME) {
+            window_start = window_start.max(settled);
+        }
+        for (_, neighbor) in tunnels {
+            if flaps.count_since(*neighbor, window_start) >= policy.flap_threshold {
+                self.triggered.insert(*neighbor, now);
+            }
+        }
+        let expired: Vec<IpAddr> = self
+            .triggered
+            .keys()
+            .filter(|n| self.current_penalty(policy, **n, now) < MIN_PENALTY)
+            .copied()
+            .collect();
+        for neighbor in expired {
+            self.triggered.remove(&neighbor);
+        }
+
+        let mut updates = Vec::new();
+        for (iface, neighbor) in tunnels {
+            let rxcost = policy
+                .base_rxcost
+                .saturating_add(self.current_penalty(policy, *neighbor, now));
+            let unchanged = match self.applied.get(iface) {
+                Some(applied) => *applied == rxcost,
+                None => rxcost == policy.base_rxcost,
+            };
+            if !unchanged {
+                self.applied.insert(iface.clone(), rxcost);
+                updates.push((iface.clone(), rxcost));
+            }
+        }
+        self.applied
+            .retain(|iface, _| tunnels.iter().any(|(i, _)| i == iface));
+        if !updates.is_empty() {
+            self.last_change = Some(now);
+        }
+        updates
+    }
+
+    /// Forgets what we set on an interface, used when setting it in babel failed so it is retried
+    pub fn forget(&mut self, iface: &str) {
+        self.applied.remove(iface);
+    }
+
+    pub fn penalties(
+        &self,
+        policy: Option<&StabilityPolicy>,
+        flaps: &RouteFlapTracker,
+        now: SystemTime,
+    ) -> Vec<PenaltyStatus> {
+        let policy = match policy {
+            Some(policy) => policy,
+            None => return Vec::new(),
+        };
+        let window_start = now
+            .checked_sub(Duration::from_secs(policy.window_secs))
+            .unwrap_or(now);
+        self.triggered
+            .iter()
+            .map(|(neighbor, triggered)| PenaltyStatus {
+                neighbor: *neighbor,
+                last_triggered: *triggered,
+                current_penalty: self.current_penalty(policy, *neighbor, now),
+                recent_flaps: flaps.count_since(*neighbor, window_start),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use babel_monitor::structs::Route as BabelRoute;
+
+    fn route(prefix: &str, iface: &str) -> BabelRoute {
+        BabelRoute {
+            id: "route".to_string(),
+            iface: iface.to_string(),
+            xroute: false,
+            installed: true,
+            neigh_ip: "fe80::1".parse().unwrap(),
+            prefix: prefix.parse().unwrap(),
+            metric: 96,
+            refmetric: 0,
+            full_path_rtt: 10.0,
+            price: 0,
+            fee: 0,
+            src_prefix: None,
+        }
+    }
+
+    #[test]
+    fn test_decayed_penalty() {
+        let half_life = Duration::from_secs(600);
+        assert_eq!(decayed_penalty(512, half_life, Duration::ZERO), 512);
+        assert_eq!(decayed_penalty(512, half_life, half_life), 256);
+        assert_eq!(decayed_penalty(512, half_life, half_life * 2), 128);
+        assert_eq!(decayed_penalty(512, half_life, half_life * 7), 4);
+        // the penalty only steps down at whole half lives
+        assert_eq!(decayed_penalty(512, half_life, half_life * 3 / 2), 256);
+        assert_eq!(decayed_penalty(512, half_life, half_life * 100), 0);
+    }
+
+    #[test]
+    fn test_stability_penalty_lifecycle() {
+        let policy = StabilityPolicy {
+            flap_threshold: 2,
+            window_secs: 300,
+            penalty: 512,
+            half_life_secs: 600,
+            base_rxcost: 256,
+        };
+        let a: IpAddr = "fd00::a".parse().unwrap();
+        let b: IpAddr = "fd00::b".parse().unwrap();
+        let tunnels = vec![("wg0".to_string(), a), ("wg1".to_string(), b)];
+        let start = SystemTime::now();
+        let mut flaps = RouteFlapTracker::default();
+        let mut state = StabilityState::default();
+
+        // a route that bounces between the two neighbors, two events each per bounce
+        let via_a = vec![route("fd00::c/128", "wg0")];
+        let via_b = vec![route("fd00::c/128", "wg1")];
+        flaps.observe(&via_a, &tunnels, start);
+        assert!(state
+            .tick(Some(&policy), &flaps, &tunnels, start)
+            .is_empty());
+        flaps.observe(&via_b, &tunnels, start + Duration::from_secs(5));
+        flaps.observe(&via_a, &tunnels, start + Duration::from_secs(10));
+        let now = start + Duration::from_secs(10);
+        let mut updates = state.tick(Some(&policy), &flaps, &tunnels, now);
+        updates.sort();
+        assert_eq!(
+            updates,
+            vec![("wg0".to_string(), 768), ("wg1".to_string(), 768)]
+        );
+        assert!(state.tick(Some(&policy), &flaps, &tunnels, now).is_empty());
+        assert_eq!(state.penalties(Some(&policy), &flaps, now).len(), 2);
+
+        // one half life after the flapping stops the penalty has halved
+        let later = now + Duration::from_secs(600);
+        let updates = state.tick(Some(&policy), &flaps, &tunnels, later);
+        assert_eq!(updates.len(), 2);
+        assert!(updates.iter().all(|(_, rxcost)| *rxcost == 512));
+
+        // and eventually it is removed entirely
+        let much_later = now + Duration::from_secs(600 * 8);
+        let updates = state.tick(Some(&policy), &flaps, &tunnels, much_later);
+        assert!(updates.iter().all(|(_, rxcost)| *rxcost == 256));
+        assert!(state
+            .penalties(Some(&policy), &flaps, much_later)
+            .is_empty());
+
+        // removing the policy restores anything still penalized
+        let flapping = much_later + SETTLE_TIME + Duration::from_secs(10);
+        flaps.observe(&via_b, &tunnels, flapping);
+        flaps.observe(&via_a, &tunnels, flapping);
+        state.tick(Some(&policy), &flaps, &tunnels, flapping);
+        let mut updates = state.tick(None, &flaps, &tunnels, flapping);
+        updates.sort();
+        assert_eq!(
+            updates,
+            vec![("wg0".to_string(), 256), ("wg1".to_string(), 256)]
+        );
+    }
+
+    #[test]
+    fn test_stability_penalty_settles() {
+        let policy = StabilityPolicy {
+            flap_threshold: 2,
+            window_secs: 300,
+            penalty: 512,
+            half_life_secs: 600,
+            base_rxcost: 256,
+        };
+        let a: IpAddr = "fd00::a".parse().unwrap();
+        let b: IpAddr = "fd00::b".parse().unwrap();
+        let tunnels = vec![("wg0".to_string(), a), ("wg1".to_string(), b)];
+        let start = SystemTime::now();
+        let mut flaps = RouteFlapTracker::default();
+        let mut state = StabilityState::default();
+
+        // a drops out taking fd00::c with it, fd00::c goes through a unless a is penalized
+        let via_a = vec![route("fd00::a/128", "wg0"), route("fd00::c/128", "wg0")];
+        let via_b = vec![route("fd00::a/128", "wg0"), route("fd00::c/128", "wg1")];
+        let a_down = Vec::new();
+        flaps.observe(&via_a, &tunnels, start);
+        flaps.observe(&a_down, &tunnels, start + Duration::from_secs(5));
+        flaps.observe(&via_a, &tunnels, start + Duration::from_secs(10));
+
+        // tick every 10 seconds for three hours, babel reroutes 5 seconds after every rxcost change
+        let mut wg0 = policy.base_rxcost;
+        let mut changes = Vec::new();
+        let mut reroute = None;
+        for tick in 2..(3 * 60 * 6) {
+            let now = start + Duration::from_secs(tick * 10);
+            if let Some(at) = reroute {
+                if now >= at {
+                    let routes = if wg0 > policy.base_rxcost {
+                        &via_b
+                    } else {
+                        &via_a
+                    };
+                    flaps.observe(routes, &tunnels, at);
+                    reroute = None;
+                }
+            }
+            for (iface, rxcost) in state.tick(Some(&policy), &flaps, &tunnels, now) {
+                // b is only ever penalized by flaps we caused
+                assert_eq!(iface, "wg0");
+                wg0 = rxcost;
+                changes.push((now, rxcost));
+                reroute = Some(now + Duration::from_secs(5));
+            }
+        }
+
+        // applied once, then stepped down once per half life until it is removed
+        let costs: Vec<u16> = changes.iter().map(|(_, rxcost)| *rxcost).collect();
+        assert_eq!(costs, vec![768, 512, 384, 320, 288, 272, 264, 256]);
+        for pair in changes.windows(2) {
+            assert_eq!(
+                pair[1].0.duration_since(pair[0].0).unwrap(),
+                Duration::from_secs(600)
+            );
+        }
+        assert_eq!(wg0, policy.base_rxcost);
+        assert!(state
+            .penalties(Some(&policy), &flaps, start + Duration::from_secs(3 * 3600))
+            .is_empty());
+    }
+}
@@ -0,0 +1,498 @@
+//! An inbox of user facing notices for the dashboard, so that things like a failed payment or a low
+//! balance can still be shown to the user after the fact. Every event published on the event bus is
+//! turned into a notification by the delivery thread unless it is turned off in network.events.enabled,
+//! other subsystems can add their own with add_notification(). The inbox keeps the last
+//! MAX_NOTIFICATIONS, dropping read ones first, and is saved next to usage_tracker_file no more often
+//! than the usage tracker writes on the same storage, except that critical notices are saved right away.
+//! Notifications that are only true for a while, like an operator's maintenance notice, can be given an
+//! expiry after which they are dropped whether or not they were read.
+
+use crate::events::RitaEvent;
+use crate::memory_monitor::MemoryPressureLevel;
+use crate::usage_tracker::get_usage_storage_type;
+use crate::usage_tracker::segments::WearPolicy;
+use crate::RitaCommonError;
+use althea_types::unix_time::now_secs;
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+pub const MAX_NOTIFICATIONS: usize = 100;
+
+struct NotificationsWrapper {
+    store: NotificationStore,
+    policy: WearPolicy,
+    last_write: Instant,
+    dirty: bool,
+}
+
+impl NotificationsWrapper {
+    fn new(path: &str) -> NotificationsWrapper {
+        NotificationsWrapper {
+            store: NotificationStore::load(path),
+            policy: WearPolicy::new(
+                get_usage_storage_type(),
+                settings::get_rita_common()
+                    .network
+                    .usage_tracker_write_interval,
+            ),
+            last_write: Instant::now(),
+            dirty: false,
+        }
+    }
+
+    fn flush(&mut self, path: &str, force: bool) {
+        if !self.dirty || (!force && self.last_write.elapsed() < self.policy.write_interval) {
+            return;
+        }
+        self.last_write = Instant::now();
+        match self.store.save(path) {
+            Ok(()) => self.dirty = false,
+            Err(e) => warn!("Unable to save notifications {:?}", e),
+        }
+    }
+}
+
+lazy_static! {
+    /// The inbox, None until loaded from disk
+    static ref NOTIFICATIONS: Arc<RwLock<Option<NotificationsWrapper>>> =
+        Arc::new(RwLock::new(None));
+}
+
+/// Ordered from least to most severe
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub id: u64,
+    pub severity: NotificationSeverity,
+    /// What the notification is about, the event name for notifications from the event bus
+    pub kind: String,
+    pub message: String,
+    /// Unix time in seconds
+    pub timestamp: u64,
+    pub read: bool,
+    /// Unix time in seconds after which the notification is dropped
+    pub expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NotificationStore {
+    next_id: u64,
+    /// Oldest first
+    notifications: VecDeque<Notification>,
+}
+
+/// Notifications as saved before they could expire, to be removed once all routers have saved
+/// their inbox in the current format
+#[derive(Deserialize)]
+struct LegacyNotification {
+    id: u64,
+    severity: NotificationSeverity,
+    kind: String,
+    message: String,
+    timestamp: u64,
+    read: bool,
+}
+
+#[derive(Deserialize)]
+struct LegacyNotificationStore {
+    next_id: u64,
+    notifications: VecDeque<LegacyNotification>,
+}
+
+impl From<LegacyNotificationStore> for NotificationStore {
+    fn from(legacy: LegacyNotificationStore) -> Self {
+        NotificationStore {
+            next_id: legacy.next_id,
+            notifications: legacy
+                .notifications
+                .into_iter()
+                .map(|n| Notification {
+                    id: n.id,
+                    severity: n.severity,
+                    kind: n.kind,
+                    message: n.message,
+                    timestamp: n.timestamp,
+                    read: n.read,
+                    expires: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl NotificationStore {
+    /// Adds a notification and returns its id, dropping the oldest read notification or failing
+    /// that the oldest one if the inbox is full
+    pub fn add(
+        &mut self,
+        severity: NotificationSeverity,
+        kind: String,
+        message: String,
+        timestamp: u64,
+    ) -> u64 {
+        self.add_expiring(severity, kind, message, timestamp, None)
+    }
+
+    /// Same as add() for a notification that is dropped once expires has passed
+    pub fn add_expiring(
+        &mut self,
+        severity: NotificationSeverity,
+        kind: String,
+        message: String,
+        timestamp: u64,
+        expires: Option<u64>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.notifications.push_back(Notification {
+            id,
+            severity,
+            kind,
+            message,
+            timestamp,
+            read: false,
+            expires,
+        });
+        while self.notifications.len() > MAX_NOTIFICATIONS {
+            match self.notifications.iter().position(|n| n.read) {
+                Some(index) => self.notifications.remove(index),
+                None => self.notifications.pop_front(),
+            };
+        }
+        id
+    }
+
+    /// Returns false if there is no notification with this id
+    pub fn mark_read(&mut self, id: u64) -> bool {
+        match self.notifications.iter_mut().find(|n| n.id == id) {
+            Some(notification) => {
+                notification.read = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns how many notifications were unread
+    pub fn mark_all_read(&mut self) -> usize {
+        let mut count = 0;
+        for notification in self.notifications.iter_mut().filter(|n| !n.read) {
+            notification.read = true;
+            count += 1;
+        }
+        count
+    }
+
+    /// Drops notifications that have expired by now, returns false if there were none
+    pub fn drop_expired(&mut self, now: u64) -> bool {
+        let before = self.notifications.len();
+        self.notifications
+            .retain(|n| n.expires.map(|expires| expires > now).unwrap_or(true));
+        self.notifications.len() != before
+    }
+
+    pub fn unread(&self) -> usize {
+        self.notifications.iter().filter(|n| !n.read).count()
+    }
+
+    /// Newest first
+    pub fn list(&self, unread_only: bool) -> Vec<Notification> {
+        self.notifications
+            .iter()
+            .rev()
+            .filter(|n| !unread_only || !n.read)
+            .cloned()
+            .collect()
+    }
+
+    fn load(path: &str) -> NotificationStore {
+        match fs::read(path) {
+            Ok(bytes) => match bincode::deserialize(&bytes) {
+                Ok(store) => store,
+                Err(e) => match bincode::deserialize::<LegacyNotificationStore>(&bytes) {
+                    Ok(legacy) => legacy.into(),
+                    Err(_) => {
+                        error!("Failed to deserialize notifications {:?}", e);
+                        NotificationStore::default()
+                    }
+                },
+            },
+            Err(e) => {
+                info!("No notifications loaded {:?}", e);
+                NotificationStore::default()
+            }
+        }
+    }
+
+    /// Writes through a temporary file so that a crash can't leave a half written inbox behind
+    fn save(&self, path: &str) -> Result<(), RitaCommonError> {
+        let tmp_path = format!("{path}.tmp");
+        fs::write(&tmp_path, bincode::serialize(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The inbox is kept next to the usage tracker snapshot
+fn notifications_path() -> String {
+    format!(
+        "{}.notifications",
+        settings::get_rita_common().network.usage_tracker_file
+    )
+}
+
+/// Applies a change to the inbox, which is saved if the change says it modified anything and the
+/// storage can take another write, or right away if the change says it is urgent
+fn modify_notifications<T>(
+    change: impl FnOnce(&mut NotificationStore) -> (T, bool),
+    urgent: bool,
+) -> T {
+    let path = notifications_path();
+    let mut wrapper = NOTIFICATIONS.write().unwrap();
+    let wrapper = wrapper.get_or_insert_with(|| NotificationsWrapper::new(&path));
+    let (ret, modified) = change(&mut wrapper.store);
+    if modified {
+        wrapper.dirty = true;
+        wrapper.flush(&path, urgent);
+    }
+    ret
+}
+
+/// Writes the inbox out if it changed and the storage can take another write, called every slow
+/// loop tick and with force on shutdown
+pub fn flush_notifications(force: bool) {
+    let path = notifications_path();
+    if let Some(wrapper) = NOTIFICATIONS.write().unwrap().as_mut() {
+        wrapper.flush(&path, force);
+    }
+}
+
+/// Adds a notification to the dashboard inbox, returns its id
+pub fn add_notification(severity: NotificationSeverity, kind: &str, message: String) -> u64 {
+    add_expiring_notification(severity, kind, message, None)
+}
+
+/// Adds a notification to the dashboard inbox that is dropped at the unix time expires, returns its id
+pub fn add_expiring_notification(
+    severity: NotificationSeverity,
+    kind: &str,
+    message: String,
+    expires: Option<u64>,
+) -> u64 {
+    let timestamp = now_secs();
+    modify_notifications(
+        |store| {
+            (
+                store.add_expiring(severity, kind.to_string(), message, timestamp, expires),
+                true,
+            )
+        },
+        severity == NotificationSeverity::Critical,
+    )
+}
+
+/// The notice shown to the user for an event
+pub fn notification_for_event(event: &RitaEvent) -> (NotificationSeverity, String) {
+    match event {
+        RitaEvent::EnforcementStarted { neighbor, debt } => (
+            NotificationSeverity::Info,
+            format!("Neighbor {neighbor} was cut off for owing {debt} wei"),
+        ),
+        RitaEvent::EnforcementEnded { neighbor } => (
+            NotificationSeverity::Info,
+            format!("Neighbor {neighbor} paid and was reconnected"),
+        ),
+        RitaEvent::ExitSwitched { from: Some(from), to } => (
+            NotificationSeverity::Info,
+            format!("Switched exits from {from} to {to}"),
+        ),
+        RitaEvent::ExitSwitched { from: None, to } => (
+            NotificationSeverity::Info,
+            format!("Connected to exit {to}"),
+        ),
+        RitaEvent::BalanceLow {
+            balance,
+            warning_level,
+        } => (
+            NotificationSeverity::Warning,
+            format!(
+                "Your balance of {balance} wei is below {warning_level} wei, add funds to stay connected"
+            ),
+        ),
+        RitaEvent::Crash { message } => (
+            NotificationSeverity::Critical,
+            format!("Rita crashed: {message}"),
+        ),
+        RitaEvent::MemoryPressure { level, shed, .. } => {
+            let severity = match level {
+                MemoryPressureLevel::Normal => NotificationSeverity::Info,
+                MemoryPressureLevel::Warning => NotificationSeverity::Warning,
+                MemoryPressureLevel::Critical => NotificationSeverity::Critical,
+            };
+            let mut message = format!("The router is running low on memory, pressure is {level:?}");
+            if *shed {
+                message.push_str(", old usage and payment history was dropped to make room");
+            }
+            (severity, message)
+        }
+        RitaEvent::PaymentFailed { to, amount, reason } => (
+            NotificationSeverity::Warning,
+            format!(
+                "A payment of {amount} wei to {} failed: {reason}",
+                to.wg_public_key
+            ),
+        ),
+        RitaEvent::PaymentReverted {
+            counterparty,
+            amount,
+            incoming: true,
+            ..
+        } => (
+            NotificationSeverity::Warning,
+            format!(
+                "A payment of {amount} wei from {} was undone by a chain reorg, it counts again if it is mined again",
+                counterparty.wg_public_key
+            ),
+        ),
+        RitaEvent::PaymentReverted {
+            counterparty,
+            amount,
+            incoming: false,
+            ..
+        } => (
+            NotificationSeverity::Warning,
+            format!(
+                "Your payment of {amount} wei to {} was undone by a chain reorg, it will be paid again if it isn't mined again",
+                counterparty.wg_public_key
+            ),
+        ),
+    }
+}
+
+/// Called by the event delivery thread for every published event that is enabled
+pub fn add_notification_for_event(event: &RitaEvent, timestamp: u64) {
+    let (severity, message) = notification_for_event(event);
+    modify_notifications(
+        |store| {
+            store.add(severity, event.name().to_string(), message, timestamp);
+            ((), true)
+        },
+        severity == NotificationSeverity::Critical,
+    )
+}
+
+pub fn get_notifications(unread_only: bool) -> (usize, Vec<Notification>) {
+    let now = now_secs();
+    modify_notifications(
+        |store| {
+            let dropped = store.drop_expired(now);
+            ((store.unread(), store.list(unread_only)), dropped)
+        },
+        false,
+    )
+}
+
+/// Returns false if there is no notification with this id
+pub fn mark_notification_read(id: u64) -> bool {
+    modify_notifications(
+        |store| {
+            let found = store.mark_read(id);
+            (found, found)
+        },
+        false,
+    )
+}
+
+/// Returns how many notifications were unread
+pub fn mark_all_notifications_read() -> usize {
+    modify_notifications(
+        |store| {
+            let count = store.mark_all_read();
+            (count, count > 0)
+        },
+        false,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_store() {
+        let mut store = NotificationStore::default();
+        let first = store.add(NotificationSeverity::Info, "a".into(), "a".into(), 1);
+        let second = store.add(NotificationSeverity::Warning, "b".into(), "b".into(), 2);
+        assert_eq!(store.unread(), 2);
+        assert!(store.mark_read(first));
+        assert!(!store.mark_read(1000));
+        assert_eq!(store.unread(), 1);
+        let unread = store.list(true);
+        assert_eq!(unread.len(), 1);
+        assert_eq!(unread[0].id, second);
+        // newest first
+        assert_eq!(store.list(false)[0].id, second);
+
+        // read notifications are dropped before unread ones
+        for i in 0..MAX_NOTIFICATIONS {
+            store.add(
+                NotificationSeverity::Info,
+                "c".into(),
+                "c".into(),
+                3 + i as u64,
+            );
+        }
+        assert_eq!(store.list(false).len(), MAX_NOTIFICATIONS);
+        assert!(store.list(false).iter().all(|n| n.id != first));
+        assert!(store.list(false).iter().any(|n| n.id == second));
+        // then the oldest
+        store.add(NotificationSeverity::Info, "c".into(), "c".into(), 1000);
+        assert!(store.list(false).iter().all(|n| n.id != second));
+
+        assert_eq!(store.mark_all_read(), MAX_NOTIFICATIONS);
+        assert_eq!(store.unread(), 0);
+    }
+
+    #[test]
+    fn test_notification_expiry() {
+        let mut store = NotificationStore::default();
+        let kept = store.add(NotificationSeverity::Info, "a".into(), "a".into(), 1);
+        store.add_expiring(
+            NotificationSeverity::Warning,
+            "b".into(),
+            "b".into(),
+            1,
+            Some(100),
+        );
+        assert!(!store.drop_expired(99));
+        assert_eq!(store.unread(), 2);
+        assert!(store.drop_expired(100));
+        let left = store.list(false);
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, kept);
+    }
+
+    #[test]
+    fn test_notification_for_event() {
+        let (severity, message) = notification_for_event(&RitaEvent::Crash {
+            message: "panicked at src/lib.rs".to_string(),
+        });
+        assert_eq!(severity, NotificationSeverity::Critical);
+        assert_eq!(message, "Rita crashed: panicked at src/lib.rs");
+        let (severity, message) = notification_for_event(&RitaEvent::ExitSwitched {
+            from: None,
+            to: "fd00::1337".parse().unwrap(),
+        });
+        assert_eq!(severity, NotificationSeverity::Info);
+        assert_eq!(message, "Connected to exit fd00::1337");
+    }
+}
@@ -0,0 +1,475 @@
+//! Per hop diagnostics for multi hop mesh paths. Babel only tells each node about its own next hop toward a
+//! destination, so a path is traced by handing it along. Each node reports the installed route, babel's view
+//! of the link to its next hop and the result of probing that link with pings over the tunnel, then asks that
+//! next hop on the rita contact port to trace the rest of the path the same way. Nodes only trace for their
+//! mesh neighbors and only a few times a minute, since each trace pings for seconds. The ui gets the whole
+//! path and can point at the link that is actually dropping or delaying traffic.
+
+use crate::tunnel_manager::mtu::{get_tunnel_mtu, TunnelMtu};
+use crate::tunnel_manager::tm_get_neighbors;
+use crate::RitaCommonError;
+use crate::KI;
+use actix_web_async::http::StatusCode;
+use actix_web_async::web::{self, Path};
+use actix_web_async::{HttpRequest, HttpResponse};
+use babel_monitor::pool::get_babel_stream;
+use babel_monitor::structs::{Neighbor as BabelNeighbor, Route};
+use babel_monitor::{parse_neighs, parse_routes};
+use ipnetwork::IpNetwork;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Paths longer than this are cut short, babel routes on a healthy mesh are far shorter
+pub const MAX_PATH_HOPS: usize = 16;
+/// Pings sent over each link
+const PROBE_COUNT: u32 = 5;
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// A hop takes up to PROBE_COUNT * PROBE_TIMEOUT to probe its link, plus time to reach it, the rest
+/// of a path gets this much for every hop it may have
+const HOP_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const BABEL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Traces we run for neighbors per RATE_LIMIT_WINDOW, the rest are refused
+const MAX_TRACES_PER_WINDOW: usize = 10;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// When the traces we ran for neighbors within the last RATE_LIMIT_WINDOW started
+    static ref RECENT_TRACES: Arc<Mutex<VecDeque<Instant>>> =
+        Arc::new(Mutex::new(VecDeque::new()));
+}
+
+/// The result of probing a single link with pings
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LinkProbe {
+    pub sent: u32,
+    pub received: u32,
+    pub loss_percent: f32,
+    /// Average round trip of the pings that were answered, None if none were
+    pub avg_rtt_ms: Option<f32>,
+}
+
+/// One node's view of the next link toward a destination
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HopReport {
+    /// Mesh ip of the node that produced this report
+    pub from: Option<IpAddr>,
+    /// Mesh ip of the neighbor babel forwards to, None if it is not one of our tunnel peers
+    pub next_hop: Option<IpAddr>,
+    /// Tunnel interface the route uses
+    pub iface: String,
+    /// Link local address of the neighbor on that tunnel
+    pub neigh_ip: IpAddr,
+    /// Babel metric of the route to the destination from this node
+    pub route_metric: u16,
+    /// Babel's link cost and smoothed rtt to the neighbor, when babel knows them
+    pub babel_link_cost: Option<u16>,
+    pub babel_rtt_ms: Option<f32>,
+    pub probe: LinkProbe,
+    /// What mtu discovery last found for the tunnel, None from nodes that predate it or before the
+    /// tunnel was first probed
+    #[serde(default)]
+    pub tunnel_mtu: Option<TunnelMtu>,
+}
+
+/// The full trace to a destination, hops are in path order starting with our own
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PathReport {
+    pub destination: IpAddr,
+    pub hops: Vec<HopReport>,
+    /// True if the trace reached the destination
+    pub complete: bool,
+    /// Why the trace stopped early, if it did
+    pub error: Option<String>,
+}
+
+/// The parts of our routing state that determine the next hop toward a destination
+#[derive(Debug, Clone)]
+struct NextHop {
+    route: Route,
+    babel_neighbor: Option<BabelNeighbor>,
+    mesh_ip: Option<IpAddr>,
+}
+
+/// Finds the installed route to dest along with the babel neighbor it goes through and, using the
+/// tunnel interface name, the mesh ip of that neighbor. tunnels maps interface names to peer mesh ips
+fn find_next_hop(
+    dest: IpAddr,
+    routes: &[Route],
+    neighbors: &[BabelNeighbor],
+    tunnels: &[(String, IpAddr)],
+) -> Option<NextHop> {
+    let route = routes.iter().find(|route| match route.prefix {
+        IpNetwork::V6(ref ip) => {
+            route.installed && ip.prefix() == 128 && IpAddr::V6(ip.ip()) == dest
+        }
+        IpNetwork::V4(_) => false,
+    })?;
+    let babel_neighbor = neighbors
+        .iter()
+        .find(|n| n.address == route.neigh_ip && n.iface == route.iface)
+        .cloned();
+    let mesh_ip = tunnels
+        .iter()
+        .find(|(iface, _)| *iface == route.iface)
+        .map(|(_, ip)| *ip);
+    Some(NextHop {
+        route: route.clone(),
+        babel_neighbor,
+        mesh_ip,
+    })
+}
+
+fn summarize_probe(rtts: &[Option<Duration>]) -> LinkProbe {
+    let answered: Vec<Duration> = rtts.iter().flatten().copied().collect();
+    let sent = rtts.len() as u32;
+    let received = answered.len() as u32;
+    let loss_percent = if sent == 0 {
+        0.0
+    } else {
+        (sent - received) as f32 * 100.0 / sent as f32
+    };
+    let avg_rtt_ms = if answered.is_empty() {
+        None
+    } else {
+        let total: Duration = answered.iter().sum();
+        Some(total.as_secs_f32() * 1000.0 / received as f32)
+    };
+    LinkProbe {
+        sent,
+        received,
+        loss_percent,
+        avg_rtt_ms,
+    }
+}
+
+/// Pings a neighbor over the given tunnel, returns how many answered and how quickly
+fn probe_link(neigh_ip: IpAddr, iface: &str) -> LinkProbe {
+    let mut rtts = Vec::new();
+    for _ in 0..PROBE_COUNT {
+        let start = Instant::now();
+        let rtt = match KI.ping_check(&neigh_ip, PROBE_TIMEOUT, Some(iface)) {
+            Ok(true) => Some(start.elapsed()),
+            Ok(false) => None,
+            Err(e) => {
+                warn!("Failed to probe {} on {} {:?}", neigh_ip, iface, e);
+                None
+            }
+        };
+        rtts.push(rtt);
+    }
+    summarize_probe(&rtts)
+}
+
+/// Builds this node's report of the next link toward dest
+pub fn local_hop_report(dest: IpAddr) -> Result<HopReport, RitaCommonError> {
+    let common = settings::get_rita_common();
+    let mut stream = get_babel_stream(common.network.babel_port, BABEL_TIMEOUT)?;
+    let routes = parse_routes(&mut stream)?;
+    let neighbors = parse_neighs(&mut stream)?;
+    let tunnels: Vec<(String, IpAddr)> = tm_get_neighbors()
+        .into_iter()
+        .map(|n| (n.iface_name, n.identity.global.mesh_ip))
+        .collect();
+
+    let next = match find_next_hop(dest, &routes, &neighbors, &tunnels) {
+        Some(next) => next,
+        None => {
+            return Err(RitaCommonError::MiscStringError(format!(
+                "No installed route to {dest}"
+            )))
+        }
+    };
+    Ok(HopReport {
+        from: common.network.mesh_ip,
+        next_hop: next.mesh_ip,
+        iface: next.route.iface.clone(),
+        neigh_ip: next.route.neigh_ip,
+        route_metric: next.route.metric,
+        babel_link_cost: next.babel_neighbor.as_ref().map(|n| n.cost),
+        babel_rtt_ms: next.babel_neighbor.as_ref().map(|n| n.rtt),
+        probe: probe_link(next.route.neigh_ip, &next.route.iface),
+        tunnel_mtu: get_tunnel_mtu(&next.route.iface),
+    })
+}
+
+/// local_hop_report off the async runtime, the babel queries and pings block for seconds
+async fn blocking_hop_report(dest: IpAddr) -> Result<HopReport, RitaCommonError> {
+    match web::block(move || local_hop_report(dest)).await {
+        Ok(report) => report,
+        Err(e) => Err(RitaCommonError::MiscStringError(format!(
+            "Failed to build hop report {e}"
+        ))),
+    }
+}
+
+/// Asks our next hop to trace the rest of the path, it has hops_left hops to reach dest
+async fn query_rest_of_path(
+    hop: IpAddr,
+    dest: IpAddr,
+    hops_left: usize,
+) -> Result<PathReport, RitaCommonError> {
+    let contact_port = settings::get_rita_common().network.rita_contact_port;
+    let url = format!("http://[{hop}]:{contact_port}/diagnostics/trace/{dest}/{hops_left}");
+    let client = awc::Client::default();
+    let mut response = client
+        .get(&url)
+        .timeout(HOP_QUERY_TIMEOUT * hops_left as u32)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let message: String = response.json().await.unwrap_or_default();
+        return Err(RitaCommonError::MiscStringError(format!(
+            "{hop} could not trace the rest of the path {message}"
+        )));
+    }
+    Ok(response.json().await?)
+}
+
+/// Traces the path to dest starting here, our next hop traces the rest of it the same way. Stops at
+/// the destination, the first hop that can not be queried, a routing loop or after hops_left hops
+pub async fn trace_path(dest: IpAddr, hops_left: usize) -> PathReport {
+    let mut report = PathReport {
+        destination: dest,
+        hops: Vec::new(),
+        complete: false,
+        error: None,
+    };
+    let hop = match blocking_hop_report(dest).await {
+        Ok(hop) => hop,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+    let from = hop.from;
+    let next = hop.next_hop;
+    report.hops.push(hop);
+    let next = match next {
+        Some(next) => next,
+        None => {
+            report.error = Some("Next hop is not a known tunnel peer".to_string());
+            return report;
+        }
+    };
+    if next == dest {
+        report.complete = true;
+        return report;
+    }
+    if hops_left <= 1 {
+        report.error = Some(format!("Path is longer than {MAX_PATH_HOPS} hops"));
+        return report;
+    }
+    match query_rest_of_path(next, dest, hops_left - 1).await {
+        Ok(rest) => append_rest_of_path(&mut report, rest, from),
+        Err(e) => report.error = Some(e.to_string()),
+    }
+    report
+}
+
+/// Appends what our next hop traced, cutting it short if the path comes back through us
+fn append_rest_of_path(report: &mut PathReport, rest: PathReport, us: Option<IpAddr>) {
+    let back_to_us = us.and_then(|us| {
+        rest.hops
+            .iter()
+            .position(|hop| hop.from == Some(us))
+            .map(|position| (us, position))
+    });
+    match back_to_us {
+        Some((us, position)) => {
+            report.hops.extend(rest.hops.into_iter().take(position));
+            report.error = Some(format!("Routing loop through {us}"));
+        }
+        None => {
+            report.hops.extend(rest.hops);
+            report.complete = rest.complete;
+            report.error = rest.error;
+        }
+    }
+}
+
+/// Records a trace started at now unless MAX_TRACES_PER_WINDOW already were within the window
+fn allow_trace(recent: &mut VecDeque<Instant>, now: Instant) -> bool {
+    while let Some(oldest) = recent.front() {
+        if now.saturating_duration_since(*oldest) < RATE_LIMIT_WINDOW {
+            break;
+        }
+        recent.pop_front();
+    }
+    if recent.len() >= MAX_TRACES_PER_WINDOW {
+        return false;
+    }
+    recent.push_back(now);
+    true
+}
+
+/// Contact port endpoint, a neighbor whose path to dest goes through us asks us to trace the rest of it
+pub async fn get_rest_of_path(path: Path<(IpAddr, usize)>, req: HttpRequest) -> HttpResponse {
+    let neighbor = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return HttpResponse::BadRequest().finish(),
+    };
+    if !tm_get_neighbors()
+        .iter()
+        .any(|n| n.identity.global.mesh_ip == neighbor)
+    {
+        return HttpResponse::build(StatusCode::FORBIDDEN)
+            .json(format!("{neighbor} is not a mesh neighbor"));
+    }
+    if !allow_trace(&mut RECENT_TRACES.lock().unwrap(), Instant::now()) {
+        return HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+            .json("Too many path traces, try again later".to_string());
+    }
+    let (dest, hops_left) = path.into_inner();
+    HttpResponse::Ok().json(trace_path(dest, hops_left.min(MAX_PATH_HOPS)).await)
+}
+
+/// Dashboard endpoint, traces the path to the destination mesh ip
+pub async fn get_path_diagnostics(path: Path<IpAddr>) -> HttpResponse {
+    HttpResponse::Ok().json(trace_path(path.into_inner(), MAX_PATH_HOPS).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, iface: &str, neigh_ip: &str, installed: bool) -> Route {
+        Route {
+            id: "route".to_string(),
+            iface: iface.to_string(),
+            xroute: false,
+            installed,
+            neigh_ip: neigh_ip.parse().unwrap(),
+            prefix: prefix.parse().unwrap(),
+            metric: 256,
+            refmetric: 96,
+            full_path_rtt: 12.0,
+            price: 0,
+            fee: 0,
+            src_prefix: None,
+        }
+    }
+
+    #[test]
+    fn test_find_next_hop() {
+        let dest: IpAddr = "fd00::3".parse().unwrap();
+        let peer: IpAddr = "fd00::2".parse().unwrap();
+        let routes = vec![
+            route("fd00::3/128", "wg1", "fe80::1", false),
+            route("fd00::3/128", "wg2", "fe80::2", true),
+            route("fd00::2/128", "wg2", "fe80::2", true),
+        ];
+        let neighbors = vec![BabelNeighbor {
+            id: "neigh".to_string(),
+            address: "fe80::2".parse().unwrap(),
+            iface: "wg2".to_string(),
+            reach: 0xffff,
+            txcost: 96,
+            rxcost: 96,
+            rtt: 4.5,
+            rttcost: 0,
+            cost: 96,
+        }];
+        let tunnels = vec![("wg2".to_string(), peer)];
+
+        let next = find_next_hop(dest, &routes, &neighbors, &tunnels).unwrap();
+        assert_eq!(next.route.iface, "wg2");
+        assert_eq!(next.mesh_ip, Some(peer));
+        assert_eq!(next.babel_neighbor.unwrap().cost, 96);
+
+        // a route over a tunnel we have no record of still reports the link
+        let next = find_next_hop(dest, &routes, &[], &[]).unwrap();
+        assert_eq!(next.mesh_ip, None);
+        assert!(next.babel_neighbor.is_none());
+
+        assert!(find_next_hop("fd00::9".parse().unwrap(), &routes, &neighbors, &tunnels).is_none());
+    }
+
+    #[test]
+    fn test_summarize_probe() {
+        let probe = summarize_probe(&[
+            Some(Duration::from_millis(10)),
+            None,
+            Some(Duration::from_millis(30)),
+            None,
+        ]);
+        assert_eq!(probe.sent, 4);
+        assert_eq!(probe.received, 2);
+        assert_eq!(probe.loss_percent, 50.0);
+        assert_eq!(probe.avg_rtt_ms, Some(20.0));
+
+        let probe = summarize_probe(&[None, None]);
+        assert_eq!(probe.loss_percent, 100.0);
+        assert_eq!(probe.avg_rtt_ms, None);
+    }
+
+    fn hop(from: &str, next_hop: &str) -> HopReport {
+        HopReport {
+            from: Some(from.parse().unwrap()),
+            next_hop: Some(next_hop.parse().unwrap()),
+            iface: "wg0".to_string(),
+            neigh_ip: "fe80::1".parse().unwrap(),
+            route_metric: 256,
+            babel_link_cost: None,
+            babel_rtt_ms: None,
+            probe: summarize_probe(&[]),
+            tunnel_mtu: None,
+        }
+    }
+
+    #[test]
+    fn test_append_rest_of_path() {
+        let dest: IpAddr = "fd00::9".parse().unwrap();
+        let us: IpAddr = "fd00::1".parse().unwrap();
+        let report = || PathReport {
+            destination: dest,
+            hops: vec![hop("fd00::1", "fd00::2")],
+            complete: false,
+            error: None,
+        };
+
+        let mut done = report();
+        let rest = PathReport {
+            destination: dest,
+            hops: vec![hop("fd00::2", "fd00::9")],
+            complete: true,
+            error: None,
+        };
+        append_rest_of_path(&mut done, rest, Some(us));
+        assert!(done.complete);
+        assert_eq!(done.hops.len(), 2);
+
+        // the path comes back through us, everything from there on is the loop
+        let mut looped = report();
+        let rest = PathReport {
+            destination: dest,
+            hops: vec![
+                hop("fd00::2", "fd00::1"),
+                hop("fd00::1", "fd00::2"),
+                hop("fd00::2", "fd00::1"),
+            ],
+            complete: false,
+            error: Some("Path is longer than 16 hops".to_string()),
+        };
+        append_rest_of_path(&mut looped, rest, Some(us));
+        assert!(!looped.complete);
+        assert_eq!(looped.hops.len(), 2);
+        assert_eq!(
+            looped.error,
+            Some("Routing loop through fd00::1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_allow_trace() {
+        let mut recent = VecDeque::new();
+        let start = Instant::now();
+        for _ in 0..MAX_TRACES_PER_WINDOW {
+            assert!(allow_trace(&mut recent, start));
+        }
+        assert!(!allow_trace(&mut recent, start + Duration::from_secs(1)));
+        // the window has passed
+        assert!(allow_trace(&mut recent, start + RATE_LIMIT_WINDOW));
+    }
+}
@@ -2,17 +2,29 @@
 //! managing the retry flow for failed payment attempts. We will retry a payment
 //! until it is successfully in a block, see payment_validator, once the payment is on
 //! the blockchain it's up to the reciever to validate that it's correct
+//!
+//! On eth based chains a payment sent during a gas price spike can sit unmined until it times
+//! out, and since debt keeper won't pay a node again while a payment is in flight that stalls
+//! all payments to it. So sent transactions are tracked until their nonce is used and are
+//! rebroadcast with a higher gas price if they take longer than gas_escalation_timeout. Each
+//! replacement is validated and sent to the neighbor like a new payment, payment_validator knows
+//! that only one broadcast of a nonce can be mined
+
+pub mod pause;
 
 use crate::blockchain_oracle::get_oracle_balance;
 use crate::debt_keeper::normalize_payment_amount;
 use crate::debt_keeper::payment_failed;
+use crate::events::{publish_event, RitaEvent};
+use crate::payment_controller::pause::{payments_paused, withhold_payment};
 use crate::payment_validator::ToValidate;
 use crate::payment_validator::{ALTHEA_CHAIN_PREFIX, ALTHEA_CONTACT_TIMEOUT};
 use crate::rita_loop::get_web3_server;
 use althea_types::interop::UnpublishedPaymentTx;
-use althea_types::{Denom, PaymentTx};
+use althea_types::{Amount, AmountUnit, Denom, PaymentTx};
 use althea_types::{Identity, SystemChain};
 use awc;
+use clarity::Transaction;
 use deep_space::client::ChainStatus;
 use deep_space::{Coin, Contact, EthermintPrivateKey};
 use futures::future::{join, join_all};
@@ -35,6 +47,8 @@ pub const MAX_TXID_RETRIES: u8 = 15u8;
 /// How many blocks after submission a MicroTX will be valid for. If we wait this many blocks after submitting the
 /// tx we can be sure that it will not be included in a block and we can safely retry it
 pub const ALTHEA_L1_MICROTX_TIMEOUT: u64 = 25;
+/// The smallest gas price increase full nodes accept for replacing a pending transaction
+pub const MIN_GAS_ESCALATION_PERCENT: u32 = 10;
 
 #[derive(Default, Clone)]
 pub struct PaymentController {
@@ -48,6 +62,24 @@ pub struct PaymentController {
     /// info over to our neighbor. Even if we fail to do so we should still consider
     /// this debt as paid
     resend_queue: Vec<ResendInfo>,
+    /// Set once a payment fails to send and cleared once one goes through, so that a payment
+    /// failed event is published once per outage rather than every tick
+    failing: bool,
+    /// Eth transactions we have broadcast that may still need a gas price replacement
+    pending_txs: Vec<PendingTx>,
+}
+
+/// A broadcast eth transaction whose nonce hasn't been used yet
+#[derive(Debug, Clone)]
+struct PendingTx {
+    /// The latest broadcast, signed
+    tx: Transaction,
+    /// The payment as published with the latest txid
+    pmt: PaymentTx,
+    /// Every txid broadcast for this nonce, oldest first
+    txids: Vec<Uint256>,
+    last_broadcast: Instant,
+    escalations: u8,
 }
 
 impl PaymentController {
@@ -55,9 +87,16 @@ impl PaymentController {
         PaymentController {
             outgoing_queue: Vec::new(),
             resend_queue: Vec::new(),
+            failing: false,
+            pending_txs: Vec::new(),
         }
     }
 
+    /// Payments waiting to be sent or resent
+    pub fn queued_payments(&self) -> usize {
+        self.outgoing_queue.len() + self.resend_queue.len()
+    }
+
     /// This function is called by the async loop in order to perform payment
     /// controller actions
     pub async fn tick_payment_controller(
@@ -68,8 +107,20 @@ impl PaymentController {
         // move these new payments into the outgoing queue
         self.outgoing_queue.extend(new_outgoing_payments);
 
+        // debt keeper asks again once payments resume, payments already broadcast are still
+        // resent and escalated below
+        if payments_paused() {
+            for pmt in self.outgoing_queue.drain(..) {
+                withhold_payment(&pmt);
+                payment_failed(pmt.to);
+            }
+        }
+
         // nothing to do this round
-        if self.outgoing_queue.is_empty() && self.resend_queue.is_empty() {
+        if self.outgoing_queue.is_empty()
+            && self.resend_queue.is_empty()
+            && self.pending_txs.is_empty()
+        {
             return Vec::new();
         }
 
@@ -88,14 +139,26 @@ impl PaymentController {
         let mut requeue = Vec::new();
         while let Some(pmt) = self.outgoing_queue.pop() {
             match make_payment(pmt, &previously_sent_payments).await {
-                Ok((pmt, resend)) => {
+                Ok((pmt, resend, pending)) => {
+                    self.failing = false;
                     payments_sent_this_round.push(pmt);
                     if let Some(retry) = resend {
                         self.resend_queue.push(retry)
                     }
+                    if let Some(pending) = pending {
+                        self.pending_txs.push(pending)
+                    }
                 }
                 Err(e) => {
                     warn!("Failed to send payment with {:?}!", e);
+                    if !self.failing {
+                        self.failing = true;
+                        publish_event(RitaEvent::PaymentFailed {
+                            to: pmt.to,
+                            amount: pmt.amount,
+                            reason: e.to_string(),
+                        });
+                    }
                     requeue.push(pmt)
                 }
             }
@@ -130,8 +193,179 @@ impl PaymentController {
             self.resend_queue.push(resend);
         }
 
+        payments_sent_this_round.extend(
+            self.escalate_stuck_transactions(&previously_sent_payments)
+                .await,
+        );
+
         payments_sent_this_round
     }
+
+    /// Forgets transactions whose nonce has been used and rebroadcasts the ones that have been
+    /// waiting longer than gas_escalation_timeout with a higher gas price, returns the
+    /// replacements for validation
+    async fn escalate_stuck_transactions(
+        &mut self,
+        previously_sent_payments: &HashMap<Identity, HashSet<PaymentTx>>,
+    ) -> Vec<ToValidate> {
+        let payment_settings = settings::get_rita_common().payment;
+        let timeout = Duration::from_secs(payment_settings.gas_escalation_timeout);
+        if payment_settings.gas_escalation_timeout == 0
+            || !self
+                .pending_txs
+                .iter()
+                .any(|p| p.last_broadcast.elapsed() > timeout)
+        {
+            return Vec::new();
+        }
+        let our_private_key = match payment_settings.eth_private_key {
+            Some(key) => key,
+            None => return Vec::new(),
+        };
+        let full_node = get_web3_server();
+        let web3 = Web3::new(&full_node, TRANSACTION_SUBMISSION_TIMEOUT);
+        // every nonce below this one has been used by some broadcast
+        let next_nonce = match web3
+            .eth_get_transaction_count(our_private_key.to_address())
+            .await
+        {
+            Ok(nonce) => nonce,
+            Err(e) => {
+                warn!("Failed to get our nonce to check pending payments {:?}", e);
+                return Vec::new();
+            }
+        };
+        self.pending_txs
+            .retain(|p| matches!(transaction_nonce(&p.tx), Some(nonce) if nonce >= next_nonce));
+
+        let mut replacements = Vec::new();
+        let mut still_pending = Vec::new();
+        let network_settings = settings::get_rita_common().network;
+        for mut pending in std::mem::take(&mut self.pending_txs) {
+            if pending.last_broadcast.elapsed() <= timeout {
+                still_pending.push(pending);
+                continue;
+            }
+            if pending.escalations >= payment_settings.max_gas_escalations {
+                warn!(
+                    "Payment {:#066x} is still not mined after {} replacements, leaving it to time out",
+                    pending.pmt.txid, pending.escalations
+                );
+                continue;
+            }
+            let network_gas_price = web3.eth_gas_price().await.unwrap_or(0u8.into());
+            let (replacement, chain_id) = match (
+                escalated_transaction(
+                    &pending.tx,
+                    payment_settings.gas_escalation_percent,
+                    network_gas_price,
+                ),
+                web3.net_version().await,
+            ) {
+                (Some(tx), Ok(chain_id)) => (tx.sign(&our_private_key, Some(chain_id)), chain_id),
+                (None, _) => continue,
+                (_, Err(e)) => {
+                    warn!("Failed to get chain id to replace a payment {:?}", e);
+                    still_pending.push(pending);
+                    continue;
+                }
+            };
+            let txid = match web3.send_prepared_transaction(replacement.clone()).await {
+                Ok(txid) => txid,
+                // the full node refused the replacement, the last broadcast is still valid
+                Err(Web3Error::JsonRpcError { message, .. }) => {
+                    warn!(
+                        "Replacement for payment {:#066x} on chain {} rejected {}",
+                        pending.pmt.txid, chain_id, message
+                    );
+                    pending.escalations += 1;
+                    pending.last_broadcast = Instant::now();
+                    still_pending.push(pending);
+                    continue;
+                }
+                // it may have been published, so treat it like it was
+                Err(_) => replacement.txid(),
+            };
+            info!(
+                "Replaced stuck payment {:#066x} to {} with {:#066x}",
+                pending.pmt.txid, pending.pmt.to.wg_public_key, txid
+            );
+
+            let pmt = PaymentTx {
+                txid,
+                ..pending.pmt
+            };
+            if let Some(resend) = send_make_payment_endpoints(
+                pmt,
+                network_settings.clone(),
+                full_node.clone(),
+                previously_sent_payments,
+                0,
+            )
+            .await
+            {
+                self.resend_queue.push(resend);
+            }
+            replacements.push(ToValidate {
+                payment: pmt,
+                received: Instant::now(),
+                timeout_block: None,
+                replaces: pending.txids.clone(),
+            });
+
+            pending.txids.push(txid);
+            pending.tx = replacement;
+            pending.pmt = pmt;
+            pending.escalations += 1;
+            pending.last_broadcast = Instant::now();
+            still_pending.push(pending);
+        }
+        self.pending_txs = still_pending;
+        replacements
+    }
+}
+
+fn transaction_nonce(tx: &Transaction) -> Option<Uint256> {
+    match tx {
+        Transaction::Legacy { nonce, .. } => Some(*nonce),
+        // we only ever send legacy payments
+        _ => None,
+    }
+}
+
+/// An unsigned copy of a legacy transaction with its gas price raised by percent, or to the network
+/// gas price if that is higher. Full nodes only accept a replacement for the same nonce if it raises
+/// the price by at least MIN_GAS_ESCALATION_PERCENT
+fn escalated_transaction(
+    tx: &Transaction,
+    percent: u32,
+    network_gas_price: Uint256,
+) -> Option<Transaction> {
+    match tx.clone() {
+        Transaction::Legacy {
+            nonce,
+            gas_price,
+            gas_limit,
+            to,
+            value,
+            data,
+            ..
+        } => {
+            let percent = percent.max(MIN_GAS_ESCALATION_PERCENT);
+            let escalated = gas_price * Uint256::from(100 + percent) / Uint256::from(100u8);
+            Some(Transaction::Legacy {
+                nonce,
+                gas_price: escalated.max(network_gas_price),
+                gas_limit,
+                to,
+                value,
+                data,
+                signature: None,
+            })
+        }
+        // we only ever send legacy payments
+        _ => None,
+    }
 }
 
 #[derive(Debug)]
@@ -140,8 +374,8 @@ pub enum PaymentControllerError {
     /// we have paid and our neighbor will not know
     ResendFailed,
     InsufficientFunds {
-        amount: Uint256,
-        balance: Uint256,
+        amount: Amount,
+        balance: Amount,
     },
     ZeroPayment,
     FailedToSendPayment,
@@ -152,7 +386,12 @@ impl Display for PaymentControllerError {
         match self {
             Self::ResendFailed => write!(f, "Failed to resend txid after all attempts!"),
             Self::InsufficientFunds { amount, balance } => {
-                write!(f, "Can not send amount {amount} with balance {balance}")
+                write!(
+                    f,
+                    "Can not send amount {} with balance {}",
+                    amount.display_as(AmountUnit::Ether),
+                    balance.display_as(AmountUnit::Ether)
+                )
             }
             Self::ZeroPayment => write!(f, "Attempted to send zero value payment!"),
             Self::FailedToSendPayment => write!(f, "Failed to send payment!"),
@@ -163,11 +402,12 @@ impl Display for PaymentControllerError {
 impl Error for PaymentControllerError {}
 
 /// This is called by debt_keeper to make payments. It sends a
-/// PaymentTx to the `mesh_ip` in its `to` field. It returns a payment to validate
+/// PaymentTx to the `mesh_ip` in its `to` field. It returns a payment to validate, a potential
+/// retry and on eth based chains the transaction to watch for gas price replacement
 async fn make_payment(
     pmt: UnpublishedPaymentTx,
     previously_sent_payments: &HashMap<Identity, HashSet<PaymentTx>>,
-) -> Result<(ToValidate, Option<ResendInfo>), PaymentControllerError> {
+) -> Result<(ToValidate, Option<ResendInfo>, Option<PendingTx>), PaymentControllerError> {
     let common = settings::get_rita_common();
     let network_settings = common.network;
     let payment_settings = common.payment;
@@ -203,7 +443,7 @@ async fn make_althea_payment(
     payment_settings: PaymentSettings,
     network_settings: NetworkSettings,
     previously_sent_payments: &HashMap<Identity, HashSet<PaymentTx>>,
-) -> Result<(ToValidate, Option<ResendInfo>), PaymentControllerError> {
+) -> Result<(ToValidate, Option<ResendInfo>, Option<PendingTx>), PaymentControllerError> {
     // On althea chain, we default to paying with usdc, config must specify this as an accepted denom
     let payment_denom = payment_settings.althea_l1_payment_denom;
 
@@ -335,19 +575,22 @@ async fn make_althea_payment(
         // for some reason takes a very long time (each response just shy of it's own timeout)
         // we don't want to timeout a still valid transaction
         timeout_block: Some(block_height + ALTHEA_L1_MICROTX_TIMEOUT + 5),
+        replaces: Vec::new(),
     };
 
-    Ok((ts, retry))
+    // microtxs expire instead of getting stuck so there is nothing to replace
+    Ok((ts, retry, None))
 }
 
 /// Sends a payment on ETH based chains, this is a basic send transaction with no payload
-/// returns a payment to validate and optionally a retry if we have to send details to the neighbor again
+/// returns a payment to validate, optionally a retry if we have to send details to the neighbor again
+/// and the transaction to replace if it gets stuck
 async fn make_xdai_payment(
     pmt: UnpublishedPaymentTx,
     payment_settings: PaymentSettings,
     network_settings: NetworkSettings,
     previously_sent_payments: &HashMap<Identity, HashSet<PaymentTx>>,
-) -> Result<(ToValidate, Option<ResendInfo>), PaymentControllerError> {
+) -> Result<(ToValidate, Option<ResendInfo>, Option<PendingTx>), PaymentControllerError> {
     let balance = get_oracle_balance();
     let our_private_key = &payment_settings
         .eth_private_key
@@ -422,9 +665,17 @@ async fn make_xdai_payment(
                 payment: pmt,
                 received: Instant::now(),
                 timeout_block: None,
+                replaces: Vec::new(),
+            };
+            let pending = PendingTx {
+                tx,
+                pmt,
+                txids: vec![tx_id],
+                last_broadcast: Instant::now(),
+                escalations: 0,
             };
 
-            Ok((ts, resend))
+            Ok((ts, resend, Some(pending)))
         }
         Err(e) => {
             error!(
@@ -443,9 +694,10 @@ fn sanity_check_balance(
     balance: Option<Uint256>,
     pmt: &UnpublishedPaymentTx,
 ) -> Result<(), PaymentControllerError> {
-    match balance {
+    let amount = Amount::from_wei(pmt.amount);
+    match balance.map(Amount::from_wei) {
         Some(value) => {
-            if value < pmt.amount {
+            if value < amount {
                 warn!("Not enough money to pay debts! Cutoff imminent");
                 // having this here really doesn't matter much, either we
                 // tell debt keeper the payment failed and it enqueues another
@@ -453,10 +705,10 @@ fn sanity_check_balance(
                 // and does the same thing.
                 payment_failed(pmt.to);
                 Err(PaymentControllerError::InsufficientFunds {
-                    amount: pmt.amount,
+                    amount,
                     balance: value,
                 })
-            } else if pmt.amount == 0u32.into() {
+            } else if amount.is_zero() {
                 // in this case we just drop the tx, no retry no other messages
                 error!("Trying to pay nothing!");
                 return Err(PaymentControllerError::ZeroPayment);
@@ -467,8 +719,8 @@ fn sanity_check_balance(
         None => {
             warn!("Balance is none");
             Err(PaymentControllerError::InsufficientFunds {
-                amount: pmt.amount,
-                balance: balance.unwrap_or_else(|| 0u64.into()),
+                amount,
+                balance: Amount::zero(),
             })
         }
     }
@@ -643,3 +895,40 @@ fn parse_althea_txhash() {
     println!("Parsed: {:?}", parsed);
     println!("{:?}", parsed.to_str_radix(16));
 }
+
+#[test]
+fn test_escalated_transaction() {
+    let tx = Transaction::Legacy {
+        nonce: 7u8.into(),
+        gas_price: 1_000_000_000u64.into(),
+        gas_limit: 21_000u64.into(),
+        to: "0x0000000000000000000000000000000000000001"
+            .parse()
+            .unwrap(),
+        value: 100u64.into(),
+        data: Vec::new(),
+        signature: None,
+    };
+    let gas_price = |tx: Option<Transaction>| match tx {
+        Some(Transaction::Legacy { gas_price, .. }) => gas_price,
+        _ => panic!("Expected a legacy transaction"),
+    };
+    assert_eq!(
+        gas_price(escalated_transaction(&tx, 25, 0u8.into())),
+        1_250_000_000u64.into()
+    );
+    // never less than full nodes accept as a replacement
+    assert_eq!(
+        gas_price(escalated_transaction(&tx, 1, 0u8.into())),
+        1_100_000_000u64.into()
+    );
+    // or than the going rate
+    assert_eq!(
+        gas_price(escalated_transaction(&tx, 25, 3_000_000_000u64.into())),
+        3_000_000_000u64.into()
+    );
+    assert_eq!(
+        transaction_nonce(&escalated_transaction(&tx, 25, 0u8.into()).unwrap()),
+        Some(7u8.into())
+    );
+}
@@ -0,0 +1,167 @@
+//! Pausing outgoing payments from the dashboard, for troubleshooting a suspected billing problem
+//! without unplugging the router. While paused debts keep accruing as usual, but the payments debt
+//! keeper asks for are logged and handed back to it as failed instead of being sent, so it asks again
+//! with the full debt once payments resume. Payments already broadcast are still delivered to the
+//! neighbor and escalated. A pause ends on its own after payment.max_payment_pause so that a forgotten
+//! pause doesn't leave the router unpaid until every neighbor enforces on it.
+
+use crate::KI;
+use althea_types::interop::UnpublishedPaymentTx;
+use althea_types::unix_time::now_secs;
+use althea_types::Identity;
+use num256::Uint256;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// Keyed by netns so that integration tests running many nodes in one process keep them apart
+    static ref PAYMENT_PAUSE: Arc<RwLock<HashMap<u32, PaymentPause>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+#[derive(Debug, Clone, Default)]
+struct PaymentPause {
+    /// Unix timestamp in seconds the pause ends, None when payments aren't paused
+    until: Option<u64>,
+    /// The latest payment withheld from each neighbor, debt keeper asks for the whole debt every
+    /// time so this is what would be sent on resume
+    withheld: HashMap<Identity, Uint256>,
+}
+
+impl PaymentPause {
+    /// Ends the pause if it has run out, returns whether payments are still paused
+    fn check(&mut self, now: u64) -> bool {
+        match self.until {
+            Some(until) if now >= until => {
+                info!("Payment pause ran out, resuming payments");
+                self.resume();
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    fn resume(&mut self) {
+        for (to, amount) in self.withheld.drain() {
+            info!(
+                "Resuming payments, {} wei to {} was withheld",
+                amount, to.wg_public_key
+            );
+        }
+        self.until = None;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WithheldPayment {
+    pub to: Identity,
+    pub amount: Uint256,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PaymentPauseStatus {
+    pub paused: bool,
+    /// Unix timestamp in seconds payments resume on their own
+    pub until: Option<u64>,
+    /// What would be paid to each neighbor right now if payments weren't paused
+    pub withheld: Vec<WithheldPayment>,
+}
+
+fn with_pause<T>(f: impl FnOnce(&mut PaymentPause) -> T) -> T {
+    let netns = KI.check_integration_test_netns();
+    f(PAYMENT_PAUSE.write().unwrap().entry(netns).or_default())
+}
+
+fn status(pause: &mut PaymentPause) -> PaymentPauseStatus {
+    let paused = pause.check(now_secs());
+    PaymentPauseStatus {
+        paused,
+        until: pause.until,
+        withheld: pause
+            .withheld
+            .iter()
+            .map(|(to, amount)| WithheldPayment {
+                to: *to,
+                amount: *amount,
+            })
+            .collect(),
+    }
+}
+
+/// Pauses outgoing payments for payment.max_payment_pause seconds, pausing again restarts the clock
+pub fn pause_payments() -> PaymentPauseStatus {
+    let max = settings::get_rita_common().payment.max_payment_pause;
+    with_pause(|pause| {
+        let until = now_secs().saturating_add(max);
+        warn!(
+            "Outgoing payments paused from the dashboard until {}",
+            until
+        );
+        pause.until = Some(until);
+        status(pause)
+    })
+}
+
+pub fn resume_payments() -> PaymentPauseStatus {
+    with_pause(|pause| {
+        if pause.until.is_some() {
+            info!("Outgoing payments resumed from the dashboard");
+            pause.resume();
+        }
+        status(pause)
+    })
+}
+
+pub fn get_payment_pause_status() -> PaymentPauseStatus {
+    with_pause(status)
+}
+
+/// Checked by the payment controller each tick before sending
+pub fn payments_paused() -> bool {
+    with_pause(|pause| pause.check(now_secs()))
+}
+
+/// Logs and records a payment that would have been sent
+pub fn withhold_payment(pmt: &UnpublishedPaymentTx) {
+    info!(
+        "Payments are paused, not paying {} wei to {}",
+        pmt.amount, pmt.to.wg_public_key
+    );
+    with_pause(|pause| {
+        pause.withheld.insert(pmt.to, pmt.amount);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payment_pause() {
+        let neighbor = Identity::new(
+            "fd00::1".parse().unwrap(),
+            "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"
+                .parse()
+                .unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let mut pause = PaymentPause::default();
+        assert!(!pause.check(1000));
+
+        pause.until = Some(2000);
+        pause.withheld.insert(neighbor, 100u32.into());
+        // debt keeper asks for the whole debt, the latest ask replaces the last
+        pause.withheld.insert(neighbor, 150u32.into());
+        assert!(pause.check(1999));
+        assert_eq!(pause.withheld.get(&neighbor), Some(&150u32.into()));
+
+        // runs out on its own
+        assert!(!pause.check(2000));
+        assert_eq!(pause.until, None);
+        assert!(pause.withheld.is_empty());
+    }
+}
@@ -6,11 +6,24 @@
 //! attempt to validate these payments every 5 seconds, if successful the payment is sent
 //! off to debt keeper to be removed from the owed balance. Payments may time out after a
 //! configured period.
-
+//!
+//! Payments validated on eth based chains are remembered along with the block they were mined in
+//! until they are too old to be reorged. When the blockchain oracle sees a reorg deeper than
+//! BLOCKS_TO_CONFIRM each of them is looked up again, and those that are no longer in a block have
+//! their credit reversed in debt keeper and go back into the validation queue in case they are
+//! mined again.
+
+use crate::blockchain_oracle::get_oracle_last_seen_block;
+use crate::blockchain_oracle::take_oracle_reorg;
+use crate::debt_keeper::incoming_payment_reverted;
+use crate::debt_keeper::outgoing_payment_reverted;
 use crate::debt_keeper::payment_received;
 use crate::debt_keeper::payment_succeeded;
+use crate::events::{publish_event, RitaEvent};
+use crate::reconciliation::receipts::issue_receipt;
 use crate::rita_loop::fast_loop::FAST_LOOP_TIMEOUT;
 use crate::rita_loop::get_web3_server;
+use crate::usage_tracker::remove_reverted_payment;
 use crate::usage_tracker::update_payments;
 use crate::RitaCommonError;
 use crate::KI;
@@ -60,10 +73,12 @@ pub const PAYMENT_RECEIVE_TIMEOUT: Duration = Duration::from_secs(259200u64);
 /// submit after ALTHEA_L1_MICROTX_TIMEOUT blocks have elapsed, so there's no need to guess
 pub const ETH_PAYMENT_SEND_TIMEOUT: Duration = Duration::from_secs(600u64);
 /// How many blocks before we assume finality
-const BLOCKS_TO_CONFIRM: u32 = 4;
+pub const BLOCKS_TO_CONFIRM: u32 = 4;
 /// How old does a txid need to be before we don't accept it?
 /// this is 12 hours
 const BLOCKS_TO_OLD: u32 = 1440;
+/// How many transactions we look up on the full node at once
+const VALIDATE_BATCH_SIZE: usize = 10;
 
 // These parameters are used to set up a contact with althea chain
 pub const ALTHEA_CHAIN_PREFIX: &str = "althea";
@@ -125,6 +140,26 @@ pub struct ToValidate {
     /// actually not possible for the transaction to be included once this timeout has passed
     /// versus the recieved field which is just a guess
     pub timeout_block: Option<u64>,
+    /// Txids of the earlier broadcasts of this transaction, oldest first, set on gas price
+    /// replacements we send. Only one of them can ever be mined since they share a nonce
+    pub replaces: Vec<Uint256>,
+}
+
+/// Where a payment we validated on an eth based chain was mined
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ConfirmedPayment {
+    pub payment: PaymentTx,
+    pub block_number: Uint256,
+    pub block_hash: Uint256,
+    /// Paid to us rather than by us
+    pub incoming: bool,
+}
+
+impl ToValidate {
+    /// The txid of the first broadcast of this transaction, shared by all its replacements
+    fn original_txid(&self) -> Uint256 {
+        self.replaces.first().copied().unwrap_or(self.payment.txid)
+    }
 }
 
 // Ensure that duplicate txid are always treated as the same object
@@ -155,6 +190,10 @@ pub struct PaymentValidator {
     previously_sent_payments: HashMap<Identity, HashSet<PaymentTx>>,
     /// All successful txids TO this router that have been verified, used to check for duplicate payments
     successful_transactions: HashSet<PaymentTx>,
+    /// Successful eth payments in either direction by txid, until they are BLOCKS_TO_OLD deep
+    confirmed_payments: HashMap<Uint256, ConfirmedPayment>,
+    /// Set when a reorg was detected and cleared once every confirmed payment has been checked
+    reorg_check_pending: bool,
 }
 
 impl PaymentValidator {
@@ -163,6 +202,8 @@ impl PaymentValidator {
             unvalidated_transactions: HashSet::new(),
             previously_sent_payments: HashMap::new(),
             successful_transactions: HashSet::new(),
+            confirmed_payments: HashMap::new(),
+            reorg_check_pending: false,
         }
     }
 }
@@ -174,6 +215,17 @@ impl Default for PaymentValidator {
 }
 
 impl PaymentValidator {
+    /// Every transaction held, whether still being validated or kept to catch duplicates
+    pub fn tracked_transactions(&self) -> usize {
+        self.unvalidated_transactions.len()
+            + self
+                .previously_sent_payments
+                .values()
+                .map(|txs| txs.len())
+                .sum::<usize>()
+            + self.successful_transactions.len()
+    }
+
     /// Performs a sanity check of the Payment validator struct
     /// this checks that we do not have duplicate data anywhere in the struct
     /// returns true if the struct contains no duplicate data, false otherwise
@@ -273,6 +325,108 @@ impl PaymentValidator {
         }
     }
 
+    /// Payments mined more than BLOCKS_TO_OLD blocks ago are assumed to be beyond any reorg
+    fn prune_confirmed_payments(&mut self, latest_block: Uint256) {
+        self.confirmed_payments
+            .retain(|_, c| c.block_number + Uint256::from(BLOCKS_TO_OLD) >= latest_block);
+    }
+
+    /// Looks up every confirmed payment again after a reorg, reverting those that are no longer in a
+    /// block. Payments that couldn't be looked up are tried again next tick
+    async fn revalidate_confirmed_payments(&mut self) {
+        info!(
+            "Chain reorg detected, checking {} validated payments again",
+            self.confirmed_payments.len()
+        );
+        let web3 = Web3::new(&get_web3_server(), TRANSACTION_VERIFICATION_TIMEOUT);
+        let txids: Vec<Uint256> = self.confirmed_payments.keys().copied().collect();
+        let mut complete = true;
+        for batch in txids.chunks(VALIDATE_BATCH_SIZE) {
+            let lookups = batch
+                .iter()
+                .map(|txid| web3.eth_get_transaction_by_hash(*txid));
+            for (txid, lookup) in batch.iter().zip(join_all(lookups).await) {
+                let mined_in = match lookup {
+                    Ok(Some(transaction)) => {
+                        let (_, _, _, block_number, block_hash) =
+                            get_xdai_transaction_details(transaction);
+                        block_number.zip(block_hash)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!("Failed to look up payment {:#066x} {:?}", txid, e);
+                        complete = false;
+                        continue;
+                    }
+                };
+                match (mined_in, self.confirmed_payments.get_mut(txid)) {
+                    // mined again in the new chain, the credit stands
+                    (Some((block_number, block_hash)), Some(confirmed)) => {
+                        confirmed.block_number = block_number;
+                        confirmed.block_hash = block_hash;
+                    }
+                    (None, Some(_)) => self.revert_payment(*txid),
+                    (_, None) => {}
+                }
+            }
+        }
+        self.reorg_check_pending = !complete;
+    }
+
+    /// Reverses the credit and usage history record of a payment a reorg dropped and puts it back into
+    /// the validation queue, if it is mined again it counts again like any other payment
+    fn revert_payment(&mut self, txid: Uint256) {
+        let confirmed = match self.confirmed_payments.remove(&txid) {
+            Some(confirmed) => confirmed,
+            None => return,
+        };
+        let pmt = confirmed.payment;
+        warn!(
+            "Payment {:#066x} of {} wei was dropped by a reorg, reversing it",
+            txid, pmt.amount
+        );
+        let (counterparty, res) = if confirmed.incoming {
+            self.successful_transactions.remove(&pmt);
+            (pmt.from, incoming_payment_reverted(pmt.from, pmt.amount))
+        } else {
+            if let Some(txs) = self.previously_sent_payments.get_mut(&pmt.to) {
+                txs.remove(&pmt);
+            }
+            (pmt.to, outgoing_payment_reverted(pmt.to, pmt.amount))
+        };
+        if let Err(e) = res {
+            error!("Failed to reverse payment {:#066x} {:?}", txid, e);
+        }
+        remove_reverted_payment(txid);
+        publish_event(RitaEvent::PaymentReverted {
+            counterparty,
+            amount: pmt.amount,
+            txid,
+            incoming: confirmed.incoming,
+        });
+        let _ = self.add_to_validation_queue(ToValidate {
+            payment: pmt,
+            received: Instant::now(),
+            timeout_block: None,
+            replaces: Vec::new(),
+        });
+    }
+
+    /// Once one broadcast of a transaction we sent has been validated the others can never be mined,
+    /// returns those still waiting for validation
+    fn replaced_transactions(&self, removed: &[(ToValidate, bool)]) -> Vec<ToValidate> {
+        let validated: HashSet<Uint256> = removed
+            .iter()
+            .filter(|(_, success)| *success)
+            .map(|(tx, _)| tx.original_txid())
+            .collect();
+        self.unvalidated_transactions
+            .iter()
+            .filter(|tx| validated.contains(&tx.original_txid()))
+            .cloned()
+            .collect()
+    }
+
     /// Iterates the payment validator state, checking transactions for validity. If a transaction to this router
     /// is found to be valid it is removed from the unvalidated_transactions list and the debt keeper is updated
     /// if a transaction from this router is found to be valid it is removed from the unvalidated_transactions list
@@ -305,6 +459,19 @@ impl PaymentValidator {
         let our_address = settings::get_rita_common().payment.eth_address.unwrap();
         let mut to_delete = Vec::new();
 
+        // althea chain has instant finality
+        if chain != SystemChain::AltheaL1 {
+            if let Some(latest_block) = get_oracle_last_seen_block() {
+                self.prune_confirmed_payments(latest_block);
+            }
+            if take_oracle_reorg() {
+                self.reorg_check_pending = true;
+            }
+            if self.reorg_check_pending {
+                self.revalidate_confirmed_payments().await;
+            }
+        }
+
         // there's nothing to do, exit early
         if self.unvalidated_transactions.is_empty() {
             return self.previously_sent_payments.clone();
@@ -358,7 +525,6 @@ impl PaymentValidator {
         // becuase make_payments_v2 plays back the entire payment history of a node
         // in order to resync. This is batched to avoid issues with making too many
         // requests at once
-        const VALIDATE_BATCH_SIZE: usize = 10;
         let mut validation_results = Vec::new();
         let mut buf = Vec::new();
         for f in futs.into_iter() {
@@ -375,9 +541,13 @@ impl PaymentValidator {
 
         // take all validation results and add them to the to_delete list from the
         // timeout checking, so that we can process everything in one go
-        for (tx, success) in validation_results.into_iter().flatten() {
+        for (tx, success, confirmed) in validation_results.into_iter().flatten() {
             // transactions that have finished being procssed return a Some()
             // value and are removed from the queue.
+            if let Some(confirmed) = confirmed {
+                self.confirmed_payments
+                    .insert(confirmed.payment.txid, confirmed);
+            }
             to_delete.push((tx, success));
         }
 
@@ -386,8 +556,16 @@ impl PaymentValidator {
         // Messaging to debt keeper and usage tracker is done within the validate
         // functions themselves
         for (tx, success) in to_delete.iter() {
+            // debt keeper has credited validated payments to us by now, the payer gets a receipt
+            if *success && tx.payment.from.eth_address != our_address {
+                issue_receipt(&tx.payment);
+            }
             self.remove(tx.clone(), our_address, *success)
         }
+        for tx in self.replaced_transactions(&to_delete) {
+            info!("Transaction {} was replaced and won't be mined", tx);
+            self.remove(tx, our_address, false)
+        }
 
         // we return our list of sent payments this is passed to payment_controller
         // so that it can be played back to other nodes as part of make_payment_v2
@@ -398,9 +576,15 @@ impl PaymentValidator {
 }
 
 /// This wrapper function handles validating a transaction on either Althea or Xdai based on the system chain
-async fn validate_transaction(ts: ToValidate, chain: SystemChain) -> Option<(ToValidate, bool)> {
+/// Also returns where successful payments on eth based chains were mined
+async fn validate_transaction(
+    ts: ToValidate,
+    chain: SystemChain,
+) -> Option<(ToValidate, bool, Option<ConfirmedPayment>)> {
     match chain {
-        SystemChain::AltheaL1 => handle_althea_tx_checking(ts.clone()).await,
+        SystemChain::AltheaL1 => handle_althea_tx_checking(ts.clone())
+            .await
+            .map(|(ts, success)| (ts, success, None)),
         SystemChain::Xdai | SystemChain::Ethereum | SystemChain::Sepolia => {
             handle_xdai_tx_checking(ts.clone()).await
         }
@@ -675,7 +859,9 @@ fn decode_althea_microtx(response: GetTxResponse) -> Vec<MsgMicrotx> {
 /// and then checking the results to determine if the transaction is valid. If the transaction
 /// is valid or invalid Some(true) or Some(false) respectively is returned. If the transaction
 /// is still pending None is returned.
-async fn handle_xdai_tx_checking(ts: ToValidate) -> Option<(ToValidate, bool)> {
+async fn handle_xdai_tx_checking(
+    ts: ToValidate,
+) -> Option<(ToValidate, bool, Option<ConfirmedPayment>)> {
     let full_node = get_web3_server();
     let web3 = Web3::new(&full_node, TRANSACTION_VERIFICATION_TIMEOUT);
 
@@ -694,43 +880,54 @@ async fn handle_xdai_tx_checking(ts: ToValidate) -> Option<(ToValidate, bool)> {
     }
 }
 
+/// Returns to, from, value, block number and block hash
 fn get_xdai_transaction_details(
     transaction: TransactionResponse,
-) -> (Option<Address>, Address, Uint256, Option<Uint256>) {
+) -> (
+    Option<Address>,
+    Address,
+    Uint256,
+    Option<Uint256>,
+    Option<Uint256>,
+) {
     match transaction {
         TransactionResponse::Eip1559 {
             to,
             from,
             value,
             block_number,
+            block_hash,
             ..
-        } => (to, from, value, block_number),
+        } => (to, from, value, block_number, block_hash),
         TransactionResponse::Eip2930 {
             to,
             from,
             value,
             block_number,
+            block_hash,
             ..
-        } => (to, from, value, block_number),
+        } => (to, from, value, block_number, block_hash),
         TransactionResponse::Legacy {
             to,
             from,
             value,
             block_number,
+            block_hash,
             ..
-        } => (to, from, value, block_number),
+        } => (to, from, value, block_number, block_hash),
     }
 }
 
 /// This function is used to validate transactions both incoming and outgoing, it must reject any payment
 /// that is not correct and returns the payment and a boolean indicating if it was successful, if we do not
-/// yet know if the payment was successful we return None
+/// yet know if the payment was successful we return None. Successful payments come with the block they
+/// were mined in
 fn handle_tx_messaging_xdai(
     txid: Uint256,
     transaction: TransactionResponse,
     ts: ToValidate,
     current_block: Uint256,
-) -> Option<(ToValidate, bool)> {
+) -> Option<(ToValidate, bool, Option<ConfirmedPayment>)> {
     let from_address = ts.payment.from.eth_address;
     let amount = ts.payment.amount;
     let pmt = ts.payment;
@@ -739,13 +936,25 @@ fn handle_tx_messaging_xdai(
         .eth_address
         .expect("No Address!");
 
-    let (tx_to, tx_from, tx_value, tx_block_number) = get_xdai_transaction_details(transaction);
+    let (tx_to, tx_from, tx_value, tx_block_number, tx_block_hash) =
+        get_xdai_transaction_details(transaction);
+    // where this was mined, for successful payments
+    let confirmed = |incoming: bool| {
+        tx_block_number
+            .zip(tx_block_hash)
+            .map(|(block_number, block_hash)| ConfirmedPayment {
+                payment: pmt,
+                block_number,
+                block_hash,
+                incoming,
+            })
+    };
 
     let to = match tx_to {
         Some(val) => val,
         None => {
             error!("Invalid TX! No destination!");
-            return Some((ts, false));
+            return Some((ts, false, None));
         }
     };
 
@@ -760,12 +969,12 @@ fn handle_tx_messaging_xdai(
 
     if !value_correct {
         error!("Transaction with invalid amount!");
-        return Some((ts, false));
+        return Some((ts, false, None));
     }
 
     if is_old {
         error!("Transaction is more than 6 hours old! {:#066x}", txid);
-        return Some((ts, false));
+        return Some((ts, false, None));
     }
 
     match (to_us, from_us, is_in_chain) {
@@ -788,7 +997,7 @@ fn handle_tx_messaging_xdai(
             // update the usage tracker with the details of this payment
             update_payments(pmt);
 
-            Some((ts, true))
+            Some((ts, true, confirmed(true)))
         }
         // we successfully paid someone
         (false, true, true) => {
@@ -809,15 +1018,15 @@ fn handle_tx_messaging_xdai(
             // update the usage tracker with the details of this payment
             update_payments(pmt);
 
-            Some((ts, true))
+            Some((ts, true, confirmed(false)))
         }
         (true, true, _) => {
             error!("Transaction to ourselves!");
-            Some((ts, false))
+            Some((ts, false, None))
         }
         (false, false, _) => {
             error!("Transaction has nothing to do with us?");
-            Some((ts, false))
+            Some((ts, false, None))
         }
         (_, _, false) => {
             //transaction waiting for validation, do nothingi
@@ -891,6 +1100,7 @@ mod tests {
             payment: tx,
             received: Instant::now(),
             timeout_block: None,
+            replaces: Vec::new(),
         }
     }
 
@@ -947,6 +1157,93 @@ mod tests {
         assert_eq!(validator.previously_sent_payments.len(), 1);
     }
 
+    /// payments dropped by a reorg go back into the validation queue
+    #[test]
+    fn test_revert_payment() {
+        settings::set_rita_client(settings::client::RitaClientSettings::default());
+        let mut validator = PaymentValidator::new();
+        let incoming = generate_fake_payment();
+        let outgoing = generate_fake_payment();
+        let our_address = outgoing.payment.from.eth_address;
+        for (tx, is_incoming) in [(&incoming, true), (&outgoing, false)] {
+            validator.add_to_validation_queue(tx.clone()).unwrap();
+            validator.remove(tx.clone(), our_address, true);
+            validator.confirmed_payments.insert(
+                tx.payment.txid,
+                ConfirmedPayment {
+                    payment: tx.payment,
+                    block_number: 100u8.into(),
+                    block_hash: 1u8.into(),
+                    incoming: is_incoming,
+                },
+            );
+            update_payments(tx.payment);
+        }
+        assert_eq!(validator.successful_transactions.len(), 1);
+        let in_usage_history = |txid: Uint256| {
+            let txid = format!("{txid:#066x}");
+            crate::usage_tracker::get_payments_data()
+                .iter()
+                .any(|hour| hour.payments.iter().any(|p| p.txid == txid))
+        };
+        assert!(in_usage_history(incoming.payment.txid));
+
+        validator.revert_payment(incoming.payment.txid);
+        validator.revert_payment(outgoing.payment.txid);
+        assert!(!in_usage_history(incoming.payment.txid));
+        assert!(!in_usage_history(outgoing.payment.txid));
+        assert!(validator.successful_transactions.is_empty());
+        assert!(validator
+            .previously_sent_payments
+            .values()
+            .all(|txs| txs.is_empty()));
+        assert!(validator.confirmed_payments.is_empty());
+        assert_eq!(validator.unvalidated_transactions.len(), 2);
+        assert!(validator.is_consistent());
+
+        // old enough payments are forgotten
+        validator.confirmed_payments.insert(
+            incoming.payment.txid,
+            ConfirmedPayment {
+                payment: incoming.payment,
+                block_number: 100u8.into(),
+                block_hash: 1u8.into(),
+                incoming: true,
+            },
+        );
+        validator.prune_confirmed_payments(Uint256::from(100 + BLOCKS_TO_OLD));
+        assert_eq!(validator.confirmed_payments.len(), 1);
+        validator.prune_confirmed_payments(Uint256::from(101 + BLOCKS_TO_OLD));
+        assert!(validator.confirmed_payments.is_empty());
+    }
+
+    /// validating any broadcast of a replaced transaction drops the others
+    #[test]
+    fn test_replaced_transactions() {
+        let mut validator = PaymentValidator::new();
+        let unrelated = generate_fake_payment();
+        let original = generate_fake_payment();
+        let mut replacement = generate_fake_payment();
+        replacement.replaces = vec![original.payment.txid];
+        let mut second_replacement = generate_fake_payment();
+        second_replacement.replaces = vec![original.payment.txid, replacement.payment.txid];
+        for tx in [&unrelated, &original, &replacement, &second_replacement] {
+            validator.add_to_validation_queue(tx.clone()).unwrap();
+        }
+
+        let our_address = replacement.payment.from.eth_address;
+        validator.remove(replacement.clone(), our_address, true);
+        let replaced = validator.replaced_transactions(&[(replacement, true)]);
+        assert_eq!(replaced.len(), 2);
+        assert!(replaced.contains(&original));
+        assert!(replaced.contains(&second_replacement));
+
+        // failures don't say anything about the other broadcasts
+        assert!(validator
+            .replaced_transactions(&[(original, false)])
+            .is_empty());
+    }
+
     #[test]
     #[should_panic]
     fn test_double_remove() {
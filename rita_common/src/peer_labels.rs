@@ -0,0 +1,137 @@
+//! Neighbors only know each other by wg key and mesh ip, which makes for an unreadable neighbors page.
+//! Nodes that opt in with network.share_device_info answer /peer_label on the contact port with their
+//! device model and nickname. The slow loop asks every neighbor for theirs once every LABEL_REFRESH and
+//! keeps the answers in memory for the dashboard, nodes that don't share or predate this just have no label.
+
+use crate::tunnel_manager::tm_get_neighbors;
+use crate::RitaCommonError;
+use actix_web_async::{HttpRequest, HttpResponse};
+use althea_types::{Identity, PeerLabel, WgKey};
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often each neighbor is asked for its label
+pub const LABEL_REFRESH: Duration = Duration::from_secs(3600);
+const LABEL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Labels are shown as is on the dashboard, anything longer is cut off
+pub const MAX_LABEL_LEN: usize = 64;
+
+lazy_static! {
+    /// The last label each neighbor answered with, None if it didn't answer, and when we asked
+    static ref PEER_LABELS: Arc<RwLock<HashMap<WgKey, (Option<PeerLabel>, Instant)>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// Cuts a neighbor supplied string to MAX_LABEL_LEN characters and drops control characters
+fn clean_label(label: Option<String>) -> Option<String> {
+    let label: String = label?
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_LABEL_LEN)
+        .collect();
+    let label = label.trim().to_string();
+    if label.is_empty() {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+/// What we tell neighbors about ourselves
+pub fn our_peer_label() -> PeerLabel {
+    let common = settings::get_rita_common_snapshot();
+    let network = common.network();
+    if !network.share_device_info {
+        return PeerLabel::default();
+    }
+    PeerLabel {
+        device: network.device.clone(),
+        nickname: network.nickname.map(|n| n.to_string()),
+    }
+}
+
+pub async fn get_peer_label(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(our_peer_label())
+}
+
+/// The label a neighbor last gave us, if it shares one
+pub fn get_neighbor_label(key: &WgKey) -> Option<PeerLabel> {
+    PEER_LABELS
+        .read()
+        .unwrap()
+        .get(key)
+        .and_then(|(label, _)| label.clone())
+}
+
+async fn request_label(neighbor: Identity) -> Result<PeerLabel, RitaCommonError> {
+    let url = format!(
+        "http://[{}]:{}/peer_label",
+        neighbor.mesh_ip,
+        settings::get_rita_common_snapshot()
+            .network()
+            .rita_contact_port
+    );
+    let client = awc::Client::default();
+    let mut response = client.get(url).timeout(LABEL_TIMEOUT).send().await?;
+    let label: PeerLabel = response.json().await?;
+    Ok(PeerLabel {
+        device: clean_label(label.device),
+        nickname: clean_label(label.nickname),
+    })
+}
+
+/// Called from the slow loop, asks neighbors we have no recent label for and forgets the labels of
+/// nodes that are no longer neighbors
+pub async fn tick_peer_labels() {
+    let mut neighbors: HashMap<WgKey, Identity> = HashMap::new();
+    for neighbor in tm_get_neighbors() {
+        let id = neighbor.identity.global;
+        neighbors.insert(id.wg_public_key, id);
+    }
+    let stale: Vec<Identity> = {
+        let mut labels = PEER_LABELS.write().unwrap();
+        labels.retain(|key, _| neighbors.contains_key(key));
+        neighbors
+            .values()
+            .filter(|id| match labels.get(&id.wg_public_key) {
+                Some((_, asked)) => asked.elapsed() > LABEL_REFRESH,
+                None => true,
+            })
+            .copied()
+            .collect()
+    };
+    // asked all at once so that a few silent neighbors don't hold up the slow loop for their timeouts
+    let answers = join_all(stale.iter().map(|neighbor| request_label(*neighbor))).await;
+    let mut labels = PEER_LABELS.write().unwrap();
+    for (neighbor, answer) in stale.into_iter().zip(answers) {
+        let label = match answer {
+            Ok(label) => Some(label),
+            Err(e) => {
+                trace!("No label from neighbor {} {:?}", neighbor.wg_public_key, e);
+                None
+            }
+        };
+        labels.insert(neighbor.wg_public_key, (label, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_label() {
+        assert_eq!(clean_label(None), None);
+        assert_eq!(clean_label(Some("  ".to_string())), None);
+        assert_eq!(
+            clean_label(Some("GL-B1300\n".to_string())),
+            Some("GL-B1300".to_string())
+        );
+        assert_eq!(
+            clean_label(Some("x".repeat(100))).unwrap().len(),
+            MAX_LABEL_LEN
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Detects mesh loops, which almost always mean a peer interface has been bridged to another one, for
+//! example a WAN port plugged into the same switch as a mesh port. Either we hear our own ImHere on a
+//! different interface than the one it was sent from, or a Hello arrives carrying our own wg key. Either
+//! only counts when the packet's source address is one of our own link local addresses, anyone can put
+//! our ip or wg key in a packet. Once a loop has been seen LOOP_DETECTIONS times within DETECTION_WINDOW
+//! the interface it arrived on is quarantined, the peer listener stops listening on it so no tunnels
+//! are made over the loop, and a critical notification is put in the dashboard inbox. Quarantine doubles
+//! in length each time the same interface loops again so that a loop nobody fixes doesn't fill the inbox.
+
+use crate::notifications::{add_notification, NotificationSeverity};
+use althea_types::unix_time::now_secs;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How long an interface is quarantined the first time it loops
+pub const BASE_QUARANTINE: Duration = Duration::from_secs(600);
+/// Quarantine never lasts longer than this however often the interface loops
+pub const MAX_QUARANTINE: Duration = Duration::from_secs(86400);
+/// How many times a loop has to be seen on an interface before it is quarantined
+pub const LOOP_DETECTIONS: usize = 3;
+/// Detections older than this don't count towards LOOP_DETECTIONS
+pub const DETECTION_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref QUARANTINE: Arc<RwLock<HashMap<String, QuarantinedInterface>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+    /// Unix times in seconds of recent loop detections by interface
+    static ref DETECTIONS: Arc<RwLock<HashMap<String, Vec<u64>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedInterface {
+    pub ifname: String,
+    pub reason: String,
+    /// How many times this interface has looped since rita started
+    pub strikes: u32,
+    /// Unix time in seconds of the last detection
+    pub since: u64,
+    /// Unix time in seconds the quarantine ends
+    pub until: u64,
+}
+
+impl QuarantinedInterface {
+    pub fn is_active(&self, now: u64) -> bool {
+        now < self.until
+    }
+}
+
+/// Quarantine length for the given strike, doubling from BASE_QUARANTINE up to MAX_QUARANTINE
+pub fn quarantine_duration(strikes: u32) -> Duration {
+    let factor = 1u32
+        .checked_shl(strikes.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    BASE_QUARANTINE
+        .checked_mul(factor)
+        .unwrap_or(MAX_QUARANTINE)
+        .min(MAX_QUARANTINE)
+}
+
+/// If an ImHere received on `received_on` from `source` carries the link local ip of another one of
+/// our own interfaces, and was really sent from it, returns that interface. Hearing our own ImHere on
+/// the interface that sent it is normal
+pub fn own_im_here_source(
+    received_on: &str,
+    source: IpAddr,
+    ip: Ipv6Addr,
+    own_ips: &HashMap<String, Ipv6Addr>,
+) -> Option<String> {
+    if source != IpAddr::V6(ip) {
+        return None;
+    }
+    own_ips
+        .iter()
+        .find(|(ifname, own_ip)| **own_ip == ip && ifname.as_str() != received_on)
+        .map(|(ifname, _)| ifname.clone())
+}
+
+/// If a packet came from one of our own link local addresses
+pub fn is_own_source(source: IpAddr, own_ips: &HashMap<String, Ipv6Addr>) -> bool {
+    own_ips.values().any(|ip| IpAddr::V6(*ip) == source)
+}
+
+/// Records a detection at `now` and returns true once there have been LOOP_DETECTIONS within
+/// DETECTION_WINDOW, clearing them for next time
+fn note_detection(detections: &mut Vec<u64>, now: u64) -> bool {
+    detections.retain(|t| now.saturating_sub(*t) < DETECTION_WINDOW.as_secs());
+    detections.push(now);
+    if detections.len() >= LOOP_DETECTIONS {
+        detections.clear();
+        true
+    } else {
+        false
+    }
+}
+
+pub fn is_quarantined(ifname: &str) -> bool {
+    let now = now_secs();
+    QUARANTINE
+        .read()
+        .unwrap()
+        .get(ifname)
+        .map(|q| q.is_active(now))
+        .unwrap_or(false)
+}
+
+/// Notes a mesh loop seen on an interface, quarantines it and tells the user about it once it has
+/// been seen LOOP_DETECTIONS times within DETECTION_WINDOW. Does nothing if the interface is already
+/// quarantined
+pub fn quarantine_interface(ifname: &str, reason: String) {
+    let now = now_secs();
+    if is_quarantined(ifname) {
+        return;
+    }
+    let confirmed = note_detection(
+        DETECTIONS
+            .write()
+            .unwrap()
+            .entry(ifname.to_string())
+            .or_default(),
+        now,
+    );
+    if !confirmed {
+        warn!("Possible mesh loop on {}: {}", ifname, reason);
+        return;
+    }
+    let mut quarantine = QUARANTINE.write().unwrap();
+    let entry = quarantine
+        .entry(ifname.to_string())
+        .or_insert_with(|| QuarantinedInterface {
+            ifname: ifname.to_string(),
+            reason: String::new(),
+            strikes: 0,
+            since: 0,
+            until: 0,
+        });
+    if entry.is_active(now) {
+        return;
+    }
+    entry.strikes = entry.strikes.saturating_add(1);
+    let duration = quarantine_duration(entry.strikes);
+    entry.reason = reason;
+    entry.since = now;
+    entry.until = now + duration.as_secs();
+    error!(
+        "Mesh loop detected on {}: {}, not peering on it for {}s",
+        ifname,
+        entry.reason,
+        duration.as_secs()
+    );
+    add_notification(
+        NotificationSeverity::Critical,
+        "mesh_loop",
+        format!(
+            "A network loop was detected on port {ifname}: {}. This usually means a cable connects \
+             this port to another port of the same router, for example through a switch. Meshing on \
+             {ifname} is paused for {} minutes, check the cabling",
+            entry.reason,
+            duration.as_secs() / 60
+        ),
+    );
+}
+
+/// Every interface that has looped, with whether it is still quarantined given by `until`
+pub fn get_quarantined_interfaces() -> Vec<QuarantinedInterface> {
+    let mut list: Vec<QuarantinedInterface> =
+        QUARANTINE.read().unwrap().values().cloned().collect();
+    list.sort_by(|a, b| a.ifname.cmp(&b.ifname));
+    list
+}
+
+/// Ends the quarantine of an interface early once the user has fixed the cabling, returns false if
+/// it wasn't quarantined
+pub fn release_interface(ifname: &str) -> bool {
+    let now = now_secs();
+    match QUARANTINE.write().unwrap().get_mut(ifname) {
+        Some(q) if q.is_active(now) => {
+            info!("Mesh loop quarantine on {} released by the user", ifname);
+            q.until = now;
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_own_im_here_source() {
+        let mut own_ips = HashMap::new();
+        own_ips.insert("eth0".to_string(), "fe80::1".parse().unwrap());
+        own_ips.insert("eth1".to_string(), "fe80::2".parse().unwrap());
+        let peer: IpAddr = "fe80::3".parse().unwrap();
+        // our own multicast coming back on the same port
+        assert_eq!(
+            own_im_here_source(
+                "eth0",
+                "fe80::1".parse().unwrap(),
+                "fe80::1".parse().unwrap(),
+                &own_ips
+            ),
+            None
+        );
+        // a peer
+        assert_eq!(
+            own_im_here_source("eth0", peer, "fe80::3".parse().unwrap(), &own_ips),
+            None
+        );
+        // eth0 and eth1 are bridged
+        assert_eq!(
+            own_im_here_source(
+                "eth0",
+                "fe80::2".parse().unwrap(),
+                "fe80::2".parse().unwrap(),
+                &own_ips
+            ),
+            Some("eth1".to_string())
+        );
+        // a peer claiming to be eth1 is not a loop
+        assert_eq!(
+            own_im_here_source("eth0", peer, "fe80::2".parse().unwrap(), &own_ips),
+            None
+        );
+        assert!(is_own_source("fe80::2".parse().unwrap(), &own_ips));
+        assert!(!is_own_source(peer, &own_ips));
+    }
+
+    #[test]
+    fn test_note_detection() {
+        let mut detections = Vec::new();
+        assert!(!note_detection(&mut detections, 1000));
+        assert!(!note_detection(&mut detections, 1010));
+        // the first one has aged out
+        assert!(!note_detection(&mut detections, 1065));
+        assert!(note_detection(&mut detections, 1070));
+        // and counting starts over
+        assert!(detections.is_empty());
+        assert!(!note_detection(&mut detections, 1075));
+    }
+
+    #[test]
+    fn test_quarantine_duration() {
+        assert_eq!(quarantine_duration(1), BASE_QUARANTINE);
+        assert_eq!(quarantine_duration(2), BASE_QUARANTINE * 2);
+        assert_eq!(quarantine_duration(4), BASE_QUARANTINE * 8);
+        assert_eq!(quarantine_duration(20), MAX_QUARANTINE);
+        assert_eq!(quarantine_duration(200), MAX_QUARANTINE);
+    }
+}
@@ -1,5 +1,5 @@
-use althea_types::LocalIdentity;
-use bincode;
+use althea_types::{Extensions, LocalIdentity};
+use bincode::Options;
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::BufMut;
 use serde_derive::{Deserialize, Serialize};
@@ -52,6 +52,20 @@ impl From<io::Error> for MessageError {
 const MSG_IM_HERE: u8 = 0x5b;
 const MSG_IM_HERE_LEN: u16 = 19;
 const MSG_HELLO: u8 = 0x6c;
+/// Magic <u8> and Size <u16>
+const MSG_HEADER_LEN: u16 = 3;
+/// Starts the extensions block that follows the bincode payload of a hello. Older nodes decode the
+/// payload with trailing bytes allowed, so they never see it
+const MSG_HELLO_EXTENSIONS: u8 = 0xe7;
+
+/// The bincode options hellos have always been encoded with, bincode::serialize's defaults, limited to
+/// the size of the packet so that a bogus length prefix can't make us allocate more than we received
+fn hello_options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
 
 /**
  * An enum that contains all supported p2p packets
@@ -65,6 +79,9 @@ pub enum PeerMessage {
         my_id: Box<LocalIdentity>,
         response: bool,
         sender_wgport: u16,
+        /// Sent after the bincode payload rather than in it, see MSG_HELLO_EXTENSIONS
+        #[serde(skip)]
+        extensions: Extensions,
     },
 }
 
@@ -73,6 +90,7 @@ impl PeerMessage {
      * Encode an ImHere or Hello message
      * Message format is very simple
      * Magic <u8>, Size <u16>, Payload (Ipaddr &[u16; 8] for ImHere)
+     * A Hello with extensions has them after the payload, MSG_HELLO_EXTENSIONS <u8>, Extensions
      */
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
@@ -89,10 +107,9 @@ impl PeerMessage {
                 buf
             }
 
-            //This is a PeerMessage::Hello{ }
-            _ => {
+            PeerMessage::Hello { ref extensions, .. } => {
                 buf.put_u8(MSG_HELLO);
-                let encoded_hello = match bincode::serialize(self) {
+                let mut encoded_hello = match bincode::serialize(self) {
                     Ok(a) => a,
                     Err(_) => {
                         info!(
@@ -101,7 +118,22 @@ impl PeerMessage {
                         return Vec::new();
                     }
                 };
-                let buf_len: u16 = 1 + 2 + encoded_hello.len() as u16;
+                if !extensions.is_empty() {
+                    match bincode::serialize(extensions) {
+                        Ok(encoded) => {
+                            encoded_hello.push(MSG_HELLO_EXTENSIONS);
+                            encoded_hello.extend_from_slice(&encoded);
+                        }
+                        Err(e) => warn!("Unable to serialize hello extensions {:?}", e),
+                    }
+                }
+                let buf_len = match u16::try_from(encoded_hello.len() + MSG_HEADER_LEN as usize) {
+                    Ok(len) => len,
+                    Err(_) => {
+                        error!("Hello too large to encode, returning empty buffer");
+                        return Vec::new();
+                    }
+                };
                 buf.put_u16(buf_len);
                 for i in encoded_hello.iter() {
                     buf.put_u8(*i);
@@ -112,7 +144,8 @@ impl PeerMessage {
         }
     }
     /**
-     * Decode buffer of data into a ImHere message
+     * Decode buffer of data into a ImHere or Hello message, bytes past the size in the header are ignored.
+     * Never panics on malformed input, the buffer comes straight off the network
      * Message format is very simple
      * Magic <u8>, Size <u16>, Payload (Ipaddr &[u16; 8] for ImHere)
      */
@@ -168,18 +201,31 @@ impl PeerMessage {
             }
 
             MSG_HELLO => {
-                let _packet_size = pointer.read_u16::<BigEndian>()?;
-
-                // First 3 bytes are overhead (Magic <u8>, Size <u16>)
-                let des_buf = &buf[3..];
-                let hello_peer_message = match bincode::deserialize(des_buf) {
-                    Ok(a) => a,
-                    Err(_) => {
-                        return Err(MessageError::DeserializationError);
-                    }
-                };
+                let packet_size = pointer.read_u16::<BigEndian>()?;
+                if packet_size < MSG_HEADER_LEN || packet_size as usize > buf.len() {
+                    trace!(
+                        "Received a Hello packet with an invalid size: {:?}",
+                        packet_size
+                    );
+                    return Err(MessageError::BufferUnderflow);
+                }
 
-                Ok(hello_peer_message)
+                let mut des_buf = &buf[MSG_HEADER_LEN as usize..packet_size as usize];
+                match hello_options(des_buf.len() as u64).deserialize_from(&mut des_buf) {
+                    Ok(PeerMessage::Hello {
+                        my_id,
+                        response,
+                        sender_wgport,
+                        ..
+                    }) => Ok(PeerMessage::Hello {
+                        my_id,
+                        response,
+                        sender_wgport,
+                        extensions: decode_hello_extensions(des_buf),
+                    }),
+                    // an ImHere smuggled in as a hello would skip the address checks above
+                    Ok(PeerMessage::ImHere(_)) | Err(_) => Err(MessageError::DeserializationError),
+                }
             }
             _ => {
                 trace!("Received packet with an unknown magic: {:X?}", packet_magic);
@@ -189,6 +235,22 @@ impl PeerMessage {
     }
 }
 
+/// The extensions after a hello's payload, a bad block only costs the extensions and not the hello
+fn decode_hello_extensions(rest: &[u8]) -> Extensions {
+    match rest.split_first() {
+        Some((&MSG_HELLO_EXTENSIONS, block)) => {
+            match hello_options(block.len() as u64).deserialize(block) {
+                Ok(extensions) => extensions,
+                Err(e) => {
+                    trace!("Ignoring bad hello extensions {:?}", e);
+                    Extensions::default()
+                }
+            }
+        }
+        _ => Extensions::default(),
+    }
+}
+
 #[test]
 fn test_encode_im_here() {
     let data = PeerMessage::ImHere(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xc00a, 0x2ff)).encode();
@@ -330,6 +392,7 @@ fn test_encoded_hello_size() {
         my_id: Box::new(hello_struct.my_id),
         response: hello_struct.response,
         sender_wgport: hello_struct.my_id.wg_port,
+        extensions: Extensions::default(),
     };
     let result = PeerMessage::encode(&res);
 
@@ -388,6 +451,7 @@ fn test_hello_encode_decode() {
         my_id: Box::new(hello_struct.my_id),
         response: hello_struct.response,
         sender_wgport: s_wgport,
+        extensions: Extensions::default(),
     };
     let result = PeerMessage::encode(&res).to_vec();
 
@@ -399,6 +463,7 @@ fn test_hello_encode_decode() {
             my_id,
             response,
             sender_wgport,
+            ..
         } => {
             assert_eq!(my_id, Box::new(hello_struct.my_id));
             assert_eq!(response, hello_struct.response);
@@ -451,6 +516,7 @@ fn test_deserialize_with_wrong_serialization() {
         my_id: Box::new(hello_struct.my_id),
         response: hello_struct.response,
         sender_wgport: hello_struct.my_id.wg_port,
+        extensions: Extensions::default(),
     };
     let mut result = PeerMessage::encode(&res);
 
@@ -464,3 +530,155 @@ fn test_deserialize_with_wrong_serialization() {
         Err(_) => panic!("Wrong Error"),
     };
 }
+
+#[cfg(test)]
+fn get_test_hello() -> PeerMessage {
+    use althea_types::Identity;
+    use althea_types::WgKey;
+    use clarity::Address;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    let address: [u8; 20] = [0x42; 20];
+    let wgkey = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk=";
+    PeerMessage::Hello {
+        my_id: Box::new(LocalIdentity {
+            global: Identity::new(
+                IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)),
+                Address::from_slice(&address).unwrap(),
+                WgKey::from_str(wgkey).unwrap(),
+                Some(arrayvec::ArrayString::from("nickname").unwrap()),
+            ),
+            wg_port: 0x3b23,
+            have_tunnel: Some(true),
+        }),
+        response: false,
+        sender_wgport: 0x1232,
+        extensions: Extensions::default(),
+    }
+}
+
+#[test]
+fn test_decode_hello_with_trailing_bytes() {
+    let hello = get_test_hello();
+    let mut data = hello.encode();
+    // the receive buffer is larger than the packet
+    data.extend_from_slice(&[0; 100]);
+    assert_eq!(PeerMessage::decode(&data).unwrap(), hello);
+}
+
+#[test]
+fn test_hello_extensions() {
+    use althea_types::{MAX_EXTENSIONS, MAX_EXTENSIONS_BYTES};
+
+    let mut hello = get_test_hello();
+    if let PeerMessage::Hello {
+        ref mut extensions, ..
+    } = hello
+    {
+        let value_len = MAX_EXTENSIONS_BYTES / MAX_EXTENSIONS - 1;
+        for i in 0..MAX_EXTENSIONS {
+            extensions
+                .insert(i.to_string(), vec![0xab; value_len])
+                .unwrap();
+        }
+    }
+    let mut data = hello.encode();
+    // older nodes receive hellos into a 500 byte buffer
+    assert!(data.len() < 500);
+    assert_eq!(PeerMessage::decode(&data).unwrap(), hello);
+
+    // older nodes decode the payload and ignore the extensions
+    let payload = &data[MSG_HEADER_LEN as usize..];
+    match hello_options(payload.len() as u64).deserialize(payload) {
+        Ok(PeerMessage::Hello {
+            sender_wgport,
+            extensions,
+            ..
+        }) => {
+            assert_eq!(sender_wgport, 0x1232);
+            assert!(extensions.is_empty());
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+
+    // a corrupt extensions block costs only the extensions
+    let last = data.len() - 1;
+    data.truncate(last);
+    let size = data.len() as u16;
+    data[1..3].copy_from_slice(&size.to_be_bytes());
+    match PeerMessage::decode(&data) {
+        Ok(PeerMessage::Hello { extensions, .. }) => assert!(extensions.is_empty()),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_decode_hello_with_bad_size() {
+    let mut data = get_test_hello().encode();
+    let too_long = data.len() as u16 + 1;
+    data[1..3].copy_from_slice(&too_long.to_be_bytes());
+    match PeerMessage::decode(&data) {
+        Err(MessageError::BufferUnderflow) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+    data[1..3].copy_from_slice(&1u16.to_be_bytes());
+    match PeerMessage::decode(&data) {
+        Err(MessageError::BufferUnderflow) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_decode_imhere_inside_hello() {
+    // a loopback ImHere wrapped in hello framing must not get past the address checks
+    let im_here = bincode::serialize(&PeerMessage::ImHere(Ipv6Addr::LOCALHOST)).unwrap();
+    let mut data = vec![MSG_HELLO];
+    data.extend_from_slice(&(im_here.len() as u16 + MSG_HEADER_LEN).to_be_bytes());
+    data.extend_from_slice(&im_here);
+    match PeerMessage::decode(&data) {
+        Err(MessageError::DeserializationError) => (),
+        other => panic!("Unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_fuzz_decode() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let valid = [
+        get_test_hello().encode(),
+        PeerMessage::ImHere(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)).encode(),
+    ];
+    for packet in valid.iter() {
+        for _ in 0..5000 {
+            let mut mutated = packet.clone();
+            match rng.gen_range(0..3) {
+                0 => {
+                    for _ in 0..rng.gen_range(1..8) {
+                        let i = rng.gen_range(0..mutated.len());
+                        mutated[i] = rng.gen();
+                    }
+                }
+                1 => mutated.truncate(rng.gen_range(0..packet.len())),
+                // huge length prefixes inside the bincode payload
+                _ => {
+                    let i = rng.gen_range(3..mutated.len());
+                    for byte in mutated.iter_mut().skip(i).take(8) {
+                        *byte = 0xff;
+                    }
+                }
+            }
+            let _ = PeerMessage::decode(&mutated);
+        }
+    }
+    for _ in 0..5000 {
+        let len = rng.gen_range(0..600);
+        let mut junk: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        if let Some(first) = junk.first_mut() {
+            *first = if rng.gen() { MSG_HELLO } else { MSG_IM_HERE };
+        }
+        let _ = PeerMessage::decode(&junk);
+    }
+}
@@ -6,11 +6,16 @@
 //! rita_loop iteration we send out our own IP as a UDP broadcast packet and then get our peers
 //! off the queue. These are turned into Peer structs which are passed to TunnelManager to do
 //! whatever remaining work there may be.
+pub mod loop_detection;
 pub mod message;
 
+use self::loop_detection::{
+    is_own_source, is_quarantined, own_im_here_source, quarantine_interface,
+};
 use self::message::PeerMessage;
 use self::structs::Hello;
 use self::structs::Peer;
+use crate::extensions::{local_extensions, record_neighbor_extensions};
 use crate::peer_listener::structs::PeerListener;
 use crate::tm_identity_callback;
 use crate::IdentityCallback;
@@ -28,6 +33,13 @@ fn listen_to_available_ifaces(pl_interfaces: &mut HashMap<String, ListenInterfac
     let interfaces = settings::get_rita_common().network.peer_interfaces;
     let iface_list = interfaces;
     for iface in iface_list.iter() {
+        if is_quarantined(iface) {
+            trace!(
+                "Not listening on {:?}, it is quarantined for a mesh loop",
+                iface
+            );
+            continue;
+        }
         if !pl_interfaces.contains_key(iface) {
             match ListenInterface::new(iface) {
                 Ok(new_listen_interface) => {
@@ -67,6 +79,7 @@ pub fn peerlistener_tick(mut pl: PeerListener) -> PeerListener {
         }
     }
     receive_hello(&mut pl);
+    unlisten_quarantined_interfaces(&mut pl);
     listen_to_available_ifaces(&mut pl.interfaces);
 
     check_and_unlisten_interfaces(&mut pl);
@@ -91,6 +104,28 @@ pub fn check_and_unlisten_interfaces(pl: &mut PeerListener) {
     }
 }
 
+/// Stops listening on interfaces a mesh loop was detected on this tick
+fn unlisten_quarantined_interfaces(pl: &mut PeerListener) {
+    pl.interfaces.retain(|ifname, _| !is_quarantined(ifname));
+    let interfaces = &pl.interfaces;
+    pl.interface_map
+        .retain(|_, ifname| interfaces.contains_key(ifname));
+}
+
+/// Drops every listen interface if the hello port or discovery address changed, they are bound
+/// again with the new settings on the next tick
+pub fn unlisten_if_settings_changed(pl: &mut PeerListener) {
+    let network = settings::get_rita_common().network;
+    let outdated = pl.interfaces.values().any(|iface| {
+        iface.multicast_socketaddr.port() != network.rita_hello_port
+            || *iface.multicast_socketaddr.ip() != network.discovery_ip
+    });
+    if outdated {
+        info!("Peerlistener hello settings changed, rebinding all interfaces");
+        pl.interfaces.clear();
+    }
+}
+
 #[derive(Debug)]
 pub struct ListenInterface {
     ifname: String,
@@ -175,6 +210,10 @@ fn receive_im_here(
     trace!("About to receive ImHere");
     let mut output = HashMap::<IpAddr, Peer>::new();
     let mut interface_map = HashMap::<SocketAddr, String>::new();
+    let own_ips: HashMap<String, Ipv6Addr> = interfaces
+        .iter()
+        .map(|(ifname, iface)| (ifname.clone(), iface.linklocal_ip))
+        .collect();
     for obj in interfaces.iter_mut() {
         trace!("PEER LISTENER: Looking at imHere on interface: {:?}", obj.0);
         let listen_interface = obj.1;
@@ -198,7 +237,7 @@ fn receive_im_here(
                 bytes_read, sock_addr
             );
 
-            let ipaddr = match PeerMessage::decode(datagram.as_ref()) {
+            let ipaddr = match PeerMessage::decode(&datagram[..bytes_read]) {
                 Ok(PeerMessage::ImHere(ipaddr)) => ipaddr,
                 Err(e) => {
                     error!("ImHere decode failed: {:?}", e);
@@ -215,6 +254,19 @@ fn receive_im_here(
                 continue;
             }
 
+            if let Some(sent_from) =
+                own_im_here_source(&listen_interface.ifname, sock_addr.ip(), ipaddr, &own_ips)
+            {
+                // both ends of the loop hear each other, only quarantine one of them
+                if !is_quarantined(&sent_from) {
+                    quarantine_interface(
+                        &listen_interface.ifname,
+                        format!("our own ImHere from {sent_from} was received"),
+                    );
+                }
+                continue;
+            }
+
             if output.contains_key(&ipaddr.into()) {
                 info!(
                     "Discarding ImHere We already have a peer with {:?} for this cycle",
@@ -250,6 +302,7 @@ pub fn send_hello(
         my_id: Box::new(msg.my_id),
         response: msg.response,
         sender_wgport,
+        extensions: local_extensions(),
     };
     let encoded_message = PeerMessage::encode(&message).to_vec();
     let result = socket.send_to(&encoded_message, send_addr);
@@ -265,6 +318,12 @@ pub fn send_hello(
 /// receive UDP hello messages over IPV6 link local ports
 pub fn receive_hello(pl: &mut PeerListener) {
     info!("Receiving Hellos");
+    let our_key = settings::get_rita_common().network.wg_public_key;
+    let own_ips: HashMap<String, Ipv6Addr> = pl
+        .interfaces
+        .iter()
+        .map(|(ifname, iface)| (ifname.clone(), iface.linklocal_ip))
+        .collect();
     for obj in pl.interfaces.iter() {
         let listen_interface = obj.1;
 
@@ -299,7 +358,7 @@ pub fn receive_hello(pl: &mut PeerListener) {
             pl.interface_map
                 .insert(sock_addr, listen_interface.ifname.clone());
 
-            let encoded_msg = datagram.to_vec();
+            let encoded_msg = datagram[..bytes_read].to_vec();
             match PeerMessage::decode(&encoded_msg) {
                 Ok(PeerMessage::ImHere(_ipaddr)) => {
                     error!("Should not revceive Im Here on linklocal socket, Error");
@@ -309,7 +368,23 @@ pub fn receive_hello(pl: &mut PeerListener) {
                     my_id,
                     response,
                     sender_wgport,
+                    extensions,
                 }) => {
+                    if Some(my_id.global.wg_public_key) == our_key {
+                        // only a loop if it really came from us, otherwise someone is using our key
+                        if is_own_source(sock_addr.ip(), &own_ips) {
+                            quarantine_interface(
+                                &listen_interface.ifname,
+                                format!(
+                                    "a Hello with our own wg key was received from {sock_addr}"
+                                ),
+                            );
+                        } else {
+                            warn!("Dropping a Hello with our own wg key from {}", sock_addr);
+                        }
+                        continue;
+                    }
+                    record_neighbor_extensions(my_id.global.wg_public_key, extensions);
                     //We received an initial hello contact message
                     if !response {
                         info!(
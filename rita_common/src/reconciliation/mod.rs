@@ -0,0 +1,333 @@
+//! Billing reconciliation between neighbors. Every reconciliation_interval we send each node we have
+//! debts with a signed summary of our books, they answer with theirs and both sides compare the two.
+//! Differences beyond reconciliation_threshold are logged and the latest comparison with every neighbor
+//! is kept for GET /debts/reconciliation, on both sides of the exchange.
+//!
+//! Exits never start an exchange, they have too many clients to contact each one, but they answer the
+//! clients that do and so still see every comparison.
+
+use crate::debt_keeper::{dump, NodeDebtData};
+use crate::reconciliation::receipts::receipts_with;
+use crate::RitaCommonError;
+use crate::KI;
+use althea_types::unix_time::now_secs;
+use althea_types::{DebtSummary, Identity, SignedDebtSummary, SignedPaymentReceipt};
+use num256::{Int256, Uint256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Summaries older or further in the future than this are refused, routers don't keep great time
+/// so this is generous, it only stops summaries from being replayed much later
+const MAX_SUMMARY_AGE: Duration = Duration::from_secs(3600);
+/// How long we wait for a neighbor to answer with their summary
+const RECONCILIATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub mod receipts;
+
+lazy_static! {
+    static ref RECONCILIATIONS: Arc<RwLock<HashMap<u32, HashMap<Identity, Reconciliation>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+    static ref LAST_RECONCILIATION: Arc<RwLock<HashMap<u32, Instant>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// A way in which our books and a neighbor's disagree, both values are given from our side, so the
+/// two would be equal if the books agreed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    /// What we think we owe them against what they think we owe them
+    Debt { ours: Int256, theirs: Int256 },
+    /// What we have paid them against what they have received from us
+    PaymentsSent { ours: Uint256, theirs: Uint256 },
+    /// What we have received from them against what they have paid us
+    PaymentsReceived { ours: Uint256, theirs: Uint256 },
+}
+
+/// The latest comparison of our books with a neighbor's
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reconciliation {
+    pub neighbor: Identity,
+    pub ours: DebtSummary,
+    pub theirs: SignedDebtSummary,
+    /// Empty if the books agree within the threshold
+    pub discrepancies: Vec<Discrepancy>,
+    pub checked_at: SystemTime,
+    /// Receipts exchanged with the neighbor for validated payments, filled in when the report is read
+    #[serde(default)]
+    pub receipts: Vec<SignedPaymentReceipt>,
+}
+
+fn uint_difference(a: &Uint256, b: &Uint256) -> Uint256 {
+    if a > b {
+        *a - *b
+    } else {
+        *b - *a
+    }
+}
+
+/// Compares our summary with theirs, they are looking at the same relationship from the other side
+/// so their debt is the negative of ours and what they sent is what we received
+pub fn find_discrepancies(
+    ours: &DebtSummary,
+    theirs: &DebtSummary,
+    threshold: Uint256,
+) -> Vec<Discrepancy> {
+    let mut ret = Vec::new();
+    let their_debt_to_us = Int256::from(0) - theirs.debt;
+    let debt_difference = (ours.debt - their_debt_to_us).abs();
+    if debt_difference.to_uint256().unwrap_or(threshold) > threshold {
+        ret.push(Discrepancy::Debt {
+            ours: ours.debt,
+            theirs: their_debt_to_us,
+        });
+    }
+    if uint_difference(&ours.total_payment_sent, &theirs.total_payment_received) > threshold {
+        ret.push(Discrepancy::PaymentsSent {
+            ours: ours.total_payment_sent,
+            theirs: theirs.total_payment_received,
+        });
+    }
+    if uint_difference(&ours.total_payment_received, &theirs.total_payment_sent) > threshold {
+        ret.push(Discrepancy::PaymentsReceived {
+            ours: ours.total_payment_received,
+            theirs: theirs.total_payment_sent,
+        });
+    }
+    ret
+}
+
+/// Our signed summary of the given debt data, None if we don't have an identity or key yet
+fn our_summary(neighbor: &Identity, data: &NodeDebtData) -> Option<SignedDebtSummary> {
+    let common = settings::get_rita_common();
+    let our_id = common.get_identity()?;
+    let key = common.payment.eth_private_key?;
+    Some(
+        DebtSummary {
+            from: our_id,
+            to: *neighbor,
+            debt: data.debt,
+            total_payment_sent: data.total_payment_sent,
+            total_payment_received: data.total_payment_received,
+            timestamp: now_secs(),
+        }
+        .sign(key),
+    )
+}
+
+/// Checks a neighbor's summary is signed by them, about us, and recent
+fn check_summary(theirs: &SignedDebtSummary, our_id: &Identity) -> Result<(), RitaCommonError> {
+    if let Err(e) = theirs.verify() {
+        return Err(RitaCommonError::MiscStringError(e.to_string()));
+    }
+    if theirs.summary.to.eth_address != our_id.eth_address {
+        return Err(RitaCommonError::MiscStringError(
+            "Debt summary is not about us".to_string(),
+        ));
+    }
+    let now = now_secs();
+    if now.abs_diff(theirs.summary.timestamp) > MAX_SUMMARY_AGE.as_secs() {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Debt summary timestamp {} is too far from our time {}",
+            theirs.summary.timestamp, now
+        )));
+    }
+    Ok(())
+}
+
+fn record(neighbor: Identity, ours: DebtSummary, theirs: SignedDebtSummary) {
+    let threshold = settings::get_rita_common().payment.reconciliation_threshold;
+    let discrepancies = find_discrepancies(&ours, &theirs.summary, threshold);
+    if discrepancies.is_empty() {
+        info!("Books agree with {}", neighbor);
+    } else {
+        warn!(
+            "Books disagree with {} by more than {} wei {:?}",
+            neighbor, threshold, discrepancies
+        );
+    }
+    let netns = KI.check_integration_test_netns();
+    RECONCILIATIONS
+        .write()
+        .unwrap()
+        .entry(netns)
+        .or_default()
+        .insert(
+            neighbor,
+            Reconciliation {
+                neighbor,
+                ours,
+                theirs,
+                discrepancies,
+                checked_at: SystemTime::now(),
+                receipts: Vec::new(),
+            },
+        );
+}
+
+/// The latest comparison with every neighbor and the payment receipts exchanged with them
+pub fn get_reconciliations() -> Vec<Reconciliation> {
+    let netns = KI.check_integration_test_netns();
+    let mut reconciliations: Vec<Reconciliation> = RECONCILIATIONS
+        .read()
+        .unwrap()
+        .get(&netns)
+        .map(|r| r.values().cloned().collect())
+        .unwrap_or_default();
+    for reconciliation in reconciliations.iter_mut() {
+        reconciliation.receipts = receipts_with(&reconciliation.neighbor);
+    }
+    reconciliations
+}
+
+/// Answers a neighbor's summary with ours and records the comparison, only nodes we have debts
+/// with are answered
+pub fn handle_summary(theirs: SignedDebtSummary) -> Result<SignedDebtSummary, RitaCommonError> {
+    let our_id = match settings::get_rita_common().get_identity() {
+        Some(id) => id,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "No identity yet".to_string(),
+            ))
+        }
+    };
+    check_summary(&theirs, &our_id)?;
+    let debts = dump();
+    let (neighbor, data) = match debts
+        .iter()
+        .find(|(id, _)| id.eth_address == theirs.summary.from.eth_address)
+    {
+        Some(entry) => entry,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "No debts with this node".to_string(),
+            ))
+        }
+    };
+    let ours = match our_summary(neighbor, data) {
+        Some(ours) => ours,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "No eth key to sign with".to_string(),
+            ))
+        }
+    };
+    record(*neighbor, ours.summary.clone(), theirs);
+    Ok(ours)
+}
+
+async fn reconcile_with(neighbor: Identity, data: NodeDebtData) -> Result<(), RitaCommonError> {
+    let ours = match our_summary(&neighbor, &data) {
+        Some(ours) => ours,
+        None => return Ok(()),
+    };
+    let our_id = ours.summary.from;
+    let url = format!(
+        "http://[{}]:{}/debts/reconcile",
+        neighbor.mesh_ip,
+        settings::get_rita_common().network.rita_contact_port
+    );
+    let client = awc::Client::default();
+    let mut response = client
+        .post(url)
+        .timeout(RECONCILIATION_TIMEOUT)
+        .send_json(&ours)
+        .await?;
+    let theirs: SignedDebtSummary = response.json().await?;
+    check_summary(&theirs, &our_id)?;
+    if theirs.summary.from.eth_address != neighbor.eth_address {
+        return Err(RitaCommonError::MiscStringError(
+            "Debt summary answered by another node".to_string(),
+        ));
+    }
+    record(neighbor, ours.summary, theirs);
+    Ok(())
+}
+
+/// Called from the slow loop, swaps summaries with every node we have debts with once every
+/// reconciliation_interval
+pub async fn tick_reconciliation() {
+    let interval = settings::get_rita_common().payment.reconciliation_interval;
+    if interval == 0 || settings::check_if_exit() {
+        return;
+    }
+    let netns = KI.check_integration_test_netns();
+    {
+        let mut last = LAST_RECONCILIATION.write().unwrap();
+        if let Some(last) = last.get(&netns) {
+            if last.elapsed() < Duration::from_secs(interval) {
+                return;
+            }
+        }
+        last.insert(netns, Instant::now());
+    }
+
+    for (neighbor, data) in dump() {
+        if let Err(e) = reconcile_with(neighbor, data).await {
+            warn!("Failed to reconcile books with {} {:?}", neighbor, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(debt: i64, sent: u64, received: u64) -> DebtSummary {
+        let id = Identity::new(
+            "fd00::1".parse().unwrap(),
+            "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        DebtSummary {
+            from: id,
+            to: id,
+            debt: debt.into(),
+            total_payment_sent: sent.into(),
+            total_payment_received: received.into(),
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_find_discrepancies() {
+        let threshold = Uint256::from(100u32);
+        // we owe 5000, they think we owe 4950 and payments line up
+        let ours = summary(5000, 1000, 0);
+        let theirs = summary(-4950, 0, 1000);
+        assert!(find_discrepancies(&ours, &theirs, threshold).is_empty());
+
+        // they think we owe a lot more and never got our last payment
+        let theirs = summary(-9000, 0, 500);
+        assert_eq!(
+            find_discrepancies(&ours, &theirs, threshold),
+            vec![
+                Discrepancy::Debt {
+                    ours: Int256::from(5000i64),
+                    theirs: Int256::from(9000i64)
+                },
+                Discrepancy::PaymentsSent {
+                    ours: 1000u32.into(),
+                    theirs: 500u32.into()
+                }
+            ]
+        );
+
+        // they say they paid us and we never saw it
+        let theirs = summary(-5000, 300, 1000);
+        assert_eq!(
+            find_discrepancies(&ours, &theirs, threshold),
+            vec![Discrepancy::PaymentsReceived {
+                ours: 0u32.into(),
+                theirs: 300u32.into()
+            }]
+        );
+    }
+}
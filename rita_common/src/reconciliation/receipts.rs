@@ -0,0 +1,364 @@
+//! Signed receipts for validated payments. When a payment to us is validated we sign a receipt with the
+//! txid, the amount and what our books say once it was credited, and post it to the payer's contact
+//! port. The payer checks the signature and keeps it, so after a chain hiccup either side can show
+//! which payments were seen and what the debt was at the time. Receipts we issued and receipts we were
+//! sent are both kept in payment.receipts_file and listed with each neighbor's reconciliation. Only the
+//! latest receipts with each neighbor are kept, and the file is written on the usage tracker's wear policy
+//! since it usually sits on the same flash.
+//!
+//! Like reconciliation exchanges receipts are only issued by routers, exits have too many clients to
+//! contact, but exits keep the receipts they are sent.
+
+use super::MAX_SUMMARY_AGE;
+use crate::debt_keeper::dump;
+use crate::usage_tracker::get_usage_storage_type;
+use crate::usage_tracker::segments::WearPolicy;
+use crate::utils::json_file::write_atomically;
+use crate::RitaCommonError;
+use crate::KI;
+use althea_types::unix_time::now_secs;
+use althea_types::{Identity, PaymentReceipt, PaymentTx, SignedPaymentReceipt};
+use clarity::Address;
+use num256::Int256;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Receipts kept in each direction with any one neighbor, the oldest are dropped first
+const MAX_RECEIPTS_PER_NEIGHBOR: usize = 20;
+/// Receipts kept in each direction overall so that an exit with many clients keeps a small file
+const MAX_RECEIPTS: usize = 250;
+/// Receipts waiting to be delivered, if payers stay unreachable the oldest are dropped
+const MAX_PENDING: usize = 100;
+/// Delivery attempts, one per slow loop tick, before a receipt is only kept on our side
+const SEND_ATTEMPTS: u8 = 3;
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref RECEIPTS: Arc<RwLock<HashMap<u32, ReceiptStore>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// Every receipt we have, as saved to disk
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReceiptLog {
+    /// Receipts we signed for payments made to us
+    pub issued: VecDeque<SignedPaymentReceipt>,
+    /// Receipts neighbors signed for payments we made to them
+    pub received: VecDeque<SignedPaymentReceipt>,
+}
+
+/// The neighbor on the other side of a receipt we issued
+fn issued_to(receipt: &SignedPaymentReceipt) -> Address {
+    receipt.receipt.to.eth_address
+}
+
+/// The neighbor on the other side of a receipt we were sent
+fn received_from(receipt: &SignedPaymentReceipt) -> Address {
+    receipt.receipt.from.eth_address
+}
+
+impl ReceiptLog {
+    /// Adds a receipt unless its txid is already there, dropping the oldest receipt with the same
+    /// neighbor, as found by `neighbor`, past MAX_RECEIPTS_PER_NEIGHBOR and the oldest overall past
+    /// MAX_RECEIPTS
+    fn push(
+        list: &mut VecDeque<SignedPaymentReceipt>,
+        receipt: SignedPaymentReceipt,
+        neighbor: fn(&SignedPaymentReceipt) -> Address,
+    ) -> bool {
+        if list.iter().any(|r| r.receipt.txid == receipt.receipt.txid) {
+            return false;
+        }
+        let address = neighbor(&receipt);
+        list.push_back(receipt);
+        if list.iter().filter(|r| neighbor(r) == address).count() > MAX_RECEIPTS_PER_NEIGHBOR {
+            if let Some(oldest) = list.iter().position(|r| neighbor(r) == address) {
+                list.remove(oldest);
+            }
+        }
+        while list.len() > MAX_RECEIPTS {
+            list.pop_front();
+        }
+        true
+    }
+}
+
+struct ReceiptStore {
+    log: Option<ReceiptLog>,
+    /// Issued receipts that haven't reached the payer yet and the attempts made
+    pending: VecDeque<(SignedPaymentReceipt, u8)>,
+    policy: WearPolicy,
+    last_write: Instant,
+    /// Set when the log changed since it was last saved
+    dirty: bool,
+}
+
+impl ReceiptStore {
+    fn new() -> ReceiptStore {
+        ReceiptStore {
+            log: None,
+            pending: VecDeque::new(),
+            policy: WearPolicy::new(
+                get_usage_storage_type(),
+                settings::get_rita_common()
+                    .network
+                    .usage_tracker_write_interval,
+            ),
+            last_write: Instant::now(),
+            dirty: false,
+        }
+    }
+}
+
+fn load_receipts(path: &str) -> ReceiptLog {
+    match fs::read(path) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(log) => log,
+            Err(e) => {
+                error!("Failed to deserialize payment receipts file {:?}", e);
+                ReceiptLog::default()
+            }
+        },
+        Err(e) => {
+            info!("No payment receipts file loaded {:?}", e);
+            ReceiptLog::default()
+        }
+    }
+}
+
+/// Runs a change against this namespace's receipts, loading them from disk the first time
+fn with_receipts<T>(change: impl FnOnce(&mut ReceiptLog, &mut ReceiptStore) -> T) -> T {
+    let netns = KI.check_integration_test_netns();
+    let mut receipts = RECEIPTS.write().unwrap();
+    let store = receipts.entry(netns).or_insert_with(ReceiptStore::new);
+    let mut log = match store.log.take() {
+        Some(log) => log,
+        None => load_receipts(&settings::get_rita_common().payment.receipts_file),
+    };
+    let ret = change(&mut log, store);
+    store.log = Some(log);
+    ret
+}
+
+/// Signs a receipt for a payment to us that was just validated and queues it for the payer, called
+/// by the payment validator after debt keeper has credited the payment
+pub fn issue_receipt(payment: &PaymentTx) {
+    if settings::check_if_exit() {
+        return;
+    }
+    let common = settings::get_rita_common();
+    let (our_id, key) = match (common.get_identity(), common.payment.eth_private_key) {
+        (Some(id), Some(key)) => (id, key),
+        _ => return,
+    };
+    let debt = dump()
+        .iter()
+        .find(|(id, _)| id.eth_address == payment.from.eth_address)
+        .map(|(_, data)| data.debt)
+        .unwrap_or_else(|| Int256::from(0));
+    let receipt = PaymentReceipt {
+        from: our_id,
+        to: payment.from,
+        txid: payment.txid,
+        amount: payment.amount,
+        debt,
+        timestamp: now_secs(),
+    }
+    .sign(key);
+
+    with_receipts(|log, store| {
+        // payments replayed by make_payment_v2 are validated again, they already have a receipt
+        if !ReceiptLog::push(&mut log.issued, receipt.clone(), issued_to) {
+            return;
+        }
+        store.dirty = true;
+        store.pending.push_back((receipt, 0));
+        while store.pending.len() > MAX_PENDING {
+            store.pending.pop_front();
+        }
+    });
+}
+
+/// Checks and stores a receipt a neighbor sent us for a payment we made
+pub fn handle_receipt(receipt: SignedPaymentReceipt) -> Result<(), RitaCommonError> {
+    if let Err(e) = receipt.verify() {
+        return Err(RitaCommonError::MiscStringError(e.to_string()));
+    }
+    let our_id = match settings::get_rita_common().get_identity() {
+        Some(id) => id,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "No identity yet".to_string(),
+            ))
+        }
+    };
+    if receipt.receipt.to.eth_address != our_id.eth_address {
+        return Err(RitaCommonError::MiscStringError(
+            "Payment receipt is not for us".to_string(),
+        ));
+    }
+    let now = now_secs();
+    if now.abs_diff(receipt.receipt.timestamp) > MAX_SUMMARY_AGE.as_secs() {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Payment receipt timestamp {} is too far from our time {}",
+            receipt.receipt.timestamp, now
+        )));
+    }
+    if !dump()
+        .iter()
+        .any(|(id, _)| id.eth_address == receipt.receipt.from.eth_address)
+    {
+        return Err(RitaCommonError::MiscStringError(
+            "No debts with this node".to_string(),
+        ));
+    }
+    info!(
+        "Got a receipt from {} for payment {:#066x} of {} wei",
+        receipt.receipt.from, receipt.receipt.txid, receipt.receipt.amount
+    );
+    with_receipts(|log, store| {
+        if ReceiptLog::push(&mut log.received, receipt, received_from) {
+            store.dirty = true;
+        }
+    });
+    Ok(())
+}
+
+/// The receipts exchanged with a neighbor in either direction, oldest first
+pub fn receipts_with(neighbor: &Identity) -> Vec<SignedPaymentReceipt> {
+    with_receipts(|log, _| {
+        let mut ret: Vec<SignedPaymentReceipt> = log
+            .issued
+            .iter()
+            .filter(|r| issued_to(r) == neighbor.eth_address)
+            .chain(
+                log.received
+                    .iter()
+                    .filter(|r| received_from(r) == neighbor.eth_address),
+            )
+            .cloned()
+            .collect();
+        ret.sort_by_key(|r| r.receipt.timestamp);
+        ret
+    })
+}
+
+async fn send_receipt(receipt: &SignedPaymentReceipt) -> Result<(), RitaCommonError> {
+    let url = format!(
+        "http://[{}]:{}/payments/receipt",
+        receipt.receipt.to.mesh_ip,
+        settings::get_rita_common().network.rita_contact_port
+    );
+    let client = awc::Client::default();
+    let response = client
+        .post(url)
+        .timeout(RECEIPT_TIMEOUT)
+        .send_json(receipt)
+        .await?;
+    if !response.status().is_success() {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Payment receipt refused with {}",
+            response.status()
+        )));
+    }
+    Ok(())
+}
+
+/// Called from the slow loop, delivers pending receipts and saves the log if it changed
+pub async fn tick_payment_receipts() {
+    let pending: Vec<(SignedPaymentReceipt, u8)> =
+        with_receipts(|_, store| store.pending.drain(..).collect());
+    let mut retry = Vec::new();
+    for (receipt, attempts) in pending {
+        if let Err(e) = send_receipt(&receipt).await {
+            warn!(
+                "Failed to send payment receipt to {} {:?}",
+                receipt.receipt.to, e
+            );
+            if attempts + 1 < SEND_ATTEMPTS {
+                retry.push((receipt, attempts + 1));
+            }
+        }
+    }
+
+    with_receipts(|_, store| store.pending.extend(retry));
+    flush_payment_receipts(false);
+}
+
+/// Writes the receipts out if they changed and the storage can take another write
+pub fn flush_payment_receipts(force: bool) {
+    let path = settings::get_rita_common().payment.receipts_file;
+    with_receipts(|log, store| {
+        if !store.dirty || (!force && store.last_write.elapsed() < store.policy.write_interval) {
+            return;
+        }
+        store.last_write = Instant::now();
+        let serialized = serde_json::to_vec(log).expect("Failed to serialize payment receipts!");
+        match write_atomically(&path, &serialized) {
+            Ok(()) => store.dirty = false,
+            Err(e) => error!("Failed to save payment receipts {:?}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::PrivateKey;
+
+    #[test]
+    fn test_receipt_log_push() {
+        let key: PrivateKey = "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap();
+        let id = Identity::new(
+            "fd00::1".parse().unwrap(),
+            key.to_address(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let signed = PaymentReceipt {
+            from: id,
+            to: id,
+            txid: 0u32.into(),
+            amount: 1000u32.into(),
+            debt: Int256::from(0),
+            timestamp: 0,
+        }
+        .sign(key);
+        // push doesn't check signatures
+        let receipt = |txid: u32| {
+            let mut receipt = signed.clone();
+            receipt.receipt.txid = txid.into();
+            receipt
+        };
+        let mut list = VecDeque::new();
+        assert!(ReceiptLog::push(&mut list, receipt(1), issued_to));
+        // a replayed payment keeps its first receipt
+        assert!(!ReceiptLog::push(&mut list, receipt(1), issued_to));
+        for txid in 2..=(MAX_RECEIPTS_PER_NEIGHBOR as u32 + 1) {
+            assert!(ReceiptLog::push(&mut list, receipt(txid), issued_to));
+        }
+        assert_eq!(list.len(), MAX_RECEIPTS_PER_NEIGHBOR);
+        assert_eq!(list.front().unwrap().receipt.txid, 2u32.into());
+
+        // a busy neighbor only pushes out its own receipts
+        let mut list = VecDeque::new();
+        let mut other = receipt(0);
+        let other_key: PrivateKey =
+            "0x0000000000000000000000000000000000000000000000000000000000000002"
+                .parse()
+                .unwrap();
+        other.receipt.to.eth_address = other_key.to_address();
+        assert!(ReceiptLog::push(&mut list, other, issued_to));
+        for txid in 1..=(MAX_RECEIPTS_PER_NEIGHBOR as u32 * 2) {
+            assert!(ReceiptLog::push(&mut list, receipt(txid), issued_to));
+        }
+        assert_eq!(list.len(), MAX_RECEIPTS_PER_NEIGHBOR + 1);
+        assert_eq!(list.front().unwrap().receipt.txid, 0u32.into());
+    }
+}
@@ -1,19 +1,24 @@
 use crate::blockchain_oracle::update as BlockchainOracleUpdate;
 use crate::debt_keeper::send_debt_update;
+use crate::memory_monitor::record_payment_queue_len;
+use crate::network_monitor::apply_stability_penalties;
 use crate::network_monitor::update_network_info;
 use crate::network_monitor::NetworkInfo as NetworkMonitorTick;
 use crate::payment_controller::PaymentController;
 use crate::payment_validator::PaymentValidator;
 use crate::peer_listener::peerlistener_tick;
 use crate::peer_listener::structs::PeerListener;
+use crate::peer_listener::unlisten_if_settings_changed;
+use crate::rita_loop::slow_loop::update_babel_price_and_metric_factor;
 use crate::traffic_watcher::watch;
 use crate::tunnel_manager::contact_peers::tm_contact_peers;
 use crate::tunnel_manager::tm_get_neighbors;
 use crate::KI;
 use actix_async::System as AsyncSystem;
-use babel_monitor::open_babel_stream;
 use babel_monitor::parse_neighs;
 use babel_monitor::parse_routes;
+use babel_monitor::pool::get_babel_stream;
+use settings::subscriptions::{subscribe, NETWORK_SECTION, PAYMENT_SECTION};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -42,21 +47,39 @@ pub fn start_rita_fast_loop() {
                 trace!("Common Fast tick!");
                 let start = Instant::now();
                 let runner = AsyncSystem::new();
-                let babel_port = settings::get_rita_common().network.babel_port;
-                let system_chain = settings::get_rita_common().payment.system_chain;
                 runner.block_on(async move {
+                    let mut network_changes = subscribe(NETWORK_SECTION);
+                    let mut payment_changes = subscribe(PAYMENT_SECTION);
+                    let mut babel_port = settings::get_rita_common().network.babel_port;
+                    let mut system_chain = settings::get_rita_common().payment.system_chain;
                     let mut payment_validator_state = PaymentValidator::new();
                     let mut payment_controller_state = PaymentController::new();
                     let mut outgoing_payments = Vec::new();
                     loop {
                         trace!("Common tick!");
 
+                        // pick up settings changed since the last tick rather than waiting for
+                        // a restart, price changes are pushed to babel right away instead of on
+                        // the next slow loop
+                        let network_changed = network_changes.has_changed();
+                        if network_changed {
+                            babel_port = settings::get_rita_common().network.babel_port;
+                        }
+                        if payment_changes.has_changed() {
+                            system_chain = settings::get_rita_common().payment.system_chain;
+                        }
+
                         let res = tm_get_neighbors();
                         trace!("Currently open tunnels: {:?}", res);
                         let neighbors = res;
                         let neigh = Instant::now();
 
-                        if let Ok(mut stream) = open_babel_stream(babel_port, FAST_LOOP_TIMEOUT) {
+                        if let Ok(mut stream) = get_babel_stream(babel_port, FAST_LOOP_TIMEOUT) {
+                            if network_changed {
+                                if let Err(e) = update_babel_price_and_metric_factor(&mut stream) {
+                                    warn!("Failed to update babel price with {:?}", e);
+                                }
+                            }
                             if let Ok(babel_routes) = parse_routes(&mut stream) {
                                 if let Err(e) = watch(babel_routes.clone(), &neighbors) {
                                     error!("Error for Rita common traffic watcher {}", e);
@@ -76,6 +99,7 @@ pub fn start_rita_fast_loop() {
                                         babel_routes,
                                         rita_neighbors,
                                     });
+                                    apply_stability_penalties(&mut stream);
                                 }
                             }
                         }
@@ -108,6 +132,10 @@ pub fn start_rita_fast_loop() {
                             .tick_payment_controller(payments_to_send, previously_sent_payments)
                             .await;
                         info!("Finished tick payment controller!");
+                        record_payment_queue_len(
+                            payment_controller_state.queued_payments()
+                                + payment_validator_state.tracked_transactions(),
+                        );
                     }
                 });
                 info!(
@@ -146,12 +174,17 @@ pub fn peer_discovery_loop() {
                 let runner = AsyncSystem::new();
                 runner.block_on(async move {
                     let mut pl = PeerListener::new();
+                    let mut network_changes = subscribe(NETWORK_SECTION);
                     loop {
                         let start = Instant::now();
                         info!("Common peer discovery tick!");
                         let measure_tick = Instant::now();
                         info!("Starting PeerListener tick");
 
+                        if network_changes.has_changed() {
+                            unlisten_if_settings_changed(&mut pl);
+                        }
+
                         pl = peerlistener_tick(pl);
 
                         info!(
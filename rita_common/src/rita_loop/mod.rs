@@ -5,7 +5,12 @@
 //! all system functions. Anything that blocks will eventually filter up to block this loop and
 //! halt essential functions like opening tunnels and managing peers
 
+use crate::artifact_cache::{get_artifact, get_artifact_list};
+use crate::broadcast_notices::get_broadcast_notices;
 use crate::network_endpoints::*;
+use crate::path_diagnostics::get_rest_of_path;
+use crate::peer_labels::get_peer_label;
+use crate::speed_test::receive_speed_test;
 use crate::traffic_watcher::init_traffic_watcher;
 use actix_async::System;
 use actix_web_async::{web, App, HttpServer};
@@ -41,11 +46,11 @@ pub fn set_gateway(input: bool) {
 /// one or more a random entry from the list is returned in an attempt
 /// to load balance across fullnodes
 pub fn get_web3_server() -> String {
-    let common = settings::get_rita_common();
-    if common.payment.eth_node_list.is_empty() {
+    let common = settings::get_rita_common_snapshot();
+    let node_list = &common.payment().eth_node_list;
+    if node_list.is_empty() {
         panic!("no full nodes configured!");
     }
-    let node_list = common.payment.eth_node_list;
     let mut rng = thread_rng();
     let val = rng.gen_range(0..node_list.len());
 
@@ -56,11 +61,11 @@ pub fn get_web3_server() -> String {
 /// one or more a random entry from the list is returned in an attempt
 /// to load balance across fullnodes
 pub fn get_altheal1_server() -> String {
-    let common = settings::get_rita_common();
-    if common.payment.althea_grpc_list.is_empty() {
+    let common = settings::get_rita_common_snapshot();
+    let node_list = &common.payment().althea_grpc_list;
+    if node_list.is_empty() {
         panic!("no full nodes configured!");
     }
-    let node_list = common.payment.althea_grpc_list;
     let mut rng = thread_rng();
     let val = rng.gen_range(0..node_list.len());
 
@@ -96,6 +101,17 @@ pub fn start_core_rita_endpoints(workers: usize) {
                 App::new()
                     .route("/make_payment", web::post().to(make_payments))
                     .route("/make_payment_v2", web::post().to(make_payments_v2))
+                    .route("/debts/reconcile", web::post().to(reconcile_debts))
+                    .route("/payments/receipt", web::post().to(receive_payment_receipt))
+                    .route("/artifacts", web::get().to(get_artifact_list))
+                    .route("/artifacts/{hash}", web::get().to(get_artifact))
+                    .route(
+                        "/diagnostics/trace/{dest}/{hops_left}",
+                        web::get().to(get_rest_of_path),
+                    )
+                    .route("/speed_test", web::post().to(receive_speed_test))
+                    .route("/peer_label", web::get().to(get_peer_label))
+                    .route("/broadcast_notices", web::get().to(get_broadcast_notices))
             })
             .workers(workers)
             .bind(format!("[::0]:{}", common.network.rita_contact_port))
@@ -110,6 +126,7 @@ pub fn start_core_rita_endpoints(workers: usize) {
 
 pub fn start_rita_common_loops() {
     init_traffic_watcher();
+    crate::events::start_event_delivery();
     crate::rita_loop::slow_loop::start_rita_slow_loop();
     crate::rita_loop::fast_loop::start_rita_fast_loop();
     crate::rita_loop::fast_loop::peer_discovery_loop();
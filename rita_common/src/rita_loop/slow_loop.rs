@@ -1,11 +1,21 @@
+use crate::broadcast_notices::tick_broadcast_notices;
 use crate::handle_shaping;
+use crate::memory_monitor::check_memory;
+use crate::notifications::flush_notifications;
+use crate::peer_labels::tick_peer_labels;
+use crate::reconciliation::receipts::tick_payment_receipts;
+use crate::reconciliation::tick_reconciliation;
 use crate::simulated_txfee_manager::tick_simulated_tx;
 use crate::token_bridge::tick_token_bridge;
+use crate::tunnel_manager::keepalive::tick_tunnel_keepalive;
+use crate::tunnel_manager::mtu::tick_tunnel_mtu;
 use crate::tunnel_manager::tm_common_slow_loop_helper;
+use crate::usage_tracker::balance_history::update_balance_history;
+use crate::usage_tracker::save_usage_to_disk;
 use crate::KI;
 use actix_async::System as AsyncSystem;
-use babel_monitor::open_babel_stream;
 use babel_monitor::parse_interfaces;
+use babel_monitor::pool::get_babel_stream;
 use babel_monitor::set_local_fee;
 use babel_monitor::set_metric_factor;
 use babel_monitor::structs::BabelMonitorError;
@@ -36,19 +46,39 @@ pub fn start_rita_slow_loop() {
                 // checks for and updates tunnel manager traffic shaper values
                 handle_shaping();
 
+                // warns and sheds history before we run out of memory
+                check_memory();
+
+                // appends usage and payments to disk when the storage can take another write
+                save_usage_to_disk();
+
+                // samples our balance for the earnings timeline
+                update_balance_history();
+
+                // saves the notifications inbox when the storage can take another write
+                flush_notifications(false);
+
                 let runner = AsyncSystem::new();
                 runner.block_on(async move {
                     info!("Ticking token bridge");
                     tick_token_bridge().await;
                     info!("Ticking simulated tx!");
                     tick_simulated_tx().await;
+                    info!("Ticking reconciliation!");
+                    tick_reconciliation().await;
+                    info!("Ticking payment receipts!");
+                    tick_payment_receipts().await;
+                    info!("Ticking peer labels!");
+                    tick_peer_labels().await;
+                    info!("Ticking broadcast notices!");
+                    tick_broadcast_notices().await;
                     info!("Common Slow tick async completed!");
                     AsyncSystem::current().stop();
                 });
 
                 // This checks that all tunnels are attached to babel. This may not be the case when babel restarts
                 let babel_port = settings::get_rita_common().network.babel_port;
-                match open_babel_stream(babel_port, SLOW_LOOP_TIMEOUT) {
+                match get_babel_stream(babel_port, SLOW_LOOP_TIMEOUT) {
                     Ok(mut stream) => {
                         // we really only need to run this on startup, but doing so periodically
                         // could catch the edge case where babel is restarted under us
@@ -83,6 +113,9 @@ pub fn start_rita_slow_loop() {
 
                     },
                 }
+                tick_tunnel_keepalive();
+                tick_tunnel_mtu();
+
                 // auto recovery when babel crashes or otherwise behaves poorly
                 num_babel_failures += 1;
                 if num_babel_failures > BABEL_RESTART_COUNT {
@@ -115,7 +148,9 @@ pub fn start_rita_slow_loop() {
 
 /// This function updates the babeld price and metric factor by connecting to the babel instance and
 /// setting those values.
-fn update_babel_price_and_metric_factor(stream: &mut TcpStream) -> Result<(), BabelMonitorError> {
+pub(crate) fn update_babel_price_and_metric_factor(
+    stream: &mut TcpStream,
+) -> Result<(), BabelMonitorError> {
     let start = Instant::now();
     let common = settings::get_rita_common();
     let local_fee = common.network.babeld_settings.local_fee;
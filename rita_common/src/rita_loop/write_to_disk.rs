@@ -1,4 +1,4 @@
-use crate::{debt_keeper::save_debt_to_disk, usage_tracker::save_usage_to_disk};
+use crate::debt_keeper::save_debt_to_disk;
 use settings::{
     check_if_exit, client::RitaClientSettings, exit::RitaExitSettingsStruct, get_rita_client,
     get_rita_exit, write_config,
@@ -21,7 +21,8 @@ pub enum SettingsOnDisk {
     RitaExitSettingsStruct(Box<RitaExitSettingsStruct>),
 }
 /// This loop attempts to perform all write operations for writing to disk
-/// This includes writing config/settings, usage tracker, and debt tracker.
+/// This includes writing config/settings and debt tracker. The usage tracker
+/// is written from the slow loop on its own wear aware schedule.
 /// It takes in a settings enum in order to identify what type the device
 /// is. There is also a consideration for the amount of storage the device
 /// has on disk since we don't want to save too often if the disk doesn't
@@ -92,9 +93,6 @@ pub fn save_to_disk_loop(mut old_settings: SettingsOnDisk) {
         if !router_storage_small {
             save_debt_to_disk(save_frequency);
         }
-
-        // usage tracker monitors and saves bandwidth usage info and payment metadata
-        save_usage_to_disk();
     });
 }
 /// If the router storage is small/16mb
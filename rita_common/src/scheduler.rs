@@ -0,0 +1,163 @@
+//! Schedules periodic work that thousands of routers do against the same servers, like checking in with
+//! exits and operator tools, so that it is spread over the period instead of everyone hitting the server
+//! at the same moment. Each router runs a task at a fixed point in every period, offset from the wall
+//! clock cycle by a phase derived from its wg key and the task name. The first run is right at startup so
+//! a freshly booted router checks in without waiting out a period, after that routers that came up
+//! together after a power outage spread out, and a router's tasks don't line up with each other. Failures
+//! back off exponentially up to a cap, jittered by the same phase.
+
+use althea_types::unix_time::since_unix_epoch;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    period: Duration,
+    max_backoff: Duration,
+    /// How far into each period this router runs the task, from 0 to 1
+    phase: f64,
+    failures: u32,
+    next_run: Instant,
+}
+
+/// A fraction from 0 to 1 that is the same every time for the same seed and task
+pub fn task_phase(seed: &[u8], task: &str) -> f64 {
+    let digest = Sha256::new()
+        .chain_update(seed)
+        .chain_update(task.as_bytes())
+        .finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    // the top 53 bits fit an f64 exactly, which keeps the result below 1
+    (u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Our wg key, or on the first boot before it is generated a random seed, which still spreads routers out
+fn device_seed() -> Vec<u8> {
+    match settings::get_rita_common().network.wg_public_key {
+        Some(key) => key.to_string().into_bytes(),
+        None => rand::random::<[u8; 32]>().to_vec(),
+    }
+}
+
+/// Time from now until the next point that is phase of the way into a period, periods start at multiples
+/// of period in unix time
+fn until_next_slot(now: Duration, period: Duration, phase: f64) -> Duration {
+    let period_ms = period.as_millis().max(1);
+    let offset_ms = (period_ms as f64 * phase) as u128 % period_ms;
+    let into_period = (now.as_millis() + period_ms - offset_ms) % period_ms;
+    Duration::from_millis((period_ms - into_period) as u64)
+}
+
+/// The wait after failures failures in a row, between half and all of the doubled period
+fn backoff(period: Duration, max_backoff: Duration, failures: u32, phase: f64) -> Duration {
+    let doublings = failures.saturating_sub(1).min(31);
+    let full = period.saturating_mul(1 << doublings).min(max_backoff);
+    full / 2 + full.mul_f64(phase / 2.0)
+}
+
+impl Schedule {
+    /// A task named task that runs once every period, backing off to at most max_backoff while it
+    /// fails. The first run is due right away, later ones at this router's slot
+    pub fn new(task: &str, period: Duration, max_backoff: Duration) -> Schedule {
+        Schedule {
+            period,
+            max_backoff,
+            phase: task_phase(&device_seed(), task),
+            failures: 0,
+            next_run: Instant::now(),
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next_run
+    }
+
+    pub fn until_due(&self) -> Duration {
+        self.next_run.saturating_duration_since(Instant::now())
+    }
+
+    /// The period, or the backoff while the task is failing
+    pub fn interval(&self) -> Duration {
+        if self.failures == 0 {
+            self.period
+        } else {
+            backoff(self.period, self.max_backoff, self.failures, self.phase)
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records a successful run, the next one is at our next slot
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.next_run = Instant::now()
+            + until_next_slot(since_unix_epoch(SystemTime::now()), self.period, self.phase);
+    }
+
+    /// Records a failed run and backs off
+    pub fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        self.next_run = Instant::now() + self.interval();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use settings::client::RitaClientSettings;
+
+    #[test]
+    fn test_task_phase() {
+        let phase = task_phase(b"router", "operator_update");
+        assert_eq!(phase, task_phase(b"router", "operator_update"));
+        assert!((0.0..1.0).contains(&phase));
+        assert_ne!(phase, task_phase(b"router", "exit_status"));
+        assert_ne!(phase, task_phase(b"other router", "operator_update"));
+    }
+
+    #[test]
+    fn test_first_run_at_startup() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut schedule = Schedule::new(
+            "exit_status",
+            Duration::from_secs(600),
+            Duration::from_secs(3600),
+        );
+        assert!(schedule.is_due());
+        schedule.succeeded();
+        assert!(!schedule.is_due());
+    }
+
+    #[test]
+    fn test_until_next_slot() {
+        let period = Duration::from_secs(600);
+        // a quarter of the way in is 150s past every multiple of 600s
+        assert_eq!(
+            until_next_slot(Duration::from_secs(6000), period, 0.25),
+            Duration::from_secs(150)
+        );
+        assert_eq!(
+            until_next_slot(Duration::from_secs(6200), period, 0.25),
+            Duration::from_secs(550)
+        );
+        // right on the slot waits for the next one
+        assert_eq!(
+            until_next_slot(Duration::from_secs(6150), period, 0.25),
+            period
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let period = Duration::from_secs(5);
+        let cap = Duration::from_secs(3600);
+        assert_eq!(backoff(period, cap, 1, 0.0), Duration::from_millis(2500));
+        assert_eq!(backoff(period, cap, 3, 0.0), Duration::from_secs(10));
+        assert!(backoff(period, cap, 3, 0.99) < Duration::from_secs(20));
+        assert!(backoff(period, cap, 40, 0.99) <= cap);
+        assert!(backoff(period, cap, 40, 0.0) >= cap / 2);
+    }
+}
@@ -0,0 +1,489 @@
+//! Operator requested throughput tests between two routers, see althea_types::speed_test for the signed test
+//! format. The sender posts the test to the receiver's contact port, the receiver checks it and opens a tcp
+//! listener on its mesh ip for the sender alone. The sender then streams data over the mesh path for the
+//! agreed duration and rate, the receiver counts what arrives and writes the measurement back over the same
+//! connection. Results are queued on the sender until its next operator checkin.
+
+use crate::RitaCommonError;
+use actix_async::System;
+use actix_web_async::http::StatusCode;
+use actix_web_async::web::Json;
+use actix_web_async::HttpResponse;
+use althea_types::{SignedSpeedTest, SpeedTestOffer, SpeedTestResult};
+use clarity::Address;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Results wait here until a checkin succeeds, this bounds how many pile up while the operator is unreachable
+const MAX_PENDING_RESULTS: usize = 16;
+/// Ids of tests already run are remembered so that a captured test can't be replayed
+const MAX_SEEN_TESTS: usize = 256;
+/// How long the receiver waits for the sender to connect
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time allowed on top of the test duration before either side gives up on the other
+const GRACE_TIME: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CHUNK_SIZE: usize = 16 * 1024;
+/// How long the sender sleeps while it is ahead of the rate limit
+const PACE_TIME: Duration = Duration::from_millis(5);
+
+lazy_static! {
+    static ref SPEED_TEST_RESULTS: Arc<RwLock<VecDeque<SpeedTestResult>>> =
+        Arc::new(RwLock::new(VecDeque::new()));
+    static ref SEEN_SPEED_TESTS: Arc<RwLock<VecDeque<u64>>> =
+        Arc::new(RwLock::new(VecDeque::new()));
+}
+
+/// Only one test runs at a time in either role, so tests can't be stacked to saturate a link
+static SPEED_TEST_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The local limits a test is capped to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SpeedTestLimits {
+    signer: Address,
+    max_duration_secs: u64,
+    max_rate: u64,
+}
+
+/// The duration and rate a test actually runs with once capped to the local limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AcceptedTest {
+    duration: Duration,
+    rate: u64,
+}
+
+/// Our limits, None if this router does not take part in speed tests or its operator has not set a speed
+/// test key. Exits have no operator and never do
+fn local_limits() -> Option<SpeedTestLimits> {
+    if settings::check_if_exit() {
+        return None;
+    }
+    let operator = settings::get_rita_client().operator;
+    if !operator.allow_speed_tests {
+        return None;
+    }
+    Some(SpeedTestLimits {
+        signer: operator.speed_test_signer?,
+        max_duration_secs: operator.max_speed_test_duration,
+        max_rate: operator.max_speed_test_rate,
+    })
+}
+
+/// Checks that a test was signed by our operator's speed test key, names us in the given role and has not
+/// expired, then caps it to our limits
+fn validate_test(
+    signed: &SignedSpeedTest,
+    our_ip: IpAddr,
+    as_sender: bool,
+    limits: Option<SpeedTestLimits>,
+    now: u64,
+) -> Result<AcceptedTest, RitaCommonError> {
+    let limits = match limits {
+        Some(limits) => limits,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "Speed tests are disabled on this router".to_string(),
+            ))
+        }
+    };
+    let test = signed.test;
+    match signed.signer() {
+        Ok(signer) if signer == limits.signer => {}
+        Ok(_) => {
+            return Err(RitaCommonError::MiscStringError(
+                "Speed test was not signed by this router's speed test key".to_string(),
+            ))
+        }
+        Err(e) => return Err(RitaCommonError::MiscStringError(e.to_string())),
+    }
+    let our_role = if as_sender {
+        test.sender
+    } else {
+        test.receiver
+    };
+    if our_role != our_ip || test.sender == test.receiver {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Speed test {} is not for this router",
+            test.id
+        )));
+    }
+    if test.expiry < now {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Speed test {} has expired",
+            test.id
+        )));
+    }
+    let duration_secs = test.duration_secs.min(limits.max_duration_secs);
+    let rate = test
+        .rate_limit
+        .unwrap_or(limits.max_rate)
+        .min(limits.max_rate);
+    if duration_secs == 0 || rate == 0 {
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Speed test {} has no duration or rate",
+            test.id
+        )));
+    }
+    Ok(AcceptedTest {
+        duration: Duration::from_secs(duration_secs),
+        rate,
+    })
+}
+
+/// Records that a test id has been used, returns false if it already was
+fn mark_seen(id: u64) -> bool {
+    let mut seen = SEEN_SPEED_TESTS.write().unwrap();
+    if seen.contains(&id) {
+        return false;
+    }
+    if seen.len() >= MAX_SEEN_TESTS {
+        seen.pop_front();
+    }
+    seen.push_back(id);
+    true
+}
+
+/// Validates a test for the given role and claims the single running slot, which the caller must release
+fn accept_test(signed: &SignedSpeedTest, as_sender: bool) -> Result<AcceptedTest, RitaCommonError> {
+    let our_ip = match settings::get_rita_common().network.mesh_ip {
+        Some(ip) => ip,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "No mesh ip configured".to_string(),
+            ))
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let accepted = validate_test(signed, our_ip, as_sender, local_limits(), now)?;
+    if SPEED_TEST_RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(RitaCommonError::MiscStringError(
+            "Another speed test is already running".to_string(),
+        ));
+    }
+    if !mark_seen(signed.test.id) {
+        SPEED_TEST_RUNNING.store(false, Ordering::SeqCst);
+        return Err(RitaCommonError::MiscStringError(format!(
+            "Speed test {} has already been run",
+            signed.test.id
+        )));
+    }
+    Ok(accepted)
+}
+
+fn encode_measurement(bytes: u64, elapsed: Duration) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out[..8].copy_from_slice(&bytes.to_be_bytes());
+    out[8..].copy_from_slice(&(elapsed.as_millis() as u64).to_be_bytes());
+    out
+}
+
+fn decode_measurement(input: [u8; 16]) -> (u64, u64) {
+    let mut bytes = [0u8; 8];
+    let mut millis = [0u8; 8];
+    bytes.copy_from_slice(&input[..8]);
+    millis.copy_from_slice(&input[8..]);
+    (u64::from_be_bytes(bytes), u64::from_be_bytes(millis))
+}
+
+fn throughput_mbps(bytes: u64, duration_ms: u64) -> Option<f32> {
+    if duration_ms == 0 {
+        return None;
+    }
+    Some((bytes as f64 * 8.0 / (duration_ms as f64 * 1000.0)) as f32)
+}
+
+/// Bytes the sender may have sent after elapsed time at the given rate, one chunk is always allowed so that
+/// the test starts immediately
+fn send_allowance(elapsed: Duration, rate: u64) -> u64 {
+    (elapsed.as_secs_f64() * rate as f64) as u64 + CHUNK_SIZE as u64
+}
+
+/// Waits for the sender to connect, counts what it sends until it closes its side or the test overruns, then
+/// writes the measurement back
+fn receive(
+    listener: TcpListener,
+    sender: IpAddr,
+    test: AcceptedTest,
+) -> Result<(), RitaCommonError> {
+    listener.set_nonblocking(true)?;
+    let start = Instant::now();
+    let mut stream = loop {
+        match listener.accept() {
+            Ok((stream, addr)) if addr.ip() == sender => break stream,
+            Ok((_, addr)) => warn!("Dropping speed test connection from {}", addr),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if start.elapsed() > ACCEPT_TIMEOUT {
+                    return Err(RitaCommonError::MiscStringError(
+                        "Speed test sender never connected".to_string(),
+                    ));
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(GRACE_TIME))?;
+    let deadline = test.duration + GRACE_TIME;
+    let start = Instant::now();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut bytes = 0u64;
+    while start.elapsed() < deadline {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => bytes += n as u64,
+        }
+    }
+    stream.write_all(&encode_measurement(bytes, start.elapsed()))?;
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+/// Opens a listener for an accepted test and starts receiving in the background, returns the port
+fn start_receiver(signed: &SignedSpeedTest, test: AcceptedTest) -> Result<u16, RitaCommonError> {
+    let our_ip = signed.test.receiver;
+    let listener = TcpListener::bind(SocketAddr::new(our_ip, 0))?;
+    let port = listener.local_addr()?.port();
+    let id = signed.test.id;
+    let sender = signed.test.sender;
+    info!(
+        "Receiving speed test {} from {} for {:?}",
+        id, sender, test.duration
+    );
+    thread::spawn(move || {
+        if let Err(e) = receive(listener, sender, test) {
+            warn!("Speed test {} failed {:?}", id, e);
+        }
+        SPEED_TEST_RUNNING.store(false, Ordering::SeqCst);
+    });
+    Ok(port)
+}
+
+/// Contact port endpoint, a sender asks us to receive a speed test
+pub async fn receive_speed_test(signed: Json<SignedSpeedTest>) -> HttpResponse {
+    let signed = signed.into_inner();
+    let test = match accept_test(&signed, false) {
+        Ok(test) => test,
+        Err(e) => return HttpResponse::build(StatusCode::FORBIDDEN).json(e.to_string()),
+    };
+    match start_receiver(&signed, test) {
+        Ok(port) => HttpResponse::Ok().json(SpeedTestOffer {
+            port,
+            duration_secs: test.duration.as_secs(),
+            rate_limit: test.rate,
+        }),
+        Err(e) => {
+            SPEED_TEST_RUNNING.store(false, Ordering::SeqCst);
+            HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(e.to_string())
+        }
+    }
+}
+
+async fn request_offer(signed: &SignedSpeedTest) -> Result<SpeedTestOffer, RitaCommonError> {
+    let contact_port = settings::get_rita_common().network.rita_contact_port;
+    let receiver = signed.test.receiver;
+    let url = format!("http://[{receiver}]:{contact_port}/speed_test");
+    let client = awc::Client::default();
+    let mut response = client
+        .post(&url)
+        .timeout(REQUEST_TIMEOUT)
+        .send_json(signed)
+        .await?;
+    if !response.status().is_success() {
+        let message: String = response.json().await.unwrap_or_default();
+        return Err(RitaCommonError::MiscStringError(format!(
+            "{receiver} refused the speed test {message}"
+        )));
+    }
+    Ok(response.json().await?)
+}
+
+/// Streams data to the receiver at no more than the agreed rate, returns the receiver's measurement
+fn send(receiver: SocketAddr, test: AcceptedTest) -> Result<(u64, u64), RitaCommonError> {
+    let mut stream = TcpStream::connect_timeout(&receiver, REQUEST_TIMEOUT)?;
+    stream.set_write_timeout(Some(GRACE_TIME))?;
+    stream.set_read_timeout(Some(GRACE_TIME * 2))?;
+    let buf = [0u8; CHUNK_SIZE];
+    let start = Instant::now();
+    let mut sent = 0u64;
+    while start.elapsed() < test.duration {
+        if sent >= send_allowance(start.elapsed(), test.rate) {
+            thread::sleep(PACE_TIME);
+            continue;
+        }
+        stream.write_all(&buf)?;
+        sent += CHUNK_SIZE as u64;
+    }
+    stream.shutdown(Shutdown::Write)?;
+    let mut measurement = [0u8; 16];
+    stream.read_exact(&mut measurement)?;
+    Ok(decode_measurement(measurement))
+}
+
+async fn run_speed_test(
+    signed: &SignedSpeedTest,
+    test: AcceptedTest,
+) -> Result<(u64, u64), RitaCommonError> {
+    let offer = request_offer(signed).await?;
+    // the receiver may have capped the test further
+    let test = AcceptedTest {
+        duration: test.duration.min(Duration::from_secs(offer.duration_secs)),
+        rate: test.rate.min(offer.rate_limit),
+    };
+    info!(
+        "Sending speed test {} to {} for {:?} at up to {} bytes/s",
+        signed.test.id, signed.test.receiver, test.duration, test.rate
+    );
+    send(SocketAddr::new(signed.test.receiver, offer.port), test)
+}
+
+fn push_result(result: SpeedTestResult) {
+    info!("Speed test result {:?}", result);
+    let mut results = SPEED_TEST_RESULTS.write().unwrap();
+    if results.len() >= MAX_PENDING_RESULTS {
+        results.pop_front();
+    }
+    results.push_back(result);
+}
+
+/// Runs a speed test the operator asked this router to send, in the background. The result is queued for
+/// the next operator checkin, including when the test is refused
+pub fn start_speed_test(signed: SignedSpeedTest) {
+    let result = SpeedTestResult {
+        id: signed.test.id,
+        sender: signed.test.sender,
+        receiver: signed.test.receiver,
+        bytes: 0,
+        duration_ms: 0,
+        mbps: None,
+        error: None,
+    };
+    let test = match accept_test(&signed, true) {
+        Ok(test) => test,
+        Err(e) => {
+            push_result(SpeedTestResult {
+                error: Some(e.to_string()),
+                ..result
+            });
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let runner = System::new();
+        let res = runner.block_on(async move { run_speed_test(&signed, test).await });
+        SPEED_TEST_RUNNING.store(false, Ordering::SeqCst);
+        match res {
+            Ok((bytes, duration_ms)) => push_result(SpeedTestResult {
+                bytes,
+                duration_ms,
+                mbps: throughput_mbps(bytes, duration_ms),
+                ..result
+            }),
+            Err(e) => push_result(SpeedTestResult {
+                error: Some(e.to_string()),
+                ..result
+            }),
+        }
+    });
+}
+
+/// Results waiting to be sent to the operator
+pub fn get_speed_test_results() -> Vec<SpeedTestResult> {
+    SPEED_TEST_RESULTS.read().unwrap().iter().cloned().collect()
+}
+
+/// Drops results once the operator has them
+pub fn remove_speed_test_results(ids: &[u64]) {
+    SPEED_TEST_RESULTS
+        .write()
+        .unwrap()
+        .retain(|r| !ids.contains(&r.id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::SpeedTest;
+    use clarity::PrivateKey;
+
+    fn speed_test_key() -> PrivateKey {
+        "0x0000000000000000000000000000000000000000000000000000000000000001"
+            .parse()
+            .unwrap()
+    }
+
+    fn limits() -> Option<SpeedTestLimits> {
+        Some(SpeedTestLimits {
+            signer: speed_test_key().to_address(),
+            max_duration_secs: 10,
+            max_rate: 1_000_000,
+        })
+    }
+
+    fn signed_test(key: PrivateKey) -> SignedSpeedTest {
+        SpeedTest {
+            id: 1,
+            sender: "fd00::1".parse().unwrap(),
+            receiver: "fd00::2".parse().unwrap(),
+            duration_secs: 60,
+            rate_limit: None,
+            expiry: 1000,
+        }
+        .sign(key)
+    }
+
+    #[test]
+    fn test_validate_test() {
+        let sender: IpAddr = "fd00::1".parse().unwrap();
+        let receiver: IpAddr = "fd00::2".parse().unwrap();
+        let signed = signed_test(speed_test_key());
+
+        // capped to our limits in either role
+        let expected = AcceptedTest {
+            duration: Duration::from_secs(10),
+            rate: 1_000_000,
+        };
+        assert_eq!(
+            validate_test(&signed, sender, true, limits(), 500).unwrap(),
+            expected
+        );
+        assert_eq!(
+            validate_test(&signed, receiver, false, limits(), 500).unwrap(),
+            expected
+        );
+
+        // wrong role, expired, disabled
+        assert!(validate_test(&signed, receiver, true, limits(), 500).is_err());
+        assert!(validate_test(&signed, sender, true, limits(), 1001).is_err());
+        assert!(validate_test(&signed, sender, true, None, 500).is_err());
+
+        // signed by some other key, such as the operator's own
+        let other: PrivateKey =
+            "0x0000000000000000000000000000000000000000000000000000000000000002"
+                .parse()
+                .unwrap();
+        assert!(validate_test(&signed_test(other), sender, true, limits(), 500).is_err());
+    }
+
+    #[test]
+    fn test_measurement() {
+        let encoded = encode_measurement(12_500_000, Duration::from_millis(10_000));
+        assert_eq!(decode_measurement(encoded), (12_500_000, 10_000));
+        assert_eq!(throughput_mbps(12_500_000, 10_000), Some(10.0));
+        assert_eq!(throughput_mbps(100, 0), None);
+    }
+
+    #[test]
+    fn test_send_allowance() {
+        assert_eq!(send_allowance(Duration::ZERO, 1000), CHUNK_SIZE as u64);
+        assert_eq!(
+            send_allowance(Duration::from_secs(2), 1000),
+            2000 + CHUNK_SIZE as u64
+        );
+    }
+}
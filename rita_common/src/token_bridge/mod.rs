@@ -26,6 +26,7 @@ pub mod xdai_bridge;
 use crate::rita_loop::slow_loop::SLOW_LOOP_TIMEOUT;
 use crate::token_bridge::xdai_bridge::*;
 use crate::RitaCommonError;
+use althea_types::Amount;
 use althea_types::SystemChain;
 use auto_bridge::encode_relaytokens;
 use auto_bridge::TokenBridge as TokenBridgeCore;
@@ -43,13 +44,11 @@ lazy_static! {
 
 pub const ETH_TRANSFER_TIMEOUT: Duration = Duration::from_secs(600);
 
-const WEI_PER_ETH: u128 = 1_000_000_000_000_000_000_u128;
 const SIGNATURES_TIMEOUT: Duration = ETH_TRANSFER_TIMEOUT;
 const BLOCKS: u64 = 720;
 
 pub fn eth_to_wei(eth: u64) -> Uint256 {
-    let wei = eth as u128 * WEI_PER_ETH;
-    wei.into()
+    Amount::from_ether(eth).wei()
 }
 
 /// This struct contains the state of the bridge. TokenBridgeAmounts contains the
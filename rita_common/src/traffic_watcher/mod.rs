@@ -13,8 +13,8 @@ use crate::KI;
 use althea_kernel_interface::open_tunnel::is_link_local;
 use althea_kernel_interface::FilterTarget;
 use althea_types::Identity;
+use babel_monitor::parsing::get_billing_routes;
 use babel_monitor::structs::Route;
-use ipnetwork::IpNetwork;
 
 use std::collections::HashMap;
 use std::net::IpAddr;
@@ -65,33 +65,25 @@ pub fn prepare_helper_maps(
 pub fn get_babel_info(routes: Vec<Route>) -> Result<(HashMap<IpAddr, i128>, u32), RitaCommonError> {
     trace!("Got {} routes: {:?}", routes.len(), routes);
     let mut destinations = HashMap::new();
-    let common = settings::get_rita_common();
+    let common = settings::get_rita_common_snapshot();
     // we assume this matches what is actually set it babel because we
     // panic on startup if it does not get set correctly
-    let local_fee = common.network.babeld_settings.local_fee;
-    let max_fee = common.payment.max_fee;
-    for route in &routes {
-        // Only ip6
-        if let IpNetwork::V6(ref ip) = route.prefix {
-            // Only host addresses and installed routes
-            if ip.prefix() == 128 && route.installed {
-                let price = if route.price > max_fee {
-                    max_fee
-                } else {
-                    route.price
-                };
-
-                trace!(
-                    "Inserting {} into the destinations map",
-                    IpAddr::V6(ip.ip())
-                );
-                destinations.insert(IpAddr::V6(ip.ip()), i128::from(price + local_fee));
-            }
-        }
+    let local_fee = common.network().babeld_settings.local_fee;
+    let max_fee = common.payment().max_fee;
+    // one route per destination even where babel installed source specific routes to it
+    for (ip, route) in get_billing_routes(&routes) {
+        let price = if route.price > max_fee {
+            max_fee
+        } else {
+            route.price
+        };
+
+        trace!("Inserting {} into the destinations map", ip);
+        destinations.insert(ip, i128::from(price + local_fee));
     }
 
     destinations.insert(
-        match common.network.mesh_ip {
+        match common.network().mesh_ip {
             Some(ip) => ip,
             None => {
                 return Err(RitaCommonError::MiscStringError(
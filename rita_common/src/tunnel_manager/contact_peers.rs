@@ -4,21 +4,29 @@
 //! it's mostly used for Gateways to reach exits and bridge them into the local babel mesh network, allowing clients
 //! to reach them and send traffic to the internet.
 
+use crate::extensions::{local_extensions, record_neighbor_extensions};
 use crate::peer_listener::send_hello;
 use crate::peer_listener::structs::Hello as NewHello;
 use crate::peer_listener::structs::Peer;
 use crate::peer_listener::structs::PeerListener;
 use crate::rita_loop::is_gateway;
 use crate::tm_identity_callback;
-use crate::tunnel_manager::get_tunnel_manager;
+use crate::tunnel_manager::with_tunnel_manager;
 use crate::IdentityCallback;
 use crate::RitaCommonError;
 use crate::KI;
-use althea_types::LocalIdentity;
-use futures::future::join_all;
+use althea_types::{ExtendedLocalIdentity, LocalIdentity};
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use futures::stream::{self, StreamExt};
 use std::net::ToSocketAddrs;
 use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
+use tokio::time::timeout as future_timeout;
+
+/// Max number of neighbor contacts that may be in flight at the same time
+pub const MAX_CONCURRENT_CONTACTS: usize = 16;
+/// Overall time budget for contacting every peer in a single peer discovery tick
+pub const CONTACT_PEERS_DEADLINE: Duration = Duration::from_secs(4);
 
 /// Resolves a hostname and sends a hello to the resulting IP, this function may block, this is the
 /// primary reason peer discovery is given it's own thread currently.
@@ -71,7 +79,7 @@ pub struct Hello {
 
 pub async fn tm_neighbor_inquiry_manual_peer(peer: Peer) -> Result<(), RitaCommonError> {
     trace!("TunnelManager neigh inquiry for {:?}", peer);
-    let our_port = get_tunnel_manager().get_next_available_port()?;
+    let our_port = with_tunnel_manager(|tm| tm.get_next_available_port())?;
     let mut settings = settings::get_rita_common();
     let changed = KI.manual_peers_route(
         &peer.contact_socket.ip(),
@@ -115,7 +123,10 @@ pub async fn tm_neighbor_inquiry_manual_peer(peer: Peer) -> Result<(), RitaCommo
     let response = client
         .post(endpoint)
         .timeout(Duration::from_secs(5))
-        .send_json(&msg.my_id)
+        .send_json(&ExtendedLocalIdentity {
+            identity: msg.my_id,
+            extensions: local_extensions(),
+        })
         .await;
 
     let mut response = match response {
@@ -126,13 +137,15 @@ pub async fn tm_neighbor_inquiry_manual_peer(peer: Peer) -> Result<(), RitaCommo
         }
     };
 
-    let response: LocalIdentity = match response.json().await {
+    let response: ExtendedLocalIdentity = match response.json().await {
         Ok(a) => a,
         Err(e) => {
             error!("Got error deserializing Hello {:?}", e);
             return Err(RitaCommonError::JsonPayloadError(e));
         }
     };
+    record_neighbor_extensions(response.identity.global.wg_public_key, response.extensions);
+    let response = response.identity;
 
     info!("Received a local identity, setting a tunnel");
     let peer = msg.to;
@@ -147,7 +160,7 @@ pub async fn tm_neighbor_inquiry_manual_peer(peer: Peer) -> Result<(), RitaCommo
 /// interface name. Sends a Hello over udp
 pub fn tm_neighbor_inquiry_udp_peer(peer: &Peer, pl: &PeerListener) -> Result<(), RitaCommonError> {
     trace!("TunnelManager neigh inquiry for {:?}", peer);
-    let our_port = get_tunnel_manager().get_next_available_port()?;
+    let our_port = with_tunnel_manager(|tm| tm.get_next_available_port())?;
 
     let peer_listener = pl;
     let iface_name = match peer_listener.interface_map.get(&peer.contact_socket) {
@@ -187,6 +200,10 @@ pub fn tm_neighbor_inquiry_udp_peer(peer: &Peer, pl: &PeerListener) -> Result<()
 /// takes a list of peers to contact and dispatches UDP hello messages to peers discovered via IPv6 link local
 /// multicast peer discovery, also sends http hello messages to manual peers, only resolves manual peers with
 /// hostnames if the devices is detected to be a gateway.
+///
+/// Contacts are run concurrently, at most MAX_CONCURRENT_CONTACTS at a time, and the whole operation is bounded
+/// by CONTACT_PEERS_DEADLINE so that large gateways with dozens of peers don't stall the peer discovery loop.
+/// Any contacts not complete by the deadline are dropped and simply retried next tick
 pub async fn tm_contact_peers(pl: &PeerListener) {
     let network_settings = settings::get_rita_common().network;
     let manual_peers = network_settings.manual_peers.clone();
@@ -196,13 +213,18 @@ pub async fn tm_contact_peers(pl: &PeerListener) {
 
     trace!("TunnelManager contacting peers");
 
-    let mut manual_peers_ip_fut = Vec::new();
-    let mut manual_peers_dns_fut = Vec::new();
+    let mut contacts: Vec<LocalBoxFuture<'_, (String, Result<(), RitaCommonError>)>> = Vec::new();
     for (_, peer) in pl.peers.iter() {
         trace!("contacting peer found by UDP {:?}", peer);
-        if let Err(e) = tm_neighbor_inquiry_udp_peer(peer, pl) {
-            error!("Neighbor inqury for {} failed with: {:?}", peer.ifidx, e);
-        }
+        contacts.push(
+            async move {
+                (
+                    format!("udp peer on {}", peer.ifidx),
+                    tm_neighbor_inquiry_udp_peer(peer, pl),
+                )
+            }
+            .boxed_local(),
+        );
     }
     for manual_peer in manual_peers.iter() {
         trace!("contacting manual peer {:?}", manual_peer);
@@ -215,8 +237,15 @@ pub async fn tm_contact_peers(pl: &PeerListener) {
                     ifidx: 0,
                     contact_socket: socket,
                 };
-                // we must run these in the local context because the peer struct does not live long enough
-                manual_peers_ip_fut.push(tm_neighbor_inquiry_manual_peer(man_peer));
+                contacts.push(
+                    async move {
+                        (
+                            format!("manual peer {ip}"),
+                            tm_neighbor_inquiry_manual_peer(man_peer).await,
+                        )
+                    }
+                    .boxed_local(),
+                );
             }
             Err(_) => {
                 // Do not contact manual peers on the internet if we are not a gateway
@@ -224,28 +253,39 @@ pub async fn tm_contact_peers(pl: &PeerListener) {
                 // in bad behavior, we do allow the addressing of direct ip address gateways
                 // for the special case that the user is attempting some special behavior
                 if is_gateway {
-                    manual_peers_dns_fut
-                        .push(tm_neighbor_inquiry_hostname(manual_peer.to_string()));
+                    let hostname = manual_peer.to_string();
+                    contacts.push(
+                        async move {
+                            let res = tm_neighbor_inquiry_hostname(hostname.clone()).await;
+                            (format!("manual peer {hostname}"), res)
+                        }
+                        .boxed_local(),
+                    );
                 }
             }
         }
     }
 
-    // now that we've constructed our futures arrays, now we execute them in parallel
-    // executing udp peers in this way has no advantage of doing them sync becuase it's all
-    // sync actions, but for manual peers the advantage is huge since there are half a dozen exits
-    // and they each may take seconds to respond, joining the udp peers in just lets us run their sync
-    // operations while efficiencly waiting for http exit responses
-    let manual_result_ip = join_all(manual_peers_ip_fut).await;
-    let manual_result_dns = join_all(manual_peers_dns_fut).await;
-    for r in manual_result_ip {
-        if let Err(e) = r {
-            error!("Neighbor inqury for ip failed with: {:?}", e);
-        }
-    }
-    for r in manual_result_dns {
-        if let Err(e) = r {
-            error!("Neighbor inqury for dns failed with: {:?}", e);
-        }
+    // udp contacts are sync and gain nothing from this, but manual peers are http requests to exits
+    // that may each take seconds to respond, so running them concurrently is a huge advantage
+    let total = contacts.len();
+    let mut completed = 0;
+    let contact_all = stream::iter(contacts)
+        .buffer_unordered(MAX_CONCURRENT_CONTACTS)
+        .for_each(|(peer, res)| {
+            completed += 1;
+            if let Err(e) = res {
+                error!("Neighbor inqury for {} failed with: {:?}", peer, e);
+            }
+            future::ready(())
+        });
+    if future_timeout(CONTACT_PEERS_DEADLINE, contact_all)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Contacting peers hit the {:?} deadline, {} of {} contacts completed",
+            CONTACT_PEERS_DEADLINE, completed, total
+        );
     }
 }
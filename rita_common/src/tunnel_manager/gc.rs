@@ -1,9 +1,11 @@
 use super::{Tunnel, TunnelManager};
+use crate::extensions::prune_neighbor_extensions;
 use crate::KI;
 use althea_types::Identity;
 use babel_monitor::structs::Interface;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
-use std::{collections::HashMap, time::Instant};
+use std::time::Instant;
 
 impl TunnelManager {
     /// Performs a cleanup of all babel tunnels that we have not heard from in the configured time
@@ -30,33 +32,16 @@ impl TunnelManager {
     ) {
         let interfaces = into_interfaces_hashmap(&babel_interfaces);
         trace!("Starting tunnel gc {:?}", interfaces);
-        let mut good: HashMap<Identity, Vec<Tunnel>> = HashMap::new();
-        let mut to_delete: HashMap<Identity, Vec<Tunnel>> = HashMap::new();
-        // Split entries into good and timed out rebuilding the double hashmap structure
-        // as you can tell this is totally copy based and uses 2n ram to prevent borrow
-        // checker issues, we should consider a method that does modify in place
-        for (identity, tunnels) in self.tunnels.iter() {
-            for tunnel in tunnels.iter() {
-                if tunnel_should_be_kept(
-                    *identity,
-                    tunnel,
-                    tunnel_handshake_timeout,
-                    tunnel_timeout,
-                    &interfaces,
-                ) {
-                    insert_into_tunnel_list(tunnel, &mut good);
-                } else {
-                    insert_into_tunnel_list(tunnel, &mut to_delete)
-                }
-            }
-        }
-
-        for (id, tunnels) in to_delete.iter() {
-            for tunnel in tunnels {
-                info!("TriggerGC: removing tunnel: {} {}", id, tunnel);
-            }
+        // a handshake proves the peer holds the key, so a new identity claimed for it can be trusted
+        for id in self
+            .tunnels
+            .confirm(|tunnel| check_handshake_time(tunnel_handshake_timeout, &tunnel.iface_name))
+        {
+            info!("Handshake confirmed new identity {} for its key", id);
         }
-
+        // Split entries into good and timed out, the timed out ones are taken out of the map
+        // in place
+        //
         // Please keep in mind it makes more sense to update the tunnel map *before* yielding the
         // actual interfaces and ports from timed_out.
         //
@@ -65,23 +50,43 @@ impl TunnelManager {
         //
         // The former would be a mere performance bug while inconsistent-with-reality Rita state
         // would lead to nasty bugs in case del_interface() goes wrong for whatever reason.
-        self.tunnels = good;
+        let to_delete = self.tunnels.retain(|identity, tunnel| {
+            tunnel_should_be_kept(
+                *identity,
+                tunnel,
+                tunnel_handshake_timeout,
+                tunnel_timeout,
+                &interfaces,
+            )
+        });
+
+        for tunnel in to_delete.iter() {
+            info!(
+                "TriggerGC: removing tunnel: {} {}",
+                tunnel.neigh_id.global, tunnel
+            );
+        }
 
         unmonitor_tunnels(to_delete);
+
+        let neighbors: HashSet<_> = self
+            .tunnels
+            .tunnels()
+            .map(|tunnel| tunnel.neigh_id.global.wg_public_key)
+            .collect();
+        prune_neighbor_extensions(&neighbors);
     }
 }
 
-fn unmonitor_tunnels(to_delete: HashMap<Identity, Vec<Tunnel>>) {
-    for (_ident, tunnels) in to_delete {
-        for tunnel in tunnels {
-            // In the same spirit, we return the port to the free port pool only after tunnel
-            // deletion goes well.
-            if let Err(e) = tunnel.unmonitor() {
-                error!(
-                    "Tunnel unmonitor failed during gc, garbage idle tunnel! {:?}",
-                    e
-                );
-            }
+fn unmonitor_tunnels(to_delete: Vec<Tunnel>) {
+    for tunnel in to_delete {
+        // In the same spirit, we return the port to the free port pool only after tunnel
+        // deletion goes well.
+        if let Err(e) = tunnel.unmonitor() {
+            error!(
+                "Tunnel unmonitor failed during gc, garbage idle tunnel! {:?}",
+                e
+            );
         }
     }
 }
@@ -177,18 +182,6 @@ fn tunnel_should_be_kept(
     }
 }
 
-/// A simple helper function to reduce the number of if/else statements in tunnel GC
-pub fn insert_into_tunnel_list(input: &Tunnel, tunnels_list: &mut HashMap<Identity, Vec<Tunnel>>) {
-    let identity = &input.neigh_id.global;
-    let input = input.clone();
-    match tunnels_list.get_mut(identity) {
-        None => {
-            tunnels_list.insert(*identity, vec![input]);
-        }
-        Some(v) => v.push(input),
-    }
-}
-
 /// This function checks the handshake time of a tunnel when compared to the handshake timeout,
 /// it returns false if we fail to get the handshake time or if all last tunnel handshakes are
 /// older than the allowed time limit
@@ -0,0 +1,191 @@
+//! Persistent keepalive for mesh tunnels, see settings::network::TunnelKeepaliveSettings. Neighbors on
+//! the same link reach us over link local addresses and never cross a nat, their tunnels are left without
+//! keepalive to save airtime and battery. Tunnels to manual peers over the internet start with keepalive
+//! and keep it if the path crosses a nat, which we can tell from our external address being private or
+//! from wireguard seeing the peer's packets arrive from a different address or port than the one it told
+//! us in its hello. Checked from the slow loop, the keepalive is only changed on tunnels where it differs.
+
+use crate::tunnel_manager::{get_tunnel_manager_write_ref, with_tunnel_manager, TUNNEL_MANAGER};
+use crate::KI;
+use althea_kernel_interface::open_tunnel::is_link_local;
+use althea_types::WgKey;
+use settings::network::TunnelKeepaliveSettings;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// The keepalive a new tunnel starts with, before wireguard has heard from the peer
+pub fn initial_keepalive(
+    settings: &TunnelKeepaliveSettings,
+    peer: &WgKey,
+    endpoint: IpAddr,
+) -> u16 {
+    desired_keepalive(settings, peer, SocketAddr::new(endpoint, 0), None, false)
+}
+
+/// The keepalive a tunnel should have, `configured` is the endpoint from the peer's hello and
+/// `observed` where wireguard last heard from it
+fn desired_keepalive(
+    settings: &TunnelKeepaliveSettings,
+    peer: &WgKey,
+    configured: SocketAddr,
+    observed: Option<SocketAddr>,
+    behind_nat: bool,
+) -> u16 {
+    if let Some(keepalive) = settings.overrides.get(peer) {
+        return *keepalive;
+    }
+    if !settings.auto || crosses_nat(configured, observed, behind_nat) {
+        settings.interval
+    } else {
+        0
+    }
+}
+
+/// Until the peer has been heard from an internet path is assumed to cross a nat
+fn crosses_nat(configured: SocketAddr, observed: Option<SocketAddr>, behind_nat: bool) -> bool {
+    if is_link_local(configured.ip()) {
+        return false;
+    }
+    match observed {
+        Some(observed) => behind_nat || observed != configured,
+        None => true,
+    }
+}
+
+/// Private and carrier grade nat addresses are only ever reached through a nat
+fn is_nat_address(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_private() || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+}
+
+/// True if our internet facing interface only has private addresses
+fn we_are_behind_nat(external_nic: &Option<String>) -> bool {
+    let nic = match external_nic {
+        Some(nic) => nic,
+        None => return false,
+    };
+    match KI.get_ip_from_iface(nic) {
+        Ok(addresses) => {
+            !addresses.is_empty() && addresses.iter().all(|(ip, _)| is_nat_address(*ip))
+        }
+        Err(e) => {
+            warn!("Unable to get the addresses of {} {:?}", nic, e);
+            false
+        }
+    }
+}
+
+/// Called from the slow loop, sets the keepalive of every tunnel whose path changed
+pub fn tick_tunnel_keepalive() {
+    let network = settings::get_rita_common().network;
+    let behind_nat = we_are_behind_nat(&network.external_nic);
+    let tunnels: Vec<(String, WgKey, SocketAddr, u16)> = with_tunnel_manager(|tunnel_manager| {
+        tunnel_manager
+            .tunnels
+            .tunnels()
+            .map(|t| {
+                (
+                    t.iface_name.clone(),
+                    t.neigh_id.global.wg_public_key,
+                    SocketAddr::new(t.ip, t.neigh_id.wg_port),
+                    t.keepalive,
+                )
+            })
+            .collect()
+    });
+
+    let mut changed = Vec::new();
+    for (iface, peer, configured, current) in tunnels {
+        let observed = match KI.get_wg_endpoint(&iface) {
+            Ok(observed) => observed,
+            Err(e) => {
+                trace!("Unable to get the endpoint of {} {:?}", iface, e);
+                continue;
+            }
+        };
+        let desired = desired_keepalive(
+            &network.tunnel_keepalive,
+            &peer,
+            configured,
+            observed,
+            behind_nat,
+        );
+        if desired == current {
+            continue;
+        }
+        info!(
+            "Setting keepalive on {} to {}s, observed endpoint {:?}",
+            iface, desired, observed
+        );
+        match KI.set_peer_keepalive(&iface, &peer, desired) {
+            Ok(()) => changed.push((iface, desired)),
+            Err(e) => warn!("Failed to set keepalive on {} {:?}", iface, e),
+        }
+    }
+    if changed.is_empty() {
+        return;
+    }
+
+    let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
+    let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
+    for (_, tunnels) in tunnel_manager.tunnels.iter_mut() {
+        for tunnel in tunnels.iter_mut() {
+            if let Some((_, keepalive)) = changed.iter().find(|(i, _)| *i == tunnel.iface_name) {
+                tunnel.keepalive = *keepalive;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_desired_keepalive() {
+        let peer: WgKey = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap();
+        let mut settings = TunnelKeepaliveSettings::default();
+        let link_local: SocketAddr = "[fe80::1]:60000".parse().unwrap();
+        let internet: SocketAddr = "71.8.186.226:60000".parse().unwrap();
+        let remapped: SocketAddr = "71.8.186.226:41234".parse().unwrap();
+
+        assert_eq!(initial_keepalive(&settings, &peer, link_local.ip()), 0);
+        assert_eq!(initial_keepalive(&settings, &peer, internet.ip()), 25);
+        // heard from on the port it told us, nothing in between
+        assert_eq!(
+            desired_keepalive(&settings, &peer, internet, Some(internet), false),
+            0
+        );
+        // the peer's nat rewrote the port
+        assert_eq!(
+            desired_keepalive(&settings, &peer, internet, Some(remapped), false),
+            25
+        );
+        // we are behind a nat ourselves
+        assert_eq!(
+            desired_keepalive(&settings, &peer, internet, Some(internet), true),
+            25
+        );
+
+        settings.overrides.insert(peer, 10);
+        assert_eq!(
+            desired_keepalive(&settings, &peer, link_local, Some(link_local), false),
+            10
+        );
+        settings.overrides.clear();
+        settings.auto = false;
+        assert_eq!(
+            desired_keepalive(&settings, &peer, link_local, Some(link_local), false),
+            25
+        );
+    }
+
+    #[test]
+    fn test_is_nat_address() {
+        assert!(is_nat_address("192.168.1.10".parse().unwrap()));
+        assert!(is_nat_address("100.72.3.4".parse().unwrap()));
+        assert!(!is_nat_address("100.128.3.4".parse().unwrap()));
+        assert!(!is_nat_address("71.8.186.226".parse().unwrap()));
+    }
+}
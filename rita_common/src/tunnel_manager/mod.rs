@@ -8,13 +8,17 @@ pub mod contact_peers;
 pub mod error;
 pub mod gc;
 pub mod id_callback;
+pub mod keepalive;
+pub mod mtu;
 pub mod neighbor_status;
 pub mod shaping;
+pub mod tunnel_map;
 
 use crate::blockchain_oracle::potential_payment_issues_detected;
-use crate::insert_into_tunnel_list;
 use crate::peer_listener::structs::Peer;
 use crate::tunnel_manager::error::TunnelManagerError;
+use crate::tunnel_manager::keepalive::initial_keepalive;
+use crate::tunnel_manager::tunnel_map::TunnelMap;
 use crate::RitaCommonError;
 use crate::Shaper;
 use crate::FAST_LOOP_TIMEOUT;
@@ -25,7 +29,7 @@ use althea_kernel_interface::open_tunnel::TunnelOpenArgs;
 use althea_types::Identity;
 use althea_types::LocalIdentity;
 use babel_monitor::monitor;
-use babel_monitor::open_babel_stream;
+use babel_monitor::pool::query_babel;
 use babel_monitor::structs::BabelMonitorError;
 use babel_monitor::structs::Interface;
 use babel_monitor::unmonitor;
@@ -51,12 +55,22 @@ pub fn get_tunnel_manager() -> TunnelManager {
     TUNNEL_MANAGER
         .read()
         .unwrap()
-        .clone()
         .get(&netns)
         .cloned()
         .unwrap_or_default()
 }
 
+/// Runs a read only function against the TunnelManager without copying it, prefer this over
+/// get_tunnel_manager() when only a little of the tunnel list is needed
+pub fn with_tunnel_manager<T>(read: impl FnOnce(&TunnelManager) -> T) -> T {
+    let netns = KI.check_integration_test_netns();
+    let tunnel_managers = TUNNEL_MANAGER.read().unwrap();
+    match tunnel_managers.get(&netns) {
+        Some(tunnel_manager) => read(tunnel_manager),
+        None => read(&TunnelManager::default()),
+    }
+}
+
 /// Gets a write ref for the tunnel manager lock, since this is a mutable reference
 /// the lock will be held until you drop the return value, this lets the caller abstract the namespace handling
 /// but still hold the lock in the local thread to prevent parallel modification
@@ -127,6 +141,8 @@ pub struct Tunnel {
     /// all routers do only exits are in question
     pub speed_limit: Option<usize>,
     payment_state: PaymentState,
+    /// The wireguard persistent keepalive set on this tunnel in seconds, see keepalive
+    pub keepalive: u16,
 }
 
 impl Display for Tunnel {
@@ -164,6 +180,11 @@ impl Tunnel {
         };
         // after this step we have created a blank wg interface that we should clean up if we fail
         let iface_name = KI.create_blank_wg_numbered_wg_interface()?;
+        let keepalive = initial_keepalive(
+            &network.tunnel_keepalive,
+            &neigh_id.global.wg_public_key,
+            ip,
+        );
 
         let args = TunnelOpenArgs {
             interface: iface_name.clone(),
@@ -175,6 +196,7 @@ impl Tunnel {
             own_ip_v2: network.mesh_ip_v2,
             external_nic: network.external_nic.clone(),
             settings_default_route: &mut network.last_default_route,
+            persistent_keepalive: keepalive,
         };
 
         if let Err(e) = KI.open_tunnel(args) {
@@ -200,6 +222,7 @@ impl Tunnel {
             speed_limit,
             // By default new tunnels are in paid state
             payment_state: PaymentState::Paid,
+            keepalive,
         };
 
         // If we fail to set this up in babeld we should try again in a moment
@@ -224,8 +247,9 @@ impl Tunnel {
         let babel_port = settings::get_rita_common().network.babel_port;
 
         // this operation blocks while opening and using a tcp stream
-        let mut stream = open_babel_stream(babel_port, FAST_LOOP_TIMEOUT)?;
-        monitor(&mut stream, &iface_name, None)
+        query_babel(babel_port, FAST_LOOP_TIMEOUT, |stream| {
+            monitor(stream, &iface_name, None)
+        })
     }
 
     pub fn unmonitor(&self) -> Result<(), RitaCommonError> {
@@ -235,8 +259,9 @@ impl Tunnel {
         let tunnel = self.clone();
 
         // this operation blocks while opening and using a tcp stream
-        let mut stream = open_babel_stream(babel_port, FAST_LOOP_TIMEOUT)?;
-        unmonitor(&mut stream, &iface_name)?;
+        query_babel(babel_port, FAST_LOOP_TIMEOUT, |stream| {
+            unmonitor(stream, &iface_name)
+        })?;
 
         // We must wait until we have flushed the interface before deleting it
         // otherwise we will experience this error
@@ -251,7 +276,7 @@ impl Tunnel {
 
 #[derive(Clone)]
 pub struct TunnelManager {
-    tunnels: HashMap<Identity, Vec<Tunnel>>,
+    tunnels: TunnelMap,
     shaper: Shaper,
 }
 
@@ -286,19 +311,25 @@ impl Neighbor {
 }
 
 pub fn tm_get_neighbors() -> Vec<Neighbor> {
-    let tunnel_manager = get_tunnel_manager();
-    let mut res = Vec::new();
-    for (_, tunnels) in tunnel_manager.tunnels.iter() {
-        for tunnel in tunnels.iter() {
-            res.push(Neighbor::new(
-                tunnel.neigh_id,
-                tunnel.iface_name.clone(),
-                tunnel.ip,
-                tunnel.speed_limit,
-            ));
-        }
-    }
-    res
+    with_tunnel_manager(|tunnel_manager| {
+        tunnel_manager
+            .tunnels
+            .tunnels()
+            .map(|tunnel| {
+                Neighbor::new(
+                    tunnel.neigh_id,
+                    tunnel.iface_name.clone(),
+                    tunnel.ip,
+                    tunnel.speed_limit,
+                )
+            })
+            .collect()
+    })
+}
+
+/// The number of open tunnels, without copying the tunnel manager
+pub fn tm_tunnel_count() -> usize {
+    with_tunnel_manager(|tunnel_manager| tunnel_manager.tunnels.tunnel_count())
 }
 
 /// Simple helper function to run tunnel GC + check babel interfaces
@@ -323,7 +354,7 @@ pub fn tm_tunnel_state_change(msg: Vec<TunnelChange>) -> Result<(), RitaCommonEr
 impl TunnelManager {
     pub fn new() -> Self {
         TunnelManager {
-            tunnels: HashMap::new(),
+            tunnels: TunnelMap::new(),
             shaper: Shaper::default(),
         }
     }
@@ -331,13 +362,11 @@ impl TunnelManager {
     /// Gets all ports currently in use by TunnelManager
     fn get_all_used_ports(&self) -> HashSet<u16> {
         let mut ports = HashSet::new();
-        for (_, tl) in self.tunnels.iter() {
-            for t in tl {
-                if !ports.insert(t.listen_port) {
-                    // we panic here in tests so we can identify the issue
-                    if cfg!(test) || cfg!(integration_test) || cfg!(legacy_integration_test) {
-                        panic!("Found duplicate port in use by tunnel manager!?");
-                    }
+        for t in self.tunnels.tunnels() {
+            if !ports.insert(t.listen_port) {
+                // we panic here in tests so we can identify the issue
+                if cfg!(test) || cfg!(integration_test) || cfg!(legacy_integration_test) {
+                    panic!("Found duplicate port in use by tunnel manager!?");
                 }
             }
         }
@@ -369,18 +398,15 @@ impl TunnelManager {
             interface_map.insert(int.name.clone());
         }
 
-        let rita_tunnels = self.tunnels.iter();
-        for (_, tunnels) in rita_tunnels {
-            for tun in tunnels.iter() {
-                if !interface_map.contains(&tun.iface_name) {
-                    info!(
-                        "Babel was not monitored a tunnel, Readding the tunnel: {:?}",
-                        tun.iface_name
-                    );
-                    let res = tun.monitor();
-                    if let Err(e) = res {
-                        error!("Unable to re-add tunnel to babel with: {:?}", e);
-                    }
+        for tun in self.tunnels.tunnels() {
+            if !interface_map.contains(&tun.iface_name) {
+                info!(
+                    "Babel was not monitored a tunnel, Readding the tunnel: {:?}",
+                    tun.iface_name
+                );
+                let res = tun.monitor();
+                if let Err(e) = res {
+                    error!("Unable to re-add tunnel to babel with: {:?}", e);
                 }
             }
         }
@@ -388,44 +414,34 @@ impl TunnelManager {
 
     /// gets the tunnel from the list with the same ifidx, ip, and identity
     fn get_tunnel_mut(&mut self, ifidx: u32, ip: IpAddr, id: Identity) -> Option<&mut Tunnel> {
-        for (index_id, tunnel_list) in self.tunnels.iter_mut() {
-            for tunnel in tunnel_list.iter_mut() {
-                // ensure a match, including an index match to protect against misfiled tunnels
-                if tunnel.listen_ifidx == ifidx
-                    && tunnel.neigh_id.global == id
-                    && *index_id == id
-                    && tunnel.ip == ip
-                {
-                    return Some(tunnel);
-                }
-            }
+        // the lookup only finds tunnels filed under this exact id, the id check on the tunnel
+        // protects against misfiled tunnels. Tunnels for an identity not confirmed yet are held apart
+        let matches = |tunnel: &Tunnel| {
+            tunnel.listen_ifidx == ifidx && tunnel.neigh_id.global == id && tunnel.ip == ip
+        };
+        if self.tunnels.get(&id).map(|t| t.iter().any(matches)) == Some(true) {
+            return self
+                .tunnels
+                .get_mut(&id)?
+                .iter_mut()
+                .find(|t| matches(&**t));
         }
-        None
+        self.tunnels
+            .get_unconfirmed_mut(&id)?
+            .iter_mut()
+            .find(|t| matches(&**t))
     }
 
     /// deletes all instances of a given tunnel with the same ip, ifidx, and wgkey
     fn del_tunnel(&mut self, target_tunnel: Tunnel) {
-        // the problem with just running 'retain' with the tunnel is that duplicates may remain
-        // say for example a duplicate tunnel set has different open times, the duplicate would
-        // not be equal and thus not be deleted.
-        // Instead we find matching tunnels and then mark them for deletion, the tunnel won't change out
-        // from under us so we can be sure that the matches we found are being deleted in the second loop
-        for (_, tunnel_list) in self.tunnels.iter_mut() {
-            let mut tunnels_to_delete: Vec<Tunnel> = Vec::new();
-
-            for tunnel in tunnel_list.iter() {
-                if target_tunnel.listen_ifidx == tunnel.listen_ifidx
-                    && target_tunnel.ip == tunnel.ip
-                    && target_tunnel.neigh_id.global == tunnel.neigh_id.global
-                {
-                    tunnels_to_delete.push(tunnel.clone());
-                }
-            }
-
-            for to_del in tunnels_to_delete {
-                tunnel_list.retain(|val| *val != to_del)
-            }
-        }
+        // we match on ip, ifidx and identity rather than the whole tunnel so that duplicates are
+        // deleted too, say for example a duplicate tunnel set has different open times, the duplicate
+        // would not be equal and thus not be deleted
+        self.tunnels.retain(|_, tunnel| {
+            !(target_tunnel.listen_ifidx == tunnel.listen_ifidx
+                && target_tunnel.ip == tunnel.ip
+                && target_tunnel.neigh_id.global == tunnel.neigh_id.global)
+        });
     }
 
     fn add_new_tunnel_to_list(
@@ -440,7 +456,7 @@ impl TunnelManager {
         match tunnel {
             Ok(tunnel) => {
                 trace!("Tunnel {:?} is open", tunnel);
-                insert_into_tunnel_list(&tunnel, &mut self.tunnels);
+                self.tunnels.insert(tunnel.clone());
                 Ok(tunnel)
             }
             Err(e) => {
@@ -648,12 +664,14 @@ pub fn get_test_tunnel(ip: Ipv4Addr) -> Tunnel {
         created: Instant::now(),
         speed_limit: None,
         payment_state: PaymentState::Paid,
+        keepalive: 0,
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::PaymentState;
+    use crate::tunnel_manager::get_test_id;
     use crate::tunnel_manager::get_test_tunnel;
     use crate::tunnel_manager::Tunnel;
     use crate::tunnel_manager::TunnelManager;
@@ -674,7 +692,7 @@ pub mod tests {
         let mut tunnel_manager = TunnelManager::new();
 
         // Create dummy identity
-        let id = Identity::new(
+        let other_id = Identity::new(
             "0.0.0.0".parse().unwrap(),
             Address::from_str("ffffffffffffffffffffffffffffffffffffffff").unwrap(),
             "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
@@ -682,14 +700,14 @@ pub mod tests {
                 .unwrap(),
             None,
         );
+        let id = get_test_id();
         assert!(tunnel_manager.tunnels.get(&id).is_none());
 
         // Create dummy tunnel
         tunnel_manager
             .tunnels
-            .entry(id)
-            .or_default()
-            .push(get_test_tunnel("0.0.0.0".parse().unwrap()));
+            .insert(get_test_tunnel("0.0.0.0".parse().unwrap()));
+        assert!(tunnel_manager.tunnels.get(&other_id).is_none());
         {
             let existing_tunnel =
                 get_mut_tunnel_by_ifidx(0u32, tunnel_manager.tunnels.get_mut(&id).unwrap())
@@ -0,0 +1,267 @@
+//! Mtu discovery on mesh tunnels, see settings::network::MeshMtuSettings. Radios and other links in the
+//! mesh often have a smaller mtu than ethernet, a tunnel left at the wireguard default over such a link
+//! has its packets fragmented or dropped without either side noticing. From the slow loop a couple of
+//! tunnels at a time are probed in the background with don't fragment pings to the neighbor over the
+//! underlying link, and the tunnel mtu is set to the largest packet that made it across less the
+//! wireguard overhead. The results are kept for the dashboard and path diagnostics, a link too small to
+//! carry a 1280 byte tunnel packet unfragmented is flagged there since ipv6 can't go lower.
+
+use crate::tunnel_manager::with_tunnel_manager;
+use crate::KI;
+use althea_kernel_interface::mtu::{MtuProbeResult, MINIMUM_PROBE_MTU};
+use althea_kernel_interface::open_tunnel::is_link_local;
+use althea_types::unix_time::now_secs;
+use althea_types::WgKey;
+use settings::network::NetworkSettings;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+/// Wireguard overhead over ipv6, ip (40) udp (8) and wireguard (32) headers
+const WG_IPV6_OVERHEAD: usize = 80;
+/// Wireguard overhead over ipv4, ip (20) udp (8) and wireguard (32) headers
+const WG_IPV4_OVERHEAD: usize = 60;
+/// Each probe can take a dozen pings with a second timeout, this keeps a batch short enough that
+/// results show up between ticks
+const MAX_PROBES_PER_TICK: usize = 2;
+
+lazy_static! {
+    /// Keyed by netns and then by tunnel interface
+    static ref TUNNEL_MTUS: Arc<RwLock<HashMap<u32, HashMap<String, TunnelMtu>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+    /// Netns that have a batch of probes running in the background
+    static ref PROBING: Arc<RwLock<HashSet<u32>>> = Arc::new(RwLock::new(HashSet::new()));
+}
+
+/// What the last probe of a tunnel found
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TunnelMtu {
+    pub iface: String,
+    pub neighbor: WgKey,
+    /// The physical interface the tunnel runs over
+    pub link: String,
+    pub link_mtu: usize,
+    /// Largest packet seen to make it to the neighbor over the link, None if the probe got no answer
+    pub path_mtu: Option<usize>,
+    /// The mtu set on the tunnel
+    pub mtu: usize,
+    /// True if the link can't carry a minimum sized tunnel packet without fragmenting it
+    pub fragmenting: bool,
+    /// Unix timestamp in seconds of the probe
+    pub checked: u64,
+}
+
+/// The largest packet a probe saw make it across, None when the neighbor didn't answer
+fn path_mtu(result: MtuProbeResult) -> Option<usize> {
+    match result {
+        MtuProbeResult::Ok { mtu } => Some(mtu),
+        MtuProbeResult::Blackhole {
+            largest_working, ..
+        } => Some(largest_working),
+        MtuProbeResult::Unreachable => None,
+    }
+}
+
+/// The tunnel mtu for a path mtu to the tunnel endpoint and whether the path fragments tunnel packets
+pub fn tunnel_mtu_for_path(path_mtu: usize, endpoint: IpAddr, max: usize) -> (usize, bool) {
+    let overhead = if endpoint.is_ipv6() {
+        WG_IPV6_OVERHEAD
+    } else {
+        WG_IPV4_OVERHEAD
+    };
+    let mtu = path_mtu.saturating_sub(overhead);
+    (mtu.min(max).max(MINIMUM_PROBE_MTU), mtu < MINIMUM_PROBE_MTU)
+}
+
+/// The physical interface traffic to a tunnel endpoint leaves from
+fn link_for(endpoint: IpAddr, external_nic: &Option<String>) -> Option<String> {
+    if is_link_local(endpoint) {
+        match KI.get_device_name(endpoint) {
+            Ok(dev) => Some(dev),
+            Err(e) => {
+                trace!("Unable to find the link to {} {:?}", endpoint, e);
+                None
+            }
+        }
+    } else {
+        external_nic.clone()
+    }
+}
+
+/// The probe results of every tunnel, for the dashboard
+pub fn get_tunnel_mtus() -> Vec<TunnelMtu> {
+    let netns = KI.check_integration_test_netns();
+    let mut ret: Vec<TunnelMtu> = TUNNEL_MTUS
+        .read()
+        .unwrap()
+        .get(&netns)
+        .map(|mtus| mtus.values().cloned().collect())
+        .unwrap_or_default();
+    ret.sort_by(|a, b| a.iface.cmp(&b.iface));
+    ret
+}
+
+/// The probe results of one tunnel, for path diagnostics
+pub fn get_tunnel_mtu(iface: &str) -> Option<TunnelMtu> {
+    let netns = KI.check_integration_test_netns();
+    TUNNEL_MTUS.read().unwrap().get(&netns)?.get(iface).cloned()
+}
+
+/// Sets the mtu of a tunnel if it isn't already what we want, tunnels recreated since they were probed
+/// come back at the wireguard default
+fn apply_mtu(iface: &str, mtu: usize) {
+    match KI.get_mtu(iface) {
+        Ok(current) if current == mtu => {}
+        Ok(current) => {
+            info!("Setting the mtu of {} to {}, was {}", iface, mtu, current);
+            if let Err(e) = KI.set_mtu(iface, mtu) {
+                warn!("Failed to set the mtu of {} {:?}", iface, e);
+            }
+        }
+        Err(e) => trace!("Unable to get the mtu of {} {:?}", iface, e),
+    }
+}
+
+/// Probes one tunnel, previous is what the last probe of it found
+fn probe_tunnel(
+    iface: String,
+    neighbor: WgKey,
+    endpoint: IpAddr,
+    previous: Option<TunnelMtu>,
+    network: &NetworkSettings,
+) -> Option<TunnelMtu> {
+    let link = link_for(endpoint, &network.external_nic)?;
+    let link_mtu = match KI.get_mtu(&link) {
+        Ok(mtu) => mtu,
+        Err(e) => {
+            warn!("Unable to get the mtu of {} {:?}", link, e);
+            return None;
+        }
+    };
+    let result = match KI.check_mtu_blackhole(endpoint, &link, link_mtu) {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("Failed to probe the mtu of {} {:?}", iface, e);
+            return None;
+        }
+    };
+    let path_mtu = path_mtu(result);
+    let (mtu, fragmenting) = match (path_mtu, previous) {
+        (Some(path_mtu), _) => tunnel_mtu_for_path(path_mtu, endpoint, network.mesh_mtu.max),
+        // a neighbor that doesn't answer pings keeps the mtu it has
+        (None, Some(previous)) => (previous.mtu, previous.fragmenting),
+        (None, None) => (network.mesh_mtu.max, false),
+    };
+    if fragmenting {
+        warn!(
+            "The link to {} over {} only carries {:?} byte packets, tunnel packets will fragment",
+            iface, link, path_mtu
+        );
+    }
+    Some(TunnelMtu {
+        iface,
+        neighbor,
+        link,
+        link_mtu,
+        path_mtu,
+        mtu,
+        fragmenting,
+        checked: now_secs(),
+    })
+}
+
+/// Called from the slow loop, keeps the set mtus applied to tunnels that were recreated since they were
+/// probed and starts probing the tunnels that are due in the background, a probe can take a dozen one
+/// second pings so they don't run on the slow loop itself
+pub fn tick_tunnel_mtu() {
+    let network = settings::get_rita_common().network;
+    if !network.mesh_mtu.probe {
+        return;
+    }
+    let tunnels: Vec<(String, WgKey, IpAddr)> = with_tunnel_manager(|tunnel_manager| {
+        tunnel_manager
+            .tunnels
+            .tunnels()
+            .map(|t| (t.iface_name.clone(), t.neigh_id.global.wg_public_key, t.ip))
+            .collect()
+    });
+    let netns = KI.check_integration_test_netns();
+    let known: HashMap<String, TunnelMtu> = {
+        let mut mtus = TUNNEL_MTUS.write().unwrap();
+        let known = mtus.entry(netns).or_default();
+        // tunnels that are gone or now lead to another neighbor are forgotten
+        known.retain(|iface, mtu| {
+            tunnels
+                .iter()
+                .any(|(i, key, _)| i == iface && *key == mtu.neighbor)
+        });
+        known.clone()
+    };
+
+    for mtu in known.values() {
+        apply_mtu(&mtu.iface, mtu.mtu);
+    }
+
+    let now = now_secs();
+    let mut due: Vec<(String, WgKey, IpAddr)> = tunnels
+        .into_iter()
+        .filter(|(iface, _, _)| match known.get(iface) {
+            Some(mtu) => now.saturating_sub(mtu.checked) >= network.mesh_mtu.interval,
+            None => true,
+        })
+        .collect();
+    due.sort_by_key(|(iface, _, _)| known.get(iface).map(|mtu| mtu.checked).unwrap_or(0));
+    due.truncate(MAX_PROBES_PER_TICK);
+
+    // only one batch of probes at a time, the next tick picks up whatever is still due
+    if due.is_empty() || !PROBING.write().unwrap().insert(netns) {
+        return;
+    }
+    thread::spawn(move || {
+        for (iface, neighbor, endpoint) in due {
+            let previous = known.get(&iface).cloned();
+            if let Some(mtu) = probe_tunnel(iface, neighbor, endpoint, previous, &network) {
+                if known.get(&mtu.iface).map(|previous| previous.mtu) != Some(mtu.mtu) {
+                    apply_mtu(&mtu.iface, mtu.mtu);
+                }
+                TUNNEL_MTUS
+                    .write()
+                    .unwrap()
+                    .entry(netns)
+                    .or_default()
+                    .insert(mtu.iface.clone(), mtu);
+            }
+        }
+        PROBING.write().unwrap().remove(&netns);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_mtu_for_path() {
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        // ethernet, the wireguard default
+        assert_eq!(tunnel_mtu_for_path(1500, v6, 1420), (1420, false));
+        // a bigger link is still capped
+        assert_eq!(tunnel_mtu_for_path(9000, v4, 1420), (1420, false));
+        // a radio link somewhere between us and the neighbor
+        let blackhole = MtuProbeResult::Blackhole {
+            mtu: 1500,
+            largest_working: 1400,
+        };
+        assert_eq!(path_mtu(blackhole), Some(1400));
+        assert_eq!(tunnel_mtu_for_path(1400, v6, 1420), (1320, false));
+        assert_eq!(tunnel_mtu_for_path(1400, v4, 1420), (1340, false));
+        // too small for ipv6 inside the tunnel
+        assert_eq!(
+            tunnel_mtu_for_path(1300, v6, 1420),
+            (MINIMUM_PROBE_MTU, true)
+        );
+        assert_eq!(path_mtu(MtuProbeResult::Unreachable), None);
+    }
+}
@@ -1,5 +1,6 @@
-use super::get_tunnel_manager;
+use super::with_tunnel_manager;
 use super::PaymentState;
+use super::TunnelManager;
 use althea_types::Identity;
 use althea_types::NeighborStatus;
 use std::collections::HashMap;
@@ -8,7 +9,10 @@ use std::collections::HashMap;
 /// a mapping by identity, meaning that if a given id has multiple tunnels using different shaped speeds it may not
 /// paint the full picture, that being said my observation is that this never seems to be the case, I can of course be wrong
 pub fn get_neighbor_status() -> HashMap<Identity, NeighborStatus> {
-    let tunnel_manager = get_tunnel_manager();
+    with_tunnel_manager(neighbor_status)
+}
+
+fn neighbor_status(tunnel_manager: &TunnelManager) -> HashMap<Identity, NeighborStatus> {
     let mut external_list = HashMap::new();
     for (id, tunnel_list) in tunnel_manager.tunnels.iter() {
         // we may have many tunnels with this same peer, we want to get
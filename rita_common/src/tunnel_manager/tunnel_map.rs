@@ -0,0 +1,201 @@
+//! Tunnel storage keyed by the neighbor's wg key. Exits and busy gateways hold thousands of tunnels and
+//! keying them by the full Identity meant every key repeated the mesh ip, eth address and nickname that
+//! each tunnel's LocalIdentity already carries. Now the key is the 32 byte wg key and the identity a
+//! neighbor is filed under is kept once in a side index. Hellos are not authenticated, so a tunnel for a
+//! known key that claims a different identity is held apart as unconfirmed and the key stays filed under
+//! its existing identity. Only a wireguard handshake on that tunnel, which needs the key's private half,
+//! confirms the new identity. Wireguard can only have one peer per key, so the key is then refiled under
+//! the new identity and its old tunnels, no longer matching it, are collected by gc like any other
+//! misfiled tunnel. Unconfirmed tunnels that never handshake are collected by gc as well.
+
+use super::Tunnel;
+use althea_types::Identity;
+use althea_types::WgKey;
+use std::collections::HashMap;
+
+#[derive(Clone, Default)]
+pub struct TunnelMap {
+    tunnels: HashMap<WgKey, Vec<Tunnel>>,
+    /// The identity the tunnels for each key are filed under
+    identities: HashMap<WgKey, Identity>,
+    /// Tunnels for a filed key that claim a different identity, until a handshake confirms it
+    unconfirmed: HashMap<WgKey, Vec<Tunnel>>,
+}
+
+impl TunnelMap {
+    pub fn new() -> TunnelMap {
+        TunnelMap::default()
+    }
+
+    /// Files a tunnel under its neighbor's identity, or holds it as unconfirmed if its key is already
+    /// filed under another identity
+    pub fn insert(&mut self, tunnel: Tunnel) {
+        let id = tunnel.neigh_id.global;
+        match self.identities.get(&id.wg_public_key) {
+            Some(filed) if *filed != id => {
+                info!(
+                    "Tunnel for {} claims a new identity {}, holding it until a handshake confirms it",
+                    filed, id
+                );
+                self.unconfirmed
+                    .entry(id.wg_public_key)
+                    .or_default()
+                    .push(tunnel);
+            }
+            _ => {
+                self.identities.insert(id.wg_public_key, id);
+                self.tunnels
+                    .entry(id.wg_public_key)
+                    .or_default()
+                    .push(tunnel);
+            }
+        }
+    }
+
+    /// The unconfirmed tunnels held for this identity's key, they may claim other identities too
+    pub fn get_unconfirmed_mut(&mut self, id: &Identity) -> Option<&mut Vec<Tunnel>> {
+        self.unconfirmed.get_mut(&id.wg_public_key)
+    }
+
+    /// Refiles keys under the identity of an unconfirmed tunnel that confirmed returns true for, meaning
+    /// it has seen a handshake. Returns the identities that were confirmed
+    pub fn confirm(&mut self, mut confirmed: impl FnMut(&Tunnel) -> bool) -> Vec<Identity> {
+        let mut ret = Vec::new();
+        for (key, pending) in self.unconfirmed.iter_mut() {
+            let id = match pending.iter().find(|tunnel| confirmed(tunnel)) {
+                Some(tunnel) => tunnel.neigh_id.global,
+                None => continue,
+            };
+            let (moved, kept): (Vec<Tunnel>, Vec<Tunnel>) = pending
+                .drain(..)
+                .partition(|tunnel| tunnel.neigh_id.global == id);
+            *pending = kept;
+            self.identities.insert(*key, id);
+            self.tunnels.entry(*key).or_default().extend(moved);
+            ret.push(id);
+        }
+        self.unconfirmed.retain(|_, pending| !pending.is_empty());
+        ret
+    }
+
+    /// The identity the tunnels for this key are filed under
+    pub fn identity(&self, key: &WgKey) -> Option<&Identity> {
+        self.identities.get(key)
+    }
+
+    /// The tunnels filed under exactly this identity
+    pub fn get(&self, id: &Identity) -> Option<&Vec<Tunnel>> {
+        match self.identities.get(&id.wg_public_key) {
+            Some(filed) if filed == id => self.tunnels.get(&id.wg_public_key),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, id: &Identity) -> Option<&mut Vec<Tunnel>> {
+        match self.identities.get(&id.wg_public_key) {
+            Some(filed) if filed == id => self.tunnels.get_mut(&id.wg_public_key),
+            _ => None,
+        }
+    }
+
+    /// Every neighbor and its tunnels
+    pub fn iter(&self) -> impl Iterator<Item = (&Identity, &Vec<Tunnel>)> {
+        let identities = &self.identities;
+        self.tunnels
+            .iter()
+            .filter_map(move |(key, tunnels)| identities.get(key).map(|id| (id, tunnels)))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&Identity, &mut Vec<Tunnel>)> {
+        let identities = &self.identities;
+        self.tunnels
+            .iter_mut()
+            .filter_map(move |(key, tunnels)| identities.get(key).map(|id| (id, tunnels)))
+    }
+
+    /// Every tunnel regardless of neighbor, unconfirmed ones included
+    pub fn tunnels(&self) -> impl Iterator<Item = &Tunnel> {
+        self.tunnels
+            .values()
+            .flatten()
+            .chain(self.unconfirmed.values().flatten())
+    }
+
+    pub fn tunnel_count(&self) -> usize {
+        self.tunnels().count()
+    }
+
+    /// Keeps the tunnels keep returns true for, given the identity they are filed under, and returns
+    /// the rest. Unconfirmed tunnels are given the identity they claim. Neighbors left without tunnels
+    /// are dropped
+    pub fn retain(&mut self, mut keep: impl FnMut(&Identity, &Tunnel) -> bool) -> Vec<Tunnel> {
+        let mut removed = Vec::new();
+        for pending in self.unconfirmed.values_mut() {
+            let (kept, gone): (Vec<Tunnel>, Vec<Tunnel>) = pending
+                .drain(..)
+                .partition(|tunnel| keep(&tunnel.neigh_id.global, tunnel));
+            *pending = kept;
+            removed.extend(gone);
+        }
+        self.unconfirmed.retain(|_, pending| !pending.is_empty());
+        for (key, tunnels) in self.tunnels.iter_mut() {
+            let id = match self.identities.get(key) {
+                Some(id) => id,
+                None => {
+                    removed.append(tunnels);
+                    continue;
+                }
+            };
+            let (kept, gone): (Vec<Tunnel>, Vec<Tunnel>) =
+                tunnels.drain(..).partition(|tunnel| keep(id, tunnel));
+            *tunnels = kept;
+            removed.extend(gone);
+        }
+        self.tunnels.retain(|_, tunnels| !tunnels.is_empty());
+        let tunnels = &self.tunnels;
+        self.identities.retain(|key, _| tunnels.contains_key(key));
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_manager::{get_test_id, get_test_tunnel};
+
+    #[test]
+    fn test_tunnel_map() {
+        let mut map = TunnelMap::new();
+        let id = get_test_id();
+        map.insert(get_test_tunnel("10.0.0.1".parse().unwrap()));
+        map.insert(get_test_tunnel("10.0.0.2".parse().unwrap()));
+        assert_eq!(map.get(&id).unwrap().len(), 2);
+        assert_eq!(map.tunnel_count(), 2);
+        assert_eq!(map.identity(&id.wg_public_key), Some(&id));
+
+        // the same key with a new identity is held apart until it is confirmed
+        let mut moved = get_test_tunnel("10.0.0.3".parse().unwrap());
+        moved.neigh_id.global.mesh_ip = "::2".parse().unwrap();
+        let moved_id = moved.neigh_id.global;
+        map.insert(moved);
+        assert_eq!(map.get(&id).unwrap().len(), 2);
+        assert!(map.get(&moved_id).is_none());
+        assert_eq!(map.get_unconfirmed_mut(&moved_id).unwrap().len(), 1);
+        assert_eq!(map.tunnel_count(), 3);
+        assert!(map.confirm(|_| false).is_empty());
+        assert_eq!(map.identity(&id.wg_public_key), Some(&id));
+
+        // a handshake refiles the key, the old tunnels are now misfiled
+        assert_eq!(map.confirm(|_| true), vec![moved_id]);
+        assert!(map.get(&id).is_none());
+        assert_eq!(map.get(&moved_id).unwrap().len(), 3);
+        let removed = map.retain(|filed, tunnel| *filed == tunnel.neigh_id.global);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(map.tunnel_count(), 1);
+
+        // neighbors without tunnels are dropped entirely
+        map.retain(|_, _| false);
+        assert_eq!(map.iter().count(), 0);
+        assert!(map.identity(&id.wg_public_key).is_none());
+    }
+}
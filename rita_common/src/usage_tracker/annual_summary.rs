@@ -0,0 +1,467 @@
+//! Annual income summaries for node operators who have to report what their router earned. Payments
+//! from the usage tracker are split by month into bandwidth earned, bandwidth spent, operator fees and
+//! network fees, next to the bytes used and relayed that month. If a fiat price source is configured each
+//! month is also converted at that month's price. The summary only covers what the usage tracker still
+//! holds, a year of usage and the most recent payments.
+
+use super::structs::UsageTrackerPayment;
+use super::USAGE_TRACKER_STORAGE;
+use crate::RitaCommonError;
+use althea_types::user_info::Usage;
+use clarity::Address;
+use num256::Uint256;
+use settings::payment::FiatPriceSource;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+const PRICE_TIMEOUT: Duration = Duration::from_secs(10);
+const WEI_PER_TOKEN: f64 = 1e18;
+
+/// One month of the summary, amounts are in wei
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MonthSummary {
+    /// 1 to 12
+    pub month: u32,
+    /// Payments received from neighbors for bandwidth
+    pub earned: Uint256,
+    /// Payments sent to neighbors for bandwidth
+    pub spent: Uint256,
+    /// Fees paid to the operator
+    pub operator_fees: Uint256,
+    /// Simulated transaction fees paid to the network
+    pub network_fees: Uint256,
+    /// Bytes used by this router's own clients
+    pub client_bytes: u64,
+    /// Bytes forwarded for other routers
+    pub relay_bytes: u64,
+    /// Price of one token, 1e18 wei, in the summary currency that month
+    pub fiat_price: Option<f64>,
+    pub earned_fiat: Option<f64>,
+    pub spent_fiat: Option<f64>,
+    /// Operator and network fees together
+    pub fees_fiat: Option<f64>,
+}
+
+impl MonthSummary {
+    fn new(month: u32) -> MonthSummary {
+        MonthSummary {
+            month,
+            earned: Uint256::zero(),
+            spent: Uint256::zero(),
+            operator_fees: Uint256::zero(),
+            network_fees: Uint256::zero(),
+            client_bytes: 0,
+            relay_bytes: 0,
+            fiat_price: None,
+            earned_fiat: None,
+            spent_fiat: None,
+            fees_fiat: None,
+        }
+    }
+
+    fn has_payments(&self) -> bool {
+        self.earned != Uint256::zero()
+            || self.spent != Uint256::zero()
+            || self.operator_fees != Uint256::zero()
+            || self.network_fees != Uint256::zero()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnnualSummary {
+    pub year: i32,
+    pub address: Address,
+    /// Currency of the fiat fields, None if no price source is configured
+    pub currency: Option<String>,
+    /// January first
+    pub months: Vec<MonthSummary>,
+    pub total_earned: Uint256,
+    pub total_spent: Uint256,
+    pub total_operator_fees: Uint256,
+    pub total_network_fees: Uint256,
+    /// Fiat totals are only given when every month with payments has a price
+    pub total_earned_fiat: Option<f64>,
+    pub total_spent_fiat: Option<f64>,
+    pub total_fees_fiat: Option<f64>,
+}
+
+/// Days since the unix epoch of a calendar date, from Howard Hinnant's date algorithms
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The first hour since the unix epoch of each month of the year, followed by the first hour of the next year
+fn month_boundaries(year: i32) -> [i64; 13] {
+    let mut out = [0; 13];
+    for (i, boundary) in out.iter_mut().enumerate() {
+        let (y, m) = if i == 12 {
+            (year as i64 + 1, 1)
+        } else {
+            (year as i64, i as u32 + 1)
+        };
+        *boundary = days_from_civil(y, m, 1) * 24;
+    }
+    out
+}
+
+/// Index of the month an hour falls in, None if it is outside the year
+fn month_of(boundaries: &[i64; 13], hour: u64) -> Option<usize> {
+    let hour = hour as i64;
+    (0..12).find(|&i| hour >= boundaries[i] && hour < boundaries[i + 1])
+}
+
+fn wei_to_tokens(amount: Uint256) -> f64 {
+    amount.to_string().parse::<f64>().unwrap_or(0.0) / WEI_PER_TOKEN
+}
+
+/// Builds the summary for a year from the payment and usage history, without fiat values. Payments to
+/// the operator and to the network fee address are counted as fees rather than bandwidth spending
+pub fn build_annual_summary<'a>(
+    year: i32,
+    payments: impl Iterator<Item = &'a UsageTrackerPayment>,
+    client_usage: &HashMap<u64, Usage>,
+    relay_usage: &HashMap<u64, Usage>,
+    our_address: Address,
+    operator_address: Option<Address>,
+    network_fee_address: Address,
+) -> AnnualSummary {
+    let boundaries = month_boundaries(year);
+    let mut months: Vec<MonthSummary> = (1..=12).map(MonthSummary::new).collect();
+    for payment in payments {
+        let month = match month_of(&boundaries, payment.index) {
+            Some(month) => &mut months[month],
+            None => continue,
+        };
+        if payment.to.eth_address == our_address {
+            month.earned += payment.amount;
+        } else if payment.from.eth_address == our_address {
+            if Some(payment.to.eth_address) == operator_address {
+                month.operator_fees += payment.amount;
+            } else if payment.to.eth_address == network_fee_address {
+                month.network_fees += payment.amount;
+            } else {
+                month.spent += payment.amount;
+            }
+        }
+    }
+    for (hour, usage) in client_usage {
+        if let Some(month) = month_of(&boundaries, *hour) {
+            months[month].client_bytes += usage.up + usage.down;
+        }
+    }
+    for (hour, usage) in relay_usage {
+        // relayed traffic is counted on the way in and on the way out, up alone is what was forwarded
+        if let Some(month) = month_of(&boundaries, *hour) {
+            months[month].relay_bytes += usage.up;
+        }
+    }
+
+    let mut summary = AnnualSummary {
+        year,
+        address: our_address,
+        currency: None,
+        months: Vec::new(),
+        total_earned: Uint256::zero(),
+        total_spent: Uint256::zero(),
+        total_operator_fees: Uint256::zero(),
+        total_network_fees: Uint256::zero(),
+        total_earned_fiat: None,
+        total_spent_fiat: None,
+        total_fees_fiat: None,
+    };
+    for month in months.iter() {
+        summary.total_earned += month.earned;
+        summary.total_spent += month.spent;
+        summary.total_operator_fees += month.operator_fees;
+        summary.total_network_fees += month.network_fees;
+    }
+    summary.months = months;
+    summary
+}
+
+/// Converts each month at its price, prices holds one entry per month
+pub fn apply_fiat_prices(summary: &mut AnnualSummary, currency: String, prices: &[Option<f64>]) {
+    summary.currency = Some(currency);
+    let mut totals = Some((0.0, 0.0, 0.0));
+    for (month, price) in summary.months.iter_mut().zip(prices) {
+        month.fiat_price = *price;
+        match price {
+            Some(price) => {
+                let earned = wei_to_tokens(month.earned) * price;
+                let spent = wei_to_tokens(month.spent) * price;
+                let fees = wei_to_tokens(month.operator_fees + month.network_fees) * price;
+                month.earned_fiat = Some(earned);
+                month.spent_fiat = Some(spent);
+                month.fees_fiat = Some(fees);
+                if let Some(totals) = totals.as_mut() {
+                    totals.0 += earned;
+                    totals.1 += spent;
+                    totals.2 += fees;
+                }
+            }
+            None => {
+                month.earned_fiat = None;
+                month.spent_fiat = None;
+                month.fees_fiat = None;
+                if month.has_payments() {
+                    totals = None;
+                }
+            }
+        }
+    }
+    summary.total_earned_fiat = totals.map(|(earned, _, _)| earned);
+    summary.total_spent_fiat = totals.map(|(_, spent, _)| spent);
+    summary.total_fees_fiat = totals.map(|(_, _, fees)| fees);
+}
+
+fn csv_fiat(value: Option<f64>) -> String {
+    value.map(|v| format!("{v:.2}")).unwrap_or_default()
+}
+
+/// Renders the summary as a csv with one row per month and a total row
+pub fn summary_to_csv(summary: &AnnualSummary) -> String {
+    let currency = summary
+        .currency
+        .clone()
+        .unwrap_or_else(|| "fiat".to_string());
+    let mut out = format!(
+        "month,earned_wei,spent_wei,operator_fees_wei,network_fees_wei,client_bytes,relay_bytes,\
+         price_{currency},earned_{currency},spent_{currency},fees_{currency}\n"
+    );
+    for month in summary.months.iter() {
+        let _ = writeln!(
+            out,
+            "{}-{:02},{},{},{},{},{},{},{},{},{},{}",
+            summary.year,
+            month.month,
+            month.earned,
+            month.spent,
+            month.operator_fees,
+            month.network_fees,
+            month.client_bytes,
+            month.relay_bytes,
+            month.fiat_price.map(|p| p.to_string()).unwrap_or_default(),
+            csv_fiat(month.earned_fiat),
+            csv_fiat(month.spent_fiat),
+            csv_fiat(month.fees_fiat),
+        );
+    }
+    let _ = writeln!(
+        out,
+        "total,{},{},{},{},{},{},,{},{},{}",
+        summary.total_earned,
+        summary.total_spent,
+        summary.total_operator_fees,
+        summary.total_network_fees,
+        summary.months.iter().map(|m| m.client_bytes).sum::<u64>(),
+        summary.months.iter().map(|m| m.relay_bytes).sum::<u64>(),
+        csv_fiat(summary.total_earned_fiat),
+        csv_fiat(summary.total_spent_fiat),
+        csv_fiat(summary.total_fees_fiat),
+    );
+    out
+}
+
+/// The price of one token during the given month
+async fn get_fiat_price(
+    source: &FiatPriceSource,
+    year: i32,
+    month: u32,
+) -> Result<f64, RitaCommonError> {
+    match source {
+        FiatPriceSource::Fixed { price, .. } => price.parse().map_err(|e| {
+            RitaCommonError::MiscStringError(format!("Invalid fixed fiat price {price} {e}"))
+        }),
+        FiatPriceSource::Url { url, .. } => {
+            let url = url.replace("{month}", &format!("{year}-{month:02}"));
+            let client = awc::Client::default();
+            let mut response = client.get(&url).timeout(PRICE_TIMEOUT).send().await?;
+            Ok(response.json().await?)
+        }
+    }
+}
+
+/// Builds the summary for a year from the usage tracker, converted to fiat if a price source is set
+pub async fn get_annual_summary(year: i32) -> Result<AnnualSummary, RitaCommonError> {
+    let payment = settings::get_rita_common().payment;
+    let our_address = match payment.eth_address {
+        Some(address) => address,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "No eth address configured".to_string(),
+            ))
+        }
+    };
+    // exits have no operator
+    let operator_address = if settings::check_if_exit() {
+        None
+    } else {
+        settings::get_rita_client().operator.operator_address
+    };
+    let mut summary = {
+        let usage_tracker = USAGE_TRACKER_STORAGE.read().unwrap();
+        let storage = &usage_tracker.usage_tracker;
+        build_annual_summary(
+            year,
+            storage.payments.iter(),
+            &storage.client_bandwidth,
+            &storage.relay_bandwidth,
+            our_address,
+            operator_address,
+            payment.simulated_transaction_fee_address,
+        )
+    };
+    if let Some(source) = payment.fiat_price_source {
+        let mut prices = Vec::new();
+        for month in 1..=12 {
+            match get_fiat_price(&source, year, month).await {
+                Ok(price) => prices.push(Some(price)),
+                Err(e) => {
+                    warn!("Failed to get fiat price for {}-{:02} {:?}", year, month, e);
+                    prices.push(None);
+                }
+            }
+        }
+        apply_fiat_prices(&mut summary, source.currency().to_string(), &prices);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_manager::get_test_id;
+    use althea_types::Identity;
+
+    #[test]
+    fn test_month_boundaries() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        let boundaries = month_boundaries(2024);
+        assert_eq!(boundaries[0], 19_723 * 24);
+        // leap year
+        assert_eq!(boundaries[2], 19_783 * 24);
+        assert_eq!(boundaries[12], days_from_civil(2025, 1, 1) * 24);
+
+        assert_eq!(month_of(&boundaries, 19_723 * 24), Some(0));
+        assert_eq!(month_of(&boundaries, 19_783 * 24 - 1), Some(1));
+        assert_eq!(month_of(&boundaries, 19_723 * 24 - 1), None);
+        assert_eq!(month_of(&boundaries, boundaries[12] as u64), None);
+    }
+
+    #[test]
+    fn test_annual_summary() {
+        let us = Identity {
+            eth_address: "0x5aee3dff733f56cfe7e5390b9cc3a46a90ca1cfa"
+                .parse()
+                .unwrap(),
+            ..get_test_id()
+        };
+        let neighbor = Identity {
+            eth_address: "0xbda3c7fa35896de7fa3e3591b44b44baaa3e3bc1"
+                .parse()
+                .unwrap(),
+            ..get_test_id()
+        };
+        let operator = Identity {
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            ..get_test_id()
+        };
+        let network = Identity {
+            eth_address: "0xee8bba37508cd6f9db7c8ad0ae2b3de0168c1b36"
+                .parse()
+                .unwrap(),
+            ..get_test_id()
+        };
+        let jan = (19_723 * 24) as u64;
+        let mar = (19_783 * 24) as u64;
+        let token = 1_000_000_000_000_000_000u128;
+        let payment = |to: Identity, from: Identity, amount: u128, hour: u64| UsageTrackerPayment {
+            to,
+            from,
+            amount: amount.into(),
+            txid: (hour as u128 + amount).into(),
+            index: hour,
+        };
+        let payments = vec![
+            payment(us, neighbor, 2 * token, jan + 5),
+            payment(neighbor, us, token / 2, jan + 6),
+            payment(operator, us, token / 10, mar),
+            payment(network, us, token / 20, mar + 1),
+            // the year before
+            payment(us, neighbor, 100 * token, jan - 1),
+        ];
+        let mut client_usage = HashMap::new();
+        client_usage.insert(
+            jan,
+            Usage {
+                up: 10,
+                down: 90,
+                price: 1,
+            },
+        );
+        let mut relay_usage = HashMap::new();
+        relay_usage.insert(
+            mar + 2,
+            Usage {
+                up: 500,
+                down: 500,
+                price: 1,
+            },
+        );
+
+        let mut summary = build_annual_summary(
+            2024,
+            payments.iter(),
+            &client_usage,
+            &relay_usage,
+            us.eth_address,
+            Some(operator.eth_address),
+            network.eth_address,
+        );
+        assert_eq!(summary.months.len(), 12);
+        assert_eq!(summary.total_earned, (2 * token).into());
+        assert_eq!(summary.total_spent, (token / 2).into());
+        assert_eq!(summary.total_operator_fees, (token / 10).into());
+        assert_eq!(summary.total_network_fees, (token / 20).into());
+        assert_eq!(summary.months[0].client_bytes, 100);
+        assert_eq!(summary.months[2].relay_bytes, 500);
+        assert_eq!(summary.months[2].operator_fees, (token / 10).into());
+
+        let mut prices = vec![Some(2.0); 12];
+        // a missing price for a month with payments leaves the totals out
+        prices[2] = None;
+        apply_fiat_prices(&mut summary, "usd".to_string(), &prices);
+        assert_eq!(summary.months[0].earned_fiat, Some(4.0));
+        assert_eq!(summary.months[0].spent_fiat, Some(1.0));
+        assert_eq!(summary.months[2].fees_fiat, None);
+        assert_eq!(summary.total_earned_fiat, None);
+
+        prices[2] = Some(2.0);
+        // a missing price for a quiet month does not
+        prices[5] = None;
+        apply_fiat_prices(&mut summary, "usd".to_string(), &prices);
+        assert_eq!(summary.total_earned_fiat, Some(4.0));
+        assert!((summary.total_fees_fiat.unwrap() - 0.3).abs() < 1e-9);
+
+        let csv = summary_to_csv(&summary);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 14);
+        assert!(lines[0].ends_with("price_usd,earned_usd,spent_usd,fees_usd"));
+        assert_eq!(
+            lines[1],
+            "2024-01,2000000000000000000,500000000000000000,0,0,100,0,2,4.00,1.00,0.00"
+        );
+        assert!(lines[13].starts_with("total,2000000000000000000,"));
+    }
+}
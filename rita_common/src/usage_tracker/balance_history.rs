@@ -0,0 +1,334 @@
+//! A timeline of this router's balance so that users can see what they earned over the last day, week or
+//! month. The oracle balance is sampled every slow loop tick and the last sample of each day is kept as that
+//! day's closing balance. The daily balances go to a small file next to usage_tracker_file, written no more
+//! often than the usage tracker itself writes on the same storage. Payment events come from the usage
+//! tracker's payment history, the earnings series combines the two.
+
+use super::get_usage_storage_type;
+use super::segments::WearPolicy;
+use super::structs::UsageTrackerPayment;
+use super::USAGE_TRACKER_STORAGE;
+use crate::blockchain_oracle::get_oracle_balance;
+use crate::RitaCommonError;
+use clarity::Address;
+use num256::Uint256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub const SECONDS_PER_DAY: u64 = 86_400;
+/// Two years of daily balances, a few tens of KiB on disk
+pub const MAX_BALANCE_DAYS: usize = 730;
+
+/// Closing balance of each day, indexed by days since the unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BalanceHistory {
+    pub days: BTreeMap<u64, Uint256>,
+}
+
+impl BalanceHistory {
+    /// Records a balance sample as the closing balance of the given day so far, returns true if
+    /// the history changed
+    pub fn record(&mut self, day: u64, balance: Uint256) -> bool {
+        if self.days.get(&day) == Some(&balance) {
+            return false;
+        }
+        self.days.insert(day, balance);
+        while self.days.len() > MAX_BALANCE_DAYS {
+            match self.days.keys().next().copied() {
+                Some(oldest) => self.days.remove(&oldest),
+                None => break,
+            };
+        }
+        true
+    }
+
+    fn load(path: &str) -> BalanceHistory {
+        match fs::read(path) {
+            Ok(bytes) => match bincode::deserialize(&bytes) {
+                Ok(history) => history,
+                Err(e) => {
+                    error!("Failed to deserialize balance history {:?}", e);
+                    BalanceHistory::default()
+                }
+            },
+            Err(e) => {
+                info!("No balance history loaded {:?}", e);
+                BalanceHistory::default()
+            }
+        }
+    }
+
+    /// Writes through a temporary file so that a crash can't leave a half written history behind
+    fn save(&self, path: &str) -> Result<(), RitaCommonError> {
+        let tmp_path = format!("{path}.tmp");
+        fs::write(&tmp_path, bincode::serialize(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The balance history is kept next to the usage tracker snapshot
+fn balance_history_path() -> String {
+    format!(
+        "{}.balance",
+        settings::get_rita_common().network.usage_tracker_file
+    )
+}
+
+struct BalanceHistoryWrapper {
+    history: BalanceHistory,
+    policy: WearPolicy,
+    last_write: Instant,
+    dirty: bool,
+}
+
+impl BalanceHistoryWrapper {
+    fn new() -> BalanceHistoryWrapper {
+        let network = settings::get_rita_common().network;
+        BalanceHistoryWrapper {
+            history: BalanceHistory::load(&balance_history_path()),
+            policy: WearPolicy::new(
+                get_usage_storage_type(),
+                network.usage_tracker_write_interval,
+            ),
+            last_write: Instant::now(),
+            dirty: false,
+        }
+    }
+}
+
+lazy_static! {
+    static ref BALANCE_HISTORY: Arc<RwLock<BalanceHistoryWrapper>> =
+        Arc::new(RwLock::new(BalanceHistoryWrapper::new()));
+}
+
+fn current_day() -> Result<u64, RitaCommonError> {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    Ok(seconds.as_secs() / SECONDS_PER_DAY)
+}
+
+/// Writes the balance history out if it changed and the storage can take another write
+pub fn flush_balance_history(force: bool) {
+    let wrapper = &mut *BALANCE_HISTORY.write().unwrap();
+    if !wrapper.dirty || (!force && wrapper.last_write.elapsed() < wrapper.policy.write_interval) {
+        return;
+    }
+    wrapper.last_write = Instant::now();
+    match wrapper.history.save(&balance_history_path()) {
+        Ok(()) => wrapper.dirty = false,
+        Err(e) => warn!("Unable to save balance history {:?}", e),
+    }
+}
+
+/// Samples the current balance into the history, called every slow loop tick
+pub fn update_balance_history() {
+    let balance = match get_oracle_balance() {
+        Some(balance) => balance,
+        // nothing to record until the oracle has heard from a full node
+        None => return,
+    };
+    let day = match current_day() {
+        Ok(day) => day,
+        Err(e) => {
+            error!("System time is set earlier than unix epoch {:?}", e);
+            return;
+        }
+    };
+    {
+        let wrapper = &mut *BALANCE_HISTORY.write().unwrap();
+        if wrapper.history.record(day, balance) {
+            wrapper.dirty = true;
+        }
+    }
+    flush_balance_history(false);
+}
+
+/// One day of the earnings timeline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EarningsDay {
+    /// Days since the unix epoch
+    pub day: u64,
+    /// Unix timestamp in seconds of the start of the day
+    pub timestamp: u64,
+    /// Balance at the end of the day, carried forward from the last sample when none was taken that
+    /// day. None if there is no sample at or before this day
+    pub balance: Option<Uint256>,
+    /// Payments received and sent during the day, in wei
+    pub earned: Uint256,
+    pub spent: Uint256,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EarningsTimeline {
+    /// Oldest day first
+    pub days: Vec<EarningsDay>,
+    pub total_earned: Uint256,
+    pub total_spent: Uint256,
+}
+
+/// Builds the daily series from first_day to last_day inclusive
+pub fn build_earnings_timeline<'a>(
+    history: &BalanceHistory,
+    payments: impl Iterator<Item = &'a UsageTrackerPayment>,
+    our_address: Address,
+    first_day: u64,
+    last_day: u64,
+) -> EarningsTimeline {
+    let mut earned: BTreeMap<u64, Uint256> = BTreeMap::new();
+    let mut spent: BTreeMap<u64, Uint256> = BTreeMap::new();
+    for payment in payments {
+        // payment indexes are hours since the unix epoch
+        let day = payment.index / 24;
+        if day < first_day || day > last_day {
+            continue;
+        }
+        if payment.to.eth_address == our_address {
+            let total = earned.entry(day).or_insert_with(Uint256::zero);
+            *total += payment.amount;
+        }
+        if payment.from.eth_address == our_address {
+            let total = spent.entry(day).or_insert_with(Uint256::zero);
+            *total += payment.amount;
+        }
+    }
+
+    let mut balance = history
+        .days
+        .range(..first_day)
+        .next_back()
+        .map(|(_, balance)| *balance);
+    let mut timeline = EarningsTimeline {
+        days: Vec::new(),
+        total_earned: Uint256::zero(),
+        total_spent: Uint256::zero(),
+    };
+    for day in first_day..=last_day {
+        if let Some(closing) = history.days.get(&day) {
+            balance = Some(*closing);
+        }
+        let day_earned = earned.get(&day).copied().unwrap_or_else(Uint256::zero);
+        let day_spent = spent.get(&day).copied().unwrap_or_else(Uint256::zero);
+        timeline.total_earned += day_earned;
+        timeline.total_spent += day_spent;
+        timeline.days.push(EarningsDay {
+            day,
+            timestamp: day * SECONDS_PER_DAY,
+            balance,
+            earned: day_earned,
+            spent: day_spent,
+        });
+    }
+    timeline
+}
+
+/// The earnings timeline for the last num_days days, including today
+pub fn get_earnings_timeline(num_days: u64) -> Result<EarningsTimeline, RitaCommonError> {
+    let our_address = match settings::get_rita_common().payment.eth_address {
+        Some(address) => address,
+        None => {
+            return Err(RitaCommonError::MiscStringError(
+                "No eth address configured".to_string(),
+            ))
+        }
+    };
+    let last_day = current_day()?;
+    let first_day = last_day.saturating_sub(num_days.saturating_sub(1));
+    let history = BALANCE_HISTORY.read().unwrap().history.clone();
+    let usage_tracker = USAGE_TRACKER_STORAGE.read().unwrap();
+    Ok(build_earnings_timeline(
+        &history,
+        usage_tracker.usage_tracker.payments.iter(),
+        our_address,
+        first_day,
+        last_day,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_manager::get_test_id;
+    use althea_types::Identity;
+
+    #[test]
+    fn test_record_balance() {
+        let mut history = BalanceHistory::default();
+        assert!(history.record(10, 5u32.into()));
+        assert!(!history.record(10, 5u32.into()));
+        // a later sample the same day replaces the closing balance
+        assert!(history.record(10, 7u32.into()));
+        assert_eq!(history.days.get(&10), Some(&7u32.into()));
+
+        for day in 0..(MAX_BALANCE_DAYS as u64 + 20) {
+            history.record(100 + day, day.into());
+        }
+        assert_eq!(history.days.len(), MAX_BALANCE_DAYS);
+        assert!(!history.days.contains_key(&10));
+    }
+
+    #[test]
+    fn test_build_earnings_timeline() {
+        let us = Identity {
+            eth_address: "0x5aee3dff733f56cfe7e5390b9cc3a46a90ca1cfa"
+                .parse()
+                .unwrap(),
+            ..get_test_id()
+        };
+        let them = Identity {
+            eth_address: "0xbda3c7fa35896de7fa3e3591b44b44baaa3e3bc1"
+                .parse()
+                .unwrap(),
+            ..get_test_id()
+        };
+        let payment = |to: Identity, from: Identity, amount: u32, hour: u64| UsageTrackerPayment {
+            to,
+            from,
+            amount: amount.into(),
+            txid: (hour + amount as u64).into(),
+            index: hour,
+        };
+        let payments = vec![
+            // day 100
+            payment(us, them, 50, 100 * 24 + 1),
+            payment(us, them, 25, 100 * 24 + 23),
+            payment(them, us, 10, 100 * 24 + 5),
+            // day 102
+            payment(us, them, 40, 102 * 24),
+            // before the range
+            payment(us, them, 1000, 90 * 24),
+        ];
+        let mut history = BalanceHistory::default();
+        history.record(95, 1000u32.into());
+        history.record(100, 1065u32.into());
+        history.record(102, 1105u32.into());
+
+        let timeline = build_earnings_timeline(&history, payments.iter(), us.eth_address, 99, 102);
+        assert_eq!(timeline.days.len(), 4);
+        assert_eq!(timeline.total_earned, 115u32.into());
+        assert_eq!(timeline.total_spent, 10u32.into());
+
+        let day_99 = &timeline.days[0];
+        assert_eq!(day_99.timestamp, 99 * SECONDS_PER_DAY);
+        // carried forward from the sample before the range
+        assert_eq!(day_99.balance, Some(1000u32.into()));
+        assert_eq!(day_99.earned, 0u32.into());
+
+        assert_eq!(timeline.days[1].earned, 75u32.into());
+        assert_eq!(timeline.days[1].spent, 10u32.into());
+        assert_eq!(timeline.days[2].balance, Some(1065u32.into()));
+        assert_eq!(timeline.days[3].earned, 40u32.into());
+        assert_eq!(timeline.days[3].balance, Some(1105u32.into()));
+
+        // no samples at all
+        let timeline = build_earnings_timeline(
+            &BalanceHistory::default(),
+            payments.iter(),
+            us.eth_address,
+            100,
+            100,
+        );
+        assert_eq!(timeline.days[0].balance, None);
+    }
+}
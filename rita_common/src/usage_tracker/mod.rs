@@ -3,24 +3,32 @@
 //! in that round and exactly what type of bandwidth it is is sent to this module, from there
 //! the handler updates the storage to reflect the new total. When a user would like to inspect
 //! or graph usage they query an endpoint which will request the data from this module.
+//!
+//! Usage is persisted as a snapshot plus append only segments, see the segments module. The balance
+//! timeline used to graph earnings is kept alongside, see the balance_history module.
 
 use crate::rita_loop::write_to_disk::is_router_storage_small;
 use crate::RitaCommonError;
+use crate::KI;
+use althea_kernel_interface::StorageType;
 use althea_types::convert_flat_to_map_usage_data;
 use althea_types::convert_map_to_flat_usage_data;
 use althea_types::user_info::Usage;
 use althea_types::IndexedUsageHour;
 use althea_types::PaymentTx;
+use balance_history::flush_balance_history;
 use bincode::Error as BincodeError;
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
+use num256::Uint256;
+use segments::{replay_segments, UsagePersistence, WearPolicy};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::fs;
 use std::fs::File;
 use std::io::Error as IOError;
-use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
@@ -32,6 +40,9 @@ use std::time::UNIX_EPOCH;
 use std::usize;
 use structs::*;
 
+pub mod annual_summary;
+pub mod balance_history;
+pub mod segments;
 pub mod structs;
 pub mod tests;
 
@@ -47,16 +58,13 @@ const MAX_TX_ENTRIES: usize = 5_000;
 // essentially only occurs when prompted for an upgrade, or a reboot command is sent
 // the most common form of restart, yanking the power cord, will not be graceful.
 pub const MINIMUM_NUMBER_OF_TRANSACTIONS_LARGE_STORAGE: usize = 5;
-pub const MINIMUM_NUMBER_OF_TRANSACTIONS_SMALL_STORAGE: usize = 75;
-
-/// The maximum amount of usage data that may be unsaved before we save out to the disk
-pub const MAX_UNSAVED_USAGE: u64 = 10 * 1000u64.pow(3);
 
 /// Just a storage wrapper for the usage tracker lazy static, to wrap the persisted data
 /// (usage tracker) and the non persistated data (throughput tracker) without using raw tuple
 pub struct UsageTrackerWrapper {
     usage_tracker: UsageTrackerStorage,
     throughtput_tracker: ThroughputTracker,
+    persistence: UsagePersistence,
 }
 
 impl Default for UsageTrackerWrapper {
@@ -67,89 +75,94 @@ impl Default for UsageTrackerWrapper {
 
 impl UsageTrackerWrapper {
     pub fn new() -> UsageTrackerWrapper {
+        let network = settings::get_rita_common().network;
+        let mut usage_tracker = UsageTrackerStorage::load_from_disk();
+        let (segments, next_seq) = replay_segments(&network.usage_tracker_file, &mut usage_tracker);
+        usage_tracker.trim_history(MAX_USAGE_ENTRIES, MAX_TX_ENTRIES);
+        let storage = get_usage_storage_type();
+        info!(
+            "Usage tracker is on {:?} storage with {} segments",
+            storage, segments
+        );
         UsageTrackerWrapper {
-            usage_tracker: UsageTrackerStorage::load_from_disk(),
+            usage_tracker,
             throughtput_tracker: ThroughputTracker::default(),
+            persistence: UsagePersistence::new(
+                WearPolicy::new(storage, network.usage_tracker_write_interval),
+                segments,
+                next_seq,
+            ),
         }
     }
 }
 
-lazy_static! {
-    static ref USAGE_TRACKER_STORAGE: Arc<RwLock<UsageTrackerWrapper>> =
-        Arc::new(RwLock::new(UsageTrackerWrapper::new()));
+/// Reads usage_tracker_file and its segments without touching the running tracker, for offline tools
+pub fn load_usage_tracker_from_disk() -> UsageTrackerStorage {
+    let network = settings::get_rita_common().network;
+    let mut usage_tracker = UsageTrackerStorage::load_from_disk();
+    replay_segments(&network.usage_tracker_file, &mut usage_tracker);
+    usage_tracker
 }
 
-/// Utility function that grabs usage tracker from it's lock and
-/// saves it out. Should be called when we want to save anywhere outside this file
-pub fn save_usage_to_disk() {
-    match USAGE_TRACKER_STORAGE.write().unwrap().usage_tracker.save() {
-        Ok(_val) => info!("Saved usage tracker successfully"),
-        Err(e) => warn!("Unable to save usage tracker {:}", e),
+/// The storage usage_tracker_file is on, from the settings if set, otherwise detected, falling back to
+/// the list of devices known to have small flash
+pub(crate) fn get_usage_storage_type() -> StorageType {
+    let network = settings::get_rita_common().network;
+    if let Some(storage) = network.usage_tracker_storage {
+        return storage;
+    }
+    let path = Path::new(&network.usage_tracker_file);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().to_string(),
+        _ => ".".to_string(),
     };
+    match KI.get_storage_type(&dir) {
+        Ok(storage) => storage,
+        Err(e) => {
+            warn!("Failed to detect usage tracker storage type {:?}", e);
+            if is_router_storage_small(&network.device.unwrap_or_else(|| "x86_64".to_string())) {
+                StorageType::NorFlash
+            } else {
+                StorageType::Disk
+            }
+        }
+    }
 }
 
-/// Helps determine how often we write out to the disk on different devices by setting a device specific mininum
-/// number of transactions before saving
-pub fn get_minimum_number_of_transactions_to_store() -> usize {
-    let settings = settings::get_rita_common();
-    if is_router_storage_small(
-        &settings
-            .network
-            .device
-            .unwrap_or_else(|| "x86_64".to_string()),
-    ) {
-        MINIMUM_NUMBER_OF_TRANSACTIONS_SMALL_STORAGE
-    } else {
-        MINIMUM_NUMBER_OF_TRANSACTIONS_LARGE_STORAGE
-    }
+lazy_static! {
+    static ref USAGE_TRACKER_STORAGE: Arc<RwLock<UsageTrackerWrapper>> =
+        Arc::new(RwLock::new(UsageTrackerWrapper::new()));
 }
 
-impl UsageTrackerStorage {
-    /// This function checks to see how many bytes were used
-    /// and if the amount used is not greater than 10gb than
-    /// it will return false. Essentially, it's checking to make
-    /// sure that there is enough usage to be worth saving
-    pub fn check_unsaved_usage(&self) -> bool {
-        let mut total_unsaved_bytes = 0;
-        let v = vec![
-            &self.client_bandwidth,
-            &self.relay_bandwidth,
-            &self.exit_bandwidth,
-        ];
-        for i in v {
-            for (index, usage) in i {
-                if *index > self.last_save_hour {
-                    total_unsaved_bytes += usage.up;
-                    total_unsaved_bytes += usage.down;
-                }
-            }
-        }
-        total_unsaved_bytes > MAX_UNSAVED_USAGE
+fn flush_usage(force: bool) {
+    let file = settings::get_rita_common().network.usage_tracker_file;
+    let wrapper = &mut *USAGE_TRACKER_STORAGE.write().unwrap();
+    if let Err(e) = wrapper
+        .persistence
+        .flush(&file, &mut wrapper.usage_tracker, force)
+    {
+        warn!("Unable to save usage tracker {:}", e);
     }
+}
 
-    /// Returns true if the numberof unsaved payments is greater than the mininum number of transactions to store
-    pub fn check_unsaved_payments(&self) -> bool {
-        let mut total_num_unsaved_payments = 0;
-        for p in self.payments.iter() {
-            if p.index > self.last_save_hour {
-                total_num_unsaved_payments += 1;
-            }
-        }
-        total_num_unsaved_payments > get_minimum_number_of_transactions_to_store()
-    }
+/// Writes out usage that changed since the last write, at most as often as the storage usage_tracker_file
+/// is on can take. Called every slow loop tick
+pub fn save_usage_to_disk() {
+    flush_usage(false)
+}
 
+impl UsageTrackerStorage {
+    /// Writes a full snapshot to usage_tracker_file
     pub fn save(&mut self) -> Result<(), RitaCommonError> {
-        let settings = settings::get_rita_common();
-
-        if self.check_unsaved_payments() || self.check_unsaved_usage() {
-            return Err(RitaCommonError::StdError(IOError::new(
-                ErrorKind::Other,
-                "Too little data for writing",
-            )));
-        }
+        self.save_to(&settings::get_rita_common().network.usage_tracker_file)
+    }
 
+    /// Writes a full snapshot to the given path, through a temporary file so that a crash
+    /// can't leave a half written snapshot behind
+    pub fn save_to(&mut self, path: &str) -> Result<(), RitaCommonError> {
         let serialized = bincode::serialize(self)?;
-        let mut file = File::create(settings.network.usage_tracker_file)?;
+        let tmp_path = format!("{path}.tmp");
+        let mut file = File::create(&tmp_path)?;
 
         let mut compressed_bytes = match compress_serialized(serialized) {
             Ok(bytes) => bytes,
@@ -161,12 +174,14 @@ impl UsageTrackerStorage {
         // data, if this occurs we trim the data we store until it fits
         loop {
             match file.write_all(&compressed_bytes) {
-                Ok(save) => {
+                Ok(()) => {
+                    file.sync_all()?;
+                    fs::rename(&tmp_path, path)?;
                     info!(
                         "Saved to disk for usage tracker {:}",
                         compressed_bytes.len()
                     );
-                    return Ok(save);
+                    return Ok(());
                 }
                 Err(e) => {
                     warn!("Failed to save usage tracker data with {:?}", e);
@@ -181,7 +196,10 @@ impl UsageTrackerStorage {
                             Ok(bytes) => bytes,
                             Err(e) => return Err(RitaCommonError::StdError(e)),
                         };
+                        // start over rather than after whatever part made it out
+                        file = File::create(&tmp_path)?;
                     } else {
+                        let _ = fs::remove_file(&tmp_path);
                         return Err(RitaCommonError::StdError(e));
                     }
                     continue;
@@ -346,6 +364,7 @@ pub fn update_usage_data(msg: UpdateUsage) {
     usage_tracker
         .usage_tracker
         .process_usage_update(curr_hour, msg);
+    usage_tracker.persistence.mark_hour(msg.kind, curr_hour);
     usage_tracker.throughtput_tracker.process_usage_update(msg);
 }
 
@@ -423,26 +442,70 @@ pub fn update_payments(payment: PaymentTx) {
         return;
     }
 
-    history.usage_tracker.handle_payments(&payment);
+    if let Some(formatted) = history.usage_tracker.handle_payments(&payment) {
+        history.persistence.mark_payment(formatted);
+    }
+}
+
+/// Drops a payment a reorg reversed from the payment history, if it is mined again it is added back
+/// by update_payments like any other payment
+pub fn remove_reverted_payment(txid: Uint256) {
+    let mut history = USAGE_TRACKER_STORAGE.write().unwrap();
+    let before = history.usage_tracker.payments.len();
+    history.usage_tracker.payments.retain(|p| p.txid != txid);
+    // the payment may already be in a segment, which would bring it back on the next start
+    if history.usage_tracker.payments.len() != before {
+        history.persistence.compact_next = true;
+    }
 }
 
 impl UsageTrackerStorage {
-    /// Internal handler function that deals with adding a payment to the list
-    /// and saving if required
-    fn handle_payments(&mut self, payment: &PaymentTx) {
+    /// Internal handler function that deals with adding a payment to the list,
+    /// returns the payment as it was stored
+    fn handle_payments(&mut self, payment: &PaymentTx) -> Option<UsageTrackerPayment> {
         let current_hour = match get_current_hour() {
             Ok(hour) => hour,
             Err(e) => {
                 error!("System time is set earlier than unix epoch! {:?}", e);
-                return;
+                return None;
             }
         };
         let formatted_payment = UsageTrackerPayment::from_payment_tx(*payment, current_hour);
-        self.payments.insert(formatted_payment);
+        self.payments.insert(formatted_payment.clone());
 
         while self.payments.len() > MAX_TX_ENTRIES {
             self.remove_oldest_payment_history_entry()
         }
+        Some(formatted_payment)
+    }
+
+    /// Drops all but the newest keep_hours of each usage history and keep_payments payments,
+    /// returns how many entries were removed
+    fn trim_history(&mut self, keep_hours: usize, keep_payments: usize) -> usize {
+        let mut removed = 0;
+        for history in [
+            &mut self.client_bandwidth,
+            &mut self.relay_bandwidth,
+            &mut self.exit_bandwidth,
+        ] {
+            if history.len() > keep_hours {
+                let mut hours: Vec<u64> = history.keys().cloned().collect();
+                hours.sort_unstable();
+                for hour in &hours[..hours.len() - keep_hours] {
+                    history.remove(hour);
+                    removed += 1;
+                }
+            }
+        }
+        if self.payments.len() > keep_payments {
+            let mut payments: Vec<UsageTrackerPayment> = self.payments.iter().cloned().collect();
+            payments.sort_by_key(|p| p.index);
+            for payment in &payments[..payments.len() - keep_payments] {
+                self.payments.remove(payment);
+                removed += 1;
+            }
+        }
+        removed
     }
 
     /// Removes a single tx from the payment history entry, oldest first
@@ -474,6 +537,30 @@ pub fn get_current_throughput(kind: UsageType) -> Option<u64> {
     }
 }
 
+/// The number of usage hours and payments held in memory
+pub fn get_usage_history_len() -> usize {
+    let usage_tracker_var = USAGE_TRACKER_STORAGE.read().unwrap();
+    let storage = &usage_tracker_var.usage_tracker;
+    storage.client_bandwidth.len()
+        + storage.relay_bandwidth.len()
+        + storage.exit_bandwidth.len()
+        + storage.payments.len()
+}
+
+/// Drops old usage and payment history to free memory, the dropped entries are also gone from
+/// disk after the next save. Returns how many entries were removed
+pub fn shed_usage_history(keep_hours: usize, keep_payments: usize) -> usize {
+    let mut usage_tracker_var = USAGE_TRACKER_STORAGE.write().unwrap();
+    let removed = usage_tracker_var
+        .usage_tracker
+        .trim_history(keep_hours, keep_payments);
+    // otherwise the dropped entries would come back from the segments on the next start
+    if removed > 0 {
+        usage_tracker_var.persistence.compact_next = true;
+    }
+    removed
+}
+
 /// Gets usage data for this router, stored on the local disk at periodic intervals
 pub fn get_usage_data_map(kind: UsageType) -> HashMap<u64, Usage> {
     let usage_tracker_var = USAGE_TRACKER_STORAGE.read().unwrap();
@@ -511,5 +598,6 @@ pub fn get_payments_data() -> VecDeque<PaymentHour> {
 /// On an interupt (SIGTERM), saving USAGE_TRACKER before exiting, this is essentially
 /// a reboot or restart only, most common form of shutdown is power being pulled
 pub fn save_usage_on_shutdown() {
-    save_usage_to_disk()
+    flush_usage(true);
+    flush_balance_history(true);
 }
@@ -0,0 +1,435 @@
+//! Append only persistence for the usage tracker. Rewriting the whole compressed history every save
+//! wears out the NOR flash small routers keep their config on, so instead the hours and payments that
+//! changed since the last write are appended as a record to a small segment file next to the snapshot
+//! at usage_tracker_file. Once enough segments pile up they are compacted into a new snapshot.
+//!
+//! How often records are written depends on the storage usage_tracker_file is on, as detected through
+//! KernelInterface. Each record carries its length and a checksum and is synced before the next one is
+//! written, so a power cut can at most tear the last record, which is skipped on load. Anything newer than
+//! the last record written is lost on a power cut, up to one write interval of usage.
+//!
+//! Replaying is not idempotent in general. A crash between writing a snapshot and deleting the segments
+//! it replaced leaves segments older than the snapshot behind, and replaying them re-adds payments and
+//! hours that were shed from the snapshot until the next trim. Records hold the full value of each hour
+//! rather than a delta and an hour only ever grows, so replay keeps the larger of the snapshot's and the
+//! record's value and a stale record can not roll an hour back.
+
+use super::structs::{UsageTrackerPayment, UsageTrackerStorage, UsageType};
+use crate::RitaCommonError;
+use althea_kernel_interface::StorageType;
+use althea_types::user_info::Usage;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Segments are closed once they grow past this, well under a 64KiB flash erase block
+const MAX_SEGMENT_LEN: u64 = 16 * 1024;
+/// Length and checksum
+const RECORD_HEADER_LEN: usize = 8;
+
+/// How often records are written and how many segments are kept before compacting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WearPolicy {
+    pub write_interval: Duration,
+    pub max_segments: usize,
+}
+
+impl WearPolicy {
+    pub fn new(storage: StorageType, write_interval_override: Option<u64>) -> WearPolicy {
+        let (write_interval, max_segments) = match storage {
+            StorageType::NorFlash => (Duration::from_secs(60 * 60), 16),
+            StorageType::NandFlash => (Duration::from_secs(60 * 60), 16),
+            StorageType::Disk => (Duration::from_secs(10 * 60), 8),
+            // nothing to wear out, but the data doesn't outlive a reboot anyway
+            StorageType::Ram => (Duration::from_secs(60), 1),
+        };
+        WearPolicy {
+            write_interval: write_interval_override
+                .map(Duration::from_secs)
+                .unwrap_or(write_interval),
+            max_segments,
+        }
+    }
+}
+
+/// The changes written out in one go
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct SegmentRecord {
+    pub hours: Vec<(UsageType, u64, Usage)>,
+    pub payments: Vec<UsageTrackerPayment>,
+}
+
+impl SegmentRecord {
+    pub fn is_empty(&self) -> bool {
+        self.hours.is_empty() && self.payments.is_empty()
+    }
+
+    pub fn apply(self, storage: &mut UsageTrackerStorage) {
+        for (kind, hour, usage) in self.hours {
+            let history = match kind {
+                UsageType::Client => &mut storage.client_bandwidth,
+                UsageType::Relay => &mut storage.relay_bandwidth,
+                UsageType::Exit => &mut storage.exit_bandwidth,
+            };
+            let entry = history.entry(hour).or_insert(usage);
+            if usage.up + usage.down > entry.up + entry.down {
+                *entry = usage;
+            }
+            storage.last_save_hour = storage.last_save_hour.max(hour);
+        }
+        for payment in self.payments {
+            storage.payments.insert(payment);
+        }
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+fn encode_record(record: &SegmentRecord) -> Result<Vec<u8>, RitaCommonError> {
+    let payload = bincode::serialize(record)?;
+    let mut out = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&checksum(&payload));
+    out.extend_from_slice(&payload);
+    Ok(out)
+}
+
+/// Decodes every intact record in a segment, stopping at the first torn or corrupt one
+fn decode_records(mut bytes: &[u8]) -> Vec<SegmentRecord> {
+    let mut records = Vec::new();
+    while bytes.len() >= RECORD_HEADER_LEN {
+        let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let payload = match bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) {
+            Some(payload) => payload,
+            None => break,
+        };
+        if checksum(payload) != bytes[4..RECORD_HEADER_LEN] {
+            break;
+        }
+        match bincode::deserialize(payload) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+        bytes = &bytes[RECORD_HEADER_LEN + len..];
+    }
+    if !bytes.is_empty() {
+        warn!(
+            "Skipping {} bytes of torn or corrupt usage tracker segment",
+            bytes.len()
+        );
+    }
+    records
+}
+
+fn segment_path(base: &str, seq: u64) -> PathBuf {
+    PathBuf::from(format!("{base}.seg{seq}"))
+}
+
+/// The segments of the snapshot at base, oldest first
+fn list_segments(base: &str) -> Vec<(u64, PathBuf)> {
+    let base_path = Path::new(base);
+    let (dir, prefix) = match (base_path.parent(), base_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, format!("{}.seg", name.to_string_lossy())),
+        _ => return Vec::new(),
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let mut segments: Vec<(u64, PathBuf)> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let seq = name.strip_prefix(&prefix)?.parse().ok()?;
+                Some((seq, entry.path()))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    segments.sort();
+    segments
+}
+
+/// Applies every segment of the snapshot at base on top of it, returns the number of segments
+/// found and the sequence number to write the next one at
+pub fn replay_segments(base: &str, storage: &mut UsageTrackerStorage) -> (usize, u64) {
+    let segments = list_segments(base);
+    for (_, path) in segments.iter() {
+        match fs::read(path) {
+            Ok(bytes) => {
+                for record in decode_records(&bytes) {
+                    record.apply(storage);
+                }
+            }
+            Err(e) => error!("Failed to read usage tracker segment {:?} {:?}", path, e),
+        }
+    }
+    let next_seq = segments.last().map(|(seq, _)| seq + 1).unwrap_or(0);
+    (segments.len(), next_seq)
+}
+
+/// Removes every segment of the snapshot at base, called once a snapshot including them is on disk
+pub fn remove_segments(base: &str) {
+    for (_, path) in list_segments(base) {
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Failed to remove usage tracker segment {:?} {:?}", path, e);
+        }
+    }
+}
+
+/// Appends a record to the given segment and syncs it, returns the new length of the segment
+fn append_record(base: &str, seq: u64, record: &SegmentRecord) -> Result<u64, RitaCommonError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(base, seq))?;
+    file.write_all(&encode_record(record)?)?;
+    file.sync_data()?;
+    Ok(file.metadata()?.len())
+}
+
+/// Tracks what has changed since the last write and where the next record goes
+#[derive(Debug)]
+pub struct UsagePersistence {
+    pub policy: WearPolicy,
+    next_seq: u64,
+    segment_len: u64,
+    /// Segments on disk since the last snapshot
+    segments: usize,
+    last_write: Instant,
+    /// Set when history was dropped, so the next write replaces the snapshot instead of leaving
+    /// the dropped entries in segments to be replayed
+    pub compact_next: bool,
+    dirty_hours: HashSet<(UsageType, u64)>,
+    dirty_payments: Vec<UsageTrackerPayment>,
+}
+
+impl UsagePersistence {
+    pub fn new(policy: WearPolicy, segments: usize, next_seq: u64) -> UsagePersistence {
+        UsagePersistence {
+            policy,
+            next_seq,
+            segment_len: 0,
+            segments,
+            last_write: Instant::now(),
+            compact_next: false,
+            dirty_hours: HashSet::new(),
+            dirty_payments: Vec::new(),
+        }
+    }
+
+    pub fn mark_hour(&mut self, kind: UsageType, hour: u64) {
+        self.dirty_hours.insert((kind, hour));
+    }
+
+    pub fn mark_payment(&mut self, payment: UsageTrackerPayment) {
+        self.dirty_payments.push(payment);
+    }
+
+    fn take_record(&mut self, storage: &UsageTrackerStorage) -> SegmentRecord {
+        let mut hours: Vec<(UsageType, u64, Usage)> = self
+            .dirty_hours
+            .drain()
+            .filter_map(|(kind, hour)| {
+                let history = match kind {
+                    UsageType::Client => &storage.client_bandwidth,
+                    UsageType::Relay => &storage.relay_bandwidth,
+                    UsageType::Exit => &storage.exit_bandwidth,
+                };
+                // hours trimmed since they were marked are not worth bringing back
+                history.get(&hour).map(|usage| (kind, hour, *usage))
+            })
+            .collect();
+        hours.sort_by_key(|(_, hour, _)| *hour);
+        SegmentRecord {
+            hours,
+            payments: self
+                .dirty_payments
+                .drain(..)
+                .filter(|payment| storage.payments.contains(payment))
+                .collect(),
+        }
+    }
+
+    /// Writes out what changed once the write interval has passed, or right away if forced, for
+    /// example on shutdown. Compacts into a new snapshot when too many segments have built up
+    pub fn flush(
+        &mut self,
+        base: &str,
+        storage: &mut UsageTrackerStorage,
+        force: bool,
+    ) -> Result<(), RitaCommonError> {
+        if !force && !self.compact_next && self.last_write.elapsed() < self.policy.write_interval {
+            return Ok(());
+        }
+        self.last_write = Instant::now();
+
+        if self.compact_next || self.segments >= self.policy.max_segments {
+            self.dirty_hours.clear();
+            self.dirty_payments.clear();
+            storage.save_to(base)?;
+            remove_segments(base);
+            self.segments = 0;
+            self.segment_len = 0;
+            self.compact_next = false;
+            info!("Compacted usage tracker segments into a new snapshot");
+            return Ok(());
+        }
+
+        let record = self.take_record(storage);
+        if record.is_empty() {
+            return Ok(());
+        }
+        if self.segment_len == 0 {
+            self.segments += 1;
+        }
+        self.segment_len = match append_record(base, self.next_seq, &record) {
+            Ok(len) => len,
+            Err(e) => {
+                // the segment may end in a partial record now, anything after it would be lost
+                // on replay, so start a fresh one and try these changes again next time
+                self.next_seq += 1;
+                self.segment_len = 0;
+                for (kind, hour, _) in record.hours {
+                    self.mark_hour(kind, hour);
+                }
+                self.dirty_payments.extend(record.payments);
+                return Err(e);
+            }
+        };
+        info!(
+            "Appended {} usage hours and {} payments to usage tracker segment {}",
+            record.hours.len(),
+            record.payments.len(),
+            self.next_seq
+        );
+        if self.segment_len >= MAX_SEGMENT_LEN {
+            self.next_seq += 1;
+            self.segment_len = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn usage(up: u64) -> Usage {
+        Usage {
+            up,
+            down: up * 2,
+            price: 10,
+        }
+    }
+
+    fn blank_storage() -> UsageTrackerStorage {
+        UsageTrackerStorage {
+            last_save_hour: 0,
+            client_bandwidth: HashMap::new(),
+            relay_bandwidth: HashMap::new(),
+            exit_bandwidth: HashMap::new(),
+            payments: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn test_torn_record_is_skipped() {
+        let first = SegmentRecord {
+            hours: vec![(UsageType::Client, 5, usage(1))],
+            payments: Vec::new(),
+        };
+        let second = SegmentRecord {
+            hours: vec![(UsageType::Relay, 6, usage(2))],
+            payments: Vec::new(),
+        };
+        let mut bytes = encode_record(&first).unwrap();
+        let second_bytes = encode_record(&second).unwrap();
+        bytes.extend_from_slice(&second_bytes);
+        assert_eq!(decode_records(&bytes), vec![first.clone(), second]);
+
+        // a power cut in the middle of the second record
+        let torn = &bytes[..bytes.len() - 3];
+        assert_eq!(decode_records(torn), vec![first.clone()]);
+
+        // a flipped bit in the second record
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert_eq!(decode_records(&corrupt), vec![first]);
+        assert!(decode_records(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_segments_replay() {
+        let dir = std::env::temp_dir().join(format!("usage_segments_{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("usage.bincode").to_string_lossy().to_string();
+
+        let mut storage = blank_storage();
+        let mut persistence = UsagePersistence::new(WearPolicy::new(StorageType::Disk, None), 0, 0);
+        storage.client_bandwidth.insert(100, usage(1));
+        persistence.mark_hour(UsageType::Client, 100);
+        // not due yet
+        persistence.flush(&base, &mut storage, false).unwrap();
+        assert!(list_segments(&base).is_empty());
+        persistence.flush(&base, &mut storage, true).unwrap();
+
+        // the hour keeps growing, only its latest value matters
+        storage.client_bandwidth.insert(100, usage(5));
+        storage.exit_bandwidth.insert(101, usage(3));
+        persistence.mark_hour(UsageType::Client, 100);
+        persistence.mark_hour(UsageType::Exit, 101);
+        persistence.flush(&base, &mut storage, true).unwrap();
+        assert_eq!(list_segments(&base).len(), 1);
+
+        let mut loaded = blank_storage();
+        let (segments, next_seq) = replay_segments(&base, &mut loaded);
+        assert_eq!((segments, next_seq), (1, 1));
+        assert_eq!(loaded.client_bandwidth, storage.client_bandwidth);
+        assert_eq!(loaded.exit_bandwidth, storage.exit_bandwidth);
+        assert_eq!(loaded.last_save_hour, 101);
+
+        // replaying again changes nothing
+        replay_segments(&base, &mut loaded);
+        assert_eq!(loaded.client_bandwidth, storage.client_bandwidth);
+
+        // a stale segment left behind by a crash mid compaction can't roll an hour back
+        let mut newer = blank_storage();
+        newer.client_bandwidth.insert(100, usage(9));
+        replay_segments(&base, &mut newer);
+        assert_eq!(newer.client_bandwidth.get(&100), Some(&usage(9)));
+
+        // a crash mid write leaves everything before it readable
+        let (_, path) = list_segments(&base).pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+        let mut loaded = blank_storage();
+        replay_segments(&base, &mut loaded);
+        assert_eq!(loaded.client_bandwidth, storage.client_bandwidth);
+
+        remove_segments(&base);
+        assert!(list_segments(&base).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wear_policy() {
+        let nor = WearPolicy::new(StorageType::NorFlash, None);
+        let disk = WearPolicy::new(StorageType::Disk, None);
+        assert!(nor.write_interval > disk.write_interval);
+        assert_eq!(
+            WearPolicy::new(StorageType::NorFlash, Some(30)).write_interval,
+            Duration::from_secs(30)
+        );
+    }
+}
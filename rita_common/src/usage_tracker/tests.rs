@@ -111,6 +111,35 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn trim_usage_history() {
+        let mut tracker = generate_dummy_usage_tracker();
+        let before = tracker.clone();
+        let payments = tracker.payments.len();
+        let removed = tracker.trim_history(24, 10);
+        assert_eq!(tracker.client_bandwidth.len(), 24);
+        assert_eq!(tracker.payments.len(), 10);
+        assert_eq!(
+            removed,
+            before.client_bandwidth.len()
+                + before.relay_bandwidth.len()
+                + before.exit_bandwidth.len()
+                + payments
+                - 3 * 24
+                - 10
+        );
+        // the newest entries are the ones kept
+        let newest_hour = *before.client_bandwidth.keys().max().unwrap();
+        assert!(tracker.client_bandwidth.contains_key(&newest_hour));
+        let newest_payment = before.payments.iter().map(|p| p.index).max().unwrap();
+        assert!(tracker.payments.iter().any(|p| p.index == newest_payment));
+
+        // nothing to drop
+        let mut small = tracker.clone();
+        assert_eq!(small.trim_history(MAX_USAGE_ENTRIES, 5_000), 0);
+        assert_eq!(small, tracker);
+    }
+
     // generates a nontrivial usage tracker struct for testing
     pub fn generate_dummy_usage_tracker() -> UsageTrackerStorage {
         let current_hour = get_current_hour().unwrap();
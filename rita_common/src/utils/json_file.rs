@@ -0,0 +1,88 @@
+//! Loading and saving the small json files that operator managed state is kept in, lists like the exit
+//! denylist that must survive restarts. Loads fail closed, a file that exists but can't be read or parsed
+//! is an error rather than an empty list, so that a bad file is never mistaken for an empty one and then
+//! written over by the next save. Saves are atomic, a power cut mid write leaves the last complete save
+//! in place.
+
+use crate::RitaCommonError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{Error as IOError, ErrorKind, Write};
+
+/// Writes to a temporary file next to path then moves it over path, so a power cut mid write leaves
+/// the last complete save in place
+pub fn write_atomically(path: &str, contents: &[u8]) -> Result<(), IOError> {
+    let tmp_path = format!("{path}.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+/// Loads the json file at path, the default if there is no file yet. A file that can't be read or
+/// parsed is an error, callers must not carry on with the default in its place
+pub fn load_json_file<T: DeserializeOwned + Default>(path: &str) -> Result<T, RitaCommonError> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| RitaCommonError::MiscStringError(format!("Failed to parse {path} {e}"))),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("No {} yet, starting empty", path);
+            Ok(T::default())
+        }
+        Err(e) => Err(RitaCommonError::MiscStringError(format!(
+            "Failed to read {path} {e}"
+        ))),
+    }
+}
+
+/// The value cached in cache, loaded from path with load_json_file first if it isn't yet. Nothing is
+/// cached when the load fails so that it is tried again next time
+pub fn cached_json_file<'a, T: DeserializeOwned + Default>(
+    cache: &'a mut Option<T>,
+    path: &str,
+) -> Result<&'a mut T, RitaCommonError> {
+    let value = match cache.take() {
+        Some(value) => value,
+        None => load_json_file(path)?,
+    };
+    Ok(cache.insert(value))
+}
+
+/// Saves value to path as json with write_atomically
+pub fn save_json_file<T: Serialize>(path: &str, value: &T) -> Result<(), RitaCommonError> {
+    let serialized = serde_json::to_vec(value)
+        .map_err(|e| RitaCommonError::MiscStringError(format!("Failed to serialize {path} {e}")))?;
+    write_atomically(path, &serialized)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_json_file() {
+        let path = std::env::temp_dir().join(format!("json-file-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        // no file yet is empty
+        let loaded: HashSet<u64> = load_json_file(path).unwrap();
+        assert!(loaded.is_empty());
+
+        let saved: HashSet<u64> = [1, 2, 3].into_iter().collect();
+        save_json_file(path, &saved).unwrap();
+        assert_eq!(load_json_file::<HashSet<u64>>(path).unwrap(), saved);
+
+        // a torn or corrupt file is an error, not an empty set
+        fs::write(path, b"[1, 2,").unwrap();
+        assert!(load_json_file::<HashSet<u64>>(path).is_err());
+        let mut cache: Option<HashSet<u64>> = None;
+        assert!(cached_json_file(&mut cache, path).is_err());
+        assert!(cache.is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -2,12 +2,13 @@ use std::env;
 use std::time::Duration;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use babel_monitor::open_babel_stream;
+use babel_monitor::pool::get_babel_stream;
 use babel_monitor::structs::BabeldConfig;
 
 /// Random utilities that don't go anywhere else, many of these are used only in one or the other of rita_exit or rita_client so one will use it and the other will
 /// throw a dead code warning.
 pub mod ip_increment;
+pub mod json_file;
 
 #[allow(dead_code)]
 pub fn option_convert<B: std::convert::From<A>, A>(item: Option<A>) -> Option<B> {
@@ -43,7 +44,7 @@ pub fn apply_babeld_settings_defaults(babeld_port: u16, config: BabeldConfig) {
     const BABEL_CONTACT_TIMEOUT: Duration = Duration::from_secs(20);
     let start = Instant::now();
     while Instant::now() < start + BABEL_CONTACT_TIMEOUT {
-        match open_babel_stream(babeld_port, BABEL_CONTACT_TIMEOUT) {
+        match get_babel_stream(babeld_port, BABEL_CONTACT_TIMEOUT) {
             Ok(mut stream) => {
                 if let Err(e) = babel_monitor::set_local_fee(&mut stream, config.local_fee) {
                     error!("Failed to set babel local fee with {:?}", e);
@@ -0,0 +1,110 @@
+//! The exit operator admin api, client management, pricing and the denylist are served here on their
+//! own address behind mandatory basic auth, so that the exit_hello_port only carries client protocol
+//! traffic and none of these can be reached by clients over the mesh
+
+use crate::network_endpoints::{
+    add_client_override, add_denylist_entry, add_isolated_client, delete_client_override,
+    get_client_audit, get_client_denylist, get_client_isolation, get_client_override_list,
+    get_client_promotion_history, get_cluster_bootstrap, get_consistency_audit,
+    get_enforcement_shadow, get_exit_backups, get_exit_clients, get_exit_maintenance,
+    get_exit_migration, get_exit_price, get_exit_settings, get_exit_shard_status, get_promotions,
+    remove_client_isolation, remove_denylist_entry, run_exit_backup, set_enforcement_backend,
+    set_exit_maintenance, set_exit_price, set_exit_settings, start_exit_migration,
+    stop_exit_migration,
+};
+use actix_async::System;
+use actix_web_async::{web, App, HttpServer};
+use rita_common::dashboard::babel::*;
+use rita_common::dashboard::debts::bulk_debt_update_endpoint;
+use rita_common::dashboard::development::wipe;
+use rita_common::dashboard::wallet::{withdraw, withdraw_all};
+use rita_common::middleware;
+use std::thread;
+
+/// Starts the admin api if it is configured, it is never started without a password
+pub fn start_rita_exit_admin_api() {
+    let admin_api = match settings::get_rita_exit().exit_network.admin_api {
+        Some(admin_api) => admin_api,
+        None => {
+            info!("No admin api configured, exit client management is unavailable");
+            return;
+        }
+    };
+    if admin_api.password.is_empty() {
+        error!("The exit admin api requires a password, not starting it");
+        return;
+    }
+
+    thread::spawn(move || {
+        let runner = System::new();
+        runner.block_on(async move {
+            let password = admin_api.password.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .wrap(middleware::CsrfMiddlewareFactory)
+                    .wrap(middleware::RequiredAuthMiddlewareFactory {
+                        password: password.clone(),
+                    })
+                    .wrap(middleware::HeadersMiddlewareFactory)
+                    .route("/settings", web::get().to(get_exit_settings))
+                    .route("/settings", web::post().to(set_exit_settings))
+                    .route("/wipe", web::post().to(wipe))
+                    .route("/withdraw/{address}/{amount}", web::post().to(withdraw))
+                    .route("/withdraw_all/{address}", web::post().to(withdraw_all))
+                    .route("/debts/bulk", web::post().to(bulk_debt_update_endpoint))
+                    .route("/clients", web::get().to(get_exit_clients))
+                    .route("/clients/consistency", web::get().to(get_consistency_audit))
+                    .route(
+                        "/clients/audit/{wg_key:.+}",
+                        web::get().to(get_client_audit),
+                    )
+                    .route("/denylist", web::get().to(get_client_denylist))
+                    .route("/denylist", web::post().to(add_denylist_entry))
+                    .route("/denylist/remove", web::post().to(remove_denylist_entry))
+                    .route("/overrides", web::get().to(get_client_override_list))
+                    .route("/overrides", web::post().to(add_client_override))
+                    .route("/overrides/remove", web::post().to(delete_client_override))
+                    .route("/isolation", web::get().to(get_client_isolation))
+                    .route("/isolation", web::post().to(add_isolated_client))
+                    .route("/isolation/remove", web::post().to(remove_client_isolation))
+                    .route("/maintenance", web::get().to(get_exit_maintenance))
+                    .route("/maintenance", web::post().to(set_exit_maintenance))
+                    .route("/migration", web::get().to(get_exit_migration))
+                    .route("/migration", web::post().to(start_exit_migration))
+                    .route("/migration/cancel", web::post().to(stop_exit_migration))
+                    .route("/enforcement/shadow", web::get().to(get_enforcement_shadow))
+                    .route(
+                        "/enforcement/backend/{backend}",
+                        web::post().to(set_enforcement_backend),
+                    )
+                    .route("/cluster/bootstrap", web::post().to(get_cluster_bootstrap))
+                    .route("/cluster/shard", web::get().to(get_exit_shard_status))
+                    .route("/backups", web::get().to(get_exit_backups))
+                    .route("/backups/run", web::post().to(run_exit_backup))
+                    .route("/promotions", web::get().to(get_promotions))
+                    .route(
+                        "/promotions/{wg_key:.+}",
+                        web::get().to(get_client_promotion_history),
+                    )
+                    .route("/exit_price", web::get().to(get_exit_price))
+                    .route("/exit_price/{price}", web::post().to(set_exit_price))
+                    .route("/local_fee", web::get().to(get_local_fee))
+                    .route("/local_fee/{fee}", web::post().to(set_local_fee))
+                    .route("/metric_factor", web::get().to(get_metric_factor))
+                    .route("/metric_factor/{factor}", web::post().to(set_metric_factor))
+            })
+            .workers(1)
+            .shutdown_timeout(0);
+            match server.bind(&admin_api.bind_address) {
+                Ok(server) => {
+                    info!("Exit admin api listening on {}", admin_api.bind_address);
+                    let _res = server.run().await;
+                }
+                Err(e) => error!(
+                    "Failed to bind the exit admin api to {} {:?}",
+                    admin_api.bind_address, e
+                ),
+            }
+        });
+    });
+}
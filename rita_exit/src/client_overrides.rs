@@ -0,0 +1,211 @@
+//! Operator managed per client overrides, used by support to exempt a paying customer from a false
+//! positive geoip block or from enforcement while a problem is investigated. An override names a client
+//! by wireguard key, says which checks to skip and why, and normally expires on its own so that nobody
+//! has to remember to remove it. Overrides are kept on disk next to the denylist, validate_clients_region
+//! and signup skip the region check and enforce_exit_clients treats the client as paid up, every skipped
+//! check is logged with the reason and the override shows up in the client audit.
+
+use crate::RitaExitError;
+use althea_types::unix_time::now_secs;
+use althea_types::WgKey;
+use rita_common::utils::json_file::{cached_json_file, save_json_file};
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// The overrides, None until loaded from disk
+    static ref CLIENT_OVERRIDES: Arc<RwLock<Option<Vec<ClientOverride>>>> =
+        Arc::new(RwLock::new(None));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientOverride {
+    pub wg_key: WgKey,
+    /// Serve the client wherever geoip places them
+    pub skip_region_check: bool,
+    /// Never suspend or throttle the client, their debt still accrues
+    pub skip_enforcement: bool,
+    pub reason: String,
+    /// Unix time in seconds the override was added
+    pub added: u64,
+    /// Unix time in seconds after which the override no longer applies, None to keep it until removed
+    pub expires_at: Option<u64>,
+}
+
+impl ClientOverride {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.map(|expires| now < expires).unwrap_or(true)
+    }
+}
+
+/// Request body for adding or replacing a client's override from the dashboard
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientOverrideRequest {
+    pub wg_key: WgKey,
+    #[serde(default)]
+    pub skip_region_check: bool,
+    #[serde(default)]
+    pub skip_enforcement: bool,
+    pub reason: String,
+    pub expires_at: Option<u64>,
+}
+
+/// Request body for removing a client's override from the dashboard
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientOverrideRemoval {
+    pub wg_key: WgKey,
+}
+
+/// The overrides, loaded from disk first if needed. A list that can't be loaded is an error rather than
+/// an empty list, which the next change would save over every other override
+fn overrides(
+    overrides: &mut Option<Vec<ClientOverride>>,
+) -> Result<&mut Vec<ClientOverride>, Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.client_overrides_file;
+    cached_json_file(overrides, &path).map_err(|e| {
+        error!("Failed to load client overrides {}", e);
+        Box::new(e.into())
+    })
+}
+
+/// Applies a change to the overrides and saves them, dropping expired ones. The change is only kept
+/// if it was saved so that the on disk list is never behind what we apply
+fn modify_overrides<T>(
+    change: impl FnOnce(&mut Vec<ClientOverride>) -> T,
+) -> Result<T, Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.client_overrides_file;
+    let mut cached = CLIENT_OVERRIDES.write().unwrap();
+    let mut list = overrides(&mut cached)?.clone();
+    let ret = change(&mut list);
+    let now = now_secs();
+    list.retain(|entry| entry.is_active(now));
+    if let Err(e) = save_json_file(&path, &list) {
+        error!("Failed to save client overrides {}", e);
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Failed to save client overrides".to_string(),
+        )));
+    }
+    *cached = Some(list);
+    Ok(ret)
+}
+
+/// Every override, including any that expired since the list was last saved
+pub fn get_client_overrides() -> Result<Vec<ClientOverride>, Box<RitaExitError>> {
+    Ok(overrides(&mut CLIENT_OVERRIDES.write().unwrap())?.clone())
+}
+
+/// The overrides to apply, while they can't be loaded nobody is exempt from any check
+fn applied_overrides() -> Vec<ClientOverride> {
+    get_client_overrides().unwrap_or_default()
+}
+
+/// Adds an override for a client, replacing any it already has
+pub fn set_client_override(
+    request: ClientOverrideRequest,
+) -> Result<ClientOverride, Box<RitaExitError>> {
+    if !request.skip_region_check && !request.skip_enforcement {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "An override needs to skip the region check, enforcement or both".to_string(),
+        )));
+    }
+    let added = now_secs();
+    if let Some(expires_at) = request.expires_at {
+        if expires_at <= added {
+            return Err(Box::new(RitaExitError::MiscStringError(format!(
+                "expires_at {expires_at} is in the past"
+            ))));
+        }
+    }
+    let entry = ClientOverride {
+        wg_key: request.wg_key,
+        skip_region_check: request.skip_region_check,
+        skip_enforcement: request.skip_enforcement,
+        reason: request.reason,
+        added,
+        expires_at: request.expires_at,
+    };
+    info!("Setting client override {:?}", entry);
+    modify_overrides(|list| {
+        list.retain(|e| e.wg_key != entry.wg_key);
+        list.push(entry.clone())
+    })?;
+    Ok(entry)
+}
+
+/// Removes a client's override, returns true if it had one
+pub fn remove_client_override(removal: ClientOverrideRemoval) -> Result<bool, Box<RitaExitError>> {
+    info!("Removing client override for {}", removal.wg_key);
+    modify_overrides(|list| {
+        let before = list.len();
+        list.retain(|e| e.wg_key != removal.wg_key);
+        before != list.len()
+    })
+}
+
+/// The client's active override, if any
+pub fn find_override<'a>(
+    list: &'a [ClientOverride],
+    wg_key: &WgKey,
+    now: u64,
+) -> Option<&'a ClientOverride> {
+    list.iter()
+        .find(|entry| entry.wg_key == *wg_key && entry.is_active(now))
+}
+
+/// The client's active override, if any
+pub fn check_client_override(wg_key: &WgKey) -> Option<ClientOverride> {
+    find_override(&applied_overrides(), wg_key, now_secs()).cloned()
+}
+
+/// The reason to skip the region check for this client, if an override says to
+pub fn region_check_override(wg_key: &WgKey) -> Option<String> {
+    check_client_override(wg_key)
+        .filter(|o| o.skip_region_check)
+        .map(|o| o.reason)
+}
+
+/// The clients that are exempt from enforcement and why, looked up once per enforcement run
+pub fn enforcement_overrides() -> Vec<ClientOverride> {
+    let now = now_secs();
+    applied_overrides()
+        .into_iter()
+        .filter(|o| o.skip_enforcement && o.is_active(now))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_override() {
+        let key: WgKey = "V9I9yrxAqFqLV+9GeT5pnXPwk4Cxgfvl30Fv8khVGsM="
+            .parse()
+            .unwrap();
+        let other_key: WgKey = [7; 32].into();
+        let list = vec![
+            ClientOverride {
+                wg_key: other_key,
+                skip_region_check: false,
+                skip_enforcement: true,
+                reason: "billing dispute".to_string(),
+                added: 0,
+                expires_at: None,
+            },
+            ClientOverride {
+                wg_key: key,
+                skip_region_check: true,
+                skip_enforcement: false,
+                reason: "geoip places them across the border".to_string(),
+                added: 0,
+                expires_at: Some(1000),
+            },
+        ];
+        assert!(find_override(&list, &key, 999).unwrap().skip_region_check);
+        // the override has expired
+        assert!(find_override(&list, &key, 1000).is_none());
+        assert_eq!(
+            find_override(&list, &other_key, 5000).unwrap().reason,
+            "billing dispute"
+        );
+    }
+}
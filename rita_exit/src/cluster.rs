@@ -0,0 +1,240 @@
+//! Exit cluster bootstrap, see althea_types::exit_cluster. Every member answers bootstrap requests on its
+//! admin api, an exit started with exit_network.cluster_bootstrap set fetches the shared config from the
+//! member named there and saves it before anything else starts, so standing up a replacement exit no longer
+//! means copying wg keys around by hand.
+
+use crate::network_endpoints::CLIENT_STATUS_TIMEOUT;
+use crate::RitaExitError;
+use althea_types::{
+    EncryptedExitClusterConfig, ExitClusterBootstrapRequest, ExitClusterConfig, WgKey,
+};
+use rita_client_registration::client_db::get_exits_list;
+use rita_common::rita_loop::get_web3_server;
+use rita_common::RitaCommonError;
+use rita_common::KI;
+use settings::exit::{ExitClusterBootstrapSettings, RitaExitSettingsStruct};
+use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+use web30::client::Web3;
+
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The part of our settings every exit in the cluster shares
+pub fn get_cluster_config(settings: &RitaExitSettingsStruct) -> ExitClusterConfig {
+    let exit_network = &settings.exit_network;
+    ExitClusterConfig {
+        wg_public_key: exit_network.wg_public_key,
+        wg_private_key: exit_network.wg_private_key,
+        exit_hello_port: exit_network.exit_hello_port,
+        wg_tunnel_port: exit_network.wg_tunnel_port,
+        wg_v2_tunnel_port: exit_network.wg_v2_tunnel_port,
+        exit_price: exit_network.exit_price,
+        own_internal_ip: exit_network.own_internal_ip,
+        netmask: exit_network.netmask,
+        client_subnet_size: exit_network.client_subnet_size,
+        enable_enforcement: exit_network.enable_enforcement,
+        enforcement_exempt_destinations: exit_network.enforcement_exempt_destinations.clone(),
+        min_client_version: exit_network.min_client_version.clone(),
+        min_client_version_deadline: exit_network.min_client_version_deadline,
+        tunnel_mtu: exit_network.tunnel_mtu,
+        allowed_countries: settings.allowed_countries.clone(),
+        country_prices: exit_network.country_prices.clone(),
+    }
+}
+
+/// Overwrites our share of the cluster config, everything specific to this exit is left alone
+pub fn apply_cluster_config(settings: &mut RitaExitSettingsStruct, config: ExitClusterConfig) {
+    let exit_network = &mut settings.exit_network;
+    exit_network.wg_public_key = config.wg_public_key;
+    exit_network.wg_private_key = config.wg_private_key;
+    exit_network.exit_hello_port = config.exit_hello_port;
+    exit_network.wg_tunnel_port = config.wg_tunnel_port;
+    exit_network.wg_v2_tunnel_port = config.wg_v2_tunnel_port;
+    exit_network.exit_price = config.exit_price;
+    exit_network.own_internal_ip = config.own_internal_ip;
+    exit_network.netmask = config.netmask;
+    exit_network.client_subnet_size = config.client_subnet_size;
+    exit_network.enable_enforcement = config.enable_enforcement;
+    exit_network.enforcement_exempt_destinations = config.enforcement_exempt_destinations;
+    exit_network.min_client_version = config.min_client_version;
+    exit_network.min_client_version_deadline = config.min_client_version_deadline;
+    exit_network.tunnel_mtu = config.tunnel_mtu;
+    exit_network.country_prices = config.country_prices;
+    settings.allowed_countries = config.allowed_countries;
+}
+
+/// A config whose wg_exit keys don't match would leave clients unable to reach us
+fn validate_cluster_config(config: &ExitClusterConfig) -> Result<(), RitaExitError> {
+    let secret: SecretKey = config.wg_private_key.into();
+    if WgKey::from(secret.public_key().0) != config.wg_public_key {
+        return Err(RitaExitError::MiscStringError(
+            "Cluster config wg_exit keys do not match".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The mesh wg keys of every exit registered in the exit contract
+pub async fn get_registered_exit_keys() -> Result<HashSet<WgKey>, RitaExitError> {
+    let rita_exit = settings::get_rita_exit();
+    let our_addr = match rita_exit.payment.eth_private_key {
+        Some(key) => key.to_address(),
+        None => {
+            return Err(RitaExitError::MiscStringError(
+                "No eth key to query the exit contract with".to_string(),
+            ))
+        }
+    };
+    let contact = Web3::new(&get_web3_server(), CLIENT_STATUS_TIMEOUT);
+    match get_exits_list(
+        &contact,
+        our_addr,
+        rita_exit.exit_network.registered_users_contract_addr,
+    )
+    .await
+    {
+        Ok(exits) => Ok(exits.into_iter().map(|exit| exit.wg_key).collect()),
+        Err(e) => Err(RitaExitError::MiscStringError(format!(
+            "Failed to get the registered exits {e}"
+        ))),
+    }
+}
+
+/// Seals our cluster config to the exit asking for it, the caller checks it is a registered exit
+pub fn seal_cluster_config(
+    request: ExitClusterBootstrapRequest,
+) -> Result<EncryptedExitClusterConfig, RitaExitError> {
+    let rita_exit = settings::get_rita_exit();
+    let (our_publickey, our_secretkey) = match (
+        rita_exit.network.wg_public_key,
+        rita_exit.network.wg_private_key,
+    ) {
+        (Some(public), Some(private)) => (public, private),
+        _ => {
+            return Err(RitaExitError::MiscStringError(
+                "This exit doesnt have a network wg key?".to_string(),
+            ))
+        }
+    };
+    Ok(EncryptedExitClusterConfig::seal(
+        &get_cluster_config(&rita_exit),
+        our_publickey,
+        &our_secretkey.into(),
+        &request.wg_key.into(),
+    ))
+}
+
+/// Fetches the cluster config from the member in our bootstrap settings and saves it along with the
+/// wg_exit key file, the bootstrap settings are removed once this succeeds
+pub async fn bootstrap_from_cluster(
+    bootstrap: ExitClusterBootstrapSettings,
+) -> Result<(), RitaExitError> {
+    let rita_exit = settings::get_rita_exit();
+    let (our_publickey, our_secretkey) = match (
+        rita_exit.network.wg_public_key,
+        rita_exit.network.wg_private_key,
+    ) {
+        (Some(public), Some(private)) => (public, private),
+        _ => {
+            return Err(RitaExitError::MiscStringError(
+                "No network wg key to bootstrap with".to_string(),
+            ))
+        }
+    };
+
+    let url = format!(
+        "{}/cluster/bootstrap",
+        bootstrap.member_url.trim_end_matches('/')
+    );
+    info!("Bootstrapping exit cluster config from {}", url);
+    let client = awc::Client::default();
+    let response = client
+        .post(&url)
+        .basic_auth("rita", &bootstrap.password)
+        .timeout(BOOTSTRAP_TIMEOUT)
+        .send_json(&ExitClusterBootstrapRequest {
+            wg_key: our_publickey,
+        })
+        .await;
+    let sealed: EncryptedExitClusterConfig = match response {
+        Ok(mut response) if response.status().is_success() => match response.json().await {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                return Err(RitaExitError::MiscStringError(format!(
+                    "Failed to parse cluster config from {url} {e}"
+                )))
+            }
+        },
+        Ok(response) => {
+            return Err(RitaExitError::MiscStringError(format!(
+                "Cluster member {url} refused bootstrap with {}",
+                response.status()
+            )))
+        }
+        Err(e) => {
+            return Err(RitaExitError::MiscStringError(format!(
+                "Failed to contact cluster member {url} {e}"
+            )))
+        }
+    };
+
+    // anyone who learned the admin password could answer, only trust a registered exit
+    if sealed.pubkey == our_publickey || !get_registered_exit_keys().await?.contains(&sealed.pubkey)
+    {
+        return Err(RitaExitError::MiscStringError(format!(
+            "Cluster config was sealed by {} which is not a registered exit",
+            sealed.pubkey
+        )));
+    }
+    let config = sealed.open(&our_secretkey.into())?;
+    validate_cluster_config(&config)?;
+
+    let mut rita_exit = settings::get_rita_exit();
+    apply_cluster_config(&mut rita_exit, config);
+    rita_exit.exit_network.cluster_bootstrap = None;
+    KI.create_wg_key(
+        Path::new(&rita_exit.exit_network.wg_private_key_path),
+        &rita_exit.exit_network.wg_private_key,
+    )?;
+    settings::set_rita_exit(rita_exit);
+    if let Err(e) = settings::write_config() {
+        return Err(RitaCommonError::SettingsError(e).into());
+    }
+    info!("Bootstrapped exit cluster config from {}", url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::regions::Regions;
+    use sodiumoxide::crypto::box_;
+
+    #[test]
+    fn test_apply_cluster_config() {
+        let mut member = RitaExitSettingsStruct::test_default();
+        let (public, secret) = box_::gen_keypair();
+        member.exit_network.wg_public_key = WgKey::from(public.0);
+        member.exit_network.wg_private_key = WgKey::from(secret.0);
+        let mut new_exit = RitaExitSettingsStruct::test_default();
+        let (public, secret) = box_::gen_keypair();
+        new_exit.exit_network.wg_public_key = WgKey::from(public.0);
+        new_exit.exit_network.wg_private_key = WgKey::from(secret.0);
+        new_exit.exit_network.exit_price = 1;
+        new_exit.exit_network.subnet = None;
+        new_exit.allowed_countries.insert(Regions::Colombia);
+
+        let config = get_cluster_config(&member);
+        assert!(validate_cluster_config(&config).is_ok());
+        apply_cluster_config(&mut new_exit, config.clone());
+        assert_eq!(get_cluster_config(&new_exit), config);
+        // the ipv6 subnet is specific to each exit
+        assert_eq!(new_exit.exit_network.subnet, None);
+
+        let mut mismatched = config;
+        mismatched.wg_public_key = WgKey::from(public.0);
+        assert!(validate_cluster_config(&mismatched).is_err());
+    }
+}
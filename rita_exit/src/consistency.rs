@@ -0,0 +1,205 @@
+//! Audits the registered clients list for corrupt registrations. Billing and tunnel setup assume each wg key
+//! belongs to exactly one eth address and the other way around, when the registration contract holds two rows
+//! that share one but not the other, usage and debts are attributed to whichever row happens to be processed
+//! last. Every conflict is reported on the dashboard, but only the rows registered after the wg key or eth
+//! address was first claimed are quarantined and left without tunnels until the registrations are fixed.
+//! Otherwise anyone could get an established client cut off by registering its key or address again.
+
+use althea_types::{Identity, WgKey};
+use clarity::Address;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+lazy_static! {
+    static ref CONSISTENCY_REPORT: Arc<RwLock<ConsistencyReport>> =
+        Arc::new(RwLock::new(ConsistencyReport::default()));
+    /// When the last audit ran, used to rate limit audits to the configured interval
+    static ref LAST_AUDIT: Arc<RwLock<Option<Instant>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ConsistencyConflict {
+    /// One wg key is registered with more than one eth address
+    WgKeyWithManyAddresses {
+        wg_key: WgKey,
+        eth_addresses: Vec<Address>,
+    },
+    /// One eth address is registered with more than one wg key
+    AddressWithManyWgKeys {
+        eth_address: Address,
+        wg_keys: Vec<WgKey>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConsistencyReport {
+    /// When the last audit finished
+    pub last_audit: Option<SystemTime>,
+    /// How many registrations the last audit looked at
+    pub clients_checked: usize,
+    pub conflicts: Vec<ConsistencyConflict>,
+    /// Registrations that are part of a conflict, these are not set up
+    pub quarantined: Vec<Identity>,
+}
+
+/// Finds every wg key and eth address that appears in more than one distinct registration. clients is
+/// in registration order, the first row to claim a key or address keeps it and later rows that claim
+/// it with something else are quarantined
+pub fn find_conflicts(clients: &[Identity]) -> (Vec<ConsistencyConflict>, Vec<Identity>) {
+    let mut by_key: HashMap<WgKey, HashSet<Address>> = HashMap::new();
+    let mut by_address: HashMap<Address, HashSet<WgKey>> = HashMap::new();
+    for client in clients {
+        by_key
+            .entry(client.wg_public_key)
+            .or_default()
+            .insert(client.eth_address);
+        by_address
+            .entry(client.eth_address)
+            .or_default()
+            .insert(client.wg_public_key);
+    }
+
+    // sorted so that repeated audits of the same list produce the same report
+    let mut many_addresses: Vec<(WgKey, HashSet<Address>)> =
+        by_key.into_iter().filter(|(_, a)| a.len() > 1).collect();
+    many_addresses.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+    let mut many_keys: Vec<(Address, HashSet<WgKey>)> = by_address
+        .into_iter()
+        .filter(|(_, k)| k.len() > 1)
+        .collect();
+    many_keys.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+
+    let mut conflicts = Vec::new();
+    let mut bad_keys = HashSet::new();
+    let mut bad_addresses = HashSet::new();
+    for (wg_key, addresses) in many_addresses {
+        let mut eth_addresses: Vec<Address> = addresses.into_iter().collect();
+        eth_addresses.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        bad_keys.insert(wg_key);
+        conflicts.push(ConsistencyConflict::WgKeyWithManyAddresses {
+            wg_key,
+            eth_addresses,
+        });
+    }
+    for (eth_address, keys) in many_keys {
+        let mut wg_keys: Vec<WgKey> = keys.into_iter().collect();
+        wg_keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        bad_addresses.insert(eth_address);
+        conflicts.push(ConsistencyConflict::AddressWithManyWgKeys {
+            eth_address,
+            wg_keys,
+        });
+    }
+
+    // first claims are recorded for every row, a quarantined row still claims what it was first to
+    // register so that a later row reusing it can't become established either
+    let mut key_owner: HashMap<WgKey, Address> = HashMap::new();
+    let mut address_owner: HashMap<Address, WgKey> = HashMap::new();
+    let mut quarantined: Vec<Identity> = Vec::new();
+    for client in clients {
+        if !bad_keys.contains(&client.wg_public_key) && !bad_addresses.contains(&client.eth_address)
+        {
+            continue;
+        }
+        let key_taken = *key_owner
+            .entry(client.wg_public_key)
+            .or_insert(client.eth_address)
+            != client.eth_address;
+        let address_taken = *address_owner
+            .entry(client.eth_address)
+            .or_insert(client.wg_public_key)
+            != client.wg_public_key;
+        if (key_taken || address_taken) && !quarantined.contains(client) {
+            quarantined.push(*client);
+        }
+    }
+    (conflicts, quarantined)
+}
+
+/// Audits the registered clients if the configured interval has passed since the last audit, an
+/// interval of zero disables the audit and releases anyone quarantined
+pub fn run_consistency_audit(clients: &[Identity], interval: Duration) {
+    if interval.is_zero() {
+        *CONSISTENCY_REPORT.write().unwrap() = ConsistencyReport::default();
+        return;
+    }
+    {
+        let mut last_audit = LAST_AUDIT.write().unwrap();
+        if let Some(last) = *last_audit {
+            if last.elapsed() < interval {
+                return;
+            }
+        }
+        *last_audit = Some(Instant::now());
+    }
+
+    let (conflicts, quarantined) = find_conflicts(clients);
+    for conflict in conflicts.iter() {
+        error!("Inconsistent client registration {:?}", conflict);
+    }
+    *CONSISTENCY_REPORT.write().unwrap() = ConsistencyReport {
+        last_audit: Some(SystemTime::now()),
+        clients_checked: clients.len(),
+        conflicts,
+        quarantined,
+    };
+}
+
+pub fn get_consistency_report() -> ConsistencyReport {
+    CONSISTENCY_REPORT.read().unwrap().clone()
+}
+
+/// Clients quarantined by the last audit
+pub fn quarantined_clients() -> Vec<Identity> {
+    CONSISTENCY_REPORT.read().unwrap().quarantined.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8, key: u8, address: &str) -> Identity {
+        Identity {
+            mesh_ip: format!("fd00::{n}").parse().unwrap(),
+            eth_address: address.parse().unwrap(),
+            wg_public_key: [key; 32].into(),
+            nickname: None,
+        }
+    }
+
+    #[test]
+    fn test_find_conflicts() {
+        let a = "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53";
+        let b = "0x4288C538A553357Bb6c3b77Cf1A60Da6E77931F6";
+        let c = "0x9BAbFde52Fe18A5CD00a542b87b4D124a4879582";
+        let clients = vec![
+            id(1, 1, a),
+            // the same registration twice is not a conflict
+            id(1, 1, a),
+            // key 2 is registered to two addresses
+            id(2, 2, b),
+            id(3, 2, c),
+            // address c is also registered with key 4
+            id(4, 4, c),
+        ];
+        let (conflicts, quarantined) = find_conflicts(&clients);
+        assert_eq!(conflicts.len(), 2);
+        assert!(
+            conflicts.contains(&ConsistencyConflict::AddressWithManyWgKeys {
+                eth_address: c.parse().unwrap(),
+                wg_keys: vec![[2; 32].into(), [4; 32].into()],
+            })
+        );
+        // the rows registered first keep their key and address
+        assert_eq!(quarantined, vec![clients[3], clients[4]]);
+
+        // registering an established client's key doesn't get the client quarantined
+        let victim = id(5, 5, a);
+        let (conflicts, quarantined) = find_conflicts(&[victim, id(6, 5, b)]);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(quarantined, vec![id(6, 5, b)]);
+
+        assert_eq!(find_conflicts(&clients[..2]), (Vec::new(), Vec::new()));
+    }
+}
@@ -0,0 +1,114 @@
+//! Access to the registered clients list, selected by exit_network.client_store. Production exits keep
+//! their clients in the registration contract and sign clients up through the registration server. The
+//! memory store exists for integration tests and demo networks that have no blockchain node or
+//! registration server, it registers every client that passes the signup checks on the spot and forgets
+//! them all on restart. It is NOT supported for production, nothing stops a client from signing up as
+//! many times as it likes and exits in a cluster don't share it.
+
+use super::forward_client_signup_request;
+use crate::RitaExitError;
+use althea_types::{ExitClientIdentity, Identity, WgKey};
+use clarity::Address;
+use rita_client_registration::client_db::{
+    get_all_regsitered_clients, get_registered_client_using_wgkey,
+};
+use rita_client_registration::ExitSignupReturn;
+use settings::exit::ExitClientStore;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use web30::client::Web3;
+
+lazy_static! {
+    static ref MEMORY_CLIENTS: Arc<RwLock<HashMap<WgKey, Identity>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+pub fn get_client_store() -> ExitClientStore {
+    settings::get_rita_exit().exit_network.client_store
+}
+
+/// Every registered client
+pub async fn get_all_clients(
+    web3: &Web3,
+    our_address: Address,
+    contract: Address,
+) -> Result<Vec<Identity>, Box<RitaExitError>> {
+    match get_client_store() {
+        ExitClientStore::Contract => get_all_regsitered_clients(web3, our_address, contract)
+            .await
+            .map_err(|e| Box::new(RitaExitError::MiscStringError(format!("{e:?}")))),
+        ExitClientStore::Memory => Ok(get_memory_clients()),
+    }
+}
+
+/// The registered client with this key, an error if there is none
+pub async fn get_client_by_wgkey(
+    key: WgKey,
+    our_address: Address,
+    contract: Address,
+    web3: &Web3,
+) -> Result<Identity, Box<RitaExitError>> {
+    match get_client_store() {
+        ExitClientStore::Contract => {
+            get_registered_client_using_wgkey(key, our_address, contract, web3)
+                .await
+                .map_err(|e| Box::new(RitaExitError::MiscStringError(format!("{e:?}"))))
+        }
+        ExitClientStore::Memory => match MEMORY_CLIENTS.read().unwrap().get(&key) {
+            Some(id) => Ok(*id),
+            None => Err(Box::new(RitaExitError::NoClientError)),
+        },
+    }
+}
+
+/// Registers a client that has passed the signup checks, with the memory store this always succeeds
+pub async fn register_client(client: ExitClientIdentity) -> ExitSignupReturn {
+    match get_client_store() {
+        ExitClientStore::Contract => forward_client_signup_request(client).await,
+        ExitClientStore::Memory => {
+            add_memory_client(client.global);
+            ExitSignupReturn::RegistrationOk
+        }
+    }
+}
+
+fn add_memory_client(id: Identity) {
+    let mut clients = MEMORY_CLIENTS.write().unwrap();
+    if clients.insert(id.wg_public_key, id).is_none() {
+        info!("Registered client {} in the memory client store", id);
+    }
+}
+
+fn get_memory_clients() -> Vec<Identity> {
+    MEMORY_CLIENTS.read().unwrap().values().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_clients() {
+        let id = Identity {
+            mesh_ip: "fd00::1337".parse().unwrap(),
+            eth_address: "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            nickname: None,
+        };
+        add_memory_client(id);
+        // signing up again doesn't add a second entry
+        add_memory_client(id);
+        let clients = get_memory_clients();
+        assert_eq!(
+            clients
+                .iter()
+                .filter(|c| c.wg_public_key == id.wg_public_key)
+                .count(),
+            1
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use althea_types::regions::Regions;
-use babel_monitor::open_babel_stream;
 use babel_monitor::parse_routes;
+use babel_monitor::pool::get_babel_stream;
 use ipnetwork::IpNetwork;
 use rita_common::utils::ip_increment::is_unicast_link_local;
 use rita_common::KI;
@@ -15,7 +15,7 @@ use crate::RitaExitError;
 pub fn get_gateway_ip_single(mesh_ip: IpAddr) -> Result<IpAddr, Box<RitaExitError>> {
     let babel_port = settings::get_rita_exit().network.babel_port;
 
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
+    match get_babel_stream(babel_port, Duration::from_secs(5)) {
         Ok(mut stream) => {
             match parse_routes(&mut stream) {
                 Ok(routes) => {
@@ -67,7 +67,7 @@ pub fn get_gateway_ip_bulk(
     let babel_port = settings::get_rita_exit().network.babel_port;
     trace!("getting gateway ip bulk");
 
-    match open_babel_stream(babel_port, timeout) {
+    match get_babel_stream(babel_port, timeout) {
         Ok(mut stream) => {
             match parse_routes(&mut stream) {
                 Ok(routes) => {
@@ -133,9 +133,10 @@ struct CountryDetails {
 pub fn get_country(ip: IpAddr) -> Result<Regions, Box<RitaExitError>> {
     trace!("get GeoIP country for {}", ip.to_string());
 
-    // if allowed countries is not configured we don't care and will use
-    // unkonwn region as a placeholder
-    if settings::get_rita_exit().allowed_countries.is_empty() {
+    // if neither allowed countries nor country prices are configured we don't
+    // care and will use unkonwn region as a placeholder
+    let rita_exit = settings::get_rita_exit_snapshot();
+    if rita_exit.allowed_countries.is_empty() && rita_exit.exit_network.country_prices.is_empty() {
         return Ok(Regions::UnkownRegion);
     }
 
@@ -143,29 +144,32 @@ pub fn get_country(ip: IpAddr) -> Result<Regions, Box<RitaExitError>> {
     // peer address for them will be an fe80 linklocal ip address. When we
     // detect this we go ahead and assign the user one of our allowed countries
     // and move on. In the common case where we have only one allowed country
-    // this will produce the correct result. We can affirm this will never panic
-    // because we just checked that allowed countries contains at least one value
-    // above
+    // this will produce the correct result. Without allowed countries we can't
+    // tell where the exit is and use unknown region
     if let IpAddr::V6(val) = ip {
         if is_unicast_link_local(&val) {
-            return Ok(*settings::get_rita_exit()
+            return Ok(rita_exit
                 .allowed_countries
                 .iter()
                 .next()
-                .unwrap());
+                .copied()
+                .unwrap_or(Regions::UnkownRegion));
         }
     }
 
     // on the other hand if there is a configured list of allowed countries
-    // but no configured api details, we panic
-    let api_user = settings::get_rita_exit()
-        .exit_network
-        .geoip_api_user
-        .expect("No api key configured!");
-    let api_key = settings::get_rita_exit()
-        .exit_network
-        .geoip_api_key
-        .expect("No api key configured!");
+    // or country prices but no configured api details, we can't look anything up
+    let (api_user, api_key) = match (
+        rita_exit.exit_network.geoip_api_user.clone(),
+        rita_exit.exit_network.geoip_api_key.clone(),
+    ) {
+        (Some(user), Some(key)) => (user, key),
+        _ => {
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "No geoip api credentials configured".to_string(),
+            )))
+        }
+    };
 
     // we have to turn this option into a string in order to avoid
     // the borrow checker trying to keep this lock open for a long period
@@ -9,7 +9,9 @@ use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use crate::RitaExitError;
+use rita_common::utils::json_file::{cached_json_file, save_json_file};
 
+use super::ClientInterfaceType;
 use super::RITA_EXIT_STATE;
 
 /// Wg exit port on client side
@@ -64,6 +66,101 @@ pub fn add_new_internal_ip_assignement(addr: IpAddr, key: WgKey) {
         .insert(addr, key);
 }
 
+/// The negotiated protocol versions, loaded from disk first if needed. Until a bad file is fixed they
+/// are neither used nor saved, saving would overwrite every other client's version
+fn with_protocol_versions<T>(
+    f: impl FnOnce(&mut HashMap<WgKey, u32>, &str) -> Option<T>,
+) -> Option<T> {
+    let path = settings::get_rita_exit()
+        .exit_network
+        .protocol_versions_file;
+    let mut state = RITA_EXIT_STATE.write().unwrap();
+    match cached_json_file(&mut state.protocol_versions, &path) {
+        Ok(versions) => f(versions, &path),
+        Err(e) => {
+            error!("Failed to load protocol versions {}", e);
+            None
+        }
+    }
+}
+
+/// Applies a change to the negotiated protocol versions. The change returns the version it replaced
+/// and the versions are written back if that differs from `new`, versions only change when a client
+/// updates so this is rare
+fn modify_protocol_versions(
+    new: Option<u32>,
+    change: impl FnOnce(&mut HashMap<WgKey, u32>) -> Option<u32>,
+) -> Option<u32> {
+    with_protocol_versions(|versions, path| {
+        let previous = change(versions);
+        if previous != new {
+            if let Err(e) = save_json_file(path, versions) {
+                error!("Failed to save protocol versions {}", e);
+            }
+        }
+        previous
+    })
+}
+
+/// Gets the exit protocol version negotiated with this client, None if the client
+/// has not negotiated one
+pub fn get_client_protocol_version(key: WgKey) -> Option<u32> {
+    if let Some(versions) = &RITA_EXIT_STATE.read().unwrap().protocol_versions {
+        return versions.get(&key).copied();
+    }
+    with_protocol_versions(|versions, _| versions.get(&key).copied())
+}
+
+/// Records the exit protocol version negotiated with this client, returns the version it replaces
+pub fn set_client_protocol_version(key: WgKey, version: u32) -> Option<u32> {
+    modify_protocol_versions(Some(version), |versions| versions.insert(key, version))
+}
+
+/// Forgets the version of a client that stopped negotiating, for example after a firmware downgrade,
+/// so that its interface is guessed from handshakes again
+pub fn clear_client_protocol_version(key: WgKey) -> Option<u32> {
+    modify_protocol_versions(None, |versions| versions.remove(&key))
+}
+
+/// Records the router version a client last reported, None if it did not report one
+pub fn set_client_version(key: WgKey, version: Option<String>) {
+    RITA_EXIT_STATE
+        .write()
+        .unwrap()
+        .client_versions
+        .insert(key, version);
+}
+
+/// The router version every client that checked in since startup last reported, forgetting
+/// clients that are no longer registered
+pub fn get_client_versions(registered: &[Identity]) -> HashMap<WgKey, Option<String>> {
+    let keys: HashSet<WgKey> = registered.iter().map(|c| c.wg_public_key).collect();
+    let mut state = RITA_EXIT_STATE.write().unwrap();
+    state.client_versions.retain(|key, _| keys.contains(key));
+    state.client_versions.clone()
+}
+
+/// Records that a client moved off an interface, setup_clients cleans up the peer it left there
+pub fn add_interface_migration(key: WgKey, left: ClientInterfaceType) {
+    RITA_EXIT_STATE
+        .write()
+        .unwrap()
+        .interface_migrations
+        .insert(key, left);
+}
+
+pub fn get_interface_migrations() -> HashMap<WgKey, ClientInterfaceType> {
+    RITA_EXIT_STATE.read().unwrap().interface_migrations.clone()
+}
+
+/// Removes a finished migration, unless the client has since moved again
+pub fn remove_interface_migration(key: WgKey, left: &ClientInterfaceType) {
+    let migrations = &mut RITA_EXIT_STATE.write().unwrap().interface_migrations;
+    if migrations.get(&key) == Some(left) {
+        migrations.remove(&key);
+    }
+}
+
 /// Take an index i, a larger subnet and a smaller subnet length and generate the ith smaller subnet in the larger subnet
 /// For instance, if our larger subnet is fd00::1330/120, smaller sub len is 124, and index is 1, our generated subnet would be fd00::1310/124
 pub fn generate_iterative_client_subnet(
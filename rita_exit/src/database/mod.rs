@@ -1,35 +1,61 @@
 //! This module contains all the tools and functions that integrate with the clients database
 //! for the exit, which is most exit logic in general. Keep in mind database connections are remote
 //! and therefore synchronous database requests are quite expensive (on the order of tens of milliseconds)
+use crate::client_overrides::{enforcement_overrides, region_check_override};
+use crate::database::client_store::{get_client_by_wgkey, register_client};
+use crate::database::geoip::get_country;
 use crate::database::geoip::get_gateway_ip_bulk;
 use crate::database::geoip::get_gateway_ip_single;
 use crate::database::geoip::verify_ip;
+use crate::database::in_memory_database::add_interface_migration;
+use crate::database::in_memory_database::clear_client_protocol_version;
 use crate::database::in_memory_database::display_hashset;
 use crate::database::in_memory_database::get_client_internal_ip;
 use crate::database::in_memory_database::get_client_ipv6;
+use crate::database::in_memory_database::get_client_protocol_version;
+use crate::database::in_memory_database::get_client_versions;
+use crate::database::in_memory_database::get_interface_migrations;
+use crate::database::in_memory_database::remove_interface_migration;
+use crate::database::in_memory_database::set_client_protocol_version;
+use crate::database::in_memory_database::set_client_version;
 use crate::database::in_memory_database::to_exit_client;
 use crate::database::in_memory_database::DEFAULT_CLIENT_SUBNET_SIZE;
+use crate::database::reconcile::{reconcile_peers, reconcile_routes, record_desired_state};
+use crate::denylist::check_denylist;
+use crate::enforcement::{check_backend_switch, shadow_tick, EnforcementState};
+use crate::isolation::{get_isolated_clients, isolation_configs, reconcile_isolation};
+use crate::maintenance::check_maintenance;
+use crate::migration::{check_migration, migration_action, migration_denial, migration_hint};
+use crate::pricing::{client_exit_info, record_client_country};
+use crate::response_cache::cached_exit_info;
 use crate::rita_loop::EXIT_INTERFACE;
 use crate::rita_loop::EXIT_LOOP_TIMEOUT;
 use crate::rita_loop::LEGACY_INTERFACE;
 use crate::IpAssignmentMap;
 use crate::RitaExitError;
+use althea_kernel_interface::wg_netlink::WgPeerInfo;
 use althea_kernel_interface::ExitClient;
 use althea_types::regions::Regions;
+use althea_types::unix_time::now_secs;
 use althea_types::Identity;
 use althea_types::WgKey;
+use althea_types::{client_version_below, ClientVersionStatus, ExitDenialCode};
+use althea_types::{negotiate_exit_protocol, EXIT_PROTOCOL_V1, EXIT_PROTOCOL_V2};
 use althea_types::{ExitClientDetails, ExitClientIdentity, ExitDetails, ExitState, ExitVerifMode};
+use althea_types::{SignupChallenge, MAX_SIGNUP_DIFFICULTY};
 use clarity::Address;
-use rita_client_registration::client_db::get_registered_client_using_wgkey;
+use ipnetwork::IpNetwork;
 use rita_client_registration::ExitSignupReturn;
 use rita_common::blockchain_oracle::calculate_close_thresh;
 use rita_common::debt_keeper::get_debts_list;
 use rita_common::debt_keeper::DebtAction;
 use rita_common::KI;
+use settings::exit::EnforcementBackend;
 use settings::get_rita_exit;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::net::IpAddr;
+use std::net::ToSocketAddrs;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::Duration;
@@ -37,13 +63,26 @@ use std::time::Instant;
 use std::time::SystemTime;
 use web30::client::Web3;
 
+pub mod client_store;
 pub mod geoip;
 pub mod in_memory_database;
+pub mod reconcile;
 
 #[derive(Clone, Debug, Default)]
 pub struct RitaExitState {
     ip_assignment_map: IpAssignmentMap,
     geoip_cache: HashMap<IpAddr, Regions>,
+    /// Exit protocol version negotiated with each client during setup or status requests, kept in
+    /// exit_network.protocol_versions_file so restarts don't fall back to guessing. None until loaded
+    protocol_versions: Option<HashMap<WgKey, u32>>,
+    /// Clients whose protocol version moved them to the other exit interface, mapped to the
+    /// interface they left, until their stale peer there has been removed
+    interface_migrations: HashMap<WgKey, ClientInterfaceType>,
+    /// Addresses each enforcement exempt hostname resolved to and when it was looked up, failed
+    /// lookups are kept as empty so a dead resolver only stalls the loop once per interval
+    exempt_hosts: HashMap<String, (Instant, Vec<IpAddr>)>,
+    /// Router version each client reported in its last signup or status request
+    client_versions: HashMap<WgKey, Option<String>>,
 }
 
 lazy_static! {
@@ -59,6 +98,14 @@ pub const ONE_DAY: i64 = 86400;
 /// Timeout when requesting client registration
 pub const CLIENT_REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// How long an enforcement exempt hostname lookup is used before the hostname is resolved again
+pub const EXEMPT_RESOLVE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Exit protocol versions this exit can serve, see ClientInterfaceType for how each maps
+/// onto our wireguard interfaces
+pub const EXIT_SUPPORTED_PROTOCOL_VERSIONS: [u32; 2] = [EXIT_PROTOCOL_V1, EXIT_PROTOCOL_V2];
+
+/// Builds the exit details handed to clients, responses should use response_cache::cached_exit_info()
 pub fn get_exit_info() -> ExitDetails {
     let exit_settings = get_rita_exit();
     ExitDetails {
@@ -69,6 +116,175 @@ pub fn get_exit_info() -> ExitDetails {
         netmask: exit_settings.exit_network.netmask,
         description: exit_settings.description,
         verif_mode: ExitVerifMode::Phone,
+        signup_challenge: match exit_settings.exit_network.signup_pow_difficulty {
+            0 => None,
+            difficulty => Some(SignupChallenge {
+                difficulty: difficulty.min(MAX_SIGNUP_DIFFICULTY),
+            }),
+        },
+    }
+}
+
+/// Outcome of checking a client version against the exit's minimum version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionGate {
+    /// The client may be served, with a deprecation notice if it is inside the grace period
+    Allowed(Option<ClientVersionStatus>),
+    /// The client is too old and the grace period is over
+    Denied(ExitState),
+}
+
+/// Compares the version a client reports against the configured minimum, clients below
+/// the minimum are served with a deprecation status until the deadline and denied after
+pub fn check_client_version(
+    version: Option<&str>,
+    minimum: Option<&str>,
+    deadline: Option<u64>,
+    now: SystemTime,
+) -> VersionGate {
+    let minimum = match minimum {
+        Some(m) => m,
+        None => return VersionGate::Allowed(None),
+    };
+    if !client_version_below(version, minimum) {
+        return VersionGate::Allowed(None);
+    }
+    let deadline = deadline.map(|d| SystemTime::UNIX_EPOCH + Duration::from_secs(d));
+    match deadline {
+        Some(deadline) if now < deadline => VersionGate::Allowed(Some(
+            ClientVersionStatus::Deprecated {
+                minimum_version: minimum.to_string(),
+                deadline: Some(deadline),
+            },
+        )),
+        _ => VersionGate::Denied(ExitState::Denied {
+            message: format!(
+                "Your router version {} is no longer supported by this exit, please update to {} or newer",
+                version.unwrap_or("unknown"),
+                minimum
+            ),
+            code: Some(ExitDenialCode::VersionTooOld),
+        }),
+    }
+}
+
+/// Checks a client against this exit's minimum version settings, the version is remembered so the
+/// exit loop can tear down the tunnel of a client once it is denied
+fn gate_client_version(client: &ExitClientIdentity) -> VersionGate {
+    set_client_version(client.global.wg_public_key, client.version.clone());
+    let exit_network = get_rita_exit().exit_network;
+    check_client_version(
+        client.version.as_deref(),
+        exit_network.min_client_version.as_deref(),
+        exit_network.min_client_version_deadline,
+        SystemTime::now(),
+    )
+}
+
+/// Registered clients whose last reported version is below the minimum with the grace period over,
+/// clients that have not checked in since startup are left alone until they do
+pub fn version_denied_clients(clients: &[Identity]) -> Vec<Identity> {
+    let exit_network = get_rita_exit().exit_network;
+    let versions = get_client_versions(clients);
+    if exit_network.min_client_version.is_none() {
+        return Vec::new();
+    }
+    let now = SystemTime::now();
+    clients
+        .iter()
+        .filter(|c| match versions.get(&c.wg_public_key) {
+            Some(version) => matches!(
+                check_client_version(
+                    version.as_deref(),
+                    exit_network.min_client_version.as_deref(),
+                    exit_network.min_client_version_deadline,
+                    now,
+                ),
+                VersionGate::Denied(_)
+            ),
+            None => false,
+        })
+        .copied()
+        .collect()
+}
+
+/// Picks an exit protocol version for this client from the ones it advertised and records it so
+/// that tunnel setup does not have to guess from handshakes. Clients that do not advertise any
+/// versions predate negotiation and get Ok(None), clients we share no version with get a denial
+fn negotiate_client_protocol(client: &ExitClientIdentity) -> Result<Option<u32>, ExitState> {
+    let key = client.global.wg_public_key;
+    if client.supported_protocol_versions.is_empty() {
+        let previous = clear_client_protocol_version(key);
+        if let Some(left) = interface_left(previous, None) {
+            info!(
+                "{} stopped negotiating an exit protocol version, moving it off {:?}",
+                key, left
+            );
+            add_interface_migration(key, left);
+        }
+        return Ok(None);
+    }
+    match negotiate_exit_protocol(
+        &client.supported_protocol_versions,
+        &EXIT_SUPPORTED_PROTOCOL_VERSIONS,
+    ) {
+        Some(version) => {
+            let previous = set_client_protocol_version(key, version);
+            if previous != Some(version) {
+                info!("Negotiated exit protocol version {} with {}", version, key);
+                if let Some(left) = interface_left(previous, Some(version)) {
+                    add_interface_migration(key, left);
+                }
+            }
+            Ok(Some(version))
+        }
+        None => Err(ExitState::Denied {
+            message: format!(
+                "This exit supports protocol versions {:?} but you support {:?}, please update",
+                EXIT_SUPPORTED_PROTOCOL_VERSIONS, client.supported_protocol_versions
+            ),
+            code: Some(ExitDenialCode::UnsupportedProtocol),
+        }),
+    }
+}
+
+/// The denial to send a client the operator has banned, if they have been. Nobody is served while
+/// the denylist can't be loaded
+fn denylist_denial(client: &ExitClientIdentity) -> Result<Option<ExitState>, Box<RitaExitError>> {
+    let denial = check_denylist(&client.global)?;
+    Ok(denial.map(|entry| ExitState::Denied {
+        message: format!("This exit has refused you service: {}", entry.reason),
+        code: Some(ExitDenialCode::Denylisted),
+    }))
+}
+
+/// The denial to send a client that didn't solve our signup challenge, if we have one
+fn signup_proof_denial(client: &ExitClientIdentity) -> Option<ExitState> {
+    let challenge = cached_exit_info().signup_challenge?;
+    match challenge.verify(
+        &client.global.wg_public_key,
+        client.signup_proof,
+        now_secs(),
+    ) {
+        Ok(()) => None,
+        Err(e) => Some(ExitState::Denied {
+            message: e.to_string(),
+            code: Some(ExitDenialCode::BadRequest),
+        }),
+    }
+}
+
+/// True if an operator override exempts this client from the region check, logged with its reason
+fn region_check_overridden(wg_key: &WgKey) -> bool {
+    match region_check_override(wg_key) {
+        Some(reason) => {
+            info!(
+                "Client {} is outside the allowed regions, allowed by override: {}",
+                wg_key, reason
+            );
+            true
+        }
+        None => false,
     }
 }
 
@@ -78,54 +294,108 @@ pub fn get_exit_info() -> ExitDetails {
 pub async fn signup_client(client: ExitClientIdentity) -> Result<ExitState, Box<RitaExitError>> {
     let exit_settings = get_rita_exit();
     info!("got setup request {:?}", client);
+    if let Some(state) = denylist_denial(&client)? {
+        info!(
+            "Denying signup for denylisted client {}",
+            client.global.wg_public_key
+        );
+        return Ok(state);
+    }
+    if let Some(state) = migration_denial(true) {
+        info!(
+            "Denying signup for {}, this exit is migrating its clients",
+            client.global.wg_public_key
+        );
+        return Ok(state);
+    }
+    if let Some(state) = signup_proof_denial(&client) {
+        info!(
+            "Denying signup for {} without a valid proof of work",
+            client.global.wg_public_key
+        );
+        return Ok(state);
+    }
+    let version_status = match gate_client_version(&client) {
+        VersionGate::Allowed(status) => status,
+        VersionGate::Denied(state) => {
+            info!(
+                "Denying signup for {} with version {:?}",
+                client.global.wg_public_key, client.version
+            );
+            return Ok(state);
+        }
+    };
+    let protocol_version = match negotiate_client_protocol(&client) {
+        Ok(version) => version,
+        Err(state) => return Ok(state),
+    };
     let gateway_ip = get_gateway_ip_single(client.global.mesh_ip)?;
     info!("got gateway ip {:?}", client);
 
     let verify_status = verify_ip(gateway_ip)?;
     info!("verified the ip country {:?}", client);
+    // a client whose country can't be found is billed exit_price until the region check finds it
+    if !exit_settings.exit_network.country_prices.is_empty() {
+        match get_country(gateway_ip) {
+            Ok(country) => record_client_country(client.global.wg_public_key, country),
+            Err(e) => warn!(
+                "Failed to get country of {} for pricing, using exit_price {:?}",
+                client.global.wg_public_key, e
+            ),
+        }
+    }
 
     // Is client requesting from a valid country? If so send registration request to ops
-    if !verify_status {
+    if !verify_status && !region_check_overridden(&client.global.wg_public_key) {
         return Ok(ExitState::Denied {
             message: format!(
                 "This exit only accepts connections from {}",
                 display_hashset(&exit_settings.allowed_countries),
             ),
+            code: Some(ExitDenialCode::RegionNotAllowed),
         });
     }
 
     // Forward request to ops and send result to client accordingly
     let exit_client = to_exit_client(client.global);
+    let client_key = client.global.wg_public_key;
     if let Ok(exit_client) = exit_client {
-        match forward_client_signup_request(client).await {
+        match register_client(client).await {
             ExitSignupReturn::RegistrationOk => Ok(ExitState::Registered {
                 our_details: ExitClientDetails {
                     client_internal_ip: exit_client.internal_ip,
                     internet_ipv6_subnet: exit_client.internet_ipv6,
                 },
-                general_details: get_exit_info(),
+                general_details: client_exit_info(&client_key),
                 message: "Registration OK".to_string(),
+                version_status,
+                protocol_version,
+                migration: None,
             }),
 
             ExitSignupReturn::PendingRegistration => Ok(ExitState::Pending {
-                general_details: get_exit_info(),
+                general_details: client_exit_info(&client_key),
                 message: "awaiting email verification".to_string(),
                 email_code: None,
                 phone_code: None,
+                version_status,
             }),
             ExitSignupReturn::BadPhoneNumber => Ok(ExitState::Denied {
                 message: format!(
                     "Error parsing client phone number {:?}",
                     exit_client.public_key,
                 ),
+                code: Some(ExitDenialCode::RegistrationFailed),
             }),
             ExitSignupReturn::InternalServerError { e } => Ok(ExitState::Denied {
                 message: format!("Internal Error from registration server {:?}", e,),
+                code: Some(ExitDenialCode::RegistrationFailed),
             }),
         }
     } else {
         Ok(ExitState::Denied {
             message: format!("Error parsing client details with {:?}", exit_client,),
+            code: Some(ExitDenialCode::BadRequest),
         })
     }
 }
@@ -187,8 +457,22 @@ pub async fn client_status(
     contact: &Web3,
 ) -> Result<ExitState, Box<RitaExitError>> {
     trace!("Checking if record exists for {:?}", client.global.mesh_ip);
+    if let Some(state) = denylist_denial(&client)? {
+        return Ok(state);
+    }
+    if let Some(state) = migration_denial(false) {
+        return Ok(state);
+    }
+    let version_status = match gate_client_version(&client) {
+        VersionGate::Allowed(status) => status,
+        VersionGate::Denied(state) => return Ok(state),
+    };
+    let protocol_version = match negotiate_client_protocol(&client) {
+        Ok(version) => version,
+        Err(state) => return Ok(state),
+    };
 
-    match get_registered_client_using_wgkey(
+    match get_client_by_wgkey(
         client.global.wg_public_key,
         our_address,
         contract_addr,
@@ -217,8 +501,11 @@ pub async fn client_status(
                     client_internal_ip: current_ip,
                     internet_ipv6_subnet: current_internet_ipv6,
                 },
-                general_details: get_exit_info(),
+                general_details: client_exit_info(&client.global.wg_public_key),
                 message: "Registration OK".to_string(),
+                version_status,
+                protocol_version,
+                migration: migration_hint(),
             })
         }
         Err(e) => {
@@ -230,7 +517,8 @@ pub async fn client_status(
 
 /// Every 5 seconds we validate all online clients to make sure that they are in the right region
 /// we also do this in the client status requests but we want to handle the edge case of a modified
-/// client that doesn't make status requests
+/// client that doesn't make status requests. With country pricing this also records each client's
+/// country for billing
 pub fn validate_clients_region(
     clients_list: Vec<Identity>,
 ) -> Result<Vec<Identity>, Box<RitaExitError>> {
@@ -247,10 +535,23 @@ pub fn validate_clients_region(
         ip_vec.push(item.mesh_ip);
     }
     let list = get_gateway_ip_bulk(ip_vec, EXIT_LOOP_TIMEOUT)?;
+    let country_pricing = !settings::get_rita_exit_snapshot()
+        .exit_network
+        .country_prices
+        .is_empty();
     for item in list.iter() {
+        if country_pricing {
+            match get_country(item.gateway_ip) {
+                Ok(country) => {
+                    record_client_country(client_map[&item.mesh_ip].wg_public_key, country)
+                }
+                Err(e) => warn!("Failed to get client country for pricing {:?}", e),
+            }
+        }
         let res = verify_ip(item.gateway_ip);
         match res {
             Ok(true) => trace!("{:?} is from an allowed ip", item),
+            Ok(false) if region_check_overridden(&client_map[&item.mesh_ip].wg_public_key) => {}
             Ok(false) => {
                 info!(
                     "Found unauthorized client already registered {}, removing",
@@ -273,48 +574,22 @@ pub fn validate_clients_region(
     Ok(blacklist)
 }
 
-#[derive(Default, Clone, Serialize, Deserialize, Debug)]
-pub struct ExitClientSetupStates {
-    // cache of clients from previous tick. Used to check if we need to
-    // rerun some setup code
-    pub old_clients: HashSet<ExitClient>,
-    // List of clients we see on wg_exit from previous tick. Used to check for new clients on the
-    // interface
-    pub wg_exit_clients: HashSet<WgKey>,
-    // List of clients on wg_exit_v2 from previous tick
-    pub wg_exit_v2_clients: HashSet<WgKey>,
-}
-
-#[derive(Default, Debug, Serialize, Deserialize)]
-pub struct CurrentExitClientState {
-    new_v2: HashSet<WgKey>,
-    new_v1: HashSet<WgKey>,
-    all_v2: HashSet<WgKey>,
-    all_v1: HashSet<WgKey>,
-}
-
-/// Gets a complete list of clients from the database and transforms that list
-/// into a single very long wg tunnel setup command which is then applied to the
-/// wg_exit tunnel (or created if it's the first run). This is the offically supported
-/// way to update live WireGuard tunnels and should not disrupt traffic
+/// Gets a complete list of clients from the database and reconciles the exit interfaces against it.
+/// The wg peers of wg_exit and wg_exit_v2 and the host routes of legacy clients are compared with what
+/// the kernel currently has and only the difference is applied, so a partial failure on one tick is
+/// picked up and repaired on the next without any cached state having to be correct
 pub fn setup_clients(
     clients_list: Vec<Identity>,
     geoip_blacklist: Vec<Identity>,
-    client_states: ExitClientSetupStates,
-) -> Result<ExitClientSetupStates, Box<RitaExitError>> {
-    let mut client_states = client_states;
+) -> Result<(), Box<RitaExitError>> {
     let start = Instant::now();
 
     // use hashset to ensure uniqueness and check for duplicate db entries
     let mut wg_clients = HashSet::new();
     let mut geoip_blacklist_map = HashSet::new();
-    let key_to_client_map: HashMap<WgKey, Identity> = HashMap::new();
+    let mut key_to_client_map: HashMap<WgKey, Identity> = HashMap::new();
 
-    trace!(
-        "got clients from db {:?} {:?}",
-        clients_list,
-        client_states.old_clients
-    );
+    trace!("got clients from db {:?}", clients_list);
 
     for c in clients_list.iter() {
         match to_exit_client(*c) {
@@ -322,6 +597,7 @@ pub fn setup_clients(
                 if !wg_clients.insert(a) {
                     error!("Duplicate database entry! {}", c.wg_public_key);
                 }
+                key_to_client_map.insert(c.wg_public_key, *c);
             }
             Err(e) => {
                 error!(
@@ -356,211 +632,228 @@ pub fn setup_clients(
         .copied()
         .collect();
 
-    // symetric difference is an iterator of all items in A but not in B
-    // or in B but not in A, in short if there's any difference between the two
-    // it must be nonzero, since all entires must be unique there can not be duplicates
-    if wg_clients
-        .symmetric_difference(&client_states.old_clients)
-        .count()
-        != 0
-    {
-        info!("Setting up configs for wg_exit and wg_exit_v2");
-        // setup all the tunnels
-        let exit_status = KI.set_exit_wg_config(
-            &wg_clients,
-            settings::get_rita_exit().exit_network.wg_tunnel_port,
-            &settings::get_rita_exit().exit_network.wg_private_key_path,
-            LEGACY_INTERFACE,
-        );
-
-        match exit_status {
-            Ok(_a) => {
-                trace!("Successfully setup Exit WG!");
-            }
-            Err(e) => warn!(
-                "Error in Exit WG setup {:?}, 
-                        this usually happens when a Rita service is 
-                        trying to auto restart in the background",
-                e
-            ),
+    let exit_settings = settings::get_rita_exit_snapshot();
+    let legacy_peers = reconcile_peers(
+        &wg_clients,
+        exit_settings.exit_network.wg_tunnel_port,
+        &exit_settings.exit_network.wg_private_key_path,
+        LEGACY_INTERFACE,
+    );
+    let exit_peers = reconcile_peers(
+        &wg_clients,
+        exit_settings.exit_network.wg_v2_tunnel_port,
+        &exit_settings.network.wg_private_key_path,
+        EXIT_INTERFACE,
+    );
+    let (legacy_peers, exit_peers) = match (legacy_peers, exit_peers) {
+        (Ok(legacy), Ok(exit)) => (legacy, exit),
+        (Err(e), _) | (_, Err(e)) => {
+            // without both peer lists we can't tell which interface each client is on, leave
+            // the routes alone until the next tick rather than tearing down working ones
+            warn!("Failed to reconcile exit wg peers, skipping routes {:?}", e);
+            return Err(e);
         }
+    };
 
-        // Setup new tunnels
-        let exit_status_new = KI.set_exit_wg_config(
-            &wg_clients,
-            settings::get_rita_exit().exit_network.wg_v2_tunnel_port,
-            &settings::get_rita_exit().network.wg_private_key_path,
-            EXIT_INTERFACE,
-        );
-
-        match exit_status_new {
-            Ok(()) => {
-                trace!("Successfully setup Exit wg_exit_v2!");
-            }
-            Err(e) => warn!(
-                "Error in Exit wg_exit_v2 setup {:?}, 
-                        this usually happens when a Rita service is 
-                        trying to auto restart in the background",
-                e
-            ),
-        }
-        info!(
-            "exit setup loop completed in {}s {}ms with {} clients and {} wg_clients",
-            start.elapsed().as_secs(),
-            start.elapsed().subsec_millis(),
-            clients_list.len(),
-            wg_clients.len(),
-        );
+    // Setup v4 routes for legacy clients. wg_exit_v2 carries a route for the whole client subnet,
+    // clients still on wg_exit get a /32 route that takes precedence over it. Only clients we
+    // have seen a handshake from can be placed on an interface
+    let handshakes = |peers: Vec<WgPeerInfo>| -> HashMap<WgKey, SystemTime> {
+        peers
+            .into_iter()
+            .filter_map(|p| p.last_handshake.map(|t| (p.public_key, t)))
+            .collect()
+    };
+    let mut legacy_handshakes = handshakes(legacy_peers);
+    let mut exit_handshakes = handshakes(exit_peers);
+    // a client that just switched interfaces still has a recent handshake on the one it left, that
+    // must not pull its route back there
+    let migrations = get_interface_migrations();
+    for (key, left) in migrations.iter() {
+        match left {
+            ClientInterfaceType::LegacyInterface => legacy_handshakes.remove(key),
+            ClientInterfaceType::ExitInterface => exit_handshakes.remove(key),
+        };
     }
-
-    // Setup ipv6 and v4 routes and rules for clients
-    // We optimise by setting up routes/rules only for those routers we see a latest handshake value with.
-    // 1.) Find handshakes on both interfaces
-    // 2.) From these timestamps, determine if client is wg exit v1 or v2
-    // 3.) Compare this to our datastore of previous clients we set up routes for
-    // 4.) Set up routes for v2 or v1 based on this
-    let new_wg_exit_clients_timestamps: HashMap<WgKey, SystemTime> = KI
-        .get_last_active_handshake_time(EXIT_INTERFACE)
-        .expect("There should be a new wg_exit interface")
-        .into_iter()
-        .collect();
-    let wg_exit_clients_timestamps: HashMap<WgKey, SystemTime> = KI
-        .get_last_active_handshake_time(LEGACY_INTERFACE)
-        .expect("There should be a wg_exit interface")
-        .into_iter()
+    let legacy_routes: HashSet<IpAddr> = wg_clients
+        .iter()
+        .filter(|c| {
+            exit_handshakes.contains_key(&c.public_key)
+                || legacy_handshakes.contains_key(&c.public_key)
+        })
+        .filter(|c| match key_to_client_map.get(&c.public_key) {
+            Some(id) => matches!(
+                get_client_interface(*id, &exit_handshakes, &legacy_handshakes),
+                Ok(ClientInterfaceType::LegacyInterface)
+            ),
+            None => false,
+        })
+        .map(|c| c.internal_ip)
         .collect();
+    record_desired_state(&wg_clients, &legacy_routes);
+    let res = reconcile_routes(
+        &legacy_routes,
+        exit_settings.exit_network.own_internal_ip.into(),
+        LEGACY_INTERFACE,
+    );
+    // the stale peer is only removed once the route has moved, so traffic is never routed to a
+    // peer that is gone
+    if res.is_ok() && !migrations.is_empty() {
+        finish_interface_migrations(&migrations, &wg_clients);
+    }
 
-    let client_list_for_setup: Vec<Identity> = key_to_client_map
-        .clone()
-        .into_iter()
-        .filter_map(|(k, v)| {
-            if new_wg_exit_clients_timestamps.contains_key(&k)
-                || wg_exit_clients_timestamps.contains_key(&k)
-            {
-                Some(v)
-            } else {
-                None
+    // isolated clients get a routing table of their own pointing at whichever interface they are on,
+    // if the list can't be loaded the isolation in place is left alone until it can
+    match get_isolated_clients() {
+        Ok(isolated) => {
+            let isolated_clients: Vec<(ExitClient, &str)> = wg_clients
+                .iter()
+                .filter(|c| isolated.iter().any(|i| i.wg_key == c.public_key))
+                .filter_map(|c| {
+                    let id = key_to_client_map.get(&c.public_key)?;
+                    let interface =
+                        match get_client_interface(*id, &exit_handshakes, &legacy_handshakes) {
+                            Ok(ClientInterfaceType::LegacyInterface) => LEGACY_INTERFACE,
+                            _ => EXIT_INTERFACE,
+                        };
+                    Some((*c, interface))
+                })
+                .collect();
+            match exit_settings.network.external_nic.as_deref() {
+                Some(external_nic) => reconcile_isolation(isolation_configs(
+                    &isolated,
+                    &isolated_clients,
+                    &[LEGACY_INTERFACE, EXIT_INTERFACE],
+                    external_nic,
+                )),
+                None if !isolated.is_empty() => {
+                    error!("Client isolation needs network.external_nic to be set")
+                }
+                None => {}
             }
-        })
-        .collect();
+        }
+        Err(e) => error!("Not updating client isolation {}", e),
+    }
 
-    let exit_settings = settings::get_rita_exit();
-    let internal_ip_v4 = exit_settings.exit_network.own_internal_ip;
-
-    // Get all new clients that need rule setup for wg_exit_v2 and wg_exit respectively
-    let changed_clients_return = find_changed_clients(
-        client_states.clone(),
-        new_wg_exit_clients_timestamps,
-        wg_exit_clients_timestamps,
-        client_list_for_setup,
+    info!(
+        "exit setup loop completed in {}s {}ms with {} clients, {} wg_clients and {} legacy routes",
+        start.elapsed().as_secs(),
+        start.elapsed().subsec_millis(),
+        clients_list.len(),
+        wg_clients.len(),
+        legacy_routes.len(),
     );
+    res
+}
 
-    // set previous tick states to current clients on wg interfaces
-    client_states.wg_exit_v2_clients = changed_clients_return.all_v2;
-    client_states.wg_exit_clients = changed_clients_return.all_v1;
-
-    // setup wg_exit routes (downgrade from b20 -> 19 and new b19 routers)
-    // note these are spot routes for routers still on beta19 by default
-    // all traffic will go over wg_exit_v2
-    for c_key in changed_clients_return.new_v1 {
-        if let Some(c) = key_to_client_map.get(&c_key) {
-            KI.setup_individual_client_routes(
-                match get_client_internal_ip(
-                    *c,
-                    get_rita_exit().exit_network.netmask,
-                    get_rita_exit().exit_network.own_internal_ip,
-                ) {
-                    Ok(a) => a,
-                    Err(e) => {
-                        error!(
-                            "Received error while trying to retrieve client internal ip {}",
-                            e
-                        );
-                        continue;
-                    }
-                },
-                internal_ip_v4.into(),
-                LEGACY_INTERFACE,
-            );
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ClientInterfaceType {
+    LegacyInterface,
+    ExitInterface,
+}
+
+impl ClientInterfaceType {
+    /// The interface a client tunnels over for a negotiated exit protocol version, new protocol
+    /// versions that change the wire format should be added here rather than in setup_clients
+    pub fn from_protocol_version(version: u32) -> Option<ClientInterfaceType> {
+        match version {
+            EXIT_PROTOCOL_V1 => Some(ClientInterfaceType::LegacyInterface),
+            EXIT_PROTOCOL_V2 => Some(ClientInterfaceType::ExitInterface),
+            _ => None,
         }
     }
-    for c_key in changed_clients_return.new_v2 {
-        if let Some(c) = key_to_client_map.get(&c_key) {
-            KI.teardown_individual_client_routes(
-                match get_client_internal_ip(
-                    *c,
-                    get_rita_exit().exit_network.netmask,
-                    get_rita_exit().exit_network.own_internal_ip,
-                ) {
-                    Ok(a) => a,
-                    Err(e) => {
-                        error!(
-                            "Received error while trying to retrieve client internal ip {}",
-                            e
-                        );
-                        continue;
-                    }
-                },
-            );
+
+    pub fn other(&self) -> ClientInterfaceType {
+        match self {
+            ClientInterfaceType::LegacyInterface => ClientInterfaceType::ExitInterface,
+            ClientInterfaceType::ExitInterface => ClientInterfaceType::LegacyInterface,
         }
     }
+}
 
-    Ok(client_states)
+/// The interface a client moved off when its negotiated protocol version changed from previous to
+/// current, None if it stayed put or there is no way to tell. A client that stops negotiating is assumed
+/// to have left the interface of its old version
+pub fn interface_left(previous: Option<u32>, current: Option<u32>) -> Option<ClientInterfaceType> {
+    let previous = previous.and_then(ClientInterfaceType::from_protocol_version);
+    match current.and_then(ClientInterfaceType::from_protocol_version) {
+        Some(current) if previous.as_ref() == Some(&current) => None,
+        Some(current) => Some(current.other()),
+        None => previous,
+    }
 }
 
-/// Find all clients that underwent transition from b19 -> 20 or vice versa and need updated rules and routes
-/// This function returns (v2_clients to setup, v1_clients to setup, all_v2 clients, all_v1 clients)
-fn find_changed_clients(
-    client_states: ExitClientSetupStates,
-    all_v2: HashMap<WgKey, SystemTime>,
-    all_v1: HashMap<WgKey, SystemTime>,
-    clients_list: Vec<Identity>,
-) -> CurrentExitClientState {
-    let mut v1_clients = HashSet::new();
-
-    let mut v2_clients = HashSet::new();
-
-    // Look at handshakes of each client to determine if they are a V1 or V2 client
-    for c in clients_list {
-        match get_client_interface(c, all_v2.clone(), all_v1.clone()) {
-            Ok(interface) => {
-                if interface == ClientInterfaceType::LegacyInterface {
-                    v1_clients.insert(c.wg_public_key);
-                } else if interface == ClientInterfaceType::ExitInterface {
-                    v2_clients.insert(c.wg_public_key);
+/// Removes the peers clients left behind on the interface they switched away from, the next reconcile
+/// adds them back without the old session and handshake. A client's internal ip is derived from its key
+/// so it is the same on both interfaces, only its route and its peer move
+fn finish_interface_migrations(
+    migrations: &HashMap<WgKey, ClientInterfaceType>,
+    wg_clients: &HashSet<ExitClient>,
+) {
+    let exit_settings = settings::get_rita_exit();
+    let current: HashSet<WgKey> = wg_clients.iter().map(|c| c.public_key).collect();
+    for left in [
+        ClientInterfaceType::LegacyInterface,
+        ClientInterfaceType::ExitInterface,
+    ] {
+        let keys: Vec<WgKey> = migrations
+            .iter()
+            .filter(|(key, l)| **l == left && current.contains(key))
+            .map(|(key, _)| *key)
+            .collect();
+        if keys.is_empty() {
+            continue;
+        }
+        let (listen_port, private_key_path, interface) = match left {
+            ClientInterfaceType::LegacyInterface => (
+                exit_settings.exit_network.wg_tunnel_port,
+                &exit_settings.exit_network.wg_private_key_path,
+                LEGACY_INTERFACE,
+            ),
+            ClientInterfaceType::ExitInterface => (
+                exit_settings.exit_network.wg_v2_tunnel_port,
+                &exit_settings.network.wg_private_key_path,
+                EXIT_INTERFACE,
+            ),
+        };
+        match KI.update_exit_wg_peers_netlink(&[], &keys, listen_port, private_key_path, interface)
+        {
+            Ok(()) => {
+                info!(
+                    "Removed {} peers left on {} by clients that switched interfaces",
+                    keys.len(),
+                    interface
+                );
+                for key in keys {
+                    remove_interface_migration(key, &left);
                 }
             }
-            Err(_) => {
-                // There is no handshake on either wg_exit or wg_exit_v2, which can happen during a restart
-                // in this case the client will not have an ipv6 route until they initiate a handshake again
-                continue;
-            }
-        };
+            Err(e) => warn!(
+                "Failed to remove peers left on {}, retrying next tick {:?}",
+                interface, e
+            ),
+        }
     }
-
-    // All new client (that need rules setup) are Set{clients on wg interface} - Set{clients from previous tick}
-    let new_v2 = &v2_clients - &client_states.wg_exit_v2_clients;
-    let new_v1 = &v1_clients - &client_states.wg_exit_clients;
-
-    CurrentExitClientState {
-        new_v2,
-        new_v1,
-        all_v2: v2_clients,
-        all_v1: v1_clients,
+    // clients we no longer serve have no peers left to clean up
+    for (key, left) in migrations.iter() {
+        if !current.contains(key) {
+            remove_interface_migration(*key, left);
+        }
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
-pub enum ClientInterfaceType {
-    LegacyInterface,
-    ExitInterface,
-}
-
 pub fn get_client_interface(
     c: Identity,
-    new_wg_exit_clients: HashMap<WgKey, SystemTime>,
-    wg_exit_clients: HashMap<WgKey, SystemTime>,
+    new_wg_exit_clients: &HashMap<WgKey, SystemTime>,
+    wg_exit_clients: &HashMap<WgKey, SystemTime>,
 ) -> Result<ClientInterfaceType, Box<RitaExitError>> {
+    // clients that negotiated a protocol version tell us which interface they use, only older
+    // clients need to be guessed from their handshakes
+    if let Some(interface) = get_client_protocol_version(c.wg_public_key)
+        .and_then(ClientInterfaceType::from_protocol_version)
+    {
+        return Ok(interface);
+    }
     trace!(
         "New list is {:?} \n Old list is {:?}",
         new_wg_exit_clients,
@@ -594,14 +887,34 @@ pub fn get_client_interface(
 /// setting the htb class they are assigned to to a maximum speed of the free tier value.
 /// Unlike intermediary enforcement we do not need to subdivide the free tier to prevent
 /// ourselves from exceeding the upstream free tier. As an exit we are the upstream.
+/// Clients in the throttle stage are limited the same way, to the faster throttle_throughput.
+/// While the exit is in maintenance mode every client is treated as paid up, debts still accrue, the
+/// same goes for clients with an operator override that skips enforcement. While the exit is migrating
+/// its clients to another exit their enforcement is tightened as the deadline nears, see crate::migration.
 pub fn enforce_exit_clients(
     clients_list: Vec<Identity>,
     old_debt_actions: &HashSet<(Identity, DebtAction)>,
+    exemptions: &HashSet<IpNetwork>,
 ) -> Result<HashSet<(Identity, DebtAction)>, Box<RitaExitError>> {
     let start = Instant::now();
     let mut clients_by_id = HashMap::new();
-    let free_tier_limit = settings::get_rita_exit().payment.free_tier_throughput;
+    let rita_exit = settings::get_rita_exit_snapshot();
+    let payment = &rita_exit.payment;
+    let free_tier_limit = payment.free_tier_throughput;
+    let throttle_limit = payment.throttle_throughput;
     let close_threshold = calculate_close_thresh();
+    let paused = check_maintenance();
+    let migration = check_migration();
+    let overrides = enforcement_overrides();
+    let exempt = |id: &Identity| overrides.iter().find(|o| o.wg_key == id.wg_public_key);
+    let effective_action = |id: &Identity, action: &DebtAction| {
+        let action = if paused || exempt(id).is_some() {
+            DebtAction::OpenTunnel
+        } else {
+            action.clone()
+        };
+        migration_action(migration, action)
+    };
     for client_id in clients_list.iter() {
         if let Ok(exit_client) = to_exit_client(*client_id) {
             clients_by_id.insert(client_id, exit_client);
@@ -617,17 +930,73 @@ pub fn enforce_exit_clients(
     // build the new debt actions list and see if we need to do anything
     let mut new_debt_actions = HashSet::new();
     for debt_entry in list.iter() {
+        if debt_entry.payment_details.action != DebtAction::OpenTunnel {
+            if let Some(o) = exempt(&debt_entry.identity) {
+                info!(
+                    "Not enforcing {:?} on {}, exempted by override: {}",
+                    debt_entry.payment_details.action, debt_entry.identity.wg_public_key, o.reason
+                );
+            }
+        }
         new_debt_actions.insert((
             debt_entry.identity,
-            debt_entry.payment_details.action.clone(),
+            effective_action(&debt_entry.identity, &debt_entry.payment_details.action),
         ));
     }
-    if new_debt_actions
-        .symmetric_difference(old_debt_actions)
-        .count()
-        == 0
+
+    // the limits every backend is asked to program, also handed to the shadow backend
+    let mut plan = EnforcementState::new();
+    let mut plan_ipv6 = HashMap::new();
+    let mut client_ips = Vec::new();
+    for debt_entry in list.iter() {
+        if let Some(IpAddr::V4(ip)) = clients_by_id
+            .get(&debt_entry.identity)
+            .map(|c| c.internal_ip)
+        {
+            client_ips.push(ip);
+            let limit =
+                match effective_action(&debt_entry.identity, &debt_entry.payment_details.action) {
+                    DebtAction::SuspendTunnel => free_tier_limit,
+                    DebtAction::ThrottleTunnel => throttle_limit,
+                    _ => continue,
+                };
+            plan.insert(ip, limit);
+            if let Ok(Some(client_ipv6)) = get_client_ipv6(
+                debt_entry.identity,
+                rita_exit.exit_network.subnet,
+                rita_exit
+                    .get_client_subnet_size()
+                    .unwrap_or(DEFAULT_CLIENT_SUBNET_SIZE),
+            ) {
+                plan_ipv6.insert(ip, client_ipv6);
+            }
+        }
+    }
+    let backend = rita_exit.exit_network.enforcement_backend;
+    // a new backend starts out with nothing programmed so it has to be run even without changes
+    let switched = check_backend_switch(backend, &client_ips);
+
+    if !switched
+        && new_debt_actions
+            .symmetric_difference(old_debt_actions)
+            .count()
+            == 0
     {
         info!("No change in enforcement list found, skipping tc calls");
+        shadow_tick(&plan, &client_ips);
+        return Ok(new_debt_actions);
+    }
+
+    if backend == EnforcementBackend::Nftables {
+        KI.set_nft_enforcement(&plan, &plan_ipv6, exemptions)
+            .map_err(RitaExitError::from)?;
+        info!(
+            "Exit nftables enforcement of {} clients completed in {}s {}ms",
+            plan.len(),
+            start.elapsed().as_secs(),
+            start.elapsed().subsec_millis(),
+        );
+        shadow_tick(&plan, &client_ips);
         return Ok(new_debt_actions);
     }
 
@@ -636,8 +1005,21 @@ pub fn enforce_exit_clients(
             Some(client) => {
                 match client.internal_ip {
                     IpAddr::V4(ip) => {
-                        if debt_entry.payment_details.action == DebtAction::SuspendTunnel {
-                            info!("Exit is enforcing on {} because their debt of {} is greater than the limit of {}", client.public_key, debt_entry.payment_details.debt, close_threshold);
+                        let limit = match effective_action(
+                            &debt_entry.identity,
+                            &debt_entry.payment_details.action,
+                        ) {
+                            DebtAction::SuspendTunnel => {
+                                info!("Exit is enforcing on {} because their debt of {} is greater than the limit of {}", client.public_key, debt_entry.payment_details.debt, close_threshold);
+                                Some(free_tier_limit)
+                            }
+                            DebtAction::ThrottleTunnel => {
+                                info!("Exit is throttling {} because their debt of {} is nearing the limit of {}", client.public_key, debt_entry.payment_details.debt, close_threshold);
+                                Some(throttle_limit)
+                            }
+                            _ => None,
+                        };
+                        if let Some(limit) = limit {
                             // setup flows this allows us to classify traffic we then limit the class, we delete the class as part of unenforcment but it's difficult to delete the flows
                             // so a user who has been enforced and unenforced while the exit has been online may already have them setup
                             let flow_setup_required = match (
@@ -670,8 +1052,8 @@ pub fn enforce_exit_clients(
                                 // gets the client ipv6 flow for this exit specifically
                                 let client_ipv6 = get_client_ipv6(
                                     debt_entry.identity,
-                                    settings::get_rita_exit().exit_network.subnet,
-                                    settings::get_rita_exit()
+                                    rita_exit.exit_network.subnet,
+                                    rita_exit
                                         .get_client_subnet_size()
                                         .unwrap_or(DEFAULT_CLIENT_SUBNET_SIZE),
                                 );
@@ -688,20 +1070,10 @@ pub fn enforce_exit_clients(
                                 )
                             }
 
-                            if let Err(e) = KI.set_class_limit(
-                                LEGACY_INTERFACE,
-                                free_tier_limit,
-                                free_tier_limit,
-                                ip,
-                            ) {
+                            if let Err(e) = KI.set_class_limit(LEGACY_INTERFACE, limit, limit, ip) {
                                 error!("Unable to setup enforcement class on wg_exit: {:?}", e);
                             }
-                            if let Err(e) = KI.set_class_limit(
-                                EXIT_INTERFACE,
-                                free_tier_limit,
-                                free_tier_limit,
-                                ip,
-                            ) {
+                            if let Err(e) = KI.set_class_limit(EXIT_INTERFACE, limit, limit, ip) {
                                 error!("Unable to setup enforcement class on wg_exit_v2: {:?}", e);
                             }
                         } else {
@@ -752,5 +1124,209 @@ pub fn enforce_exit_clients(
         start.elapsed().as_secs(),
         start.elapsed().subsec_millis(),
     );
+    shadow_tick(&plan, &client_ips);
     Ok(new_debt_actions)
 }
+
+/// Turns the configured list of enforcement exempt destinations into a set of ip prefixes, entries
+/// that are not valid prefixes are treated as hostnames and handed to `resolve`. Hostnames that
+/// resolve to nothing are skipped so one bad hostname does not remove the others
+pub fn resolve_exempt_destinations(
+    destinations: &[String],
+    resolve: impl Fn(&str) -> Vec<IpAddr>,
+) -> HashSet<IpNetwork> {
+    let mut ret = HashSet::new();
+    for dest in destinations {
+        match dest.parse::<IpNetwork>() {
+            Ok(prefix) => {
+                ret.insert(prefix);
+            }
+            Err(_) => ret.extend(resolve(dest).into_iter().map(IpNetwork::from)),
+        }
+    }
+    ret
+}
+
+/// Resolves an enforcement exempt hostname, the lookup blocks so results are reused for
+/// EXEMPT_RESOLVE_INTERVAL rather than resolved again every loop tick
+fn cached_exempt_lookup(host: &str) -> Vec<IpAddr> {
+    if let Some((resolved_at, addrs)) = RITA_EXIT_STATE.read().unwrap().exempt_hosts.get(host) {
+        if resolved_at.elapsed() < EXEMPT_RESOLVE_INTERVAL {
+            return addrs.clone();
+        }
+    }
+    let addrs: Vec<IpAddr> = match (host, 0).to_socket_addrs() {
+        Ok(addrs) => addrs.map(|a| a.ip()).collect(),
+        Err(e) => {
+            warn!("Failed to resolve enforcement exempt destination {host} {e:?}");
+            Vec::new()
+        }
+    };
+    RITA_EXIT_STATE
+        .write()
+        .unwrap()
+        .exempt_hosts
+        .insert(host.to_string(), (Instant::now(), addrs.clone()));
+    addrs
+}
+
+/// Programs the enforcement exempt destinations onto both exit interfaces if they have changed since the
+/// last time they where applied, returns the set that is currently programmed
+pub fn update_enforcement_exemptions(
+    old_exemptions: &HashSet<IpNetwork>,
+) -> Result<HashSet<IpNetwork>, Box<RitaExitError>> {
+    let exit_settings = get_rita_exit();
+    if !exit_settings.exit_network.enable_enforcement {
+        return Ok(old_exemptions.clone());
+    }
+    let destinations = exit_settings.exit_network.enforcement_exempt_destinations;
+    RITA_EXIT_STATE
+        .write()
+        .unwrap()
+        .exempt_hosts
+        .retain(|host, _| destinations.contains(host));
+    let exemptions = resolve_exempt_destinations(&destinations, cached_exempt_lookup);
+    if exemptions == *old_exemptions {
+        return Ok(exemptions);
+    }
+
+    info!(
+        "Updating enforcement exempt destinations to {:?}",
+        exemptions
+    );
+    KI.set_enforcement_exemptions(LEGACY_INTERFACE, &exemptions)
+        .map_err(RitaExitError::from)?;
+    KI.set_enforcement_exemptions(EXIT_INTERFACE, &exemptions)
+        .map_err(RitaExitError::from)?;
+    Ok(exemptions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_exempt_destinations() {
+        let destinations = vec![
+            "1.1.1.0/24".to_string(),
+            "fd00::1/128".to_string(),
+            "payments.example.com".to_string(),
+            "not a valid host name".to_string(),
+        ];
+        let resolve = |host: &str| match host {
+            "payments.example.com" => {
+                vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()]
+            }
+            _ => Vec::new(),
+        };
+        let resolved = resolve_exempt_destinations(&destinations, resolve);
+        assert_eq!(resolved.len(), 4);
+        assert!(resolved.contains(&"1.1.1.0/24".parse().unwrap()));
+        assert!(resolved.contains(&"fd00::1/128".parse().unwrap()));
+        assert!(resolved.contains(&"192.0.2.1/32".parse().unwrap()));
+        assert!(resolved.contains(&"2001:db8::1/128".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_negotiated_client_interface() {
+        let id: Identity = Identity {
+            mesh_ip: "fd00::1337".parse().unwrap(),
+            eth_address: "0x0101010101010101010101010101010101010101"
+                .parse()
+                .unwrap(),
+            wg_public_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            nickname: None,
+        };
+        let path = std::env::temp_dir().join("rita-exit-test-protocol-versions.json");
+        let _ = std::fs::remove_file(&path);
+        let mut rita_exit = settings::exit::RitaExitSettingsStruct::test_default();
+        rita_exit.exit_network.protocol_versions_file = path.to_string_lossy().to_string();
+        settings::set_rita_exit(rita_exit);
+
+        let mut v1_handshake = HashMap::new();
+        v1_handshake.insert(id.wg_public_key, SystemTime::now());
+        // without a negotiated version we guess from the handshake
+        assert_eq!(
+            get_client_interface(id, &HashMap::new(), &v1_handshake).unwrap(),
+            ClientInterfaceType::LegacyInterface
+        );
+        set_client_protocol_version(id.wg_public_key, EXIT_PROTOCOL_V2);
+        assert_eq!(
+            get_client_interface(id, &HashMap::new(), &v1_handshake).unwrap(),
+            ClientInterfaceType::ExitInterface
+        );
+        // the negotiated version survives a restart
+        RITA_EXIT_STATE.write().unwrap().protocol_versions = None;
+        assert_eq!(
+            get_client_protocol_version(id.wg_public_key),
+            Some(EXIT_PROTOCOL_V2)
+        );
+        // a corrupt file is neither used nor written over
+        RITA_EXIT_STATE.write().unwrap().protocol_versions = None;
+        std::fs::write(&path, b"{").unwrap();
+        assert_eq!(get_client_protocol_version(id.wg_public_key), None);
+        set_client_protocol_version(id.wg_public_key, EXIT_PROTOCOL_V2);
+        assert_eq!(std::fs::read(&path).unwrap(), b"{");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_interface_left() {
+        use ClientInterfaceType::*;
+        assert_eq!(interface_left(None, None), None);
+        assert_eq!(
+            interface_left(Some(EXIT_PROTOCOL_V1), Some(EXIT_PROTOCOL_V1)),
+            None
+        );
+        // upgrading moves the client onto wg_exit_v2
+        assert_eq!(
+            interface_left(Some(EXIT_PROTOCOL_V1), Some(EXIT_PROTOCOL_V2)),
+            Some(LegacyInterface)
+        );
+        assert_eq!(
+            interface_left(None, Some(EXIT_PROTOCOL_V2)),
+            Some(LegacyInterface)
+        );
+        // downgrading to firmware that doesn't negotiate leaves the interface of the old version
+        assert_eq!(
+            interface_left(Some(EXIT_PROTOCOL_V2), None),
+            Some(ExitInterface)
+        );
+        assert_eq!(
+            interface_left(Some(EXIT_PROTOCOL_V2), Some(EXIT_PROTOCOL_V1)),
+            Some(ExitInterface)
+        );
+    }
+
+    #[test]
+    fn test_check_client_version() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(
+            check_client_version(Some("0.1.0"), None, None, now),
+            VersionGate::Allowed(None)
+        );
+        assert_eq!(
+            check_client_version(Some("0.22.0"), Some("0.22.0"), None, now),
+            VersionGate::Allowed(None)
+        );
+        assert_eq!(
+            check_client_version(Some("0.21.5"), Some("0.22.0"), Some(2000), now),
+            VersionGate::Allowed(Some(ClientVersionStatus::Deprecated {
+                minimum_version: "0.22.0".to_string(),
+                deadline: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(2000)),
+            }))
+        );
+        match check_client_version(Some("0.21.5"), Some("0.22.0"), Some(500), now) {
+            VersionGate::Denied(ExitState::Denied { code, .. }) => {
+                assert_eq!(code, Some(ExitDenialCode::VersionTooOld))
+            }
+            v => panic!("Expected denial, got {v:?}"),
+        }
+        assert!(matches!(
+            check_client_version(None, Some("0.22.0"), None, now),
+            VersionGate::Denied(_)
+        ));
+    }
+}
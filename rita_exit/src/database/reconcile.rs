@@ -0,0 +1,491 @@
+//! Exit client setup as desired state reconciliation. Every tick the state the exit should have, the wg
+//! peers of both exit interfaces and a host route for each legacy client, is computed from the client list
+//! and compared against what the kernel actually has. Only the difference is applied, so a command that
+//! fails part way through is retried next tick rather than leaving a cache that no longer matches the kernel.
+//! The desired state of the last tick is kept so that a single client can be audited against the kernel
+//! from the admin api.
+
+use crate::client_overrides::{check_client_override, ClientOverride};
+use crate::heartbeat::get_registered_client;
+use crate::isolation::get_isolated_clients;
+use crate::rita_loop::{EXIT_INTERFACE, LEGACY_INTERFACE};
+use crate::RitaExitError;
+use althea_kernel_interface::wg_netlink::WgPeerInfo;
+use althea_kernel_interface::ExitClient;
+use althea_types::WgKey;
+use ipnetwork::IpNetwork;
+use rita_common::KI;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
+
+lazy_static! {
+    /// The desired state setup_clients last reconciled towards, None until the first tick
+    static ref DESIRED_STATE: Arc<RwLock<Option<DesiredState>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Debug, Default, Clone)]
+struct DesiredState {
+    /// Clients that should be peers on both exit interfaces
+    clients: HashMap<WgKey, ExitClient>,
+    /// Internal ips that should have a host route on the legacy interface
+    legacy_routes: HashSet<IpAddr>,
+}
+
+/// Peers to add or update and peers to remove to bring an interface to the desired state
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PeerDiff {
+    pub upsert: Vec<ExitClient>,
+    pub remove: Vec<WgKey>,
+}
+
+impl PeerDiff {
+    pub fn is_empty(&self) -> bool {
+        self.upsert.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// Host routes to add and remove to bring an interface to the desired state
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RouteDiff {
+    pub add: Vec<IpAddr>,
+    pub remove: Vec<IpAddr>,
+}
+
+impl RouteDiff {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// The kernel stores allowed ips with the host bits masked off, so compare them that way
+fn normalize(net: IpNetwork) -> IpNetwork {
+    IpNetwork::new(net.network(), net.prefix()).unwrap_or(net)
+}
+
+fn client_allowed_ips(c: &ExitClient) -> HashSet<IpNetwork> {
+    let mut ips = HashSet::new();
+    ips.insert(IpNetwork::from(c.internal_ip));
+    if let Some(ipv6) = c.internet_ipv6 {
+        ips.insert(normalize(ipv6));
+    }
+    ips
+}
+
+/// If the peer on the interface is configured the way we would configure this client
+fn peer_matches(c: &ExitClient, peer: &WgPeerInfo) -> bool {
+    // the kernel reports the endpoint as a full sockaddr, only the address and port are ours to compare
+    let endpoint_matches = peer
+        .endpoint
+        .map(|e| e.ip() == c.mesh_ip && e.port() == c.port)
+        .unwrap_or(false);
+    let allowed_ips: HashSet<IpNetwork> = peer.allowed_ips.iter().copied().map(normalize).collect();
+    endpoint_matches && allowed_ips == client_allowed_ips(c)
+}
+
+/// Compares the clients an interface should have with the peers it actually has
+pub fn diff_peers(desired: &HashSet<ExitClient>, actual: &[WgPeerInfo]) -> PeerDiff {
+    let actual: HashMap<WgKey, &WgPeerInfo> = actual.iter().map(|p| (p.public_key, p)).collect();
+    let desired_keys: HashSet<WgKey> = desired.iter().map(|c| c.public_key).collect();
+    let mut upsert: Vec<ExitClient> = desired
+        .iter()
+        .filter(|c| match actual.get(&c.public_key) {
+            Some(peer) => !peer_matches(c, peer),
+            None => true,
+        })
+        .copied()
+        .collect();
+    let mut remove: Vec<WgKey> = actual
+        .keys()
+        .filter(|k| !desired_keys.contains(k))
+        .copied()
+        .collect();
+    // stable ordering keeps the logs and netlink messages readable
+    upsert.sort_by_key(|c| c.public_key.to_string());
+    remove.sort_by_key(|k| k.to_string());
+    PeerDiff { upsert, remove }
+}
+
+pub fn diff_routes(desired: &HashSet<IpAddr>, actual: &HashSet<IpAddr>) -> RouteDiff {
+    let mut add: Vec<IpAddr> = desired.difference(actual).copied().collect();
+    let mut remove: Vec<IpAddr> = actual.difference(desired).copied().collect();
+    add.sort();
+    remove.sort();
+    RouteDiff { add, remove }
+}
+
+/// One exit interface as the kernel has it for a client, next to what the client list says it should have
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAudit {
+    pub interface: String,
+    pub expected_allowed_ips: Vec<IpNetwork>,
+    /// None if the client is not a peer on this interface
+    pub actual_allowed_ips: Option<Vec<IpNetwork>>,
+    pub expected_endpoint: Option<SocketAddr>,
+    pub actual_endpoint: Option<SocketAddr>,
+    /// Unix time in seconds of the last handshake on this interface
+    pub last_handshake: Option<u64>,
+    /// Whether the client's internal ip should have a host route on this interface
+    pub expected_route: bool,
+    pub actual_route: bool,
+    pub mismatches: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientAudit {
+    pub wg_key: WgKey,
+    pub registered: bool,
+    /// Whether the last exit loop tick set this client up, registered clients may be served by another
+    /// exit in the cluster or blacklisted
+    pub served: bool,
+    pub interfaces: Vec<InterfaceAudit>,
+    /// The routing table of an isolated client
+    pub isolation_table: Option<u32>,
+    /// The isolation rules installed for the client's addresses
+    pub isolation_rules: Vec<String>,
+    /// Every mismatch on any interface or in the isolation rules, empty if the kernel is in sync
+    pub mismatches: Vec<String>,
+    /// The operator override in effect for this client, if any
+    pub client_override: Option<ClientOverride>,
+}
+
+fn sorted(ips: impl IntoIterator<Item = IpNetwork>) -> Vec<IpNetwork> {
+    let mut ips: Vec<IpNetwork> = ips.into_iter().collect();
+    ips.sort();
+    ips
+}
+
+/// Compares one interface's peer and host route for a client with what should be there, expected is None
+/// if the client should not be on the interface at all
+pub fn audit_interface(
+    interface: &str,
+    expected: Option<&ExitClient>,
+    peer: Option<&WgPeerInfo>,
+    expected_route: bool,
+    actual_route: bool,
+) -> InterfaceAudit {
+    let expected_allowed_ips = expected.map(client_allowed_ips).unwrap_or_default();
+    let actual_allowed_ips: Option<HashSet<IpNetwork>> =
+        peer.map(|p| p.allowed_ips.iter().copied().map(normalize).collect());
+    let expected_endpoint = expected.map(|c| SocketAddr::new(c.mesh_ip, c.port));
+    let actual_endpoint = peer.and_then(|p| p.endpoint);
+
+    let mut mismatches = Vec::new();
+    match (expected, &actual_allowed_ips) {
+        (Some(_), None) => mismatches.push(format!("{interface}: peer is missing")),
+        (None, Some(_)) => mismatches.push(format!("{interface}: peer should not be present")),
+        (Some(_), Some(actual)) => {
+            for ip in sorted(expected_allowed_ips.difference(actual).copied()) {
+                mismatches.push(format!("{interface}: allowed ip {ip} is missing"));
+            }
+            for ip in sorted(actual.difference(&expected_allowed_ips).copied()) {
+                mismatches.push(format!("{interface}: unexpected allowed ip {ip}"));
+            }
+            let endpoint_matches = actual_endpoint
+                .map(|e| Some(SocketAddr::new(e.ip(), e.port())) == expected_endpoint)
+                .unwrap_or(false);
+            if !endpoint_matches {
+                mismatches.push(format!(
+                    "{interface}: endpoint is {actual_endpoint:?}, expected {expected_endpoint:?}"
+                ));
+            }
+        }
+        (None, None) => {}
+    }
+    if expected_route && !actual_route {
+        mismatches.push(format!("{interface}: host route is missing"));
+    } else if !expected_route && actual_route {
+        mismatches.push(format!("{interface}: host route should not be present"));
+    }
+
+    InterfaceAudit {
+        interface: interface.to_string(),
+        expected_allowed_ips: sorted(expected_allowed_ips),
+        actual_allowed_ips: actual_allowed_ips.map(sorted),
+        expected_endpoint,
+        actual_endpoint,
+        last_handshake: peer
+            .and_then(|p| p.last_handshake)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+        expected_route,
+        actual_route,
+        mismatches,
+    }
+}
+
+/// Records what setup_clients reconciled towards this tick, for audit_client
+pub fn record_desired_state(clients: &HashSet<ExitClient>, legacy_routes: &HashSet<IpAddr>) {
+    *DESIRED_STATE.write().unwrap() = Some(DesiredState {
+        clients: clients.iter().map(|c| (c.public_key, *c)).collect(),
+        legacy_routes: legacy_routes.clone(),
+    });
+}
+
+/// Dumps the peers, host routes and isolation rules the kernel has for a client alongside what the last
+/// exit loop tick wanted there, listing every difference
+pub fn audit_client(wg_key: WgKey) -> Result<ClientAudit, Box<RitaExitError>> {
+    let desired = match DESIRED_STATE.read().unwrap().clone() {
+        Some(desired) => desired,
+        None => {
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "The exit loop has not set up any clients yet".to_string(),
+            )))
+        }
+    };
+    let expected = desired.clients.get(&wg_key);
+
+    let mut interfaces = Vec::new();
+    for interface in [LEGACY_INTERFACE, EXIT_INTERFACE] {
+        let peers = KI
+            .get_wg_peers_netlink(interface)
+            .map_err(RitaExitError::from)?;
+        let routes = KI
+            .get_individual_client_routes(interface)
+            .map_err(RitaExitError::from)?;
+        let peer = peers.iter().find(|p| p.public_key == wg_key);
+        // a client that isn't expected is looked up by the ip its peer claims, if it has one
+        let internal_ip = expected.map(|c| c.internal_ip).or_else(|| {
+            peer.and_then(|p| p.allowed_ips.iter().find(|ip| ip.is_ipv4()))
+                .map(|ip| ip.ip())
+        });
+        let expected_route = interface == LEGACY_INTERFACE
+            && expected
+                .map(|c| desired.legacy_routes.contains(&c.internal_ip))
+                .unwrap_or(false);
+        let actual_route = internal_ip.map(|ip| routes.contains(&ip)).unwrap_or(false);
+        interfaces.push(audit_interface(
+            interface,
+            expected,
+            peer,
+            expected_route,
+            actual_route,
+        ));
+    }
+
+    let isolation_table = match expected {
+        Some(_) => get_isolated_clients()?
+            .into_iter()
+            .find(|c| c.wg_key == wg_key)
+            .map(|c| c.table),
+        None => None,
+    };
+    let isolation_rules = match expected {
+        Some(c) => KI
+            .get_isolation_rules(&c.internal_ip, c.internet_ipv6.map(normalize))
+            .map_err(RitaExitError::from)?,
+        None => Vec::new(),
+    };
+
+    let mut mismatches: Vec<String> = interfaces
+        .iter()
+        .flat_map(|i| i.mismatches.iter().cloned())
+        .collect();
+    match isolation_table {
+        Some(table) => {
+            let lookup = format!("lookup {table}");
+            if !isolation_rules.iter().any(|r| r.ends_with(&lookup)) {
+                mismatches.push(format!("isolation rule for table {table} is missing"));
+            }
+        }
+        None if !isolation_rules.is_empty() => {
+            mismatches.push("isolation rules present for a client that isn't isolated".to_string())
+        }
+        None => {}
+    }
+
+    Ok(ClientAudit {
+        wg_key,
+        registered: get_registered_client(&wg_key).is_some(),
+        served: expected.is_some(),
+        interfaces,
+        isolation_table,
+        isolation_rules,
+        mismatches,
+        client_override: check_client_override(&wg_key),
+    })
+}
+
+/// Brings the peers of an exit interface in line with the desired clients, returns the peers as they
+/// were before any changes so that the caller can use their handshakes
+pub fn reconcile_peers(
+    desired: &HashSet<ExitClient>,
+    listen_port: u16,
+    private_key_path: &str,
+    interface: &str,
+) -> Result<Vec<WgPeerInfo>, Box<RitaExitError>> {
+    let actual = KI
+        .get_wg_peers_netlink(interface)
+        .map_err(RitaExitError::from)?;
+    let diff = diff_peers(desired, &actual);
+    if !diff.is_empty() {
+        info!(
+            "Reconciling {}, updating {} peers and removing {} of {}",
+            interface,
+            diff.upsert.len(),
+            diff.remove.len(),
+            actual.len()
+        );
+    }
+    // the listen port and key are always applied, the kernel ignores them if they are unchanged
+    KI.update_exit_wg_peers_netlink(
+        &diff.upsert,
+        &diff.remove,
+        listen_port,
+        private_key_path,
+        interface,
+    )
+    .map_err(RitaExitError::from)?;
+    Ok(actual)
+}
+
+/// Brings the host routes on an exit interface in line with the desired client ips, every route is
+/// attempted even if some fail so that one bad client can't hold up the rest
+pub fn reconcile_routes(
+    desired: &HashSet<IpAddr>,
+    exit_internal_v4: IpAddr,
+    interface: &str,
+) -> Result<(), Box<RitaExitError>> {
+    let actual = KI
+        .get_individual_client_routes(interface)
+        .map_err(RitaExitError::from)?;
+    let diff = diff_routes(desired, &actual);
+    if diff.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Reconciling routes on {}, adding {} and removing {}",
+        interface,
+        diff.add.len(),
+        diff.remove.len()
+    );
+    let mut failures = 0;
+    for ip in diff.add {
+        if let Err(e) = KI.add_individual_client_route(ip, exit_internal_v4, interface) {
+            warn!("Failed to add client route {:?}", e);
+            failures += 1;
+        }
+    }
+    for ip in diff.remove {
+        if let Err(e) = KI.remove_individual_client_route(ip, interface) {
+            warn!("Failed to remove client route {:?}", e);
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "{failures} client routes on {interface} failed to apply"
+        ))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(key: &str, last_octet: u8) -> ExitClient {
+        ExitClient {
+            internal_ip: format!("172.168.1.{last_octet}").parse().unwrap(),
+            internet_ipv6: Some(format!("2001:db8:{last_octet}::/64").parse().unwrap()),
+            public_key: key.parse().unwrap(),
+            mesh_ip: format!("fd00::{last_octet}").parse().unwrap(),
+            port: 59999,
+        }
+    }
+
+    fn peer_for(c: &ExitClient) -> WgPeerInfo {
+        WgPeerInfo {
+            public_key: c.public_key,
+            endpoint: Some((c.mesh_ip, c.port).into()),
+            allowed_ips: client_allowed_ips(c).into_iter().collect(),
+            last_handshake: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+        }
+    }
+
+    #[test]
+    fn test_diff_peers() {
+        let a = client("88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs=", 2);
+        let b = client("bGkj7Z6bX1593G0pExfzxocWKhS3Un9uifIhZP9c5iM=", 3);
+        let c = client("9jRr6euMHu3tBIsZyqxUmjbuKVVFZCBOYApOR2pLNkQ=", 4);
+        let desired: HashSet<ExitClient> = [a, b].into_iter().collect();
+
+        // already in sync, nothing to do
+        assert!(diff_peers(&desired, &[peer_for(&a), peer_for(&b)]).is_empty());
+
+        // a missing peer is added and a stale one removed
+        let diff = diff_peers(&desired, &[peer_for(&a), peer_for(&c)]);
+        assert_eq!(diff.upsert, vec![b]);
+        assert_eq!(diff.remove, vec![c.public_key]);
+
+        // a peer whose allowed ips drifted, say from a partially applied update, is rewritten
+        let mut drifted = peer_for(&b);
+        drifted.allowed_ips.retain(|ip| ip.is_ipv4());
+        let diff = diff_peers(&desired, &[peer_for(&a), drifted]);
+        assert_eq!(diff.upsert, vec![b]);
+        assert!(diff.remove.is_empty());
+
+        // as is one that moved mesh ip
+        let mut moved = peer_for(&a);
+        moved.endpoint = Some("[fd00::99]:59999".parse().unwrap());
+        let diff = diff_peers(&desired, &[moved, peer_for(&b)]);
+        assert_eq!(diff.upsert, vec![a]);
+    }
+
+    #[test]
+    fn test_diff_peers_normalizes_allowed_ips() {
+        let mut a = client("88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs=", 2);
+        let peer = peer_for(&a);
+        // host bits set in the assigned subnet are masked off by the kernel
+        a.internet_ipv6 = Some("2001:db8:2::1/64".parse().unwrap());
+        let desired: HashSet<ExitClient> = [a].into_iter().collect();
+        assert!(diff_peers(&desired, &[peer]).is_empty());
+    }
+
+    #[test]
+    fn test_audit_interface() {
+        let a = client("88gbNAZx7NoNK9hatYuDkeZOjQ8EBmJ8VBpcFhXPqHs=", 2);
+        let audit = audit_interface("wg_exit", Some(&a), Some(&peer_for(&a)), true, true);
+        assert!(audit.mismatches.is_empty());
+        assert_eq!(audit.actual_allowed_ips, Some(audit.expected_allowed_ips));
+
+        let mut drifted = peer_for(&a);
+        drifted.allowed_ips.retain(|ip| ip.is_ipv4());
+        drifted.allowed_ips.push("172.168.1.99/32".parse().unwrap());
+        let audit = audit_interface("wg_exit", Some(&a), Some(&drifted), true, false);
+        assert_eq!(
+            audit.mismatches,
+            vec![
+                "wg_exit: allowed ip 2001:db8:2::/64 is missing".to_string(),
+                "wg_exit: unexpected allowed ip 172.168.1.99/32".to_string(),
+                "wg_exit: host route is missing".to_string(),
+            ]
+        );
+
+        assert_eq!(
+            audit_interface("wg_exit_v2", Some(&a), None, false, false).mismatches,
+            vec!["wg_exit_v2: peer is missing".to_string()]
+        );
+        assert_eq!(
+            audit_interface("wg_exit_v2", None, Some(&peer_for(&a)), false, true).mismatches,
+            vec![
+                "wg_exit_v2: peer should not be present".to_string(),
+                "wg_exit_v2: host route should not be present".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_routes() {
+        let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+        let desired: HashSet<IpAddr> = [ip("172.168.1.2"), ip("172.168.1.3")].into();
+        let actual: HashSet<IpAddr> = [ip("172.168.1.3"), ip("172.168.1.9")].into();
+        let diff = diff_routes(&desired, &actual);
+        assert_eq!(diff.add, vec![ip("172.168.1.2")]);
+        assert_eq!(diff.remove, vec![ip("172.168.1.9")]);
+        assert!(diff_routes(&desired, &desired).is_empty());
+    }
+}
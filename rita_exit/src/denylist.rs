@@ -0,0 +1,195 @@
+//! Operator managed list of clients that are refused service, for example after fraud. Entries match a
+//! client by wireguard key, eth address or both, carry a reason and an optional expiry, and are kept on disk
+//! so they survive restarts. Denied clients are refused at signup and status requests and have their
+//! tunnels torn down by setup_clients even though they remain registered in the contract.
+
+use crate::RitaExitError;
+use althea_types::unix_time::now_secs;
+use althea_types::{Identity, WgKey};
+use clarity::Address;
+use rita_common::utils::json_file::{cached_json_file, save_json_file};
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// The denylist, None until loaded from disk
+    static ref DENYLIST: Arc<RwLock<Option<Vec<DenylistEntry>>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DenylistEntry {
+    /// Clients with this wireguard key are denied
+    pub wg_key: Option<WgKey>,
+    /// Clients with this eth address are denied
+    pub eth_address: Option<Address>,
+    pub reason: String,
+    /// Unix time in seconds the entry was added
+    pub added: u64,
+    /// Unix time in seconds after which the entry no longer applies, None for a permanent ban
+    pub expires: Option<u64>,
+}
+
+impl DenylistEntry {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires.map(|expires| now < expires).unwrap_or(true)
+    }
+
+    fn matches(&self, id: &Identity) -> bool {
+        self.wg_key == Some(id.wg_public_key) || self.eth_address == Some(id.eth_address)
+    }
+}
+
+/// Request body for adding a denylist entry from the dashboard
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DenylistRequest {
+    pub wg_key: Option<WgKey>,
+    pub eth_address: Option<Address>,
+    pub reason: String,
+    /// How long the ban lasts, None for a permanent ban
+    pub duration_secs: Option<u64>,
+}
+
+/// Request body for removing every entry for a key or address from the dashboard
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DenylistRemoval {
+    pub wg_key: Option<WgKey>,
+    pub eth_address: Option<Address>,
+}
+
+/// The denylist, loaded from disk first if needed. A list that can't be loaded is an error rather than
+/// an empty list, which would quietly lift every ban
+fn denylist(
+    denylist: &mut Option<Vec<DenylistEntry>>,
+) -> Result<&mut Vec<DenylistEntry>, Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.client_denylist_file;
+    cached_json_file(denylist, &path).map_err(|e| {
+        error!("Failed to load client denylist {}", e);
+        Box::new(e.into())
+    })
+}
+
+/// Applies a change to the denylist and saves it, dropping expired entries. The change is only kept
+/// if it was saved so that the on disk list is never behind what we enforce
+fn modify_denylist<T>(
+    change: impl FnOnce(&mut Vec<DenylistEntry>) -> T,
+) -> Result<T, Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.client_denylist_file;
+    let mut cached = DENYLIST.write().unwrap();
+    let mut list = denylist(&mut cached)?.clone();
+    let ret = change(&mut list);
+    let now = now_secs();
+    list.retain(|entry| entry.is_active(now));
+    if let Err(e) = save_json_file(&path, &list) {
+        error!("Failed to save client denylist {}", e);
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Failed to save client denylist".to_string(),
+        )));
+    }
+    *cached = Some(list);
+    Ok(ret)
+}
+
+/// Every denylist entry, including any that expired since the list was last saved
+pub fn get_denylist() -> Result<Vec<DenylistEntry>, Box<RitaExitError>> {
+    Ok(denylist(&mut DENYLIST.write().unwrap())?.clone())
+}
+
+pub fn add_to_denylist(request: DenylistRequest) -> Result<DenylistEntry, Box<RitaExitError>> {
+    if request.wg_key.is_none() && request.eth_address.is_none() {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "A denylist entry needs a wg key or an eth address".to_string(),
+        )));
+    }
+    let added = now_secs();
+    let entry = DenylistEntry {
+        wg_key: request.wg_key,
+        eth_address: request.eth_address,
+        reason: request.reason,
+        added,
+        expires: request.duration_secs.map(|d| added.saturating_add(d)),
+    };
+    info!("Adding client denylist entry {:?}", entry);
+    modify_denylist(|list| list.push(entry.clone()))?;
+    Ok(entry)
+}
+
+/// Removes every entry for the given key or address, returns how many were removed
+pub fn remove_from_denylist(removal: DenylistRemoval) -> Result<usize, Box<RitaExitError>> {
+    modify_denylist(|list| {
+        let before = list.len();
+        list.retain(|entry| {
+            let key_match = removal.wg_key.is_some() && entry.wg_key == removal.wg_key;
+            let address_match =
+                removal.eth_address.is_some() && entry.eth_address == removal.eth_address;
+            !(key_match || address_match)
+        });
+        before - list.len()
+    })
+}
+
+/// The active entry denying this client, if any
+pub fn find_denial<'a>(
+    list: &'a [DenylistEntry],
+    id: &Identity,
+    now: u64,
+) -> Option<&'a DenylistEntry> {
+    list.iter()
+        .find(|entry| entry.is_active(now) && entry.matches(id))
+}
+
+/// The active entry denying this client, if any
+pub fn check_denylist(id: &Identity) -> Result<Option<DenylistEntry>, Box<RitaExitError>> {
+    Ok(find_denial(&get_denylist()?, id, now_secs()).cloned())
+}
+
+/// The clients in the given list that are currently denied
+pub fn denied_clients(clients: &[Identity]) -> Result<Vec<Identity>, Box<RitaExitError>> {
+    let list = get_denylist()?;
+    let now = now_secs();
+    Ok(clients
+        .iter()
+        .filter(|id| find_denial(&list, id, now).is_some())
+        .copied()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rita_common::tunnel_manager::get_test_id;
+
+    #[test]
+    fn test_find_denial() {
+        let id = get_test_id();
+        let other_key: WgKey = [7; 32].into();
+        let list = vec![
+            DenylistEntry {
+                wg_key: Some(other_key),
+                eth_address: None,
+                reason: "someone else".to_string(),
+                added: 0,
+                expires: None,
+            },
+            DenylistEntry {
+                wg_key: None,
+                eth_address: Some(id.eth_address),
+                reason: "chargebacks".to_string(),
+                added: 0,
+                expires: Some(1000),
+            },
+        ];
+        assert_eq!(find_denial(&list, &id, 999).unwrap().reason, "chargebacks");
+        // the ban has expired
+        assert!(find_denial(&list, &id, 1000).is_none());
+
+        // a different eth address with the banned key is still denied
+        let mut same_key = id;
+        same_key.wg_public_key = other_key;
+        same_key.eth_address = "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            find_denial(&list, &same_key, 5000).unwrap().reason,
+            "someone else"
+        );
+    }
+}
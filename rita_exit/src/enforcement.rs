@@ -0,0 +1,283 @@
+//! Enforcement can be programmed with tc htb classes, the original backend, or nftables policing rules.
+//! Switching backends on a production exit in one go is risky, so a backend can first run in shadow mode
+//! set by exit_network.enforcement_shadow. Every enforcement tick the shadow backend is handed the same
+//! plan as the active one, which clients to limit to what, and what it would end up enforcing, worked out
+//! the way that backend programs its rules, is compared to what the active backend actually has programmed
+//! in the kernel. Discrepancies are logged and reset the count
+//! of clean ticks, once the shadow has agreed for enforcement_shadow_ticks in a row the operator can switch
+//! to it from the admin api. The old backend then becomes the shadow so switching back is checked the same way.
+
+use crate::rita_loop::{EXIT_INTERFACE, LEGACY_INTERFACE};
+use crate::RitaExitError;
+use rita_common::KI;
+use settings::exit::EnforcementBackend;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+
+/// Client internal ip to enforced limit in kbit, clients that aren't limited are left out
+pub type EnforcementState = HashMap<Ipv4Addr, u32>;
+
+/// How many discrepancies are logged each tick, the rest are only counted
+const MAX_LOGGED_DISCREPANCIES: usize = 10;
+
+#[derive(Debug, Clone, Default)]
+struct ShadowTracker {
+    backend: Option<EnforcementBackend>,
+    clean_ticks: u32,
+    discrepancies: Vec<String>,
+}
+
+lazy_static! {
+    static ref SHADOW: Arc<RwLock<ShadowTracker>> =
+        Arc::new(RwLock::new(ShadowTracker::default()));
+    /// The backend that programmed enforcement on the last tick, to clean up after a switch
+    static ref LAST_BACKEND: Arc<RwLock<Option<EnforcementBackend>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShadowStatus {
+    pub active: EnforcementBackend,
+    pub shadow: Option<EnforcementBackend>,
+    /// Ticks in a row the shadow backend agreed with the active one
+    pub clean_ticks: u32,
+    pub required_ticks: u32,
+    pub switch_allowed: bool,
+    /// Where the shadow backend disagreed on the last tick
+    pub discrepancies: Vec<String>,
+}
+
+/// tc prints rates rounded to its units and nftables polices in bytes, so limits within a percent agree
+fn limits_match(a: u32, b: u32) -> bool {
+    u64::from(a.abs_diff(b)) * 100 <= u64::from(a.max(b))
+}
+
+/// Differences between the enforcement a backend would program and what is programmed, sorted by ip
+pub fn diff_enforcement(expected: &EnforcementState, actual: &EnforcementState) -> Vec<String> {
+    let mut ips: Vec<&Ipv4Addr> = expected.keys().chain(actual.keys()).collect();
+    ips.sort();
+    ips.dedup();
+    let mut ret = Vec::new();
+    for ip in ips {
+        match (expected.get(ip), actual.get(ip)) {
+            (Some(want), Some(have)) if !limits_match(*want, *have) => ret.push(format!(
+                "{ip} would be limited to {want}kbit but is at {have}kbit"
+            )),
+            (Some(want), None) => ret.push(format!(
+                "{ip} would be limited to {want}kbit but is not limited"
+            )),
+            (None, Some(have)) => {
+                ret.push(format!("{ip} would not be limited but is at {have}kbit"))
+            }
+            _ => {}
+        }
+    }
+    ret
+}
+
+/// What a backend currently has programmed for these clients
+fn read_backend(
+    backend: EnforcementBackend,
+    client_ips: &[Ipv4Addr],
+) -> Result<EnforcementState, Box<RitaExitError>> {
+    match backend {
+        EnforcementBackend::Tc => {
+            let mut ret = HashMap::new();
+            for iface in [LEGACY_INTERFACE, EXIT_INTERFACE] {
+                let limits = KI.get_class_limits(iface).map_err(RitaExitError::from)?;
+                for ip in client_ips {
+                    if let Some(limit) = limits.get(&KI.get_class_id(*ip)) {
+                        ret.insert(*ip, *limit);
+                    }
+                }
+            }
+            Ok(ret)
+        }
+        EnforcementBackend::Nftables => {
+            Ok(KI.get_nft_enforcement().map_err(RitaExitError::from)?)
+        }
+    }
+}
+
+/// What a backend would end up enforcing on these clients if it were handed this plan
+fn plan_backend(
+    backend: EnforcementBackend,
+    plan: &EnforcementState,
+    client_ips: &[Ipv4Addr],
+) -> EnforcementState {
+    match backend {
+        EnforcementBackend::Tc => KI.planned_class_limits(plan, client_ips),
+        EnforcementBackend::Nftables => KI.planned_nft_enforcement(plan),
+    }
+}
+
+/// Removes everything a backend programmed, after switching away from it
+fn clear_backend(backend: EnforcementBackend, client_ips: &[Ipv4Addr]) {
+    info!("Clearing enforcement programmed by {:?}", backend);
+    match backend {
+        EnforcementBackend::Tc => {
+            for ip in client_ips {
+                for iface in [LEGACY_INTERFACE, EXIT_INTERFACE] {
+                    if let Err(e) = KI.delete_class(iface, *ip) {
+                        error!("Unable to delete class for {} on {} {:?}", ip, iface, e);
+                    }
+                }
+            }
+        }
+        EnforcementBackend::Nftables => {
+            if let Err(e) = KI.delete_nft_enforcement() {
+                error!("Unable to delete nftables enforcement {:?}", e);
+            }
+        }
+    }
+}
+
+/// Called before enforcing, cleans up after the previous backend and returns true if the backend
+/// changed since the last tick, in which case the new one has to program everything from scratch
+pub fn check_backend_switch(active: EnforcementBackend, client_ips: &[Ipv4Addr]) -> bool {
+    let mut last = LAST_BACKEND.write().unwrap();
+    let switched = match *last {
+        Some(previous) if previous != active => {
+            clear_backend(previous, client_ips);
+            true
+        }
+        _ => false,
+    };
+    *last = Some(active);
+    switched
+}
+
+/// Called every enforcement tick with the plan the active backend was given
+pub fn shadow_tick(plan: &EnforcementState, client_ips: &[Ipv4Addr]) {
+    let exit_network = settings::get_rita_exit_snapshot().exit_network.clone();
+    let active = exit_network.enforcement_backend;
+    let mut tracker = SHADOW.write().unwrap();
+    let shadow = match exit_network.enforcement_shadow {
+        Some(shadow) if shadow != active => shadow,
+        _ => {
+            *tracker = ShadowTracker::default();
+            return;
+        }
+    };
+    if tracker.backend != Some(shadow) {
+        *tracker = ShadowTracker {
+            backend: Some(shadow),
+            ..Default::default()
+        };
+    }
+    let expected = plan_backend(shadow, plan, client_ips);
+    let discrepancies = match read_backend(active, client_ips) {
+        Ok(actual) => diff_enforcement(&expected, &actual),
+        Err(e) => vec![format!("Failed to read {active:?} enforcement {e}")],
+    };
+    if discrepancies.is_empty() {
+        tracker.clean_ticks = tracker.clean_ticks.saturating_add(1);
+    } else {
+        warn!(
+            "Shadow enforcement backend {:?} disagrees with {:?} on {} clients, resetting {} clean ticks",
+            shadow,
+            active,
+            discrepancies.len(),
+            tracker.clean_ticks
+        );
+        for discrepancy in discrepancies.iter().take(MAX_LOGGED_DISCREPANCIES) {
+            warn!("Shadow enforcement: {}", discrepancy);
+        }
+        tracker.clean_ticks = 0;
+    }
+    tracker.discrepancies = discrepancies;
+}
+
+pub fn get_shadow_status() -> ShadowStatus {
+    let exit_network = settings::get_rita_exit_snapshot().exit_network.clone();
+    let tracker = SHADOW.read().unwrap().clone();
+    let shadow = exit_network
+        .enforcement_shadow
+        .filter(|shadow| *shadow != exit_network.enforcement_backend);
+    let clean_ticks = if tracker.backend == shadow {
+        tracker.clean_ticks
+    } else {
+        0
+    };
+    ShadowStatus {
+        active: exit_network.enforcement_backend,
+        shadow,
+        clean_ticks,
+        required_ticks: exit_network.enforcement_shadow_ticks,
+        switch_allowed: shadow.is_some() && clean_ticks >= exit_network.enforcement_shadow_ticks,
+        discrepancies: tracker.discrepancies,
+    }
+}
+
+/// Makes the shadow backend the active one, only once it has agreed with the active backend for long
+/// enough. The old backend is cleared on the next enforcement tick and becomes the shadow
+pub fn switch_enforcement_backend(
+    backend: EnforcementBackend,
+) -> Result<ShadowStatus, Box<RitaExitError>> {
+    let status = get_shadow_status();
+    if status.active == backend {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "{backend:?} is already the enforcement backend"
+        ))));
+    }
+    if status.shadow != Some(backend) {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "Run {backend:?} as the enforcement shadow before switching to it"
+        ))));
+    }
+    if !status.switch_allowed {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "{backend:?} has only agreed for {} of {} ticks",
+            status.clean_ticks, status.required_ticks
+        ))));
+    }
+    info!(
+        "Enforcement backend switched from {:?} to {:?} by the operator",
+        status.active, backend
+    );
+    let mut rita_exit = settings::get_rita_exit();
+    rita_exit.exit_network.enforcement_backend = backend;
+    rita_exit.exit_network.enforcement_shadow = Some(status.active);
+    settings::set_rita_exit(rita_exit);
+    *SHADOW.write().unwrap() = ShadowTracker::default();
+    if let Err(e) = settings::write_config() {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "Failed to save enforcement backend {e:?}"
+        ))));
+    }
+    Ok(get_shadow_status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_enforcement() {
+        let a: Ipv4Addr = "172.168.0.2".parse().unwrap();
+        let b: Ipv4Addr = "172.168.0.3".parse().unwrap();
+        let c: Ipv4Addr = "172.168.0.4".parse().unwrap();
+        let expected: EnforcementState = [(a, 1000), (b, 10_000)].into_iter().collect();
+        assert!(diff_enforcement(&expected, &expected).is_empty());
+
+        // rounding by the backend is not a discrepancy
+        let actual: EnforcementState = [(a, 1000), (b, 10_050)].into_iter().collect();
+        assert!(diff_enforcement(&expected, &actual).is_empty());
+
+        let actual: EnforcementState = [(a, 2000), (c, 1000)].into_iter().collect();
+        let diff = diff_enforcement(&expected, &actual);
+        assert_eq!(diff.len(), 3);
+        assert_eq!(
+            diff[0],
+            "172.168.0.2 would be limited to 1000kbit but is at 2000kbit"
+        );
+        assert_eq!(
+            diff[1],
+            "172.168.0.3 would be limited to 10000kbit but is not limited"
+        );
+        assert_eq!(
+            diff[2],
+            "172.168.0.4 would not be limited but is at 1000kbit"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Orders the exit list handed to clients so that default exit selection spreads clients across a
+//! cluster. Exits that serve the client's region, as found by geoip on the gateway the client
+//! reaches us through, are listed first. Every exit polls the /health of the others, and exits that
+//! are not healthy or don't answer get a lower weight, so all exits in the cluster weigh each other
+//! from the same reports. Exits of equal weight are ordered by rendezvous hashing of the client and
+//! exit keys, so each client gets a stable order, different clients get different orders and every
+//! exit in the cluster hands a given client the same order. Clients break metric ties by list order
+//! and newer clients may use the weights directly.
+
+use crate::database::geoip::{get_country, get_gateway_ip_single};
+use crate::health::{get_health_state, HealthReport, HealthState};
+use crate::network_endpoints::CLIENT_STATUS_TIMEOUT;
+use crate::response_cache::cached_registered_exits;
+use althea_types::regions::Regions;
+use althea_types::{ExitIdentity, ExitListV2, WgKey};
+use rita_common::rita_loop::get_web3_server;
+use rita_common::utils::ip_increment::is_unicast_link_local;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use web30::client::Web3;
+
+/// Weight of an exit that serves the client's region, or of any exit when the region is unknown
+pub const REGIONAL_EXIT_WEIGHT: u32 = 100;
+/// Weight of an exit that does not serve the client's region, older clients may still try it
+pub const OUT_OF_REGION_EXIT_WEIGHT: u32 = 0;
+
+/// How often the other exits' health is polled
+const HEALTH_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Health of every registered exit as of the last poll, ours included
+pub type ClusterHealth = HashMap<WgKey, HealthState>;
+
+#[derive(Default)]
+struct ClusterHealthState {
+    health: ClusterHealth,
+    last_probe: Option<Instant>,
+}
+
+lazy_static! {
+    static ref CLUSTER_HEALTH: Arc<RwLock<ClusterHealthState>> =
+        Arc::new(RwLock::new(ClusterHealthState::default()));
+}
+
+pub fn get_cluster_health() -> ClusterHealth {
+    CLUSTER_HEALTH.read().unwrap().health.clone()
+}
+
+/// The state an exit reports at /health, an exit that doesn't answer is failing
+async fn probe_health(exit: &ExitIdentity) -> HealthState {
+    let url = format!(
+        "http://[{}]:{}/health",
+        exit.mesh_ip, exit.registration_port
+    );
+    let client = awc::Client::default();
+    let mut response = match client.get(&url).timeout(HEALTH_PROBE_TIMEOUT).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            trace!("Exit {} did not answer a health probe {:?}", exit.wg_key, e);
+            return HealthState::Failing;
+        }
+    };
+    match response.json::<HealthReport>().await {
+        Ok(report) => report.state,
+        Err(e) => {
+            trace!("Bad health report from exit {} {:?}", exit.wg_key, e);
+            HealthState::Failing
+        }
+    }
+}
+
+/// Run every exit loop tick, polls the health of every registered exit at most every
+/// HEALTH_PROBE_INTERVAL
+pub async fn update_cluster_health() {
+    if let Some(last_probe) = CLUSTER_HEALTH.read().unwrap().last_probe {
+        if last_probe.elapsed() < HEALTH_PROBE_INTERVAL {
+            return;
+        }
+    }
+    let rita_exit = settings::get_rita_exit();
+    let our_addr = match rita_exit.payment.eth_private_key {
+        Some(key) => key.to_address(),
+        None => return,
+    };
+    let contact = Web3::new(&get_web3_server(), CLIENT_STATUS_TIMEOUT);
+    let exits = match cached_registered_exits(
+        &contact,
+        our_addr,
+        rita_exit.exit_network.registered_users_contract_addr,
+    )
+    .await
+    {
+        Ok(exits) => exits,
+        Err(e) => {
+            warn!(
+                "Failed to get exits for health probes, keeping the last ones {}",
+                e
+            );
+            return;
+        }
+    };
+
+    let us = rita_exit.get_exit_identity();
+    let mut health = HashMap::new();
+    health.insert(us.wg_key, get_health_state());
+    for exit in exits {
+        if exit.wg_key != us.wg_key {
+            let state = probe_health(&exit).await;
+            health.insert(exit.wg_key, state);
+        }
+    }
+
+    let mut cluster = CLUSTER_HEALTH.write().unwrap();
+    cluster.health = health;
+    cluster.last_probe = Some(Instant::now());
+}
+
+/// Exits that haven't been polled yet are assumed healthy
+fn exit_weight(exit: &ExitIdentity, client_region: Option<Regions>, health: &ClusterHealth) -> u32 {
+    let weight = match client_region {
+        Some(region) if !exit.allowed_regions.contains(&region) => OUT_OF_REGION_EXIT_WEIGHT,
+        _ => REGIONAL_EXIT_WEIGHT,
+    };
+    match health.get(&exit.wg_key) {
+        Some(HealthState::Degraded) => weight / 2,
+        Some(HealthState::Failing) => OUT_OF_REGION_EXIT_WEIGHT,
+        Some(HealthState::Healthy) | None => weight,
+    }
+}
+
+pub(crate) fn rendezvous_score(client: &WgKey, exit: &ExitIdentity) -> [u8; 32] {
+    let mut input = Vec::with_capacity(64);
+    input.extend_from_slice(client.as_ref());
+    input.extend_from_slice(exit.wg_key.as_ref());
+    sha256::hash(&input).0
+}
+
+/// Sorts exits for the given client, highest weight first and by rendezvous score within a weight
+pub fn order_exit_list(
+    exits: Vec<ExitIdentity>,
+    client: WgKey,
+    client_region: Option<Regions>,
+    health: &ClusterHealth,
+) -> ExitListV2 {
+    let mut scored: Vec<(u32, [u8; 32], ExitIdentity)> = exits
+        .into_iter()
+        .map(|exit| {
+            (
+                exit_weight(&exit, client_region, health),
+                rendezvous_score(&client, &exit),
+                exit,
+            )
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let weights = scored.iter().map(|(weight, _, _)| *weight).collect();
+    ExitListV2 {
+        exit_list: scored.into_iter().map(|(_, _, exit)| exit).collect(),
+        weights,
+    }
+}
+
+/// Geoip region of the gateway a client reaches us through. None if the client is directly
+/// attached, has no route or the lookup fails, in which case every exit is treated as equally close
+pub fn get_client_region(client_mesh_ip: IpAddr) -> Option<Regions> {
+    let gateway_ip = match get_gateway_ip_single(client_mesh_ip) {
+        Ok(ip) => ip,
+        Err(e) => {
+            trace!("No gateway ip for {} {:?}", client_mesh_ip, e);
+            return None;
+        }
+    };
+    if let IpAddr::V6(ip) = gateway_ip {
+        if is_unicast_link_local(&ip) {
+            return None;
+        }
+    }
+    match get_country(gateway_ip) {
+        Ok(Regions::UnkownRegion) => None,
+        Ok(region) => Some(region),
+        Err(e) => {
+            warn!("Geoip lookup for exit list failed {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::SystemChain;
+    use std::collections::{HashMap, HashSet};
+
+    fn exit(n: u8, regions: &[Regions]) -> ExitIdentity {
+        ExitIdentity {
+            mesh_ip: format!("fd00::{n}").parse().unwrap(),
+            wg_key: [n; 32].into(),
+            eth_addr: "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"
+                .parse()
+                .unwrap(),
+            registration_port: 4875,
+            wg_exit_listen_port: 59998,
+            allowed_regions: regions.iter().copied().collect(),
+            payment_types: HashSet::from([SystemChain::AltheaL1]),
+        }
+    }
+
+    #[test]
+    fn test_order_exit_list() {
+        let exits = vec![
+            exit(1, &[Regions::Canada]),
+            exit(2, &[Regions::Colombia]),
+            exit(3, &[Regions::Colombia, Regions::Canada]),
+            exit(4, &[Regions::Colombia]),
+        ];
+        let client: WgKey = [9; 32].into();
+        let healthy = &ClusterHealth::new();
+
+        let list = order_exit_list(exits.clone(), client, Some(Regions::Colombia), healthy);
+        assert_eq!(list.exit_list.len(), 4);
+        assert_eq!(list.weights, vec![100, 100, 100, 0]);
+        assert_eq!(list.exit_list[3].mesh_ip, exits[0].mesh_ip);
+
+        // the order is stable regardless of the order the exits came in
+        let mut reversed = exits.clone();
+        reversed.reverse();
+        assert_eq!(
+            order_exit_list(reversed, client, Some(Regions::Colombia), healthy),
+            list
+        );
+
+        // without a region every exit is equally weighted
+        let list = order_exit_list(exits.clone(), client, None, healthy);
+        assert!(list.weights.iter().all(|w| *w == REGIONAL_EXIT_WEIGHT));
+
+        // many clients should not all end up with the same first choice
+        let mut first_choices = HashMap::new();
+        for n in 0..64u8 {
+            let list = order_exit_list(
+                exits.clone(),
+                [n; 32].into(),
+                Some(Regions::Colombia),
+                healthy,
+            );
+            *first_choices.entry(list.exit_list[0].mesh_ip).or_insert(0) += 1;
+        }
+        assert_eq!(first_choices.len(), 3);
+
+        // an unhealthy exit moves down the list
+        let degraded = HashMap::from([
+            (exits[1].wg_key, HealthState::Degraded),
+            (exits[2].wg_key, HealthState::Healthy),
+        ]);
+        let list = order_exit_list(exits.clone(), client, Some(Regions::Colombia), &degraded);
+        assert_eq!(list.weights, vec![100, 100, 50, 0]);
+        assert_eq!(list.exit_list[2].mesh_ip, exits[1].mesh_ip);
+        let failing = HashMap::from([(exits[1].wg_key, HealthState::Failing)]);
+        let list = order_exit_list(exits.clone(), client, Some(Regions::Colombia), &failing);
+        assert_eq!(list.weights, vec![100, 100, 0, 0]);
+    }
+}
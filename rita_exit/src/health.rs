@@ -0,0 +1,313 @@
+//! Health of the exit as a small state machine fed by the exit loop. Every tick each check records
+//! whether it worked, one failure makes the exit degraded and a check that keeps failing makes it
+//! failing, after which it has to pass a few ticks in a row before it counts as healthy again so
+//! that a flapping dependency doesn't flap the exit. The combined state and the reasons for it are
+//! served at /health for load balancers and monitoring, and every exit in the cluster polls it to
+//! weigh this exit in the exit lists it hands out, see crate::exit_list.
+
+use crate::maintenance::{get_maintenance_status, MaintenanceStatus};
+use althea_types::unix_time::unix_secs;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// Successful ticks in a row a check needs after failing before it is healthy again
+pub const RECOVERY_TICKS: u32 = 3;
+/// If the exit loop has not finished a tick for this long the exit is failing
+pub const LOOP_STALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheck {
+    /// Fetching the registered client list from the full node
+    Database,
+    /// Reading routes from babel for billing
+    Babel,
+    /// Programming client peers and routes into the exit wg interfaces
+    WgSetup,
+    /// Applying enforcement to clients that are behind on payments
+    Enforcement,
+    /// The exit loop itself completing ticks
+    ExitLoop,
+    /// OS settings the exit needs that the preflight checks could not fix, see crate::preflight
+    Preflight,
+}
+
+impl HealthCheck {
+    /// What failed, shown in place of the error itself where the report is public
+    fn description(&self) -> &'static str {
+        match self {
+            HealthCheck::Database => "Failed to fetch the registered client list",
+            HealthCheck::Babel => "Failed to read routes from babel",
+            HealthCheck::WgSetup => "Failed to set up client tunnels",
+            HealthCheck::Enforcement => "Failed to apply enforcement",
+            HealthCheck::ExitLoop => "The exit loop is not finishing ticks",
+            HealthCheck::Preflight => "An os setting the exit needs is wrong",
+        }
+    }
+
+    /// Consecutive failures after which this check makes the exit failing, None if it can only
+    /// ever degrade it. The client list is cached so a full node outage is tolerated for a while,
+    /// enforcement failures cost the operator money but don't break service for anyone. Preflight
+    /// problems are checked rarely and may only affect some clients, so they only degrade it
+    fn failing_after(&self) -> Option<u32> {
+        match self {
+            HealthCheck::Database => Some(12),
+            HealthCheck::Babel => Some(3),
+            HealthCheck::WgSetup => Some(3),
+            HealthCheck::Enforcement => None,
+            HealthCheck::ExitLoop => Some(1),
+            HealthCheck::Preflight => None,
+        }
+    }
+}
+
+/// Ordered by severity, the exit is in the worst state of any of its checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Healthy,
+    Degraded,
+    Failing,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct CheckStatus {
+    consecutive_failures: u32,
+    /// Successes since the last failure, None if the check has never failed
+    recovering_for: Option<u32>,
+    last_error: Option<String>,
+    /// When the current run of failures started
+    failing_since: Option<SystemTime>,
+}
+
+impl CheckStatus {
+    fn state(&self, check: HealthCheck) -> HealthState {
+        if self.consecutive_failures > 0 {
+            match check.failing_after() {
+                Some(limit) if self.consecutive_failures >= limit => HealthState::Failing,
+                _ => HealthState::Degraded,
+            }
+        } else {
+            match self.recovering_for {
+                Some(ticks) if ticks < RECOVERY_TICKS => HealthState::Degraded,
+                _ => HealthState::Healthy,
+            }
+        }
+    }
+}
+
+/// Why the exit is not healthy, one per check that isn't
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReason {
+    pub check: HealthCheck,
+    pub state: HealthState,
+    pub message: String,
+    pub consecutive_failures: u32,
+    /// Unix time in seconds the check started failing
+    pub since: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub state: HealthState,
+    pub reasons: Vec<HealthReason>,
+    /// Unix time in seconds the exit loop last finished a tick
+    pub last_tick: Option<u64>,
+    /// Set while enforcement is paused for maintenance, which doesn't affect the health state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceStatus>,
+}
+
+impl HealthReport {
+    /// The report with each error replaced by a description of the check, errors can carry
+    /// addresses, paths and full node responses
+    pub fn without_errors(mut self) -> HealthReport {
+        for reason in self.reasons.iter_mut() {
+            if reason.consecutive_failures > 0 {
+                reason.message = reason.check.description().to_string();
+            }
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthMonitor {
+    checks: BTreeMap<HealthCheck, CheckStatus>,
+    last_tick: Option<SystemTime>,
+}
+
+impl HealthMonitor {
+    pub fn record(&mut self, check: HealthCheck, result: Result<(), String>, now: SystemTime) {
+        let status = self.checks.entry(check).or_default();
+        match result {
+            Ok(()) => {
+                if status.consecutive_failures > 0 {
+                    info!("Exit health check {:?} recovered", check);
+                }
+                status.consecutive_failures = 0;
+                status.failing_since = None;
+                status.recovering_for = status.recovering_for.map(|ticks| ticks.saturating_add(1));
+            }
+            Err(e) => {
+                if status.consecutive_failures == 0 {
+                    warn!("Exit health check {:?} failed {}", check, e);
+                    status.failing_since = Some(now);
+                }
+                status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+                status.recovering_for = Some(0);
+                status.last_error = Some(e);
+            }
+        }
+    }
+
+    pub fn record_tick(&mut self, now: SystemTime) {
+        self.last_tick = Some(now);
+    }
+
+    pub fn report(&self, now: SystemTime) -> HealthReport {
+        let mut reasons = Vec::new();
+        match self.last_tick {
+            None => reasons.push(HealthReason {
+                check: HealthCheck::ExitLoop,
+                state: HealthState::Degraded,
+                message: "The exit loop has not finished a tick yet".to_string(),
+                consecutive_failures: 0,
+                since: None,
+            }),
+            Some(last_tick) => {
+                let elapsed = now.duration_since(last_tick).unwrap_or_default();
+                if elapsed > LOOP_STALL_TIMEOUT {
+                    reasons.push(HealthReason {
+                        check: HealthCheck::ExitLoop,
+                        state: HealthState::Failing,
+                        message: format!(
+                            "The exit loop has not finished a tick in {}s",
+                            elapsed.as_secs()
+                        ),
+                        consecutive_failures: 1,
+                        since: Some(unix_secs(last_tick)),
+                    })
+                }
+            }
+        }
+        for (check, status) in self.checks.iter() {
+            let state = status.state(*check);
+            if state == HealthState::Healthy {
+                continue;
+            }
+            let message = if status.consecutive_failures > 0 {
+                status.last_error.clone().unwrap_or_default()
+            } else {
+                format!(
+                    "Recovering, {} of {} successful ticks",
+                    status.recovering_for.unwrap_or(0),
+                    RECOVERY_TICKS
+                )
+            };
+            reasons.push(HealthReason {
+                check: *check,
+                state,
+                message,
+                consecutive_failures: status.consecutive_failures,
+                since: status.failing_since.map(unix_secs),
+            });
+        }
+        HealthReport {
+            state: reasons
+                .iter()
+                .map(|reason| reason.state)
+                .max()
+                .unwrap_or(HealthState::Healthy),
+            reasons,
+            last_tick: self.last_tick.map(unix_secs),
+            maintenance: None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref EXIT_HEALTH: Arc<RwLock<HealthMonitor>> =
+        Arc::new(RwLock::new(HealthMonitor::default()));
+}
+
+/// Records the outcome of a check this tick
+pub fn record_health(check: HealthCheck, result: Result<(), String>) {
+    EXIT_HEALTH
+        .write()
+        .unwrap()
+        .record(check, result, SystemTime::now());
+}
+
+/// Called at the end of every exit loop tick
+pub fn record_exit_tick() {
+    EXIT_HEALTH.write().unwrap().record_tick(SystemTime::now());
+}
+
+pub fn get_health_report() -> HealthReport {
+    let mut report = EXIT_HEALTH.read().unwrap().report(SystemTime::now());
+    let maintenance = get_maintenance_status();
+    if maintenance.active {
+        report.maintenance = Some(maintenance);
+    }
+    report
+}
+
+pub fn get_health_state() -> HealthState {
+    get_health_report().state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_health_state_machine() {
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut monitor = HealthMonitor::default();
+        assert_eq!(monitor.report(start).state, HealthState::Degraded);
+        monitor.record_tick(start);
+        monitor.record(HealthCheck::Babel, Ok(()), start);
+        assert_eq!(monitor.report(start).state, HealthState::Healthy);
+        assert!(monitor.report(start).reasons.is_empty());
+
+        // enforcement failures only ever degrade the exit
+        for _ in 0..10 {
+            monitor.record(HealthCheck::Enforcement, Err("tc".to_string()), start);
+        }
+        assert_eq!(monitor.report(start).state, HealthState::Degraded);
+        monitor.record(HealthCheck::Enforcement, Ok(()), start);
+
+        // babel fails the exit after three ticks in a row
+        monitor.record(HealthCheck::Babel, Err("refused".to_string()), start);
+        monitor.record(HealthCheck::Babel, Err("refused".to_string()), start);
+        assert_eq!(monitor.report(start).state, HealthState::Degraded);
+        monitor.record(HealthCheck::Babel, Err("refused".to_string()), start);
+        let report = monitor.report(start);
+        assert_eq!(report.state, HealthState::Failing);
+        let babel = report
+            .reasons
+            .iter()
+            .find(|r| r.check == HealthCheck::Babel)
+            .unwrap();
+        assert_eq!(babel.message, "refused");
+        assert_eq!(babel.since, Some(1000));
+        let public = report.without_errors();
+        assert!(public.reasons.iter().all(|r| r.message != "refused"));
+
+        // and needs several good ticks before it counts as healthy again
+        for _ in 0..RECOVERY_TICKS - 1 {
+            monitor.record(HealthCheck::Babel, Ok(()), start);
+            monitor.record(HealthCheck::Enforcement, Ok(()), start);
+            assert_eq!(monitor.report(start).state, HealthState::Degraded);
+        }
+        monitor.record(HealthCheck::Babel, Ok(()), start);
+        assert_eq!(monitor.report(start).state, HealthState::Healthy);
+
+        // a stalled loop is failing
+        let later = start + LOOP_STALL_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(monitor.report(later).state, HealthState::Failing);
+    }
+}
@@ -0,0 +1,182 @@
+//! Receives the udp heartbeats clients send over the exit tunnel, see althea_types::exit_heartbeat for the
+//! packet format. Wireguard handshakes take minutes to age out, with heartbeats arriving every few seconds
+//! we can tell a client is offline almost right away. The last heard times are exposed through the clients
+//! listing on the dashboard.
+
+use althea_types::unix_time::now_secs;
+use althea_types::ExitHeartbeatMessage;
+use althea_types::Identity;
+use althea_types::WgKey;
+use althea_types::EXIT_HEARTBEAT_PORT;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Heartbeats with a timestamp further than this from our own clock are rejected, this bounds
+/// how long a captured packet can be replayed for
+const MAX_CLOCK_SKEW: u64 = 120;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct ClientHeartbeat {
+    /// The timestamp in the last accepted heartbeat, only newer heartbeats are accepted
+    last_timestamp: u64,
+    /// When we received the last accepted heartbeat, in seconds since the unix epoch
+    last_heard: u64,
+}
+
+lazy_static! {
+    /// Registered clients, heartbeats from anyone else are ignored
+    static ref REGISTERED_CLIENTS: Arc<RwLock<HashMap<WgKey, Identity>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+    static ref LAST_HEARD: Arc<RwLock<HashMap<WgKey, ClientHeartbeat>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExitClientHeartbeatStatus {
+    pub identity: Identity,
+    /// When we last heard a heartbeat from this client in seconds since the unix epoch
+    pub last_heard: Option<u64>,
+}
+
+/// Updates the set of clients we accept heartbeats from, called by the exit loop each time the
+/// registered clients list is refreshed
+pub fn update_heartbeat_clients(clients: &[Identity]) {
+    let registered: HashMap<WgKey, Identity> =
+        clients.iter().map(|c| (c.wg_public_key, *c)).collect();
+    LAST_HEARD
+        .write()
+        .unwrap()
+        .retain(|key, _| registered.contains_key(key));
+    *REGISTERED_CLIENTS.write().unwrap() = registered;
+}
+
+/// The registered client with this key, if there is one
+pub fn get_registered_client(key: &WgKey) -> Option<Identity> {
+    REGISTERED_CLIENTS.read().unwrap().get(key).copied()
+}
+
+/// Returns every registered client along with when we last heard from them
+pub fn get_clients_heartbeat_status() -> Vec<ExitClientHeartbeatStatus> {
+    let last_heard = LAST_HEARD.read().unwrap();
+    REGISTERED_CLIENTS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(key, identity)| ExitClientHeartbeatStatus {
+            identity: *identity,
+            last_heard: last_heard.get(key).map(|hb| hb.last_heard),
+        })
+        .collect()
+}
+
+/// Validates and records a single heartbeat, returns the sender if it was accepted
+fn handle_heartbeat(message: ExitHeartbeatMessage, sender: WgKey, now: u64) -> Option<WgKey> {
+    if !REGISTERED_CLIENTS.read().unwrap().contains_key(&sender) {
+        trace!("Heartbeat from unregistered client {}", sender);
+        return None;
+    }
+    if message.timestamp.abs_diff(now) > MAX_CLOCK_SKEW {
+        trace!("Heartbeat from {} outside of allowed clock skew", sender);
+        return None;
+    }
+    let mut last_heard = LAST_HEARD.write().unwrap();
+    let entry = last_heard.entry(sender).or_default();
+    // a replayed or reordered packet
+    if message.timestamp < entry.last_timestamp {
+        return None;
+    }
+    entry.last_timestamp = message.timestamp;
+    entry.last_heard = now;
+    Some(sender)
+}
+
+pub fn start_exit_heartbeat_listener() {
+    // outer thread is a watchdog, inner thread is the runner
+    thread::spawn(move || {
+        while let Err(e) = {
+            thread::spawn(move || {
+                let local = SocketAddr::from((Ipv6Addr::UNSPECIFIED, EXIT_HEARTBEAT_PORT));
+                let socket = match UdpSocket::bind(local) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Failed to bind exit heartbeat socket {:?}", e);
+                        thread::sleep(Duration::from_secs(10));
+                        panic!("Failed to bind exit heartbeat socket");
+                    }
+                };
+                // clients know us by our identity, which uses the mesh wg key
+                let our_secretkey = match settings::get_rita_exit().network.wg_private_key {
+                    Some(key) => key.into(),
+                    None => {
+                        error!("No wg private key, can't receive exit heartbeats");
+                        return;
+                    }
+                };
+                let mut buf = [0; 1500];
+                loop {
+                    let bytes = match socket.recv_from(&mut buf) {
+                        Ok((bytes, _)) => bytes,
+                        Err(e) => {
+                            warn!("Exit heartbeat recv failed with {:?}", e);
+                            continue;
+                        }
+                    };
+                    match ExitHeartbeatMessage::decrypt(&buf[..bytes], &our_secretkey) {
+                        Ok((sender, message)) => {
+                            handle_heartbeat(message, sender, now_secs());
+                        }
+                        Err(e) => trace!("Bad exit heartbeat {}", e),
+                    }
+                }
+            })
+            .join()
+        } {
+            error!("Exit heartbeat thread panicked! Respawning {:?}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_heartbeat() {
+        let id: Identity = Identity::new(
+            "fd00::1".parse().unwrap(),
+            "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let message = |timestamp| ExitHeartbeatMessage {
+            timestamp,
+            version: "test".to_string(),
+        };
+        let now = 1_700_000_000;
+
+        // not yet registered
+        assert!(handle_heartbeat(message(now), id.wg_public_key, now).is_none());
+        update_heartbeat_clients(&[id]);
+        assert!(handle_heartbeat(message(now), id.wg_public_key, now).is_some());
+        // too far in the past and replays of older packets are rejected
+        assert!(handle_heartbeat(message(now - 600), id.wg_public_key, now).is_none());
+        assert!(handle_heartbeat(message(now - 5), id.wg_public_key, now).is_none());
+        assert!(handle_heartbeat(message(now + 5), id.wg_public_key, now + 5).is_some());
+
+        let status = get_clients_heartbeat_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].last_heard, Some(now + 5));
+
+        update_heartbeat_clients(&[]);
+        assert!(get_clients_heartbeat_status().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+//! Operator managed list of clients that get layer 3 isolation from the rest of the mesh, for business
+//! customers that need more than the shared firewall rules. Each isolated client gets a routing table of its
+//! own that can only reach the internet and optionally a dedicated public address to nat to. The list is
+//! kept on disk next to the denylist, setup_clients installs the tables for clients on this exit and tears
+//! them down when a client is removed from the list or stops being served.
+
+use crate::RitaExitError;
+use althea_kernel_interface::client_isolation::{IsolatedClientConfig, ISOLATION_TABLE_BASE};
+use althea_kernel_interface::ExitClient;
+use althea_types::unix_time::now_secs;
+use althea_types::WgKey;
+use ipnetwork::IpNetwork;
+use rita_common::utils::json_file::{cached_json_file, save_json_file};
+use rita_common::KI;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// The isolated client list, None until loaded from disk
+    static ref ISOLATED_CLIENTS: Arc<RwLock<Option<Vec<IsolatedClient>>>> =
+        Arc::new(RwLock::new(None));
+    /// What is currently installed in the kernel, None until the first setup after startup
+    static ref APPLIED_ISOLATION: Arc<RwLock<Option<HashMap<WgKey, IsolatedClientConfig>>>> =
+        Arc::new(RwLock::new(None));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IsolatedClient {
+    pub wg_key: WgKey,
+    /// Routing table assigned to this client, kept for as long as it is on the list
+    pub table: u32,
+    /// Public address this client's v4 traffic is natted to, None to share the exit's address
+    pub public_ipv4: Option<Ipv4Addr>,
+    /// Unix time in seconds the client was isolated
+    pub added: u64,
+}
+
+/// Request body for isolating a client, or changing its public address if it already is
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IsolationRequest {
+    pub wg_key: WgKey,
+    pub public_ipv4: Option<Ipv4Addr>,
+}
+
+/// Request body for removing a client from the isolated list
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IsolationRemoval {
+    pub wg_key: WgKey,
+}
+
+/// The isolated list, loaded from disk first if needed. A list that can't be loaded is an error rather
+/// than an empty list, which would quietly take away every client's isolation
+fn isolated_clients(
+    isolated: &mut Option<Vec<IsolatedClient>>,
+) -> Result<&mut Vec<IsolatedClient>, Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.client_isolation_file;
+    cached_json_file(isolated, &path).map_err(|e| {
+        error!("Failed to load isolated clients {}", e);
+        Box::new(e.into())
+    })
+}
+
+/// Applies a change to the isolated list and saves it, the change is only kept if it was saved
+fn modify_isolated_clients<T>(
+    change: impl FnOnce(&mut Vec<IsolatedClient>) -> Result<T, Box<RitaExitError>>,
+) -> Result<T, Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.client_isolation_file;
+    let mut isolated = ISOLATED_CLIENTS.write().unwrap();
+    let mut list = isolated_clients(&mut isolated)?.clone();
+    let ret = change(&mut list)?;
+    if let Err(e) = save_json_file(&path, &list) {
+        error!("Failed to save isolated clients {}", e);
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Failed to save isolated clients".to_string(),
+        )));
+    }
+    *isolated = Some(list);
+    Ok(ret)
+}
+
+pub fn get_isolated_clients() -> Result<Vec<IsolatedClient>, Box<RitaExitError>> {
+    Ok(isolated_clients(&mut ISOLATED_CLIENTS.write().unwrap())?.clone())
+}
+
+/// The lowest routing table not assigned to any client on the list
+fn next_free_table(list: &[IsolatedClient]) -> u32 {
+    let mut table = ISOLATION_TABLE_BASE;
+    while list.iter().any(|c| c.table == table) {
+        table += 1;
+    }
+    table
+}
+
+/// Adds a client to the isolated list or updates its public address, a public address can only be
+/// given to one client
+pub fn isolate_client(request: IsolationRequest) -> Result<IsolatedClient, Box<RitaExitError>> {
+    modify_isolated_clients(|list| {
+        if let Some(public_ipv4) = request.public_ipv4 {
+            if list
+                .iter()
+                .any(|c| c.wg_key != request.wg_key && c.public_ipv4 == Some(public_ipv4))
+            {
+                return Err(Box::new(RitaExitError::MiscStringError(format!(
+                    "{public_ipv4} is already assigned to another isolated client"
+                ))));
+            }
+        }
+        if let Some(existing) = list.iter_mut().find(|c| c.wg_key == request.wg_key) {
+            existing.public_ipv4 = request.public_ipv4;
+            info!("Updated isolated client {:?}", existing);
+            return Ok(existing.clone());
+        }
+        let client = IsolatedClient {
+            wg_key: request.wg_key,
+            table: next_free_table(list),
+            public_ipv4: request.public_ipv4,
+            added: now_secs(),
+        };
+        info!("Isolating client {:?}", client);
+        list.push(client.clone());
+        Ok(client)
+    })
+}
+
+/// Removes a client from the isolated list, returns false if it wasn't on it
+pub fn remove_isolated_client(removal: IsolationRemoval) -> Result<bool, Box<RitaExitError>> {
+    modify_isolated_clients(|list| {
+        let before = list.len();
+        list.retain(|c| c.wg_key != removal.wg_key);
+        Ok(before != list.len())
+    })
+}
+
+/// Builds the kernel config for the isolated clients among the given clients, each paired with the name
+/// of the exit interface it is on
+pub fn isolation_configs(
+    isolated: &[IsolatedClient],
+    clients: &[(ExitClient, &str)],
+    exit_interfaces: &[&str],
+    external_nic: &str,
+) -> HashMap<WgKey, IsolatedClientConfig> {
+    let exit_settings = settings::get_rita_exit();
+    let exit_network = exit_settings.exit_network;
+    let own_ip = exit_network.own_internal_ip;
+    let client_subnet_v4 = match IpNetwork::new(own_ip.into(), exit_network.netmask) {
+        Ok(net) => IpNetwork::new(net.network(), net.prefix()).unwrap_or(net),
+        Err(e) => {
+            error!("Invalid exit client subnet {:?}", e);
+            return HashMap::new();
+        }
+    };
+    let mut configs = HashMap::new();
+    for entry in isolated {
+        let (client, interface) = match clients.iter().find(|(c, _)| c.public_key == entry.wg_key) {
+            Some(client) => client,
+            // not served by this exit right now
+            None => continue,
+        };
+        configs.insert(
+            entry.wg_key,
+            IsolatedClientConfig {
+                table: entry.table,
+                internal_ip: client.internal_ip,
+                // ip prints subnets with the host bits masked off, rules are matched against that
+                internet_ipv6: client
+                    .internet_ipv6
+                    .map(|net| IpNetwork::new(net.network(), net.prefix()).unwrap_or(net)),
+                interface: interface.to_string(),
+                exit_interfaces: exit_interfaces.iter().map(|i| i.to_string()).collect(),
+                exit_internal_v4: own_ip.into(),
+                client_subnet_v4,
+                client_subnet_v6: exit_network.subnet,
+                external_nic: external_nic.to_string(),
+                public_ipv4: entry.public_ipv4,
+            },
+        );
+    }
+    configs
+}
+
+/// Brings the kernel in line with the desired isolation configs. Clients whose config changed are set up
+/// again, as are clients whose routes, rules or nat went missing from the kernel, a tunnel restart or
+/// firewall reload removes them, or whose table's default route no longer matches the main table.
+/// Failures are retried next tick
+pub fn reconcile_isolation(desired: HashMap<WgKey, IsolatedClientConfig>) {
+    let mut applied = APPLIED_ISOLATION.write().unwrap();
+    if applied.is_none() {
+        // rules left from before a restart may belong to clients no longer isolated
+        if let Err(e) = KI.remove_all_isolation_rules() {
+            error!("Failed to clear old isolation rules {:?}", e);
+            return;
+        }
+    }
+    let applied = applied.get_or_insert_with(HashMap::new);
+
+    let stale: Vec<WgKey> = applied
+        .iter()
+        .filter(|(key, config)| desired.get(key) != Some(config))
+        .map(|(key, _)| *key)
+        .collect();
+    for key in stale {
+        if let Some(config) = applied.remove(&key) {
+            info!("Removing isolation for {}", key);
+            if let Err(e) = KI.teardown_isolated_client(&config) {
+                warn!("Failed to remove isolation for {} {:?}", key, e);
+                applied.insert(key, config);
+            }
+        }
+    }
+    for (key, config) in desired {
+        if applied.contains_key(&key) {
+            match KI.is_isolated_client_current(&config) {
+                Ok(true) => continue,
+                Ok(false) => info!("Isolation for {} changed in the kernel, restoring it", key),
+                Err(e) => {
+                    warn!("Failed to check isolation for {} {:?}", key, e);
+                    continue;
+                }
+            }
+        } else {
+            info!("Isolating {} in table {}", key, config.table);
+        }
+        match KI.setup_isolated_client(&config) {
+            Ok(()) => {
+                applied.insert(key, config);
+            }
+            Err(e) => error!("Failed to isolate {} {:?}", key, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_free_table() {
+        let client = |table| IsolatedClient {
+            wg_key: [table as u8; 32].into(),
+            table,
+            public_ipv4: None,
+            added: 0,
+        };
+        assert_eq!(next_free_table(&[]), ISOLATION_TABLE_BASE);
+        let list = vec![
+            client(ISOLATION_TABLE_BASE),
+            client(ISOLATION_TABLE_BASE + 2),
+        ];
+        assert_eq!(next_free_table(&list), ISOLATION_TABLE_BASE + 1);
+    }
+}
@@ -7,11 +7,31 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod admin_api;
+pub mod backup;
+pub mod client_overrides;
+pub mod cluster;
+pub mod consistency;
 pub mod database;
+pub mod denylist;
+pub mod enforcement;
+pub mod exit_list;
+pub mod health;
+pub mod heartbeat;
+pub mod isolation;
+pub mod maintenance;
+pub mod migration;
 pub mod network_endpoints;
 pub mod operator_update;
+pub mod preflight;
+pub mod pricing;
+pub mod promotions;
+pub mod response_cache;
 pub mod rita_loop;
+pub mod sharding;
+pub mod speedtest;
 pub mod traffic_watcher;
+pub mod vouchers;
 
 mod error;
 use actix_async::System;
@@ -22,19 +42,20 @@ pub use error::RitaExitError;
 
 pub use crate::database::geoip::*;
 pub use crate::database::in_memory_database::*;
+use rita_common::capabilities::get_capabilities_endpoint;
 use rita_common::dashboard::babel::*;
 use rita_common::dashboard::debts::*;
-use rita_common::dashboard::development::*;
+use rita_common::dashboard::logging::*;
 use rita_common::dashboard::nickname::*;
+use rita_common::dashboard::notifications::*;
 use rita_common::dashboard::own_info::READABLE_VERSION;
 use rita_common::dashboard::own_info::*;
-use rita_common::dashboard::settings::*;
 use rita_common::dashboard::token_bridge::*;
 use rita_common::dashboard::usage::*;
-use rita_common::dashboard::wallet::*;
 use rita_common::dashboard::wg_key::*;
 use rita_common::middleware;
 use rita_common::network_endpoints::version;
+use rita_common::path_diagnostics::get_path_diagnostics;
 use std::path::PathBuf;
 use std::thread;
 
@@ -61,24 +82,53 @@ pub fn start_rita_exit_dashboard() {
         runner.block_on(async move {
             let _res = HttpServer::new(|| {
                 App::new()
+                    .wrap(middleware::CsrfMiddlewareFactory)
                     .wrap(middleware::HeadersMiddlewareFactory)
                     .route("/info", web::get().to(get_own_info))
-                    .route("/local_fee", web::get().to(get_local_fee))
-                    .route("/local_fee/{fee}", web::post().to(set_local_fee))
-                    .route("/metric_factor", web::get().to(get_metric_factor))
-                    .route("/metric_factor/{factor}", web::post().to(set_metric_factor))
-                    .route("/settings", web::get().to(get_settings))
-                    .route("/settings", web::post().to(set_settings))
+                    .route("/logging/levels", web::get().to(get_log_levels))
+                    .route(
+                        "/logging/level/{module}/{level}",
+                        web::post().to(set_log_level),
+                    )
+                    .route(
+                        "/neighbors/{id}/flaps",
+                        web::get().to(get_neighbor_route_flaps),
+                    )
+                    .route(
+                        "/neighbors/penalties",
+                        web::get().to(get_neighbor_penalties),
+                    )
                     .route("/version", web::get().to(version))
+                    .route("/capabilities", web::get().to(get_capabilities_endpoint))
                     .route("/wg_public_key", web::get().to(get_wg_public_key))
-                    .route("/wipe", web::post().to(wipe))
                     .route("/debts", web::get().to(get_debts))
                     .route("/debts/reset", web::post().to(reset_debt))
-                    .route("/withdraw/{address}/{amount}", web::post().to(withdraw))
-                    .route("/withdraw_all/{address}", web::post().to(withdraw_all))
+                    .route("/debts/shadow", web::get().to(get_shadow_debts))
+                    .route(
+                        "/debts/reconciliation",
+                        web::get().to(get_debt_reconciliation),
+                    )
+                    .route(
+                        "/diagnostics/path/{dest}",
+                        web::get().to(get_path_diagnostics),
+                    )
                     .route("/nickname/get/", web::get().to(get_nickname))
                     .route("/nickname/set/", web::post().to(set_nickname))
                     .route("/usage/payments", web::get().to(get_payments))
+                    .route("/earnings", web::get().to(get_earnings))
+                    .route("/notifications", web::get().to(get_notifications_endpoint))
+                    .route(
+                        "/notifications/{id}/read",
+                        web::post().to(mark_notification_read_endpoint),
+                    )
+                    .route(
+                        "/notifications/read_all",
+                        web::post().to(mark_all_notifications_read_endpoint),
+                    )
+                    .route(
+                        "/accounting/summary/{year}",
+                        web::get().to(get_annual_summary_endpoint),
+                    )
                     .route("/token_bridge/status", web::get().to(get_bridge_status))
             })
             .bind(format!(
@@ -0,0 +1,136 @@
+//! Maintenance mode pauses enforcement while the billing system is being worked on, so that clients are
+//! not throttled or cut off over debts that may be wrong. Traffic is still billed and debts still change
+//! as usual, once maintenance ends enforcement picks up from whatever the debts are then. The exit is in
+//! maintenance mode while it is turned on by hand from the admin api or a scheduled window is open, both
+//! are kept in exit_network.maintenance so that a restart doesn't end maintenance early.
+
+use crate::RitaExitError;
+use althea_types::unix_time::now_secs;
+use settings::exit::ExitMaintenanceSettings;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// Whether the exit loop last saw maintenance mode on, to log when it starts and ends
+    static ref LAST_MAINTENANCE: Arc<RwLock<bool>> = Arc::new(RwLock::new(false));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceReason {
+    Manual,
+    Scheduled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceStatus {
+    pub active: bool,
+    /// Why maintenance mode is on, None when it is off
+    pub reason: Option<MaintenanceReason>,
+    pub manual: bool,
+    pub window_start: Option<u64>,
+    pub window_end: Option<u64>,
+}
+
+/// Whether the exit is in maintenance mode at unix time now
+pub fn maintenance_status(settings: &ExitMaintenanceSettings, now: u64) -> MaintenanceStatus {
+    let scheduled = match (settings.window_start, settings.window_end) {
+        (Some(start), Some(end)) => start <= now && now < end,
+        (Some(start), None) => start <= now,
+        (None, _) => false,
+    };
+    let reason = if settings.manual {
+        Some(MaintenanceReason::Manual)
+    } else if scheduled {
+        Some(MaintenanceReason::Scheduled)
+    } else {
+        None
+    };
+    MaintenanceStatus {
+        active: reason.is_some(),
+        reason,
+        manual: settings.manual,
+        window_start: settings.window_start,
+        window_end: settings.window_end,
+    }
+}
+
+pub fn get_maintenance_status() -> MaintenanceStatus {
+    let now = now_secs();
+    maintenance_status(&settings::get_rita_exit().exit_network.maintenance, now)
+}
+
+/// Called by the exit loop before enforcing, returns true if enforcement is paused
+pub fn check_maintenance() -> bool {
+    let status = get_maintenance_status();
+    let mut last = LAST_MAINTENANCE.write().unwrap();
+    if status.active != *last {
+        if status.active {
+            warn!(
+                "Exit entering maintenance mode {:?}, enforcement is paused until it ends",
+                status
+            );
+        } else {
+            info!("Exit maintenance mode ended, resuming enforcement");
+        }
+        *last = status.active;
+    }
+    status.active
+}
+
+/// Replaces the maintenance settings and saves them, a window has to end after it starts
+pub fn set_maintenance(
+    maintenance: ExitMaintenanceSettings,
+) -> Result<MaintenanceStatus, Box<RitaExitError>> {
+    if let (Some(start), Some(end)) = (maintenance.window_start, maintenance.window_end) {
+        if end <= start {
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "The maintenance window has to end after it starts".to_string(),
+            )));
+        }
+    }
+    if maintenance.window_start.is_none() && maintenance.window_end.is_some() {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "A maintenance window needs a start".to_string(),
+        )));
+    }
+    info!("Exit maintenance set to {:?} by the operator", maintenance);
+    let mut rita_exit = settings::get_rita_exit();
+    rita_exit.exit_network.maintenance = maintenance;
+    settings::set_rita_exit(rita_exit);
+    if let Err(e) = settings::write_config() {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "Failed to save maintenance settings {e:?}"
+        ))));
+    }
+    Ok(get_maintenance_status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maintenance_status() {
+        let mut settings = ExitMaintenanceSettings::default();
+        assert!(!maintenance_status(&settings, 1000).active);
+
+        settings.window_start = Some(1000);
+        settings.window_end = Some(2000);
+        assert!(!maintenance_status(&settings, 999).active);
+        let status = maintenance_status(&settings, 1000);
+        assert!(status.active);
+        assert_eq!(status.reason, Some(MaintenanceReason::Scheduled));
+        assert!(!maintenance_status(&settings, 2000).active);
+
+        // open ended windows last until they are removed
+        settings.window_end = None;
+        assert!(maintenance_status(&settings, 5000).active);
+
+        settings.window_start = None;
+        settings.manual = true;
+        assert_eq!(
+            maintenance_status(&settings, 0).reason,
+            Some(MaintenanceReason::Manual)
+        );
+    }
+}
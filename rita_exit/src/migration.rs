@@ -0,0 +1,238 @@
+//! Graceful migration of this exit's clients to another exit before this one is decommissioned. Without it
+//! clients only leave once the exit stops answering and they time out and fail over to whatever exit
+//! babel likes best. Once the operator starts a migration from the admin api every registered client
+//! is told about the replacement in its status responses and new signups are turned away. Clients that
+//! haven't moved by throttle_after are throttled, and at deadline_after status requests are refused and
+//! everyone left is cut down to the free tier. The migration is kept in exit_network.migration so that
+//! a restart doesn't reset its clock.
+
+use crate::RitaExitError;
+use althea_types::unix_time::now_secs;
+use althea_types::{ExitDenialCode, ExitIdentity, ExitMigration, ExitState};
+use rita_common::debt_keeper::DebtAction;
+use settings::exit::{
+    default_migration_deadline_after, default_migration_throttle_after, ExitMigrationSettings,
+};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+lazy_static! {
+    /// The stage the exit loop last saw, to log when the migration moves on
+    static ref LAST_STAGE: Arc<RwLock<Option<MigrationStage>>> = Arc::new(RwLock::new(None));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStage {
+    /// Clients are told about the replacement, service is unchanged
+    Advertising,
+    /// Clients still here are throttled
+    Throttling,
+    /// Past the deadline, status requests are refused and clients are on the free tier
+    Closed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub replacement: ExitIdentity,
+    pub stage: MigrationStage,
+    pub started: u64,
+    /// Unix timestamp in seconds clients start being throttled
+    pub throttle_at: u64,
+    /// Unix timestamp in seconds the exit stops serving clients
+    pub deadline: u64,
+}
+
+/// Starts a migration, the durations are in seconds from now
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MigrationRequest {
+    pub replacement: ExitIdentity,
+    #[serde(default = "default_migration_throttle_after")]
+    pub throttle_after: u64,
+    #[serde(default = "default_migration_deadline_after")]
+    pub deadline_after: u64,
+}
+
+pub fn migration_status(settings: &ExitMigrationSettings, now: u64) -> MigrationStatus {
+    let throttle_at = settings.started.saturating_add(settings.throttle_after);
+    let deadline = settings.started.saturating_add(settings.deadline_after);
+    let stage = if now >= deadline {
+        MigrationStage::Closed
+    } else if now >= throttle_at {
+        MigrationStage::Throttling
+    } else {
+        MigrationStage::Advertising
+    };
+    MigrationStatus {
+        replacement: settings.replacement.clone(),
+        stage,
+        started: settings.started,
+        throttle_at,
+        deadline,
+    }
+}
+
+/// The migration in progress, None if there isn't one
+pub fn get_migration_status() -> Option<MigrationStatus> {
+    let migration = settings::get_rita_exit().exit_network.migration?;
+    Some(migration_status(&migration, now_secs()))
+}
+
+/// The hint added to the status of registered clients while a migration is in progress
+pub fn migration_hint() -> Option<ExitMigration> {
+    let status = get_migration_status()?;
+    Some(ExitMigration {
+        replacement: status.replacement,
+        deadline: UNIX_EPOCH + Duration::from_secs(status.deadline),
+        throttled: status.stage != MigrationStage::Advertising,
+    })
+}
+
+/// The denial for a client during a migration. New signups are refused from the start, status requests
+/// from registered clients only once the deadline has passed
+pub fn migration_denial(signup: bool) -> Option<ExitState> {
+    let status = get_migration_status()?;
+    if !signup && status.stage != MigrationStage::Closed {
+        return None;
+    }
+    Some(ExitState::Denied {
+        message: format!(
+            "This exit is being retired, please use the exit at {} instead",
+            status.replacement.mesh_ip
+        ),
+        code: Some(ExitDenialCode::Migrated),
+    })
+}
+
+/// Called by the exit loop before enforcing, returns the stage of the migration in progress
+pub fn check_migration() -> Option<MigrationStage> {
+    let stage = get_migration_status().map(|s| s.stage);
+    let mut last = LAST_STAGE.write().unwrap();
+    if stage != *last {
+        match stage {
+            Some(stage) => warn!("Exit migration is now in the {:?} stage", stage),
+            None => info!("Exit migration ended"),
+        }
+        *last = stage;
+    }
+    stage
+}
+
+/// Tightens a client's enforcement for the stage of the migration, never loosens it
+pub fn migration_action(stage: Option<MigrationStage>, action: DebtAction) -> DebtAction {
+    match (stage, action) {
+        (Some(MigrationStage::Closed), DebtAction::OpenTunnel | DebtAction::ThrottleTunnel) => {
+            DebtAction::SuspendTunnel
+        }
+        (Some(MigrationStage::Throttling), DebtAction::OpenTunnel) => DebtAction::ThrottleTunnel,
+        (_, action) => action,
+    }
+}
+
+fn save_migration(migration: Option<ExitMigrationSettings>) -> Result<(), Box<RitaExitError>> {
+    let mut rita_exit = settings::get_rita_exit();
+    rita_exit.exit_network.migration = migration;
+    settings::set_rita_exit(rita_exit);
+    if let Err(e) = settings::write_config() {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "Failed to save migration settings {e:?}"
+        ))));
+    }
+    Ok(())
+}
+
+/// Starts migrating clients to the replacement, replacing any migration already in progress
+pub fn start_migration(request: MigrationRequest) -> Result<MigrationStatus, Box<RitaExitError>> {
+    if settings::get_rita_exit().network.mesh_ip == Some(request.replacement.mesh_ip) {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "An exit can't be migrated to itself".to_string(),
+        )));
+    }
+    if request.deadline_after == 0 || request.throttle_after > request.deadline_after {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Clients have to be throttled before the deadline".to_string(),
+        )));
+    }
+    let migration = ExitMigrationSettings {
+        replacement: request.replacement,
+        started: now_secs(),
+        throttle_after: request.throttle_after,
+        deadline_after: request.deadline_after,
+    };
+    warn!(
+        "Exit migration to {} started by the operator, deadline in {}s",
+        migration.replacement.mesh_ip, migration.deadline_after
+    );
+    let status = migration_status(&migration, migration.started);
+    save_migration(Some(migration))?;
+    Ok(status)
+}
+
+pub fn cancel_migration() -> Result<(), Box<RitaExitError>> {
+    info!("Exit migration cancelled by the operator");
+    save_migration(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_migration_status() {
+        let migration = ExitMigrationSettings {
+            replacement: ExitIdentity {
+                mesh_ip: "fd00::1338".parse().unwrap(),
+                wg_key: "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                    .parse()
+                    .unwrap(),
+                eth_addr: "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"
+                    .parse()
+                    .unwrap(),
+                registration_port: 4875,
+                wg_exit_listen_port: 59998,
+                allowed_regions: HashSet::new(),
+                payment_types: HashSet::new(),
+            },
+            started: 1000,
+            throttle_after: 100,
+            deadline_after: 200,
+        };
+        assert_eq!(
+            migration_status(&migration, 1000).stage,
+            MigrationStage::Advertising
+        );
+        let status = migration_status(&migration, 1100);
+        assert_eq!(status.stage, MigrationStage::Throttling);
+        assert_eq!(status.deadline, 1200);
+        assert_eq!(
+            migration_status(&migration, 1200).stage,
+            MigrationStage::Closed
+        );
+    }
+
+    #[test]
+    fn test_migration_action() {
+        assert_eq!(
+            migration_action(None, DebtAction::OpenTunnel),
+            DebtAction::OpenTunnel
+        );
+        assert_eq!(
+            migration_action(Some(MigrationStage::Advertising), DebtAction::OpenTunnel),
+            DebtAction::OpenTunnel
+        );
+        assert_eq!(
+            migration_action(Some(MigrationStage::Throttling), DebtAction::OpenTunnel),
+            DebtAction::ThrottleTunnel
+        );
+        // already suspended clients stay suspended
+        assert_eq!(
+            migration_action(Some(MigrationStage::Throttling), DebtAction::SuspendTunnel),
+            DebtAction::SuspendTunnel
+        );
+        assert_eq!(
+            migration_action(Some(MigrationStage::Closed), DebtAction::ThrottleTunnel),
+            DebtAction::SuspendTunnel
+        );
+    }
+}
@@ -1,36 +1,66 @@
 //! Network endpoints for rita-exit that are not dashboard or local infromational endpoints
 //! these are called by rita instances to operate the mesh
 
-use crate::database::{client_status, get_exit_info, signup_client};
+use crate::database::{client_status, signup_client};
 #[cfg(feature = "development")]
 use crate::rita_exit::database::db_client::DbClient;
 #[cfg(feature = "development")]
 use crate::rita_exit::database::db_client::TruncateTables;
 
+use crate::backup::{get_backup_status, request_backup};
+use crate::client_overrides::{
+    get_client_overrides, remove_client_override, set_client_override, ClientOverrideRemoval,
+    ClientOverrideRequest,
+};
+use crate::cluster::{get_registered_exit_keys, seal_cluster_config};
+use crate::consistency::get_consistency_report;
+use crate::database::reconcile::audit_client;
+use crate::denylist::{
+    add_to_denylist, get_denylist, remove_from_denylist, DenylistRemoval, DenylistRequest,
+};
+use crate::enforcement::{get_shadow_status, switch_enforcement_backend};
+use crate::exit_list::{get_client_region, get_cluster_health, order_exit_list};
+use crate::health::{get_health_report, HealthState};
+use crate::heartbeat::get_clients_heartbeat_status;
+use crate::isolation::{
+    get_isolated_clients, isolate_client, remove_isolated_client, IsolationRemoval,
+    IsolationRequest,
+};
+use crate::maintenance::{get_maintenance_status, set_maintenance};
+use crate::migration::{cancel_migration, get_migration_status, start_migration, MigrationRequest};
+use crate::promotions::{get_client_promotions, get_promotion_reports};
+use crate::response_cache::{cached_exit_info, cached_registered_exits};
+use crate::sharding::{get_shard_status, note_client_contact};
+use crate::speedtest::{speedtest_allowed, start_speedtest, SpeedtestRefusal, SPEEDTEST_MAX_BYTES};
+use crate::vouchers::redeem_voucher;
 use crate::RitaExitError;
 #[cfg(feature = "development")]
 use actix::SystemService;
 #[cfg(feature = "development")]
 use actix_web::AsyncResponder;
+use actix_web_async::web::{Bytes, Path};
 use actix_web_async::{http::StatusCode, web::Json, HttpRequest, HttpResponse, Result};
 use althea_types::exit_identity_to_id;
 use althea_types::regions::Regions;
+use althea_types::ExitClusterBootstrapRequest;
+use althea_types::ExitDenialCode;
 use althea_types::ExitListV2;
+use althea_types::VoucherRedemption;
 use althea_types::{
     EncryptedExitClientIdentity, EncryptedExitState, ExitClientIdentity, ExitState, ExitSystemTime,
 };
 use althea_types::{EncryptedExitList, Identity};
 use althea_types::{ExitList, WgKey};
+use ipnetwork::IpNetwork;
 use num256::Int256;
-use rita_client_registration::client_db::get_exits_list;
 use rita_common::blockchain_oracle::potential_payment_issues_detected;
 use rita_common::debt_keeper::get_debts_list;
 use rita_common::rita_loop::get_web3_server;
+use settings::exit::{EnforcementBackend, ExitMaintenanceSettings};
 use settings::get_rita_exit;
-use sodiumoxide::crypto::box_;
-use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::Nonce;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::PublicKey;
 use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::SecretKey;
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::time::Duration;
 use std::time::SystemTime;
@@ -45,130 +75,65 @@ fn secure_setup_return(
     our_secretkey: &SecretKey,
     their_pubkey: PublicKey,
 ) -> Json<EncryptedExitState> {
-    let plaintext = serde_json::to_string(&ret)
-        .expect("Failed to serialize ExitState!")
-        .into_bytes();
-    let nonce = box_::gen_nonce();
-    let ciphertext = box_::seal(&plaintext, &nonce, &their_pubkey, our_secretkey);
-    Json(EncryptedExitState {
-        nonce: nonce.0,
-        encrypted_exit_state: ciphertext,
-    })
+    Json(EncryptedExitState::seal(&ret, our_secretkey, &their_pubkey))
 }
 
 enum DecryptResult {
-    Success(Box<ExitClientIdentity>),
-    Failure(Result<Json<EncryptedExitState>, RitaExitError>),
+    /// The client identity and the one of our keys it was sealed to
+    Success(Box<ExitClientIdentity>, SecretKey),
+    Failure(Json<EncryptedExitState>),
+}
+
+/// Our current mesh key followed by the legacy exit key, clients may seal to either
+fn get_exit_secret_keys() -> Vec<SecretKey> {
+    let exit_settings = get_rita_exit();
+    let our_old_secretkey: WgKey = exit_settings.exit_network.wg_private_key;
+    let our_new_secretkey: WgKey = exit_settings.network.wg_private_key.unwrap();
+    vec![our_new_secretkey.into(), our_old_secretkey.into()]
 }
 
 fn decrypt_exit_client_id(
     val: EncryptedExitClientIdentity,
-    our_secretkey: &SecretKey,
+    our_secretkeys: &[SecretKey],
 ) -> DecryptResult {
-    let their_wg_pubkey = val.pubkey;
-    let their_nacl_pubkey = val.pubkey.into();
-    let their_nonce = Nonce(val.nonce);
-    let ciphertext = val.encrypted_exit_client_id;
-
-    let decrypted_bytes =
-        match box_::open(&ciphertext, &their_nonce, &their_nacl_pubkey, our_secretkey) {
-            Ok(value) => value,
-            Err(e) => {
-                warn!(
-                    "Error decrypting exit setup request for {} with {:?}",
-                    their_wg_pubkey, e
-                );
-                let state = ExitState::Denied {
-                    message: "could not decrypt your message!".to_string(),
-                };
-                return DecryptResult::Failure(Ok(secure_setup_return(
-                    state,
-                    our_secretkey,
-                    their_nacl_pubkey,
-                )));
-            }
-        };
-
-    let decrypted_string = match String::from_utf8(decrypted_bytes) {
-        Ok(value) => value,
+    match val.open(our_secretkeys) {
+        Ok((decrypted_id, key)) => DecryptResult::Success(Box::new(decrypted_id), key),
         Err(e) => {
-            error!(
-                "Error decrypting exit setup request for {} with {:?}",
-                their_wg_pubkey, e
+            warn!(
+                "Error opening exit client identity from {} with {}",
+                val.pubkey, e
             );
+            // the reason is only logged, the client just learns that opening failed
             let state = ExitState::Denied {
                 message: "could not decrypt your message!".to_string(),
+                code: Some(ExitDenialCode::BadRequest),
             };
-            return DecryptResult::Failure(Ok(secure_setup_return(
-                state,
-                our_secretkey,
-                their_nacl_pubkey,
-            )));
-        }
-    };
-
-    let decrypted_id = match serde_json::from_str(&decrypted_string) {
-        Ok(value) => value,
-        Err(e) => {
-            error!(
-                "Error deserializing exit setup request for {} with {:?}",
-                their_wg_pubkey, e
-            );
-            let state = ExitState::Denied {
-                message: "could not deserialize your message!".to_string(),
-            };
-            return DecryptResult::Failure(Ok(secure_setup_return(
+            DecryptResult::Failure(secure_setup_return(
                 state,
-                our_secretkey,
-                their_nacl_pubkey,
-            )));
+                &our_secretkeys[0],
+                val.pubkey.into(),
+            ))
         }
-    };
-
-    DecryptResult::Success(Box::new(decrypted_id))
+    }
 }
 
 pub async fn secure_setup_request(
     request: (Json<EncryptedExitClientIdentity>, HttpRequest),
 ) -> HttpResponse {
-    let exit_settings = get_rita_exit();
-
-    let our_old_secretkey: WgKey = exit_settings.exit_network.wg_private_key;
-    let our_new_secretkey = exit_settings.network.wg_private_key.unwrap();
-
-    let our_old_secretkey: SecretKey = our_old_secretkey.into();
-    let our_new_secretkey = our_new_secretkey.into();
-    // The secret key that is used by the client, this value
-    let valid_secret_key;
-
     let their_wg_pubkey = request.0.pubkey;
     let their_nacl_pubkey = request.0.pubkey.into();
     let socket = request.1;
     let exit_client_id = request.0.into_inner();
 
-    let decrypted_id = match (
-        decrypt_exit_client_id(exit_client_id.clone(), &our_new_secretkey),
-        decrypt_exit_client_id(exit_client_id, &our_old_secretkey),
-    ) {
-        (DecryptResult::Success(val_new), DecryptResult::Success(_)) => {
-            valid_secret_key = our_new_secretkey;
-            val_new
-        }
-        (DecryptResult::Success(val), _) => {
-            valid_secret_key = our_new_secretkey;
-            val
-        }
-        (_, DecryptResult::Success(val)) => {
-            valid_secret_key = our_old_secretkey;
-            val
-        }
-        (DecryptResult::Failure(val), _) => match val {
-            Ok(val) => return HttpResponse::Ok().json(val),
-            Err(_) => return HttpResponse::InternalServerError().finish(),
-        },
-    };
+    // The secret key that is used by the client, replies are sealed with the same key
+    let (decrypted_id, valid_secret_key) =
+        match decrypt_exit_client_id(exit_client_id, &get_exit_secret_keys()) {
+            DecryptResult::Success(id, key) => (id, key),
+            DecryptResult::Failure(val) => return HttpResponse::Ok().json(val),
+        };
 
     info!("Received Encrypted setup request from, {}", their_wg_pubkey);
+    note_client_contact(their_wg_pubkey);
 
     let remote_mesh_socket: SocketAddr = match socket.peer_addr() {
         Some(val) => val,
@@ -204,6 +169,7 @@ pub async fn secure_setup_request(
     } else {
         let state = ExitState::Denied {
             message: "The request ip does not match the signup ip".to_string(),
+            code: Some(ExitDenialCode::IpMismatch),
         };
         HttpResponse::Ok().json(secure_setup_return(
             state,
@@ -215,12 +181,6 @@ pub async fn secure_setup_request(
 
 pub async fn secure_status_request(request: Json<EncryptedExitClientIdentity>) -> HttpResponse {
     let exit_settings = get_rita_exit();
-    let our_old_secretkey: WgKey = exit_settings.exit_network.wg_private_key;
-    let our_new_secretkey = exit_settings.network.wg_private_key.unwrap();
-
-    let our_old_secretkey = our_old_secretkey.into();
-    let our_new_secretkey = our_new_secretkey.into();
-
     let our_address = exit_settings
         .payment
         .eth_private_key
@@ -232,32 +192,16 @@ pub async fn secure_status_request(request: Json<EncryptedExitClientIdentity>) -
     let their_wg_pubkey = request.pubkey;
     let their_nacl_pubkey = request.pubkey.into();
     let exit_client_id = request.into_inner();
-    // The secret key that is used by the client, this value
-    let valid_secret_key;
-
-    let decrypted_id = match (
-        decrypt_exit_client_id(exit_client_id.clone(), &our_new_secretkey),
-        decrypt_exit_client_id(exit_client_id, &our_old_secretkey),
-    ) {
-        (DecryptResult::Success(val_new), DecryptResult::Success(_)) => {
-            valid_secret_key = our_new_secretkey;
-            val_new
-        }
-        (DecryptResult::Success(val), _) => {
-            valid_secret_key = our_new_secretkey;
-            val
-        }
-        (_, DecryptResult::Success(val)) => {
-            valid_secret_key = our_old_secretkey;
-            val
-        }
-        (DecryptResult::Failure(val), _) => match val {
-            Ok(val) => return HttpResponse::Ok().json(val),
-            Err(_) => return HttpResponse::InternalServerError().finish(),
-        },
-    };
+
+    // The secret key that is used by the client, replies are sealed with the same key
+    let (decrypted_id, valid_secret_key) =
+        match decrypt_exit_client_id(exit_client_id, &get_exit_secret_keys()) {
+            DecryptResult::Success(id, key) => (id, key),
+            DecryptResult::Failure(val) => return HttpResponse::Ok().json(val),
+        };
 
     trace!("got status request from {}", their_wg_pubkey);
+    note_client_contact(their_wg_pubkey);
 
     // We use our eth address as the requesting address
     let state = match client_status(*decrypted_id, our_address, contract_addr, &contact).await {
@@ -287,7 +231,7 @@ pub async fn secure_status_request(request: Json<EncryptedExitClientIdentity>) -
 
 pub async fn get_exit_info_http(_req: HttpRequest) -> HttpResponse {
     HttpResponse::Ok().json(ExitState::GotInfo {
-        general_details: get_exit_info(),
+        general_details: cached_exit_info(),
         message: "Got info successfully".to_string(),
     })
 }
@@ -321,7 +265,7 @@ pub async fn get_exit_list(request: Json<EncryptedExitClientIdentity>) -> HttpRe
     let contract_addr = rita_exit.exit_network.registered_users_contract_addr;
 
     let ret: ExitList = ExitList {
-        exit_list: match get_exits_list(&contact, our_addr, contract_addr).await {
+        exit_list: match cached_registered_exits(&contact, our_addr, contract_addr).await {
             Ok(a) => {
                 let exit_regions = rita_exit.network.allowed_countries;
                 let accepted_payments = rita_exit.network.payment_chains;
@@ -364,19 +308,15 @@ pub async fn get_exit_list(request: Json<EncryptedExitClientIdentity>) -> HttpRe
         wg_exit_listen_port: settings::get_rita_exit().exit_network.wg_v2_tunnel_port,
     };
 
-    let plaintext = serde_json::to_string(&ret)
-        .expect("Failed to serialize Vec of ips!")
-        .into_bytes();
-    let nonce = box_::gen_nonce();
-    let ciphertext = box_::seal(&plaintext, &nonce, &their_nacl_pubkey, &our_secretkey);
-    HttpResponse::Ok().json(Json(EncryptedExitList {
-        nonce: nonce.0,
-        exit_list: ciphertext,
-    }))
+    HttpResponse::Ok().json(Json(EncryptedExitList::seal(
+        &ret,
+        &our_secretkey,
+        &their_nacl_pubkey,
+    )))
 }
 
 /// Exit list v2, for newer router that do the fitering (region and payment type) themselves, this endpoint
-/// returns the entire list
+/// returns the entire list, ordered and weighted for the requesting client
 pub async fn get_exit_list_v2(request: Json<EncryptedExitClientIdentity>) -> HttpResponse {
     let exit_settings = get_rita_exit();
     let our_secretkey: WgKey = match exit_settings.network.wg_private_key {
@@ -388,7 +328,18 @@ pub async fn get_exit_list_v2(request: Json<EncryptedExitClientIdentity>) -> Htt
     };
     let our_secretkey = our_secretkey.into();
 
+    let their_wg_pubkey = request.pubkey;
     let their_nacl_pubkey = request.pubkey.into();
+    let client_region = match request.open(&get_exit_secret_keys()) {
+        Ok((client, _)) => get_client_region(client.global.mesh_ip),
+        Err(e) => {
+            warn!(
+                "Could not open exit list request from {} {}",
+                their_wg_pubkey, e
+            );
+            None
+        }
+    };
 
     let contact = Web3::new(&get_web3_server(), CLIENT_STATUS_TIMEOUT);
     let rita_exit = get_rita_exit();
@@ -399,29 +350,36 @@ pub async fn get_exit_list_v2(request: Json<EncryptedExitClientIdentity>) -> Htt
         .to_address();
     let contract_addr = rita_exit.exit_network.registered_users_contract_addr;
 
-    let mut ret: ExitListV2 = ExitListV2 {
-        exit_list: match get_exits_list(&contact, our_addr, contract_addr).await {
-            Ok(a) => a,
-            Err(e) => {
-                error!(
-                    "Unable to retreive the exit list with {}, returning empty list",
-                    e
-                );
-                vec![]
-            }
-        },
+    let mut exits = match cached_registered_exits(&contact, our_addr, contract_addr).await {
+        Ok(a) => a,
+        Err(e) => {
+            error!(
+                "Unable to retreive the exit list with {}, returning empty list",
+                e
+            );
+            vec![]
+        }
     };
-    ret.exit_list.push(exit_settings.get_exit_identity()); // add ourselves to the list
+    exits.push(exit_settings.get_exit_identity()); // add ourselves to the list
+    let ret: ExitListV2 =
+        order_exit_list(exits, their_wg_pubkey, client_region, &get_cluster_health());
+
+    HttpResponse::Ok().json(Json(EncryptedExitList::seal(
+        &ret,
+        &our_secretkey,
+        &their_nacl_pubkey,
+    )))
+}
 
-    let plaintext = serde_json::to_string(&ret)
-        .expect("Failed to serialize Vec of ips!")
-        .into_bytes();
-    let nonce = box_::gen_nonce();
-    let ciphertext = box_::seal(&plaintext, &nonce, &their_nacl_pubkey, &our_secretkey);
-    HttpResponse::Ok().json(Json(EncryptedExitList {
-        nonce: nonce.0,
-        exit_list: ciphertext,
-    }))
+/// The health of this exit and the reasons for it, load balancers should stop sending clients here
+/// while it is failing, which is also the only state that is not a 200. The errors behind each reason
+/// are only logged, anyone on the mesh can read this
+pub async fn get_exit_health(_req: HttpRequest) -> HttpResponse {
+    let report = get_health_report().without_errors();
+    match report.state {
+        HealthState::Failing => HttpResponse::ServiceUnavailable().json(report),
+        HealthState::Healthy | HealthState::Degraded => HttpResponse::Ok().json(report),
+    }
 }
 
 /// Used by clients to get their debt from the exits. While it is in theory possible for the
@@ -472,3 +430,438 @@ pub async fn get_client_debt(client: Json<Identity>) -> HttpResponse {
     }
     HttpResponse::NotFound().json("No client by that ID")
 }
+
+/// Used by clients to redeem an operator signed prepaid voucher, the credit shows up in the debt the
+/// client fetches from get_client_debt
+pub async fn redeem_voucher_http(redemption: Json<VoucherRedemption>) -> HttpResponse {
+    match redeem_voucher(redemption.into_inner()) {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => {
+            warn!("Failed to redeem voucher {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// Returns the address of a speedtest requester if it is a client connecting over the exit tunnel,
+/// the test is only offered to our own clients and never to the wider mesh or internet
+fn speedtest_client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let ip = req.peer_addr()?.ip();
+    let exit_network = get_rita_exit().exit_network;
+    let tunnel_v4 =
+        IpNetwork::new(exit_network.own_internal_ip.into(), exit_network.netmask).ok()?;
+    if tunnel_v4.contains(ip) || exit_network.subnet.map(|s| s.contains(ip)).unwrap_or(false) {
+        Some(ip)
+    } else {
+        None
+    }
+}
+
+fn speedtest_refused(refusal: SpeedtestRefusal) -> HttpResponse {
+    match refusal {
+        SpeedtestRefusal::TooSoon(wait) => HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+            .json(format!("Try again in {} seconds", wait.as_secs() + 1)),
+        SpeedtestRefusal::NoSlot => {
+            HttpResponse::build(StatusCode::TOO_MANY_REQUESTS).json("Start a bandwidth test first")
+        }
+    }
+}
+
+/// Claims a bandwidth test slot for the requesting client
+pub async fn speedtest_start(req: HttpRequest) -> HttpResponse {
+    let ip = match speedtest_client_ip(&req) {
+        Some(ip) => ip,
+        None => return HttpResponse::Forbidden().json("Only available over the exit tunnel"),
+    };
+    match start_speedtest(ip) {
+        Ok(()) => HttpResponse::Ok().json(SPEEDTEST_MAX_BYTES),
+        Err(e) => speedtest_refused(e),
+    }
+}
+
+/// Sends the requested number of bytes of test data, capped at SPEEDTEST_MAX_BYTES
+pub async fn speedtest_download(req: HttpRequest, bytes: Path<usize>) -> HttpResponse {
+    let ip = match speedtest_client_ip(&req) {
+        Some(ip) => ip,
+        None => return HttpResponse::Forbidden().json("Only available over the exit tunnel"),
+    };
+    if let Err(e) = speedtest_allowed(ip) {
+        return speedtest_refused(e);
+    }
+    let bytes = bytes.into_inner().min(SPEEDTEST_MAX_BYTES);
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(vec![0u8; bytes])
+}
+
+/// Receives test data and replies with the number of bytes received, the payload size is capped
+/// where this route is registered
+pub async fn speedtest_upload(req: HttpRequest, body: Bytes) -> HttpResponse {
+    let ip = match speedtest_client_ip(&req) {
+        Some(ip) => ip,
+        None => return HttpResponse::Forbidden().json("Only available over the exit tunnel"),
+    };
+    if let Err(e) = speedtest_allowed(ip) {
+        return speedtest_refused(e);
+    }
+    HttpResponse::Ok().json(body.len())
+}
+
+/// Lists registered clients along with when we last received a heartbeat from them
+pub async fn get_exit_clients(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_clients_heartbeat_status())
+}
+
+/// Compares the wg peers, host routes and isolation rules the kernel has for a client with what the
+/// client list says it should have
+pub async fn get_client_audit(wg_key: Path<WgKey>) -> HttpResponse {
+    match audit_client(wg_key.into_inner()) {
+        Ok(audit) => HttpResponse::Ok().json(audit),
+        Err(e) => {
+            warn!("Failed to audit client {}", e);
+            HttpResponse::InternalServerError().json(e.to_string())
+        }
+    }
+}
+
+/// Lists the clients the operator has banned from this exit
+pub async fn get_client_denylist(_req: HttpRequest) -> HttpResponse {
+    match get_denylist() {
+        Ok(denylist) => HttpResponse::Ok().json(denylist),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+/// Bans a client by wg key, eth address or both, their tunnel is removed on the next exit loop
+pub async fn add_denylist_entry(request: Json<DenylistRequest>) -> HttpResponse {
+    match add_to_denylist(request.into_inner()) {
+        Ok(entry) => HttpResponse::Ok().json(entry),
+        Err(e) => {
+            warn!("Failed to add denylist entry {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// Lifts every ban on the given wg key or eth address
+pub async fn remove_denylist_entry(removal: Json<DenylistRemoval>) -> HttpResponse {
+    match remove_from_denylist(removal.into_inner()) {
+        Ok(removed) => HttpResponse::Ok().json(removed),
+        Err(e) => {
+            warn!("Failed to remove denylist entry {}", e);
+            HttpResponse::InternalServerError().json(e.to_string())
+        }
+    }
+}
+
+/// Lists the per client overrides of the region check and enforcement
+pub async fn get_client_override_list(_req: HttpRequest) -> HttpResponse {
+    match get_client_overrides() {
+        Ok(overrides) => HttpResponse::Ok().json(overrides),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+/// Exempts a client from the region check, enforcement or both, replacing any override it had
+pub async fn add_client_override(request: Json<ClientOverrideRequest>) -> HttpResponse {
+    match set_client_override(request.into_inner()) {
+        Ok(entry) => HttpResponse::Ok().json(entry),
+        Err(e) => {
+            warn!("Failed to add client override {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// Removes a client's override, its checks apply again on the next exit loop
+pub async fn delete_client_override(removal: Json<ClientOverrideRemoval>) -> HttpResponse {
+    match remove_client_override(removal.into_inner()) {
+        Ok(removed) => HttpResponse::Ok().json(removed),
+        Err(e) => {
+            warn!("Failed to remove client override {}", e);
+            HttpResponse::InternalServerError().json(e.to_string())
+        }
+    }
+}
+
+/// Each configured promotion and what it gave clients this month
+pub async fn get_promotions(_req: HttpRequest) -> HttpResponse {
+    match get_promotion_reports() {
+        Ok(reports) => HttpResponse::Ok().json(reports),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+/// How scheduled backups are going and the backups in the bucket
+pub async fn get_exit_backups(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_backup_status())
+}
+
+/// Takes a backup on the next exit loop tick
+pub async fn run_exit_backup(_req: HttpRequest) -> HttpResponse {
+    match request_backup() {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => HttpResponse::BadRequest().json(e.to_string()),
+    }
+}
+
+/// Settings that are never handed out by the admin api, as json pointers into the exit settings
+const SECRET_SETTINGS: [&str; 8] = [
+    "/exit_network/admin_api/password",
+    "/exit_network/cluster_bootstrap/password",
+    "/exit_network/wg_private_key",
+    "/exit_network/pass",
+    "/exit_network/geoip_api_key",
+    "/network/wg_private_key",
+    "/network/rita_dashboard_password",
+    "/payment/eth_private_key",
+];
+/// Takes the place of a secret in the settings the admin api returns
+const REDACTED: &str = "<redacted>";
+
+/// Replaces every secret that is set with REDACTED
+fn redact_settings(settings: &mut serde_json::Value) {
+    for pointer in SECRET_SETTINGS {
+        if let Some(value) = settings.pointer_mut(pointer) {
+            if !value.is_null() {
+                *value = REDACTED.into();
+            }
+        }
+    }
+}
+
+/// Drops secrets still set to REDACTED so that posting back settings fetched from the admin api
+/// doesn't overwrite them
+fn drop_redacted_settings(settings: &mut serde_json::Value) {
+    for pointer in SECRET_SETTINGS {
+        let (parent, field) = pointer.rsplit_once('/').unwrap();
+        if let Some(serde_json::Value::Object(parent)) = settings.pointer_mut(parent) {
+            if parent.get(field).and_then(|v| v.as_str()) == Some(REDACTED) {
+                parent.remove(field);
+            }
+        }
+    }
+}
+
+/// The exit settings with secrets redacted
+pub async fn get_exit_settings(_req: HttpRequest) -> HttpResponse {
+    match settings::get_config_json() {
+        Ok(mut settings) => {
+            redact_settings(&mut settings);
+            HttpResponse::Ok().json(settings)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("Unable to get config: {e}")),
+    }
+}
+
+/// Merges a subset of the exit settings, redacted secrets are left as they are
+pub async fn set_exit_settings(new_settings: Json<serde_json::Value>) -> HttpResponse {
+    let mut new_settings = new_settings.into_inner();
+    drop_redacted_settings(&mut new_settings);
+    if let Err(e) = settings::merge_config_json(new_settings) {
+        return HttpResponse::InternalServerError().json(format!("Unable to set settings: {e}"));
+    }
+    HttpResponse::Ok().finish()
+}
+
+/// What promotions gave a client in each month
+pub async fn get_client_promotion_history(wg_key: Path<WgKey>) -> HttpResponse {
+    match get_client_promotions(&wg_key.into_inner()) {
+        Ok(applied) => HttpResponse::Ok().json(applied),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+/// Lists the clients isolated from the rest of the mesh
+pub async fn get_client_isolation(_req: HttpRequest) -> HttpResponse {
+    match get_isolated_clients() {
+        Ok(isolated) => HttpResponse::Ok().json(isolated),
+        Err(e) => HttpResponse::InternalServerError().json(e.to_string()),
+    }
+}
+
+/// Isolates a client, or changes its public address if it already is, applied on the next exit loop
+pub async fn add_isolated_client(request: Json<IsolationRequest>) -> HttpResponse {
+    match isolate_client(request.into_inner()) {
+        Ok(client) => HttpResponse::Ok().json(client),
+        Err(e) => {
+            warn!("Failed to isolate client {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// Returns a client to the shared routing table
+pub async fn remove_client_isolation(removal: Json<IsolationRemoval>) -> HttpResponse {
+    match remove_isolated_client(removal.into_inner()) {
+        Ok(removed) => HttpResponse::Ok().json(removed),
+        Err(e) => {
+            warn!("Failed to remove client isolation {}", e);
+            HttpResponse::InternalServerError().json(e.to_string())
+        }
+    }
+}
+
+/// Whether enforcement is paused for maintenance, and the configured manual toggle and window
+pub async fn get_exit_maintenance(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_maintenance_status())
+}
+
+/// Replaces the maintenance toggle and window, takes effect on the next exit loop
+pub async fn set_exit_maintenance(maintenance: Json<ExitMaintenanceSettings>) -> HttpResponse {
+    match set_maintenance(maintenance.into_inner()) {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => {
+            warn!("Failed to set exit maintenance {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// The migration of our clients to another exit in progress, null if there isn't one
+pub async fn get_exit_migration(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_migration_status())
+}
+
+/// Starts moving every client to the replacement exit, clients are told right away
+pub async fn start_exit_migration(request: Json<MigrationRequest>) -> HttpResponse {
+    match start_migration(request.into_inner()) {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => {
+            warn!("Failed to start exit migration {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// Ends the migration in progress, clients still here are served as usual again
+pub async fn stop_exit_migration(_req: HttpRequest) -> HttpResponse {
+    match cancel_migration() {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            warn!("Failed to cancel exit migration {}", e);
+            HttpResponse::InternalServerError().json(e.to_string())
+        }
+    }
+}
+
+/// The active and shadow enforcement backends and how long the shadow has agreed with the active one
+pub async fn get_enforcement_shadow(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_shadow_status())
+}
+
+/// Switches to the shadow enforcement backend, refused until it has agreed for enforcement_shadow_ticks
+pub async fn set_enforcement_backend(backend: Path<EnforcementBackend>) -> HttpResponse {
+    match switch_enforcement_backend(backend.into_inner()) {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(e) => {
+            warn!("Failed to switch enforcement backend {}", e);
+            HttpResponse::BadRequest().json(e.to_string())
+        }
+    }
+}
+
+/// Hands our cluster config to a new exit bootstrapping from us, sealed to its mesh wg key. Only exits
+/// registered in the exit contract are answered
+pub async fn get_cluster_bootstrap(request: Json<ExitClusterBootstrapRequest>) -> HttpResponse {
+    let request = request.into_inner();
+    match get_registered_exit_keys().await {
+        Ok(keys) => {
+            if !keys.contains(&request.wg_key) {
+                warn!(
+                    "Refused cluster bootstrap for unregistered exit {}",
+                    request.wg_key
+                );
+                return HttpResponse::Forbidden().json("Not a registered exit");
+            }
+        }
+        Err(e) => {
+            error!("Failed to check cluster bootstrap request {}", e);
+            return HttpResponse::InternalServerError().json(e.to_string());
+        }
+    }
+    match seal_cluster_config(request) {
+        Ok(sealed) => {
+            info!("Sent cluster config to new exit {}", request.wg_key);
+            HttpResponse::Ok().json(sealed)
+        }
+        Err(e) => {
+            error!("Failed to seal cluster config {}", e);
+            HttpResponse::InternalServerError().json(e.to_string())
+        }
+    }
+}
+
+/// Which exits we are sharding clients with and how many clients we serve
+pub async fn get_exit_shard_status(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_shard_status())
+}
+
+/// The price in wei per byte this exit charges its clients
+pub async fn get_exit_price(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_rita_exit().exit_network.exit_price)
+}
+
+/// Sets the price in wei per byte charged to clients, traffic is billed at the new price from the
+/// next traffic watcher run
+pub async fn set_exit_price(path: Path<u64>) -> HttpResponse {
+    let new_price = path.into_inner();
+    info!("Exit price set to {} by the operator", new_price);
+    let mut rita_exit = get_rita_exit();
+    rita_exit.exit_network.exit_price = new_price;
+    settings::set_rita_exit(rita_exit);
+    if let Err(e) = settings::write_config() {
+        return HttpResponse::InternalServerError().json(format!("{e:?}"));
+    }
+    HttpResponse::Ok().json(())
+}
+
+/// Findings of the last registration consistency audit, including which clients are quarantined
+pub async fn get_consistency_audit(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(get_consistency_report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_settings() {
+        let mut settings = serde_json::json!({
+            "exit_network": {
+                "admin_api": {"bind_address": "[::1]:4879", "password": "hunter2"},
+                "cluster_bootstrap": {"member_url": "http://10.0.0.2:4879", "password": "hunter3"},
+                "pass": null,
+                "exit_price": 50
+            },
+            "payment": {"eth_private_key": "0x01"}
+        });
+        redact_settings(&mut settings);
+        assert_eq!(settings["exit_network"]["admin_api"]["password"], REDACTED);
+        assert_eq!(
+            settings["exit_network"]["admin_api"]["bind_address"],
+            "[::1]:4879"
+        );
+        assert_eq!(
+            settings["exit_network"]["cluster_bootstrap"]["password"],
+            REDACTED
+        );
+        assert_eq!(
+            settings["exit_network"]["cluster_bootstrap"]["member_url"],
+            "http://10.0.0.2:4879"
+        );
+        assert_eq!(settings["exit_network"]["pass"], serde_json::Value::Null);
+        assert_eq!(settings["payment"]["eth_private_key"], REDACTED);
+
+        // posting it back leaves the secrets alone but applies everything else
+        drop_redacted_settings(&mut settings);
+        assert!(settings["exit_network"]["admin_api"]
+            .get("password")
+            .is_none());
+        assert!(settings["exit_network"]["cluster_bootstrap"]
+            .get("password")
+            .is_none());
+        assert!(settings["payment"].get("eth_private_key").is_none());
+        assert_eq!(settings["exit_network"]["exit_price"], 50);
+    }
+}
@@ -1,6 +1,7 @@
 //! This module is responsible for checking in with the operator server and getting updated local settings
 pub mod update_loop;
 use althea_types::OperatorExitCheckinMessage;
+use rita_common::capabilities::get_capabilities;
 use rita_common::KI;
 use std::time::{Duration, Instant};
 
@@ -50,6 +51,7 @@ pub async fn operator_update(rita_started: Instant) {
                 exit_uptime: rita_started.elapsed(),
                 // Since this checkin works only from b20, we only need to look on wg_exit_v2
                 users_online: KI.get_wg_exit_clients_online(EXIT_INTERFACE).ok(),
+                capabilities: get_capabilities(),
             })
             .await;
         match response {
@@ -0,0 +1,227 @@
+//! Preflight checks of the OS settings an exit depends on. An exit with ip forwarding off, strict
+//! reverse path filtering or the conntrack and nat modules missing sets up clients without error and
+//! then drops their traffic, which is hard to tell apart from a network problem. The checks run once
+//! at startup before clients are served and again every PREFLIGHT_INTERVAL from the exit loop, anything
+//! wrong is fixed through the KernelInterface where that is safe and what can't be fixed is reported as
+//! a degraded exit in /health.
+
+use crate::health::{record_health, HealthCheck};
+use crate::rita_loop::{EXIT_INTERFACE, LEGACY_INTERFACE};
+use rita_common::KI;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often the exit loop repeats the checks, something else on the system may change these
+pub const PREFLIGHT_INTERVAL: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    static ref LAST_PREFLIGHT: Arc<RwLock<Option<Instant>>> = Arc::new(RwLock::new(None));
+}
+
+struct SysctlCheck {
+    key: &'static str,
+    /// Values the exit works with
+    accepted: &'static [&'static str],
+    /// Value set when the current one isn't accepted
+    fix: &'static str,
+}
+
+const FORWARDING_CHECKS: [SysctlCheck; 2] = [
+    SysctlCheck {
+        key: "net.ipv4.ip_forward",
+        accepted: &["1"],
+        fix: "1",
+    },
+    SysctlCheck {
+        key: "net.ipv6.conf.all.forwarding",
+        accepted: &["1"],
+        fix: "1",
+    },
+];
+
+/// Strict reverse path filtering drops client traffic arriving over the exit tunnels, loose mode is
+/// as safe for an exit since the tunnels only accept the client's own addresses
+const RP_FILTER_ACCEPTED: &[&str] = &["0", "2"];
+const RP_FILTER_FIX: &str = "2";
+
+/// Modules needed for nat and for the exit tunnels, loaded with modprobe if they are missing
+const REQUIRED_MODULES: [&str; 3] = ["nf_conntrack", "nf_nat", "wireguard"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreflightProblem {
+    /// The sysctl key or module name
+    pub item: String,
+    /// The value found, None for a missing module or a sysctl that couldn't be read
+    pub found: Option<String>,
+    pub wanted: String,
+    pub fixed: bool,
+}
+
+impl PreflightProblem {
+    fn describe(&self) -> String {
+        format!(
+            "{} is {} but should be {}",
+            self.item,
+            self.found.as_deref().unwrap_or("missing"),
+            self.wanted
+        )
+    }
+}
+
+/// The sysctls to check, rp_filter is checked on the exit tunnels too since the kernel uses the
+/// stricter of the interface's setting and the all setting
+fn sysctl_checks() -> Vec<SysctlCheck> {
+    let mut checks: Vec<SysctlCheck> = FORWARDING_CHECKS.into_iter().collect();
+    for key in [
+        "net.ipv4.conf.all.rp_filter",
+        "net.ipv4.conf.default.rp_filter",
+    ] {
+        checks.push(SysctlCheck {
+            key,
+            accepted: RP_FILTER_ACCEPTED,
+            fix: RP_FILTER_FIX,
+        });
+    }
+    checks
+}
+
+fn tunnel_rp_filter_keys() -> Vec<String> {
+    [LEGACY_INTERFACE, EXIT_INTERFACE]
+        .iter()
+        .map(|iface| format!("net.ipv4.conf.{iface}.rp_filter"))
+        .collect()
+}
+
+fn is_accepted(accepted: &[&str], found: &str) -> bool {
+    accepted.contains(&found.trim())
+}
+
+/// Checks one sysctl and sets it if needed, None if it is fine
+fn check_sysctl(key: &str, accepted: &[&str], fix: &str) -> Option<PreflightProblem> {
+    let found = match KI.get_sysctl(key) {
+        Ok(found) if is_accepted(accepted, &found) => return None,
+        Ok(found) => Some(found),
+        Err(e) => {
+            warn!("Preflight could not read {} {:?}", key, e);
+            None
+        }
+    };
+    let fixed = match KI.set_sysctl(key, fix) {
+        Ok(()) => KI
+            .get_sysctl(key)
+            .map(|v| is_accepted(accepted, &v))
+            .unwrap_or(false),
+        Err(e) => {
+            error!("Preflight could not set {} to {} {:?}", key, fix, e);
+            false
+        }
+    };
+    Some(PreflightProblem {
+        item: key.to_string(),
+        found,
+        wanted: accepted.join(" or "),
+        fixed,
+    })
+}
+
+fn check_module(module: &str) -> Option<PreflightProblem> {
+    if KI.is_module_loaded(module) {
+        return None;
+    }
+    let fixed = match KI.load_module(module) {
+        Ok(()) => KI.is_module_loaded(module),
+        Err(e) => {
+            error!("Preflight could not load {} {:?}", module, e);
+            false
+        }
+    };
+    Some(PreflightProblem {
+        item: module.to_string(),
+        found: None,
+        wanted: "loaded".to_string(),
+        fixed,
+    })
+}
+
+/// Runs every check, fixing what it can, and records what is left over in the exit health
+pub fn run_preflight_checks() -> Vec<PreflightProblem> {
+    *LAST_PREFLIGHT.write().unwrap() = Some(Instant::now());
+
+    // modules first, the conntrack sysctls only exist once it is loaded
+    let mut problems: Vec<PreflightProblem> = REQUIRED_MODULES
+        .iter()
+        .filter_map(|module| check_module(module))
+        .collect();
+    for check in sysctl_checks() {
+        problems.extend(check_sysctl(check.key, check.accepted, check.fix));
+    }
+    for key in tunnel_rp_filter_keys() {
+        // the tunnels don't exist until the exit loop sets them up
+        if KI.get_sysctl(&key).is_ok() {
+            problems.extend(check_sysctl(&key, RP_FILTER_ACCEPTED, RP_FILTER_FIX));
+        }
+    }
+
+    for problem in problems.iter() {
+        if problem.fixed {
+            warn!("Preflight fixed {}", problem.describe());
+        } else {
+            error!("Preflight could not fix {}", problem.describe());
+        }
+    }
+    record_health(HealthCheck::Preflight, preflight_result(&problems));
+    problems
+}
+
+fn preflight_result(problems: &[PreflightProblem]) -> Result<(), String> {
+    let unresolved: Vec<String> = problems
+        .iter()
+        .filter(|p| !p.fixed)
+        .map(|p| p.describe())
+        .collect();
+    if unresolved.is_empty() {
+        Ok(())
+    } else {
+        Err(unresolved.join(", "))
+    }
+}
+
+/// Called every exit loop tick, reruns the checks once PREFLIGHT_INTERVAL has passed
+pub fn tick_preflight_checks() {
+    if let Some(last) = *LAST_PREFLIGHT.read().unwrap() {
+        if last.elapsed() < PREFLIGHT_INTERVAL {
+            return;
+        }
+    }
+    run_preflight_checks();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preflight_result() {
+        assert!(is_accepted(RP_FILTER_ACCEPTED, "2\n"));
+        assert!(!is_accepted(RP_FILTER_ACCEPTED, "1"));
+        assert!(preflight_result(&[]).is_ok());
+
+        let fixed = PreflightProblem {
+            item: "net.ipv4.ip_forward".to_string(),
+            found: Some("0".to_string()),
+            wanted: "1".to_string(),
+            fixed: true,
+        };
+        assert!(preflight_result(&[fixed.clone()]).is_ok());
+        let missing = PreflightProblem {
+            item: "nf_nat".to_string(),
+            found: None,
+            wanted: "loaded".to_string(),
+            fixed: false,
+        };
+        assert_eq!(
+            preflight_result(&[fixed, missing]),
+            Err("nf_nat is missing but should be loaded".to_string())
+        );
+    }
+}
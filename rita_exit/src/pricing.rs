@@ -0,0 +1,91 @@
+//! Exits serving several countries can charge each of them a different price. The exit loop records the
+//! country geoip places each client's gateway in, billing and the exit details handed to a client then use
+//! the price configured for that country in exit_network.country_prices, or exit_price for clients in any
+//! other country or whose country isn't known yet.
+
+use crate::response_cache::cached_exit_info;
+use althea_types::regions::Regions;
+use althea_types::{ExitDetails, Identity, WgKey};
+use settings::exit::ExitNetworkSettings;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// The country of each client's gateway as of the last region check
+    static ref CLIENT_COUNTRIES: Arc<RwLock<HashMap<WgKey, Regions>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// The price for clients in a country, exit_price if it has none of its own
+pub fn price_for_country(exit_network: &ExitNetworkSettings, country: Option<Regions>) -> u64 {
+    country
+        .and_then(|country| {
+            exit_network
+                .country_prices
+                .iter()
+                .find(|price| price.country == country)
+        })
+        .map(|price| price.price)
+        .unwrap_or(exit_network.exit_price)
+}
+
+pub fn record_client_country(key: WgKey, country: Regions) {
+    CLIENT_COUNTRIES.write().unwrap().insert(key, country);
+}
+
+pub fn get_client_country(key: &WgKey) -> Option<Regions> {
+    CLIENT_COUNTRIES.read().unwrap().get(key).copied()
+}
+
+/// Forgets the countries of clients that are no longer registered
+pub fn prune_client_countries(registered: &[Identity]) {
+    let registered: HashSet<WgKey> = registered.iter().map(|id| id.wg_public_key).collect();
+    CLIENT_COUNTRIES
+        .write()
+        .unwrap()
+        .retain(|key, _| registered.contains(key));
+}
+
+/// The price a client is billed per byte
+pub fn client_price(exit_network: &ExitNetworkSettings, key: &WgKey) -> u64 {
+    if exit_network.country_prices.is_empty() {
+        return exit_network.exit_price;
+    }
+    price_for_country(exit_network, get_client_country(key))
+}
+
+/// The exit details as a client should see them, with the price it is billed
+pub fn client_exit_info(key: &WgKey) -> ExitDetails {
+    let mut info = cached_exit_info();
+    info.exit_price = client_price(&settings::get_rita_exit_snapshot().exit_network, key);
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use althea_types::regions::CountryPrice;
+
+    #[test]
+    fn test_price_for_country() {
+        let mut exit_network = ExitNetworkSettings::test_default();
+        exit_network.exit_price = 10;
+        exit_network.country_prices = vec![
+            CountryPrice {
+                country: Regions::Colombia,
+                price: 5,
+            },
+            CountryPrice {
+                country: Regions::UnitedStates,
+                price: 20,
+            },
+        ];
+        assert_eq!(price_for_country(&exit_network, Some(Regions::Colombia)), 5);
+        assert_eq!(
+            price_for_country(&exit_network, Some(Regions::UnitedStates)),
+            20
+        );
+        assert_eq!(price_for_country(&exit_network, Some(Regions::Canada)), 10);
+        assert_eq!(price_for_country(&exit_network, None), 10);
+    }
+}
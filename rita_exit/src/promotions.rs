@@ -0,0 +1,351 @@
+//! Operator configured promotions such as "the first 10GB each month are free", see
+//! settings::exit::ExitPromotion. watch_exit_traffic bills each client's traffic through
+//! PromotionBilling, which takes the promotions off the exit's own price and records what each one
+//! gave each client per calendar month. Only the exit's share of a bill is discounted, what is
+//! forwarded to the mesh for a client's traffic is billed in full. The records are kept on disk in
+//! exit_network.promotions_file so that free monthly bytes aren't handed out again after a restart.
+
+use crate::RitaExitError;
+use althea_types::unix_time::now_secs;
+use althea_types::WgKey;
+use rita_common::utils::json_file::{cached_json_file, save_json_file};
+use settings::exit::{ExitPromotion, PromotionRule};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often the records are written out while they change, a crash loses at most this much of the
+/// free bytes used
+const PROMOTION_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// Months of records kept for each client
+const MONTHS_KEPT: u32 = 12;
+
+lazy_static! {
+    /// The applied promotions, None until loaded from disk
+    static ref PROMOTION_LEDGER: Arc<RwLock<Option<PromotionLedger>>> =
+        Arc::new(RwLock::new(None));
+    static ref LAST_SAVE: Arc<RwLock<Option<Instant>>> = Arc::new(RwLock::new(None));
+}
+
+/// What one promotion gave one client in a month
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AppliedPromotion {
+    pub promotion: String,
+    /// The month as year * 100 + month, UTC
+    pub month: u32,
+    /// Bytes not charged the exit price
+    pub free_bytes: u64,
+    /// Wei not billed
+    pub discount: u128,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct PromotionLedger {
+    clients: HashMap<WgKey, Vec<AppliedPromotion>>,
+}
+
+/// Totals for one promotion this month, for the reporting endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PromotionReport {
+    pub promotion: ExitPromotion,
+    pub active: bool,
+    pub month: u32,
+    /// Clients the promotion gave anything to this month
+    pub clients: usize,
+    pub free_bytes: u64,
+    pub discount: u128,
+}
+
+/// The UTC year, month and day of a unix timestamp, from Howard Hinnant's date algorithms
+pub fn civil_date(secs: u64) -> (i64, u32, u32) {
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// The month a unix timestamp falls in as year * 100 + month
+pub fn month_of(secs: u64) -> u32 {
+    let (year, month, _) = civil_date(secs);
+    (year * 100 + i64::from(month)) as u32
+}
+
+/// The month before the given one, in the same format
+fn previous_month(month: u32) -> u32 {
+    if month % 100 == 1 {
+        month - 100 + 11
+    } else {
+        month - 1
+    }
+}
+
+fn record<'a>(
+    applied: &'a mut Vec<AppliedPromotion>,
+    promotion: &str,
+    month: u32,
+) -> &'a mut AppliedPromotion {
+    match applied
+        .iter()
+        .position(|a| a.promotion == promotion && a.month == month)
+    {
+        Some(i) => &mut applied[i],
+        None => {
+            applied.push(AppliedPromotion {
+                promotion: promotion.to_string(),
+                month,
+                free_bytes: 0,
+                discount: 0,
+            });
+            applied.last_mut().unwrap()
+        }
+    }
+}
+
+/// Bills bytes of a client's traffic at the exit price less any active promotions, recording what
+/// each promotion gave. Free bytes are taken first and percentages come off what is left
+pub fn apply_promotions(
+    promotions: &[ExitPromotion],
+    applied: &mut Vec<AppliedPromotion>,
+    used: u64,
+    price: u64,
+    now: u64,
+) -> u128 {
+    let month = month_of(now);
+    let price = u128::from(price);
+    let mut billable = used;
+    let active: Vec<&ExitPromotion> = promotions.iter().filter(|p| p.is_active(now)).collect();
+    for promotion in active.iter() {
+        if let PromotionRule::FreeMonthlyBytes { bytes } = promotion.rule {
+            if billable == 0 {
+                break;
+            }
+            let left = match applied
+                .iter()
+                .find(|a| a.promotion == promotion.name && a.month == month)
+            {
+                Some(a) => bytes.saturating_sub(a.free_bytes),
+                None => bytes,
+            };
+            let free = billable.min(left);
+            if free > 0 {
+                let entry = record(applied, &promotion.name, month);
+                entry.free_bytes += free;
+                entry.discount += u128::from(free) * price;
+                billable -= free;
+            }
+        }
+    }
+    let mut value = u128::from(billable) * price;
+    for promotion in active.iter() {
+        if let PromotionRule::PercentOff { percent } = promotion.rule {
+            let off = value * u128::from(percent.min(100)) / 100;
+            if off > 0 {
+                record(applied, &promotion.name, month).discount += off;
+                value -= off;
+            }
+        }
+    }
+    value
+}
+
+/// The records, loaded from disk first if needed. Records that can't be loaded are an error rather
+/// than an empty ledger, which would hand every client this month's free bytes again
+fn get_ledger() -> Result<PromotionLedger, Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.promotions_file;
+    match cached_json_file(&mut PROMOTION_LEDGER.write().unwrap(), &path) {
+        Ok(ledger) => Ok(ledger.clone()),
+        Err(e) => {
+            error!("Failed to load promotions {}", e);
+            Err(Box::new(e.into()))
+        }
+    }
+}
+
+fn save_ledger(ledger: &PromotionLedger) -> Result<(), Box<RitaExitError>> {
+    let path = settings::get_rita_exit().exit_network.promotions_file;
+    if let Err(e) = save_json_file(&path, ledger) {
+        error!("Failed to save promotions {}", e);
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Failed to save promotions".to_string(),
+        )));
+    }
+    *LAST_SAVE.write().unwrap() = Some(Instant::now());
+    Ok(())
+}
+
+/// Applies promotions over one round of billing, started and finished by watch_exit_traffic
+pub struct PromotionBilling {
+    promotions: Vec<ExitPromotion>,
+    ledger: PromotionLedger,
+    now: u64,
+    changed: bool,
+}
+
+impl PromotionBilling {
+    /// While the records can't be loaded no promotions are applied and everyone is billed in full
+    pub fn start(promotions: &[ExitPromotion]) -> PromotionBilling {
+        let (promotions, ledger) = if promotions.is_empty() {
+            (Vec::new(), PromotionLedger::default())
+        } else {
+            match get_ledger() {
+                Ok(ledger) => (promotions.to_vec(), ledger),
+                Err(_) => (Vec::new(), PromotionLedger::default()),
+            }
+        };
+        PromotionBilling {
+            promotions,
+            ledger,
+            now: now_secs(),
+            changed: false,
+        }
+    }
+
+    /// The wei to bill a client for bytes at the exit's own price
+    pub fn bill(&mut self, key: &WgKey, used: u64, price: u64) -> i128 {
+        if self.promotions.is_empty() || used == 0 {
+            return i128::from(price) * i128::from(used);
+        }
+        let applied = self.ledger.clients.entry(*key).or_default();
+        let before = applied.clone();
+        let value = apply_promotions(&self.promotions, applied, used, price, self.now);
+        if *applied != before {
+            self.changed = true;
+        }
+        i128::try_from(value).unwrap_or(i128::MAX)
+    }
+
+    /// Keeps the records and writes them out once PROMOTION_SAVE_INTERVAL has passed
+    pub fn finish(mut self) {
+        if !self.changed {
+            return;
+        }
+        let oldest = (1..MONTHS_KEPT).fold(month_of(self.now), |m, _| previous_month(m));
+        for applied in self.ledger.clients.values_mut() {
+            applied.retain(|a| a.month >= oldest);
+        }
+        self.ledger.clients.retain(|_, applied| !applied.is_empty());
+        let due = LAST_SAVE
+            .read()
+            .unwrap()
+            .map(|last| last.elapsed() >= PROMOTION_SAVE_INTERVAL)
+            .unwrap_or(true);
+        if due {
+            let _ = save_ledger(&self.ledger);
+        }
+        *PROMOTION_LEDGER.write().unwrap() = Some(self.ledger);
+    }
+}
+
+/// Writes out the records if they were ever loaded, called on shutdown
+pub fn save_promotions() {
+    if let Some(ledger) = PROMOTION_LEDGER.read().unwrap().clone() {
+        let _ = save_ledger(&ledger);
+    }
+}
+
+/// What promotions gave a client, newest month first
+pub fn get_client_promotions(key: &WgKey) -> Result<Vec<AppliedPromotion>, Box<RitaExitError>> {
+    let mut applied = get_ledger()?.clients.remove(key).unwrap_or_default();
+    applied.sort_by(|a, b| b.month.cmp(&a.month).then(a.promotion.cmp(&b.promotion)));
+    Ok(applied)
+}
+
+/// Each configured promotion with what it gave this month
+pub fn get_promotion_reports() -> Result<Vec<PromotionReport>, Box<RitaExitError>> {
+    let promotions = settings::get_rita_exit().exit_network.promotions;
+    let ledger = get_ledger()?;
+    let now = now_secs();
+    let month = month_of(now);
+    Ok(promotions
+        .into_iter()
+        .map(|promotion| {
+            let mut report = PromotionReport {
+                active: promotion.is_active(now),
+                month,
+                clients: 0,
+                free_bytes: 0,
+                discount: 0,
+                promotion,
+            };
+            for applied in ledger.clients.values().flatten() {
+                if applied.promotion == report.promotion.name && applied.month == month {
+                    report.clients += 1;
+                    report.free_bytes += applied.free_bytes;
+                    report.discount += applied.discount;
+                }
+            }
+            report
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_month_of() {
+        assert_eq!(month_of(0), 197001);
+        // 2024-02-29 12:00 UTC
+        assert_eq!(month_of(1_709_208_000), 202402);
+        // 2024-12-31 23:59:59 UTC and a second later
+        assert_eq!(month_of(1_735_689_599), 202412);
+        assert_eq!(month_of(1_735_689_600), 202501);
+        assert_eq!(civil_date(1_709_208_000), (2024, 2, 29));
+        assert_eq!(civil_date(1_735_689_599), (2024, 12, 31));
+        assert_eq!(previous_month(202501), 202412);
+        assert_eq!(previous_month(202412), 202411);
+    }
+
+    #[test]
+    fn test_apply_promotions() {
+        let free = ExitPromotion {
+            name: "first 1000 free".to_string(),
+            rule: PromotionRule::FreeMonthlyBytes { bytes: 1000 },
+            starts: None,
+            ends: None,
+        };
+        let half = ExitPromotion {
+            name: "half off".to_string(),
+            rule: PromotionRule::PercentOff { percent: 50 },
+            starts: None,
+            ends: Some(2_000_000_000),
+        };
+        let promotions = vec![half.clone(), free];
+        let jan = 1_735_689_600;
+        let mut applied = Vec::new();
+
+        // all free
+        assert_eq!(apply_promotions(&promotions, &mut applied, 600, 10, jan), 0);
+        // 400 free bytes left, the other 600 at half price
+        assert_eq!(
+            apply_promotions(&promotions, &mut applied, 1000, 10, jan),
+            3000
+        );
+        assert_eq!(applied[0].free_bytes, 1000);
+        assert_eq!(applied[0].discount, 10_000);
+        assert_eq!(applied[1].promotion, "half off");
+        assert_eq!(applied[1].discount, 3000);
+
+        // a new month starts over
+        let feb = jan + 31 * 86_400;
+        assert_eq!(
+            apply_promotions(&promotions, &mut applied, 1000, 10, feb),
+            0
+        );
+        assert_eq!(applied.len(), 3);
+
+        // after the half off promotion ends
+        let ended = vec![half];
+        assert_eq!(
+            apply_promotions(&ended, &mut applied, 100, 10, 2_000_000_000),
+            1000
+        );
+    }
+}
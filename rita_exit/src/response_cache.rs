@@ -0,0 +1,149 @@
+//! Short lived cache for the read mostly responses every client asks the exit for, the exit details
+//! served with every status request and the registered exit list that the exit list endpoints otherwise
+//! fetch from the full node on every request. Entries expire after a few seconds so that a registration
+//! in the exit contract shows up quickly, and are dropped as soon as the settings they are built from
+//! change, so a price change never serves the old price. Like the settings the cache is per integration
+//! test namespace.
+
+use crate::database::get_exit_info;
+use althea_types::{ExitDetails, ExitIdentity};
+use clarity::Address;
+use rita_client_registration::client_db::get_exits_list;
+use rita_common::KI;
+use settings::subscriptions::{
+    subscribe, SettingsSubscription, EXIT_NETWORK_SECTION, NETWORK_SECTION, PAYMENT_SECTION,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use web30::client::Web3;
+use web30::jsonrpc::error::Web3Error;
+
+pub const EXIT_INFO_TTL: Duration = Duration::from_secs(30);
+pub const REGISTERED_EXITS_TTL: Duration = Duration::from_secs(60);
+
+/// Settings sections the cached responses are built from
+const CACHED_SECTIONS: [&str; 4] = [
+    EXIT_NETWORK_SECTION,
+    NETWORK_SECTION,
+    PAYMENT_SECTION,
+    "description",
+];
+
+lazy_static! {
+    static ref RESPONSE_CACHE: Arc<Mutex<HashMap<u32, ResponseCache>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+struct Cached<T> {
+    value: T,
+    at: Instant,
+}
+
+impl<T: Clone> Cached<T> {
+    fn fresh(&self, ttl: Duration) -> Option<T> {
+        if self.at.elapsed() < ttl {
+            Some(self.value.clone())
+        } else {
+            None
+        }
+    }
+}
+
+struct ResponseCache {
+    settings_changes: Vec<SettingsSubscription>,
+    exit_info: Option<Cached<ExitDetails>>,
+    registered_exits: Option<Cached<Vec<ExitIdentity>>>,
+}
+
+impl ResponseCache {
+    fn new() -> ResponseCache {
+        ResponseCache {
+            settings_changes: CACHED_SECTIONS.iter().map(|s| subscribe(s)).collect(),
+            exit_info: None,
+            registered_exits: None,
+        }
+    }
+
+    /// Drops everything if any of the sections the responses are built from changed
+    fn invalidate_on_settings_change(&mut self) {
+        // every subscription has to be polled so that each one forgets the change
+        let mut changed = false;
+        for subscription in self.settings_changes.iter_mut() {
+            changed |= subscription.has_changed();
+        }
+        if changed {
+            trace!("Exit settings changed, dropping cached responses");
+            self.exit_info = None;
+            self.registered_exits = None;
+        }
+    }
+}
+
+fn with_cache<T>(f: impl FnOnce(&mut ResponseCache) -> T) -> T {
+    let netns = KI.check_integration_test_netns();
+    let mut caches = RESPONSE_CACHE.lock().unwrap();
+    let cache = caches.entry(netns).or_insert_with(ResponseCache::new);
+    cache.invalidate_on_settings_change();
+    f(cache)
+}
+
+/// get_exit_info(), rebuilt at most every EXIT_INFO_TTL
+pub fn cached_exit_info() -> ExitDetails {
+    with_cache(|cache| {
+        if let Some(info) = cache
+            .exit_info
+            .as_ref()
+            .and_then(|c| c.fresh(EXIT_INFO_TTL))
+        {
+            return info;
+        }
+        let info = get_exit_info();
+        cache.exit_info = Some(Cached {
+            value: info.clone(),
+            at: Instant::now(),
+        });
+        info
+    })
+}
+
+/// The exits registered in the exit contract, fetched at most every REGISTERED_EXITS_TTL. Failures are
+/// not cached so the next request tries again
+pub async fn cached_registered_exits(
+    web3: &Web3,
+    our_address: Address,
+    contract: Address,
+) -> Result<Vec<ExitIdentity>, Web3Error> {
+    let cached = with_cache(|cache| {
+        cache
+            .registered_exits
+            .as_ref()
+            .and_then(|c| c.fresh(REGISTERED_EXITS_TTL))
+    });
+    if let Some(exits) = cached {
+        return Ok(exits);
+    }
+    let exits = get_exits_list(web3, our_address, contract).await?;
+    with_cache(|cache| {
+        cache.registered_exits = Some(Cached {
+            value: exits.clone(),
+            at: Instant::now(),
+        })
+    });
+    Ok(exits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_expiry() {
+        let cached = Cached {
+            value: 1,
+            at: Instant::now(),
+        };
+        assert_eq!(cached.fresh(Duration::from_secs(10)), Some(1));
+        assert_eq!(cached.fresh(Duration::ZERO), None);
+    }
+}
@@ -10,21 +10,34 @@
 //! Two threads are generated by this, one actual worker thread and a watchdog restarting thread that only
 //! wakes up to restart the inner thread if anything goes wrong.
 
+use crate::backup::tick_backups;
+use crate::consistency::{quarantined_clients, run_consistency_audit};
+use crate::database::client_store::get_all_clients;
 use crate::database::{
-    enforce_exit_clients, setup_clients, validate_clients_region, ExitClientSetupStates,
+    enforce_exit_clients, setup_clients, update_enforcement_exemptions, validate_clients_region,
+    version_denied_clients,
 };
+use crate::denylist::denied_clients;
+use crate::exit_list::update_cluster_health;
+use crate::health::{record_exit_tick, record_health, HealthCheck};
+use crate::heartbeat::update_heartbeat_clients;
 use crate::network_endpoints::*;
+use crate::preflight::tick_preflight_checks;
+use crate::pricing::prune_client_countries;
+use crate::sharding::{shard_clients, update_shard_members};
+use crate::speedtest::SPEEDTEST_MAX_BYTES;
 use crate::traffic_watcher::watch_exit_traffic;
 use actix_async::System as AsyncSystem;
 use actix_web_async::{web, App, HttpServer};
 use althea_kernel_interface::wg_iface_counter::WgUsage;
-use althea_kernel_interface::ExitClient;
 use althea_types::{Identity, WgKey};
-use babel_monitor::{open_babel_stream, parse_routes};
-use rita_client_registration::client_db::get_all_regsitered_clients;
+use babel_monitor::parse_routes;
+use babel_monitor::pool::query_babel;
+use ipnetwork::IpNetwork;
 use rita_common::debt_keeper::DebtAction;
 use rita_common::rita_loop::get_web3_server;
 use rita_common::KI;
+use settings::subscriptions::{subscribe, EXIT_NETWORK_SECTION};
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
 use std::thread;
@@ -44,20 +57,16 @@ pub const EXIT_INTERFACE: &str = "wg_exit_v2";
 /// Cache of rita exit state to track across ticks
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct RitaExitCache {
-    // a cache of what tunnels we had setup last round, used to prevent extra setup ops
-    wg_clients: HashSet<ExitClient>,
     // a list of client debts from the last round, to prevent extra enforcement ops
     debt_actions: HashSet<(Identity, DebtAction)>,
     // if we have successfully setup the wg exit tunnel in the past, if false we have never
     // setup exit clients and should crash if we fail to do so, otherwise we are preventing
     // proper failover
     successful_setup: bool,
-    // cache of b19 routers we have successful rules and routes for
-    wg_exit_clients: HashSet<WgKey>,
-    // cache of b20 routers we have successful rules and routes for
-    wg_exit_v2_clients: HashSet<WgKey>,
     // A blacklist of clients that we fail geoip verification for. We tear down these routes
     geoip_blacklist: Vec<Identity>,
+    // the enforcement exempt destinations currently programmed into the exit interfaces
+    enforcement_exemptions: HashSet<IpNetwork>,
 }
 
 pub type ExitLock = Arc<RwLock<HashMap<WgKey, WgUsage>>>;
@@ -88,11 +97,20 @@ pub fn start_rita_exit_loop(reg_clients_list: Vec<Identity>) {
                 let mut reg_clients_list = reg_clients_list.clone();
                 let runner = AsyncSystem::new();
                 runner.block_on(async move {
+                    let mut exit_network_changes = subscribe(EXIT_NETWORK_SECTION);
                     loop {
+                        if exit_network_changes.has_changed() {
+                            apply_exit_tunnel_mtu();
+                        }
                         reg_clients_list = update_client_list(reg_clients_list).await;
+                        update_heartbeat_clients(&reg_clients_list);
+                        prune_client_countries(&reg_clients_list);
+                        update_shard_members().await;
+                        update_cluster_health().await;
+                        tick_backups(&reg_clients_list);
 
                         rita_exit_cache = rita_exit_loop(
-                            reg_clients_list.clone(),
+                            shard_clients(reg_clients_list.clone()),
                             rita_exit_cache,
                             usage_history.clone(),
                         )
@@ -118,13 +136,14 @@ async fn update_client_list(reg_clients_list: Vec<Identity>) -> Vec<Identity> {
     let web3 = web30::client::Web3::new(&full_node, Duration::from_secs(5));
 
     let get_clients_benchmark = Instant::now();
-    match get_all_regsitered_clients(&web3, our_address, contract_address).await {
+    match get_all_clients(&web3, our_address, contract_address).await {
         Ok(list) => {
             info!(
                 "Finished Rita get clients, got {:?} clients in {}ms",
                 reg_clients_list.len(),
                 get_clients_benchmark.elapsed().as_millis()
             );
+            record_health(HealthCheck::Database, Ok(()));
 
             list
         }
@@ -133,6 +152,10 @@ async fn update_client_list(reg_clients_list: Vec<Identity>) -> Vec<Identity> {
                 "Failed to get registered clients this this round, using last successful {:?}",
                 e
             );
+            record_health(
+                HealthCheck::Database,
+                Err(format!("Failed to get registered clients {e:?}")),
+            );
             reg_clients_list
         }
     }
@@ -146,7 +169,7 @@ async fn rita_exit_loop(
     let mut rita_exit_cache = rita_exit_cache;
     let start = Instant::now();
 
-    let rita_exit = settings::get_rita_exit();
+    let rita_exit = settings::get_rita_exit_snapshot();
     let babel_port = rita_exit.network.babel_port;
 
     let ids = reg_clients_list.clone();
@@ -160,23 +183,31 @@ async fn rita_exit_loop(
 
     info!("About to setup clients");
     let start_setup_benchmark = Instant::now();
-    // Create and update client tunnels
-    match setup_clients(
-        reg_clients_list.clone(),
-        rita_exit_cache.geoip_blacklist.clone(),
-        ExitClientSetupStates {
-            old_clients: rita_exit_cache.wg_clients.clone(),
-            wg_exit_clients: rita_exit_cache.wg_exit_clients.clone(),
-            wg_exit_v2_clients: rita_exit_cache.wg_exit_v2_clients.clone(),
-        },
-    ) {
-        Ok(client_states) => {
+    tick_preflight_checks();
+    run_consistency_audit(
+        &reg_clients_list,
+        Duration::from_secs(rita_exit.exit_network.consistency_audit_interval),
+    );
+    // clients the operator has banned, whose version is too old or whose registrations conflict
+    // are torn down the same way as geoip unauthorized ones, while the denylist can't be loaded tunnels
+    // are left as they are rather than set up for banned clients
+    let setup = denied_clients(&reg_clients_list).and_then(|denied| {
+        let mut blacklist = rita_exit_cache.geoip_blacklist.clone();
+        blacklist.extend(denied);
+        blacklist.extend(version_denied_clients(&reg_clients_list));
+        blacklist.extend(quarantined_clients());
+        // Reconcile client tunnels and routes against the kernel
+        setup_clients(reg_clients_list.clone(), blacklist)
+    });
+    match setup {
+        Ok(()) => {
             rita_exit_cache.successful_setup = true;
-            rita_exit_cache.wg_clients = client_states.old_clients;
-            rita_exit_cache.wg_exit_clients = client_states.wg_exit_clients;
-            rita_exit_cache.wg_exit_v2_clients = client_states.wg_exit_v2_clients;
+            record_health(HealthCheck::WgSetup, Ok(()));
+        }
+        Err(e) => {
+            error!("Setup clients failed with {:?}", e);
+            record_health(HealthCheck::WgSetup, Err(e.to_string()));
         }
-        Err(e) => error!("Setup clients failed with {:?}", e),
     }
     info!(
         "Finished Rita setting up clients in {}ms",
@@ -197,9 +228,34 @@ async fn rita_exit_loop(
     // handle enforcement on client tunnels by querying debt keeper
     // this consumes client list
     let start_enforce_benchmark = Instant::now();
-    match enforce_exit_clients(reg_clients_list, &rita_exit_cache.debt_actions.clone()) {
-        Ok(new_debt_actions) => rita_exit_cache.debt_actions = new_debt_actions,
-        Err(e) => warn!("Failed to enforce exit clients with {:?}", e,),
+    match update_enforcement_exemptions(&rita_exit_cache.enforcement_exemptions) {
+        Ok(exemptions) => {
+            // the nftables backend programs the exemptions along with the limits, forgetting the
+            // actions last enforced makes it reprogram them
+            if exemptions != rita_exit_cache.enforcement_exemptions {
+                rita_exit_cache.debt_actions.clear();
+            }
+            rita_exit_cache.enforcement_exemptions = exemptions
+        }
+        Err(e) => warn!("Failed to update enforcement exemptions with {:?}", e),
+    }
+    if rita_exit.exit_network.billing_dry_run {
+        info!("Billing is in dry run mode, skipping enforcement");
+    } else {
+        match enforce_exit_clients(
+            reg_clients_list,
+            &rita_exit_cache.debt_actions.clone(),
+            &rita_exit_cache.enforcement_exemptions,
+        ) {
+            Ok(new_debt_actions) => {
+                rita_exit_cache.debt_actions = new_debt_actions;
+                record_health(HealthCheck::Enforcement, Ok(()));
+            }
+            Err(e) => {
+                warn!("Failed to enforce exit clients with {:?}", e,);
+                record_health(HealthCheck::Enforcement, Err(e.to_string()));
+            }
+        }
     }
     info!(
         "Finished Rita enforcement in {}ms ",
@@ -209,6 +265,7 @@ async fn rita_exit_loop(
         "Finished Rita exit loop in {}ms, all vars should be dropped",
         start.elapsed().as_millis(),
     );
+    record_exit_tick();
 
     thread::sleep(EXIT_LOOP_SPEED_DURATION);
     rita_exit_cache
@@ -217,45 +274,46 @@ async fn rita_exit_loop(
 fn bill(babel_port: u16, start: Instant, ids: Vec<Identity>, usage_history: ExitLock) {
     trace!("about to try opening babel stream");
 
-    match open_babel_stream(babel_port, EXIT_LOOP_TIMEOUT) {
-        Ok(mut stream) => match parse_routes(&mut stream) {
-            Ok(routes) => {
-                trace!("Sending traffic watcher message?");
-                if let Err(e) = watch_exit_traffic(usage_history, &routes, &ids) {
-                    error!(
-                        "Watch exit traffic failed with {}, in {} millis",
-                        e,
-                        start.elapsed().as_millis()
-                    );
-                } else {
-                    info!(
-                        "Watch exit traffic completed successfully in {} millis",
-                        start.elapsed().as_millis()
-                    );
-                }
-            }
-            Err(e) => {
+    match query_babel(babel_port, EXIT_LOOP_TIMEOUT, parse_routes) {
+        Ok(routes) => {
+            record_health(HealthCheck::Babel, Ok(()));
+            trace!("Sending traffic watcher message?");
+            if let Err(e) = watch_exit_traffic(usage_history, &routes, &ids) {
                 error!(
-                    "Watch exit traffic failed with: {} in {} millis",
+                    "Watch exit traffic failed with {}, in {} millis",
                     e,
                     start.elapsed().as_millis()
                 );
+            } else {
+                info!(
+                    "Watch exit traffic completed successfully in {} millis",
+                    start.elapsed().as_millis()
+                );
             }
-        },
+        }
         Err(e) => {
             error!(
                 "Watch exit traffic failed with: {} in {} millis",
                 e,
                 start.elapsed().as_millis()
             );
+            let message = if e.is_babel_down() {
+                format!("Failed to reach babel {e}")
+            } else {
+                format!("Failed to parse babel routes {e}")
+            };
+            record_health(HealthCheck::Babel, Err(message));
         }
     }
 }
 
 /// Run a region validation and return a list of blacklisted clients. This list is later used
-/// in setup clients to teardown blacklisted client tunnels
+/// in setup clients to teardown blacklisted client tunnels. Also runs with only country pricing
+/// configured, to find out what each client should be billed
 fn check_regions(start: Instant, clients_list: Vec<Identity>) -> Option<Vec<Identity>> {
-    let val = settings::get_rita_exit().allowed_countries.is_empty();
+    let rita_exit = settings::get_rita_exit_snapshot();
+    let val =
+        rita_exit.allowed_countries.is_empty() && rita_exit.exit_network.country_prices.is_empty();
     if !val {
         let res = validate_clients_region(clients_list);
         match res {
@@ -279,6 +337,20 @@ fn check_regions(start: Instant, clients_list: Vec<Identity>) -> Option<Vec<Iden
     None
 }
 
+/// Applies the configured tunnel mtu to the exit interfaces, so that changing it does not require
+/// a restart. Mss clamps are only ever lowered, so a raised mtu only fully applies to tcp after one
+fn apply_exit_tunnel_mtu() {
+    let tunnel_mtu = settings::get_rita_exit().exit_network.tunnel_mtu;
+    for iface in [LEGACY_INTERFACE, EXIT_INTERFACE] {
+        if let Err(e) = KI.set_mtu(iface, tunnel_mtu) {
+            error!("Failed to set {} mtu {:?}", iface, e);
+        }
+        if let Err(e) = KI.set_mss_clamp(iface, tunnel_mtu) {
+            error!("Failed to set {} mss clamp {:?}", iface, e);
+        }
+    }
+}
+
 fn setup_exit_wg_tunnel() {
     // Setup legacy wg_exit
     if let Err(e) = KI.create_blank_wg_interface(LEGACY_INTERFACE) {
@@ -298,14 +370,22 @@ fn setup_exit_wg_tunnel() {
         .mesh_ip
         .expect("Expected a mesh ip for this exit");
     let enforcement_enabled = exit_settings.exit_network.enable_enforcement;
+    let tunnel_mtu = exit_settings.exit_network.tunnel_mtu;
     let external_v6 = exit_settings
         .exit_network
         .subnet
         .map(|ipv6_subnet| (ipv6_subnet.ip(), ipv6_subnet.prefix()));
 
     // Setup legacy wg_exit
-    KI.one_time_exit_setup(None, None, mesh_ip, LEGACY_INTERFACE, enforcement_enabled)
-        .expect("Failed to setup wg_exit!");
+    KI.one_time_exit_setup(
+        None,
+        None,
+        mesh_ip,
+        LEGACY_INTERFACE,
+        enforcement_enabled,
+        tunnel_mtu,
+    )
+    .expect("Failed to setup wg_exit!");
 
     // Setup wg_exit_v2. Local address added is same as that used by wg_exit
     KI.one_time_exit_setup(
@@ -314,6 +394,7 @@ fn setup_exit_wg_tunnel() {
         mesh_ip,
         EXIT_INTERFACE,
         enforcement_enabled,
+        tunnel_mtu,
     )
     .expect("Failed to setup wg_exit_v2!");
 
@@ -321,12 +402,14 @@ fn setup_exit_wg_tunnel() {
         &settings::get_rita_exit().network.external_nic.unwrap(),
         LEGACY_INTERFACE,
         None,
+        tunnel_mtu,
     )
     .unwrap();
     KI.setup_nat(
         &settings::get_rita_exit().network.external_nic.unwrap(),
         EXIT_INTERFACE,
         external_v6,
+        tunnel_mtu,
     )
     .unwrap();
 }
@@ -342,9 +425,21 @@ pub fn start_rita_exit_endpoints(workers: usize) {
                     .route("/secure_status", web::post().to(secure_status_request))
                     .route("/exit_info", web::get().to(get_exit_info_http))
                     .route("/client_debt", web::post().to(get_client_debt))
+                    .route("/redeem_voucher", web::post().to(redeem_voucher_http))
                     .route("/time", web::get().to(get_exit_timestamp_http))
+                    .route("/health", web::get().to(get_exit_health))
                     .route("/exit_list", web::post().to(get_exit_list))
                     .route("/exit_list_v2", web::post().to(get_exit_list_v2))
+                    .route("/speedtest/start", web::post().to(speedtest_start))
+                    .route(
+                        "/speedtest/download/{bytes}",
+                        web::get().to(speedtest_download),
+                    )
+                    .service(
+                        web::resource("/speedtest/upload")
+                            .app_data(web::PayloadConfig::new(SPEEDTEST_MAX_BYTES))
+                            .route(web::post().to(speedtest_upload)),
+                    )
             })
             .workers(workers)
             .bind(format!(
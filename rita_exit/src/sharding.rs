@@ -0,0 +1,303 @@
+//! Sharding mode, splits the registered clients of a cluster between its exits so that a big deployment
+//! can run several exit workers, each on its own host or network namespace with its own cores, instead of
+//! one process setting up, billing and enforcing on every client. Each worker is an ordinary exit
+//! registered in the exit contract, the contract is what coordinates them.
+//!
+//! Every worker probes the others and assigns each client to the live worker with the best rendezvous
+//! score for it, the same score exit_list orders exits by, so a client's default exit is its owner and
+//! every worker agrees on the assignment without talking to the others. A worker only serves the clients
+//! it owns plus any client that recently contacted it or handshaked with it, so clients that picked a
+//! different worker by metric, or roamed when their owner went down, are not cut off. When a worker stops
+//! answering probes its clients are spread over the rest.
+
+use crate::exit_list::rendezvous_score;
+use crate::network_endpoints::CLIENT_STATUS_TIMEOUT;
+use crate::rita_loop::{EXIT_INTERFACE, LEGACY_INTERFACE};
+use althea_types::{ExitIdentity, Identity, WgKey};
+use rita_client_registration::client_db::get_exits_list;
+use rita_common::rita_loop::get_web3_server;
+use rita_common::KI;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use web30::client::Web3;
+
+/// How often the other workers are probed
+const MEMBER_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const MEMBER_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Clients that contacted or handshaked with us this recently are served even if another worker owns them
+const CLIENT_ACTIVE_TIMEOUT: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    static ref SHARD_STATE: Arc<RwLock<ShardState>> = Arc::new(RwLock::new(ShardState::default()));
+}
+
+#[derive(Debug, Default)]
+struct ShardState {
+    /// Workers that answered the last probe, including us. Empty until the first probe completes
+    alive: Vec<ExitIdentity>,
+    last_probe: Option<Instant>,
+    /// Clients that made a setup or status request to us and when
+    contacted: HashMap<WgKey, Instant>,
+    owned_clients: usize,
+    served_clients: usize,
+}
+
+/// Shown on the admin api
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShardStatus {
+    pub enabled: bool,
+    pub alive_members: Vec<ExitIdentity>,
+    /// Clients assigned to this worker
+    pub owned_clients: usize,
+    /// Owned clients plus active clients owned by other workers
+    pub served_clients: usize,
+}
+
+pub fn get_shard_status() -> ShardStatus {
+    let state = SHARD_STATE.read().unwrap();
+    ShardStatus {
+        enabled: settings::get_rita_exit().exit_network.sharding.is_some(),
+        alive_members: state.alive.clone(),
+        owned_clients: state.owned_clients,
+        served_clients: state.served_clients,
+    }
+}
+
+/// Records a setup or status request so the client is served by us even if it is not ours
+pub fn note_client_contact(key: WgKey) {
+    let mut state = SHARD_STATE.write().unwrap();
+    state.contacted.insert(key, Instant::now());
+}
+
+async fn probe_member(member: &ExitIdentity) -> bool {
+    let url = format!(
+        "http://[{}]:{}/time",
+        member.mesh_ip, member.registration_port
+    );
+    let client = awc::Client::default();
+    match client.get(&url).timeout(MEMBER_PROBE_TIMEOUT).send().await {
+        Ok(response) => response.status().is_success(),
+        Err(e) => {
+            trace!("Shard member {} did not answer {:?}", member.wg_key, e);
+            false
+        }
+    }
+}
+
+/// Run every exit loop tick, refreshes the workers from the exit contract and probes them
+pub async fn update_shard_members() {
+    let rita_exit = settings::get_rita_exit();
+    let sharding = match rita_exit.exit_network.sharding {
+        Some(sharding) => sharding,
+        None => return,
+    };
+    if let Some(last_probe) = SHARD_STATE.read().unwrap().last_probe {
+        if last_probe.elapsed() < MEMBER_PROBE_INTERVAL {
+            return;
+        }
+    }
+    let our_addr = match rita_exit.payment.eth_private_key {
+        Some(key) => key.to_address(),
+        None => return,
+    };
+    let contact = Web3::new(&get_web3_server(), CLIENT_STATUS_TIMEOUT);
+    let members = match get_exits_list(
+        &contact,
+        our_addr,
+        rita_exit.exit_network.registered_users_contract_addr,
+    )
+    .await
+    {
+        Ok(exits) => exits,
+        Err(e) => {
+            warn!("Failed to get shard members, keeping the last ones {}", e);
+            return;
+        }
+    };
+
+    let us = rita_exit.get_exit_identity();
+    let mut alive = vec![us.clone()];
+    for member in members {
+        if member.wg_key == us.wg_key
+            || (!sharding.members.is_empty() && !sharding.members.contains(&member.wg_key))
+        {
+            continue;
+        }
+        if probe_member(&member).await {
+            alive.push(member);
+        }
+    }
+    info!("Sharding clients across {} live exits", alive.len());
+
+    let mut state = SHARD_STATE.write().unwrap();
+    state.alive = alive;
+    state.last_probe = Some(Instant::now());
+    state
+        .contacted
+        .retain(|_, time| time.elapsed() < CLIENT_ACTIVE_TIMEOUT);
+}
+
+/// The worker a client belongs to, the one exit_list puts first for it among equally weighted exits
+fn shard_owner(client: &WgKey, members: &[ExitIdentity]) -> Option<WgKey> {
+    members
+        .iter()
+        .min_by_key(|member| rendezvous_score(client, member))
+        .map(|member| member.wg_key)
+}
+
+/// Splits out the clients we serve, along with how many of them we own
+fn select_served_clients(
+    clients: Vec<Identity>,
+    us: WgKey,
+    alive: &[ExitIdentity],
+    active: &HashSet<WgKey>,
+) -> (Vec<Identity>, usize) {
+    // until the first probe we don't know who else is up, serving everyone is the safe choice
+    if alive.is_empty() {
+        let count = clients.len();
+        return (clients, count);
+    }
+    let mut owned = 0;
+    let served = clients
+        .into_iter()
+        .filter(|client| {
+            if shard_owner(&client.wg_public_key, alive) == Some(us) {
+                owned += 1;
+                true
+            } else {
+                active.contains(&client.wg_public_key)
+            }
+        })
+        .collect();
+    (served, owned)
+}
+
+/// Clients with a recent handshake on either exit interface
+fn get_handshaking_clients() -> HashSet<WgKey> {
+    let mut active = HashSet::new();
+    for iface in [EXIT_INTERFACE, LEGACY_INTERFACE] {
+        match KI.get_last_active_handshake_time_netlink(iface) {
+            Ok(handshakes) => {
+                for (key, time) in handshakes {
+                    let recent = SystemTime::now()
+                        .duration_since(time)
+                        .map(|elapsed| elapsed < CLIENT_ACTIVE_TIMEOUT)
+                        .unwrap_or(true);
+                    if recent {
+                        active.insert(key);
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to get {} handshakes for sharding {:?}", iface, e),
+        }
+    }
+    active
+}
+
+/// The clients this exit should set up, bill and enforce on, every registered client when sharding is off
+pub fn shard_clients(clients: Vec<Identity>) -> Vec<Identity> {
+    let rita_exit = settings::get_rita_exit();
+    if rita_exit.exit_network.sharding.is_none() {
+        return clients;
+    }
+    let us = match rita_exit.network.wg_public_key {
+        Some(key) => key,
+        None => return clients,
+    };
+    let mut active = get_handshaking_clients();
+    let mut state = SHARD_STATE.write().unwrap();
+    active.extend(
+        state
+            .contacted
+            .iter()
+            .filter(|(_, time)| time.elapsed() < CLIENT_ACTIVE_TIMEOUT)
+            .map(|(key, _)| *key),
+    );
+    let (served, owned) = select_served_clients(clients, us, &state.alive, &active);
+    state.owned_clients = owned;
+    state.served_clients = served.len();
+    served
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_list::{order_exit_list, ClusterHealth};
+    use althea_types::SystemChain;
+
+    fn exit(n: u8) -> ExitIdentity {
+        ExitIdentity {
+            mesh_ip: format!("fd00::{n}").parse().unwrap(),
+            wg_key: [n; 32].into(),
+            eth_addr: "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"
+                .parse()
+                .unwrap(),
+            registration_port: 4875,
+            wg_exit_listen_port: 59998,
+            allowed_regions: HashSet::new(),
+            payment_types: HashSet::from([SystemChain::AltheaL1]),
+        }
+    }
+
+    fn client(n: u8) -> Identity {
+        Identity::new(
+            format!("fd00::1:{n}").parse().unwrap(),
+            "0xd2C5b6dd6ca641BE4c90565b5d3DA34C14949A53"
+                .parse()
+                .unwrap(),
+            [n; 32].into(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_select_served_clients() {
+        let members = vec![exit(1), exit(2), exit(3)];
+        let clients: Vec<Identity> = (10..74).map(client).collect();
+
+        // every client has exactly one owner and it is the exit the client lists first
+        let mut total = 0;
+        for member in members.iter() {
+            let (served, owned) =
+                select_served_clients(clients.clone(), member.wg_key, &members, &HashSet::new());
+            assert_eq!(served.len(), owned);
+            assert!(owned > 0);
+            for c in served {
+                let list = order_exit_list(
+                    members.clone(),
+                    c.wg_public_key,
+                    None,
+                    &ClusterHealth::new(),
+                );
+                assert_eq!(list.exit_list[0].wg_key, member.wg_key);
+            }
+            total += owned;
+        }
+        assert_eq!(total, clients.len());
+
+        // active clients are served by whoever they talk to
+        let active: HashSet<WgKey> = clients.iter().map(|c| c.wg_public_key).collect();
+        let (served, owned) =
+            select_served_clients(clients.clone(), exit(1).wg_key, &members, &active);
+        assert_eq!(served.len(), clients.len());
+        assert!(owned < clients.len());
+
+        // when a worker goes down the others pick up its clients
+        let (served_before, _) =
+            select_served_clients(clients.clone(), exit(1).wg_key, &members, &HashSet::new());
+        let (served_after, _) = select_served_clients(
+            clients.clone(),
+            exit(1).wg_key,
+            &members[..2],
+            &HashSet::new(),
+        );
+        assert!(served_after.len() > served_before.len());
+        assert!(served_before.iter().all(|c| served_after.contains(c)));
+
+        // nobody probed yet
+        let (served, _) =
+            select_served_clients(clients.clone(), exit(1).wg_key, &[], &HashSet::new());
+        assert_eq!(served.len(), clients.len());
+    }
+}
@@ -0,0 +1,122 @@
+//! A throttled bandwidth test endpoint that clients can run against over their wg tunnel, so that users can
+//! tell whether a slow connection is their wifi, the mesh or the exit. A client must claim a test slot before
+//! it is allowed to download or upload test data, each client ip may claim one slot per interval and a slot
+//! only lasts long enough to complete a single test. The amount of data moved per request is capped.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How often a single client may start a test
+pub const SPEEDTEST_MIN_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a claimed slot allows test traffic for
+pub const SPEEDTEST_SLOT_DURATION: Duration = Duration::from_secs(30);
+/// The most data a single download or upload request may move
+pub const SPEEDTEST_MAX_BYTES: usize = 10 * 1024 * 1024;
+/// Slots are only tracked until the client may start another test, this bounds the table size
+const MAX_TRACKED_SLOTS: usize = 10_000;
+
+lazy_static! {
+    /// Client ip to the time it last started a bandwidth test
+    static ref SPEEDTEST_SLOTS: Arc<RwLock<HashMap<IpAddr, Instant>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// Why a bandwidth test request was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedtestRefusal {
+    /// The client started a test too recently, it may try again after this long
+    TooSoon(Duration),
+    /// Test data was requested without a current slot
+    NoSlot,
+}
+
+fn claim_slot(
+    slots: &mut HashMap<IpAddr, Instant>,
+    client: IpAddr,
+    now: Instant,
+) -> Result<(), SpeedtestRefusal> {
+    if let Some(started) = slots.get(&client) {
+        let elapsed = now.saturating_duration_since(*started);
+        if elapsed < SPEEDTEST_MIN_INTERVAL {
+            return Err(SpeedtestRefusal::TooSoon(SPEEDTEST_MIN_INTERVAL - elapsed));
+        }
+    }
+    if slots.len() >= MAX_TRACKED_SLOTS {
+        slots.retain(|_, started| now.saturating_duration_since(*started) < SPEEDTEST_MIN_INTERVAL);
+    }
+    slots.insert(client, now);
+    Ok(())
+}
+
+fn check_slot(
+    slots: &HashMap<IpAddr, Instant>,
+    client: IpAddr,
+    now: Instant,
+) -> Result<(), SpeedtestRefusal> {
+    match slots.get(&client) {
+        Some(started) if now.saturating_duration_since(*started) < SPEEDTEST_SLOT_DURATION => {
+            Ok(())
+        }
+        _ => Err(SpeedtestRefusal::NoSlot),
+    }
+}
+
+/// Starts a bandwidth test for the given client ip if it has not run one recently
+pub fn start_speedtest(client: IpAddr) -> Result<(), SpeedtestRefusal> {
+    claim_slot(
+        &mut SPEEDTEST_SLOTS.write().unwrap(),
+        client,
+        Instant::now(),
+    )
+}
+
+/// Checks that the given client ip has a test in progress and may move test data
+pub fn speedtest_allowed(client: IpAddr) -> Result<(), SpeedtestRefusal> {
+    check_slot(&SPEEDTEST_SLOTS.read().unwrap(), client, Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speedtest_slots() {
+        let mut slots = HashMap::new();
+        let client: IpAddr = "172.16.0.2".parse().unwrap();
+        let other: IpAddr = "172.16.0.3".parse().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(
+            check_slot(&slots, client, start),
+            Err(SpeedtestRefusal::NoSlot)
+        );
+        assert_eq!(claim_slot(&mut slots, client, start), Ok(()));
+        assert_eq!(
+            check_slot(&slots, client, start + Duration::from_secs(10)),
+            Ok(())
+        );
+        assert_eq!(
+            check_slot(&slots, other, start),
+            Err(SpeedtestRefusal::NoSlot)
+        );
+
+        // the slot expires well before the client may claim another
+        let expired = start + SPEEDTEST_SLOT_DURATION;
+        assert_eq!(
+            check_slot(&slots, client, expired),
+            Err(SpeedtestRefusal::NoSlot)
+        );
+        assert_eq!(
+            claim_slot(&mut slots, client, expired),
+            Err(SpeedtestRefusal::TooSoon(
+                SPEEDTEST_MIN_INTERVAL - SPEEDTEST_SLOT_DURATION
+            ))
+        );
+
+        let later = start + SPEEDTEST_MIN_INTERVAL;
+        assert_eq!(claim_slot(&mut slots, client, later), Ok(()));
+        assert_eq!(check_slot(&slots, client, later), Ok(()));
+    }
+}
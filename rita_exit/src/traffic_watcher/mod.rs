@@ -8,6 +8,8 @@
 //!
 //! Also handles enforcement of nonpayment, since there's no need for a complicated TunnelManager for exits
 
+use crate::pricing::client_price;
+use crate::promotions::PromotionBilling;
 use crate::rita_loop::ExitLock;
 use crate::rita_loop::EXIT_INTERFACE;
 use crate::rita_loop::LEGACY_INTERFACE;
@@ -17,8 +19,9 @@ use althea_kernel_interface::wg_iface_counter::WgUsage;
 use althea_kernel_interface::KI;
 use althea_types::Identity;
 use althea_types::WgKey;
+use babel_monitor::parsing::get_billing_routes;
 use babel_monitor::structs::Route;
-use ipnetwork::IpNetwork;
+use rita_common::debt_keeper::shadow_traffic_update;
 use rita_common::debt_keeper::traffic_update;
 use rita_common::debt_keeper::Traffic;
 use rita_common::usage_tracker::structs::UsageType;
@@ -34,31 +37,27 @@ fn get_babel_info(
 ) -> HashMap<WgKey, u64> {
     // we assume this matches what is actually set it babel becuase we
     // panic on startup if it does not get set correctly
-    let local_fee = settings::get_rita_exit().network.babeld_settings.local_fee;
+    let rita_exit = settings::get_rita_exit_snapshot();
+    let local_fee = rita_exit.network.babeld_settings.local_fee;
 
     // insert ourselves as a destination, don't think this is actually needed
     let mut destinations = HashMap::new();
     destinations.insert(our_id.wg_public_key, u64::from(local_fee));
 
-    let max_fee = settings::get_rita_exit().payment.max_fee;
-    for route in routes {
-        // Only ip6
-        if let IpNetwork::V6(ref ip) = route.prefix {
-            // Only host addresses and installed routes
-            if ip.prefix() == 128 && route.installed {
-                match id_from_ip.get(&IpAddr::V6(ip.ip())) {
-                    Some(id) => {
-                        let price = if route.price > max_fee {
-                            max_fee
-                        } else {
-                            route.price
-                        };
-
-                        destinations.insert(id.wg_public_key, u64::from(price));
-                    }
-                    None => trace!("Can't find destination for client {:?}", ip.ip()),
-                }
+    let max_fee = rita_exit.payment.max_fee;
+    // one route per destination even where babel installed source specific routes to it
+    for (ip, route) in get_billing_routes(routes) {
+        match id_from_ip.get(&ip) {
+            Some(id) => {
+                let price = if route.price > max_fee {
+                    max_fee
+                } else {
+                    route.price
+                };
+
+                destinations.insert(id.wg_public_key, u64::from(price));
             }
+            None => trace!("Can't find destination for client {:?}", ip),
         }
     }
     destinations
@@ -72,8 +71,8 @@ struct HelperMapReturn {
 fn generate_helper_maps(our_id: &Identity, clients: &[Identity]) -> HelperMapReturn {
     let mut identities: HashMap<WgKey, Identity> = HashMap::new();
     let mut id_from_ip: HashMap<IpAddr, Identity> = HashMap::new();
-    let rita_exit = settings::get_rita_exit();
-    let our_settings = rita_exit.network;
+    let rita_exit = settings::get_rita_exit_snapshot();
+    let our_settings = &rita_exit.network;
     id_from_ip.insert(our_settings.mesh_ip.unwrap(), *our_id);
 
     for ident in clients.iter() {
@@ -160,12 +159,12 @@ pub fn watch_exit_traffic(
     // to our own price. In the case Exit -> A -> B -> C the exit pays A a lump sum for it's own
     // fees as well as B's fees. This means the exit pays the transaction fee (a percentage) for
     // that entire series of hops, we use the percentage number to ensure the exit recovers that amount
-    let our_price = settings::get_rita_exit().exit_network.exit_price;
-    let tx_fee_percentage = settings::get_rita_common()
-        .payment
-        .simulated_transaction_fee;
+    let rita_exit = settings::get_rita_exit_snapshot();
+    // clients may be billed a price for their country instead, see crate::pricing
+    let default_price = rita_exit.exit_network.exit_price;
+    let tx_fee_percentage = rita_exit.payment.simulated_transaction_fee;
 
-    let our_id = match settings::get_rita_exit().get_identity() {
+    let our_id = match rita_exit.get_identity() {
         Some(id) => id,
         None => {
             warn!("Our identity is not ready!");
@@ -203,6 +202,8 @@ pub fn watch_exit_traffic(
     };
 
     let mut debts = HashMap::new();
+    // discounts the exit's own price, see crate::promotions
+    let mut promotions = PromotionBilling::start(&rita_exit.exit_network.promotions);
 
     // Setup the debts table
     for (_, ident) in identities.clone() {
@@ -217,7 +218,7 @@ pub fn watch_exit_traffic(
     // creates new usage entires does not actualy update the values
     prepare_usage_history(&counters, &mut usage_history);
 
-    counters_logging(&counters, &usage_history, our_price as u32);
+    counters_logging(&counters, &usage_history, default_price as u32);
 
     // accounting for 'input'
     for (wg_key, bytes) in counters.clone() {
@@ -230,7 +231,8 @@ pub fn watch_exit_traffic(
             (Some(id), Some(_dest), Some(history)) => match debts.get_mut(id) {
                 Some(debt) => {
                     let used = bytes.download - history.download;
-                    let value = i128::from(our_price) * i128::from(used);
+                    let our_price = client_price(&rita_exit.exit_network, &id.wg_public_key);
+                    let value = promotions.bill(&id.wg_public_key, used, our_price);
                     trace!("We are billing for {} bytes input (client output) times a exit price of {} for a total of -{}", used, our_price, value);
                     *debt -= value;
                     // update history so that we know what was used from previous cycles
@@ -260,13 +262,15 @@ pub fn watch_exit_traffic(
             (Some(id), Some(dest), Some(history)) => match debts.get_mut(id) {
                 Some(debt) => {
                     let used = bytes.upload - history.upload;
+                    let our_price = client_price(&rita_exit.exit_network, &id.wg_public_key);
                     // ensure the exit recovers the percentage fee see explanation where tx_fee_percentage is declared
                     // surchage is based only on the price paid forward, since the exit keeps it's share without making
                     // an additional pyament
                     let tx_fee_surcharge =
                         (i128::from(*dest) * i128::from(used)) / i128::from(tx_fee_percentage);
-                    let value =
-                        (i128::from(dest + our_price) * i128::from(used)) + tx_fee_surcharge;
+                    let value = (i128::from(*dest) * i128::from(used))
+                        + promotions.bill(&id.wg_public_key, used, our_price)
+                        + tx_fee_surcharge;
                     trace!("We are billing for {} bytes output (client input) times a exit dest price of {} for a total of -{}", used, dest + our_price, value);
                     *debt -= value;
                     history.upload = bytes.upload;
@@ -286,14 +290,32 @@ pub fn watch_exit_traffic(
 
     debts_logging(&debts);
 
+    let dry_run = settings::get_rita_exit_snapshot()
+        .exit_network
+        .billing_dry_run;
+    // a dry run bills with promotions applied but must not use up anyone's free bytes, the ledger
+    // this round worked on is a copy and is simply dropped
+    if !dry_run {
+        promotions.finish();
+    }
     let mut traffic_vec = Vec::new();
     for (from, amount) in debts {
+        if dry_run && amount != 0 {
+            info!(
+                "Billing dry run, {} would be billed {} this round",
+                from.wg_public_key, -amount
+            );
+        }
         traffic_vec.push(Traffic {
             from,
             amount: amount.into(),
         })
     }
-    traffic_update(traffic_vec);
+    if dry_run {
+        shadow_traffic_update(traffic_vec);
+    } else {
+        traffic_update(traffic_vec);
+    }
 
     Ok(())
 }
@@ -0,0 +1,101 @@
+//! Redemption of operator signed prepaid vouchers, see althea_types::voucher for the voucher format.
+//! The exit is the single place a voucher is redeemed, so it keeps the list of redeemed voucher ids
+//! on disk and refuses to credit any id twice, or to credit anything while that list can't be read.
+
+use crate::heartbeat::get_registered_client;
+use crate::RitaExitError;
+use althea_types::unix_time::now_secs;
+use althea_types::{VoucherRedemption, VoucherRedemptionResult};
+use rita_common::debt_keeper::payment_received;
+use rita_common::debt_keeper::wei_denom;
+use rita_common::utils::json_file::{load_json_file, save_json_file};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// Ids of every voucher that has been redeemed on this exit, None until loaded from disk
+    static ref REDEEMED_VOUCHERS: Arc<RwLock<Option<HashSet<u64>>>> = Arc::new(RwLock::new(None));
+}
+
+/// Validates a voucher and credits the client with it's value, the redemption is saved to disk before
+/// any credit is applied so that a crash can not result in a voucher being redeemed twice. Only
+/// clients registered with this exit may redeem
+pub fn redeem_voucher(
+    redemption: VoucherRedemption,
+) -> Result<VoucherRedemptionResult, Box<RitaExitError>> {
+    let exit_network = settings::get_rita_exit().exit_network;
+    let signer = match exit_network.voucher_signer {
+        Some(signer) => signer,
+        None => {
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "This exit does not accept vouchers".to_string(),
+            )))
+        }
+    };
+    // only credit clients we actually serve, otherwise anyone holding a code could park credit
+    // on an arbitrary identity
+    if get_registered_client(&redemption.client.wg_public_key) != Some(redemption.client) {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Vouchers can only be redeemed by registered clients".to_string(),
+        )));
+    }
+    let voucher = redemption.voucher;
+    let voucher_signer = match voucher.signer() {
+        Ok(voucher_signer) => voucher_signer,
+        Err(e) => return Err(Box::new(e.into())),
+    };
+    if voucher_signer != signer {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Voucher was not signed by this exit's operator".to_string(),
+        )));
+    }
+    let now = now_secs();
+    if voucher.voucher.expiry < now {
+        return Err(Box::new(RitaExitError::MiscStringError(
+            "Voucher has expired".to_string(),
+        )));
+    }
+
+    let id = voucher.voucher.id;
+    {
+        let mut redeemed_pin = REDEEMED_VOUCHERS.write().unwrap();
+        if redeemed_pin.is_none() {
+            // an unreadable list could be hiding any redemption, so nothing is redeemed until it is fixed
+            match load_json_file(&exit_network.redeemed_vouchers_file) {
+                Ok(redeemed) => *redeemed_pin = Some(redeemed),
+                Err(e) => {
+                    error!("Failed to load redeemed vouchers {}", e);
+                    return Err(Box::new(RitaExitError::MiscStringError(
+                        "Voucher redemption is unavailable".to_string(),
+                    )));
+                }
+            }
+        }
+        let redeemed: &mut HashSet<u64> = redeemed_pin.as_mut().unwrap();
+        if redeemed.contains(&id) {
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "Voucher has already been redeemed".to_string(),
+            )));
+        }
+        redeemed.insert(id);
+        if let Err(e) = save_json_file(&exit_network.redeemed_vouchers_file, &*redeemed) {
+            redeemed.remove(&id);
+            error!("Failed to save redeemed vouchers {}", e);
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "Failed to record voucher redemption".to_string(),
+            )));
+        }
+    }
+
+    info!(
+        "Voucher {} redeemed by {} for {}",
+        id, redemption.client.wg_public_key, voucher.voucher.amount
+    );
+    if let Err(e) = payment_received(redemption.client, voucher.voucher.amount, wei_denom()) {
+        return Err(Box::new(e.into()));
+    }
+    Ok(VoucherRedemptionResult {
+        id,
+        amount: voucher.voucher.amount,
+    })
+}
@@ -15,6 +15,7 @@ use rita_client::get_wifi_config_internal;
 use rita_client::set_ssid;
 use rita_common::KI;
 
+use std::collections::BTreeMap;
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
@@ -241,6 +242,7 @@ fn apply_opkg_update_if_needed(router_version: String, extender_version: String)
         let opkg_install = OpkgCommand::Install {
             packages: vec!["rita_extender".to_string()],
             arguments: force_maintainer,
+            sha256: BTreeMap::new(),
         };
         let res = KI.perform_opkg(opkg_install);
         match res {
@@ -1,10 +1,16 @@
+use crate::dns::DnsSettings;
+use crate::guest_network::GuestNetworkSettings;
 use crate::localization::LocalizationSettings;
 use crate::logging::LoggingSettings;
 use crate::network::NetworkSettings;
 use crate::operator::OperatorSettings;
 use crate::payment::PaymentSettings;
+use crate::role::RitaRole;
+use crate::snmp::SnmpSettings;
+use crate::user_rules::{FirewallRule, StaticRoute};
 use crate::{json_merge, set_rita_client, SettingsError};
 use althea_types::{ContactStorage, ExitState, Identity};
+use clarity::Address;
 
 use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
@@ -45,6 +51,15 @@ pub struct ExitServer {
     #[serde(default = "default_wg_listen_port")]
     pub wg_exit_listen_port: u16,
 
+    /// Overrides exit_client.tunnel_mtu while this exit is selected, exits reached over
+    /// different backhauls may need different values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_mtu: Option<usize>,
+
+    /// Overrides exit_client.persistent_keepalive while this exit is selected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persistent_keepalive: Option<u16>,
+
     /// The registration state and other data about the exit
     #[serde(default, flatten)]
     pub info: ExitState,
@@ -123,6 +138,59 @@ pub struct ExitClientSettings {
     /// Specifies if the user would like to receive low balance messages from the exit
     #[serde(default = "default_balance_notification")]
     pub low_balance_notification: bool,
+    /// The mtu set on wg_exit, tcp connections over the tunnel are clamped to fit. Routers behind
+    /// radios with a small mtu may need to lower this to avoid blackholing large packets
+    #[serde(default = "default_exit_tunnel_mtu")]
+    pub tunnel_mtu: usize,
+    /// Wireguard persistent keepalive interval on wg_exit in seconds, keeps nat mappings between
+    /// us and the exit open
+    #[serde(default = "default_exit_keepalive")]
+    pub persistent_keepalive: u16,
+    /// When set lan traffic is only ever forwarded into wg_exit, if the exit tunnel is down it is
+    /// dropped rather than sent out the local gateway uplink unencrypted
+    #[serde(default)]
+    pub kill_switch: bool,
+    /// A signed list of exits published by the operator, merged into exits as it changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_registry: Option<ExitRegistrySettings>,
+    /// Sends part of the lan traffic through a second exit, for operators moving load between exits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_split: Option<ExitSplitSettings>,
+}
+
+/// A second exit that takes a share of the lan flows, see rita_client::exit_manager::exit_split
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct ExitSplitSettings {
+    /// The exit to send flows to, a key in exits that we must be registered with
+    pub secondary: IpAddr,
+    /// Percent of new flows that go to the secondary exit, 1 to 99
+    pub weight: u8,
+}
+
+/// Where to fetch the operator's exit registry from and the key it must be signed with
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitRegistrySettings {
+    pub url: String,
+    pub signer: Address,
+    /// Seconds between fetches
+    #[serde(default = "default_exit_registry_refresh")]
+    pub refresh_interval: u64,
+    /// Version of the last registry we took, older ones are rejected. Kept in the config so a reboot
+    /// can't be used to replay an old registry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+fn default_exit_registry_refresh() -> u64 {
+    3600
+}
+
+fn default_exit_tunnel_mtu() -> usize {
+    1340
+}
+
+fn default_exit_keepalive() -> u16 {
+    5
 }
 
 impl Default for ExitClientSettings {
@@ -133,10 +201,28 @@ impl Default for ExitClientSettings {
             contact_info: None,
             lan_nics: HashSet::new(),
             low_balance_notification: true,
+            tunnel_mtu: default_exit_tunnel_mtu(),
+            persistent_keepalive: default_exit_keepalive(),
+            kill_switch: false,
+            exit_registry: None,
+            exit_split: None,
         }
     }
 }
 
+impl ExitClientSettings {
+    /// The mtu to set on wg_exit when tunneling to this exit
+    pub fn tunnel_mtu_for(&self, exit: &ExitServer) -> usize {
+        exit.tunnel_mtu.unwrap_or(self.tunnel_mtu)
+    }
+
+    /// The persistent keepalive to set on wg_exit when tunneling to this exit
+    pub fn persistent_keepalive_for(&self, exit: &ExitServer) -> u16 {
+        exit.persistent_keepalive
+            .unwrap_or(self.persistent_keepalive)
+    }
+}
+
 impl RitaClientSettings {
     pub fn new(file_name: &str) -> Result<Self, SettingsError> {
         if !Path::new(file_name).exists() {
@@ -185,6 +271,22 @@ pub struct RitaClientSettings {
     /// The save interval defaults to 48 hours for exit settings represented in seconds
     #[serde(default = "default_save_interval")]
     pub save_interval: u64,
+    /// Runs a read only SNMP agent when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snmp: Option<SnmpSettings>,
+    #[serde(default)]
+    pub dns: DnsSettings,
+    /// Provisions an isolated guest network when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_network: Option<GuestNetworkSettings>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub static_routes: Vec<StaticRoute>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub firewall_rules: Vec<FirewallRule>,
+    /// The role rita_multi starts this config in, kept here so that it survives the config being
+    /// written back. Only client and gateway make sense for a client config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<RitaRole>,
 }
 
 impl RitaClientSettings {
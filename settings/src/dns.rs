@@ -0,0 +1,38 @@
+use std::net::IpAddr;
+
+/// A dns over tls resolver, the name is checked against the certificate the server presents
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct DnsTlsServer {
+    pub address: IpAddr,
+    pub auth_name: String,
+}
+
+/// Where dnsmasq on the router forwards the queries of lan clients
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DnsUpstream {
+    /// The resolver on the exit, reached over the exit tunnel, with public resolvers as a fallback
+    Exit,
+    /// Plain dns to these servers
+    Custom { servers: Vec<IpAddr> },
+    /// Dns over tls to these servers, through a local stubby instance
+    Tls { servers: Vec<DnsTlsServer> },
+    /// Dns over https to this resolver url, through a local https-dns-proxy instance
+    Https { url: String },
+}
+
+impl Default for DnsUpstream {
+    fn default() -> Self {
+        DnsUpstream::Exit
+    }
+}
+
+/// Settings for the dns resolver the router offers its lan, applied to dnsmasq by rita_client::dns
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct DnsSettings {
+    #[serde(default)]
+    pub upstream: DnsUpstream,
+    /// The number of names dnsmasq caches, 0 disables caching. None leaves the firmware default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_size: Option<u32>,
+}
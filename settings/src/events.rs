@@ -0,0 +1,87 @@
+//! Settings for the event subsystem in rita_common::events, which pushes notifications about things
+//! operators care about, such as enforcement starting or the exit changing, to a webhook, an MQTT broker
+//! or the log.
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_mqtt_topic() -> String {
+    "rita/events".to_string()
+}
+
+/// An MQTT broker events are published to, each event type goes to its own subtopic of topic, for
+/// example rita/events/exit_switched. Only plain tcp is supported, so point this at a broker on the
+/// local network or over the exit tunnel
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct MqttSinkSettings {
+    /// host:port of the broker
+    pub broker: String,
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    /// Defaults to the node's wg public key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// Which event types are delivered, all of them by default
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct EnabledEvents {
+    /// A neighbor's tunnel was suspended for non payment
+    #[serde(default = "default_true")]
+    pub enforcement_started: bool,
+    /// A suspended neighbor paid and was let back on
+    #[serde(default = "default_true")]
+    pub enforcement_ended: bool,
+    #[serde(default = "default_true")]
+    pub exit_switched: bool,
+    /// Our balance fell below payment.balance_warning_level
+    #[serde(default = "default_true")]
+    pub balance_low: bool,
+    /// A thread panicked
+    #[serde(default = "default_true")]
+    pub crash: bool,
+    /// Memory use crossed a network.memory_monitor threshold
+    #[serde(default = "default_true")]
+    pub memory_pressure: bool,
+    /// We started failing to send payments to a neighbor
+    #[serde(default = "default_true")]
+    pub payment_failed: bool,
+    /// A payment we had credited was dropped from the chain by a reorg
+    #[serde(default = "default_true")]
+    pub payment_reverted: bool,
+}
+
+impl Default for EnabledEvents {
+    fn default() -> Self {
+        EnabledEvents {
+            enforcement_started: true,
+            enforcement_ended: true,
+            exit_switched: true,
+            balance_low: true,
+            crash: true,
+            memory_pressure: true,
+            payment_failed: true,
+            payment_reverted: true,
+        }
+    }
+}
+
+/// Where events are delivered, events go nowhere unless at least one sink is configured
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct EventSettings {
+    /// Events are posted here as json
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttSinkSettings>,
+    /// Write events to the log at info level
+    #[serde(default)]
+    pub log: bool,
+    #[serde(default)]
+    pub enabled: EnabledEvents,
+}
@@ -2,7 +2,8 @@ use crate::localization::LocalizationSettings;
 use crate::network::NetworkSettings;
 use crate::payment::PaymentSettings;
 use crate::{json_merge, set_rita_exit, SettingsError};
-use althea_types::{regions::Regions, ExitIdentity, FromStr, Identity, WgKey};
+use althea_types::regions::{CountryPrice, Regions};
+use althea_types::{ExitIdentity, FromStr, Identity, WgKey};
 use clarity::Address;
 use ipnetwork::IpNetwork;
 use std::collections::HashSet;
@@ -20,6 +21,16 @@ pub struct ExitNetworkSettings {
     pub wg_v2_tunnel_port: u16,
     /// Price in wei per byte which is charged to traffic both coming in and out over the internet
     pub exit_price: u64,
+    /// Prices that replace exit_price for clients whose gateway geoip places them in a country,
+    /// clients anywhere else or whose country isn't known yet pay exit_price
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub country_prices: Vec<CountryPrice>,
+    /// Discounts on exit_price, every active promotion applies to every client, see ExitPromotion
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub promotions: Vec<ExitPromotion>,
+    /// Where the promotions applied to each client, per month, are stored
+    #[serde(default = "default_promotions_file")]
+    pub promotions_file: String,
     /// This is the exit's own ip/gateway ip in the exit wireguard tunnel
     pub own_internal_ip: Ipv4Addr,
     /// The netmask, in bits to mask out, for the exit tunnel
@@ -47,6 +58,322 @@ pub struct ExitNetworkSettings {
     pub enable_enforcement: bool,
     /// Address of the Althea contract to store registered users data
     pub registered_users_contract_addr: Address,
+    /// Destinations that enforced clients can always reach at full speed, such as payment processors,
+    /// the operator dashboard or emergency info pages. Each entry is either an ip prefix in cidr notation
+    /// or a hostname, hostnames are resolved again every ten minutes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub enforcement_exempt_destinations: Vec<String>,
+    /// When set the exit computes and logs what each client would be billed and records it in a
+    /// shadow ledger, but never updates real debts, enforces on clients, or demands payment. This lets
+    /// operators bringing up a new exit audit billing before charging anyone
+    #[serde(default)]
+    pub billing_dry_run: bool,
+    /// Address of the operator key that signs prepaid vouchers, if not set vouchers are not accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voucher_signer: Option<Address>,
+    /// Where the ids of redeemed vouchers are stored to prevent double spends
+    #[serde(default = "default_redeemed_vouchers_file")]
+    pub redeemed_vouchers_file: String,
+    /// Where the operator managed list of denied clients is stored
+    #[serde(default = "default_client_denylist_file")]
+    pub client_denylist_file: String,
+    /// Where the operator managed list of clients isolated from the rest of the mesh is stored
+    #[serde(default = "default_client_isolation_file")]
+    pub client_isolation_file: String,
+    /// Where the operator managed per client overrides of the region check and enforcement are stored
+    #[serde(default = "default_client_overrides_file")]
+    pub client_overrides_file: String,
+    /// Where the exit protocol version negotiated with each client is stored
+    #[serde(default = "default_protocol_versions_file")]
+    pub protocol_versions_file: String,
+    /// Clients below this version (x.y.z) are warned that they are deprecated and, once the
+    /// deadline passes, refused service. Unset to serve all versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_client_version: Option<String>,
+    /// Unix timestamp in seconds after which clients below min_client_version are denied, until
+    /// then they are served with a deprecation warning. If unset old clients are denied immediately
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_client_version_deadline: Option<u64>,
+    /// The mtu set on the exit tunnel interfaces, tcp connections through them are clamped to fit.
+    /// Lower this if clients behind small mtu links see connections stall on large transfers
+    #[serde(default = "default_tunnel_mtu")]
+    pub tunnel_mtu: usize,
+    /// How often in seconds the registered clients list is audited for wg keys or eth addresses
+    /// registered more than once, clients in conflicting registrations are not set up. 0 disables
+    #[serde(default = "default_consistency_audit_interval")]
+    pub consistency_audit_interval: u64,
+    /// The operator admin api for client management, pricing and the denylist. It is served on its
+    /// own address, never on the exit_hello_port, and is not started unless configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_api: Option<ExitAdminApiSettings>,
+    /// Starts this exit in bootstrap mode, on startup the wg_exit keys, ports and pricing are fetched
+    /// from an existing member of the cluster and saved, then this section is removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster_bootstrap: Option<ExitClusterBootstrapSettings>,
+    /// Splits the registered clients between the exits of a cluster so each only sets up, bills and
+    /// enforces on its own share, see rita_exit::sharding. Unset to serve every client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sharding: Option<ExitShardingSettings>,
+    /// Pauses enforcement, but not billing, while the billing system is being worked on, see
+    /// rita_exit::maintenance
+    #[serde(default)]
+    pub maintenance: ExitMaintenanceSettings,
+    /// Moves every client of this exit to a replacement before it is decommissioned, see
+    /// rita_exit::migration. Set from the admin api
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migration: Option<ExitMigrationSettings>,
+    /// Leading zero bits of the proof of work clients have to send with a signup, to slow down
+    /// scripted signups. Each extra bit doubles the work, 0 disables
+    #[serde(default)]
+    pub signup_pow_difficulty: u8,
+    /// What programs enforcement into the kernel, see rita_exit::enforcement
+    #[serde(default)]
+    pub enforcement_backend: EnforcementBackend,
+    /// Runs this backend alongside the active one without touching the kernel, it computes what it
+    /// would program and logs where that differs from what the active backend has programmed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforcement_shadow: Option<EnforcementBackend>,
+    /// Exit loop ticks in a row the shadow backend has to agree with the active one before the admin
+    /// api lets the operator switch to it
+    #[serde(default = "default_enforcement_shadow_ticks")]
+    pub enforcement_shadow_ticks: u32,
+    /// Where registered clients are kept, see rita_exit::database::client_store. Anything other
+    /// than the registration contract is for integration tests and demo networks only
+    #[serde(default)]
+    pub client_store: ExitClientStore,
+    /// Periodic encrypted backups of the client list and the exit's state files to s3 compatible
+    /// storage, see rita_exit::backup. Not taken unless configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<ExitBackupSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EnforcementBackend {
+    /// Htb classes on the exit wg interfaces
+    #[default]
+    Tc,
+    /// Policing rules in the rita_enforcement nftables table
+    Nftables,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitClientStore {
+    /// The registration contract, clients sign up through the registration server
+    #[default]
+    Contract,
+    /// UNSUPPORTED FOR PRODUCTION. Clients are kept in memory and registered as soon as they sign
+    /// up, no blockchain node, balance or registration server is needed and every client is
+    /// forgotten on restart
+    Memory,
+}
+
+/// Settings for the exit operator admin api
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitAdminApiSettings {
+    /// Address and port the admin api listens on, defaults to localhost only
+    #[serde(default = "default_admin_api_bind_address")]
+    pub bind_address: String,
+    /// Every request must use basic auth with the user rita and this password, the admin api
+    /// refuses to start if it is empty
+    pub password: String,
+}
+
+/// Where a new exit fetches its cluster config from, see rita_exit::cluster
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitClusterBootstrapSettings {
+    /// The admin api of an existing member, for example http://10.0.0.2:4879
+    pub member_url: String,
+    /// The admin api password of that member
+    pub password: String,
+}
+
+/// Which exits share the client set in sharding mode
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct ExitShardingSettings {
+    /// Mesh wg keys of the exits clients are split between, empty for every exit in the exit contract
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<WgKey>,
+}
+
+/// A discount on the exit's own price, the share of a bill forwarded to the mesh is never discounted
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitPromotion {
+    /// Identifies the promotion in the applied promotion records, keep it when changing the rule
+    pub name: String,
+    pub rule: PromotionRule,
+    /// Unix timestamp in seconds the promotion starts, it runs from startup if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub starts: Option<u64>,
+    /// Unix timestamp in seconds the promotion ends, it runs until removed if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ends: Option<u64>,
+}
+
+impl ExitPromotion {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.starts.map(|s| now >= s).unwrap_or(true) && self.ends.map(|e| now < e).unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PromotionRule {
+    /// The first bytes of traffic each calendar month (UTC) are not charged the exit price, upload
+    /// and download both count
+    FreeMonthlyBytes { bytes: u64 },
+    /// A percentage off the exit price, applied after any free bytes
+    PercentOff { percent: u8 },
+}
+
+/// When the exit is in maintenance mode, set from the admin api or by hand
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct ExitMaintenanceSettings {
+    /// Maintenance mode until this is turned off again
+    #[serde(default)]
+    pub manual: bool,
+    /// Unix timestamp in seconds a scheduled maintenance window opens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_start: Option<u64>,
+    /// Unix timestamp in seconds the scheduled window closes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_end: Option<u64>,
+}
+
+/// A migration of this exit's clients to another exit, clients are told about the replacement from
+/// the start, throttled after throttle_after and refused service after deadline_after
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitMigrationSettings {
+    pub replacement: ExitIdentity,
+    /// Unix timestamp in seconds the migration started
+    pub started: u64,
+    /// Seconds after the start clients still on this exit are throttled
+    #[serde(default = "default_migration_throttle_after")]
+    pub throttle_after: u64,
+    /// Seconds after the start this exit stops serving clients
+    #[serde(default = "default_migration_deadline_after")]
+    pub deadline_after: u64,
+}
+
+pub fn default_migration_throttle_after() -> u64 {
+    // three days
+    3 * 86_400
+}
+
+pub fn default_migration_deadline_after() -> u64 {
+    // a week
+    7 * 86_400
+}
+
+/// Where and how often backups are uploaded. The encryption key and storage credentials are kept in
+/// credentials_file, see ExitBackupCredentials
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitBackupSettings {
+    /// Seconds between backups
+    #[serde(default = "default_backup_interval")]
+    pub interval: u64,
+    /// The storage endpoint, for example https://s3.us-east-1.amazonaws.com, objects are addressed
+    /// path style as endpoint/bucket/key
+    pub endpoint: String,
+    #[serde(default = "default_backup_region")]
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to the object name of every backup
+    #[serde(default = "default_backup_prefix")]
+    pub prefix: String,
+    /// Toml file holding the ExitBackupCredentials, only root may be able to read it
+    #[serde(default = "default_backup_credentials_file")]
+    pub credentials_file: String,
+    /// Backups kept in the bucket, older ones are deleted after each upload
+    #[serde(default = "default_backups_kept")]
+    pub keep: usize,
+    /// Where the list of uploaded backups is kept so that old ones can be pruned after a restart
+    #[serde(default = "default_backup_index_file")]
+    pub index_file: String,
+}
+
+/// The secrets backups need, kept out of the settings so that they are never written back to the
+/// config or handed out by the admin api. Backups are encrypted with key, a hex encoded 32 byte
+/// secretbox key also kept somewhere other than the exit, without it they can't be restored
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ExitBackupCredentials {
+    pub key: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl ExitBackupCredentials {
+    pub fn load(path: &str) -> Result<Self, SettingsError> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+fn default_backup_interval() -> u64 {
+    // a day
+    86_400
+}
+
+fn default_backup_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_backup_prefix() -> String {
+    "rita-exit/".to_string()
+}
+
+fn default_backup_credentials_file() -> String {
+    "/etc/rita-exit-backup-credentials.toml".to_string()
+}
+
+fn default_backups_kept() -> usize {
+    30
+}
+
+fn default_backup_index_file() -> String {
+    "/etc/rita-exit-backups.json".to_string()
+}
+
+fn default_admin_api_bind_address() -> String {
+    "[::1]:4879".to_string()
+}
+
+fn default_enforcement_shadow_ticks() -> u32 {
+    // an hour of exit loop ticks
+    720
+}
+
+fn default_tunnel_mtu() -> usize {
+    1500
+}
+
+fn default_consistency_audit_interval() -> u64 {
+    300
+}
+
+fn default_redeemed_vouchers_file() -> String {
+    "/etc/rita-exit-redeemed-vouchers.json".to_string()
+}
+
+fn default_client_denylist_file() -> String {
+    "/etc/rita-exit-denylist.json".to_string()
+}
+
+fn default_client_isolation_file() -> String {
+    "/etc/rita-exit-isolation.json".to_string()
+}
+
+fn default_promotions_file() -> String {
+    "/etc/rita-exit-promotions.json".to_string()
+}
+
+fn default_client_overrides_file() -> String {
+    "/etc/rita-exit-overrides.json".to_string()
+}
+
+fn default_protocol_versions_file() -> String {
+    "/etc/rita-exit-protocol-versions.json".to_string()
 }
 
 fn enable_enforcement_default() -> bool {
@@ -63,6 +390,9 @@ impl ExitNetworkSettings {
             wg_tunnel_port: 59999,
             wg_v2_tunnel_port: 59998,
             exit_price: 10,
+            country_prices: Vec::new(),
+            promotions: Vec::new(),
+            promotions_file: default_promotions_file(),
             own_internal_ip: "172.16.255.254".parse().unwrap(),
             netmask: 12,
             subnet: Some(IpNetwork::V6("ff01::0/128".parse().unwrap())),
@@ -78,6 +408,29 @@ impl ExitNetworkSettings {
             registered_users_contract_addr: "0x9BAbFde52Fe18A5CD00a542b87b4D124a4879582"
                 .parse()
                 .unwrap(),
+            enforcement_exempt_destinations: Vec::new(),
+            billing_dry_run: false,
+            voucher_signer: None,
+            redeemed_vouchers_file: default_redeemed_vouchers_file(),
+            client_denylist_file: default_client_denylist_file(),
+            client_isolation_file: default_client_isolation_file(),
+            client_overrides_file: default_client_overrides_file(),
+            protocol_versions_file: default_protocol_versions_file(),
+            min_client_version: None,
+            min_client_version_deadline: None,
+            tunnel_mtu: default_tunnel_mtu(),
+            consistency_audit_interval: default_consistency_audit_interval(),
+            admin_api: None,
+            cluster_bootstrap: None,
+            sharding: None,
+            maintenance: ExitMaintenanceSettings::default(),
+            migration: None,
+            signup_pow_difficulty: 0,
+            enforcement_backend: EnforcementBackend::Tc,
+            enforcement_shadow: None,
+            enforcement_shadow_ticks: default_enforcement_shadow_ticks(),
+            client_store: ExitClientStore::Contract,
+            backup: None,
         }
     }
 }
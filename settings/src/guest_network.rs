@@ -0,0 +1,32 @@
+use std::net::Ipv4Addr;
+
+fn default_guest_router_ip() -> Ipv4Addr {
+    Ipv4Addr::new(192, 168, 50, 1)
+}
+
+fn default_guest_usage_file() -> String {
+    "/etc/rita-guest-usage.json".to_string()
+}
+
+/// Settings for the isolated guest network provisioned by rita_client::guest_network. The network only
+/// exists while this section is present in the config
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct GuestNetworkSettings {
+    /// Broadcast on every radio
+    pub ssid: String,
+    /// The network is open when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// The router's address on the guest network, which is always a /24
+    #[serde(default = "default_guest_router_ip")]
+    pub router_ip: Ipv4Addr,
+    /// A wired interface to bridge into the guest network, such as a vlan like eth0.5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wired_ifname: Option<String>,
+    /// Caps the total download speed of guests in mbit/s
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_limit: Option<usize>,
+    /// Where guest usage is kept across reboots
+    #[serde(default = "default_guest_usage_file")]
+    pub usage_file: String,
+}
@@ -8,6 +8,11 @@
 //! This can be dependent on the behavior of the borrow checker since the lock
 //! is released based on when the reference is dropped. Take care when using _mut to either
 //! namespace or clone quickly to avoid deadlocks.
+//!
+//! Settings are stored behind an Arc so that readers can take a snapshot without copying the whole
+//! struct, get_rita_client_snapshot() and friends only bump a reference count. Updates are copy on
+//! write, a snapshot someone is still holding keeps the values it was taken with. Code that wants to
+//! change settings still uses get_rita_client() and set_rita_client(), which hand out an owned copy.
 
 #[macro_use]
 extern crate lazy_static;
@@ -32,18 +37,26 @@ use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 pub mod client;
+pub mod dns;
+pub mod events;
 pub mod exit;
+pub mod guest_network;
 pub mod localization;
 pub mod logging;
 pub mod network;
 pub mod operator;
 pub mod payment;
+pub mod role;
+pub mod snmp;
+pub mod subscriptions;
+pub mod user_rules;
 
 mod error;
 pub use error::SettingsError;
 
 use crate::client::RitaClientSettings;
 use crate::exit::RitaExitSettingsStruct;
+use crate::subscriptions::{notify_subscribers, version_for_subscribers};
 /// denom that debt keeper works in. We convert all currencies received to this amount
 pub const DEBT_KEEPER_DENOM: &str = "wei";
 pub const DEBT_KEEPER_DENOM_DECIMAL: u64 = 1_000_000_000_000_000_000;
@@ -75,10 +88,9 @@ impl Debug for AdaptorSettings {
 }
 
 #[derive(Debug)]
-#[allow(clippy::large_enum_variant)]
 pub enum Settings {
-    Client(RitaClientSettings),
-    Exit(RitaExitSettingsStruct),
+    Client(Arc<RitaClientSettings>),
+    Exit(Arc<RitaExitSettingsStruct>),
     Adaptor(AdaptorSettings),
 }
 
@@ -137,6 +149,36 @@ impl RitaSettings {
     }
 }
 
+/// A cheap read only view of the settings shared by clients and exits, see get_rita_common_snapshot()
+#[derive(Debug, Clone)]
+pub enum RitaCommonSnapshot {
+    Client(Arc<RitaClientSettings>),
+    Exit(Arc<RitaExitSettingsStruct>),
+}
+
+impl RitaCommonSnapshot {
+    pub fn network(&self) -> &NetworkSettings {
+        match self {
+            RitaCommonSnapshot::Client(settings) => &settings.network,
+            RitaCommonSnapshot::Exit(settings) => &settings.network,
+        }
+    }
+
+    pub fn payment(&self) -> &PaymentSettings {
+        match self {
+            RitaCommonSnapshot::Client(settings) => &settings.payment,
+            RitaCommonSnapshot::Exit(settings) => &settings.payment,
+        }
+    }
+
+    pub fn get_identity(&self) -> Option<Identity> {
+        match self {
+            RitaCommonSnapshot::Client(settings) => settings.get_identity(),
+            RitaCommonSnapshot::Exit(settings) => settings.get_identity(),
+        }
+    }
+}
+
 /// write the current SETTINGS from memory to file
 pub fn write_config() -> Result<(), SettingsError> {
     let netns = KI.check_integration_test_netns();
@@ -146,7 +188,7 @@ pub fn write_config() -> Result<(), SettingsError> {
             let filename = FLAG_CONFIG.read().unwrap();
             let filename = filename.get(&netns);
             if let Some(filename) = filename {
-                settings.write(filename.clone())?
+                settings.as_ref().write(filename.clone())?
             }
             Ok(())
         }
@@ -154,7 +196,7 @@ pub fn write_config() -> Result<(), SettingsError> {
             let filename = FLAG_CONFIG.read().unwrap();
             let filename = filename.get(&netns);
             if let Some(filename) = filename {
-                settings.write(filename.clone())?
+                settings.as_ref().write(filename.clone())?
             }
             Ok(())
         }
@@ -187,13 +229,17 @@ pub fn get_config_json() -> Result<serde_json::Value, SettingsError> {
 pub fn merge_config_json(changed_settings: serde_json::Value) -> Result<(), SettingsError> {
     let netns = KI.check_integration_test_netns();
     let mut settings_ref = SETTINGS.write().unwrap();
-    let settings_ref = settings_ref.get_mut(&netns);
-    match settings_ref {
+    let before = version_for_subscribers(netns, settings_ref.get(&netns));
+    let ret = match settings_ref.get_mut(&netns) {
         Some(Settings::Adaptor(adapt)) => adapt.adaptor.merge_client_json(changed_settings),
-        Some(Settings::Client(client_settings)) => client_settings.merge(changed_settings),
-        Some(Settings::Exit(exit_settings)) => exit_settings.merge(changed_settings),
+        Some(Settings::Client(client_settings)) => {
+            Arc::make_mut(client_settings).merge(changed_settings)
+        }
+        Some(Settings::Exit(exit_settings)) => Arc::make_mut(exit_settings).merge(changed_settings),
         None => panic!("attempted to merge config to a missing Settings"),
-    }
+    };
+    notify_subscribers(netns, before, settings_ref.get(&netns));
+    ret
 }
 
 /// Save generic settings into memory.
@@ -202,6 +248,7 @@ pub fn merge_config_json(changed_settings: serde_json::Value) -> Result<(), Sett
 pub fn set_rita_common(input: RitaSettings) {
     let netns = KI.check_integration_test_netns();
     let mut settings_ref = SETTINGS.write().unwrap();
+    let before = version_for_subscribers(netns, settings_ref.get(&netns));
     match settings_ref.get_mut(&netns) {
         Some(Settings::Adaptor(adapt)) => {
             let mut client_settings = adapt
@@ -217,41 +264,41 @@ pub fn set_rita_common(input: RitaSettings) {
         }
         // if there's a client setting, update it
         Some(Settings::Client(client_settings)) => {
+            let client_settings = Arc::make_mut(client_settings);
             client_settings.network = input.network;
             client_settings.payment = input.payment;
         }
         // if there's an exit settings, update it
         Some(Settings::Exit(exit_settings)) => {
+            let exit_settings = Arc::make_mut(exit_settings);
             exit_settings.network = input.network;
             exit_settings.payment = input.payment;
         }
         // if there are no settings, panic
         None => panic!("attempted to save rita settings to an empty Settings var"),
     }
+    notify_subscribers(netns, before, settings_ref.get(&netns));
 }
 
 /// get the current settings and extract generic RitaSettings from it
 pub fn get_rita_common() -> RitaSettings {
+    let snapshot = get_rita_common_snapshot();
+    RitaSettings {
+        network: snapshot.network().clone(),
+        payment: snapshot.payment().clone(),
+        identity: snapshot.get_identity(),
+    }
+}
+
+/// A snapshot of the current generic settings without copying them, for code that only reads
+pub fn get_rita_common_snapshot() -> RitaCommonSnapshot {
     let netns = KI.check_integration_test_netns();
     match SETTINGS.read().unwrap().get(&netns) {
         Some(Settings::Adaptor(adapt)) => {
-            let settings = adapt.adaptor.get_client().unwrap();
-            RitaSettings {
-                network: settings.network.clone(),
-                payment: settings.payment.clone(),
-                identity: settings.get_identity(),
-            }
+            RitaCommonSnapshot::Client(Arc::new(adapt.adaptor.get_client().unwrap()))
         }
-        Some(Settings::Client(settings)) => RitaSettings {
-            network: settings.network.clone(),
-            payment: settings.payment.clone(),
-            identity: settings.get_identity(),
-        },
-        Some(Settings::Exit(settings)) => RitaSettings {
-            network: settings.network.clone(),
-            payment: settings.payment.clone(),
-            identity: settings.get_identity(),
-        },
+        Some(Settings::Client(settings)) => RitaCommonSnapshot::Client(settings.clone()),
+        Some(Settings::Exit(settings)) => RitaCommonSnapshot::Exit(settings.clone()),
         None => panic!("expected settings but got none"),
     }
 }
@@ -275,28 +322,39 @@ pub fn get_flag_config() -> PathBuf {
 pub fn set_rita_client(client_setting: RitaClientSettings) {
     let netns = KI.check_integration_test_netns();
     let mut settings_ref = SETTINGS.write().unwrap();
+    let before = version_for_subscribers(netns, settings_ref.get(&netns));
     match settings_ref.get(&netns) {
         // if there's an adaptor already saved, then use it to set there
         Some(Settings::Adaptor(adapt)) => adapt.adaptor.set_client(client_setting).unwrap(),
         // if there's a client setting, then save over it
         Some(Settings::Client(_)) => {
-            settings_ref.insert(netns, Settings::Client(client_setting));
+            settings_ref.insert(netns, Settings::Client(Arc::new(client_setting)));
         }
         // error if there's an exit here
-        Some(Settings::Exit(_)) => panic!("attempted to save client settings over exit settings"),
+        Some(Settings::Exit(_)) => {
+            panic!("attempted to save client settings over exit settings")
+        }
         // if there are no settings, then save as Client
         None => {
-            settings_ref.insert(netns, Settings::Client(client_setting));
+            settings_ref.insert(netns, Settings::Client(Arc::new(client_setting)));
         }
     }
+    notify_subscribers(netns, before, settings_ref.get(&netns));
 }
 
 /// get client settings from local or adaptor memory
 /// panics if called on exit settings
 pub fn get_rita_client() -> RitaClientSettings {
+    (*get_rita_client_snapshot()).clone()
+}
+
+/// A snapshot of the client settings without copying them, for code that only reads. Adaptor
+/// settings are owned by the wrapping binary and are still copied
+/// panics if called on exit settings
+pub fn get_rita_client_snapshot() -> Arc<RitaClientSettings> {
     let netns = KI.check_integration_test_netns();
     match SETTINGS.read().unwrap().get(&netns) {
-        Some(Settings::Adaptor(adapt)) => adapt.adaptor.get_client().unwrap(),
+        Some(Settings::Adaptor(adapt)) => Arc::new(adapt.adaptor.get_client().unwrap()),
         Some(Settings::Client(settings)) => settings.clone(),
         Some(Settings::Exit(_)) => panic!("expected client settings, but got exit setttings"),
         None => panic!("expected settings but got none"),
@@ -306,14 +364,19 @@ pub fn get_rita_client() -> RitaClientSettings {
 /// Set exit settings into memory
 pub fn set_rita_exit(exit_setting: RitaExitSettingsStruct) {
     let netns = KI.check_integration_test_netns();
-    SETTINGS
-        .write()
-        .unwrap()
-        .insert(netns, Settings::Exit(exit_setting));
+    let mut settings_ref = SETTINGS.write().unwrap();
+    let before = version_for_subscribers(netns, settings_ref.get(&netns));
+    settings_ref.insert(netns, Settings::Exit(Arc::new(exit_setting)));
+    notify_subscribers(netns, before, settings_ref.get(&netns));
 }
 
 /// Retrieve exit settings from memory
 pub fn get_rita_exit() -> RitaExitSettingsStruct {
+    (*get_rita_exit_snapshot()).clone()
+}
+
+/// A snapshot of the exit settings without copying them, for code that only reads
+pub fn get_rita_exit_snapshot() -> Arc<RitaExitSettingsStruct> {
     let netns = KI.check_integration_test_netns();
     let temp = SETTINGS.read().unwrap();
     let temp = temp.get(&netns);
@@ -1,7 +1,8 @@
-use althea_kernel_interface::DefaultRoute;
+use crate::events::EventSettings;
+use althea_kernel_interface::{DefaultRoute, StorageType};
 use althea_types::{regions::Regions, ShaperSettings, SystemChain};
 use babel_monitor::structs::{BabeldConfig, BabeldInterfaceConfig};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv6Addr};
 
 use althea_types::WgKey;
@@ -52,6 +53,171 @@ fn default_babeld_config() -> BabeldConfig {
     }
 }
 
+/// Makes babel avoid neighbors whose routes keep flapping. A neighbor with at least flap_threshold
+/// route adds and retractions within window_secs has penalty added to the rxcost of its tunnels, the
+/// penalty is held while the neighbor keeps flapping and then halves every half_life_secs
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct StabilityPolicy {
+    pub flap_threshold: usize,
+    pub window_secs: u64,
+    pub penalty: u16,
+    pub half_life_secs: u64,
+    /// The rxcost of tunnels without a penalty, babeld uses 256 for interfaces with link quality enabled
+    #[serde(default = "default_base_rxcost")]
+    pub base_rxcost: u16,
+}
+
+fn default_base_rxcost() -> u16 {
+    256
+}
+
+/// Thresholds for rita_common::memory_monitor, percentages are of total system memory
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMonitorSettings {
+    /// Warn once our resident memory reaches this share of system memory
+    #[serde(default = "default_rss_warning_percent")]
+    pub rss_warning_percent: u8,
+    #[serde(default = "default_rss_critical_percent")]
+    pub rss_critical_percent: u8,
+    /// Warn once available system memory falls to this share, other processes count here too
+    #[serde(default = "default_available_warning_percent")]
+    pub available_warning_percent: u8,
+    #[serde(default = "default_available_critical_percent")]
+    pub available_critical_percent: u8,
+    /// Drop old usage and payment history while memory is critical
+    #[serde(default = "default_shed_history")]
+    pub shed_history: bool,
+}
+
+fn default_rss_warning_percent() -> u8 {
+    25
+}
+
+fn default_rss_critical_percent() -> u8 {
+    40
+}
+
+fn default_available_warning_percent() -> u8 {
+    15
+}
+
+fn default_available_critical_percent() -> u8 {
+    5
+}
+
+fn default_shed_history() -> bool {
+    true
+}
+
+impl Default for MemoryMonitorSettings {
+    fn default() -> Self {
+        MemoryMonitorSettings {
+            rss_warning_percent: default_rss_warning_percent(),
+            rss_critical_percent: default_rss_critical_percent(),
+            available_warning_percent: default_available_warning_percent(),
+            available_critical_percent: default_available_critical_percent(),
+            shed_history: default_shed_history(),
+        }
+    }
+}
+
+/// Wireguard persistent keepalive on mesh tunnels. Tunnels to neighbors on the same link carry babel
+/// hellos every few seconds and need no keepalive, tunnels that cross a nat lose their mapping when
+/// idle, so with auto on keepalive is only turned on for tunnels found to cross one
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TunnelKeepaliveSettings {
+    /// Detect tunnels that cross a nat, when off every tunnel uses interval
+    #[serde(default = "default_keepalive_auto")]
+    pub auto: bool,
+    /// Keepalive in seconds for tunnels that cross a nat
+    #[serde(default = "default_keepalive_interval")]
+    pub interval: u16,
+    /// Keepalive in seconds for the tunnels to these neighbors whatever is detected, 0 turns it off
+    #[serde(default)]
+    pub overrides: HashMap<WgKey, u16>,
+}
+
+fn default_keepalive_auto() -> bool {
+    true
+}
+
+fn default_keepalive_interval() -> u16 {
+    25
+}
+
+impl Default for TunnelKeepaliveSettings {
+    fn default() -> Self {
+        TunnelKeepaliveSettings {
+            auto: default_keepalive_auto(),
+            interval: default_keepalive_interval(),
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+/// Mtu discovery on mesh tunnels. Every interval each tunnel's underlying link is probed with don't
+/// fragment pings to the neighbor and the tunnel mtu is set to the largest packet that makes it across
+/// less the wireguard overhead, so that links with a smaller mtu than the rest of the mesh don't
+/// silently fragment or drop tunnel traffic
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct MeshMtuSettings {
+    /// Probe tunnels and set their mtu, when off tunnels keep the wireguard default
+    #[serde(default = "default_mesh_mtu_probe")]
+    pub probe: bool,
+    /// Seconds between probes of the same tunnel
+    #[serde(default = "default_mesh_mtu_interval")]
+    pub interval: u64,
+    /// Largest mtu set on a tunnel, the wireguard default
+    #[serde(default = "default_mesh_mtu_max")]
+    pub max: usize,
+}
+
+fn default_mesh_mtu_probe() -> bool {
+    true
+}
+
+fn default_mesh_mtu_interval() -> u64 {
+    3600
+}
+
+fn default_mesh_mtu_max() -> usize {
+    1420
+}
+
+impl Default for MeshMtuSettings {
+    fn default() -> Self {
+        MeshMtuSettings {
+            probe: default_mesh_mtu_probe(),
+            interval: default_mesh_mtu_interval(),
+            max: default_mesh_mtu_max(),
+        }
+    }
+}
+
+/// A physical button wired to a gpio exported through sysfs
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GpioButton {
+    pub gpio: u32,
+    /// True if pressing the button pulls the line low, as is usual for reset buttons
+    #[serde(default = "default_active_low")]
+    pub active_low: bool,
+}
+
+fn default_active_low() -> bool {
+    true
+}
+
+fn default_dashboard_allowed_origins() -> Vec<String> {
+    vec![
+        "http://althea.net".to_string(),
+        "http://althearouter.net".to_string(),
+    ]
+}
+
+fn default_dashboard_csrf_protection() -> bool {
+    true
+}
+
 fn default_usage_tracker_file() -> String {
     "/etc/rita-usage-tracker.bincode".to_string()
 }
@@ -103,6 +269,19 @@ pub struct NetworkSettings {
     pub rita_dashboard_port: u16,
     /// The password for dashboard authentication
     pub rita_dashboard_password: Option<String>,
+    /// Directory holding a bundled dashboard ui, served under /ui on the dashboard port when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rita_dashboard_ui_dir: Option<String>,
+    /// Origins besides the dashboard's own address that may make requests to the dashboard
+    #[serde(default = "default_dashboard_allowed_origins")]
+    pub dashboard_allowed_origins: Vec<String>,
+    /// When set, state changing dashboard requests made from a web page must carry the token
+    /// the dashboard sends in the X-CSRF-Token header
+    #[serde(default = "default_dashboard_csrf_protection")]
+    pub dashboard_csrf_protection: bool,
+    /// A button that clears dashboard login lockouts when pressed, usually the reset button
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dashboard_unlock_button: Option<GpioButton>,
     /// The tick interval in seconds between rita hellos, traffic watcher measurements and payments
     pub rita_tick_interval: u64,
     /// Our private key, encoded with Base64 (what the `wg` command outputs and takes by default)
@@ -136,9 +315,20 @@ pub struct NetworkSettings {
     /// Nickname of the device on the network
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nickname: Option<ArrayString<32>>,
+    /// Tell neighbors our device model and nickname so their dashboards can label us, off by default
+    /// since it tells anyone in radio range what hardware we run
+    #[serde(default)]
+    pub share_device_info: bool,
     /// Full file path for usage tracker storage
     #[serde(default = "default_usage_tracker_file")]
     pub usage_tracker_file: String,
+    /// Overrides the storage type detected for usage_tracker_file, which decides how often usage
+    /// is written out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage_tracker_storage: Option<StorageType>,
+    /// Overrides the seconds between usage tracker writes picked for the storage type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage_tracker_write_interval: Option<u64>,
     #[serde(default)]
     /// Set to true by the dashboard when the user indicates they've made a backup
     pub backup_created: bool,
@@ -157,6 +347,25 @@ pub struct NetworkSettings {
     pub allowed_countries: HashSet<Regions>,
     /// Payment chains that this device can use
     pub payment_chains: HashSet<SystemChain>,
+    /// If set firmware images and other artifacts this node downloads are kept in this directory
+    /// and served to mesh neighbors so they don't each have to download them, normally set on gateways
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_cache_dir: Option<String>,
+    /// If set neighbors with unstable routes are penalized, see StabilityPolicy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stability_policy: Option<StabilityPolicy>,
+    /// Where notifications about enforcement, exit switches, low balance and crashes are sent
+    #[serde(default)]
+    pub events: EventSettings,
+    /// When to warn about and shed memory before the OOM killer steps in
+    #[serde(default)]
+    pub memory_monitor: MemoryMonitorSettings,
+    /// Persistent keepalive on mesh tunnels, see TunnelKeepaliveSettings
+    #[serde(default)]
+    pub tunnel_keepalive: TunnelKeepaliveSettings,
+    /// Mtu discovery on mesh tunnels, see MeshMtuSettings
+    #[serde(default)]
+    pub mesh_mtu: MeshMtuSettings,
 }
 
 impl Default for NetworkSettings {
@@ -173,6 +382,10 @@ impl Default for NetworkSettings {
             rita_hello_port: 4876,
             rita_dashboard_port: 4877,
             rita_dashboard_password: None,
+            rita_dashboard_ui_dir: None,
+            dashboard_allowed_origins: default_dashboard_allowed_origins(),
+            dashboard_csrf_protection: default_dashboard_csrf_protection(),
+            dashboard_unlock_button: None,
             rita_tick_interval: 5,
             wg_private_key: None,
             wg_private_key_path: "/tmp/priv".to_string(),
@@ -184,11 +397,20 @@ impl Default for NetworkSettings {
             last_default_route: None,
             device: None,
             nickname: None,
+            share_device_info: false,
             usage_tracker_file: default_usage_tracker_file(),
+            usage_tracker_storage: None,
+            usage_tracker_write_interval: None,
             user_bandwidth_limit: None,
             allowed_countries: default_allowed_countries(),
             payment_chains: HashSet::new(),
             babeld_settings: default_babeld_config(),
+            artifact_cache_dir: None,
+            stability_policy: None,
+            events: EventSettings::default(),
+            memory_monitor: MemoryMonitorSettings::default(),
+            tunnel_keepalive: TunnelKeepaliveSettings::default(),
+            mesh_mtu: MeshMtuSettings::default(),
         }
     }
 }
@@ -5,7 +5,7 @@
 //! simplifies things a lot (no need for complex trustless enforcement). If you find that both DAO settings and this exist at the same time
 //! that means the transition is still in prgress.
 
-use althea_types::{BillingDetails, InstallationDetails};
+use althea_types::{BillingDetails, InstallationDetails, SignedBandwidthContract};
 use clarity::Address;
 use num256::Uint256;
 
@@ -36,6 +36,39 @@ fn default_force_use_operator_price() -> bool {
     false
 }
 
+/// Extended hardware telemetry is shared by default, users may opt out
+fn default_share_hardware_telemetry() -> bool {
+    true
+}
+
+/// Antenna credentials from the operator are used by default, users may opt out
+fn default_allow_antenna_auth_injection() -> bool {
+    true
+}
+
+/// Speed tests push a lot of data over the mesh, routers only take part once the user opts in
+fn default_allow_speed_tests() -> bool {
+    false
+}
+
+/// Operator notices are passed on to neighbors unless the user opts out
+fn default_gossip_broadcast_notices() -> bool {
+    true
+}
+
+fn default_antenna_sessions_file() -> String {
+    "/etc/rita-antenna-sessions.json".to_string()
+}
+
+fn default_max_speed_test_duration() -> u64 {
+    10
+}
+
+/// 100mbps
+fn default_max_speed_test_rate() -> u64 {
+    12_500_000
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct OperatorSettings {
     /// The operator managing this router
@@ -59,6 +92,46 @@ pub struct OperatorSettings {
     /// If we should display the operator setup on the dashboard
     #[serde(default = "default_display_operator_setup")]
     pub display_operator_setup: bool,
+    /// If extended hardware telemetry such as storage wear and board model is included
+    /// in the operator checkin, this is a privacy toggle for users
+    #[serde(default = "default_share_hardware_telemetry")]
+    pub share_hardware_telemetry: bool,
+    /// If antenna login credentials sent by the operator server with an antenna forwarding request
+    /// are added to the forwarded http requests. They are only held in memory for the session
+    #[serde(default = "default_allow_antenna_auth_injection")]
+    pub allow_antenna_auth_injection: bool,
+    /// Bandwidth limit in bytes per second for antenna forwarding sessions, used when the operator
+    /// server doesn't send one with the forward request. None or zero is unlimited
+    #[serde(default)]
+    pub antenna_forwarding_rate_limit: Option<u64>,
+    /// If who asked for each antenna forwarding session, the antenna, duration and bytes moved are
+    /// recorded and sent to the operator server signed by this router. Payloads are never recorded
+    #[serde(default)]
+    pub record_antenna_sessions: bool,
+    /// Where recorded antenna sessions are kept until the operator server has them
+    #[serde(default = "default_antenna_sessions_file")]
+    pub antenna_sessions_file: String,
+    /// If this router will send or receive throughput tests signed by speed_test_signer
+    #[serde(default = "default_allow_speed_tests")]
+    pub allow_speed_tests: bool,
+    /// Address of the key the operator signs speed tests with, kept apart from the operator address
+    /// so that a key used by automated tooling can't sign anything else. Tests are refused while unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed_test_signer: Option<Address>,
+    /// The longest speed test this router will take part in, in seconds
+    #[serde(default = "default_max_speed_test_duration")]
+    pub max_speed_test_duration: u64,
+    /// The fastest a speed test is allowed to send, in bytes per second
+    #[serde(default = "default_max_speed_test_rate")]
+    pub max_speed_test_rate: u64,
+    /// The plan the operator last sent for this router to shape itself to, kept after it expires
+    /// until the operator sends another
+    #[serde(default)]
+    pub bandwidth_contract: Option<SignedBandwidthContract>,
+    /// If operator notices are served to neighbors and asked of them while the operator server can't
+    /// be reached
+    #[serde(default = "default_gossip_broadcast_notices")]
+    pub gossip_broadcast_notices: bool,
 }
 
 impl Default for OperatorSettings {
@@ -71,6 +144,17 @@ impl Default for OperatorSettings {
             installation_details: None,
             billing_details: None,
             display_operator_setup: true,
+            share_hardware_telemetry: default_share_hardware_telemetry(),
+            allow_antenna_auth_injection: default_allow_antenna_auth_injection(),
+            antenna_forwarding_rate_limit: None,
+            record_antenna_sessions: false,
+            antenna_sessions_file: default_antenna_sessions_file(),
+            allow_speed_tests: default_allow_speed_tests(),
+            speed_test_signer: None,
+            max_speed_test_duration: default_max_speed_test_duration(),
+            max_speed_test_rate: default_max_speed_test_rate(),
+            bandwidth_contract: None,
+            gossip_broadcast_notices: default_gossip_broadcast_notices(),
         }
     }
 }
@@ -77,10 +77,70 @@ fn default_enable_enforcement() -> bool {
     true
 }
 
+fn default_reconciliation_interval() -> u64 {
+    600
+}
+
+fn default_reconciliation_threshold() -> Uint256 {
+    // 3 cents, a tenth of the default payment threshold
+    30_000_000_000_000_000u64.into()
+}
+
+fn default_max_payment_pause() -> u64 {
+    // an hour
+    3600
+}
+
+fn default_receipts_file() -> String {
+    "/etc/rita-receipts.json".to_string()
+}
+
+fn default_debts_max_age() -> u64 {
+    // 30 days
+    2_592_000
+}
+
+fn default_gas_escalation_timeout() -> u64 {
+    120
+}
+
+fn default_gas_escalation_percent() -> u32 {
+    25
+}
+
+fn default_max_gas_escalations() -> u8 {
+    3
+}
+
+fn default_throttle_throughput() -> u32 {
+    5000
+}
+
 fn default_node_grpc() -> Vec<String> {
     vec!["https://althea.zone:9090".to_string()]
 }
 
+/// Where the fiat price of the system token comes from for accounting summaries. Prices are for one
+/// whole token, 1e18 wei
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FiatPriceSource {
+    /// A fixed price as a decimal string, for stablecoins
+    Fixed { currency: String, price: String },
+    /// An http endpoint returning the average price for a month as a json number, {month} in the
+    /// url is replaced with the month as YYYY-MM
+    Url { currency: String, url: String },
+}
+
+impl FiatPriceSource {
+    pub fn currency(&self) -> &str {
+        match self {
+            FiatPriceSource::Fixed { currency, .. } => currency,
+            FiatPriceSource::Url { currency, .. } => currency,
+        }
+    }
+}
+
 /// This struct is used by both rita and rita_exit to configure the dummy payment controller and
 /// debt keeper
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -108,6 +168,14 @@ pub struct PaymentSettings {
     /// When this flag is false, no client is enforced
     #[serde(default = "default_enable_enforcement")]
     pub enable_enforcement: bool,
+    /// Neighbors owing more than this percentage of the close threshold are throttled before being
+    /// enforced on at the close threshold, a warning that they need to pay. Only exits throttle,
+    /// other nodes leave the tunnel open at this stage. 0 disables throttling
+    #[serde(default)]
+    pub throttle_threshold_percent: u8,
+    /// The speed in kbit/s throttled clients are limited to, should be well above the free tier
+    #[serde(default = "default_throttle_throughput")]
+    pub throttle_throughput: u32,
     /// Our own eth private key we do not store address, instead it is derived from here
     pub eth_private_key: Option<PrivateKey>,
     /// Our own eth Address, derived from the private key on startup and not stored
@@ -136,6 +204,14 @@ pub struct PaymentSettings {
     /// Full file path for Debts storage
     #[serde(default = "default_debts_file")]
     pub debts_file: String,
+    /// When set debts are never written to or restored from debts_file, every restart starts
+    /// with a clean ledger
+    #[serde(default)]
+    pub volatile_debts: bool,
+    /// Debts saved longer ago than this, in seconds, are not restored at startup, credit from
+    /// overpayment still is. 0 restores debts of any age
+    #[serde(default = "default_debts_max_age")]
+    pub debts_max_age: u64,
     #[serde(default = "default_bridge_enabled")]
     pub bridge_enabled: bool,
     /// See where this is referenced in debt keeper, this option is on for exits and off everywhere
@@ -169,6 +245,35 @@ pub struct PaymentSettings {
     /// post-eip1599 networks that do not respect min-fee
     #[serde(default = "default_min_gas")]
     pub min_gas: Uint256,
+    /// Seconds an outgoing eth payment may go without being mined before it is rebroadcast with a
+    /// higher gas price under the same nonce, 0 disables replacement
+    #[serde(default = "default_gas_escalation_timeout")]
+    pub gas_escalation_timeout: u64,
+    /// How much each replacement raises the gas price by, full nodes won't accept less than 10
+    #[serde(default = "default_gas_escalation_percent")]
+    pub gas_escalation_percent: u32,
+    /// Replacements sent for one payment before we stop and let it time out, gas_escalation_timeout
+    /// times this should stay well under the ten minute payment timeout
+    #[serde(default = "default_max_gas_escalations")]
+    pub max_gas_escalations: u8,
+    /// How often in seconds we swap signed debt summaries with each neighbor we have debts with,
+    /// 0 disables reconciliation
+    #[serde(default = "default_reconciliation_interval")]
+    pub reconciliation_interval: u64,
+    /// Differences between our books and a neighbor's larger than this, in wei, are logged and
+    /// reported on /debts/reconciliation
+    #[serde(default = "default_reconciliation_threshold")]
+    pub reconciliation_threshold: Uint256,
+    /// Where the signed receipts for validated payments, those we sent neighbors and those they sent
+    /// us, are kept
+    #[serde(default = "default_receipts_file")]
+    pub receipts_file: String,
+    /// Seconds outgoing payments stay paused from the dashboard before they resume on their own
+    #[serde(default = "default_max_payment_pause")]
+    pub max_payment_pause: u64,
+    /// Fiat price source for the annual accounting summary, without one the summary is in wei only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fiat_price_source: Option<FiatPriceSource>,
 }
 
 /// TODO this is currently a testnet only placeholder it should be replaced
@@ -190,6 +295,8 @@ impl Default for PaymentSettings {
             balance_warning_level: default_balance_warning_level(),
             payment_threshold: default_payment_threshold(),
             enable_enforcement: true,
+            throttle_threshold_percent: 0,
+            throttle_throughput: default_throttle_throughput(),
             eth_private_key: None,
             eth_address: None,
             althea_grpc_list: default_node_grpc(),
@@ -197,6 +304,8 @@ impl Default for PaymentSettings {
             system_chain: default_system_chain(),
             withdraw_chain: default_system_chain(),
             debts_file: default_debts_file(),
+            volatile_debts: false,
+            debts_max_age: default_debts_max_age(),
             bridge_enabled: default_bridge_enabled(),
             debt_limit_enabled: default_debt_limit_enabled(),
             apply_incoming_credit_immediately: default_apply_incoming_credit(),
@@ -205,8 +314,16 @@ impl Default for PaymentSettings {
             simulated_transaction_fee: default_simulated_transaction_fee(),
             forgive_on_reboot: default_forgive_on_reboot(),
             min_gas: default_min_gas(),
+            gas_escalation_timeout: default_gas_escalation_timeout(),
+            gas_escalation_percent: default_gas_escalation_percent(),
+            max_gas_escalations: default_max_gas_escalations(),
+            reconciliation_interval: default_reconciliation_interval(),
+            reconciliation_threshold: default_reconciliation_threshold(),
+            receipts_file: default_receipts_file(),
+            max_payment_pause: default_max_payment_pause(),
             althea_l1_accepted_denoms: vec![default_althea_l1_payment_denom()],
             althea_l1_payment_denom: default_althea_l1_payment_denom(),
+            fiat_price_source: None,
         }
     }
 }
@@ -0,0 +1,85 @@
+//! The role a rita_multi binary starts in. The same image can run as a client router, a gateway or an
+//! exit, the role comes from the command line, the `role` key of the config file or failing those from
+//! whether the config file has an exit_network section.
+
+use crate::SettingsError;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RitaRole {
+    /// A router out in the mesh
+    Client,
+    /// A client router that is treated as a gateway from startup instead of once its wan port is
+    /// seen up, for gateways where link state says nothing about the uplink
+    Gateway,
+    Exit,
+}
+
+impl FromStr for RitaRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "client" => Ok(RitaRole::Client),
+            "gateway" => Ok(RitaRole::Gateway),
+            "exit" => Ok(RitaRole::Exit),
+            _ => Err(format!(
+                "Unknown role {s}, expected client, gateway or exit"
+            )),
+        }
+    }
+}
+
+impl Display for RitaRole {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            RitaRole::Client => write!(f, "client"),
+            RitaRole::Gateway => write!(f, "gateway"),
+            RitaRole::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+/// Picks the role for the contents of a config file
+pub fn role_from_config(config: &str) -> Result<RitaRole, SettingsError> {
+    let config: toml::Value = toml::from_str(config)?;
+    if let Some(role) = config.get("role") {
+        return Ok(role.clone().try_into()?);
+    }
+    if config.get("exit_network").is_some() {
+        Ok(RitaRole::Exit)
+    } else {
+        Ok(RitaRole::Client)
+    }
+}
+
+pub fn detect_role(config_file: &Path) -> Result<RitaRole, SettingsError> {
+    role_from_config(&fs::read_to_string(config_file)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_from_config() {
+        assert_eq!(
+            role_from_config("[network]\nbabel_port = 6872\n").unwrap(),
+            RitaRole::Client
+        );
+        assert_eq!(
+            role_from_config("workers = 4\n[exit_network]\nexit_hello_port = 4875\n").unwrap(),
+            RitaRole::Exit
+        );
+        assert_eq!(
+            role_from_config("role = \"gateway\"\n[network]\nbabel_port = 6872\n").unwrap(),
+            RitaRole::Gateway
+        );
+        assert!(role_from_config("role = \"router\"\n").is_err());
+        assert_eq!("Exit".parse::<RitaRole>(), Ok(RitaRole::Exit));
+    }
+}
@@ -0,0 +1,15 @@
+fn default_snmp_bind_address() -> String {
+    "127.0.0.1:161".to_string()
+}
+
+/// Settings for the read only SNMP agent in rita_client::snmp, which lets network management systems
+/// poll router and mesh statistics. The agent only runs when this section is present in the config
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SnmpSettings {
+    /// Address and port the agent listens on for SNMPv2c requests, localhost by default so the agent
+    /// is never reachable from the WAN. Set it to the LAN address to let a management system poll it
+    #[serde(default = "default_snmp_bind_address")]
+    pub bind_address: String,
+    /// Requests with any other community string are ignored
+    pub community: String,
+}
@@ -0,0 +1,257 @@
+//! Change notifications for settings sections. Loops that read a setting once and hold onto it, like the
+//! babel port in the fast loop or the sockets the peer listener binds, subscribe to the section the setting
+//! lives in and re-read it when told the section changed instead of requiring a restart. Sections are the
+//! top level keys of the settings json, such as "network", "payment" or "exit_network". Subscriptions are
+//! polled, which suits the tick based loops they are used from, or can be waited on with a timeout.
+//!
+//! Changes are only detected while someone is subscribed in the settings' namespace, so setting settings
+//! costs nothing extra until the first subscription. Subscribed sections are compared field by field
+//! while the setter still holds the settings lock, so subscribers are told about exactly the change that
+//! was made and the settings are never serialized to find out.
+
+use crate::client::RitaClientSettings;
+use crate::exit::RitaExitSettingsStruct;
+use crate::Settings;
+use althea_kernel_interface::KI;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub const NETWORK_SECTION: &str = "network";
+pub const PAYMENT_SECTION: &str = "payment";
+pub const EXIT_NETWORK_SECTION: &str = "exit_network";
+
+/// Number of changes seen for each (namespace, section), sections only appear once subscribed to
+type SectionVersions = HashMap<(u32, String), u64>;
+
+lazy_static! {
+    static ref SECTION_VERSIONS: Arc<(Mutex<SectionVersions>, Condvar)> =
+        Arc::new((Mutex::new(HashMap::new()), Condvar::new()));
+}
+
+pub struct SettingsSubscription {
+    netns: u32,
+    section: String,
+    seen: u64,
+}
+
+impl SettingsSubscription {
+    fn current(&self, versions: &SectionVersions) -> u64 {
+        versions
+            .get(&(self.netns, self.section.clone()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// True if the section changed since the subscription was made or this last returned true
+    pub fn has_changed(&mut self) -> bool {
+        let versions = SECTION_VERSIONS.0.lock().unwrap();
+        let current = self.current(&versions);
+        let changed = current != self.seen;
+        self.seen = current;
+        changed
+    }
+
+    /// Blocks until the section changes or the timeout passes, returns true if it changed
+    pub fn wait_for_change(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (lock, condvar) = &**SECTION_VERSIONS;
+        let mut versions = lock.lock().unwrap();
+        loop {
+            let current = self.current(&versions);
+            if current != self.seen {
+                self.seen = current;
+                return true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            versions = condvar.wait_timeout(versions, remaining).unwrap().0;
+        }
+    }
+}
+
+/// Subscribes to changes of a top level settings section in the current namespace
+pub fn subscribe(section: &str) -> SettingsSubscription {
+    let netns = KI.check_integration_test_netns();
+    let mut versions = SECTION_VERSIONS.0.lock().unwrap();
+    let seen = *versions.entry((netns, section.to_string())).or_insert(0);
+    SettingsSubscription {
+        netns,
+        section: section.to_string(),
+        seen,
+    }
+}
+
+/// The settings as they were before a change, kept to compare sections against afterwards
+pub(crate) enum SettingsVersion {
+    Client(Arc<RitaClientSettings>),
+    Exit(Arc<RitaExitSettingsStruct>),
+}
+
+impl SettingsVersion {
+    fn of(settings: &Settings) -> SettingsVersion {
+        match settings {
+            Settings::Adaptor(adapt) => SettingsVersion::Client(Arc::new(
+                adapt
+                    .adaptor
+                    .get_client()
+                    .expect("Adaptor failed to get_client"),
+            )),
+            Settings::Client(settings) => SettingsVersion::Client(settings.clone()),
+            Settings::Exit(settings) => SettingsVersion::Exit(settings.clone()),
+        }
+    }
+}
+
+/// True if the named top level section differs, unknown sections count as changed if anything did
+macro_rules! section_changed {
+    ($before:expr, $after:expr, $section:expr, $($field:ident),*) => {
+        match $section {
+            $(stringify!($field) => $before.$field != $after.$field,)*
+            _ => $before != $after,
+        }
+    };
+}
+
+fn client_section_changed(
+    before: &RitaClientSettings,
+    after: &RitaClientSettings,
+    section: &str,
+) -> bool {
+    section_changed!(
+        before,
+        after,
+        section,
+        payment,
+        log,
+        operator,
+        localization,
+        network,
+        exit_client,
+        app_name,
+        save_interval,
+        snmp,
+        dns,
+        guest_network,
+        static_routes,
+        firewall_rules,
+        role
+    )
+}
+
+fn exit_section_changed(
+    before: &RitaExitSettingsStruct,
+    after: &RitaExitSettingsStruct,
+    section: &str,
+) -> bool {
+    section_changed!(
+        before,
+        after,
+        section,
+        client_registration_url,
+        workers,
+        remote_log,
+        description,
+        payment,
+        localization,
+        network,
+        exit_network,
+        allowed_countries,
+        save_interval
+    )
+}
+
+fn section_differs(before: &SettingsVersion, after: &SettingsVersion, section: &str) -> bool {
+    match (before, after) {
+        (SettingsVersion::Client(before), SettingsVersion::Client(after)) => {
+            client_section_changed(before, after, section)
+        }
+        (SettingsVersion::Exit(before), SettingsVersion::Exit(after)) => {
+            exit_section_changed(before, after, section)
+        }
+        _ => true,
+    }
+}
+
+fn is_subscribed(netns: u32) -> bool {
+    SECTION_VERSIONS
+        .0
+        .lock()
+        .unwrap()
+        .keys()
+        .any(|(ns, _)| *ns == netns)
+}
+
+/// The settings to compare against once a change is made, None if nobody is subscribed in this
+/// namespace or there are no settings yet. Called with the settings write lock held
+pub(crate) fn version_for_subscribers(
+    netns: u32,
+    settings: Option<&Settings>,
+) -> Option<SettingsVersion> {
+    match settings {
+        Some(settings) if is_subscribed(netns) => Some(SettingsVersion::of(settings)),
+        _ => None,
+    }
+}
+
+/// Notifies subscribers of every subscribed section that differs from before. Called with the
+/// settings write lock still held, so the comparison sees exactly this change
+pub(crate) fn notify_subscribers(
+    netns: u32,
+    before: Option<SettingsVersion>,
+    after: Option<&Settings>,
+) {
+    let (before, after) = match (before, after) {
+        (Some(before), Some(after)) => (before, SettingsVersion::of(after)),
+        _ => return,
+    };
+    let (lock, condvar) = &**SECTION_VERSIONS;
+    let mut versions = lock.lock().unwrap();
+    let mut changed = false;
+    for ((ns, section), version) in versions.iter_mut() {
+        if *ns == netns && section_differs(&before, &after, section) {
+            *version += 1;
+            changed = true;
+        }
+    }
+    if changed {
+        condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_differs() {
+        let before = RitaClientSettings::default();
+        let mut after = before.clone();
+        after.network.rita_hello_port += 1;
+        after.log.enabled = !after.log.enabled;
+        let before = SettingsVersion::Client(Arc::new(before));
+        let after = SettingsVersion::Client(Arc::new(after));
+        assert!(section_differs(&before, &after, NETWORK_SECTION));
+        assert!(section_differs(&before, &after, "log"));
+        assert!(!section_differs(&before, &after, PAYMENT_SECTION));
+        assert!(!section_differs(&before, &after, "operator"));
+        // a section we don't know by name is assumed to have changed along with anything else
+        assert!(section_differs(&before, &after, "unknown"));
+        assert!(!section_differs(&before, &before, "unknown"));
+    }
+
+    #[test]
+    fn test_subscription() {
+        let mut sub = subscribe("test_section");
+        assert!(!sub.has_changed());
+        {
+            let mut versions = SECTION_VERSIONS.0.lock().unwrap();
+            *versions.get_mut(&(sub.netns, sub.section.clone())).unwrap() += 1;
+        }
+        assert!(sub.wait_for_change(Duration::from_millis(10)));
+        assert!(!sub.has_changed());
+        assert!(!sub.wait_for_change(Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,60 @@
+use ipnetwork::IpNetwork;
+use std::net::{IpAddr, Ipv4Addr};
+
+fn default_src_zone() -> String {
+    "wan".to_string()
+}
+
+/// A route added by the user on top of the ones babel and the exit manager maintain
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct StaticRoute {
+    pub destination: IpNetwork,
+    /// At least one of the gateway or interface must be set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metric: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallProtocol {
+    Tcp,
+    Udp,
+    TcpUdp,
+}
+
+impl FirewallProtocol {
+    /// The name uci uses for this protocol
+    pub fn as_uci(&self) -> &'static str {
+        match self {
+            FirewallProtocol::Tcp => "tcp",
+            FirewallProtocol::Udp => "udp",
+            FirewallProtocol::TcpUdp => "tcpudp",
+        }
+    }
+
+    pub fn overlaps(&self, other: FirewallProtocol) -> bool {
+        *self == other || *self == FirewallProtocol::TcpUdp || other == FirewallProtocol::TcpUdp
+    }
+}
+
+/// A port opened by the user, either on the router itself or forwarded to a device on the lan
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct FirewallRule {
+    /// Identifies the rule, letters, numbers, - and _ only
+    pub name: String,
+    /// The firewall zone the traffic comes from
+    #[serde(default = "default_src_zone")]
+    pub src_zone: String,
+    pub protocol: FirewallProtocol,
+    pub port: u16,
+    /// Forwards the port to this lan device instead of opening it on the router
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_to: Option<Ipv4Addr>,
+    /// The port on the lan device, the same as port when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_port: Option<u16>,
+}
@@ -1,13 +1,5 @@
-use integration_tests::contract_test::run_altheadb_contract_test;
-use integration_tests::db_migration_test::run_db_migration_test;
-use integration_tests::debts::run_debts_test;
 /// Binary crate for actually running the integration tests
-use integration_tests::five_nodes::run_five_node_test_scenario;
-use integration_tests::mutli_exit::run_multi_exit_test;
-use integration_tests::{
-    payments_althea::run_althea_payments_test_scenario,
-    payments_eth::run_eth_payments_test_scenario, utils::set_sigterm,
-};
+use integration_tests::{run_test_type, utils::set_sigterm};
 use log::info;
 
 use std::env;
@@ -36,23 +28,7 @@ async fn main() {
     let test_type = env::var("TEST_TYPE");
     info!("Starting tests with {:?}", test_type);
     if let Ok(test_type) = test_type {
-        if test_type == "FIVE_NODES" {
-            run_five_node_test_scenario().await;
-        } else if test_type == "DEBTS_TEST" {
-            run_debts_test().await;
-        } else if test_type == "PAYMENTS_ETH" || test_type == "ETH_PAYMENTS" {
-            run_eth_payments_test_scenario().await;
-        } else if test_type == "PAYMENTS_ALTHEA" || test_type == "ALTHEA_PAYMENTS" {
-            run_althea_payments_test_scenario().await
-        } else if test_type == "MULTI_EXIT" {
-            run_multi_exit_test().await
-        } else if test_type == "CONTRACT_TEST" {
-            run_altheadb_contract_test().await
-        } else if test_type == "MIGRATION_TEST" {
-            run_db_migration_test().await
-        } else {
-            panic!("Error unknown test type {}!", test_type);
-        }
+        run_test_type(&test_type).await;
     } else {
         panic!("Error test type not set!");
     }
@@ -0,0 +1,21 @@
+//! Runs a namespace integration test scenario under cargo test. These need root, the WireGuard kernel
+//! module and the test chains started by scripts/integration_tests/start-chains.sh, so they only build
+//! with the integration feature. TEST_TYPE picks the scenario as it does for the tester binary, the
+//! tunnel and billing scenario runs if it is not set.
+#![cfg(feature = "integration")]
+
+use integration_tests::{run_test_type, utils::set_sigterm};
+use std::env;
+
+#[actix_rt::test]
+async fn integration() {
+    let _ = env_logger::Builder::default()
+        .filter(None, log::LevelFilter::Error)
+        .filter(Some("integration_tests"), log::LevelFilter::Info)
+        .try_init();
+    set_sigterm();
+
+    // scenarios share namespaces and global settings, so only one runs per process
+    let test_type = env::var("TEST_TYPE").unwrap_or_else(|_| "TUNNEL_BILLING".to_string());
+    run_test_type(&test_type).await;
+}